@@ -0,0 +1,197 @@
+//! A line for every request to the protocols that answer one request per
+//! connection, with what was asked for, how it went, and how long it took.
+//! Each protocol has its own log in `data/logs/`, and a log is rotated once
+//! it gets too big. IPs are logged in full unless `--anonymize-logs` is used.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Instant,
+};
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+const LOGS_DIR: &str = "data/logs";
+/// Logs are rotated when they get bigger than this.
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+/// How many rotated logs are kept, as `gopher.log.1` (the newest) up to
+/// `gopher.log.5`.
+const KEPT_LOGS: usize = 5;
+
+static ANONYMIZE: OnceLock<bool> = OnceLock::new();
+/// So a log isn't rotated while another request is being written to it.
+static WRITING: Mutex<()> = Mutex::new(());
+
+/// Only log the network that clients are in instead of their whole IP. This
+/// can only be done once, before any of the servers start.
+pub fn set_anonymize(anonymize: bool) {
+    ANONYMIZE
+        .set(anonymize)
+        .expect("anonymizing logs was already set");
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp: DateTime<Utc>,
+    protocol: &'a str,
+    ip: IpAddr,
+    request: &'a str,
+    status: &'a str,
+    bytes: u64,
+    duration_ms: u128,
+}
+
+/// A request that's being answered, which is written to the log when it's
+/// finished.
+pub struct Entry {
+    protocol: &'static str,
+    ip: IpAddr,
+    request: String,
+    started: Instant,
+}
+
+impl Entry {
+    pub fn start(protocol: &'static str, ip: IpAddr) -> Self {
+        Self::started_at(protocol, ip, Instant::now())
+    }
+
+    /// For when the request was received before the entry could be made.
+    pub fn started_at(protocol: &'static str, ip: IpAddr, started: Instant) -> Self {
+        Entry {
+            protocol,
+            ip,
+            request: String::new(),
+            started,
+        }
+    }
+
+    /// What was asked for, like the path, selector, or query. It's empty if
+    /// the request couldn't be read.
+    pub fn request(&mut self, request: &str) {
+        self.request = request.to_owned();
+    }
+
+    /// Write the line, with the protocol's own status (like `51` for Gemini)
+    /// and how many bytes were sent.
+    pub fn finish(self, status: &str, bytes: u64) {
+        let ip = if ANONYMIZE.get().copied().unwrap_or_default() {
+            anonymize(self.ip)
+        } else {
+            self.ip.to_canonical()
+        };
+        let line = Line {
+            timestamp: Utc::now(),
+            protocol: self.protocol,
+            ip,
+            request: &self.request,
+            status,
+            bytes,
+            duration_ms: self.started.elapsed().as_millis(),
+        };
+
+        // the tests would fill the real logs with junk
+        if cfg!(test) {
+            return;
+        }
+
+        let path = Path::new(LOGS_DIR).join(format!("{}.log", self.protocol));
+        let write = || -> io::Result<()> {
+            let line = serde_json::to_string(&line)?;
+            let _writing = WRITING.lock();
+            append(&path, &line)
+        };
+        if let Err(err) = write() {
+            eprintln!("failed to write to the {} access log: {err}", self.protocol);
+        }
+    }
+}
+
+/// Zero the part of the IP that identifies the host: the last byte of IPv4
+/// addresses, and everything after the /48 of IPv6 ones.
+fn anonymize(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => {
+            let mut segments = ip.segments();
+            segments[3..].fill(0);
+            IpAddr::from(segments)
+        }
+    }
+}
+
+/// Add a line to the log, rotating it first if it's too big.
+fn append(path: &Path, line: &str) -> io::Result<()> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    if fs::metadata(path).is_ok_and(|metadata| metadata.len() >= MAX_LOG_SIZE) {
+        rotate(path)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{line}")
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{n}"));
+    rotated.into()
+}
+
+/// Move every log up by one, dropping the oldest.
+fn rotate(path: &Path) -> io::Result<()> {
+    for n in (1..KEPT_LOGS).rev() {
+        let from = rotated_path(path, n);
+        if from.exists() {
+            fs::rename(from, rotated_path(path, n + 1))?;
+        }
+    }
+    fs::rename(path, rotated_path(path, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn anonymizes_ips() {
+        assert_eq!(
+            anonymize(Ipv4Addr::new(192, 0, 2, 123).into()),
+            IpAddr::from([192, 0, 2, 0])
+        );
+        assert_eq!(
+            anonymize("2001:db8:1:2:3:4:5:6".parse::<Ipv6Addr>().unwrap().into()),
+            "2001:db8:1::".parse::<IpAddr>().unwrap()
+        );
+        // ipv4 addresses that came in over ipv6
+        assert_eq!(
+            anonymize("::ffff:192.0.2.123".parse::<IpAddr>().unwrap()),
+            IpAddr::from([192, 0, 2, 0])
+        );
+    }
+
+    #[test]
+    fn rotates_logs() {
+        let dir = std::env::temp_dir().join(format!("matdoesdev-logs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("gopher.log");
+        append(&path, "first").unwrap();
+        rotate(&path).unwrap();
+        append(&path, "second").unwrap();
+        rotate(&path).unwrap();
+        append(&path, "third").unwrap();
+
+        let read = |path: &Path| fs::read_to_string(path).unwrap();
+        assert_eq!(read(&path), "third\n");
+        assert_eq!(read(&rotated_path(&path, 1)), "second\n");
+        assert_eq!(read(&rotated_path(&path, 2)), "first\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,200 @@
+//! Get a real certificate from Let's Encrypt with the HTTP-01 challenge, and
+//! renew it before it expires. The challenges are answered by the HTTP server,
+//! so port 80 has to forward `/.well-known/acme-challenge/` to it.
+//!
+//! If this doesn't work, the self-signed certificate from [`crate::tls`] keeps
+//! being used.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::LazyLock,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, bail};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt,
+    NewAccount, NewOrder, OrderStatus,
+};
+use parking_lot::RwLock;
+use rcgen::{CertificateParams, DistinguishedName, KeyPair};
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::{tls, HOSTNAME};
+
+const ACCOUNT_PATH: &str = "data/acme/account.json";
+const CERT_PATH: &str = "data/acme/cert.pem";
+const KEY_PATH: &str = "data/acme/key.pem";
+
+/// Let's Encrypt certificates last for 90 days, and they recommend renewing
+/// them after 60.
+const RENEW_AFTER: Duration = Duration::from_secs(60 * 60 * 24 * 60);
+/// How often we check if the certificate has to be renewed, and how long we
+/// wait before trying again if it failed.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60 * 12);
+/// How many times the order is checked while waiting for it to be ready, and
+/// then for its certificate, before we give up on it.
+const MAX_POLLS: usize = 10;
+
+/// The key authorizations for the challenges that are in progress, by their
+/// token.
+static CHALLENGES: LazyLock<RwLock<HashMap<String, String>>> = LazyLock::new(Default::default);
+
+/// What the HTTP server should respond with at
+/// `/.well-known/acme-challenge/<token>`.
+pub fn challenge_response(token: &str) -> Option<String> {
+    CHALLENGES.read().get(token).cloned()
+}
+
+/// Load the saved certificate and keep it renewed. This never returns.
+pub async fn run() {
+    if let Err(err) = load_saved().await {
+        println!("no saved acme certificate: {err}");
+    }
+    loop {
+        if needs_renewal().await {
+            println!("getting a new certificate for {HOSTNAME}...");
+            match provision().await {
+                Ok(()) => println!("got a new certificate"),
+                Err(err) => eprintln!("failed to get a certificate: {err}"),
+            }
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+async fn load_saved() -> anyhow::Result<()> {
+    let cert_pem = fs::read(CERT_PATH).await?;
+    let key_pem = fs::read(KEY_PATH).await?;
+    let certs = rustls_pemfile::certs(&mut cert_pem.as_slice()).collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut key_pem.as_slice())?
+        .ok_or(anyhow!("{KEY_PATH} doesn't have a private key"))?;
+    tls::set_certificate(HOSTNAME, certs, key)
+}
+
+async fn needs_renewal() -> bool {
+    let Ok(modified) = fs::metadata(CERT_PATH).await.and_then(|m| m.modified()) else {
+        return true;
+    };
+    SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default()
+        > RENEW_AFTER
+}
+
+async fn account() -> anyhow::Result<Account> {
+    if let Ok(credentials) = fs::read_to_string(ACCOUNT_PATH).await {
+        let credentials: AccountCredentials = serde_json::from_str(&credentials)?;
+        return Ok(Account::from_credentials(credentials).await?);
+    }
+    let (account, credentials) = Account::create(
+        &NewAccount {
+            contact: &[],
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        },
+        LetsEncrypt::Production.url(),
+        None,
+    )
+    .await?;
+    fs::create_dir_all(Path::new(ACCOUNT_PATH).parent().unwrap()).await?;
+    fs::write(ACCOUNT_PATH, serde_json::to_string(&credentials)?).await?;
+    Ok(account)
+}
+
+async fn provision() -> anyhow::Result<()> {
+    let account = account().await?;
+    let mut order = account
+        .new_order(&NewOrder {
+            identifiers: &[Identifier::Dns(HOSTNAME.to_owned())],
+        })
+        .await?;
+
+    let mut tokens = Vec::new();
+    for authorization in order.authorizations().await? {
+        match authorization.status {
+            AuthorizationStatus::Pending => {}
+            AuthorizationStatus::Valid => continue,
+            status => bail!("authorization is {status:?}"),
+        }
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|c| c.r#type == ChallengeType::Http01)
+            .ok_or(anyhow!("no http-01 challenge"))?;
+        let key_authorization = order.key_authorization(challenge);
+        CHALLENGES.write().insert(
+            challenge.token.clone(),
+            key_authorization.as_str().to_owned(),
+        );
+        tokens.push(challenge.token.clone());
+        order.set_challenge_ready(&challenge.url).await?;
+    }
+
+    let result = finish_order(&mut order).await;
+    // the challenges are done whether it worked or not
+    {
+        let mut challenges = CHALLENGES.write();
+        for token in tokens {
+            challenges.remove(&token);
+        }
+    }
+    let (cert_pem, key_pem) = result?;
+
+    fs::create_dir_all(Path::new(CERT_PATH).parent().unwrap()).await?;
+    fs::write(CERT_PATH, &cert_pem).await?;
+    write_key(&key_pem).await?;
+    load_saved().await
+}
+
+/// Save the private key so only we can read it.
+async fn write_key(key_pem: &str) -> anyhow::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(KEY_PATH).await?;
+    // the mode is only used if the file is new
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .await?;
+    }
+    file.write_all(key_pem.as_bytes()).await?;
+    Ok(())
+}
+
+/// Wait for the challenges to be validated, and then get the certificate.
+/// Returns the certificate chain and private key as PEM.
+async fn finish_order(order: &mut instant_acme::Order) -> anyhow::Result<(String, String)> {
+    let mut delay = Duration::from_millis(250);
+    let mut polls = 0;
+    loop {
+        if polls == MAX_POLLS {
+            bail!("order still isn't ready");
+        }
+        polls += 1;
+        tokio::time::sleep(delay).await;
+        match order.refresh().await?.status {
+            OrderStatus::Ready => break,
+            OrderStatus::Invalid => bail!("order is invalid"),
+            _ => delay *= 2,
+        }
+    }
+
+    let key_pair = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![HOSTNAME.to_owned()])?;
+    params.distinguished_name = DistinguishedName::new();
+    let csr = params.serialize_request(&key_pair)?;
+    order.finalize(csr.der()).await?;
+
+    for _ in 0..MAX_POLLS {
+        match order.certificate().await? {
+            Some(cert_pem) => return Ok((cert_pem, key_pair.serialize_pem())),
+            None => tokio::time::sleep(Duration::from_secs(1)).await,
+        }
+    }
+    bail!("certificate wasn't issued")
+}
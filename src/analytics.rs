@@ -0,0 +1,146 @@
+//! Keep track of what people are looking at, across every protocol.
+
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    path::Path,
+    sync::LazyLock,
+};
+
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+pub const EVENTS_PATH: &str = "data/analytics/events.jsonl";
+/// An optional CSV file with `start_ip,end_ip,country` rows, like the free
+/// IP-to-country databases from db-ip.com.
+pub const GEOIP_PATH: &str = "data/analytics/geoip.csv";
+
+static ANALYTICS: LazyLock<Analytics> = LazyLock::new(Analytics::load);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    pub protocol: String,
+    pub path: String,
+    /// The slug of the blog post, if this was a request for one.
+    pub post: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    pub country: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Stats {
+    pub total: usize,
+    pub protocols: HashMap<String, usize>,
+    pub posts: HashMap<String, usize>,
+    pub countries: HashMap<String, usize>,
+}
+
+impl Stats {
+    fn add(&mut self, event: &Event) {
+        self.total += 1;
+        *self.protocols.entry(event.protocol.clone()).or_default() += 1;
+        if let Some(post) = &event.post {
+            *self.posts.entry(post.clone()).or_default() += 1;
+        }
+        if let Some(country) = &event.country {
+            *self.countries.entry(country.clone()).or_default() += 1;
+        }
+    }
+
+    /// Sort the entries of one of the maps by their count, highest first.
+    pub fn top(counts: &HashMap<String, usize>, limit: usize) -> Vec<(&str, usize)> {
+        let mut entries = counts
+            .iter()
+            .map(|(name, &count)| (name.as_str(), count))
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        entries.truncate(limit);
+        entries
+    }
+}
+
+struct Analytics {
+    stats: RwLock<Stats>,
+    /// Sorted by the start of the range.
+    geoip: Vec<(IpAddr, IpAddr, String)>,
+}
+
+impl Analytics {
+    fn load() -> Self {
+        let mut stats = Stats::default();
+        for line in fs::read_to_string(EVENTS_PATH).unwrap_or_default().lines() {
+            if let Ok(event) = serde_json::from_str::<Event>(line) {
+                stats.add(&event);
+            }
+        }
+
+        let mut geoip = Vec::new();
+        for line in fs::read_to_string(GEOIP_PATH).unwrap_or_default().lines() {
+            let mut columns = line.split(',').map(|c| c.trim().trim_matches('"'));
+            let (Some(start), Some(end), Some(country)) =
+                (columns.next(), columns.next(), columns.next())
+            else {
+                continue;
+            };
+            let (Ok(start), Ok(end)) = (start.parse::<IpAddr>(), end.parse::<IpAddr>()) else {
+                continue;
+            };
+            geoip.push((start, end, country.to_owned()));
+        }
+        geoip.sort_by_key(|(start, _, _)| *start);
+
+        Self {
+            stats: RwLock::new(stats),
+            geoip,
+        }
+    }
+
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let ip = ip.to_canonical();
+        let index = self.geoip.partition_point(|(start, _, _)| *start <= ip);
+        let (_, end, country) = self.geoip.get(index.checked_sub(1)?)?;
+        if ip <= *end {
+            Some(country.clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// Record a request. `post` is the slug of the blog post that was requested,
+/// if any.
+pub fn record(protocol: &str, path: &str, post: Option<&str>, remote_ip: IpAddr) {
+    let event = Event {
+        protocol: protocol.to_owned(),
+        path: path.to_owned(),
+        post: post.map(str::to_owned),
+        timestamp: Utc::now(),
+        country: ANALYTICS.country(remote_ip),
+    };
+    ANALYTICS.stats.write().add(&event);
+
+    // the tests would fill the real analytics with junk
+    if cfg!(test) {
+        return;
+    }
+
+    let write = || -> std::io::Result<()> {
+        fs::create_dir_all(Path::new(EVENTS_PATH).parent().unwrap())?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(EVENTS_PATH)?;
+        writeln!(file, "{}", serde_json::to_string(&event)?)?;
+        Ok(())
+    };
+    if let Err(err) = write() {
+        eprintln!("failed to write analytics event: {err}");
+    }
+}
+
+pub fn stats() -> Stats {
+    ANALYTICS.stats.read().clone()
+}
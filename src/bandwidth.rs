@@ -0,0 +1,234 @@
+//! Limits on how fast and how much each client can download, so one client
+//! downloading every image over gopher can't use up the whole uplink. Rates
+//! are set per protocol with `--throttle`, and the daily quota for each IP is
+//! set with `--daily-quota`. Neither is limited by default.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::{LazyLock, OnceLock},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+/// How many bytes per second each protocol can send on one connection.
+static RATES: OnceLock<HashMap<String, u64>> = OnceLock::new();
+/// How many bytes each IP can be sent per day.
+static DAILY_QUOTA: OnceLock<u64> = OnceLock::new();
+static USAGE: LazyLock<Mutex<Usage>> = LazyLock::new(Default::default);
+
+/// Parse a `--throttle` argument like `gopher=65536`.
+pub fn parse_arg(arg: &str) -> Option<(String, u64)> {
+    let (protocol, rate) = arg.split_once('=')?;
+    let rate = rate.parse().ok().filter(|&rate| rate > 0)?;
+    Some((protocol.to_owned(), rate))
+}
+
+/// Set the limits. This can only be done once, before any of the servers
+/// start.
+pub fn set(rates: HashMap<String, u64>, daily_quota: Option<u64>) {
+    RATES.set(rates).expect("rates were already set");
+    if let Some(daily_quota) = daily_quota {
+        DAILY_QUOTA
+            .set(daily_quota)
+            .expect("daily quota was already set");
+    }
+}
+
+/// How much each IP has been sent today.
+#[derive(Default)]
+struct Usage {
+    /// Days since the unix epoch, so everything is forgotten at midnight UTC.
+    day: u64,
+    bytes: HashMap<IpAddr, u64>,
+}
+
+impl Usage {
+    /// How many more bytes the IP can be sent today.
+    fn remaining(&mut self, ip: IpAddr, quota: u64, day: u64) -> u64 {
+        if day != self.day {
+            self.day = day;
+            self.bytes.clear();
+        }
+        quota.saturating_sub(self.bytes.get(&ip).copied().unwrap_or_default())
+    }
+
+    fn add(&mut self, ip: IpAddr, bytes: u64) {
+        *self.bytes.entry(ip).or_default() += bytes;
+    }
+}
+
+fn today() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() / (24 * 60 * 60)
+}
+
+/// A token bucket that fills up at `rate` bytes per second, and can hold a
+/// second's worth so short responses aren't slowed down.
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    filled_at: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Bucket {
+            rate,
+            tokens: rate as f64,
+            filled_at: Instant::now(),
+        }
+    }
+
+    /// How many bytes can be sent now, or how long to wait until one can.
+    fn available(&mut self, now: Instant) -> Result<usize, Duration> {
+        let elapsed = now.duration_since(self.filled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.filled_at = now;
+        if self.tokens >= 1. {
+            Ok(self.tokens as usize)
+        } else {
+            Err(Duration::from_secs_f64(
+                (1. - self.tokens) / self.rate as f64,
+            ))
+        }
+    }
+
+    fn take(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// A connection with the protocol's rate and the IP's quota applied to what's
+/// written to it. Reading isn't limited.
+pub struct Throttled<S> {
+    inner: S,
+    ip: IpAddr,
+    bucket: Option<Bucket>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    written: u64,
+}
+
+impl<S> Throttled<S> {
+    /// How many bytes have been sent on the connection.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+/// Apply the limits for the protocol to a connection from the IP.
+pub fn throttle<S>(protocol: &str, ip: IpAddr, inner: S) -> Throttled<S> {
+    let rate = RATES.get().and_then(|rates| rates.get(protocol)).copied();
+    Throttled {
+        inner,
+        ip,
+        bucket: rate.map(Bucket::new),
+        sleep: None,
+        written: 0,
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut len = buf.len();
+        if let Some(bucket) = &mut this.bucket {
+            loop {
+                if let Some(sleep) = &mut this.sleep {
+                    ready!(sleep.as_mut().poll(cx));
+                    this.sleep = None;
+                }
+                match bucket.available(Instant::now()) {
+                    Ok(available) => {
+                        len = len.min(available);
+                        break;
+                    }
+                    Err(wait) => this.sleep = Some(Box::pin(tokio::time::sleep(wait))),
+                }
+            }
+        }
+
+        if let Some(&quota) = DAILY_QUOTA.get() {
+            let remaining = USAGE.lock().remaining(this.ip, quota, today());
+            if remaining == 0 && len > 0 {
+                return Poll::Ready(Err(io::Error::other("daily transfer quota exceeded")));
+            }
+            len = len.min(remaining.try_into().unwrap_or(usize::MAX));
+        }
+        let result = Pin::new(&mut this.inner).poll_write(cx, &buf[..len]);
+        if let Poll::Ready(Ok(written)) = &result {
+            this.written += *written as u64;
+            if let Some(bucket) = &mut this.bucket {
+                bucket.take(*written);
+            }
+            if DAILY_QUOTA.get().is_some() {
+                USAGE.lock().add(this.ip, *written as u64);
+            }
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn buckets_refill() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(100);
+        assert_eq!(bucket.available(start), Ok(100));
+        bucket.take(100);
+        assert!(bucket.available(start).is_err());
+        assert_eq!(bucket.available(start + Duration::from_millis(500)), Ok(50));
+        // it doesn't fill up past a second's worth
+        assert_eq!(bucket.available(start + Duration::from_secs(60)), Ok(100));
+    }
+
+    #[test]
+    fn quotas_reset_every_day() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let mut usage = Usage::default();
+        assert_eq!(usage.remaining(ip, 100, 1), 100);
+        usage.add(ip, 60);
+        assert_eq!(usage.remaining(ip, 100, 1), 40);
+        usage.add(ip, 40);
+        assert_eq!(usage.remaining(ip, 100, 1), 0);
+        assert_eq!(usage.remaining(ip, 100, 2), 100);
+    }
+}
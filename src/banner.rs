@@ -0,0 +1,193 @@
+//! The big "matdoesdev" at the top of the landing pages. It's drawn from
+//! figlet-style fonts when the pages are generated, so the protocols can each
+//! pick a size that fits how they're usually read.
+
+/// What the banners say.
+pub const NAME: &str = "matdoesdev";
+
+pub struct Font {
+    /// Every glyph has this many rows.
+    height: usize,
+    /// The number of spaces between glyphs.
+    spacing: usize,
+    glyphs: &'static [(char, &'static [&'static str])],
+}
+
+/// Based on figlet's "colossal" font. It's 92 columns wide for [`NAME`].
+pub const BIG: Font = Font {
+    height: 8,
+    spacing: 1,
+    glyphs: &[
+        (
+            'a',
+            &[
+                "        ",
+                "        ",
+                "        ",
+                " 8888b. ",
+                "    \"88b",
+                ".d888888",
+                "888  888",
+                "\"Y888888",
+            ],
+        ),
+        (
+            'd',
+            &[
+                "     888",
+                "     888",
+                "     888",
+                " .d88888",
+                "d88\" 888",
+                "888  888",
+                "Y88b 888",
+                " \"Y88888",
+            ],
+        ),
+        (
+            'e',
+            &[
+                "        ",
+                "        ",
+                "        ",
+                " .d88b. ",
+                "d8P  Y8b",
+                "88888888",
+                "Y8b.    ",
+                " \"Y8888 ",
+            ],
+        ),
+        (
+            'm',
+            &[
+                "             ",
+                "             ",
+                "             ",
+                "88888b.d88b. ",
+                "888 \"888 \"88b",
+                "888  888  888",
+                "888  888  888",
+                "888  888  888",
+            ],
+        ),
+        (
+            'o',
+            &[
+                "        ",
+                "        ",
+                "        ",
+                " .d88b. ",
+                "d88\"\"88b",
+                "888  888",
+                "Y88..88P",
+                " \"Y88P\" ",
+            ],
+        ),
+        (
+            's',
+            &[
+                "        ",
+                "        ",
+                "        ",
+                ".d8888b ",
+                "88K     ",
+                "\"Y8888b.",
+                "     X88",
+                " 88888P'",
+            ],
+        ),
+        (
+            't',
+            &[
+                "888   ", "888   ", "888   ", "888888", "888   ", "888   ", "Y88b. ", " \"Y888",
+            ],
+        ),
+        (
+            'v',
+            &[
+                "        ", "        ", "        ", "888  888", "888  888", "Y88  88P", " Y8bd8P ",
+                "  Y88P  ",
+            ],
+        ),
+    ],
+};
+
+/// Based on figlet's "small" font. It's 63 columns wide for [`NAME`], so it
+/// fits in an 80 column terminal.
+pub const SMALL: Font = Font {
+    height: 4,
+    spacing: 1,
+    glyphs: &[
+        ('a', &["      ", " __ _ ", "/ _` |", "\\__,_|"]),
+        ('d', &["    _ ", " __| |", "/ _` |", "\\__,_|"]),
+        ('e', &["     ", " ___ ", "/ -_)", "\\___|"]),
+        ('m', &["       ", " _ __  ", "| '  \\ ", "|_|_|_|"]),
+        ('o', &["     ", " ___ ", "/ _ \\", "\\___/"]),
+        ('s', &["    ", " ___", "(_-<", "/__/"]),
+        ('t', &[" _   ", "| |_ ", "|  _|", " \\__|"]),
+        ('v', &["     ", "__ __", "\\ V /", " \\_/ "]),
+    ],
+};
+
+impl Font {
+    fn glyph(&self, c: char) -> Option<&'static [&'static str]> {
+        self.glyphs
+            .iter()
+            .find(|(glyph_char, _)| *glyph_char == c.to_ascii_lowercase())
+            .map(|(_, rows)| *rows)
+    }
+
+    /// How many columns the text would take up, or `None` if the font doesn't
+    /// have one of its characters.
+    pub fn width(&self, text: &str) -> Option<usize> {
+        let mut width = 0;
+        for c in text.chars() {
+            width += self.glyph(c)?[0].len();
+        }
+        Some(width + text.chars().count().saturating_sub(1) * self.spacing)
+    }
+
+    /// Draw the text, without trailing spaces on the lines. Returns `None` if
+    /// the font doesn't have one of its characters.
+    pub fn render(&self, text: &str) -> Option<String> {
+        let glyphs = text
+            .chars()
+            .map(|c| self.glyph(c))
+            .collect::<Option<Vec<_>>>()?;
+        let spacing = " ".repeat(self.spacing);
+
+        let lines = (0..self.height)
+            .map(|row| {
+                glyphs
+                    .iter()
+                    .map(|glyph| glyph[row])
+                    .collect::<Vec<_>>()
+                    .join(&spacing)
+                    .trim_end()
+                    .to_owned()
+            })
+            .collect::<Vec<_>>();
+        Some(lines.join("\n"))
+    }
+}
+
+/// The biggest banner that fits in the width. If none of the fonts fit, it's
+/// just the text.
+pub fn fit(text: &str, max_width: usize) -> String {
+    [&BIG, &SMALL]
+        .into_iter()
+        .filter(|font| font.width(text).is_some_and(|width| width <= max_width))
+        .find_map(|font| font.render(text))
+        .unwrap_or_else(|| text.to_owned())
+}
+
+/// The banner for a protocol's landing page. Finger is read in terminals, so
+/// it gets one that fits in 80 columns, and the rest can be as wide as they
+/// want.
+pub fn for_protocol(protocol: &str) -> String {
+    let max_width = match protocol {
+        "finger" => 80,
+        _ => usize::MAX,
+    };
+    fit(NAME, max_width)
+}
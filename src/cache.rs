@@ -0,0 +1,32 @@
+//! Responses that are the same every time, kept already encoded so serving one
+//! is a single `write_all` of a shared buffer. Each protocol makes its own
+//! [`ResponseCache`] when it's generated, so a recrawl starts with an empty
+//! one.
+
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::RwLock;
+
+/// Encoded responses by their route. Clones share the same responses.
+#[derive(Clone, Default)]
+pub struct ResponseCache {
+    responses: Arc<RwLock<HashMap<String, Arc<[u8]>>>>,
+}
+
+impl ResponseCache {
+    /// The response for the route, which is made with `render` the first time
+    /// it's needed. Only routes that exist should be cached, or anyone could
+    /// fill it up.
+    pub fn get_or_insert_with(&self, route: &str, render: impl FnOnce() -> Vec<u8>) -> Arc<[u8]> {
+        if let Some(response) = self.responses.read().get(route) {
+            return Arc::clone(response);
+        }
+        let response = Arc::<[u8]>::from(render());
+        Arc::clone(
+            self.responses
+                .write()
+                .entry(route.to_owned())
+                .or_insert(response),
+        )
+    }
+}
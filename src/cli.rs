@@ -0,0 +1,640 @@
+//! The `matdoesdev-protocols` binary: parsing the arguments, loading the site
+//! data, and restarting the servers when it's recrawled. Besides serving, it
+//! can save the crawled data, export the pages to files, and show how a post
+//! is rendered.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use tokio_rustls::rustls;
+
+use crate::{
+    access_log, acme, bandwidth,
+    crawl::{self, SiteData},
+    drafts, export, federation,
+    integrations::matrix,
+    lifecycle, listen,
+    motd::{self, Fragment},
+    protocols::{
+        self,
+        dict::Dict,
+        discard::Discard,
+        finger::{Finger, FingerOptions},
+        gemini::Gemini,
+        gopher::{Gopher, GopherOptions, LinkStyle},
+        http::Http,
+        ident::Ident,
+        imap::Imap,
+        minecraft_ping::MinecraftPing,
+        modbus::Modbus,
+        mqtt::Mqtt,
+        mux::Mux,
+        nex::Nex,
+        pop3::Pop3,
+        qotd::Qotd,
+        render::gemtext_to_html,
+        scroll::Scroll,
+        ssh::Ssh,
+        telnet::Telnet,
+        Protocol, ProtocolServer, ServerBuilder,
+    },
+    search, self_test, server_info,
+    sites::{self, SiteRegistry},
+    sources::{self, ContentSource},
+    terminal::{Location, TerminalSession},
+    thumbnail, timeouts, watch, ALT_HOSTNAMES, HOSTNAME, ONION_ADDRESS,
+};
+
+/// How old the cache can be before we crawl again in debug builds.
+const DEBUG_CACHE_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24);
+/// How long to wait before trying the source again when we're serving stale
+/// data. This doubles every attempt, up to [`MAX_STALE_RETRY_DELAY`].
+const STALE_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_STALE_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Parser)]
+#[command(version, about = "The servers behind matdoes.dev")]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Without a subcommand it serves, so these are `serve`'s arguments.
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve every protocol until a drain is requested, and restart them when
+    /// the site is recrawled.
+    Serve(ServeArgs),
+    /// Load the site data and save it, so it can be used later with
+    /// `--source cache`.
+    Crawl {
+        #[command(flatten)]
+        source: SourceArgs,
+        #[arg(long, default_value = sources::CACHE_PATH)]
+        output: PathBuf,
+    },
+    /// Write the generated pages to a directory instead of serving them.
+    Export {
+        #[command(flatten)]
+        source: SourceArgs,
+        dir: PathBuf,
+        /// Only export these, can be used more than once. Everything is
+        /// exported if it isn't given.
+        #[arg(long, value_enum)]
+        format: Vec<export::Format>,
+    },
+    /// Print a post the way one of the protocols shows it. The site data is
+    /// read from the cache unless `--source` says otherwise, so this doesn't
+    /// need the network.
+    Render {
+        #[command(flatten)]
+        source: SourceArgs,
+        slug: String,
+        #[arg(long, value_enum, default_value_t = RenderProtocol::Terminal)]
+        protocol: RenderProtocol,
+        /// The terminal's width in columns. The other protocols don't wrap.
+        #[arg(long, default_value_t = 80)]
+        width: usize,
+    },
+}
+
+#[derive(Args)]
+struct SourceArgs {
+    /// Where the site data comes from.
+    #[arg(long, value_enum)]
+    source: Option<SourceName>,
+    /// Use a directory of markdown posts instead of `--source`.
+    #[arg(long)]
+    markdown: Option<PathBuf>,
+    #[arg(long, default_value_t = crawl::DEFAULT_CONCURRENCY, value_parser = positive)]
+    crawl_concurrency: usize,
+    /// Like socks5h://127.0.0.1:9050 to crawl over tor.
+    #[arg(long)]
+    crawl_proxy: Option<String>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SourceName {
+    Crawl,
+    Cache,
+    Demo,
+}
+
+impl SourceArgs {
+    /// The source to load from, which is `default` if none was picked.
+    fn source(self, default: SourceName) -> Box<dyn ContentSource> {
+        if let Some(dir) = self.markdown {
+            return Box::new(sources::MarkdownDir(dir));
+        }
+        match self.source.unwrap_or(default) {
+            SourceName::Crawl => Box::new(sources::Crawler {
+                concurrency: self.crawl_concurrency,
+                proxy: self.crawl_proxy,
+            }),
+            SourceName::Cache => Box::new(sources::Cache::new(None)),
+            SourceName::Demo => Box::new(sources::Demo),
+        }
+    }
+}
+
+// clap doesn't treat an alias for `Vec` as a list of values, so this is parsed
+// from one argument
+type Fragments = Vec<Fragment>;
+
+#[derive(Args)]
+struct ServeArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    /// Also listen with tls on another port.
+    #[arg(long)]
+    gopher_tls: bool,
+    #[arg(long)]
+    finger_tls: bool,
+    /// `url` for h items with URL: selectors, or `text` to write the urls out
+    /// for clients that don't support those.
+    #[arg(long, value_parser = link_style)]
+    gopher_links: Option<LinkStyle>,
+    /// How many posts are on each page of the blog and tag menus.
+    #[arg(long)]
+    gopher_items_per_page: Option<usize>,
+    /// Also serve ssh, gemini, http, gopher, and finger on one port.
+    #[arg(long)]
+    mux_port: Option<u16>,
+    /// Get a real certificate instead of using a self-signed one.
+    #[arg(long)]
+    acme: bool,
+    /// Send webmentions and fediverse posts when a recrawl finds new posts.
+    #[arg(long)]
+    announce: bool,
+    /// Another hostname that we also serve, can be used more than once.
+    #[arg(long = "hostname")]
+    hostnames: Vec<String>,
+    /// Only listen on this address instead of every interface, can be used
+    /// more than once.
+    #[arg(long = "bind")]
+    bind_addresses: Vec<IpAddr>,
+    /// In seconds, how long clients get to send their requests.
+    #[arg(long)]
+    read_timeout: Option<u64>,
+    /// In seconds, how long clients get to read our responses.
+    #[arg(long)]
+    write_timeout: Option<u64>,
+    /// The most bytes per second a protocol can send on one connection, like
+    /// `gopher=65536`, can be used more than once.
+    #[arg(long, value_parser = throttle)]
+    throttle: Vec<(String, u64)>,
+    /// The most bytes that each ip can be sent per day.
+    #[arg(long)]
+    daily_quota: Option<u64>,
+    /// Start everything on ports that the system picks, check that some of the
+    /// protocols answer, and exit.
+    #[arg(long)]
+    self_test: bool,
+    /// Only log the network that clients are in, not their whole ip.
+    #[arg(long)]
+    anonymize_logs: bool,
+    /// Also serve someone else's site for gopher and finger, from a directory
+    /// of markdown posts like `example.com=sites/example`, can be used more
+    /// than once.
+    #[arg(long = "site", value_parser = site)]
+    sites: Vec<(String, PathBuf)>,
+    /// What's in the message shown when connecting over telnet or ssh, like
+    /// `art,qotd,latest-post,uptime`.
+    #[arg(long, value_parser = motd_fragments)]
+    motd: Option<Fragments>,
+    /// The onion service that the site is also served at.
+    #[arg(long, value_parser = onion_address)]
+    onion: Option<String>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RenderProtocol {
+    Terminal,
+    Gemini,
+    Gopher,
+    Finger,
+    Html,
+}
+
+fn positive(arg: &str) -> Result<usize, String> {
+    arg.parse()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or_else(|| "expected a positive number".to_owned())
+}
+
+fn link_style(arg: &str) -> Result<LinkStyle, String> {
+    LinkStyle::from_name(arg).ok_or_else(|| "expected url or text".to_owned())
+}
+
+fn throttle(arg: &str) -> Result<(String, u64), String> {
+    bandwidth::parse_arg(arg)
+        .ok_or_else(|| "expected a protocol and a number of bytes per second".to_owned())
+}
+
+fn site(arg: &str) -> Result<(String, PathBuf), String> {
+    sites::parse_arg(arg).ok_or_else(|| "expected a hostname and a directory".to_owned())
+}
+
+fn motd_fragments(arg: &str) -> Result<Fragments, String> {
+    motd::parse_arg(arg)
+        .ok_or_else(|| "expected a list of art, qotd, latest-post, and uptime".to_owned())
+}
+
+fn onion_address(arg: &str) -> Result<String, String> {
+    if arg.ends_with(".onion") {
+        Ok(arg.to_owned())
+    } else {
+        Err("expected a .onion address".to_owned())
+    }
+}
+
+/// Parse the arguments and do what they say.
+pub async fn run() {
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Serve(cli.serve)) {
+        Command::Serve(args) => serve_until_drained(args).await,
+        Command::Crawl { source, output } => {
+            let source = source.source(SourceName::Crawl);
+            println!("loading site data from {}...", source.name());
+            let data = match source.load().await {
+                Ok(data) => data,
+                Err(err) => {
+                    eprintln!("couldn't load from {}: {err}", source.name());
+                    std::process::exit(1);
+                }
+            };
+            let cache = sources::Cache {
+                path: output,
+                max_age: None,
+            };
+            if let Err(err) = cache.save(&data).await {
+                eprintln!("failed to write {:?}: {err}", cache.path);
+                std::process::exit(1);
+            }
+            println!("saved {} posts to {:?}", data.blog.len(), cache.path);
+        }
+        Command::Export {
+            source,
+            dir,
+            format,
+        } => {
+            let data = load_site_data(&*source.source(SourceName::Crawl)).await;
+            let formats = if format.is_empty() {
+                export::Format::ALL.to_vec()
+            } else {
+                format
+            };
+            export::export(&data, &dir, &formats).await.unwrap();
+        }
+        Command::Render {
+            source,
+            slug,
+            protocol,
+            width,
+        } => {
+            let data = load_site_data(&*source.source(SourceName::Cache)).await;
+            match render(data, &slug, protocol, width) {
+                Some(page) => print!("{page}"),
+                None => {
+                    eprintln!("there's no post called {slug}");
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+/// Load the site data, and serve it until a drain is requested.
+async fn serve_until_drained(args: ServeArgs) {
+    println!("Hello, world!");
+    server_info::start();
+
+    if let Some(style) = args.gopher_links {
+        protocols::gopher::set_link_style(style);
+    }
+    if let Some(items_per_page) = args.gopher_items_per_page {
+        protocols::gopher::set_items_per_page(items_per_page);
+    }
+    if let Some(address) = args.onion {
+        ONION_ADDRESS.set(address).unwrap();
+    }
+    ALT_HOSTNAMES.set(args.hostnames).unwrap();
+    let mut bind_addresses = args.bind_addresses;
+    if args.self_test {
+        bind_addresses = vec![IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        listen::use_ephemeral_ports();
+    }
+    listen::set_bind_addresses(bind_addresses);
+    timeouts::set(
+        args.read_timeout.map(Duration::from_secs),
+        args.write_timeout.map(Duration::from_secs),
+    );
+    bandwidth::set(HashMap::from_iter(args.throttle), args.daily_quota);
+    access_log::set_anonymize(args.anonymize_logs);
+    motd::set_fragments(args.motd);
+
+    let source = args.source.source(SourceName::Crawl);
+    let mut data = load_site_data(&*source).await;
+
+    let site_registry = SiteRegistry::load(args.sites).await;
+    remove_unused_media(&data, &site_registry).await;
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("Failed to install rustls crypto provider");
+
+    if args.self_test {
+        search::build(&data.blog);
+        build_torrents(&data).await;
+        let passed = tokio::select! {
+            _ = serve(&data, &site_registry, false, false, None) => false,
+            passed = self_test::run() => passed,
+        };
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    tokio::spawn(protocols::tracker::expire_peers());
+    if args.acme {
+        tokio::spawn(acme::run());
+    }
+
+    loop {
+        search::build(&data.blog);
+        build_torrents(&data).await;
+
+        println!("now serving");
+
+        // dropping the servers closes their listeners, but the connections
+        // that are already open keep going since they're spawned
+        let new_data = tokio::select! {
+            _ = serve(&data, &site_registry, args.gopher_tls, args.finger_tls, args.mux_port) => break,
+            new_data = recrawl(&*source, data.stale) => new_data,
+            _ = lifecycle::drain_requested() => {
+                println!("draining, waiting for connections to close: {:?}", lifecycle::sessions());
+                lifecycle::drained().await;
+                println!("drained");
+                return;
+            }
+        };
+        let new_posts = drafts::newly_published(&data.blog, &new_data.blog);
+        for post in &new_posts {
+            matrix::notify(format!(
+                "New post: {} https://{HOSTNAME}/{}",
+                post.title, post.slug
+            ));
+        }
+        if args.announce {
+            federation::announce(new_posts);
+        }
+        data = new_data;
+        remove_unused_media(&data, &site_registry).await;
+        println!("restarting with the new site data");
+    }
+}
+
+/// A post the way one of the protocols shows it, or `None` if there's no post
+/// with that slug.
+fn render(data: SiteData, slug: &str, protocol: RenderProtocol, width: usize) -> Option<String> {
+    Some(match protocol {
+        RenderProtocol::Terminal => {
+            data.blog.iter().find(|post| post.slug == slug)?;
+            let mut session =
+                TerminalSession::new(Arc::new(data), "render", IpAddr::V4(Ipv4Addr::LOCALHOST));
+            session.render_text(
+                Location::BlogPost {
+                    slug: slug.to_owned(),
+                },
+                width,
+            )
+        }
+        RenderProtocol::Gemini => Gemini::generate(&data, &Default::default())
+            .posts_gmi
+            .remove(slug)?,
+        RenderProtocol::Gopher => Gopher::generate(&data, &Default::default())
+            .posts_content
+            .get(slug)?
+            .to_string(),
+        RenderProtocol::Finger => Finger::generate(&data, &Default::default())
+            .posts_content
+            .remove(slug)?,
+        RenderProtocol::Html => gemtext_to_html(
+            Gemini::generate(&data, &Default::default())
+                .posts_gmi
+                .get(slug)?,
+        ),
+    })
+}
+
+/// Start every server. This only finishes if all of them stop.
+async fn serve(
+    data: &SiteData,
+    sites: &SiteRegistry,
+    gopher_tls: bool,
+    finger_tls: bool,
+    mux_port: Option<u16>,
+) {
+    let data = Arc::new(data.clone());
+    // every protocol that shows the quote of the day gets the same one, so
+    // they all change together
+    let qotd = Qotd::generate(&data, &None);
+    let gopher_options = GopherOptions {
+        tls: gopher_tls,
+        sites: sites.generate(),
+    };
+    let finger_options = FingerOptions {
+        qotd: qotd.clone(),
+        tls: finger_tls,
+        sites: sites.generate(),
+    };
+
+    let mux = mux_port.map(|port| Mux {
+        port,
+        ssh: Ssh::generate(&data, &qotd),
+        gemini: Arc::new(Gemini::generate(&data, &qotd)),
+        http: Arc::new(Http::generate(&data, &qotd)),
+        gopher: Arc::new(Gopher::generate(&data, &gopher_options)),
+        finger: Arc::new(Finger::generate(&data, &finger_options)),
+    });
+
+    let server = ProtocolServer::new()
+        .with(
+            &ServerBuilder::<Gemini>::new()
+                .options(qotd.clone())
+                .build(data.clone()),
+        )
+        .with(
+            &ServerBuilder::<Ssh>::new()
+                .options(qotd.clone())
+                .build(data.clone()),
+        )
+        .with(
+            &ServerBuilder::<Telnet>::new()
+                .options(qotd.clone())
+                .build(data.clone()),
+        )
+        .with(
+            &ServerBuilder::<Gopher>::new()
+                .options(gopher_options)
+                .build(data.clone()),
+        )
+        .with(
+            &ServerBuilder::<Finger>::new()
+                .options(finger_options)
+                .build(data.clone()),
+        )
+        .with(&ServerBuilder::<Nex>::new().build(data.clone()))
+        .with(&ServerBuilder::<Ident>::new().build(data.clone()))
+        .with(&ServerBuilder::<Discard>::new().build(data.clone()))
+        .with(&ServerBuilder::<Dict>::new().build(data.clone()))
+        .with(&ServerBuilder::<Imap>::new().build(data.clone()))
+        .with(&ServerBuilder::<Pop3>::new().build(data.clone()))
+        .with(&ServerBuilder::<Scroll>::new().build(data.clone()))
+        .with(
+            &ServerBuilder::<Qotd>::new()
+                .options(Some(qotd.clone()))
+                .build(data.clone()),
+        )
+        .with(
+            &ServerBuilder::<Http>::new()
+                .options(qotd.clone())
+                .build(data.clone()),
+        )
+        .with(
+            &ServerBuilder::<Modbus>::new()
+                .options(qotd.clone())
+                .build(data.clone()),
+        )
+        .with(
+            &ServerBuilder::<MinecraftPing>::new()
+                .options(qotd.clone())
+                .build(data.clone()),
+        )
+        .with(
+            &ServerBuilder::<Mqtt>::new()
+                .options(qotd.clone())
+                .build(data),
+        );
+
+    let servers = async {
+        tokio::join!(server.serve(), async {
+            if let Some(mux) = mux {
+                mux.serve().await;
+            }
+        })
+    };
+    tokio::select! {
+        _ = servers => {}
+        _ = watch::run(qotd.clone()) => {}
+        _ = matrix::run(qotd) => {}
+    }
+}
+
+/// Wait for a recrawl to be requested from the admin endpoint, and then load
+/// the site data again. If that fails we wait for the next one. When the data
+/// we have is stale, it's also tried again by itself with a backoff.
+async fn recrawl(source: &dyn ContentSource, stale: bool) -> SiteData {
+    let mut retry_delay = STALE_RETRY_DELAY;
+    loop {
+        if stale {
+            tokio::select! {
+                _ = lifecycle::recrawl_requested() => {}
+                _ = tokio::time::sleep(retry_delay) => {
+                    retry_delay = (retry_delay * 2).min(MAX_STALE_RETRY_DELAY);
+                }
+            }
+        } else {
+            lifecycle::recrawl_requested().await;
+        }
+        println!("recrawling from {}...", source.name());
+        match source.load().await {
+            Ok(data) => {
+                if source.cacheable() {
+                    save_cache(&data).await;
+                }
+                thumbnail::generate(&data).await;
+                return data;
+            }
+            Err(err) => eprintln!("couldn't recrawl from {}: {err}", source.name()),
+        }
+    }
+}
+
+/// Making the torrents reads and hashes every media file, so it's done on a
+/// blocking thread.
+async fn build_torrents(data: &SiteData) {
+    let data = data.clone();
+    if let Err(err) = tokio::task::spawn_blocking(move || protocols::tracker::build(&data)).await {
+        eprintln!("couldn't make the torrents: {err}");
+    }
+}
+
+/// Delete the media that neither the main site nor the other sites use. Stale
+/// data might be the demo data, which doesn't have the images that are still
+/// going to be needed.
+async fn remove_unused_media(data: &SiteData, site_registry: &SiteRegistry) {
+    if data.stale {
+        return;
+    }
+    let sites = std::iter::once(data)
+        .chain(site_registry.data())
+        .collect::<Vec<_>>();
+    crawl::remove_unused_media(&sites).await;
+}
+
+/// Try the source, and fall back to the cache and then the demo data if it
+/// doesn't work. The data from a fallback is marked as stale.
+async fn load_site_data(source: &dyn ContentSource) -> SiteData {
+    let recent_cache = sources::Cache::new(Some(DEBUG_CACHE_MAX_AGE));
+    let old_cache = sources::Cache::new(None);
+
+    let mut chain: Vec<&dyn ContentSource> = Vec::new();
+    // a recent cache is used first when debugging so we don't have to crawl
+    // every time
+    if cfg!(debug_assertions) && source.cacheable() {
+        chain.push(&recent_cache);
+    }
+    // anything after this is a fallback
+    let source_index = chain.len();
+    chain.push(source);
+    if source.cacheable() {
+        // an old cache is better than nothing
+        chain.push(&old_cache);
+    }
+    chain.push(&sources::Demo);
+
+    let name = source.name();
+    for (i, source) in chain.into_iter().enumerate() {
+        println!("loading site data from {}...", source.name());
+        match source.load().await {
+            Ok(mut data) => {
+                if i > source_index {
+                    println!("serving stale data until {name} works again");
+                    data.stale = true;
+                }
+                if source.cacheable() {
+                    save_cache(&data).await;
+                }
+                thumbnail::generate(&data).await;
+                return data;
+            }
+            Err(err) => eprintln!("couldn't load from {}: {err}", source.name()),
+        }
+    }
+    unreachable!("the demo data always loads")
+}
+
+async fn save_cache(data: &SiteData) {
+    if let Err(err) = sources::Cache::new(None).save(data).await {
+        eprintln!("failed to write cache: {err}");
+    }
+}
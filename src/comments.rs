@@ -0,0 +1,192 @@
+//! Comments on blog posts. New comments are hidden until they're approved from
+//! the admin endpoint in the HTTP server.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    net::IpAddr,
+    path::Path,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use chrono::{DateTime, Utc};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+
+pub const COMMENTS_PATH: &str = "data/comments/comments.json";
+
+pub const MAX_AUTHOR_LENGTH: usize = 64;
+pub const MAX_BODY_LENGTH: usize = 2000;
+
+/// How many comments someone can submit in [`SUBMISSION_WINDOW`].
+const MAX_SUBMISSIONS: usize = 5;
+const SUBMISSION_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// New comments are refused while this many are waiting to be approved, so
+/// the file can't be filled up faster than they can be moderated.
+const MAX_PENDING: usize = 100;
+
+static COMMENTS: LazyLock<RwLock<Vec<Comment>>> = LazyLock::new(|| {
+    let comments = fs::read_to_string(COMMENTS_PATH)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default();
+    RwLock::new(comments)
+});
+
+/// When each ip submitted its recent comments, oldest first.
+static SUBMISSIONS: LazyLock<Mutex<HashMap<IpAddr, VecDeque<Instant>>>> =
+    LazyLock::new(Default::default);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Comment {
+    pub id: u64,
+    /// The slug of the post that this comment is on.
+    pub post: String,
+    pub author: String,
+    pub body: String,
+    pub timestamp: DateTime<Utc>,
+    /// Whether the comment was approved by a moderator. Unapproved comments
+    /// aren't shown to anyone.
+    #[serde(default)]
+    pub approved: bool,
+    /// Where the comment is on the Fediverse, if it was a reply from there.
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+fn save(comments: &[Comment]) {
+    let write = || -> anyhow::Result<()> {
+        fs::create_dir_all(Path::new(COMMENTS_PATH).parent().unwrap())?;
+        fs::write(COMMENTS_PATH, serde_json::to_string_pretty(comments)?)?;
+        Ok(())
+    };
+    if let Err(err) = write() {
+        eprintln!("failed to save comments: {err}");
+    }
+}
+
+/// Add a new comment to a post. It won't be shown until it's approved.
+///
+/// The caller is expected to check that the post exists.
+pub fn submit(post: &str, author: &str, body: &str, ip: IpAddr) -> anyhow::Result<Comment> {
+    let now = Instant::now();
+    let mut submissions = SUBMISSIONS.lock();
+    // forget about the ips that haven't commented recently
+    submissions.retain(|_, times| {
+        while times
+            .front()
+            .is_some_and(|&time| now.duration_since(time) > SUBMISSION_WINDOW)
+        {
+            times.pop_front();
+        }
+        !times.is_empty()
+    });
+    let times = submissions.entry(ip.to_canonical()).or_default();
+    if times.len() >= MAX_SUBMISSIONS {
+        bail!("Too many comments, wait a while");
+    }
+    let comment = add(post, author, body, None)?;
+    times.push_back(now);
+    Ok(comment)
+}
+
+/// Add a reply from the Fediverse as a comment, which also has to be
+/// approved. Replies that were already added are ignored, since they can be
+/// delivered more than once.
+pub fn submit_reply(post: &str, author: &str, body: &str, url: &str) -> anyhow::Result<()> {
+    if COMMENTS
+        .read()
+        .iter()
+        .any(|c| c.url.as_deref() == Some(url))
+    {
+        return Ok(());
+    }
+    add(post, author, body, Some(url.to_owned()))?;
+    Ok(())
+}
+
+fn add(post: &str, author: &str, body: &str, url: Option<String>) -> anyhow::Result<Comment> {
+    // the name is put on a line of its own in gemtext and gopher menus, where
+    // a line break or a tab would start a new line or item
+    let author = author
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect::<String>();
+    let author = author.trim();
+    let body = body
+        .chars()
+        .filter(|&c| c == '\n' || !c.is_control())
+        .collect::<String>();
+    let body = body.trim();
+    if body.is_empty() {
+        bail!("Comment is empty");
+    }
+    if body.len() > MAX_BODY_LENGTH {
+        bail!("Comment is longer than {MAX_BODY_LENGTH} bytes");
+    }
+    if author.len() > MAX_AUTHOR_LENGTH {
+        bail!("Name is longer than {MAX_AUTHOR_LENGTH} bytes");
+    }
+
+    let mut comments = COMMENTS.write();
+    if comments.iter().filter(|c| !c.approved).count() >= MAX_PENDING {
+        bail!("Too many comments are waiting to be approved, try again later");
+    }
+    let comment = Comment {
+        id: comments.iter().map(|c| c.id + 1).max().unwrap_or_default(),
+        post: post.to_owned(),
+        author: if author.is_empty() {
+            "Anonymous".to_owned()
+        } else {
+            author.to_owned()
+        },
+        body: body.to_owned(),
+        timestamp: Utc::now(),
+        approved: false,
+        url,
+    };
+    println!("new comment on {post}: {comment:?}");
+    comments.push(comment.clone());
+    save(&comments);
+    Ok(comment)
+}
+
+/// The approved comments on a post, oldest first.
+pub fn approved(post: &str) -> Vec<Comment> {
+    COMMENTS
+        .read()
+        .iter()
+        .filter(|c| c.approved && c.post == post)
+        .cloned()
+        .collect()
+}
+
+/// Every comment, including the ones that haven't been approved yet.
+pub fn all() -> Vec<Comment> {
+    COMMENTS.read().clone()
+}
+
+/// Make a comment visible. Returns false if there's no comment with that id.
+pub fn approve(id: u64) -> bool {
+    let mut comments = COMMENTS.write();
+    let Some(comment) = comments.iter_mut().find(|c| c.id == id) else {
+        return false;
+    };
+    comment.approved = true;
+    save(&comments);
+    true
+}
+
+/// Returns false if there's no comment with that id.
+pub fn delete(id: u64) -> bool {
+    let mut comments = COMMENTS.write();
+    let len = comments.len();
+    comments.retain(|c| c.id != id);
+    if comments.len() == len {
+        return false;
+    }
+    save(&comments);
+    true
+}
@@ -1,24 +1,65 @@
 //! Obtain the project list and blog posts
 
 use std::{
+    cell::RefCell,
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Display,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use async_recursion::async_recursion;
-use chrono::{DateTime, Utc};
-use reqwest::Url;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::{stream, StreamExt};
+use reqwest::{
+    header::{HeaderName, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    RequestBuilder, Response, StatusCode, Url,
+};
 use serde::{Deserialize, Serialize};
 use tl::{HTMLTag, Node, NodeHandle};
 use tokio::fs;
 
+use crate::{drafts, markdown, media};
+
 const CRAWL_SCHEME: &str = "https";
 const CRAWL_HOSTNAME: &str = "matdoes.dev";
 
+const MANIFEST_PATH: &str = "data/crawl/manifest.json";
+
+/// How many posts are crawled at the same time by default.
+pub const DEFAULT_CONCURRENCY: usize = 8;
+/// How many times a request is tried before giving up.
+const MAX_ATTEMPTS: u32 = 4;
+/// How long to wait before retrying a request the first time. This doubles
+/// every attempt.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct SiteData {
     pub projects: Vec<Project>,
     pub blog: Vec<Post>,
+    /// Whether this is an old copy because the site couldn't be crawled. The
+    /// landing pages say so until a crawl works.
+    #[serde(skip)]
+    pub stale: bool,
+}
+
+/// Shown on the landing pages when the site data is stale.
+pub const STALE_NOTICE: &str =
+    "The content here might be out of date, since matdoes.dev couldn't be reached.";
+
+impl SiteData {
+    /// Every tag that's used by a published post, and the posts that have it.
+    pub fn tags(&self) -> BTreeMap<&str, Vec<&Post>> {
+        let mut tags = BTreeMap::<&str, Vec<&Post>>::new();
+        for post in drafts::published(&self.blog) {
+            for tag in &post.tags {
+                tags.entry(tag).or_default().push(post);
+            }
+        }
+        tags
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -31,7 +72,7 @@ pub struct Project {
     pub description: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum LanguageName {
     Python,
@@ -40,6 +81,15 @@ pub enum LanguageName {
     TypeScript,
     JavaScript,
 }
+impl LanguageName {
+    pub const ALL: [LanguageName; 5] = [
+        LanguageName::Python,
+        LanguageName::Svelte,
+        LanguageName::Rust,
+        LanguageName::TypeScript,
+        LanguageName::JavaScript,
+    ];
+}
 impl Display for LanguageName {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -58,6 +108,92 @@ pub struct Post {
     pub slug: String,
     pub published: DateTime<Utc>,
     pub content: Vec<PostPart>,
+    /// Drafts aren't listed, see [`crate::drafts`].
+    #[serde(default)]
+    pub draft: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// See [`count_words`]. Posts from caches made before this was added
+    /// have it recounted when they're loaded.
+    #[serde(default)]
+    pub word_count: usize,
+}
+
+/// How fast people read, for estimating reading time.
+const WORDS_PER_MINUTE: usize = 200;
+
+impl Post {
+    /// Roughly how long the post takes to read, rounded up to at least a
+    /// minute.
+    pub fn reading_minutes(&self) -> usize {
+        self.word_count.div_ceil(WORDS_PER_MINUTE).max(1)
+    }
+
+    /// Count the words if they haven't been counted yet.
+    pub fn fill_word_count(&mut self) {
+        if self.word_count == 0 {
+            self.word_count = count_words(&self.content);
+        }
+    }
+}
+
+/// The number of words in the prose of a post. Code blocks and images
+/// aren't counted, since they aren't read the same way.
+pub fn count_words(parts: &[PostPart]) -> usize {
+    fn words(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    parts
+        .iter()
+        .map(|part| match part {
+            PostPart::Text(text)
+            | PostPart::InlineCode(text)
+            | PostPart::Italic(text)
+            | PostPart::Bold(text)
+            | PostPart::Link { text, .. }
+            | PostPart::Heading { text, .. }
+            | PostPart::Quote(text)
+            | PostPart::Caption(text)
+            | PostPart::Footnote { text, .. } => words(text),
+            PostPart::Table(rows) => rows.iter().flatten().map(|cell| words(cell)).sum(),
+            PostPart::List { items, .. } => items
+                .iter()
+                .map(|item| words(&item.text) + count_words(&item.children))
+                .sum(),
+            PostPart::DefinitionList(definitions) => definitions
+                .iter()
+                .map(|(term, definition)| words(term) + words(definition))
+                .sum(),
+            PostPart::CodeBlock(_)
+            | PostPart::Image { .. }
+            | PostPart::LineBreak
+            | PostPart::FootnoteReference(_)
+            | PostPart::HorizontalRule => 0,
+        })
+        .sum()
+}
+
+/// The orders that listing pages can show posts in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PostSort {
+    /// Newest first, which is the order they're crawled in.
+    #[default]
+    Date,
+    /// Longest first.
+    Length,
+}
+
+impl PostSort {
+    pub fn sort<'a>(self, posts: impl IntoIterator<Item = &'a Post>) -> Vec<&'a Post> {
+        let mut posts = posts.into_iter().collect::<Vec<_>>();
+        match self {
+            PostSort::Date => posts.sort_by_key(|post| Reverse(post.published)),
+            // stable, so posts of the same length stay newest first
+            PostSort::Length => posts.sort_by_key(|post| Reverse(post.word_count)),
+        }
+        posts
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
@@ -81,6 +217,67 @@ pub enum PostPart {
         text: String,
     },
     Quote(String),
+    /// The rows of a table, where each row is a list of cells.
+    Table(Vec<Vec<String>>),
+    List {
+        ordered: bool,
+        items: Vec<ListItem>,
+    },
+    /// A link to a footnote, like the `1` in `<sup><a href="#fn1">1</a></sup>`.
+    FootnoteReference(String),
+    /// The text of a footnote. The id is the label that its references use,
+    /// and the footnotes are shown together at the end of the post.
+    Footnote {
+        id: String,
+        text: String,
+    },
+    HorizontalRule,
+    /// Terms and their definitions.
+    DefinitionList(Vec<(String, String)>),
+    /// The caption of a figure, which comes right after its image.
+    Caption(String),
+}
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ListItem {
+    pub text: String,
+    /// The lists that are nested inside of this item. These are always
+    /// `PostPart::List`.
+    pub children: Vec<PostPart>,
+}
+
+/// A line of a list with the nesting flattened, so the renderers don't have
+/// to deal with it themselves.
+pub struct ListLine<'a> {
+    pub depth: usize,
+    /// The number of the item if it's in an ordered list.
+    pub number: Option<usize>,
+    pub text: &'a str,
+}
+
+pub fn list_lines(ordered: bool, items: &[ListItem]) -> Vec<ListLine<'_>> {
+    fn add_lines<'a>(
+        ordered: bool,
+        items: &'a [ListItem],
+        depth: usize,
+        lines: &mut Vec<ListLine<'a>>,
+    ) {
+        for (i, item) in items.iter().enumerate() {
+            lines.push(ListLine {
+                depth,
+                number: if ordered { Some(i + 1) } else { None },
+                text: &item.text,
+            });
+            for child in &item.children {
+                if let PostPart::List { ordered, items } = child {
+                    add_lines(*ordered, items, depth + 1, lines);
+                }
+            }
+        }
+    }
+
+    let mut lines = Vec::new();
+    add_lines(ordered, items, 0, &mut lines);
+    lines
 }
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub enum ImageSource {
@@ -88,11 +285,24 @@ pub enum ImageSource {
     Remote(String),
 }
 
-pub async fn crawl() -> Result<SiteData, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
+/// `concurrency` is how many posts can be crawled at the same time, and every
+/// request goes through the `proxy` if there is one.
+pub async fn crawl(
+    concurrency: usize,
+    proxy: Option<&str>,
+) -> Result<SiteData, Box<dyn std::error::Error>> {
+    let mut client = reqwest::Client::builder();
+    if let Some(proxy) = proxy {
+        client = client.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    let client = client.build()?;
     let projects = crawl_projects(&client).await?;
-    let blog = crawl_blog(&client).await?;
-    Ok(SiteData { projects, blog })
+    let blog = crawl_blog(&client, concurrency).await?;
+    Ok(SiteData {
+        projects,
+        blog,
+        stale: false,
+    })
 }
 
 async fn crawl_projects(
@@ -100,248 +310,802 @@ async fn crawl_projects(
 ) -> Result<Vec<Project>, Box<dyn std::error::Error>> {
     println!("Crawling projects...");
     let url = format!("{CRAWL_SCHEME}://{CRAWL_HOSTNAME}/projects.json");
-    let response = client.get(url).send().await?;
+    let response = send_with_retry(client.get(url)).await?;
     let projects: Vec<Project> = response.json().await?;
     println!("Crawled {} projects", projects.len());
     Ok(projects)
 }
 
-async fn get_image(client: &reqwest::Client, image_url: &Url) -> PathBuf {
+/// What we got from the last crawl, so posts and images that didn't change
+/// don't have to be downloaded again.
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    /// By slug.
+    posts: HashMap<String, CachedPost>,
+    /// By URL.
+    images: HashMap<String, CachedImage>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedPost {
+    validators: Validators,
+    post: Post,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedImage {
+    validators: Validators,
+    path: PathBuf,
+}
+
+/// The headers that let us ask the server to only send something if it
+/// changed.
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Manifest {
+    async fn load() -> Self {
+        fs::read_to_string(MANIFEST_PATH)
+            .await
+            .ok()
+            .and_then(|manifest| serde_json::from_str(&manifest).ok())
+            .unwrap_or_default()
+    }
+
+    async fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        fs::create_dir_all(Path::new(MANIFEST_PATH).parent().unwrap()).await?;
+        fs::write(MANIFEST_PATH, serde_json::to_string(self)?).await?;
+        Ok(())
+    }
+}
+
+impl Validators {
+    fn from_response(response: &Response) -> Self {
+        let header = |name: HeaderName| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+        Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+
+    fn add_to(&self, mut request: RequestBuilder) -> RequestBuilder {
+        if let Some(etag) = &self.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        request
+    }
+}
+
+/// Download an image into the media directory, named after its hash. `None`
+/// if it couldn't be downloaded completely.
+async fn get_image(
+    client: &reqwest::Client,
+    manifest: &RefCell<Manifest>,
+    image_url: &Url,
+) -> Option<PathBuf> {
+    let cached = manifest
+        .borrow()
+        .images
+        .get(image_url.as_str())
+        .map(|image| (image.validators.clone(), image.path.clone()));
+
+    // only ask for it conditionally if we still have the old one
+    let mut request = client.get(image_url.clone());
+    let mut unchanged_path = None;
+    if let Some((validators, path)) = cached {
+        if media::is_hashed(&path) && fs::try_exists(&path).await.unwrap_or_default() {
+            request = validators.add_to(request);
+            unchanged_path = Some(path);
+        }
+    }
+
     // download the image
-    let response = client.get(image_url.clone()).send().await.unwrap();
-    let bytes = response.bytes().await.unwrap();
-    let directory = Path::new("media").join(image_url.path().trim_start_matches('/'));
+    let response = match send_with_retry(request).await {
+        Ok(response) => response,
+        Err(err) => {
+            println!("couldn't download {image_url}: {err}");
+            return None;
+        }
+    };
+    if response.status() == StatusCode::NOT_MODIFIED {
+        if let Some(path) = unchanged_path {
+            println!("{path:?} is unchanged");
+            return Some(path);
+        }
+    }
+    if !response.status().is_success() {
+        println!("couldn't download {image_url}: {}", response.status());
+        return None;
+    }
+    let validators = Validators::from_response(&response);
+    let expected_len = response.content_length();
+    let bytes = match response.bytes().await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            println!("couldn't download {image_url}: {err}");
+            return None;
+        }
+    };
+    if expected_len.is_some_and(|len| len != bytes.len() as u64) {
+        println!("{image_url} was cut off, so it won't be saved");
+        return None;
+    }
+
+    let extension = Path::new(image_url.path())
+        .extension()
+        .and_then(|e| e.to_str());
+    let path = media::hashed_path(&bytes, extension);
+    if !fs::try_exists(&path).await.unwrap_or_default() {
+        println!("Saving image to {path:?}");
+        // written somewhere else first so there's never half of a file at the
+        // real path
+        let partial = path.with_extension("partial");
+        fs::create_dir_all(path.parent().unwrap()).await.ok()?;
+        fs::write(&partial, &bytes).await.ok()?;
+        fs::rename(&partial, &path).await.ok()?;
+    }
+
+    manifest.borrow_mut().images.insert(
+        image_url.to_string(),
+        CachedImage {
+            validators,
+            path: path.clone(),
+        },
+    );
 
-    println!("Saving image to {:#?}", directory);
+    Some(path)
+}
+
+/// The files in the media directory that the posts use.
+fn used_media(posts: &[Post]) -> HashSet<PathBuf> {
+    posts
+        .iter()
+        .flat_map(|post| &post.content)
+        .filter_map(|part| match part {
+            PostPart::Image {
+                src: ImageSource::Local(path),
+                ..
+            } => Some(path.clone()),
+            _ => None,
+        })
+        .collect()
+}
 
-    let parent_directory = directory.parent().unwrap();
+/// Delete the files in the media directory that aren't used by any post
+/// anymore. Every site keeps its images in the same directory, so this has to
+/// be given all of them.
+pub async fn remove_unused_media(sites: &[&SiteData]) {
+    let used = sites
+        .iter()
+        .flat_map(|data| used_media(&data.blog))
+        .collect();
+    if let Err(err) = remove_unused_files(Path::new("media"), &used).await {
+        eprintln!("couldn't remove the unused media: {err}");
+    }
+}
 
-    // make the media directory if it doesn't exist
-    fs::create_dir_all(parent_directory).await.unwrap();
-    fs::write(directory.clone(), bytes).await.unwrap();
+#[async_recursion]
+async fn remove_unused_files(directory: &Path, used: &HashSet<PathBuf>) -> std::io::Result<()> {
+    let mut entries = fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if entry.file_type().await?.is_dir() {
+            remove_unused_files(&path, used).await?;
+        } else if !used.contains(&path) {
+            println!("Removing {path:?}");
+            fs::remove_file(&path).await?;
+        }
+    }
+    Ok(())
+}
 
-    directory
+/// Send the request, and try again with exponential backoff if it fails in a
+/// way that might be temporary.
+async fn send_with_retry(request: RequestBuilder) -> reqwest::Result<Response> {
+    let mut delay = RETRY_DELAY;
+    for _ in 1..MAX_ATTEMPTS {
+        // none of our requests have a body, so they can always be cloned
+        let result = request.try_clone().unwrap().send().await;
+        let reason = match &result {
+            Ok(response)
+                if response.status().is_server_error()
+                    || response.status() == StatusCode::TOO_MANY_REQUESTS =>
+            {
+                Some(format!("{} returned {}", response.url(), response.status()))
+            }
+            Err(err) if err.is_timeout() || err.is_connect() => Some(err.to_string()),
+            _ => None,
+        };
+        let Some(reason) = reason else {
+            return result;
+        };
+        println!("{reason}, retrying in {delay:?}");
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+    request.send().await
 }
 
-async fn crawl_blog(client: &reqwest::Client) -> Result<Vec<Post>, Box<dyn std::error::Error>> {
+async fn crawl_blog(
+    client: &reqwest::Client,
+    concurrency: usize,
+) -> Result<Vec<Post>, Box<dyn std::error::Error>> {
     println!("Crawling blog...");
     let url = format!("{CRAWL_SCHEME}://{CRAWL_HOSTNAME}/blog.json");
-    let response = client.get(url).send().await?;
+    let response = send_with_retry(client.get(url)).await?;
     let posts_json: serde_json::Value = response.json().await?;
 
-    let mut posts: Vec<Post> = Vec::new();
+    let manifest = RefCell::new(Manifest::load().await);
+
+    let slugs = posts_json
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|post_json| post_json["slug"].as_str().unwrap());
+    // buffered instead of buffer_unordered so the posts stay in order
+    let posts = stream::iter(slugs)
+        .map(|slug| crawl_post(client, &manifest, slug))
+        .buffered(concurrency)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
 
-    // clear the media directory
-    let _ = fs::remove_dir_all("media").await;
+    // forget about everything that isn't on the site anymore
+    let used_media = used_media(&posts);
+    let mut manifest = manifest.into_inner();
+    manifest
+        .posts
+        .retain(|slug, _| posts.iter().any(|post| &post.slug == slug));
+    manifest
+        .images
+        .retain(|_, image| used_media.contains(&image.path));
+    manifest.save().await?;
 
-    for post_json in posts_json.as_array().unwrap() {
-        let slug = post_json["slug"].as_str().unwrap();
-        println!("Crawling {slug}...");
-        let url = format!("{CRAWL_SCHEME}://{CRAWL_HOSTNAME}/{slug}.json");
-        let response = client.get(&url).send().await?;
-        let post_json: serde_json::Value = response.json().await?;
+    Ok(posts)
+}
 
-        fn html_escape(text: String) -> String {
-            html_escape::decode_html_entities(&text).to_string()
+async fn crawl_post(
+    client: &reqwest::Client,
+    manifest: &RefCell<Manifest>,
+    slug: &str,
+) -> Result<Post, Box<dyn std::error::Error>> {
+    println!("Crawling {slug}...");
+    let url = format!("{CRAWL_SCHEME}://{CRAWL_HOSTNAME}/{slug}.json");
+    let mut request = client.get(&url);
+    if let Some(cached) = manifest.borrow().posts.get(slug) {
+        request = cached.validators.add_to(request);
+    }
+    let response = send_with_retry(request).await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        if let Some(cached) = manifest.borrow().posts.get(slug) {
+            println!("{slug} is unchanged");
+            let mut post = cached.post.clone();
+            post.fill_word_count();
+            return Ok(post);
         }
+    }
+    let validators = Validators::from_response(&response);
+    let post_json: serde_json::Value = response.json().await?;
+
+    fn html_escape(text: String) -> String {
+        html_escape::decode_html_entities(&text).to_string()
+    }
+
+    fn html_tag_to_string(parser: &tl::Parser, element: &HTMLTag) -> String {
+        html_escape(
+            element
+                .children()
+                .all(parser)
+                .iter()
+                .map(|node| match node {
+                    Node::Raw(text) => text.as_utf8_str().to_string(),
+                    Node::Tag(element) => element
+                        .children()
+                        .all(parser)
+                        .iter()
+                        .map(|node| match node {
+                            Node::Raw(text) => text.as_utf8_str().to_string(),
+                            _ => "".to_string(),
+                        })
+                        .collect::<Vec<String>>()
+                        .join(""),
+                    _ => "".to_string(),
+                })
+                .collect::<Vec<String>>()
+                .join(""),
+        )
+    }
 
-        fn html_tag_to_string(parser: &tl::Parser, element: &HTMLTag) -> String {
-            html_escape(
-                element
-                    .children()
-                    .all(parser)
-                    .iter()
-                    .map(|node| match node {
-                        Node::Raw(text) => text.as_utf8_str().to_string(),
-                        Node::Tag(element) => element
-                            .children()
-                            .all(parser)
-                            .iter()
-                            .map(|node| match node {
-                                Node::Raw(text) => text.as_utf8_str().to_string(),
-                                _ => "".to_string(),
-                            })
-                            .collect::<Vec<String>>()
-                            .join(""),
-                        _ => "".to_string(),
-                    })
-                    .collect::<Vec<String>>()
-                    .join(""),
-            )
+    /// Get all the text in the element, except for nested lists.
+    fn inline_text(parser: &tl::Parser, element: &HTMLTag) -> String {
+        let mut text = String::new();
+        for child in element.children().top().iter() {
+            match child.get(parser) {
+                Some(Node::Raw(raw)) => text.push_str(&raw.as_utf8_str()),
+                Some(Node::Tag(tag))
+                    if !matches!(tag.name().as_utf8_str().as_ref(), "ul" | "ol") =>
+                {
+                    text.push_str(&inline_text(parser, tag));
+                }
+                _ => {}
+            }
         }
+        text
+    }
 
-        #[async_recursion(?Send)]
-        async fn parse_node(
-            client: &reqwest::Client,
-            parser: &tl::Parser,
-            node: &NodeHandle,
-            content: &mut Vec<PostPart>,
-            slug: &str,
-        ) {
-            match node.get(parser).unwrap() {
-                Node::Raw(text) => {
-                    let text = html_escape(text.as_utf8_str().trim_end_matches('\n').to_string());
-                    if !text.is_empty() {
-                        content.push(PostPart::Text(text));
+    fn parse_list(parser: &tl::Parser, element: &HTMLTag) -> PostPart {
+        let ordered = element.name().as_utf8_str() == "ol";
+        let mut items = Vec::new();
+        for child in element.children().top().iter() {
+            let Some(Node::Tag(item)) = child.get(parser) else {
+                continue;
+            };
+            if item.name().as_utf8_str() != "li" {
+                continue;
+            }
+            let mut children = Vec::new();
+            for nested in item.children().top().iter() {
+                match nested.get(parser) {
+                    Some(Node::Tag(nested))
+                        if matches!(nested.name().as_utf8_str().as_ref(), "ul" | "ol") =>
+                    {
+                        children.push(parse_list(parser, nested));
                     }
+                    _ => {}
                 }
-                Node::Tag(element) => {
-                    let element_name = element.name().as_utf8_str().to_string();
+            }
+            let text = html_escape(inline_text(parser, item));
+            items.push(ListItem {
+                // collapse the whitespace from the html
+                text: text.split_whitespace().collect::<Vec<_>>().join(" "),
+                children,
+            });
+        }
+        PostPart::List { ordered, items }
+    }
 
-                    if matches!(element_name.as_str(), "p" | "pre" | "h1" | "h2" | "h3")
-                        && !content.is_empty()
-                    {
-                        // sometimes there's random raw spaces in the html that aren't meant to be
-                        // displayed
-                        if content.last().unwrap() == &PostPart::Text(" ".to_owned()) {
-                            content.pop();
-                        }
+    /// The notes in a footnotes section, which is an ordered list where each
+    /// item ends with a link back to where it was referenced. They're
+    /// numbered in order, like their references.
+    fn parse_footnotes(parser: &tl::Parser, element: &HTMLTag) -> Vec<PostPart> {
+        let mut footnotes = Vec::new();
+        for list in element.query_selector(parser, "ol").into_iter().flatten() {
+            let Some(Node::Tag(list)) = list.get(parser) else {
+                continue;
+            };
+            for item in list.children().top().iter() {
+                let Some(Node::Tag(item)) = item.get(parser) else {
+                    continue;
+                };
+                if item.name().as_utf8_str() != "li" {
+                    continue;
+                }
+                let text = html_escape(inline_text(parser, item));
+                // collapse the whitespace from the html
+                let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                footnotes.push(PostPart::Footnote {
+                    id: (footnotes.len() + 1).to_string(),
+                    // without the back link, which is an arrow
+                    text: text.trim_end_matches(['↩', '\u{fe0e}', ' ']).to_owned(),
+                });
+            }
+        }
+        footnotes
+    }
+
+    #[async_recursion(?Send)]
+    async fn parse_node(
+        client: &reqwest::Client,
+        manifest: &RefCell<Manifest>,
+        parser: &tl::Parser,
+        node: &NodeHandle,
+        content: &mut Vec<PostPart>,
+        slug: &str,
+    ) {
+        match node.get(parser).unwrap() {
+            Node::Raw(text) => {
+                let text = html_escape(text.as_utf8_str().trim_end_matches('\n').to_string());
+                if !text.is_empty() {
+                    content.push(PostPart::Text(text));
+                }
+            }
+            Node::Tag(element) => {
+                let element_name = element.name().as_utf8_str().to_string();
+
+                if matches!(element_name.as_str(), "p" | "pre" | "h1" | "h2" | "h3")
+                    && !content.is_empty()
+                {
+                    // sometimes there's random raw spaces in the html that aren't meant to be
+                    // displayed
+                    if content.last().unwrap() == &PostPart::Text(" ".to_owned()) {
+                        content.pop();
                     }
+                }
 
-                    match element_name.as_str() {
-                        "img" => {
-                            let src = element
-                                .attributes()
-                                .get("src")
-                                .unwrap()
-                                .as_ref()
-                                .expect("all images must have a src")
-                                .as_utf8_str()
-                                .to_string();
-
-                            // combine the base url with the src
-                            let image_url =
-                                Url::parse(&format!("{CRAWL_SCHEME}://{CRAWL_HOSTNAME}/{slug}"))
-                                    .unwrap()
-                                    .join(&src)
-                                    .unwrap();
-
-                            if image_url.host_str().unwrap() != CRAWL_HOSTNAME {
-                                content.push(PostPart::Image {
-                                    src: ImageSource::Remote(src.to_string()),
-                                    alt: element
-                                        .attributes()
-                                        .get("alt")
-                                        .unwrap()
-                                        .map(|alt| alt.as_utf8_str().to_string()),
-                                });
-                                return;
-                            }
+                match element_name.as_str() {
+                    "img" => {
+                        let src = element
+                            .attributes()
+                            .get("src")
+                            .unwrap()
+                            .as_ref()
+                            .expect("all images must have a src")
+                            .as_utf8_str()
+                            .to_string();
 
-                            let file_path = get_image(client, &image_url).await;
+                        // combine the base url with the src
+                        let image_url =
+                            Url::parse(&format!("{CRAWL_SCHEME}://{CRAWL_HOSTNAME}/{slug}"))
+                                .unwrap()
+                                .join(&src)
+                                .unwrap();
 
+                        if image_url.host_str().unwrap() != CRAWL_HOSTNAME {
                             content.push(PostPart::Image {
-                                src: ImageSource::Local(file_path.to_path_buf()),
+                                src: ImageSource::Remote(src.to_string()),
                                 alt: element
                                     .attributes()
                                     .get("alt")
                                     .unwrap()
                                     .map(|alt| alt.as_utf8_str().to_string()),
                             });
+                            return;
                         }
-                        "a" => {
-                            let href = element
+
+                        // images that couldn't be downloaded are linked to
+                        // instead, so a broken one is never served
+                        let src = match get_image(client, manifest, &image_url).await {
+                            Some(path) => ImageSource::Local(path),
+                            None => ImageSource::Remote(image_url.to_string()),
+                        };
+                        content.push(PostPart::Image {
+                            src,
+                            alt: element
                                 .attributes()
-                                .get("href")
+                                .get("alt")
                                 .unwrap()
-                                .as_ref()
-                                .expect("all links must have a href")
-                                .as_utf8_str()
-                                .to_string();
-
-                            content.push(PostPart::Link {
-                                href: href.to_string(),
-                                text: html_tag_to_string(parser, element),
-                            });
-                        }
-                        "br" => {
-                            content.push(PostPart::LineBreak);
+                                .map(|alt| alt.as_utf8_str().to_string()),
+                        });
+                    }
+                    "a" => {
+                        let href = element
+                            .attributes()
+                            .get("href")
+                            .unwrap()
+                            .as_ref()
+                            .expect("all links must have a href")
+                            .as_utf8_str()
+                            .to_string();
+
+                        content.push(PostPart::Link {
+                            href: href.to_string(),
+                            text: html_tag_to_string(parser, element),
+                        });
+                    }
+                    "br" => {
+                        content.push(PostPart::LineBreak);
+                    }
+                    "hr" => {
+                        content.push(PostPart::HorizontalRule);
+                    }
+                    "sup" => {
+                        let text = html_tag_to_string(parser, element).trim().to_owned();
+                        // footnote references are superscript links, anything else is
+                        // just text
+                        if element
+                            .query_selector(parser, "a")
+                            .is_some_and(|mut a| a.next().is_some())
+                        {
+                            content.push(PostPart::FootnoteReference(text));
+                        } else {
+                            content.push(PostPart::Text(text));
                         }
-                        "p" | "button" => {
-                            if !content.is_empty() {
-                                // sometimes there's random raw spaces in the html that aren't meant
-                                // to be displayed
-                                if content.last().unwrap() == &PostPart::Text(" ".to_owned()) {
-                                    content.pop();
+                    }
+                    "figcaption" => {
+                        content.push(PostPart::Caption(
+                            html_tag_to_string(parser, element).trim().to_owned(),
+                        ));
+                    }
+                    "dl" => {
+                        let mut definitions: Vec<(String, String)> = Vec::new();
+                        for child in element.children().top().iter() {
+                            let Some(Node::Tag(child)) = child.get(parser) else {
+                                continue;
+                            };
+                            let text = html_escape(inline_text(parser, child));
+                            // collapse the whitespace from the html
+                            let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                            match child.name().as_utf8_str().as_ref() {
+                                "dt" => definitions.push((text, String::new())),
+                                "dd" => {
+                                    // a term can have multiple definitions
+                                    if let Some((_, definition)) = definitions.last_mut() {
+                                        if !definition.is_empty() {
+                                            definition.push('\n');
+                                        }
+                                        definition.push_str(&text);
+                                    }
                                 }
+                                _ => {}
                             }
-                            for child in element.children().top().iter() {
-                                parse_node(client, parser, child, content, slug).await;
-                            }
-                            content.push(PostPart::LineBreak);
-                        }
-                        "code" => {
-                            content.push(PostPart::InlineCode(html_tag_to_string(parser, element)));
                         }
-                        "pre" => {
-                            content.push(PostPart::CodeBlock(html_tag_to_string(parser, element)));
-                        }
-                        "blockquote" => {
-                            content.push(PostPart::Quote(html_tag_to_string(parser, element)));
-                        }
-                        "em" | "i" => {
-                            content.push(PostPart::Italic(html_tag_to_string(parser, element)));
-                        }
-                        "strong" | "b" => {
-                            content.push(PostPart::Bold(html_tag_to_string(parser, element)));
-                        }
-                        "h1" => {
-                            content.push(PostPart::Heading {
-                                level: 1,
-                                text: html_tag_to_string(parser, element),
-                            });
+                        content.push(PostPart::DefinitionList(definitions));
+                    }
+                    "p" | "button" => {
+                        if !content.is_empty() {
+                            // sometimes there's random raw spaces in the html that aren't meant
+                            // to be displayed
+                            if content.last().unwrap() == &PostPart::Text(" ".to_owned()) {
+                                content.pop();
+                            }
                         }
-                        "h2" => {
-                            content.push(PostPart::Heading {
-                                level: 2,
-                                text: html_tag_to_string(parser, element),
-                            });
+                        for child in element.children().top().iter() {
+                            parse_node(client, manifest, parser, child, content, slug).await;
                         }
-                        "h3" => {
-                            content.push(PostPart::Heading {
-                                level: 3,
-                                text: html_tag_to_string(parser, element),
-                            });
+                        content.push(PostPart::LineBreak);
+                    }
+                    "code" => {
+                        content.push(PostPart::InlineCode(html_tag_to_string(parser, element)));
+                    }
+                    "pre" => {
+                        content.push(PostPart::CodeBlock(html_tag_to_string(parser, element)));
+                    }
+                    "blockquote" => {
+                        content.push(PostPart::Quote(html_tag_to_string(parser, element)));
+                    }
+                    "em" | "i" => {
+                        content.push(PostPart::Italic(html_tag_to_string(parser, element)));
+                    }
+                    "strong" | "b" => {
+                        content.push(PostPart::Bold(html_tag_to_string(parser, element)));
+                    }
+                    "h1" => {
+                        content.push(PostPart::Heading {
+                            level: 1,
+                            text: html_tag_to_string(parser, element),
+                        });
+                    }
+                    "h2" => {
+                        content.push(PostPart::Heading {
+                            level: 2,
+                            text: html_tag_to_string(parser, element),
+                        });
+                    }
+                    "h3" => {
+                        content.push(PostPart::Heading {
+                            level: 3,
+                            text: html_tag_to_string(parser, element),
+                        });
+                    }
+                    "table" => {
+                        let mut rows = Vec::new();
+                        for row in element.query_selector(parser, "tr").into_iter().flatten() {
+                            let Some(Node::Tag(row)) = row.get(parser) else {
+                                continue;
+                            };
+                            let cells = row
+                                .children()
+                                .top()
+                                .iter()
+                                .filter_map(|cell| match cell.get(parser) {
+                                    Some(Node::Tag(cell))
+                                        if matches!(
+                                            cell.name().as_utf8_str().as_ref(),
+                                            "td" | "th"
+                                        ) =>
+                                    {
+                                        Some(html_tag_to_string(parser, cell).trim().to_owned())
+                                    }
+                                    _ => None,
+                                })
+                                .collect();
+                            rows.push(cells);
                         }
-                        "li" => {
-                            content.push(PostPart::Text(" • ".to_owned()));
-                            for child in element.children().top().iter() {
-                                parse_node(client, parser, child, content, slug).await;
-                            }
-                            content.push(PostPart::LineBreak);
+                        content.push(PostPart::Table(rows));
+                    }
+                    "section" | "div" if element.attributes().is_class_member("footnotes") => {
+                        content.extend(parse_footnotes(parser, element));
+                    }
+                    "ul" | "ol" => {
+                        content.push(parse_list(parser, element));
+                    }
+                    "li" => {
+                        content.push(PostPart::Text(" • ".to_owned()));
+                        for child in element.children().top().iter() {
+                            parse_node(client, manifest, parser, child, content, slug).await;
                         }
-                        _ => {
-                            for child in element.children().top().iter() {
-                                parse_node(client, parser, child, content, slug).await;
-                            }
+                        content.push(PostPart::LineBreak);
+                    }
+                    _ => {
+                        for child in element.children().top().iter() {
+                            parse_node(client, manifest, parser, child, content, slug).await;
                         }
                     }
                 }
-                Node::Comment(_) => {}
             }
+            Node::Comment(_) => {}
         }
+    }
 
-        let dom = tl::parse(
-            post_json["html"].as_str().unwrap(),
-            tl::ParserOptions::default(),
-        )
-        .unwrap();
-        let parser = dom.parser();
-        let mut content = Vec::new();
-        for child in dom.children() {
-            parse_node(client, parser, child, &mut content, slug).await;
+    let dom = tl::parse(
+        post_json["html"].as_str().unwrap(),
+        tl::ParserOptions::default(),
+    )
+    .unwrap();
+    let parser = dom.parser();
+    let mut content = Vec::new();
+    for child in dom.children() {
+        parse_node(client, manifest, parser, child, &mut content, slug).await;
+    }
+
+    let word_count = count_words(&content);
+    let post = Post {
+        title: post_json["title"].as_str().unwrap().to_string(),
+        slug: slug.to_string(),
+        // 2022-09-28T02:17:25.000Z
+        published: DateTime::parse_from_rfc3339(post_json["published"].as_str().unwrap())?.into(),
+        content,
+        draft: post_json["draft"].as_bool().unwrap_or(false),
+        tags: post_json["tags"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|tag| tag.as_str())
+            .map(|tag| tag.to_owned())
+            .collect(),
+        word_count,
+    };
+    manifest.borrow_mut().posts.insert(
+        slug.to_owned(),
+        CachedPost {
+            validators,
+            post: post.clone(),
+        },
+    );
+    Ok(post)
+}
+
+/// Read every `.md` file in the directory as a post. The files start with
+/// front matter like this:
+///
+/// ```md
+/// ---
+/// title: Hello, world!
+/// slug: hello-world
+/// date: 2024-01-01
+/// tags: rust, gemini
+/// ---
+/// ```
+///
+/// The slug defaults to the name of the file, `tags` is a comma-separated
+/// list, and `draft: true` makes it a draft. Projects are read from a
+/// `projects.json` in the same directory if there is one, in the same format
+/// as matdoes.dev's.
+pub async fn read_markdown_dir(dir: &Path) -> Result<SiteData, Box<dyn std::error::Error>> {
+    println!("Reading posts from {dir:?}...");
+
+    let projects = match fs::read_to_string(dir.join("projects.json")).await {
+        Ok(projects) => serde_json::from_str(&projects)?,
+        Err(_) => Vec::new(),
+    };
+
+    let mut blog = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|e| e != "md") {
+            continue;
         }
+        let file = fs::read_to_string(&path).await?;
+        let (front_matter, markdown) = split_front_matter(&file);
 
-        let post = Post {
-            title: post_json["title"].as_str().unwrap().to_string(),
-            slug: slug.to_string(),
-            // 2022-09-28T02:17:25.000Z
-            published: DateTime::parse_from_rfc3339(post_json["published"].as_str().unwrap())?
-                .into(),
-            content,
+        let slug = match front_matter.get("slug") {
+            Some(slug) => slug.to_string(),
+            None => path.file_stem().unwrap().to_string_lossy().into_owned(),
+        };
+        let Some(title) = front_matter.get("title") else {
+            return Err(format!("{path:?} doesn't have a title").into());
+        };
+        let Some(date) = front_matter.get("date") else {
+            return Err(format!("{path:?} doesn't have a date").into());
+        };
+        let published = match DateTime::parse_from_rfc3339(date) {
+            Ok(date) => date.into(),
+            Err(_) => NaiveDate::parse_from_str(date, "%Y-%m-%d")?
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+                .and_utc(),
         };
-        posts.push(post);
+
+        let mut content = markdown::to_post_parts(markdown);
+        // the images have to be in the media directory like the crawled ones
+        for part in &mut content {
+            if let PostPart::Image {
+                src: ImageSource::Local(image_path),
+                ..
+            } = part
+            {
+                // the posts can be someone else's, so they only get to use
+                // the files that are in their directory
+                let relative_path = image_path.strip_prefix(".").unwrap_or(image_path);
+                let Some(source) = relative_path
+                    .to_str()
+                    .and_then(|relative_path| media::resolve_in(dir, relative_path))
+                else {
+                    return Err(format!("{path:?} has an image that isn't in {dir:?}").into());
+                };
+                let bytes = fs::read(source).await?;
+                let extension = image_path.extension().and_then(|e| e.to_str());
+                let media_path = media::hashed_path(&bytes, extension);
+                if !fs::try_exists(&media_path).await? {
+                    fs::create_dir_all(media_path.parent().unwrap()).await?;
+                    fs::write(&media_path, bytes).await?;
+                }
+                *image_path = media_path;
+            }
+        }
+
+        let word_count = count_words(&content);
+        blog.push(Post {
+            title: title.to_string(),
+            slug,
+            published,
+            content,
+            draft: front_matter.get("draft") == Some(&"true"),
+            tags: front_matter
+                .get("tags")
+                .into_iter()
+                .flat_map(|tags| tags.split(','))
+                .map(|tag| tag.trim().to_owned())
+                .filter(|tag| !tag.is_empty())
+                .collect(),
+            word_count,
+        });
     }
+    // newest first, like the blog.json
+    blog.sort_by_key(|post| Reverse(post.published));
+    println!("Read {} posts", blog.len());
 
-    Ok(posts)
+    Ok(SiteData {
+        projects,
+        blog,
+        stale: false,
+    })
+}
+
+/// Split a Markdown file into the `key: value` pairs from its front matter and
+/// the rest of the file.
+fn split_front_matter(file: &str) -> (HashMap<&str, &str>, &str) {
+    let mut front_matter = HashMap::new();
+    let Some(rest) = file.strip_prefix("---\n") else {
+        return (front_matter, file);
+    };
+    let Some((header, markdown)) = rest.split_once("\n---\n") else {
+        return (front_matter, file);
+    };
+    for line in header.lines() {
+        if let Some((key, value)) = line.split_once(':') {
+            let value = value.trim();
+            // quotes are optional
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            front_matter.insert(key.trim(), value);
+        }
+    }
+    (front_matter, markdown)
 }
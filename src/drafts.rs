@@ -0,0 +1,47 @@
+//! Draft posts aren't listed anywhere, but they can be previewed at a path with
+//! a token in it, like `gemini://matdoes.dev/draft/<token>`.
+
+use std::{collections::HashSet, fs, sync::LazyLock};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::crawl::Post;
+
+/// The tokens are derived from this, so changing it makes all of the old
+/// preview links stop working.
+pub const DRAFTS_SECRET_PATH: &str = "data/drafts/secret.txt";
+
+static SECRET: LazyLock<Option<String>> = LazyLock::new(|| {
+    fs::read_to_string(DRAFTS_SECRET_PATH)
+        .ok()
+        .map(|secret| secret.trim().to_owned())
+        .filter(|secret| !secret.is_empty())
+});
+
+/// The token that the draft can be previewed with, or `None` if there's no
+/// secret (in which case drafts can't be previewed at all).
+pub fn preview_token(slug: &str) -> Option<String> {
+    let secret = SECRET.as_ref()?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(slug.as_bytes());
+    let hash = mac.finalize().into_bytes();
+    // 128 bits is plenty
+    Some(hash[..16].iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// The posts that should be listed.
+pub fn published(posts: &[Post]) -> impl Iterator<Item = &Post> {
+    posts.iter().filter(|post| !post.draft)
+}
+
+/// The posts in `new` that weren't published in `old`, like after a recrawl.
+pub fn newly_published(old: &[Post], new: &[Post]) -> Vec<Post> {
+    let old_slugs = published(old)
+        .map(|post| post.slug.as_str())
+        .collect::<HashSet<_>>();
+    published(new)
+        .filter(|post| !old_slugs.contains(post.slug.as_str()))
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,281 @@
+//! Write the generated pages to a directory instead of serving them, so they
+//! can be hosted by other servers.
+
+use std::path::Path;
+
+use async_recursion::async_recursion;
+use html_escape::{encode_double_quoted_attribute as attr, encode_text as escape};
+use tokio::fs;
+
+use crate::{
+    crawl::{ImageSource, ListItem, PostPart, SiteData},
+    drafts,
+    protocols::{finger::Finger, gemini::Gemini, gopher::Gopher, Artifact, Export, Protocol},
+};
+
+/// What the pages can be exported as. Each one goes in its own directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Gemtext, like the Gemini server's.
+    Gmi,
+    /// Gophermaps, for gopher servers that serve a directory.
+    Gophermap,
+    /// Plain text, like finger's.
+    Txt,
+    Html,
+}
+
+impl Format {
+    pub const ALL: [Format; 4] = [Format::Gmi, Format::Gophermap, Format::Txt, Format::Html];
+
+    fn dir_name(self) -> &'static str {
+        match self {
+            Format::Gmi => "gemini",
+            Format::Gophermap => "gopher",
+            Format::Txt => "finger",
+            Format::Html => "html",
+        }
+    }
+
+    fn artifacts(self, data: &SiteData) -> Vec<Artifact> {
+        match self {
+            Format::Gmi => Gemini::generate(data, &Default::default()).artifacts(),
+            Format::Gophermap => Gopher::generate(data, &Default::default()).artifacts(),
+            Format::Txt => Finger::generate(data, &Default::default()).artifacts(),
+            Format::Html => html_artifacts(data),
+        }
+    }
+
+    /// Whether the pages link to images, which have to be copied next to them.
+    fn has_media(self) -> bool {
+        self != Format::Txt
+    }
+}
+
+pub async fn export(data: &SiteData, dir: &Path, formats: &[Format]) -> anyhow::Result<()> {
+    for &format in formats {
+        let name = format.dir_name();
+        let protocol_dir = dir.join(name);
+        for artifact in format.artifacts(data) {
+            let path = protocol_dir.join(&artifact.path);
+            fs::create_dir_all(path.parent().unwrap()).await?;
+            fs::write(&path, artifact.content).await?;
+        }
+        println!("exported {name} to {protocol_dir:?}");
+
+        // the images are linked relative to the media directory, so they go
+        // next to the pages
+        if format.has_media() {
+            copy_dir(Path::new("media"), &protocol_dir).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[async_recursion]
+async fn copy_dir(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let Ok(mut entries) = fs::read_dir(from).await else {
+        // no media, nothing to copy
+        return Ok(());
+    };
+    fs::create_dir_all(to).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let to = to.join(entry.file_name());
+        if entry.file_type().await?.is_dir() {
+            copy_dir(&entry.path(), &to).await?;
+        } else {
+            fs::copy(entry.path(), to).await?;
+        }
+    }
+    Ok(())
+}
+
+fn html_page(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+</head>
+<body>
+{body}
+</body>
+</html>
+"#,
+        title = escape(title)
+    )
+}
+
+/// Plain HTML versions of every page, with the same layout as the other
+/// protocols.
+fn html_artifacts(data: &SiteData) -> Vec<Artifact> {
+    let mut artifacts = Vec::new();
+
+    artifacts.push(Artifact::new(
+        "index.html",
+        html_page(
+            "matdoesdev",
+            r#"<h1>matdoesdev</h1>
+<p>I'm mat, I do full-stack software development.<br>
+This portfolio contains my blog posts and links to some of the projects I've made.</p>
+<p><a href="/blog/">Blog</a> <a href="/projects/">Projects</a></p>
+<p><a href="https://github.com/mat-1">GitHub</a>
+<a href="https://matrix.to/#/@mat:matdoes.dev">Matrix</a>
+<a href="https://ko-fi.com/matdoesdev">Ko-fi (donate)</a></p>"#,
+        ),
+    ));
+
+    let mut blog = String::from("<p><a href=\"/\">← Home</a></p>\n<h1>Blog</h1>\n<ul>\n");
+    // drafts are only meant to be seen by the people with the preview links
+    for post in drafts::published(&data.blog) {
+        blog.push_str(&format!(
+            "<li><a href=\"/{slug}/\">{title}</a> {date}</li>\n",
+            slug = attr(&post.slug),
+            title = escape(&post.title),
+            date = post.published.format("%Y-%m-%d"),
+        ));
+
+        let mut content = format!(
+            "<p><a href=\"/blog/\">← Back</a></p>\n<h1>{title}</h1>\n<p>{date}</p>\n",
+            title = escape(&post.title),
+            date = post.published.format("%Y-%m-%d"),
+        );
+        for part in &post.content {
+            push_part_html(&mut content, part);
+        }
+        artifacts.push(Artifact::new(
+            format!("{}/index.html", post.slug),
+            html_page(&post.title, &content),
+        ));
+    }
+    blog.push_str("</ul>");
+    artifacts.push(Artifact::new("blog/index.html", html_page("Blog", &blog)));
+
+    let mut projects = String::from("<p><a href=\"/\">← Home</a></p>\n<h1>Projects</h1>\n");
+    for project in &data.projects {
+        projects.push_str(&format!("<h2>{}</h2>\n", escape(&project.name)));
+        projects.push_str(&format!("<p>{}</p>\n", escape(&project.description)));
+        if !project.languages.is_empty() {
+            projects.push_str(&format!(
+                "<p>Languages: {}</p>\n",
+                project
+                    .languages
+                    .iter()
+                    .map(|l| l.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        if let Some(href) = &project.href {
+            projects.push_str(&format!("<p><a href=\"{0}\">{0}</a></p>\n", attr(href)));
+        }
+        if let Some(source) = &project.source {
+            projects.push_str(&format!(
+                "<p><a href=\"{}\">Source code</a></p>\n",
+                attr(source)
+            ));
+        }
+    }
+    artifacts.push(Artifact::new(
+        "projects/index.html",
+        html_page("Projects", &projects),
+    ));
+
+    artifacts
+}
+
+fn push_part_html(out: &mut String, part: &PostPart) {
+    match part {
+        PostPart::Text(text) => out.push_str(&escape(text)),
+        PostPart::InlineCode(text) => out.push_str(&format!("<code>{}</code>", escape(text))),
+        PostPart::CodeBlock(text) => {
+            out.push_str(&format!("<pre><code>{}</code></pre>\n", escape(text)));
+        }
+        PostPart::Italic(text) => out.push_str(&format!("<em>{}</em>", escape(text))),
+        PostPart::Bold(text) => out.push_str(&format!("<strong>{}</strong>", escape(text))),
+        PostPart::Image { src, alt } => {
+            let src = match src {
+                // get the path relative to the media directory
+                ImageSource::Local(path) => format!(
+                    "/{}",
+                    path.strip_prefix("media").unwrap_or(path).to_string_lossy()
+                ),
+                ImageSource::Remote(url) => url.to_owned(),
+            };
+            out.push_str(&format!(
+                "<img src=\"{}\" alt=\"{}\">\n",
+                attr(&src),
+                attr(alt.as_deref().unwrap_or_default())
+            ));
+        }
+        PostPart::Link { text, href } => {
+            out.push_str(&format!("<a href=\"{}\">{}</a>", attr(href), escape(text)));
+        }
+        PostPart::LineBreak => out.push_str("<br><br>\n"),
+        PostPart::Heading { level, text } => {
+            // the title of the post is the h1
+            let level = (level + 1).min(6);
+            out.push_str(&format!("<h{level}>{}</h{level}>\n", escape(text)));
+        }
+        PostPart::Quote(text) => {
+            out.push_str(&format!("<blockquote>{}</blockquote>\n", escape(text)));
+        }
+        PostPart::FootnoteReference(label) => {
+            out.push_str(&format!(
+                "<sup><a href=\"#fn-{}\">{}</a></sup>",
+                attr(label),
+                escape(label)
+            ));
+        }
+        PostPart::Footnote { id, text } => {
+            out.push_str(&format!(
+                "<p id=\"fn-{}\">[{}] {}</p>\n",
+                attr(id),
+                escape(id),
+                escape(text)
+            ));
+        }
+        PostPart::HorizontalRule => out.push_str("<hr>\n"),
+        PostPart::DefinitionList(definitions) => {
+            out.push_str("<dl>\n");
+            for (term, definition) in definitions {
+                out.push_str(&format!("<dt>{}</dt>\n", escape(term)));
+                for line in definition.lines() {
+                    out.push_str(&format!("<dd>{}</dd>\n", escape(line)));
+                }
+            }
+            out.push_str("</dl>\n");
+        }
+        PostPart::Caption(text) => {
+            out.push_str(&format!("<figcaption>{}</figcaption>\n", escape(text)));
+        }
+        PostPart::Table(rows) => {
+            out.push_str("<table>\n");
+            for row in rows {
+                out.push_str("<tr>");
+                for cell in row {
+                    out.push_str(&format!("<td>{}</td>", escape(cell)));
+                }
+                out.push_str("</tr>\n");
+            }
+            out.push_str("</table>\n");
+        }
+        PostPart::List { ordered, items } => push_list_html(out, *ordered, items),
+    }
+}
+
+fn push_list_html(out: &mut String, ordered: bool, items: &[ListItem]) {
+    let tag = if ordered { "ol" } else { "ul" };
+    out.push_str(&format!("<{tag}>\n"));
+    for item in items {
+        out.push_str(&format!("<li>{}", escape(&item.text)));
+        for child in &item.children {
+            push_part_html(out, child);
+        }
+        out.push_str("</li>\n");
+    }
+    out.push_str(&format!("</{tag}>\n"));
+}
@@ -0,0 +1,46 @@
+//! Telling the rest of the web about new posts. The pages that a post links
+//! to get a webmention, and the people following the blog from the Fediverse
+//! get an ActivityPub `Create`.
+
+pub mod activitypub;
+mod webmention;
+
+use std::{sync::LazyLock, time::Duration};
+
+use crate::{crawl::Post, links, HOSTNAME};
+
+/// How long a request to another server can take before we give up on it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+static CLIENT: LazyLock<reqwest::Client> =
+    LazyLock::new(|| client_builder().build().expect("the client should build"));
+
+fn client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .user_agent(format!(
+            "matdoesdev-protocols/{} (+https://{HOSTNAME})",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .timeout(REQUEST_TIMEOUT)
+}
+
+/// Announce posts that were just published, in the background.
+pub fn announce(new_posts: Vec<Post>) {
+    if new_posts.is_empty() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        for post in &new_posts {
+            println!("announcing {}", post.slug);
+            webmention::send(post).await;
+            activitypub::announce(post).await;
+        }
+    });
+}
+
+/// Where the post is on the website, which is what's linked to everywhere
+/// else.
+fn post_url(post: &Post) -> String {
+    links::absolute(&post.slug)
+}
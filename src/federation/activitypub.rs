@@ -0,0 +1,650 @@
+//! A minimal ActivityPub actor for the blog, so it can be followed from the
+//! Fediverse as `@blog@matdoes.dev`. It accepts follows at its inbox and sends
+//! a `Create` for every new post to its followers, and replies to the posts
+//! become comments. Requests to and from other servers are signed with HTTP
+//! signatures.
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::LazyLock,
+};
+
+use anyhow::{anyhow, bail};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::{DateTime, TimeDelta, Utc};
+use parking_lot::RwLock;
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE},
+    redirect,
+};
+use rsa::{
+    pkcs1v15::{Signature, SigningKey, VerifyingKey},
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding},
+    signature::{SignatureEncoding, Signer, Verifier},
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::{net::lookup_host, sync::Semaphore};
+use url::{Host, Position, Url};
+
+use super::{client_builder, post_url};
+use crate::{comments, crawl::Post, HOSTNAME};
+
+/// The name in the blog's Fediverse address.
+pub const USERNAME: &str = "blog";
+pub const CONTENT_TYPE_ACTIVITY: &str = "application/activity+json";
+
+pub const KEY_PATH: &str = "data/federation/private_key.pem";
+pub const FOLLOWERS_PATH: &str = "data/federation/followers.json";
+
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+/// How many of the newest posts are in the outbox.
+const OUTBOX_SIZE: usize = 20;
+/// How far the `Date` of a signed request can be from ours.
+const MAX_CLOCK_SKEW: TimeDelta = TimeDelta::hours(1);
+/// How many activities can be handled at once. Each one makes requests to
+/// other servers to check its signature.
+const MAX_HANDLING: usize = 16;
+
+static HANDLING: Semaphore = Semaphore::const_new(MAX_HANDLING);
+
+/// The key that our requests are signed with. It's made the first time it's
+/// needed, and has to stay the same after that since other servers cache it.
+static KEY: LazyLock<RsaPrivateKey> = LazyLock::new(|| {
+    let pem = fs::read_to_string(KEY_PATH).ok();
+    if let Some(key) = pem.and_then(|pem| RsaPrivateKey::from_pkcs8_pem(&pem).ok()) {
+        return key;
+    }
+    println!("generating a new key for activitypub");
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).expect("key should generate");
+    let pem = key.to_pkcs8_pem(LineEnding::LF).expect("key should encode");
+    fs::create_dir_all(Path::new(KEY_PATH).parent().unwrap()).unwrap();
+    fs::write(KEY_PATH, pem.as_bytes()).unwrap();
+    key
+});
+
+/// The inboxes of the actors following the blog, by the actor's id.
+static FOLLOWERS: LazyLock<RwLock<HashMap<String, String>>> = LazyLock::new(|| {
+    let followers = fs::read_to_string(FOLLOWERS_PATH)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default();
+    RwLock::new(followers)
+});
+
+fn save_followers(followers: &HashMap<String, String>) {
+    let write = || -> anyhow::Result<()> {
+        fs::create_dir_all(Path::new(FOLLOWERS_PATH).parent().unwrap())?;
+        fs::write(FOLLOWERS_PATH, serde_json::to_string_pretty(followers)?)?;
+        Ok(())
+    };
+    if let Err(err) = write() {
+        eprintln!("failed to save followers: {err}");
+    }
+}
+
+pub fn actor_url() -> String {
+    format!("https://{HOSTNAME}/actor")
+}
+
+/// The document at [`actor_url`].
+pub fn actor() -> Value {
+    let actor_url = actor_url();
+    let public_key_pem = KEY
+        .to_public_key()
+        .to_public_key_pem(LineEnding::LF)
+        .expect("key should encode");
+    json!({
+        "@context": [
+            "https://www.w3.org/ns/activitystreams",
+            "https://w3id.org/security/v1",
+        ],
+        "id": actor_url,
+        "type": "Service",
+        "preferredUsername": USERNAME,
+        "name": "mat does dev",
+        "summary": "New posts from the blog at matdoes.dev",
+        "url": format!("https://{HOSTNAME}"),
+        "inbox": format!("{actor_url}/inbox"),
+        "outbox": format!("{actor_url}/outbox"),
+        "followers": format!("{actor_url}/followers"),
+        "publicKey": {
+            "id": format!("{actor_url}#main-key"),
+            "owner": actor_url,
+            "publicKeyPem": public_key_pem,
+        },
+    })
+}
+
+/// The answer to a WebFinger lookup of `acct:blog@matdoes.dev`, or `None` if
+/// it's for someone else.
+pub fn webfinger(resource: &str) -> Option<Value> {
+    let subject = format!("acct:{USERNAME}@{HOSTNAME}");
+    if resource != subject && resource != actor_url() {
+        return None;
+    }
+    Some(json!({
+        "subject": subject,
+        "links": [{
+            "rel": "self",
+            "type": CONTENT_TYPE_ACTIVITY,
+            "href": actor_url(),
+        }],
+    }))
+}
+
+/// The newest posts, as the activities that announced them.
+pub fn outbox(posts: &[Post]) -> Value {
+    let mut posts = posts.iter().collect::<Vec<_>>();
+    posts.sort_by_key(|post| Reverse(post.published));
+    let items = posts
+        .iter()
+        .take(OUTBOX_SIZE)
+        .map(|&post| create(post))
+        .collect::<Vec<_>>();
+    json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": format!("{}/outbox", actor_url()),
+        "type": "OrderedCollection",
+        "totalItems": posts.len(),
+        "orderedItems": items,
+    })
+}
+
+/// Only the number of followers is shown.
+pub fn followers() -> Value {
+    json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": format!("{}/followers", actor_url()),
+        "type": "OrderedCollection",
+        "totalItems": FOLLOWERS.read().len(),
+    })
+}
+
+/// A request to the inbox, with everything that's needed to check its
+/// signature after the handler has returned.
+pub struct InboxRequest {
+    pub path: String,
+    /// The header names are lowercase.
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/// Handle an activity that was sent to the inbox, in the background since its
+/// signature has to be checked with the sender's key. Follows, unfollows, and
+/// replies to posts (by their slugs in `post_slugs`) are the only ones that
+/// do anything.
+///
+/// Returns false if too many activities are already being handled, in which
+/// case the sender should try again later.
+pub fn receive(request: InboxRequest, post_slugs: HashSet<String>) -> anyhow::Result<bool> {
+    let activity: Value = serde_json::from_slice(&request.body)?;
+    let actor = activity["actor"]
+        .as_str()
+        .ok_or_else(|| anyhow!("activity has no actor"))?
+        .to_owned();
+    let Ok(permit) = HANDLING.try_acquire() else {
+        return Ok(false);
+    };
+    tokio::spawn(async move {
+        if let Err(err) = handle(&request, &actor, activity, &post_slugs).await {
+            eprintln!("couldn't handle activity from {actor}: {err}");
+        }
+        drop(permit);
+    });
+    Ok(true)
+}
+
+async fn handle(
+    request: &InboxRequest,
+    actor: &str,
+    activity: Value,
+    post_slugs: &HashSet<String>,
+) -> anyhow::Result<()> {
+    let signer = verify(request).await?;
+    if signer != actor {
+        bail!("signed by {signer} instead of the actor");
+    }
+
+    let kind = activity["type"].as_str().unwrap_or_default().to_owned();
+    match kind.as_str() {
+        "Follow" => accept_follow(actor, activity).await,
+        "Undo" if activity["object"]["type"] == "Follow" => {
+            let mut followers = FOLLOWERS.write();
+            if followers.remove(actor).is_some() {
+                println!("{actor} unfollowed");
+                save_followers(&followers);
+            }
+            Ok(())
+        }
+        "Create" => receive_reply(actor, &activity["object"], post_slugs).await,
+        _ => Ok(()),
+    }
+}
+
+/// Add a reply to one of the posts as a comment, so it's shown once it's
+/// approved. Other notes, like replies to replies, are ignored.
+async fn receive_reply(
+    actor: &str,
+    note: &Value,
+    post_slugs: &HashSet<String>,
+) -> anyhow::Result<()> {
+    let post_prefix = format!("https://{HOSTNAME}/");
+    let Some(slug) = note["inReplyTo"]
+        .as_str()
+        .and_then(|in_reply_to| in_reply_to.strip_prefix(&post_prefix))
+        .filter(|slug| post_slugs.contains(*slug))
+    else {
+        return Ok(());
+    };
+    if note["type"] != "Note" || note["attributedTo"] != actor {
+        bail!("reply isn't a note by the actor");
+    }
+    let url = note["url"]
+        .as_str()
+        .or(note["id"].as_str())
+        .ok_or_else(|| anyhow!("reply has no url"))?;
+
+    // named like `@someone@example.com`, or just the server if that's too
+    // long
+    let host = Url::parse(actor)?
+        .host_str()
+        .ok_or_else(|| anyhow!("actor has no host"))?
+        .to_owned();
+    let document = fetch(actor).await?;
+    let author = match document["preferredUsername"].as_str() {
+        Some(username) => format!("@{username}@{host}"),
+        None => host.clone(),
+    };
+    let author = if author.len() > comments::MAX_AUTHOR_LENGTH {
+        host
+    } else {
+        author
+    };
+
+    let body = html_to_text(note["content"].as_str().unwrap_or_default());
+    comments::submit_reply(slug, &author, &body, url)
+}
+
+/// The text of a note's HTML content, with its paragraphs and line breaks
+/// kept.
+fn html_to_text(html: &str) -> String {
+    let html = html
+        .replace("</p>", "\n\n")
+        .replace("<br>", "\n")
+        .replace("<br/>", "\n")
+        .replace("<br />", "\n");
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    html_escape::decode_html_entities(text.trim()).into_owned()
+}
+
+/// The parameters in a `Signature` header, like `keyId="...",signature="..."`.
+fn signature_params(header: &str) -> HashMap<&str, &str> {
+    header
+        .split(',')
+        .filter_map(|param| param.split_once('='))
+        .map(|(name, value)| (name.trim(), value.trim().trim_matches('"')))
+        .collect()
+}
+
+/// Check the request's HTTP signature, and return the actor whose key signed
+/// it. The body's digest, the date, and the host all have to be signed so a
+/// signature can't be used again for something else.
+async fn verify(request: &InboxRequest) -> anyhow::Result<String> {
+    let header = |name: &str| request.headers.get(name).map(String::as_str);
+    let params = signature_params(header("signature").ok_or_else(|| anyhow!("not signed"))?);
+    let key_id = *params
+        .get("keyId")
+        .ok_or_else(|| anyhow!("signature has no keyId"))?;
+    let signature = BASE64_STANDARD.decode(params.get("signature").copied().unwrap_or_default())?;
+    let names = params
+        .get("headers")
+        .copied()
+        .unwrap_or("date")
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    for required in ["(request-target)", "host", "date", "digest"] {
+        if !names.contains(&required) {
+            bail!("{required} isn't signed");
+        }
+    }
+
+    let digest = format!(
+        "SHA-256={}",
+        BASE64_STANDARD.encode(Sha256::digest(&request.body))
+    );
+    if header("digest") != Some(digest.as_str()) {
+        bail!("digest doesn't match the body");
+    }
+    let date = DateTime::parse_from_rfc2822(header("date").unwrap_or_default())?;
+    if (Utc::now() - date.with_timezone(&Utc)).abs() > MAX_CLOCK_SKEW {
+        bail!("date is too far off");
+    }
+
+    let mut signed = Vec::new();
+    for name in names {
+        let value = match name {
+            "(request-target)" => format!("post {}", request.path),
+            name => header(name)
+                .ok_or_else(|| anyhow!("signed header {name} is missing"))?
+                .to_owned(),
+        };
+        signed.push(format!("{name}: {value}"));
+    }
+
+    // the key is usually in the actor's document, with a fragment
+    let document = fetch(key_id).await?;
+    let key = if document["publicKeyPem"].is_string() {
+        &document
+    } else {
+        &document["publicKey"]
+    };
+    if key["id"] != key_id {
+        bail!("key has a different id");
+    }
+    let owner = key["owner"]
+        .as_str()
+        .ok_or_else(|| anyhow!("key has no owner"))?;
+    let mut key_url = Url::parse(key_id)?;
+    key_url.set_fragment(None);
+    // otherwise anyone could make a key that says it's someone else's
+    if key_url.as_str() != owner && fetch(owner).await?["publicKey"]["id"] != key_id {
+        bail!("key's owner doesn't have it");
+    }
+
+    let public_key = RsaPublicKey::from_public_key_pem(
+        key["publicKeyPem"]
+            .as_str()
+            .ok_or_else(|| anyhow!("key has no pem"))?,
+    )?;
+    VerifyingKey::<Sha256>::new(public_key).verify(
+        signed.join("\n").as_bytes(),
+        &Signature::try_from(signature.as_slice())?,
+    )?;
+    Ok(owner.to_owned())
+}
+
+/// The inbox is taken from the actor's own document instead of trusting the
+/// activity, so a follow can't make us send things somewhere else.
+async fn accept_follow(actor: &str, follow: Value) -> anyhow::Result<()> {
+    let document = fetch(actor).await?;
+    if document["id"] != actor {
+        return Err(anyhow!("actor's id doesn't match"));
+    }
+    let inbox = document["endpoints"]["sharedInbox"]
+        .as_str()
+        .or(document["inbox"].as_str())
+        .ok_or_else(|| anyhow!("actor has no inbox"))?
+        .to_owned();
+
+    let accept = json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": format!("{}#accepts/{:x}", actor_url(), rand::random::<u64>()),
+        "type": "Accept",
+        "actor": actor_url(),
+        "object": follow,
+    });
+    deliver(&inbox, &accept).await?;
+
+    println!("{actor} followed");
+    let mut followers = FOLLOWERS.write();
+    followers.insert(actor.to_owned(), inbox);
+    save_followers(&followers);
+    Ok(())
+}
+
+/// Send a `Create` for the post to everyone following the blog.
+pub async fn announce(post: &Post) {
+    let create = create(post);
+    // followers on the same server usually share an inbox
+    let mut inboxes = FOLLOWERS.read().values().cloned().collect::<Vec<_>>();
+    inboxes.sort();
+    inboxes.dedup();
+    for inbox in inboxes {
+        if let Err(err) = deliver(&inbox, &create).await {
+            eprintln!("couldn't deliver {} to {inbox}: {err}", post.slug);
+        }
+    }
+}
+
+fn create(post: &Post) -> Value {
+    let url = post_url(post);
+    let followers = format!("{}/followers", actor_url());
+    let published = post.published.to_rfc3339();
+    json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": format!("{url}#create"),
+        "type": "Create",
+        "actor": actor_url(),
+        "published": published,
+        "to": [PUBLIC],
+        "cc": [followers],
+        "object": {
+            "id": url,
+            "type": "Note",
+            "attributedTo": actor_url(),
+            "content": format!(
+                "<p>{}</p><p><a href=\"{url}\">{url}</a></p>",
+                html_escape::encode_text(&post.title)
+            ),
+            "url": url,
+            "published": published,
+            "to": [PUBLIC],
+            "cc": [followers],
+        },
+    })
+}
+
+/// The `Signature` header for a request, from the pseudo-headers and headers
+/// in `signed`. See
+/// https://datatracker.ietf.org/doc/html/draft-cavage-http-signatures-12
+fn signature(signed: &[(&str, &str)]) -> String {
+    let names = signed
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let signing_string = signed
+        .iter()
+        .map(|(name, value)| format!("{name}: {value}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let signature = SigningKey::<Sha256>::new(KEY.clone()).sign(signing_string.as_bytes());
+    format!(
+        "keyId=\"{}#main-key\",algorithm=\"rsa-sha256\",headers=\"{names}\",signature=\"{}\"",
+        actor_url(),
+        BASE64_STANDARD.encode(signature.to_bytes())
+    )
+}
+
+/// The `Host` header for the url, which has the port if it isn't the default.
+fn host(url: &Url) -> String {
+    url[Position::BeforeHost..Position::AfterPort].to_owned()
+}
+
+fn http_date() -> String {
+    Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Whether the address is on the public internet, like the unstable
+/// `IpAddr::is_global`.
+fn is_global(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "this network", shared address space, benchmarking, and
+                // reserved
+                || a == 0
+                || (a == 100 && b & 0xc0 == 64)
+                || (a == 198 && b & 0xfe == 18)
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, ..] = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // unique local, link local, and documentation
+                || a & 0xfe00 == 0xfc00
+                || a & 0xffc0 == 0xfe80
+                || (a == 0x2001 && b == 0xdb8))
+        }
+    }
+}
+
+/// A client for requests to the url's server, which only connects to the
+/// addresses that we checked are on the public internet. Otherwise anyone
+/// could have us make requests on our own network by sending an activity
+/// with a key id or inbox there.
+async fn client_for(url: &Url) -> anyhow::Result<reqwest::Client> {
+    if url.scheme() != "https" {
+        bail!("{url} isn't https");
+    }
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addrs = match url.host() {
+        Some(Host::Domain(domain)) => lookup_host((domain, port)).await?.collect(),
+        Some(Host::Ipv4(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        Some(Host::Ipv6(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        None => bail!("{url} has no host"),
+    };
+    if addrs.is_empty() {
+        bail!("{url} has no addresses");
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_global(addr.ip())) {
+        bail!("{url} is at {}, which isn't public", addr.ip());
+    }
+
+    let mut builder = client_builder()
+        // a redirect could go anywhere
+        .redirect(redirect::Policy::none());
+    if let Some(domain) = url.domain() {
+        // so it can't resolve to something else when it connects
+        builder = builder.resolve_to_addrs(domain, &addrs);
+    }
+    Ok(builder.build()?)
+}
+
+/// Get an ActivityPub document, with a signed request since some servers only
+/// answer those.
+async fn fetch(url: &str) -> anyhow::Result<Value> {
+    let url = Url::parse(url)?;
+    let client = client_for(&url).await?;
+    let date = http_date();
+    let request_target = format!("get {}", &url[Position::BeforePath..Position::AfterQuery]);
+    let signature = signature(&[
+        ("(request-target)", &request_target),
+        ("host", &host(&url)),
+        ("date", &date),
+    ]);
+    Ok(client
+        .get(url)
+        .header(ACCEPT, CONTENT_TYPE_ACTIVITY)
+        .header("Date", date)
+        .header("Signature", signature)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+/// Post an activity to an inbox.
+async fn deliver(inbox: &str, activity: &Value) -> anyhow::Result<()> {
+    let url = Url::parse(inbox)?;
+    let client = client_for(&url).await?;
+    let body = serde_json::to_vec(activity)?;
+    let date = http_date();
+    let digest = format!("SHA-256={}", BASE64_STANDARD.encode(Sha256::digest(&body)));
+    let request_target = format!("post {}", &url[Position::BeforePath..Position::AfterQuery]);
+    let signature = signature(&[
+        ("(request-target)", &request_target),
+        ("host", &host(&url)),
+        ("date", &date),
+        ("digest", &digest),
+    ]);
+    client
+        .post(url)
+        .header(CONTENT_TYPE, CONTENT_TYPE_ACTIVITY)
+        .header("Date", date)
+        .header("Digest", digest)
+        .header("Signature", signature)
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_webfinger_for_the_blog() {
+        let document = webfinger(&format!("acct:{USERNAME}@{HOSTNAME}")).unwrap();
+        assert_eq!(document["links"][0]["href"], actor_url());
+        assert!(webfinger(&format!("acct:someone@{HOSTNAME}")).is_none());
+    }
+
+    #[test]
+    fn parses_signature_params() {
+        let params = signature_params(
+            r#"keyId="https://example.com/users/a#main-key",algorithm="rsa-sha256",headers="(request-target) host date digest",signature="c2lnbmVk""#,
+        );
+        assert_eq!(params["keyId"], "https://example.com/users/a#main-key");
+        assert_eq!(params["headers"], "(request-target) host date digest");
+        assert_eq!(params["signature"], "c2lnbmVk");
+    }
+
+    #[test]
+    fn converts_replies_to_text() {
+        assert_eq!(
+            html_to_text(
+                r#"<p><span class="h-card"><a href="https://matdoes.dev/">@<span>blog</span></a></span> nice post!</p><p>a &amp; b<br>c</p>"#
+            ),
+            "@blog nice post!\n\na & b\nc"
+        );
+    }
+
+    #[test]
+    fn only_public_addresses_are_global() {
+        for ip in ["1.1.1.1", "2606:4700::1111", "::ffff:1.1.1.1"] {
+            assert!(is_global(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::ffff:127.0.0.1",
+            "fd00::1",
+            "fe80::1",
+        ] {
+            assert!(!is_global(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Sending webmentions (https://www.w3.org/TR/webmention/) to the pages that a
+//! post links to, so they can show that they were mentioned.
+
+use reqwest::header::{CONTENT_TYPE, LINK};
+use url::Url;
+
+use super::{post_url, CLIENT};
+use crate::{
+    crawl::{Post, PostPart},
+    HOSTNAME,
+};
+
+/// Send a webmention to every page the post links to that accepts them.
+pub async fn send(post: &Post) {
+    let source = post_url(post);
+    for target in targets(post) {
+        match send_one(&source, &target).await {
+            Ok(true) => println!("sent a webmention to {target}"),
+            Ok(false) => {}
+            Err(err) => eprintln!("couldn't send a webmention to {target}: {err}"),
+        }
+    }
+}
+
+/// The pages on other sites that the post links to, once each.
+fn targets(post: &Post) -> Vec<Url> {
+    let mut targets = Vec::new();
+    for part in &post.content {
+        let PostPart::Link { href, .. } = part else {
+            continue;
+        };
+        // relative links are to our own pages
+        let Ok(url) = Url::parse(href) else {
+            continue;
+        };
+        if matches!(url.scheme(), "http" | "https")
+            && url.host_str() != Some(HOSTNAME)
+            && !targets.contains(&url)
+        {
+            targets.push(url);
+        }
+    }
+    targets
+}
+
+/// Returns false if the target doesn't accept webmentions.
+async fn send_one(source: &str, target: &Url) -> anyhow::Result<bool> {
+    let Some(endpoint) = discover(target).await? else {
+        return Ok(false);
+    };
+    CLIENT
+        .post(endpoint)
+        .form(&[("source", source), ("target", target.as_str())])
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(true)
+}
+
+/// The target's webmention endpoint, from its `Link` header or from the first
+/// `<link>` or `<a>` with `rel="webmention"` in its HTML.
+async fn discover(target: &Url) -> anyhow::Result<Option<Url>> {
+    let response = CLIENT
+        .get(target.clone())
+        .send()
+        .await?
+        .error_for_status()?;
+    // relative endpoints are relative to where we were redirected to
+    let base = response.url().clone();
+
+    let from_header = response
+        .headers()
+        .get_all(LINK)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .find_map(endpoint_from_link_header);
+    let endpoint = match from_header {
+        Some(endpoint) => Some(endpoint),
+        None => {
+            let is_html = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.starts_with("text/html"));
+            if !is_html {
+                return Ok(None);
+            }
+            endpoint_from_html(&response.text().await?)
+        }
+    };
+    Ok(endpoint.and_then(|endpoint| base.join(&endpoint).ok()))
+}
+
+fn is_webmention_rel(rel: &str) -> bool {
+    rel.split_whitespace()
+        .any(|rel| rel.eq_ignore_ascii_case("webmention"))
+}
+
+/// A header like `<https://example.com/webmention>; rel="webmention"`, which
+/// can have several links separated by commas.
+fn endpoint_from_link_header(header: &str) -> Option<String> {
+    header.split(',').find_map(|link| {
+        let (url, params) = link.split_once(';')?;
+        let url = url.trim().strip_prefix('<')?.strip_suffix('>')?;
+        params
+            .split(';')
+            .filter_map(|param| param.trim().strip_prefix("rel="))
+            .any(|rel| is_webmention_rel(rel.trim_matches('"')))
+            .then(|| url.to_owned())
+    })
+}
+
+fn endpoint_from_html(html: &str) -> Option<String> {
+    let dom = tl::parse(html, tl::ParserOptions::default()).ok()?;
+    dom.nodes().iter().find_map(|node| {
+        let tag = node.as_tag()?;
+        if !matches!(tag.name().as_utf8_str().as_ref(), "link" | "a") {
+            return None;
+        }
+        let attributes = tag.attributes();
+        let rel = attributes.get("rel")??.as_utf8_str();
+        if !is_webmention_rel(&rel) {
+            return None;
+        }
+        // an empty href is the page itself
+        Some(attributes.get("href")??.as_utf8_str().into_owned())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_endpoints() {
+        assert_eq!(
+            endpoint_from_link_header(
+                r#"<https://example.com/style.css>; rel="stylesheet", </webmention>; rel="other webmention""#
+            ),
+            Some("/webmention".to_owned())
+        );
+        assert_eq!(
+            endpoint_from_link_header("<https://example.com/>; rel=canonical"),
+            None
+        );
+
+        let html = r#"<html><head><link rel="stylesheet" href="/style.css">
+            <link rel="webmention" href="https://webmention.io/example/webmention"></head></html>"#;
+        assert_eq!(
+            endpoint_from_html(html),
+            Some("https://webmention.io/example/webmention".to_owned())
+        );
+        assert_eq!(endpoint_from_html("<a href=\"/\">home</a>"), None);
+    }
+}
@@ -0,0 +1,3 @@
+//! Talking to the services that we use to keep an eye on the servers.
+
+pub mod matrix;
@@ -0,0 +1,313 @@
+//! A Matrix bot that posts to a room when there's a new post or a server stops,
+//! and changes the quote of the day when someone allowed to sends
+//! `!qotd <text>` there. It's only used if there's a config at [`CONFIG_PATH`]
+//! like:
+//!
+//! ```json
+//! {
+//!     "homeserver": "https://matrix.org",
+//!     "user": "matdoesdev-bot",
+//!     "password": "...",
+//!     "room": "#matdoesdev:matrix.org",
+//!     "admins": ["@mat:matdoes.dev"]
+//! }
+//! ```
+
+use std::{fs, path::Path, sync::LazyLock, time::Duration};
+
+use anyhow::anyhow;
+use parking_lot::Mutex;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::OnceCell;
+use url::Url;
+
+use crate::{
+    protocols::qotd::{Qotd, MAX_QUOTE_LENGTH},
+    HOSTNAME,
+};
+
+pub const CONFIG_PATH: &str = "data/matrix/config.json";
+/// The access token from logging in, so we don't make a new device every time
+/// we start.
+pub const SESSION_PATH: &str = "data/matrix/session.json";
+
+/// How long the homeserver can hold a sync open while there's nothing new.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long we wait before syncing again after it failed.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .user_agent(format!(
+            "matdoesdev-protocols/{} (+https://{HOSTNAME})",
+            env!("CARGO_PKG_VERSION")
+        ))
+        // longer than a sync is held for
+        .timeout(SYNC_TIMEOUT * 2)
+        .build()
+        .expect("the client should build")
+});
+
+/// Logged in once, the first time the bot is needed.
+static BOT: OnceCell<Bot> = OnceCell::const_new();
+/// Where the last sync left off. It's kept when [`run`] is restarted with a
+/// new quote of the day, so commands sent while it wasn't running still work.
+static NEXT_BATCH: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Deserialize)]
+struct Config {
+    homeserver: Url,
+    user: String,
+    password: String,
+    /// The room's id or an alias for it.
+    room: String,
+    /// The users that can change the quote of the day, like `@mat:matdoes.dev`.
+    #[serde(default)]
+    admins: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Session {
+    access_token: String,
+    user_id: String,
+}
+
+struct Bot {
+    homeserver: Url,
+    session: Session,
+    room_id: String,
+    admins: Vec<String>,
+}
+
+/// Post a message to the room in the background, if there's a bot.
+pub fn notify(message: impl Into<String>) {
+    let message = message.into();
+    tokio::spawn(async move {
+        let Some(bot) = bot().await else {
+            return;
+        };
+        if let Err(err) = bot.send(&message).await {
+            eprintln!("couldn't send a message to matrix: {err}");
+        }
+    });
+}
+
+/// Run `!qotd` commands from the room on `qotd`. This never returns, even if
+/// there's no bot, so it can be raced against the servers.
+pub async fn run(qotd: Qotd) {
+    if let Some(bot) = bot().await {
+        loop {
+            if let Err(err) = bot.sync(&qotd).await {
+                eprintln!("couldn't sync with matrix: {err}");
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+    std::future::pending().await
+}
+
+async fn bot() -> Option<&'static Bot> {
+    let config = fs::read_to_string(CONFIG_PATH).ok()?;
+    let config = match serde_json::from_str::<Config>(&config) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("invalid matrix config: {err}");
+            return None;
+        }
+    };
+    match BOT.get_or_try_init(|| Bot::log_in(config)).await {
+        Ok(bot) => Some(bot),
+        Err(err) => {
+            eprintln!("couldn't log in to matrix: {err}");
+            None
+        }
+    }
+}
+
+impl Bot {
+    async fn log_in(config: Config) -> anyhow::Result<Self> {
+        let saved = fs::read_to_string(SESSION_PATH)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok());
+        let session = match saved {
+            Some(session) => session,
+            None => {
+                let session = log_in_with_password(&config).await?;
+                fs::create_dir_all(Path::new(SESSION_PATH).parent().unwrap())?;
+                fs::write(SESSION_PATH, serde_json::to_string(&session)?)?;
+                session
+            }
+        };
+
+        let mut bot = Bot {
+            homeserver: config.homeserver,
+            session,
+            room_id: String::new(),
+            admins: config.admins,
+        };
+        // joining a room we're already in just gives us its id
+        let joined = bot
+            .request(Method::POST, &["join", &config.room])
+            .json(&json!({}))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Value>()
+            .await?;
+        bot.room_id = joined["room_id"]
+            .as_str()
+            .ok_or_else(|| anyhow!("joining the room didn't give its id"))?
+            .to_owned();
+        println!("logged in to matrix as {}", bot.session.user_id);
+        Ok(bot)
+    }
+
+    /// A request to the client-server API, like `["rooms", room_id, ...]` for
+    /// `/_matrix/client/v3/rooms/...`. The segments are percent-encoded.
+    fn request(&self, method: Method, path: &[&str]) -> reqwest::RequestBuilder {
+        CLIENT
+            .request(method, endpoint(&self.homeserver, path))
+            .bearer_auth(&self.session.access_token)
+    }
+
+    async fn send(&self, message: &str) -> anyhow::Result<()> {
+        // the transaction id only has to be unique for our access token
+        let transaction_id = format!("{:016x}", rand::random::<u64>());
+        self.request(
+            Method::PUT,
+            &[
+                "rooms",
+                &self.room_id,
+                "send",
+                "m.room.message",
+                &transaction_id,
+            ],
+        )
+        .json(&json!({ "msgtype": "m.notice", "body": message }))
+        .send()
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    async fn sync(&self, qotd: &Qotd) -> anyhow::Result<()> {
+        let since = NEXT_BATCH.lock().clone();
+        let mut request = self.request(Method::GET, &["sync"]);
+        if let Some(since) = &since {
+            let timeout = SYNC_TIMEOUT.as_millis().to_string();
+            request = request.query(&[("since", since.as_str()), ("timeout", timeout.as_str())]);
+        }
+        let response = request
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Value>()
+            .await?;
+
+        // the first sync has the room's history, and the commands in it were
+        // already run
+        if since.is_some() {
+            for (sender, quote) in qotd_commands(&response, &self.room_id) {
+                if sender == self.session.user_id {
+                    continue;
+                }
+                let reply = if !self.admins.contains(&sender) {
+                    format!("{sender} isn't allowed to change the quote of the day")
+                } else if quote.len() > MAX_QUOTE_LENGTH {
+                    format!("Quotes can't be longer than {MAX_QUOTE_LENGTH} bytes")
+                } else {
+                    qotd.set(&quote);
+                    "Changed the quote of the day".to_owned()
+                };
+                self.send(&reply).await?;
+            }
+        }
+
+        *NEXT_BATCH.lock() = response["next_batch"].as_str().map(str::to_owned);
+        Ok(())
+    }
+}
+
+async fn log_in_with_password(config: &Config) -> anyhow::Result<Session> {
+    let response = CLIENT
+        .post(endpoint(&config.homeserver, &["login"]))
+        .json(&json!({
+            "type": "m.login.password",
+            "identifier": { "type": "m.id.user", "user": config.user },
+            "password": config.password,
+            "initial_device_display_name": HOSTNAME,
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(response.json().await?)
+}
+
+fn endpoint(homeserver: &Url, path: &[&str]) -> Url {
+    let mut url = homeserver.clone();
+    url.path_segments_mut()
+        .expect("the homeserver should be an http url")
+        .pop_if_empty()
+        .extend(["_matrix", "client", "v3"])
+        .extend(path);
+    url
+}
+
+/// The senders and quotes of the `!qotd` messages in the room from a sync
+/// response, oldest first.
+fn qotd_commands(sync: &Value, room_id: &str) -> Vec<(String, String)> {
+    let Some(events) = sync["rooms"]["join"][room_id]["timeline"]["events"].as_array() else {
+        return Vec::new();
+    };
+    events
+        .iter()
+        .filter(|event| event["type"] == "m.room.message")
+        .filter_map(|event| {
+            let sender = event["sender"].as_str()?;
+            let quote = event["content"]["body"].as_str()?.strip_prefix("!qotd ")?;
+            Some((sender.to_owned(), quote.trim().to_owned()))
+        })
+        .filter(|(_, quote)| !quote.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_qotd_commands() {
+        let sync = json!({
+            "next_batch": "s2",
+            "rooms": { "join": {
+                "!room:matrix.org": { "timeline": { "events": [
+                    { "type": "m.room.message", "sender": "@mat:matdoes.dev",
+                      "content": { "msgtype": "m.text", "body": "!qotd hello world" } },
+                    { "type": "m.room.message", "sender": "@someone:matrix.org",
+                      "content": { "msgtype": "m.text", "body": "what's !qotd" } },
+                    { "type": "m.reaction", "sender": "@mat:matdoes.dev", "content": {} },
+                ] } },
+                "!other:matrix.org": { "timeline": { "events": [
+                    { "type": "m.room.message", "sender": "@mat:matdoes.dev",
+                      "content": { "msgtype": "m.text", "body": "!qotd elsewhere" } },
+                ] } },
+            } },
+        });
+        assert_eq!(
+            qotd_commands(&sync, "!room:matrix.org"),
+            vec![("@mat:matdoes.dev".to_owned(), "hello world".to_owned())]
+        );
+        assert!(qotd_commands(&json!({}), "!room:matrix.org").is_empty());
+    }
+
+    #[test]
+    fn encodes_endpoints() {
+        let homeserver = Url::parse("https://matrix.org").unwrap();
+        assert_eq!(
+            endpoint(&homeserver, &["join", "#matdoesdev:matrix.org"]).as_str(),
+            "https://matrix.org/_matrix/client/v3/join/%23matdoesdev:matrix.org"
+        );
+    }
+}
@@ -0,0 +1,74 @@
+//! The servers behind matdoes.dev, as a library so they can be used with
+//! other content: build a [`crawl::SiteData`], make the servers you want for
+//! it with [`ServerBuilder`]s, and run them with a [`ProtocolServer`]. The
+//! `matdoesdev-protocols` binary is [`cli::run`].
+
+use std::sync::OnceLock;
+
+pub use crate::{
+    crawl::SiteData,
+    protocols::{Protocol, ProtocolServer, ServerBuilder, ServerConfig, ServerHandle, Shutdown},
+};
+
+mod access_log;
+mod acme;
+mod analytics;
+mod bandwidth;
+mod banner;
+mod bencode;
+mod cache;
+pub mod cli;
+mod comments;
+pub mod crawl;
+mod drafts;
+mod export;
+mod federation;
+mod integrations;
+mod lifecycle;
+mod links;
+mod listen;
+mod locale;
+mod markdown;
+mod media;
+mod motd;
+pub mod protocols;
+mod related;
+mod routes;
+mod search;
+mod self_test;
+mod server_info;
+mod sites;
+mod sources;
+mod table;
+pub mod terminal;
+mod thumbnail;
+mod timeouts;
+mod tls;
+mod transcode;
+mod visitors;
+mod watch;
+
+const HOSTNAME: &str = "matdoes.dev";
+/// Other hostnames that we also serve, from `--hostname`.
+static ALT_HOSTNAMES: OnceLock<Vec<String>> = OnceLock::new();
+/// The onion service that the site is also served at, from `--onion`.
+static ONION_ADDRESS: OnceLock<String> = OnceLock::new();
+
+/// [`HOSTNAME`], then the ones from `--hostname`, and then the onion address.
+fn hostnames() -> impl Iterator<Item = &'static str> {
+    [HOSTNAME]
+        .into_iter()
+        .chain(
+            ALT_HOSTNAMES
+                .get()
+                .into_iter()
+                .flatten()
+                .map(|h| h.as_str()),
+        )
+        .chain(onion_address())
+}
+
+/// The address from `--onion`, which the landing pages point Tor users to.
+fn onion_address() -> Option<&'static str> {
+    ONION_ADDRESS.get().map(|address| address.as_str())
+}
@@ -0,0 +1,68 @@
+//! Keeping track of the connections that are open, and restarting or stopping
+//! the servers when the admin endpoints ask for it.
+
+use std::{collections::BTreeMap, sync::LazyLock, time::Duration};
+
+use parking_lot::Mutex;
+use tokio::{sync::Notify, time::sleep};
+
+/// How many connections are open for each protocol.
+static SESSIONS: LazyLock<Mutex<BTreeMap<&'static str, usize>>> = LazyLock::new(Default::default);
+
+static RECRAWL: Notify = Notify::const_new();
+static DRAIN: Notify = Notify::const_new();
+
+/// Counted as an open connection until it's dropped.
+pub struct Session {
+    protocol: &'static str,
+}
+
+impl Session {
+    pub fn start(protocol: &'static str) -> Self {
+        *SESSIONS.lock().entry(protocol).or_default() += 1;
+        Session { protocol }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut sessions = SESSIONS.lock();
+        if let Some(count) = sessions.get_mut(self.protocol) {
+            *count -= 1;
+            if *count == 0 {
+                sessions.remove(self.protocol);
+            }
+        }
+    }
+}
+
+/// The number of open connections for each protocol that has any.
+pub fn sessions() -> BTreeMap<&'static str, usize> {
+    SESSIONS.lock().clone()
+}
+
+/// Get the site data again and restart the servers with it. The old servers
+/// keep running until the new data is ready.
+pub fn request_recrawl() {
+    RECRAWL.notify_one();
+}
+
+pub async fn recrawl_requested() {
+    RECRAWL.notified().await;
+}
+
+/// Stop accepting connections, and exit once the open ones are closed.
+pub fn request_drain() {
+    DRAIN.notify_one();
+}
+
+pub async fn drain_requested() {
+    DRAIN.notified().await;
+}
+
+/// Wait until every connection is closed.
+pub async fn drained() {
+    while !SESSIONS.lock().is_empty() {
+        sleep(Duration::from_secs(1)).await;
+    }
+}
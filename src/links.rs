@@ -0,0 +1,155 @@
+//! Where the links in posts and project pages go for each protocol. A link is
+//! either to a page on this site, to another site that's also served over
+//! the protocol the reader is using, or to somewhere else entirely.
+
+use crate::{hostnames, routes::Scheme, HOSTNAME};
+
+/// Sites that have a version for another protocol, which links to them are
+/// rewritten to for the readers using that protocol.
+struct Mapping {
+    /// The start of the links that are rewritten, including the scheme.
+    from: &'static str,
+    to: &'static str,
+    schemes: &'static [Scheme],
+}
+
+/// Gemini and Gopher both show the links after each paragraph, and those
+/// readers usually have a Gemini client nearby.
+const MAPPINGS: &[Mapping] = &[
+    Mapping {
+        from: "https://gemini.circumlunar.space/",
+        to: "gemini://gemini.circumlunar.space/",
+        schemes: &[Scheme::Gemini, Scheme::Gopher],
+    },
+    Mapping {
+        from: "https://gmi.skyjake.fi/",
+        to: "gemini://skyjake.fi/",
+        schemes: &[Scheme::Gemini, Scheme::Gopher],
+    },
+];
+
+/// The schemes that links to our own hostnames can use and still be to a
+/// page that every protocol has.
+const OWN_SCHEMES: &[&str] = &["https://", "http://", "gemini://"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Href<'a> {
+    /// A page on this site, as a path that starts with a slash.
+    Internal(&'a str),
+    /// Another site's version for the reader's protocol.
+    CrossProtocol(String),
+    External(&'a str),
+}
+
+impl<'a> Href<'a> {
+    pub fn classify(href: &'a str, scheme: Scheme) -> Self {
+        if href.starts_with('/') {
+            return Href::Internal(href);
+        }
+        if let Some(path) = own_path(href) {
+            return Href::Internal(path);
+        }
+        let mapped = MAPPINGS
+            .iter()
+            .filter(|mapping| mapping.schemes.contains(&scheme))
+            .find_map(|mapping| {
+                let rest = href.strip_prefix(mapping.from)?;
+                Some(format!("{}{rest}", mapping.to))
+            });
+        match mapped {
+            Some(href) => Href::CrossProtocol(href),
+            None => Href::External(href),
+        }
+    }
+
+    /// The link as it's written for the reader's protocol, with pages on this
+    /// site as paths.
+    pub fn into_href(self) -> String {
+        match self {
+            Href::Internal(path) => path.to_owned(),
+            Href::CrossProtocol(href) => href,
+            Href::External(href) => href.to_owned(),
+        }
+    }
+}
+
+/// The path of a link to one of our hostnames, like `/blog` for
+/// `https://matdoes.dev/blog`.
+fn own_path(href: &str) -> Option<&str> {
+    let rest = OWN_SCHEMES
+        .iter()
+        .find_map(|scheme| href.strip_prefix(scheme))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    hostnames()
+        .any(|hostname| host.eq_ignore_ascii_case(hostname))
+        .then_some(path)
+}
+
+/// The link rewritten for the reader's protocol.
+pub fn rewrite(href: &str, scheme: Scheme) -> String {
+    Href::classify(href, scheme).into_href()
+}
+
+/// An absolute link to a page on the website, for the places that aren't
+/// read from the site. The path can start with a slash or not.
+pub fn absolute(path: &str) -> String {
+    format!("https://{HOSTNAME}/{}", path.trim_start_matches('/'))
+}
+
+/// The link without its scheme or a slash at the end, like `example.com/page`,
+/// which is nicer to read when it's shown as text.
+pub fn pretty(href: &str) -> &str {
+    let href = href
+        .strip_prefix("https://")
+        .or_else(|| href.strip_prefix("http://"))
+        .unwrap_or(href);
+    href.strip_suffix('/').unwrap_or(href)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_links() {
+        assert_eq!(
+            Href::classify("/blog", Scheme::Gopher),
+            Href::Internal("/blog")
+        );
+        assert_eq!(
+            Href::classify("https://matdoes.dev/blog", Scheme::Gemini),
+            Href::Internal("/blog")
+        );
+        assert_eq!(
+            Href::classify("http://MATDOES.DEV", Scheme::Http),
+            Href::Internal("/")
+        );
+        // only a different site that happens to start with our hostname
+        assert_eq!(
+            Href::classify("https://matdoes.dev.example.com/", Scheme::Http),
+            Href::External("https://matdoes.dev.example.com/")
+        );
+        assert_eq!(
+            Href::classify("https://gmi.skyjake.fi/lagrange/", Scheme::Gemini),
+            Href::CrossProtocol("gemini://skyjake.fi/lagrange/".to_owned())
+        );
+        assert_eq!(
+            Href::classify("https://gmi.skyjake.fi/lagrange/", Scheme::Http),
+            Href::External("https://gmi.skyjake.fi/lagrange/")
+        );
+        assert_eq!(
+            rewrite("https://gemini.circumlunar.space/docs/", Scheme::Gopher),
+            "gemini://gemini.circumlunar.space/docs/"
+        );
+    }
+
+    #[test]
+    fn prettifies_links() {
+        assert_eq!(pretty("https://example.com/"), "example.com");
+        assert_eq!(pretty("http://example.com/page"), "example.com/page");
+        assert_eq!(pretty("gemini://example.com/"), "gemini://example.com");
+    }
+}
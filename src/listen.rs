@@ -0,0 +1,209 @@
+//! Binding the servers to their ports. By default every protocol gets an IPv6
+//! listener and a separate IPv4 one, so it doesn't matter whether the system
+//! maps IPv4 connections onto IPv6 sockets. `--bind` picks specific addresses
+//! instead.
+//!
+//! The problems that protocols had listening are kept for `/healthz`.
+
+use std::{
+    collections::BTreeMap,
+    future::poll_fn,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{LazyLock, OnceLock},
+    task::Poll,
+};
+
+use parking_lot::RwLock;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use crate::server_info;
+
+/// The addresses from `--bind`.
+static BIND_ADDRESSES: OnceLock<Vec<IpAddr>> = OnceLock::new();
+/// Whether to let the system pick the ports, for `--self-test`.
+static EPHEMERAL_PORTS: OnceLock<()> = OnceLock::new();
+/// The TCP port that each protocol is listening on, which is only different
+/// from the one it asked for with ephemeral ports.
+static PORTS: LazyLock<RwLock<BTreeMap<String, u16>>> = LazyLock::new(Default::default);
+/// The last problem that each protocol had, like not being able to bind or
+/// accept connections. It's cleared once it's listening again.
+static FAILURES: LazyLock<RwLock<BTreeMap<String, String>>> = LazyLock::new(Default::default);
+
+/// Only listen on these addresses. This can only be set once, before any of
+/// the servers start.
+pub fn set_bind_addresses(addresses: Vec<IpAddr>) {
+    BIND_ADDRESSES
+        .set(addresses)
+        .expect("bind addresses were already set");
+}
+
+/// Listen on ports that the system picks instead of the usual ones, so it
+/// doesn't matter whether they're free. This can only be done before any of
+/// the servers start.
+pub fn use_ephemeral_ports() {
+    EPHEMERAL_PORTS
+        .set(())
+        .expect("ephemeral ports were already set");
+}
+
+/// The TCP port that the protocol is listening on, if it is.
+pub fn port(protocol: &str) -> Option<u16> {
+    PORTS.read().get(protocol).copied()
+}
+
+/// The protocols that have had a problem listening, and what it was.
+pub fn failures() -> BTreeMap<String, String> {
+    FAILURES.read().clone()
+}
+
+fn fail(protocol: &str, message: String) {
+    FAILURES.write().insert(protocol.to_owned(), message);
+}
+
+fn ephemeral_or(port: u16) -> u16 {
+    if EPHEMERAL_PORTS.get().is_some() {
+        0
+    } else {
+        port
+    }
+}
+
+fn bind_addresses() -> Vec<IpAddr> {
+    match BIND_ADDRESSES.get() {
+        Some(addresses) if !addresses.is_empty() => addresses.clone(),
+        _ => vec![
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        ],
+    }
+}
+
+/// A socket that's bound to the address, but isn't listening yet.
+fn bind_socket(address: SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(address), ty, Some(protocol))?;
+    if address.is_ipv6() {
+        // if there's also an ipv4 listener it gets the ipv4 connections, and
+        // otherwise it depends on the system like it would without this
+        let has_ipv4 = bind_addresses().iter().any(IpAddr::is_ipv4);
+        if has_ipv4 {
+            socket.set_only_v6(true)?;
+        }
+    }
+    if ty == Type::STREAM {
+        // like tokio does, so restarting doesn't have to wait for the old
+        // connections to time out
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    Ok(socket)
+}
+
+/// Say which addresses a protocol ended up listening on, and whether it has
+/// both IPv4 and IPv6.
+fn report(protocol: &str, addresses: &[SocketAddr]) {
+    if addresses.is_empty() {
+        eprintln!("{protocol} isn't listening on any addresses");
+        return;
+    }
+    let families = match (
+        addresses.iter().any(SocketAddr::is_ipv6),
+        addresses.iter().any(SocketAddr::is_ipv4),
+    ) {
+        (true, true) => "IPv6 and IPv4",
+        (true, false) => "IPv6",
+        _ => "IPv4",
+    };
+    let addresses = addresses
+        .iter()
+        .map(|address| address.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    println!("{protocol} is listening on {addresses} ({families})");
+}
+
+/// TCP listeners for one port on every bind address.
+pub struct Listener {
+    protocol: String,
+    listeners: Vec<TcpListener>,
+}
+
+impl Listener {
+    /// Addresses that can't be bound are skipped, and this is `None` if none
+    /// of them could be.
+    pub fn bind(protocol: &str, port: u16) -> Option<Listener> {
+        let port = ephemeral_or(port);
+        let mut listeners = Vec::new();
+        let mut bound = Vec::new();
+        for ip in bind_addresses() {
+            let address = SocketAddr::new(ip, port);
+            let listener = bind_socket(address, Type::STREAM, Protocol::TCP).and_then(|socket| {
+                socket.listen(1024)?;
+                TcpListener::from_std(socket.into())
+            });
+            match listener.and_then(|listener| Ok((listener.local_addr()?, listener))) {
+                Ok((address, listener)) => {
+                    listeners.push(listener);
+                    bound.push(address);
+                }
+                Err(e) => eprintln!("failed to bind {protocol} to {address}: {e}"),
+            }
+        }
+        report(protocol, &bound);
+        let Some(first) = bound.first() else {
+            fail(protocol, format!("couldn't bind to port {port}"));
+            return None;
+        };
+        server_info::register(protocol, first.port(), "tcp");
+        PORTS.write().insert(protocol.to_owned(), first.port());
+        FAILURES.write().remove(protocol);
+        Some(Listener {
+            protocol: protocol.to_owned(),
+            listeners,
+        })
+    }
+
+    /// The next connection on any of the addresses.
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let result = poll_fn(|cx| {
+            for listener in &self.listeners {
+                if let Poll::Ready(result) = listener.poll_accept(cx) {
+                    return Poll::Ready(result);
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        if let Err(e) = &result {
+            fail(&self.protocol, format!("couldn't accept a connection: {e}"));
+        }
+        result
+    }
+}
+
+/// A UDP socket for the port on every bind address that could be bound.
+pub fn bind_udp(protocol: &str, port: u16) -> Vec<UdpSocket> {
+    let port = ephemeral_or(port);
+    let mut sockets = Vec::new();
+    let mut bound = Vec::new();
+    for ip in bind_addresses() {
+        let address = SocketAddr::new(ip, port);
+        let socket = bind_socket(address, Type::DGRAM, Protocol::UDP)
+            .and_then(|socket| UdpSocket::from_std(socket.into()));
+        match socket.and_then(|socket| Ok((socket.local_addr()?, socket))) {
+            Ok((address, socket)) => {
+                sockets.push(socket);
+                bound.push(address);
+            }
+            Err(e) => eprintln!("failed to bind {protocol} to udp {address}: {e}"),
+        }
+    }
+    report(&format!("{protocol} (udp)"), &bound);
+    match bound.first() {
+        Some(first) => server_info::register(protocol, first.port(), "udp"),
+        None => fail(protocol, format!("couldn't bind to udp port {port}")),
+    }
+    sockets
+}
@@ -0,0 +1,385 @@
+//! Translations of the fixed parts of the UI, like navigation labels and error
+//! messages. The content itself (posts and projects) is only in English.
+//!
+//! Each protocol picks the language its own way: Gemini from a `/<lang>/`
+//! prefix on the path, HTTP from the `Accept-Language` header, and SSH from a
+//! suffix on the username like `ssh de@matdoes.dev` or `ssh light-de@...`.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Locale {
+    #[default]
+    En,
+    De,
+    Fr,
+}
+
+/// The UI strings for one language.
+pub struct Strings {
+    pub home: &'static str,
+    pub blog: &'static str,
+    pub projects: &'static str,
+    pub stats: &'static str,
+    /// The page about the server, like its uptime and ports.
+    pub server: &'static str,
+    /// The page listing the small services, like discard and qotd.
+    pub playground: &'static str,
+    pub tags: &'static str,
+    /// Goes before a tag's name.
+    pub tagged: &'static str,
+    pub search: &'static str,
+    pub downloads: &'static str,
+    pub quote_of_the_day: &'static str,
+    pub back: &'static str,
+    pub comments: &'static str,
+    pub related_posts: &'static str,
+    pub filter: &'static str,
+    pub no_posts_found: &'static str,
+    pub navigation_hint: &'static str,
+    pub sort: &'static str,
+    /// The name of the order that has the newest posts first.
+    pub newest: &'static str,
+    /// The name of the order that has the longest posts first.
+    pub longest: &'static str,
+    /// Goes after the estimated reading time in minutes.
+    pub min_read: &'static str,
+    /// Goes after the word count of a post.
+    pub words: &'static str,
+    /// The title of the help that's shown with `?` in the terminal.
+    pub keyboard_shortcuts: &'static str,
+    /// The keys that can be pressed in the terminal and what they do.
+    pub shortcuts: &'static [(&'static str, &'static str)],
+    pub close_hint: &'static str,
+    /// The projects filter that shows every language.
+    pub all: &'static str,
+    /// Goes before the Gemini URL where a post can be commented on.
+    pub leave_a_comment: &'static str,
+    /// Under a post, for the `r` shortcut.
+    pub print_hint: &'static str,
+    /// Goes after the number of requests on the stats page.
+    pub requests_in_total: &'static str,
+    pub protocols: &'static str,
+    pub most_read_posts: &'static str,
+
+    pub unauthorized: &'static str,
+    pub not_found: &'static str,
+    pub method_not_allowed: &'static str,
+    pub too_many_requests: &'static str,
+    pub internal_error: &'static str,
+}
+
+const EN: Strings = Strings {
+    home: "Home",
+    blog: "Blog",
+    projects: "Projects",
+    stats: "Stats",
+    server: "Server",
+    playground: "Playground",
+    tags: "Tags",
+    tagged: "Tagged",
+    search: "Search",
+    downloads: "Downloads",
+    quote_of_the_day: "Quote of the day",
+    back: "Back",
+    comments: "Comments",
+    related_posts: "Related posts",
+    filter: "Filter",
+    no_posts_found: "No posts found.",
+    navigation_hint: "(tab: links, enter: select, ?: help)",
+    sort: "Sort",
+    newest: "Newest",
+    longest: "Longest",
+    min_read: "min read",
+    words: "words",
+    keyboard_shortcuts: "Keyboard shortcuts",
+    shortcuts: &[
+        ("tab / shift+tab", "select the next or previous link"),
+        ("enter", "follow the selected link"),
+        ("j / k", "scroll down or up"),
+        ("ctrl+d / ctrl+u", "scroll half a page"),
+        ("pgdn / pgup", "scroll a whole page"),
+        ("g / G", "go to the top or bottom"),
+        ("u / backspace", "go back"),
+        ("ctrl+r", "go forward again"),
+        ("h / b / p / s", "home, blog, projects, stats"),
+        ("/", "search the blog"),
+        ("f", "filter the projects by language"),
+        ("o", "change the order of the blog"),
+        ("t", "switch to the next theme"),
+        ("r", "print the post as plain text"),
+        ("?", "show these shortcuts"),
+    ],
+    close_hint: "(press any key to close)",
+    all: "All",
+    leave_a_comment: "Leave a comment at",
+    print_hint: "Press r to print the post as plain text",
+    requests_in_total: "requests in total",
+    protocols: "Protocols",
+    most_read_posts: "Most read posts",
+
+    unauthorized: "Unauthorized",
+    not_found: "Not Found",
+    method_not_allowed: "Method Not Allowed",
+    too_many_requests: "Too Many Requests",
+    internal_error: "Internal Server Error",
+};
+
+const DE: Strings = Strings {
+    home: "Startseite",
+    blog: "Blog",
+    projects: "Projekte",
+    stats: "Statistiken",
+    server: "Server",
+    playground: "Spielplatz",
+    tags: "Tags",
+    tagged: "Mit Tag",
+    search: "Suche",
+    downloads: "Downloads",
+    quote_of_the_day: "Zitat des Tages",
+    back: "Zurück",
+    comments: "Kommentare",
+    related_posts: "Ähnliche Beiträge",
+    filter: "Filter",
+    no_posts_found: "Keine Beiträge gefunden.",
+    navigation_hint: "(Tab: Links, Enter: auswählen, ?: Hilfe)",
+    sort: "Sortieren",
+    newest: "Neueste",
+    longest: "Längste",
+    min_read: "Min. Lesezeit",
+    words: "Wörter",
+    keyboard_shortcuts: "Tastenkürzel",
+    shortcuts: &[
+        ("Tab / Umschalt+Tab", "nächsten oder vorherigen Link wählen"),
+        ("Enter", "gewählten Link öffnen"),
+        ("j / k", "nach unten oder oben scrollen"),
+        ("Strg+D / Strg+U", "eine halbe Seite scrollen"),
+        ("Bild↓ / Bild↑", "eine ganze Seite scrollen"),
+        ("g / G", "zum Anfang oder Ende"),
+        ("u / Rücktaste", "zurück"),
+        ("Strg+R", "wieder vorwärts"),
+        ("h / b / p / s", "Startseite, Blog, Projekte, Statistiken"),
+        ("/", "im Blog suchen"),
+        ("f", "Projekte nach Sprache filtern"),
+        ("o", "Reihenfolge des Blogs ändern"),
+        ("t", "zum nächsten Farbschema wechseln"),
+        ("r", "Beitrag als reinen Text ausgeben"),
+        ("?", "diese Tastenkürzel anzeigen"),
+    ],
+    close_hint: "(beliebige Taste zum Schließen)",
+    all: "Alle",
+    leave_a_comment: "Kommentieren unter",
+    print_hint: "Drücke r, um den Beitrag als reinen Text auszugeben",
+    requests_in_total: "Anfragen insgesamt",
+    protocols: "Protokolle",
+    most_read_posts: "Meistgelesene Beiträge",
+
+    unauthorized: "Nicht autorisiert",
+    not_found: "Nicht gefunden",
+    method_not_allowed: "Methode nicht erlaubt",
+    too_many_requests: "Zu viele Anfragen",
+    internal_error: "Interner Serverfehler",
+};
+
+const FR: Strings = Strings {
+    home: "Accueil",
+    blog: "Blog",
+    projects: "Projets",
+    stats: "Statistiques",
+    server: "Serveur",
+    playground: "Bac à sable",
+    tags: "Étiquettes",
+    tagged: "Étiquette",
+    search: "Recherche",
+    downloads: "Téléchargements",
+    quote_of_the_day: "Citation du jour",
+    back: "Retour",
+    comments: "Commentaires",
+    related_posts: "Articles similaires",
+    filter: "Filtre",
+    no_posts_found: "Aucun article trouvé.",
+    navigation_hint: "(tab : liens, entrée : choisir, ? : aide)",
+    sort: "Trier",
+    newest: "Plus récents",
+    longest: "Plus longs",
+    min_read: "min de lecture",
+    words: "mots",
+    keyboard_shortcuts: "Raccourcis clavier",
+    shortcuts: &[
+        ("tab / maj+tab", "choisir le lien suivant ou précédent"),
+        ("entrée", "ouvrir le lien choisi"),
+        ("j / k", "défiler vers le bas ou le haut"),
+        ("ctrl+d / ctrl+u", "défiler d'une demi-page"),
+        ("pg.suiv / pg.préc", "défiler d'une page entière"),
+        ("g / G", "aller en haut ou en bas"),
+        ("u / retour arrière", "revenir en arrière"),
+        ("ctrl+r", "avancer de nouveau"),
+        ("h / b / p / s", "accueil, blog, projets, statistiques"),
+        ("/", "rechercher dans le blog"),
+        ("f", "filtrer les projets par langage"),
+        ("o", "changer l'ordre du blog"),
+        ("t", "passer au thème suivant"),
+        ("r", "afficher l'article en texte brut"),
+        ("?", "afficher ces raccourcis"),
+    ],
+    close_hint: "(appuyez sur une touche pour fermer)",
+    all: "Tous",
+    leave_a_comment: "Laisser un commentaire sur",
+    print_hint: "Appuyez sur r pour afficher l'article en texte brut",
+    requests_in_total: "requêtes au total",
+    protocols: "Protocoles",
+    most_read_posts: "Articles les plus lus",
+
+    unauthorized: "Non autorisé",
+    not_found: "Introuvable",
+    method_not_allowed: "Méthode non autorisée",
+    too_many_requests: "Trop de requêtes",
+    internal_error: "Erreur interne du serveur",
+};
+
+impl Strings {
+    /// Like "3 min read · 512 words", for under the title of a post.
+    pub fn post_length(&self, minutes: usize, words: usize) -> String {
+        format!("{minutes} {} · {words} {}", self.min_read, self.words)
+    }
+}
+
+impl Locale {
+    pub const ALL: [Locale; 3] = [Locale::En, Locale::De, Locale::Fr];
+
+    pub fn strings(self) -> &'static Strings {
+        match self {
+            Locale::En => &EN,
+            Locale::De => &DE,
+            Locale::Fr => &FR,
+        }
+    }
+
+    /// The language code, like `de`.
+    pub fn code(self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::De => "de",
+            Locale::Fr => "fr",
+        }
+    }
+
+    /// Parse a language code. Regions are ignored, so `de-AT` is German.
+    pub fn from_code(code: &str) -> Option<Locale> {
+        Locale::from_language(code.split(['-', '_']).next().unwrap_or_default())
+    }
+
+    /// Parse a language code without a region.
+    fn from_language(language: &str) -> Option<Locale> {
+        Locale::ALL
+            .into_iter()
+            .find(|locale| locale.code().eq_ignore_ascii_case(language))
+    }
+
+    /// The language from an `Accept-Language` header, like
+    /// `fr-CH, fr;q=0.9, en;q=0.8`. Languages we don't have are skipped, and
+    /// it's English if there's nothing we have.
+    pub fn from_accept_language(header: &str) -> Locale {
+        let mut best = None;
+        for item in header.split(',') {
+            let mut parts = item.split(';');
+            let Some(locale) = parts.next().and_then(|code| Locale::from_code(code.trim())) else {
+                continue;
+            };
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.), |q| q.trim().parse::<f32>().ok());
+            let Some(quality) = quality.filter(|q| *q > 0.) else {
+                continue;
+            };
+            // the earlier one wins if they're tied
+            if best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                best = Some((locale, quality));
+            }
+        }
+        best.map(|(locale, _)| locale).unwrap_or_default()
+    }
+
+    /// Split the language off of an SSH username. The whole username can be
+    /// the language (`de`), or it can be after a dash (`light-de`). Returns
+    /// the rest of the username and the language, if there was one.
+    pub fn split_username(username: &str) -> (&str, Option<Locale>) {
+        if let Some(locale) = Locale::from_language(username) {
+            return ("", Some(locale));
+        }
+        match username.rsplit_once('-') {
+            Some((rest, code)) => match Locale::from_language(code) {
+                Some(locale) => (rest, Some(locale)),
+                None => (username, None),
+            },
+            None => (username, None),
+        }
+    }
+
+    /// Take the language off the start of a path like `/de/blog`. Returns the
+    /// language and the path without it, or `None` and the original path.
+    pub fn strip_path_prefix(path: &str) -> (Option<Locale>, &str) {
+        let Some(after_slash) = path.strip_prefix('/') else {
+            return (None, path);
+        };
+        let (code, rest) = match after_slash.find('/') {
+            Some(index) => after_slash.split_at(index),
+            None => (after_slash, "/"),
+        };
+        match Locale::from_language(code) {
+            Some(locale) => (Some(locale), rest),
+            None => (None, path),
+        }
+    }
+
+    /// What goes before the paths in links so the language is kept, which is
+    /// nothing for the default.
+    pub fn path_prefix(self) -> String {
+        if self == Locale::default() {
+            String::new()
+        } else {
+            format!("/{}", self.code())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_language() {
+        assert_eq!(Locale::from_accept_language(""), Locale::En);
+        assert_eq!(Locale::from_accept_language("de-DE"), Locale::De);
+        assert_eq!(
+            Locale::from_accept_language("ja, fr-CH;q=0.8, de;q=0.9"),
+            Locale::De
+        );
+        assert_eq!(Locale::from_accept_language("de;q=0, fr;q=0.1"), Locale::Fr);
+        assert_eq!(Locale::from_accept_language("*;q=0.5, ja"), Locale::En);
+    }
+
+    #[test]
+    fn usernames() {
+        assert_eq!(Locale::split_username("de"), ("", Some(Locale::De)));
+        assert_eq!(
+            Locale::split_username("light-fr"),
+            ("light", Some(Locale::Fr))
+        );
+        assert_eq!(Locale::split_username("light"), ("light", None));
+        assert_eq!(
+            Locale::split_username("solarized-light"),
+            ("solarized-light", None)
+        );
+    }
+
+    #[test]
+    fn path_prefixes() {
+        assert_eq!(
+            Locale::strip_path_prefix("/de/blog"),
+            (Some(Locale::De), "/blog")
+        );
+        assert_eq!(Locale::strip_path_prefix("/fr"), (Some(Locale::Fr), "/"));
+        assert_eq!(Locale::strip_path_prefix("/fr/"), (Some(Locale::Fr), "/"));
+        assert_eq!(Locale::strip_path_prefix("/deno"), (None, "/deno"));
+        assert_eq!(Locale::strip_path_prefix("/blog"), (None, "/blog"));
+    }
+}
@@ -0,0 +1,409 @@
+//! Convert Markdown into [`PostPart`]s, for people who want to serve their own
+//! posts instead of crawling matdoes.dev, and back into Markdown so posts can
+//! be downloaded as it.
+
+use std::path::PathBuf;
+
+use pulldown_cmark::{Event, Options, Parser, Tag};
+
+use crate::{
+    crawl::{list_lines, ImageSource, ListItem, Post, PostPart},
+    links::{self, Href},
+    protocols::render::{self, Link, PostVisitor},
+    routes::Scheme,
+};
+
+/// An element that we're inside of while going through the Markdown events.
+struct Frame {
+    kind: FrameKind,
+    /// The text inside of the element, if it's one that collects text.
+    text: String,
+}
+
+enum FrameKind {
+    Paragraph,
+    Heading(usize),
+    Quote,
+    CodeBlock,
+    Italic,
+    Bold,
+    Link(String),
+    Image(String),
+    /// A footnote's text, with its label.
+    Footnote(String),
+    List {
+        ordered: bool,
+        items: Vec<ListItem>,
+    },
+    Item {
+        children: Vec<PostPart>,
+    },
+    Table {
+        rows: Vec<Vec<String>>,
+    },
+    Row {
+        cells: Vec<String>,
+    },
+    Cell,
+    Other,
+}
+
+impl Frame {
+    /// Whether text inside of this element should be added to it, instead of
+    /// becoming its own part.
+    fn collects_text(&self) -> bool {
+        !matches!(
+            self.kind,
+            FrameKind::Paragraph
+                | FrameKind::List { .. }
+                | FrameKind::Table { .. }
+                | FrameKind::Row { .. }
+                | FrameKind::Other
+        )
+    }
+}
+
+/// Add the text to the innermost element that collects text, or add it as a
+/// part if there's none.
+fn push_text(frames: &mut [Frame], parts: &mut Vec<PostPart>, text: &str, part: PostPart) {
+    match frames.iter_mut().rev().find(|f| f.collects_text()) {
+        Some(frame) => frame.text.push_str(text),
+        None => parts.push(part),
+    }
+}
+
+/// Local images are returned as [`ImageSource::Local`] with the path exactly
+/// as it was written in the Markdown, so they still have to be resolved.
+pub fn to_post_parts(markdown: &str) -> Vec<PostPart> {
+    let mut parts = Vec::new();
+    let mut frames: Vec<Frame> = Vec::new();
+
+    for event in Parser::new_ext(markdown, Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES) {
+        match event {
+            Event::Start(tag) => {
+                let kind = match tag {
+                    Tag::Paragraph => FrameKind::Paragraph,
+                    Tag::Heading { level, .. } => FrameKind::Heading(level as usize),
+                    Tag::BlockQuote { .. } => FrameKind::Quote,
+                    Tag::CodeBlock(_) => FrameKind::CodeBlock,
+                    Tag::Emphasis => FrameKind::Italic,
+                    Tag::Strong => FrameKind::Bold,
+                    Tag::Link { dest_url, .. } => FrameKind::Link(dest_url.into_string()),
+                    Tag::Image { dest_url, .. } => FrameKind::Image(dest_url.into_string()),
+                    Tag::List(start) => FrameKind::List {
+                        ordered: start.is_some(),
+                        items: Vec::new(),
+                    },
+                    Tag::Item => FrameKind::Item {
+                        children: Vec::new(),
+                    },
+                    Tag::Table(_) => FrameKind::Table { rows: Vec::new() },
+                    // the header doesn't have its own row
+                    Tag::TableHead | Tag::TableRow => FrameKind::Row { cells: Vec::new() },
+                    Tag::TableCell => FrameKind::Cell,
+                    Tag::FootnoteDefinition(label) => FrameKind::Footnote(label.into_string()),
+                    _ => FrameKind::Other,
+                };
+                frames.push(Frame {
+                    kind,
+                    text: String::new(),
+                });
+            }
+            Event::End(_) => {
+                let Some(Frame { kind, text }) = frames.pop() else {
+                    continue;
+                };
+                match kind {
+                    FrameKind::Paragraph => {
+                        push_text(&mut frames, &mut parts, "\n", PostPart::LineBreak);
+                    }
+                    FrameKind::Heading(level) => parts.push(PostPart::Heading {
+                        level,
+                        text: text.trim().to_owned(),
+                    }),
+                    FrameKind::Quote => parts.push(PostPart::Quote(text.trim_end().to_owned())),
+                    FrameKind::CodeBlock => {
+                        parts.push(PostPart::CodeBlock(text.trim_end_matches('\n').to_owned()))
+                    }
+                    FrameKind::Italic => push_text(
+                        &mut frames,
+                        &mut parts,
+                        &text,
+                        PostPart::Italic(text.clone()),
+                    ),
+                    FrameKind::Bold => {
+                        push_text(&mut frames, &mut parts, &text, PostPart::Bold(text.clone()))
+                    }
+                    FrameKind::Link(href) => push_text(
+                        &mut frames,
+                        &mut parts,
+                        &text,
+                        PostPart::Link {
+                            text: text.clone(),
+                            href,
+                        },
+                    ),
+                    FrameKind::Image(src) => parts.push(PostPart::Image {
+                        src: if src.contains("://") {
+                            ImageSource::Remote(src)
+                        } else {
+                            ImageSource::Local(PathBuf::from(src))
+                        },
+                        alt: Some(text).filter(|alt| !alt.is_empty()),
+                    }),
+                    FrameKind::List { ordered, items } => {
+                        let list = PostPart::List { ordered, items };
+                        match frames.last_mut() {
+                            Some(Frame {
+                                kind: FrameKind::Item { children },
+                                ..
+                            }) => children.push(list),
+                            _ => parts.push(list),
+                        }
+                    }
+                    FrameKind::Item { children } => {
+                        if let Some(Frame {
+                            kind: FrameKind::List { items, .. },
+                            ..
+                        }) = frames.last_mut()
+                        {
+                            items.push(ListItem {
+                                text: text.trim().to_owned(),
+                                children,
+                            });
+                        }
+                    }
+                    FrameKind::Table { rows } => parts.push(PostPart::Table(rows)),
+                    FrameKind::Row { cells } => {
+                        if let Some(Frame {
+                            kind: FrameKind::Table { rows },
+                            ..
+                        }) = frames.last_mut()
+                        {
+                            rows.push(cells);
+                        }
+                    }
+                    FrameKind::Cell => {
+                        if let Some(Frame {
+                            kind: FrameKind::Row { cells },
+                            ..
+                        }) = frames.last_mut()
+                        {
+                            cells.push(text.trim().to_owned());
+                        }
+                    }
+                    FrameKind::Footnote(id) => parts.push(PostPart::Footnote {
+                        id,
+                        text: text.trim().to_owned(),
+                    }),
+                    FrameKind::Other => {}
+                }
+            }
+            Event::Text(text) => push_text(
+                &mut frames,
+                &mut parts,
+                &text,
+                PostPart::Text(text.to_string()),
+            ),
+            Event::Code(code) => push_text(
+                &mut frames,
+                &mut parts,
+                &code,
+                PostPart::InlineCode(code.to_string()),
+            ),
+            Event::SoftBreak => {
+                push_text(&mut frames, &mut parts, " ", PostPart::Text(" ".to_owned()))
+            }
+            Event::HardBreak => push_text(&mut frames, &mut parts, "\n", PostPart::LineBreak),
+            Event::Rule => parts.push(PostPart::HorizontalRule),
+            Event::FootnoteReference(label) => push_text(
+                &mut frames,
+                &mut parts,
+                &format!("[{label}]"),
+                PostPart::FootnoteReference(label.to_string()),
+            ),
+            _ => {}
+        }
+    }
+
+    parts
+}
+
+/// The post as a Markdown document, with its title as the first heading.
+pub fn from_post(post: &Post) -> String {
+    let mut markdown = format!("# {}\n\n", post.title);
+    markdown.push_str(from_parts(&post.content).trim_end());
+    markdown.push('\n');
+    markdown
+}
+
+fn from_parts(parts: &[PostPart]) -> String {
+    let mut markdown = String::new();
+    render::visit_post(parts, &mut MarkdownPost(&mut markdown));
+    markdown
+}
+
+/// Writes a post's parts as Markdown. Links to our own pages are made absolute
+/// since the file won't be on the site.
+struct MarkdownPost<'a>(&'a mut String);
+
+impl MarkdownPost<'_> {
+    /// Blocks have to start on their own line.
+    fn start_block(&mut self) {
+        if !self.0.is_empty() && !self.0.ends_with('\n') {
+            self.0.push('\n');
+        }
+    }
+}
+
+impl PostVisitor for MarkdownPost<'_> {
+    fn text(&mut self, text: &str) {
+        self.0.push_str(text);
+    }
+
+    fn inline_code(&mut self, code: &str) {
+        // a code span can have backticks in it if it's surrounded by more
+        if code.contains('`') {
+            self.0.push_str(&format!("`` {code} ``"));
+        } else {
+            self.0.push_str(&format!("`{code}`"));
+        }
+    }
+
+    fn code_block(&mut self, code: &str) {
+        self.start_block();
+        self.0.push_str(&format!("```\n{code}\n```\n\n"));
+    }
+
+    fn italic(&mut self, text: &str) {
+        self.0.push_str(&format!("*{text}*"));
+    }
+
+    fn bold(&mut self, text: &str) {
+        self.0.push_str(&format!("**{text}**"));
+    }
+
+    fn image(&mut self, src: &ImageSource, alt: Option<&str>) {
+        let href = match src {
+            ImageSource::Local(path) => render::media_path(path),
+            ImageSource::Remote(url) => url.to_owned(),
+        };
+        let alt = alt.unwrap_or_default();
+        self.0.push_str(&format!("![{alt}]({href})"));
+    }
+
+    fn link(&mut self, text: &str, href: &str) {
+        let href = match Href::classify(href, Scheme::Http) {
+            Href::Internal(path) => links::absolute(path),
+            href => href.into_href(),
+        };
+        self.0.push_str(&format!("[{text}]({href})"));
+    }
+
+    fn line_break(&mut self, repeated: bool, _links: &[Link]) {
+        if !repeated {
+            self.0.push_str("\n\n");
+        }
+    }
+
+    fn heading(&mut self, level: usize, text: &str, _after_line_break: bool) {
+        self.start_block();
+        self.0
+            .push_str(&format!("{} {text}\n\n", "#".repeat(level.clamp(1, 6))));
+    }
+
+    fn quote(&mut self, text: &str) {
+        self.start_block();
+        for line in text.lines() {
+            self.0.push_str(&format!("> {line}\n"));
+        }
+        self.0.push('\n');
+    }
+
+    fn footnote_reference(&mut self, label: &str) {
+        self.0.push_str(&format!("[^{label}]"));
+    }
+
+    fn footnotes(&mut self, footnotes: &[(&str, &str)]) {
+        self.start_block();
+        for (id, text) in footnotes {
+            self.0.push_str(&format!("\n[^{id}]: {text}\n"));
+        }
+    }
+
+    fn horizontal_rule(&mut self) {
+        self.start_block();
+        self.0.push_str("\n---\n\n");
+    }
+
+    fn definition_list(&mut self, definitions: &[(String, String)]) {
+        self.start_block();
+        for (term, definition) in definitions {
+            self.0.push_str(&format!("{term}\n: {definition}\n\n"));
+        }
+    }
+
+    fn caption(&mut self, text: &str) {
+        self.start_block();
+        self.0.push_str(&format!("*{text}*\n\n"));
+    }
+
+    fn table(&mut self, rows: &[Vec<String>]) {
+        let Some(header) = rows.first() else {
+            return;
+        };
+        self.start_block();
+        let row = |cells: &[String]| {
+            let cells = cells
+                .iter()
+                .map(|cell| cell.replace('|', "\\|"))
+                .collect::<Vec<_>>();
+            format!("| {} |\n", cells.join(" | "))
+        };
+        self.0.push_str(&row(header));
+        self.0
+            .push_str(&format!("|{}\n", " --- |".repeat(header.len())));
+        for cells in &rows[1..] {
+            self.0.push_str(&row(cells));
+        }
+        self.0.push('\n');
+    }
+
+    fn list(&mut self, ordered: bool, items: &[ListItem], _after_line_break: bool) {
+        self.start_block();
+        for line in list_lines(ordered, items) {
+            let marker = match line.number {
+                Some(number) => format!("{number}."),
+                None => "-".to_owned(),
+            };
+            // four spaces is enough for the items of either kind of list
+            let indent = "    ".repeat(line.depth);
+            self.0
+                .push_str(&format!("{indent}{marker} {}\n", line.text));
+        }
+        self.0.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let markdown =
+            "Some *italic* and **bold** text with `code` and [a link](https://example.com).\n\n\
+            A sentence with a note.[^1]\n\n\
+            ## A heading\n\n\
+            > a quote\n\n\
+            ```\nfn main() {}\n```\n\n\
+            1. one\n\
+            2. two\n\n\
+            | a | b |\n\
+            | --- | --- |\n\
+            | c | d |\n\n\
+            [^1]: The note.\n";
+        let parts = to_post_parts(markdown);
+        assert_eq!(to_post_parts(&from_parts(&parts)), parts);
+    }
+}
@@ -0,0 +1,369 @@
+//! Sending files from the media directory. Big files are streamed from disk,
+//! and small ones are kept in memory since the same few images get requested
+//! over and over.
+//!
+//! Downloaded files are named after the hash of their contents (see
+//! [`hashed_path`]), and they're checked against it before they're sent so a
+//! download that was cut off is never served.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, SeekFrom},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, LazyLock},
+    time::SystemTime,
+};
+
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+};
+
+/// Files with names like this in the media directory are named after their
+/// SHA-256 hash.
+const HASHED_DIR: &str = "sha256";
+
+/// Files bigger than this are always streamed from disk.
+const MAX_CACHED_FILE_SIZE: u64 = 256 * 1024;
+/// How many bytes of files can be in the cache at once.
+const CACHE_CAPACITY: usize = 32 * 1024 * 1024;
+
+static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(Default::default);
+/// The hashed files that matched their hash, and when they were modified then.
+static VERIFIED: LazyLock<Mutex<HashMap<PathBuf, SystemTime>>> = LazyLock::new(Default::default);
+
+/// The least recently used files are evicted first.
+#[derive(Default)]
+struct Cache {
+    files: HashMap<PathBuf, CachedFile>,
+    /// The least recently used path is at the front.
+    order: VecDeque<PathBuf>,
+    size: usize,
+}
+
+struct CachedFile {
+    /// So we know if the file was changed after it was cached.
+    modified: Option<SystemTime>,
+    bytes: Arc<[u8]>,
+}
+
+impl Cache {
+    fn get(&mut self, path: &Path, modified: Option<SystemTime>) -> Option<Arc<[u8]>> {
+        let file = self.files.get(path)?;
+        if file.modified != modified {
+            self.remove(path);
+            return None;
+        }
+        let bytes = Arc::clone(&file.bytes);
+        self.order.retain(|p| p != path);
+        self.order.push_back(path.to_owned());
+        Some(bytes)
+    }
+
+    fn insert(&mut self, path: PathBuf, modified: Option<SystemTime>, bytes: Arc<[u8]>) {
+        self.remove(&path);
+        self.size += bytes.len();
+        self.order.push_back(path.clone());
+        self.files.insert(path, CachedFile { modified, bytes });
+        while self.size > CACHE_CAPACITY {
+            let Some(oldest) = self.order.front().cloned() else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(file) = self.files.remove(path) {
+            self.size -= file.bytes.len();
+            self.order.retain(|p| p != path);
+        }
+    }
+}
+
+const MEDIA_DIR: &str = "media";
+
+/// Get the path of a file in the media directory, or `None` if it doesn't
+/// exist or would be outside of it. Hidden files like `.git` are never
+/// resolved, and symlinks only are if they point somewhere in the directory.
+pub fn resolve(relative_path: &str) -> Option<PathBuf> {
+    resolve_in(Path::new(MEDIA_DIR), relative_path)
+}
+
+/// Like [`resolve`], but for a file in any directory.
+pub fn resolve_in(root: &Path, relative_path: &str) -> Option<PathBuf> {
+    let relative_path = Path::new(relative_path);
+    let is_safe = relative_path.components().all(|c| match c {
+        Component::Normal(name) => !name.to_string_lossy().starts_with('.'),
+        _ => false,
+    });
+    if !is_safe {
+        return None;
+    }
+
+    // canonicalizing follows symlinks, so this also catches ones that point
+    // outside of the directory
+    let root = root.canonicalize().ok()?;
+    let path = root.join(relative_path).canonicalize().ok()?;
+    path.starts_with(&root).then_some(path)
+}
+
+/// Where a file with these contents goes in the media directory. The
+/// extension is kept so the type can still be guessed from the name.
+pub fn hashed_path(bytes: &[u8], extension: Option<&str>) -> PathBuf {
+    let mut name = hex(&Sha256::digest(bytes));
+    if let Some(extension) = extension {
+        name.push('.');
+        name.push_str(extension);
+    }
+    Path::new(MEDIA_DIR).join(HASHED_DIR).join(name)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Whether the file is named after its hash, so it can be verified.
+pub fn is_hashed(path: &Path) -> bool {
+    expected_hash(path).is_some()
+}
+
+/// The hash that a file should have, if it's named after one.
+fn expected_hash(path: &Path) -> Option<&str> {
+    if path.parent()?.file_name()? != HASHED_DIR {
+        return None;
+    }
+    let hash = path.file_name()?.to_str()?.split('.').next()?;
+    let is_hash = hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit());
+    is_hash.then_some(hash)
+}
+
+/// Make sure that a file named after its hash still has that hash. Files are
+/// only checked again after they're modified, since it means reading all of
+/// it.
+async fn verify(file: &mut File, path: &Path, modified: Option<SystemTime>) -> io::Result<()> {
+    let Some(expected) = expected_hash(path) else {
+        return Ok(());
+    };
+    if modified.is_some() && VERIFIED.lock().get(path) == modified.as_ref() {
+        return Ok(());
+    }
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    file.seek(SeekFrom::Start(0)).await?;
+
+    if hex(&hasher.finalize()) != expected {
+        eprintln!("{path:?} doesn't match its hash, so it won't be sent");
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "file doesn't match its hash",
+        ));
+    }
+    if let Some(modified) = modified {
+        VERIFIED.lock().insert(path.to_owned(), modified);
+    }
+    Ok(())
+}
+
+pub enum Media {
+    Cached {
+        bytes: Arc<[u8]>,
+        modified: Option<SystemTime>,
+    },
+    File {
+        file: File,
+        len: u64,
+        modified: Option<SystemTime>,
+    },
+}
+
+impl Media {
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path).await?;
+        let metadata = file.metadata().await?;
+        if !metadata.is_file() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let modified = metadata.modified().ok();
+
+        if let Some(bytes) = CACHE.lock().get(path, modified) {
+            return Ok(Media::Cached { bytes, modified });
+        }
+        verify(&mut file, path, modified).await?;
+        if metadata.len() > MAX_CACHED_FILE_SIZE {
+            return Ok(Media::File {
+                file,
+                len: metadata.len(),
+                modified,
+            });
+        }
+
+        let mut bytes = Vec::with_capacity(metadata.len() as usize);
+        file.read_to_end(&mut bytes).await?;
+        let bytes = Arc::<[u8]>::from(bytes);
+        CACHE
+            .lock()
+            .insert(path.to_owned(), modified, Arc::clone(&bytes));
+        Ok(Media::Cached { bytes, modified })
+    }
+
+    pub fn size(&self) -> u64 {
+        match self {
+            Media::Cached { bytes, .. } => bytes.len() as u64,
+            Media::File { len, .. } => *len,
+        }
+    }
+
+    pub fn modified(&self) -> Option<SystemTime> {
+        match self {
+            Media::Cached { modified, .. } | Media::File { modified, .. } => *modified,
+        }
+    }
+
+    /// Read the whole file, for when it has to be changed before it's sent.
+    pub fn into_reader(self) -> Box<dyn AsyncRead + Send + Unpin> {
+        match self {
+            Media::Cached { bytes, .. } => Box::new(io::Cursor::new(bytes)),
+            Media::File { file, .. } => Box::new(file),
+        }
+    }
+
+    /// Write the whole file.
+    pub async fn send<W: AsyncWrite + Unpin + ?Sized>(self, writer: &mut W) -> io::Result<()> {
+        let len = self.size();
+        self.send_range(writer, 0, len).await
+    }
+
+    /// Write the bytes from `start` up to but not including `end`.
+    pub async fn send_range<W: AsyncWrite + Unpin + ?Sized>(
+        self,
+        writer: &mut W,
+        start: u64,
+        end: u64,
+    ) -> io::Result<()> {
+        match self {
+            Media::Cached { bytes, .. } => {
+                writer.write_all(&bytes[start as usize..end as usize]).await
+            }
+            Media::File { mut file, .. } => {
+                file.seek(SeekFrom::Start(start)).await?;
+                tokio::io::copy(&mut file.take(end - start), writer).await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use super::{expected_hash, hashed_path, resolve_in};
+
+    /// A media directory with a file outside of it, which is removed when
+    /// it's dropped.
+    struct TestDir {
+        dir: PathBuf,
+    }
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("matdoesdev-media-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("media/images")).unwrap();
+            fs::create_dir_all(dir.join("media/.hidden")).unwrap();
+            fs::write(dir.join("media/images/cat.png"), "cat").unwrap();
+            fs::write(dir.join("media/.hidden/file.txt"), "hidden").unwrap();
+            fs::write(dir.join("media/.env"), "secret").unwrap();
+            fs::write(dir.join("secret.txt"), "secret").unwrap();
+            TestDir { dir }
+        }
+
+        fn resolve(&self, relative_path: &str) -> Option<PathBuf> {
+            resolve_in(&self.dir.join("media"), relative_path)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn resolves_files() {
+        let dir = TestDir::new("files");
+        let path = dir.resolve("images/cat.png").unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "cat");
+        assert!(dir.resolve("images/dog.png").is_none());
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        let dir = TestDir::new("traversal");
+        for path in [
+            "../secret.txt",
+            "images/../../secret.txt",
+            "images/../images/cat.png",
+            "/etc/passwd",
+            "./images/cat.png",
+        ] {
+            assert!(dir.resolve(path).is_none(), "{path} was resolved");
+        }
+    }
+
+    #[test]
+    fn rejects_hidden_files() {
+        let dir = TestDir::new("hidden");
+        assert!(dir.resolve(".env").is_none());
+        assert!(dir.resolve(".hidden/file.txt").is_none());
+    }
+
+    #[test]
+    fn hashed_paths_have_their_hash() {
+        let path = hashed_path(b"cat", Some("png"));
+        assert_eq!(
+            path,
+            PathBuf::from(
+                "media/sha256/77af778b51abd4a3c51c5ddd97204a9c3ae614ebccb75a606c3b6865aed6744e.png"
+            )
+        );
+        assert_eq!(
+            expected_hash(&path),
+            Some("77af778b51abd4a3c51c5ddd97204a9c3ae614ebccb75a606c3b6865aed6744e")
+        );
+        assert_eq!(expected_hash(Path::new("media/images/cat.png")), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_follows_symlinks_inside() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TestDir::new("symlinks");
+        symlink(dir.dir.join("secret.txt"), dir.dir.join("media/escape.txt")).unwrap();
+        symlink(
+            dir.dir.join("media/images/cat.png"),
+            dir.dir.join("media/kitten.png"),
+        )
+        .unwrap();
+
+        assert!(dir.resolve("escape.txt").is_none());
+        let path = dir.resolve("kitten.png").unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "cat");
+    }
+}
@@ -0,0 +1,99 @@
+//! The message of the day that's shown when someone connects over telnet or
+//! SSH, and at the bottom of the terminal's home page. It's put together from
+//! fragments every time someone connects, so the quote and the uptime are
+//! current. Which fragments are shown can be changed with
+//! `--motd art,qotd,latest-post,uptime`.
+
+use std::sync::OnceLock;
+
+use crate::{
+    banner,
+    crawl::SiteData,
+    drafts,
+    protocols::qotd::Qotd,
+    server_info::{self, human_duration},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fragment {
+    /// The banner, small enough for an 80 column terminal.
+    Art,
+    Qotd,
+    /// The title of the newest published post.
+    LatestPost,
+    /// How long the server has been running.
+    Uptime,
+}
+
+const DEFAULT_FRAGMENTS: &[Fragment] = &[
+    Fragment::Art,
+    Fragment::Qotd,
+    Fragment::LatestPost,
+    Fragment::Uptime,
+];
+
+static FRAGMENTS: OnceLock<Vec<Fragment>> = OnceLock::new();
+
+impl Fragment {
+    fn from_name(name: &str) -> Option<Fragment> {
+        match name {
+            "art" => Some(Fragment::Art),
+            "qotd" => Some(Fragment::Qotd),
+            "latest-post" => Some(Fragment::LatestPost),
+            "uptime" => Some(Fragment::Uptime),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a `--motd` argument, which is a comma-separated list of fragments.
+/// It can be empty to not show a message at all.
+pub fn parse_arg(arg: &str) -> Option<Vec<Fragment>> {
+    arg.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(Fragment::from_name)
+        .collect()
+}
+
+/// Change which fragments are shown from the defaults. This can only be done
+/// once, before any of the servers start.
+pub fn set_fragments(fragments: Option<Vec<Fragment>>) {
+    if let Some(fragments) = fragments {
+        FRAGMENTS
+            .set(fragments)
+            .expect("motd fragments were already set");
+    }
+}
+
+pub fn fragments() -> &'static [Fragment] {
+    FRAGMENTS.get().map_or(DEFAULT_FRAGMENTS, Vec::as_slice)
+}
+
+/// The message made of the fragments, with a blank line between each of them.
+/// Fragments that don't have anything to show (like the quote when there
+/// aren't any) are left out. The lines are separated by `\n`.
+pub fn compose<'a>(
+    fragments: impl IntoIterator<Item = &'a Fragment>,
+    site_data: &SiteData,
+    qotd: &Qotd,
+) -> String {
+    fragments
+        .into_iter()
+        .filter_map(|fragment| {
+            let text = match fragment {
+                Fragment::Art => banner::fit(banner::NAME, 80),
+                Fragment::Qotd => String::from_utf8_lossy(&qotd.message.read())
+                    .trim()
+                    .to_owned(),
+                Fragment::LatestPost => {
+                    let post = drafts::published(&site_data.blog).max_by_key(|p| p.published)?;
+                    format!("Latest post: {}", post.title)
+                }
+                Fragment::Uptime => format!("Up for {}", human_duration(server_info::uptime())),
+            };
+            (!text.is_empty()).then_some(text)
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
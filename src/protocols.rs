@@ -1,14 +1,260 @@
-use crate::crawl::SiteData;
+use std::{future::Future, path::PathBuf, pin::Pin, sync::Arc};
 
+use futures_util::future::join_all;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::{crawl::SiteData, integrations::matrix};
+
+pub mod alternate;
+pub mod dict;
+pub mod discard;
+pub mod error;
 pub mod finger;
 pub mod gemini;
 pub mod gopher;
 pub mod http;
+pub mod ident;
+pub mod imap;
+mod mail_render;
+pub mod minecraft_ping;
+pub mod modbus;
+pub mod mqtt;
+pub mod mux;
+pub mod nex;
+pub mod plain_text;
+pub mod pop3;
 pub mod qotd;
+pub mod render;
+pub mod scroll;
 pub mod ssh;
 pub mod telnet;
+pub mod tracker;
 
+/// A server for one protocol. These are made with a [`ServerBuilder`] instead
+/// of directly, so they can be regenerated when the site data changes.
+// the servers aren't all Send, so there's no bound to put on the future
+#[allow(async_fn_in_trait)]
 pub trait Protocol {
-    fn generate(data: &SiteData) -> Self;
-    async fn serve(self);
+    /// What can be set about the server besides its content and ports, like
+    /// the quote of the day that it shares with the other servers.
+    type Options: Clone + Default;
+
+    fn generate(data: &SiteData, options: &Self::Options) -> Self;
+    /// Listen until `shutdown` is triggered. Connections that are already open
+    /// keep going after that, since they're spawned.
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown);
+}
+
+/// How a server listens, for when the usual ports aren't wanted.
+#[derive(Clone, Debug, Default)]
+pub struct ServerConfig {
+    /// Instead of the protocol's usual port.
+    pub port: Option<u16>,
+    /// Instead of the usual port for the protocols that also listen with TLS.
+    pub tls_port: Option<u16>,
+}
+
+impl ServerConfig {
+    pub fn port_or(&self, default: u16) -> u16 {
+        self.port.unwrap_or(default)
+    }
+
+    pub fn tls_port_or(&self, default: u16) -> u16 {
+        self.tls_port.unwrap_or(default)
+    }
+}
+
+/// Tells servers to stop listening. Every clone is triggered together.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown(CancellationToken);
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.0.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.is_cancelled()
+    }
+
+    pub async fn triggered(&self) {
+        self.0.cancelled().await;
+    }
+
+    /// Run the future until it finishes, or drop it when shutdown is
+    /// triggered.
+    pub async fn run<F: Future>(&self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            output = future => Some(output),
+            _ = self.triggered() => None,
+        }
+    }
+}
+
+/// The options and ports for a server, before it has any content.
+pub struct ServerBuilder<P: Protocol> {
+    options: P::Options,
+    config: ServerConfig,
+}
+
+impl<P: Protocol> Default for ServerBuilder<P> {
+    fn default() -> Self {
+        ServerBuilder {
+            options: P::Options::default(),
+            config: ServerConfig::default(),
+        }
+    }
+}
+
+impl<P: Protocol> ServerBuilder<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn options(mut self, options: P::Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Listen on this port instead of the protocol's usual one.
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = Some(port);
+        self
+    }
+
+    /// Listen with TLS on this port instead of the usual one, for the
+    /// protocols that do.
+    pub fn tls_port(mut self, port: u16) -> Self {
+        self.config.tls_port = Some(port);
+        self
+    }
+
+    /// Give the server its content. It's generated when it starts serving.
+    pub fn build(self, data: impl Into<Arc<SiteData>>) -> ServerHandle<P> {
+        ServerHandle {
+            options: self.options,
+            config: self.config,
+            data: Arc::new(watch::Sender::new(data.into())),
+        }
+    }
+}
+
+/// A server that can be started and given new content. Clones are the same
+/// server, so one can be serving while another reloads it.
+pub struct ServerHandle<P: Protocol> {
+    options: P::Options,
+    config: ServerConfig,
+    data: Arc<watch::Sender<Arc<SiteData>>>,
+}
+
+impl<P: Protocol> Clone for ServerHandle<P> {
+    fn clone(&self) -> Self {
+        ServerHandle {
+            options: self.options.clone(),
+            config: self.config.clone(),
+            data: Arc::clone(&self.data),
+        }
+    }
+}
+
+impl<P: Protocol> ServerHandle<P> {
+    /// Serve until `shutdown` is triggered or the server stops by itself, like
+    /// when it can't listen. The server is generated again every time it's
+    /// reloaded, and connections that were already open keep the old content.
+    pub async fn serve(&self, shutdown: Shutdown) {
+        let mut data = self.data.subscribe();
+        loop {
+            let server = P::generate(&data.borrow_and_update(), &self.options);
+            tokio::select! {
+                _ = server.serve(&self.config, shutdown.clone()) => {
+                    if !shutdown.is_triggered() {
+                        // like `Gemini`, since the protocols don't have names
+                        let name = std::any::type_name::<P>().rsplit("::").next().unwrap();
+                        matrix::notify(format!("The {name} server stopped"));
+                    }
+                    return;
+                }
+                // the sender can't be dropped while we have a handle
+                _ = data.changed() => {}
+            }
+        }
+    }
+
+    /// Replace the content that's being served.
+    pub fn reload(&self, data: impl Into<Arc<SiteData>>) {
+        self.data.send_replace(data.into());
+    }
+}
+
+/// A set of servers that run together until they're shut down, for embedding
+/// some of the protocols with your own content:
+///
+/// ```no_run
+/// # use matdoesdev_protocols::{protocols::{gemini::Gemini, gopher::Gopher}, *};
+/// # async fn example(data: SiteData) {
+/// let data = std::sync::Arc::new(data);
+/// let gemini = ServerBuilder::<Gemini>::new().build(data.clone());
+/// let gopher = ServerBuilder::<Gopher>::new().port(7070).build(data);
+/// let server = ProtocolServer::new().with(&gemini).with(&gopher);
+/// tokio::spawn(async move {
+///     // later, when the content changes
+///     gemini.reload(SiteData::default());
+/// });
+/// server.serve().await;
+/// # }
+/// ```
+#[derive(Default)]
+pub struct ProtocolServer {
+    servers: Vec<Pin<Box<dyn Future<Output = ()>>>>,
+    shutdown: Shutdown,
+}
+
+impl ProtocolServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<P>(mut self, server: &ServerHandle<P>) -> Self
+    where
+        P: Protocol + 'static,
+        P::Options: 'static,
+    {
+        let server = server.clone();
+        let shutdown = self.shutdown.clone();
+        self.servers
+            .push(Box::pin(async move { server.serve(shutdown).await }));
+        self
+    }
+
+    /// Triggering this makes [`Self::serve`] finish.
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Run every server until they stop or shutdown is triggered.
+    pub async fn serve(self) {
+        join_all(self.servers).await;
+    }
+}
+
+/// A page that was generated by a protocol, so it can be written to a file and
+/// served by something else.
+pub struct Artifact {
+    /// Relative to the directory that the protocol is being exported to.
+    pub path: PathBuf,
+    pub content: String,
+}
+
+impl Artifact {
+    pub fn new(path: impl Into<PathBuf>, content: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            content: content.into(),
+        }
+    }
+}
+
+pub trait Export {
+    fn artifacts(&self) -> Vec<Artifact>;
 }
@@ -0,0 +1,104 @@
+//! Every post as a file in a few formats, so it can be saved or read with
+//! something other than the protocol's own client. Each protocol serves them
+//! at the same paths, like `/raw/<slug>.txt`.
+
+use std::collections::HashMap;
+
+use super::{gemini, plain_text};
+use crate::{crawl::Post, drafts, markdown};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlternateFormat {
+    PlainText,
+    Gemtext,
+    Markdown,
+}
+
+impl AlternateFormat {
+    pub const ALL: [AlternateFormat; 3] = [
+        AlternateFormat::PlainText,
+        AlternateFormat::Gemtext,
+        AlternateFormat::Markdown,
+    ];
+
+    fn directory_and_extension(self) -> (&'static str, &'static str) {
+        match self {
+            AlternateFormat::PlainText => ("raw", "txt"),
+            AlternateFormat::Gemtext => ("gmi", "gmi"),
+            AlternateFormat::Markdown => ("md", "md"),
+        }
+    }
+
+    /// Like `/raw/<slug>.txt`. The slug isn't encoded.
+    pub fn path(self, slug: &str) -> String {
+        let (directory, extension) = self.directory_and_extension();
+        format!("/{directory}/{slug}.{extension}")
+    }
+
+    /// The format and the slug for a path like `/md/<slug>.md`.
+    pub fn from_path(path: &str) -> Option<(AlternateFormat, &str)> {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        AlternateFormat::ALL.into_iter().find_map(|format| {
+            let (directory, extension) = format.directory_and_extension();
+            let slug = path
+                .strip_prefix(directory)?
+                .strip_prefix('/')?
+                .strip_suffix(extension)?
+                .strip_suffix('.')?;
+            Some((format, slug))
+        })
+    }
+
+    /// What the links to it say.
+    pub fn name(self) -> &'static str {
+        match self {
+            AlternateFormat::PlainText => "Plain text",
+            AlternateFormat::Gemtext => "Gemtext",
+            AlternateFormat::Markdown => "Markdown",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            AlternateFormat::PlainText => "text/plain; charset=utf-8",
+            AlternateFormat::Gemtext => "text/gemini; charset=utf-8",
+            AlternateFormat::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+
+    pub fn render(self, post: &Post) -> String {
+        match self {
+            AlternateFormat::PlainText => plain_text::raw_post(post),
+            // the ascii art is only for reading it in a gemini client
+            AlternateFormat::Gemtext => gemini::post_gmi(post, &HashMap::new()),
+            AlternateFormat::Markdown => markdown::from_post(post),
+        }
+    }
+}
+
+/// The post at a path like `/raw/<slug>.txt` in the format it asks for, or
+/// `None` if it isn't one of those paths or there's no published post with
+/// the slug.
+pub fn render_path(posts: &[Post], path: &str) -> Option<(AlternateFormat, String)> {
+    let (format, slug) = AlternateFormat::from_path(path)?;
+    let post = drafts::published(posts).find(|post| post.slug == slug)?;
+    Some((format, format.render(post)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_round_trip() {
+        for format in AlternateFormat::ALL {
+            let path = format.path("hello-world");
+            assert_eq!(
+                AlternateFormat::from_path(&path),
+                Some((format, "hello-world"))
+            );
+        }
+        assert_eq!(AlternateFormat::from_path("/raw/hello-world.md"), None);
+        assert_eq!(AlternateFormat::from_path("/hello-world"), None);
+    }
+}
@@ -0,0 +1,351 @@
+//! A DICT server (RFC 2229) where the words are the slugs of the blog posts, so
+//! `dict -h matdoes.dev -d blog <slug>` shows a post. MATCH looks at the titles
+//! too, so posts can be found without knowing the slug.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{analytics, crawl::SiteData, drafts, lifecycle, listen::Listener, timeouts, HOSTNAME};
+
+use super::{
+    plain_text::{Links, PlainTextSite},
+    Protocol, ServerConfig, Shutdown,
+};
+
+const BIND_PORT: u16 = 2628;
+/// The RFC says commands can't be longer than this.
+const MAX_COMMAND_LENGTH: u64 = 1024;
+/// How long clients can go between commands before they're disconnected.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+const DATABASE: &str = "blog";
+const DATABASE_DESCRIPTION: &str = "matdoes.dev blog posts";
+/// The default strategy (`.`) is the first one.
+const STRATEGIES: &[(&str, &str)] = &[
+    ("substring", "Match the slug or title anywhere"),
+    ("exact", "Match the slug or title exactly"),
+    ("prefix", "Match the start of the slug or title"),
+];
+
+const HELP: &str = r#"DEFINE database word         -- look up a post by its slug
+MATCH database strategy word -- find posts by their slug or title
+SHOW DB                      -- list all accessible databases
+SHOW STRAT                   -- list available matching strategies
+SHOW INFO database           -- provide information about the database
+SHOW SERVER                  -- provide site-specific information
+OPTION MIME                  -- use MIME headers
+CLIENT info                  -- identify client to server
+STATUS                       -- display timing information
+HELP                         -- display this help information
+QUIT                         -- terminate connection"#;
+
+/// Used for the message ids in the banners.
+static CONNECTION_COUNT: AtomicU64 = AtomicU64::new(0);
+
+pub struct Dict {
+    pub site: PlainTextSite,
+    /// The slugs and titles of the published posts, newest first.
+    pub titles: Vec<(String, String)>,
+}
+
+impl Links for Dict {
+    fn address(path: &str) -> String {
+        format!("dict://{HOSTNAME}/d:{path}:{DATABASE}")
+    }
+}
+
+impl Protocol for Dict {
+    type Options = ();
+
+    fn generate(data: &SiteData, _: &Self::Options) -> Self {
+        Dict {
+            site: PlainTextSite::generate::<Dict>(data),
+            titles: drafts::published(&data.blog)
+                .map(|post| (post.slug.clone(), post.title.clone()))
+                .collect(),
+        }
+    }
+
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        shutdown
+            .run(async move {
+                let dict = Arc::new(self);
+
+                let Some(listener) = Listener::bind("dict", port) else {
+                    return;
+                };
+
+                loop {
+                    let (stream, remote_addr) = listener.accept().await.unwrap();
+                    println!("started tcp connection for dict: {remote_addr:?}");
+
+                    let dict = Arc::clone(&dict);
+                    tokio::spawn(async move {
+                        let _session = lifecycle::Session::start("dict");
+                        if let Err(err) = handle(dict, stream, remote_addr).await {
+                            eprintln!("{:?}", err);
+                        }
+                    });
+                }
+            })
+            .await;
+    }
+}
+
+/// What's remembered between commands on one connection.
+#[derive(Default)]
+struct Session {
+    /// Whether `OPTION MIME` was sent, which makes us put headers before the
+    /// text of every definition.
+    mime: bool,
+    quit: bool,
+}
+
+async fn handle(dict: Arc<Dict>, stream: TcpStream, remote_addr: SocketAddr) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+
+    let id = CONNECTION_COUNT.fetch_add(1, Ordering::Relaxed);
+    write
+        .write_all(
+            format!(
+                "220 {HOSTNAME} matdoesdev <mime> <{id}.{}@{HOSTNAME}>\r\n",
+                std::process::id()
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+    let mut session = Session::default();
+    while !session.quit {
+        // clients can take their time between commands, but each command has
+        // to arrive within the read timeout once it starts
+        if timeout(IDLE_TIMEOUT, read.fill_buf()).await??.is_empty() {
+            break;
+        }
+        let mut line = String::new();
+        let mut limited = (&mut read).take(MAX_COMMAND_LENGTH);
+        timeout(timeouts::read_timeout(), limited.read_line(&mut line)).await??;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        println!("Dict request: {line}");
+
+        let response = respond(&dict, &mut session, line, remote_addr);
+        write.write_all(response.as_bytes()).await?;
+    }
+    write.shutdown().await?;
+    Ok(())
+}
+
+fn respond(dict: &Dict, session: &mut Session, line: &str, remote_addr: SocketAddr) -> String {
+    let Some(args) = parse_command(line) else {
+        return "501 syntax error, illegal parameters\r\n".to_owned();
+    };
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let Some((command, args)) = args.split_first() else {
+        return "500 unknown command\r\n".to_owned();
+    };
+
+    match (command.to_ascii_uppercase().as_str(), args) {
+        ("DEFINE", [database, word]) => {
+            analytics::record(
+                "dict",
+                word,
+                dict.site.posts.contains_key(*word).then_some(*word),
+                remote_addr.ip(),
+            );
+            define(dict, session, database, word)
+        }
+        ("MATCH", [database, strategy, word]) => match_words(dict, database, strategy, word),
+        ("SHOW", [what, rest @ ..]) => match (what.to_ascii_uppercase().as_str(), rest) {
+            ("DB" | "DATABASES", []) => text_response(
+                "110 1 databases present",
+                &format!("{DATABASE} \"{DATABASE_DESCRIPTION}\""),
+            ),
+            ("STRAT" | "STRATEGIES", []) => text_response(
+                &format!("111 {} strategies available", STRATEGIES.len()),
+                &STRATEGIES
+                    .iter()
+                    .map(|(name, description)| format!("{name} \"{description}\""))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+            ("INFO", [database]) => {
+                if !is_our_database(database) {
+                    return invalid_database();
+                }
+                text_response(
+                    "112 database information follows",
+                    &format!(
+                        "{DATABASE_DESCRIPTION}, with {} posts.\n\
+                        The words are the slugs of the posts, like in https://{HOSTNAME}/<slug>",
+                        dict.titles.len()
+                    ),
+                )
+            }
+            ("SERVER", []) => text_response(
+                "114 server information follows",
+                &format!(
+                    "{HOSTNAME}\nThe blog is also at https://{HOSTNAME} and gemini://{HOSTNAME}."
+                ),
+            ),
+            _ => "501 syntax error, illegal parameters\r\n".to_owned(),
+        },
+        ("OPTION", [option]) if option.eq_ignore_ascii_case("MIME") => {
+            session.mime = true;
+            "250 ok - using MIME headers\r\n".to_owned()
+        }
+        ("CLIENT", [..]) => "250 ok\r\n".to_owned(),
+        ("STATUS", []) => "210 status ok\r\n".to_owned(),
+        ("HELP", []) => text_response("113 help text follows", HELP),
+        ("QUIT", []) => {
+            session.quit = true;
+            "221 bye\r\n".to_owned()
+        }
+        ("AUTH" | "SASLAUTH", _) => "502 command not implemented\r\n".to_owned(),
+        ("DEFINE" | "MATCH" | "SHOW" | "OPTION" | "STATUS" | "HELP" | "QUIT", _) => {
+            "501 syntax error, illegal parameters\r\n".to_owned()
+        }
+        _ => "500 unknown command\r\n".to_owned(),
+    }
+}
+
+fn define(dict: &Dict, session: &Session, database: &str, word: &str) -> String {
+    if !is_our_database(database) {
+        return invalid_database();
+    }
+    let Some(post) = dict.site.posts.get(word) else {
+        return "552 no match\r\n".to_owned();
+    };
+
+    let mut text = String::new();
+    if session.mime {
+        text.push_str(
+            "Content-type: text/plain; charset=utf-8\nContent-transfer-encoding: 8bit\n\n",
+        );
+    }
+    text.push_str(post);
+
+    let mut out = "150 1 definitions retrieved\r\n".to_owned();
+    out.push_str(&text_response(
+        &format!("151 \"{word}\" {DATABASE} \"{DATABASE_DESCRIPTION}\""),
+        &text,
+    ));
+    out.push_str("250 ok\r\n");
+    out
+}
+
+fn match_words(dict: &Dict, database: &str, strategy: &str, word: &str) -> String {
+    if !is_our_database(database) {
+        return invalid_database();
+    }
+    let strategy = if strategy == "." {
+        STRATEGIES[0].0
+    } else {
+        strategy
+    };
+    let word = word.to_lowercase();
+    let matches: fn(&str, &str) -> bool = match strategy {
+        "substring" => |candidate, word| candidate.contains(word),
+        "exact" => |candidate, word| candidate == word,
+        "prefix" => |candidate, word| candidate.starts_with(word),
+        _ => {
+            return "551 invalid strategy, use \"SHOW STRAT\" for a list of strategies\r\n"
+                .to_owned()
+        }
+    };
+
+    let found = dict
+        .titles
+        .iter()
+        .filter(|(slug, title)| {
+            matches(&slug.to_lowercase(), &word) || matches(&title.to_lowercase(), &word)
+        })
+        .map(|(slug, _)| format!("{DATABASE} \"{slug}\""))
+        .collect::<Vec<_>>();
+    if found.is_empty() {
+        return "552 no match\r\n".to_owned();
+    }
+
+    let mut out = text_response(
+        &format!("152 {} matches found", found.len()),
+        &found.join("\n"),
+    );
+    out.push_str("250 ok\r\n");
+    out
+}
+
+/// `*` means every database and `!` means the first one that has a match,
+/// which are both just ours.
+fn is_our_database(database: &str) -> bool {
+    matches!(database, DATABASE | "*" | "!")
+}
+
+fn invalid_database() -> String {
+    "550 invalid database, use \"SHOW DB\" for list of databases\r\n".to_owned()
+}
+
+/// A status line followed by text that ends with a line that only has a dot.
+/// Lines in the text that start with a dot get another one so they aren't
+/// mistaken for the end.
+fn text_response(status: &str, text: &str) -> String {
+    let mut out = format!("{status}\r\n");
+    for line in text.lines() {
+        if line.starts_with('.') {
+            out.push('.');
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+    out.push_str(".\r\n");
+    out
+}
+
+/// Split a command into its words. Words can be quoted with `"` or `'` to have
+/// spaces in them, and backslashes escape the next character. Returns `None`
+/// if a quote isn't closed.
+fn parse_command(line: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            break;
+        };
+
+        let mut word = String::new();
+        let quote = if first == '"' || first == '\'' {
+            chars.next();
+            Some(first)
+        } else {
+            None
+        };
+        loop {
+            match (chars.next(), quote) {
+                (None, Some(_)) => return None,
+                (None, None) => break,
+                (Some(c), Some(quote)) if c == quote => break,
+                (Some(c), None) if c.is_whitespace() => break,
+                (Some('\\'), _) => word.push(chars.next()?),
+                (Some(c), _) => word.push(c),
+            }
+        }
+        words.push(word);
+    }
+    Some(words)
+}
@@ -0,0 +1,102 @@
+//! Discard (RFC 863), sometimes called sink: everything that's sent to it is
+//! thrown away and nothing is ever sent back. It's useful for checking that a
+//! connection can be made at all.
+
+use std::net::SocketAddr;
+
+use futures_util::future::join_all;
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpStream, UdpSocket},
+};
+
+use crate::{
+    access_log, analytics,
+    crawl::SiteData,
+    lifecycle,
+    listen::{self, Listener},
+    timeouts,
+};
+
+use super::{error::ProtocolError, Protocol, ServerConfig, Shutdown};
+
+const BIND_PORT: u16 = {
+    #[cfg(debug_assertions)]
+    {
+        1009
+    }
+    #[cfg(not(debug_assertions))]
+    9
+};
+
+pub struct Discard;
+
+impl Protocol for Discard {
+    type Options = ();
+
+    fn generate(_: &SiteData, _: &Self::Options) -> Self {
+        Discard
+    }
+
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        shutdown
+            .run(async move {
+                tokio::join!(serve_tcp(port), serve_udp(port));
+            })
+            .await;
+    }
+}
+
+async fn serve_tcp(port: u16) {
+    let Some(listener) = Listener::bind("discard", port) else {
+        return;
+    };
+
+    loop {
+        let (stream, remote_addr) = listener.accept().await.unwrap();
+        println!("started tcp connection for discard: {remote_addr:?}");
+        analytics::record("discard", "", None, remote_addr.ip());
+
+        tokio::spawn(async move {
+            let _session = lifecycle::Session::start("discard");
+            if let Err(err) = handle(stream, remote_addr).await {
+                if err.is_internal() {
+                    eprintln!("error handling discard connection: {err}");
+                }
+            }
+        });
+    }
+}
+
+/// Read until the client closes the connection or stops sending anything.
+async fn handle(mut stream: TcpStream, remote_addr: SocketAddr) -> Result<(), ProtocolError> {
+    let mut log = access_log::Entry::start("discard", remote_addr.ip());
+    let mut buf = [0u8; 4096];
+    let mut discarded = 0;
+    let result = loop {
+        match timeouts::read(stream.read(&mut buf)).await {
+            Ok(0) => break Ok(()),
+            Ok(n) => discarded += n as u64,
+            Err(err) => break Err(err),
+        }
+    };
+    log.request(&format!("{discarded} bytes"));
+    log.finish(if result.is_ok() { "ok" } else { "error" }, 0);
+    result
+}
+
+async fn serve_udp(port: u16) {
+    let sockets = listen::bind_udp("discard", port);
+    join_all(sockets.into_iter().map(serve_udp_socket)).await;
+}
+
+async fn serve_udp_socket(socket: UdpSocket) {
+    // the datagrams don't need to be read in full to be thrown away. they
+    // aren't recorded in the analytics either, since their source address can
+    // be spoofed.
+    let mut buf = [0u8; 1];
+    loop {
+        let _ = socket.recv_from(&mut buf).await;
+    }
+}
@@ -0,0 +1,95 @@
+//! Why a request couldn't be answered. The handlers return a
+//! [`ProtocolError`] and each protocol turns it into its own kind of error
+//! response, like a `51` status line in Gemini or a type `3` item in Gopher.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+};
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The request couldn't be understood. The message is shown to the
+    /// client.
+    BadRequest(String),
+    NotFound,
+    /// The request was understood, but it's asking for something we don't
+    /// serve, like a file outside of the media directory. The message is
+    /// shown to the client.
+    PolicyViolation(String),
+    /// The connection was closed before the request was answered, so there's
+    /// no one to send an error to.
+    ClientDisconnected,
+    /// The client took longer than the read timeout to send its request.
+    TimedOut,
+    /// A bug or a problem on our side. The details are logged but not shown to
+    /// the client.
+    Internal(anyhow::Error),
+}
+
+impl ProtocolError {
+    /// Whether it's worth logging, which is only the errors that were our
+    /// fault.
+    pub fn is_internal(&self) -> bool {
+        matches!(self, ProtocolError::Internal(_))
+    }
+
+    /// A short name for the kind of error, for the access logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProtocolError::BadRequest(_) => "bad-request",
+            ProtocolError::NotFound => "not-found",
+            ProtocolError::PolicyViolation(_) => "refused",
+            ProtocolError::ClientDisconnected => "disconnected",
+            ProtocolError::TimedOut => "timed-out",
+            ProtocolError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::BadRequest(message) => write!(f, "bad request: {message}"),
+            ProtocolError::NotFound => write!(f, "not found"),
+            ProtocolError::PolicyViolation(message) => write!(f, "refused: {message}"),
+            ProtocolError::ClientDisconnected => write!(f, "client disconnected"),
+            ProtocolError::TimedOut => write!(f, "timed out"),
+            ProtocolError::Internal(err) => write!(f, "internal error: {err:?}"),
+        }
+    }
+}
+
+// this doesn't implement `std::error::Error`, since then it would also be
+// caught by the `From<Into<anyhow::Error>>` impl on `HttpError`
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => ProtocolError::ClientDisconnected,
+            _ => ProtocolError::Internal(err.into()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ProtocolError {
+    fn from(err: anyhow::Error) -> Self {
+        ProtocolError::Internal(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disconnects_from_io_errors() {
+        let err = ProtocolError::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert!(matches!(err, ProtocolError::ClientDisconnected));
+        let err = ProtocolError::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(err.is_internal());
+    }
+}
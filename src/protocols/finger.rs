@@ -1,18 +1,31 @@
-use std::{collections::HashMap, path::Path, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{tcp::OwnedReadHalf, TcpListener},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
 };
+use tokio_rustls::TlsAcceptor;
 
 use crate::{
-    crawl::{ImageSource, PostPart, SiteData},
-    HOSTNAME,
+    access_log,
+    analytics::{self, Stats},
+    banner,
+    cache::ResponseCache,
+    crawl::{SiteData, STALE_NOTICE},
+    drafts, lifecycle,
+    listen::Listener,
+    search::SearchIndex,
+    server_info, timeouts, tls, HOSTNAME,
 };
 
-use super::Protocol;
+use super::{
+    error::ProtocolError,
+    plain_text::{Links, PlainTextSite},
+    qotd::Qotd,
+    Artifact, Export, Protocol, ServerConfig, Shutdown,
+};
 
-const BIND_HOST: &str = "[::]";
 const BIND_PORT: u16 = {
     #[cfg(debug_assertions)]
     {
@@ -21,244 +34,413 @@ const BIND_PORT: u16 = {
     #[cfg(not(debug_assertions))]
     79
 };
+/// Used when [`Finger::tls`] is enabled.
+const TLS_BIND_PORT: u16 = 7980;
+/// The longest query that we'll read. Finger queries are just a name.
+const MAX_REQUEST_LENGTH: usize = 1024;
 
 #[derive(Clone)]
 pub struct Finger {
     pub index_content: String,
     pub blog_content: String,
+    /// The blog with the longest posts first.
+    pub blog_by_length_content: String,
     pub projects_content: String,
     pub posts_content: HashMap<String, String>,
+    /// Draft posts by their preview token.
+    pub drafts_content: HashMap<String, String>,
+    /// The slug of the most recently published post.
+    pub latest: Option<String>,
+    pub qotd: Qotd,
+    /// Whether to also listen for finger over tls.
+    pub tls: bool,
+    /// The other sites that are served here, by lowercase hostname.
+    pub sites: HashMap<String, Arc<Finger>>,
+    /// This site's posts, since the shared index only has the main site's.
+    pub search: SearchIndex,
+    cache: ResponseCache,
 }
 
-impl Protocol for Finger {
-    fn generate(data: &SiteData) -> Self {
-        let mut blog_content = String::new();
-        blog_content.push_str("# Blog\n\n");
-        for post in &data.blog {
-            let date = post.published.format("%Y-%m-%d").to_string();
-            blog_content.push_str(&format!(
-                "{date} - {title}\n{slug}@{HOSTNAME}\n\n",
-                title = post.title,
-                slug = post.slug,
-            ));
-        }
-
-        let mut posts_content = HashMap::new();
-        for post in &data.blog {
-            let slug = &post.slug;
-            let date = post.published.format("%Y-%m-%d").to_string();
-            let title = &post.title;
-            // generate the content
-            let mut out = String::new();
-
-            out.push_str(&format!("# {title}\n{date}\n\n"));
-
-            for part in post.content.iter() {
-                match part {
-                    PostPart::Text(content) => out.push_str(content),
-                    PostPart::CodeBlock(content) => {
-                        out.push_str(&format!("\n```\n{content}\n```\n"));
-                    }
-                    PostPart::InlineCode(text) => {
-                        out.push_str(&format!("`{text}`"));
-                    }
-                    PostPart::Image { src, alt } => {
-                        match src {
-                            ImageSource::Local(path) => {
-                                // get the path relative to the media directory
-                                let local_path = path
-                                    .to_string_lossy()
-                                    .into_owned()
-                                    .strip_prefix(
-                                        &Path::new("media").to_string_lossy().into_owned(),
-                                    )
-                                    .unwrap()
-                                    .to_string();
-                                if let Some(alt) = alt {
-                                    out.push_str(&format!("![{alt}]({local_path})"));
-                                } else {
-                                    out.push_str(&format!("![]({local_path})"));
-                                }
-                            }
-                            ImageSource::Remote(url) => {
-                                // out.external_link(url, &alt.to_owned().unwrap_or_default());
-                                if let Some(alt) = alt {
-                                    out.push_str(&format!("![{alt}]({url})"));
-                                } else {
-                                    out.push_str(&format!("![]({url})"));
-                                }
-                            }
-                        };
-                    }
-                    PostPart::Link { text, href } => {
-                        if let Some(href) = href.strip_prefix('/') {
-                            out.push_str(&format!("[{text}]({href}@{HOSTNAME})"));
-                        } else {
-                            out.push_str(&format!("[{text}]({href})"));
-                        }
-                    }
-                    PostPart::LineBreak => {
-                        out.push('\n');
-                        continue;
-                    }
-                    PostPart::Heading { level, text } => match level {
-                        1 => out.push_str(&format!("\n# {text}\n")),
-                        2 => out.push_str(&format!("\n## {text}\n")),
-                        3 => out.push_str(&format!("\n### {text}\n")),
-                        _ => out.push_str(&format!("\n{text}\n")),
-                    },
-                    PostPart::Italic(text) => {
-                        out.push_str(&format!("*{text}*"));
-                    }
-                    PostPart::Bold(text) => {
-                        out.push_str(&format!("**{text}**"));
-                    }
-                    PostPart::Quote(text) => {
-                        for line in text.lines() {
-                            out.push_str(&format!("\n> {line}\n"));
-                        }
-                    }
-                }
-            }
-            // add the content to the posts map
-            posts_content.insert(slug.to_string(), out.to_string());
-        }
-
-        let mut projects_content = String::new();
-        projects_content.push_str("# Projects\n\n");
-        for project in &data.projects {
-            let name = &project.name;
-            let description = &project.description;
-            projects_content.push_str(&format!("## {name}\n{description}\n"));
-
-            // only include the link if it's different from the source
-            if project.href != project.source {
-                if let Some(href) = &project.href {
-                    if let Some(href) = href.strip_prefix('/') {
-                        projects_content.push_str(&format!("{href}@{HOSTNAME}\n"));
-                    } else {
-                        projects_content.push_str(&format!("{href}\n"));
-                    }
-                }
-            }
+#[derive(Clone, Default)]
+pub struct FingerOptions {
+    pub qotd: Qotd,
+    /// Whether to also listen for finger over tls.
+    pub tls: bool,
+    /// The other sites that are served here, by lowercase hostname.
+    pub sites: HashMap<String, Arc<Finger>>,
+}
 
-            if let Some(source) = &project.source {
-                if project.languages.is_empty() {
-                    projects_content.push_str(&format!("Source code: {source}\n"));
-                } else {
-                    projects_content.push_str(&format!(
-                        "Source code ({}): {source}\n",
-                        project
-                            .languages
-                            .iter()
-                            .map(|l| l.to_string())
-                            .collect::<Vec<String>>()
-                            .join(", ")
-                    ));
-                }
-            } else if !project.languages.is_empty() {
-                projects_content.push_str(&format!(
-                    "Languages: {}\n",
-                    project
-                        .languages
-                        .iter()
-                        .map(|l| l.to_string())
-                        .collect::<Vec<String>>()
-                        .join(", ")
-                ))
-            }
+impl Protocol for Finger {
+    type Options = FingerOptions;
 
-            projects_content.push('\n');
-        }
+    fn generate(data: &SiteData, options: &Self::Options) -> Self {
+        let site = PlainTextSite::generate::<Finger>(data);
 
         Finger {
             index_content: format!(
-                r#"{INDEX_HEADER}
+                r#"{banner}
+
+{stale}{ABOUT}
 Blog: blog@{HOSTNAME}
+Longest posts first: blog-by-length@{HOSTNAME}
 Projects: projects@{HOSTNAME}
+Latest post: latest@{HOSTNAME}
+Search: "search <query>"@{HOSTNAME}
+Quote of the day: qotd@{HOSTNAME}
+Server: server@{HOSTNAME}
+Uptime: uptime@{HOSTNAME}
+Stats: stats@{HOSTNAME}
 
 GitHub: https://github.com/mat-1
 Matrix: https://matrix.to/#/@mat:matdoes.dev
-Ko-fi (donate): https://ko-fi.com/matdoesdev"#
+Ko-fi (donate): https://ko-fi.com/matdoesdev"#,
+                banner = banner::for_protocol("finger"),
+                stale = if data.stale {
+                    format!("{STALE_NOTICE}\n\n")
+                } else {
+                    String::new()
+                },
             ),
-            blog_content,
-            posts_content,
-            projects_content,
+            blog_content: site.blog,
+            blog_by_length_content: site.blog_by_length,
+            posts_content: site.posts,
+            drafts_content: site.drafts,
+            projects_content: site.projects,
+            latest: drafts::published(&data.blog)
+                .max_by_key(|post| post.published)
+                .map(|post| post.slug.clone()),
+            qotd: options.qotd.clone(),
+            tls: options.tls,
+            sites: options.sites.clone(),
+            search: SearchIndex::new(&data.blog),
+            cache: ResponseCache::default(),
         }
     }
 
-    async fn serve(self) {
-        let listener = match TcpListener::bind(format!("{BIND_HOST}:{BIND_PORT}")).await {
-            Ok(listener) => listener,
-            Err(e) => {
-                eprintln!("failed to bind to port {BIND_PORT}: {e}");
-                return;
-            }
-        };
-        let finger = Arc::new(self);
-
-        loop {
-            let (stream, remote_addr) = listener.accept().await.unwrap();
-            println!("started tcp connection for finger: {remote_addr:?}");
-
-            let (read, mut write) = stream.into_split();
-
-            let finger = Arc::clone(&finger);
-            tokio::spawn(async move {
-                match respond(finger, read).await {
-                    Ok(response) => {
-                        write
-                            .write_all(
-                                format!(
-                                    "{}\r\n",
-                                    response.replace("\r\n", "\n").replace('\n', "\r\n").trim()
-                                )
-                                .as_bytes(),
-                            )
-                            .await
-                            .unwrap();
-                    }
-                    Err(e) => {
-                        println!("error: {}", e);
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        let tls_port = config.tls_port_or(TLS_BIND_PORT);
+        shutdown
+            .run(async move {
+                let finger = Arc::new(self);
+
+                if finger.tls {
+                    tokio::join!(
+                        listen(Arc::clone(&finger), port, None),
+                        listen(finger, tls_port, Some(tls::acceptor()))
+                    );
+                } else {
+                    listen(finger, port, None).await;
+                }
+            })
+            .await;
+    }
+}
+
+impl Links for Finger {
+    fn address(path: &str) -> String {
+        format!("{path}@{HOSTNAME}")
+    }
+}
+
+/// Start a tcp server, with tls if there's an acceptor.
+async fn listen(finger: Arc<Finger>, port: u16, acceptor: Option<TlsAcceptor>) {
+    let Some(listener) = Listener::bind("finger", port) else {
+        return;
+    };
+
+    loop {
+        let (stream, remote_addr) = listener.accept().await.unwrap();
+        finger.handle_connection(stream, remote_addr, acceptor.clone());
+    }
+}
+
+impl Finger {
+    /// Answer the query on a connection that's already been accepted, in its
+    /// own task. It's wrapped in tls if there's an acceptor.
+    pub fn handle_connection(
+        self: &Arc<Self>,
+        stream: TcpStream,
+        remote_addr: SocketAddr,
+        acceptor: Option<TlsAcceptor>,
+    ) {
+        println!("started tcp connection for finger: {remote_addr:?}");
+
+        let finger = Arc::clone(self);
+        tokio::spawn(async move {
+            let _session = lifecycle::Session::start("finger");
+            let result = match acceptor {
+                Some(acceptor) => {
+                    match timeout(timeouts::read_timeout(), acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => handle(finger, stream, remote_addr).await,
+                        Ok(Err(e)) => Err(e.into()),
+                        Err(e) => Err(e.into()),
                     }
                 }
-            });
-        }
+                None => handle(finger, stream, remote_addr).await,
+            };
+            if let Err(e) = result {
+                println!("error: {}", e);
+            }
+        });
     }
 }
 
-const INDEX_HEADER: &str = r#"                                   matdoesdev
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+    finger: Arc<Finger>,
+    stream: S,
+    remote_addr: SocketAddr,
+) -> anyhow::Result<()> {
+    let (read, mut write) = tokio::io::split(stream);
+    let mut log = access_log::Entry::start("finger", remote_addr.ip());
+    let (response, status) = match respond(finger, read, &mut log, remote_addr).await {
+        Ok(response) => (response, "ok"),
+        Err(err) => {
+            if err.is_internal() {
+                eprintln!("error handling finger request: {err}");
+            }
+            match error_message(&err) {
+                Some(message) => (message.into(), err.name()),
+                None => {
+                    log.finish(err.name(), 0);
+                    return Ok(());
+                }
+            }
+        }
+    };
+    let result = timeouts::write(write.write_all(&response)).await;
+    log.finish(status, response.len() as u64);
+    result?;
+    Ok(())
+}
 
-I'm mat, I do full-stack software development.
-This portfolio contains my blog posts and links to some of the projects I've made.
-"#;
+/// Finger responses have CRLF line endings.
+fn encode(response: &str) -> Vec<u8> {
+    format!(
+        "{}\r\n",
+        response.replace("\r\n", "\n").replace('\n', "\r\n").trim()
+    )
+    .into_bytes()
+}
 
-async fn respond(finger: Arc<Finger>, mut read: OwnedReadHalf) -> anyhow::Result<String> {
-    // read until \r\n
+/// Finger doesn't have a way to say that a query failed, so errors are just a
+/// message. Returns `None` if there's no one to send it to.
+fn error_message(err: &ProtocolError) -> Option<Vec<u8>> {
+    let message = match err {
+        ProtocolError::BadRequest(message) | ProtocolError::PolicyViolation(message) => {
+            message.as_str()
+        }
+        ProtocolError::NotFound => "Not found",
+        ProtocolError::ClientDisconnected => return None,
+        ProtocolError::TimedOut => "Request timed out",
+        ProtocolError::Internal(_) => "Internal error",
+    };
+    Some(encode(message))
+}
 
-    let mut request = String::new();
-    loop {
-        let mut buf = [0u8; 1];
-        read.read_exact(&mut buf).await?;
-        request.push(buf[0] as char);
-        if request.ends_with("\r\n") {
-            request.pop();
-            request.pop();
-            break;
+impl Export for Finger {
+    /// One text file for every name that can be fingered.
+    fn artifacts(&self) -> Vec<Artifact> {
+        let mut artifacts = vec![
+            Artifact::new("index.txt", &self.index_content),
+            Artifact::new("blog.txt", &self.blog_content),
+            Artifact::new("blog-by-length.txt", &self.blog_by_length_content),
+            Artifact::new("projects.txt", &self.projects_content),
+        ];
+        for (slug, post) in &self.posts_content {
+            artifacts.push(Artifact::new(format!("{slug}.txt"), post));
         }
+        artifacts
     }
+}
+
+const ABOUT: &str = r#"I'm mat, I do full-stack software development.
+This portfolio contains my blog posts and links to some of the projects I've made.
+"#;
+
+async fn respond(
+    finger: Arc<Finger>,
+    mut read: impl AsyncRead + Unpin,
+    log: &mut access_log::Entry,
+    remote_addr: SocketAddr,
+) -> Result<Arc<[u8]>, ProtocolError> {
+    let request = timeouts::read(read_request(&mut read)).await?;
     let request = request.trim();
     println!("Finger request: {request}");
+    log.request(request);
 
-    match request {
-        "" => Ok(finger.index_content.clone()),
-        "blog" => Ok(finger.blog_content.clone()),
-        "projects" => Ok(finger.projects_content.clone()),
-        _ => {
-            if let Some(post) = finger.posts_content.get(request) {
-                return Ok(post.clone());
-            }
-            Ok("Not found".to_string())
+    analytics::record(
+        "finger",
+        request,
+        finger
+            .posts_content
+            .contains_key(request)
+            .then_some(request),
+        remote_addr.ip(),
+    );
+
+    // other sites are picked with finger's forwarding syntax, so the query
+    // for `blog@example.com@matdoes.dev` is `blog@example.com`
+    if let Some((query, hostname)) = request.rsplit_once('@') {
+        if let Some(site) = finger.sites.get(&hostname.to_lowercase()) {
+            let response = answer(site, query)?;
+            return Ok(String::from_utf8_lossy(&response)
+                .replace(&format!("@{HOSTNAME}"), &format!("@{hostname}@{HOSTNAME}"))
+                .into_bytes()
+                .into());
+        }
+    }
+
+    answer(&finger, request)
+}
+
+/// What a [`Handler`] answers with.
+enum Answer<'a> {
+    /// Something that stays the same until the site data changes, so it's
+    /// cached.
+    Page(&'a str),
+    /// Something that changes by itself, like the uptime.
+    Computed(String),
+}
+
+type Handler = fn(&Finger) -> Result<Answer<'_>, ProtocolError>;
+
+/// The names that can be fingered besides the posts. New queries are added
+/// here.
+const HANDLERS: &[(&str, Handler)] = &[
+    ("", index),
+    ("blog", blog),
+    ("blog-by-length", blog_by_length),
+    ("projects", projects),
+    ("latest", latest),
+    ("qotd", qotd),
+    ("server", server),
+    ("uptime", uptime),
+    ("stats", stats),
+];
+
+fn index(finger: &Finger) -> Result<Answer<'_>, ProtocolError> {
+    Ok(Answer::Page(&finger.index_content))
+}
+
+fn blog(finger: &Finger) -> Result<Answer<'_>, ProtocolError> {
+    Ok(Answer::Page(&finger.blog_content))
+}
+
+fn blog_by_length(finger: &Finger) -> Result<Answer<'_>, ProtocolError> {
+    Ok(Answer::Page(&finger.blog_by_length_content))
+}
+
+fn projects(finger: &Finger) -> Result<Answer<'_>, ProtocolError> {
+    Ok(Answer::Page(&finger.projects_content))
+}
+
+fn latest(finger: &Finger) -> Result<Answer<'_>, ProtocolError> {
+    finger
+        .latest
+        .as_ref()
+        .and_then(|slug| finger.posts_content.get(slug))
+        .map(|post| Answer::Page(post))
+        .ok_or(ProtocolError::NotFound)
+}
+
+fn qotd(finger: &Finger) -> Result<Answer<'_>, ProtocolError> {
+    let message = finger.qotd.message.read();
+    if message.is_empty() {
+        return Err(ProtocolError::NotFound);
+    }
+    Ok(Answer::Computed(
+        String::from_utf8_lossy(&message).into_owned(),
+    ))
+}
+
+fn server(_: &Finger) -> Result<Answer<'_>, ProtocolError> {
+    Ok(Answer::Computed(server_info::get().to_text()))
+}
+
+fn uptime(_: &Finger) -> Result<Answer<'_>, ProtocolError> {
+    Ok(Answer::Computed(format!(
+        "Up for {}",
+        server_info::human_duration(server_info::uptime())
+    )))
+}
+
+fn stats(_: &Finger) -> Result<Answer<'_>, ProtocolError> {
+    let stats = analytics::stats();
+    let mut out = format!("# Stats\n\n{} requests in total\n\n", stats.total);
+    out.push_str("## Protocols\n\n");
+    for (protocol, count) in Stats::top(&stats.protocols, usize::MAX) {
+        out.push_str(&format!("{protocol}: {count}\n"));
+    }
+    out.push_str("\n## Most read posts\n\n");
+    for (slug, count) in Stats::top(&stats.posts, 10) {
+        out.push_str(&format!("{slug}@{HOSTNAME} ({count})\n"));
+    }
+    Ok(Answer::Computed(out))
+}
+
+/// The response to a query on one site.
+fn answer(finger: &Finger, request: &str) -> Result<Arc<[u8]>, ProtocolError> {
+    let handler = HANDLERS
+        .iter()
+        .find(|(name, _)| *name == request)
+        .map(|(_, handler)| handler);
+    let answer = match handler {
+        Some(handler) => Some(handler(finger)?),
+        None => finger
+            .posts_content
+            .get(request)
+            .map(|post| Answer::Page(post)),
+    };
+    match answer {
+        Some(Answer::Page(page)) => {
+            return Ok(finger.cache.get_or_insert_with(request, || encode(page)))
         }
+        // not cached, since the uptime and counters change
+        Some(Answer::Computed(text)) => return Ok(encode(&text).into()),
+        None => {}
+    }
+
+    if let Some(query) = request.strip_prefix("search ") {
+        return Ok(encode(&search_results(&finger.search, query)).into());
+    }
+    if let Some(post) = request
+        .strip_prefix("draft-")
+        .and_then(|token| finger.drafts_content.get(token))
+    {
+        return Ok(encode(post).into());
+    }
+    Err(ProtocolError::NotFound)
+}
+
+/// Read the query, up to the `\r\n`.
+async fn read_request(read: &mut (impl AsyncRead + Unpin)) -> Result<String, ProtocolError> {
+    let mut request = Vec::new();
+    loop {
+        request.push(read.read_u8().await?);
+        if let Some(query) = request.strip_suffix(b"\r\n") {
+            return String::from_utf8(query.to_vec())
+                .map_err(|_| ProtocolError::BadRequest("Query is not UTF-8".to_owned()));
+        }
+        if request.len() > MAX_REQUEST_LENGTH {
+            return Err(ProtocolError::BadRequest("Query is too long".to_owned()));
+        }
+    }
+}
+
+fn search_results(index: &SearchIndex, query: &str) -> String {
+    let mut out = format!("# Results for \"{query}\"\n\n");
+    let results = index.search(query);
+    if results.is_empty() {
+        out.push_str("No posts found.\n");
+    }
+    for result in results {
+        out.push_str(&format!(
+            "{title}\n{slug}@{HOSTNAME}\n> {snippet}\n\n",
+            title = result.title,
+            slug = result.slug,
+            snippet = result.snippet,
+        ));
     }
+    out
 }
@@ -1,181 +1,290 @@
-mod cert;
-
 use std::{
     collections::HashMap,
     io::{self},
-    path::Path,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
     sync::Arc,
 };
 
+use chrono::{DateTime, NaiveDate, Utc};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use sha2::{Digest, Sha256};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    net::TcpStream,
+    time::timeout,
 };
-use tokio_rustls::server::TlsStream;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
 use url::Url;
 
 use crate::{
-    crawl::{ImageSource, PostPart, SiteData},
-    HOSTNAME,
+    access_log, analytics,
+    bandwidth::{self, Throttled},
+    banner,
+    cache::ResponseCache,
+    comments,
+    crawl::{list_lines, ImageSource, ListItem, Post, PostSort, SiteData, STALE_NOTICE},
+    drafts, hostnames, lifecycle, links,
+    listen::Listener,
+    locale::Locale,
+    media::Media,
+    onion_address, related,
+    routes::{self, Scheme},
+    search, server_info, table, thumbnail, timeouts, tls, transcode, visitors,
 };
 
-use super::Protocol;
+use super::{
+    alternate::{self, AlternateFormat},
+    error::ProtocolError,
+    qotd::{self, Qotd},
+    render::{self, Link, PostVisitor},
+    tracker, Artifact, Export, Protocol, ServerConfig, Shutdown,
+};
 
-const BIND_HOST: &str = "[::]";
 const BIND_PORT: u16 = 1965;
 
-const INDEX_GMI: &str = r#"```matdoesdev
-                       888        888                                 888                   
-                       888        888                                 888                   
-                       888        888                                 888                   
-88888b.d88b.   8888b.  888888 .d88888  .d88b.   .d88b.  .d8888b   .d88888  .d88b.  888  888 
-888 "888 "88b     "88b 888   d88" 888 d88""88b d8P  Y8b 88K      d88" 888 d8P  Y8b 888  888 
-888  888  888 .d888888 888   888  888 888  888 88888888 "Y8888b. 888  888 88888888 Y88  88P 
-888  888  888 888  888 Y88b. Y88b 888 Y88..88P Y8b.          X88 Y88b 888 Y8b.      Y8bd8P  
-888  888  888 "Y888888  "Y888 "Y88888  "Y88P"   "Y8888   88888P'  "Y88888  "Y8888    Y88P
-```
+/// Connections after the tls handshake, with the bandwidth limits applied.
+type GeminiStream = Throttled<TlsStream<TcpStream>>;
 
-I'm mat, I do full-stack software development.
-This portfolio contains my blog posts and links to some of the projects I've made.
+/// The characters that have to be encoded for a tag or a slug to be used in a
+/// path. Anything that isn't ASCII is always encoded.
+const SEGMENT_ENCODE_SET: &AsciiSet = &CONTROLS.add(b' ').add(b'/').add(b'?').add(b'#').add(b'%');
 
-=> blog 📝 Blog
-=> projects 💻 Projects
+/// The introduction on the index page, after the banner.
+const ABOUT: &str = "I'm mat, I do full-stack software development.
+This portfolio contains my blog posts and links to some of the projects I've made.";
 
-=> https://github.com/mat-1 GitHub
+/// The links to elsewhere at the bottom of the index page.
+const SOCIALS: &str = "=> https://github.com/mat-1 GitHub
 => https://matrix.to/#/@mat:matdoes.dev Matrix
 => https://ko-fi.com/matdoesdev Ko-fi (donate)
-"#;
+";
 
 #[derive(Clone)]
 pub struct Gemini {
-    pub blog_gmi: String,
+    /// The pages with navigation are generated for every language, and the
+    /// links on them keep the language's path prefix.
+    pub index_gmi: HashMap<Locale, String>,
+    pub blog_gmi: HashMap<Locale, String>,
+    /// The blog with the longest posts first.
+    pub blog_by_length_gmi: HashMap<Locale, String>,
+    /// The published posts, for the blog pages of visitors who have posts
+    /// that are new to them.
+    blog: Vec<Post>,
     pub posts_gmi: HashMap<String, String>,
+    /// Draft posts by their preview token.
+    pub drafts_gmi: HashMap<String, String>,
     pub projects_gmi: String,
+    pub tags_gmi: String,
+    /// The posts with each tag, by the tag.
+    pub tag_pages_gmi: HashMap<String, String>,
+    pub downloads_gmi: String,
+    /// Links to every page, for crawlers.
+    pub urls_gmi: String,
+    pub qotd: Qotd,
+    cache: ResponseCache,
 }
 
-pub struct Link {
-    pub text: String,
-    pub href: String,
+/// Writes a post's parts as gemtext, with the ASCII art thumbnails of the
+/// images after their links.
+struct GemtextPost<'a>(&'a mut String, &'a HashMap<PathBuf, String>);
+
+impl PostVisitor for GemtextPost<'_> {
+    const QUEUE_LINKS: bool = true;
+
+    fn text(&mut self, text: &str) {
+        self.0.push_str(text);
+    }
+
+    fn inline_code(&mut self, code: &str) {
+        self.0.push_str(&format!("`{code}`"));
+    }
+
+    fn code_block(&mut self, code: &str) {
+        self.0.push_str(&format!("```\n{code}\n```\n"));
+    }
+
+    fn italic(&mut self, text: &str) {
+        self.0.push_str(&format!("*{text}*"));
+    }
+
+    fn bold(&mut self, text: &str) {
+        self.0.push_str(&format!("**{text}**"));
+    }
+
+    fn image(&mut self, src: &ImageSource, alt: Option<&str>) {
+        let href = match src {
+            ImageSource::Local(path) => render::media_path(path),
+            ImageSource::Remote(url) => url.to_owned(),
+        };
+        match alt {
+            Some(alt) => self.0.push_str(&format!("=> {href} {alt}\n")),
+            None => self.0.push_str(&format!("=> {href}\n")),
+        }
+        if let ImageSource::Local(path) = src {
+            if let Some(art) = self.1.get(path) {
+                let alt = alt.unwrap_or("image");
+                self.0.push_str(&format!("```{alt}\n{art}```\n"));
+            }
+        }
+    }
+
+    fn line_break(&mut self, repeated: bool, links: &[Link]) {
+        if !repeated {
+            self.0.push('\n');
+        }
+        for Link { href, text } in links {
+            self.0.push_str(&format!("=> {href} {text}\n"));
+        }
+        self.0.push('\n');
+    }
+
+    fn heading(&mut self, level: usize, text: &str, _after_line_break: bool) {
+        match level {
+            1 => self.0.push_str(&format!("# {text}\n")),
+            2 => self.0.push_str(&format!("## {text}\n")),
+            3 => self.0.push_str(&format!("### {text}\n")),
+            _ => {}
+        }
+    }
+
+    fn quote(&mut self, text: &str) {
+        for line in text.lines() {
+            self.0.push_str(&format!("> {line}\n"));
+        }
+    }
+
+    fn footnote_reference(&mut self, label: &str) {
+        self.0.push_str(&format!("[{label}]"));
+    }
+
+    fn horizontal_rule(&mut self) {
+        // gemtext doesn't have these
+        self.0.push_str("\n---\n\n");
+    }
+
+    fn definition_list(&mut self, definitions: &[(String, String)]) {
+        for (term, definition) in definitions {
+            self.0.push_str(&format!("{term}\n"));
+            for line in definition.lines() {
+                self.0.push_str(&format!("> {line}\n"));
+            }
+            self.0.push('\n');
+        }
+    }
+
+    fn caption(&mut self, text: &str) {
+        self.0.push_str(&format!("*{text}*\n"));
+    }
+
+    fn table(&mut self, rows: &[Vec<String>]) {
+        // preformatted so the columns stay aligned
+        self.0.push_str("```\n");
+        for line in table::render(rows, 80, &table::ASCII) {
+            self.0.push_str(&format!("{line}\n"));
+        }
+        self.0.push_str("```\n");
+    }
+
+    fn list(&mut self, ordered: bool, items: &[ListItem], after_line_break: bool) {
+        if !after_line_break && !self.0.ends_with('\n') {
+            self.0.push('\n');
+        }
+        for line in list_lines(ordered, items) {
+            let text = line.text;
+            match line.number {
+                // gemtext only has unordered lists
+                None if line.depth == 0 => self.0.push_str(&format!("* {text}\n")),
+                None => self
+                    .0
+                    .push_str(&format!("{}- {text}\n", "  ".repeat(line.depth))),
+                Some(number) => self
+                    .0
+                    .push_str(&format!("{}{number}. {text}\n", "  ".repeat(line.depth))),
+            }
+        }
+        self.0.push('\n');
+    }
+
+    fn end(&mut self, links: &[Link]) {
+        for Link { href, text } in links {
+            self.0.push_str(&format!("=> {href} {text}\n"));
+        }
+    }
+
+    fn footnotes(&mut self, footnotes: &[(&str, &str)]) {
+        self.0.push_str("\n## Notes\n");
+        for (id, text) in footnotes {
+            self.0.push_str(&format!("[{id}] {text}\n"));
+        }
+    }
+}
+
+/// The post's title and content, with the ASCII art for the images that have
+/// it.
+pub fn post_gmi(post: &Post, thumbnails: &HashMap<PathBuf, String>) -> String {
+    let date = post.published.format("%Y-%m-%d");
+    let mut content = format!("# {}\n{date}\n", post.title);
+    content.push_str(&format!(
+        "{}\n\n",
+        Locale::default()
+            .strings()
+            .post_length(post.reading_minutes(), post.word_count)
+    ));
+    render::visit_post(&post.content, &mut GemtextPost(&mut content, thumbnails));
+    content
 }
 
 impl Protocol for Gemini {
-    fn generate(data: &SiteData) -> Self {
-        let mut blog_gmi = String::new();
-        blog_gmi.push_str("# Blog\n\n");
+    type Options = Qotd;
 
+    fn generate(data: &SiteData, qotd: &Self::Options) -> Self {
         let mut posts = HashMap::new();
+        let mut drafts_gmi = HashMap::new();
+        let related = related::related_posts(&data.blog);
+        let thumbnails = thumbnail::for_posts(&data.blog);
         for post in &data.blog {
             let slug = &post.slug;
-            let date = post.published.format("%Y-%m-%d").to_string();
-            let title = &post.title;
-            // add it to the index
-            blog_gmi.push_str(&format!("=> /{slug} {date} - {title}\n"));
-            // generate the content
-            let mut content = String::new();
-
-            content.push_str(&format!("# {title}\n"));
-            content.push_str(&format!("{date}\n\n"));
-
-            let mut queued_links: Vec<Link> = Vec::new();
-            let mut last_tag_was_line_break = false;
-            for (i, part) in post.content.iter().enumerate() {
-                match part {
-                    PostPart::Text(text) => content.push_str(text),
-                    PostPart::CodeBlock(text) => {
-                        content.push_str(&format!("```\n{text}\n```\n"));
-                    }
-                    PostPart::InlineCode(text) => {
-                        content.push_str(&format!("`{text}`"));
-                    }
-                    PostPart::Image { src, alt } => {
-                        let href = match src {
-                            ImageSource::Local(path) => {
-                                // get the path relative to the media directory
-                                path.to_string_lossy()
-                                    .into_owned()
-                                    .strip_prefix(
-                                        &Path::new("media").to_string_lossy().into_owned(),
-                                    )
-                                    .unwrap()
-                                    .to_string()
-                            }
-                            ImageSource::Remote(url) => url.to_owned(),
-                        };
-                        match alt {
-                            Some(alt) => content.push_str(&format!("=> {href} {alt}\n")),
-                            None => content.push_str(&format!("=> {href}\n")),
-                        }
-                    }
-                    PostPart::Link { text, href } => {
-                        queued_links.push(Link {
-                            text: text.to_owned(),
-                            href: match href {
-                                h if h.starts_with("https://gemini.circumlunar.space/") => {
-                                    // replace the https:// with gemini://
-                                    h.replacen("https://", "gemini://", 1)
-                                }
-                                h if h.starts_with("https://gmi.skyjake.fi/") => {
-                                    // replace the https://gmi. with gemini://
-                                    h.replacen("https://gmi.", "gemini://", 1)
-                                }
-                                h => h.to_owned(),
-                            },
-                        });
-                        // add the link text unless the part before and after are line breaks
-                        let before_is_line_break =
-                            i == 0 || matches!(post.content[i - 1], PostPart::LineBreak);
-                        let after_is_line_break = i == post.content.len() - 1
-                            || matches!(post.content[i + 1], PostPart::LineBreak);
-                        if before_is_line_break && after_is_line_break {
-                            // remove the last line break too
-                            content.pop();
-                        } else {
-                            content.push_str(text);
-                        }
-                    }
-                    PostPart::LineBreak => {
-                        if !last_tag_was_line_break {
-                            content.push('\n');
-                        }
-                        if !queued_links.is_empty() {
-                            // flush the queued links
-                            for Link { href, text } in queued_links.drain(..) {
-                                content.push_str(&format!("=> {href} {text}\n"));
-                            }
-                        }
-                        content.push('\n');
-                        last_tag_was_line_break = true;
-                        continue;
-                    }
-                    PostPart::Heading { level, text } => match level {
-                        1 => content.push_str(&format!("# {text}\n")),
-                        2 => content.push_str(&format!("## {text}\n")),
-                        3 => content.push_str(&format!("### {text}\n")),
-                        _ => {}
-                    },
-                    PostPart::Italic(text) => {
-                        content.push_str(&format!("*{text}*"));
-                    }
-                    PostPart::Bold(text) => {
-                        content.push_str(&format!("**{text}**"));
-                    }
-                    PostPart::Quote(text) => {
-                        for line in text.lines() {
-                            content.push_str(&format!("> {line}\n"));
-                        }
-                    }
+            let mut content = post_gmi(post, &thumbnails);
+
+            if let Some(related) = related.get(slug).filter(|r| !r.is_empty()) {
+                content.push_str("\n## Related posts\n\n");
+                for related_post in related {
+                    content.push_str(&format!(
+                        "=> /{} {}\n",
+                        encode_segment(&related_post.slug),
+                        related_post.title
+                    ));
                 }
-                last_tag_was_line_break = false;
-            }
-            // flush the queued links
-            for Link { href, text } in queued_links.drain(..) {
-                content.push_str(&format!("=> {href} {text}\n"));
             }
 
-            content.push_str("=> /blog ⬅ Back\n");
-
             // add the content to the posts map
-            posts.insert(slug.to_string(), content);
+            if !post.draft {
+                posts.insert(slug.to_string(), content);
+            } else if let Some(token) = drafts::preview_token(slug) {
+                drafts_gmi.insert(token, content);
+            }
+        }
+
+        // tags
+        let mut tags_gmi = String::new();
+        tags_gmi.push_str("# Tags\n\n");
+        let mut tag_pages_gmi = HashMap::new();
+        for (tag, tagged_posts) in data.tags() {
+            let href = encode_segment(tag);
+            tags_gmi.push_str(&format!("=> /tag/{href} {tag} ({})\n", tagged_posts.len()));
+
+            let mut tag_gmi = format!("# Posts tagged {tag}\n\n");
+            for post in tagged_posts {
+                let date = post.published.format("%Y-%m-%d");
+                tag_gmi.push_str(&format!(
+                    "=> /{} {date} - {}\n",
+                    encode_segment(&post.slug),
+                    post.title
+                ));
+            }
+            tag_gmi.push_str("\n=> /tags ⬅ All tags\n");
+            tag_pages_gmi.insert(tag.to_owned(), tag_gmi);
         }
 
         // projects
@@ -190,10 +299,8 @@ impl Protocol for Gemini {
             // only include the link if it's different from the source
             if project.href != project.source {
                 if let Some(href) = &project.href {
-                    let pretty_href = href
-                        .strip_prefix("https://")
-                        .unwrap_or(href.strip_prefix("http://").unwrap_or(href));
-                    let pretty_href = pretty_href.strip_suffix('/').unwrap_or(pretty_href);
+                    let href = links::rewrite(href, Scheme::Gemini);
+                    let pretty_href = links::pretty(&href);
                     projects_gmi.push_str(&format!("=> {href} {pretty_href}\n"))
                 }
             }
@@ -225,73 +332,313 @@ impl Protocol for Gemini {
             }
         }
 
+        let mut downloads_gmi = String::new();
+        downloads_gmi.push_str("# Downloads\n\n");
+        downloads_gmi.push_str("Torrents of the media on the site and of every post as text.\n\n");
+        for torrent in tracker::torrents() {
+            downloads_gmi.push_str(&format!(
+                "=> /downloads/{} {} ({})\n",
+                encode_segment(&torrent.file_name),
+                torrent.name,
+                torrent.human_size()
+            ));
+        }
+
+        let mut urls_gmi = String::new();
+        urls_gmi.push_str("# Every page\n\n");
+        for route in routes::for_scheme(data, Scheme::Gemini) {
+            let href = route
+                .path
+                .split('/')
+                .map(encode_segment)
+                .collect::<Vec<_>>()
+                .join("/");
+            urls_gmi.push_str(&format!("=> {href} {}\n", route.path));
+        }
+
+        let banner = banner::for_protocol("gemini");
+        // point tor users to the onion service, if we have one
+        let onion = onion_address()
+            .map(|address| format!("=> gemini://{address}/ 🧅 Also on Tor\n\n"))
+            .unwrap_or_default();
+        let stale = if data.stale {
+            format!("> {STALE_NOTICE}\n\n")
+        } else {
+            String::new()
+        };
+        let mut index_gmi = HashMap::new();
+        let mut blog_gmi = HashMap::new();
+        let mut blog_by_length_gmi = HashMap::new();
+        for locale in Locale::ALL {
+            let strings = locale.strings();
+            let prefix = locale.path_prefix();
+
+            index_gmi.insert(
+                locale,
+                format!(
+                    "```{}\n{banner}\n```\n\n{stale}{onion}{ABOUT}\n\n\
+                    => {prefix}/blog 📝 {}\n\
+                    => {prefix}/projects 💻 {}\n\
+                    => {prefix}/downloads 📦 {}\n\
+                    => {prefix}/qotd 💬 {}\n\
+                    => {prefix}/about-server ⚙️ {}\n\
+                    => {prefix}/playground 🛝 {}\n\n\
+                    {SOCIALS}",
+                    banner::NAME,
+                    strings.blog,
+                    strings.projects,
+                    strings.downloads,
+                    strings.quote_of_the_day,
+                    strings.server,
+                    strings.playground,
+                ),
+            );
+
+            blog_gmi.insert(locale, blog_page(&data.blog, locale, PostSort::Date, None));
+            blog_by_length_gmi.insert(
+                locale,
+                blog_page(&data.blog, locale, PostSort::Length, None),
+            );
+        }
+
         Gemini {
+            index_gmi,
             blog_gmi,
+            blog_by_length_gmi,
+            blog: drafts::published(&data.blog).cloned().collect(),
             posts_gmi: posts,
+            drafts_gmi,
             projects_gmi,
+            tags_gmi,
+            tag_pages_gmi,
+            downloads_gmi,
+            urls_gmi,
+            qotd: qotd.clone(),
+            cache: ResponseCache::default(),
         }
     }
 
-    async fn serve(self) {
-        // start a tcp server
-
-        let gemini = Arc::new(self);
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        shutdown
+            .run(async move {
+                // start a tcp server
 
-        let acceptor = cert::acceptor();
-        let listener = match TcpListener::bind(format!("{BIND_HOST}:{BIND_PORT}")).await {
-            Ok(listener) => listener,
-            Err(e) => {
-                eprintln!("failed to bind to port {BIND_PORT}: {e}");
-                return;
-            }
-        };
+                let gemini = Arc::new(self);
 
-        loop {
-            let (stream, remote_addr) = listener.accept().await.unwrap();
-            println!("started tcp connection for gemini: {remote_addr:?}");
-            let acceptor = acceptor.clone();
+                // client certificates are asked for but not required, and
+                // they're only used to recognize returning visitors
+                let acceptor = tls::acceptor_with_client_certs();
+                let Some(listener) = Listener::bind("gemini", port) else {
+                    return;
+                };
 
-            let gemini = Arc::clone(&gemini);
-            let fut = async move {
-                let mut stream = acceptor.accept(stream).await?;
-                println!("wrapped stream in tls");
+                loop {
+                    let (stream, remote_addr) = listener.accept().await.unwrap();
+                    gemini.handle_connection(stream, remote_addr, acceptor.clone());
+                }
+            })
+            .await;
+    }
+}
 
-                let response = respond(gemini, &mut stream)
-                    .await
-                    .unwrap_or(b"59 Internal error\r\n".to_vec());
+impl Gemini {
+    /// A page that doesn't change, from the cache.
+    fn page(&self, path: &str, content: &str) -> Arc<[u8]> {
+        self.cache.get_or_insert_with(path, || {
+            format!("20 text/gemini\r\n{content}\n").into_bytes()
+        })
+    }
 
-                stream.write_all(&response).await?;
-                stream.shutdown().await?;
+    /// Answer the request on a connection that's already been accepted, in
+    /// its own task.
+    pub fn handle_connection(
+        self: &Arc<Self>,
+        stream: TcpStream,
+        remote_addr: SocketAddr,
+        acceptor: TlsAcceptor,
+    ) {
+        println!("started tcp connection for gemini: {remote_addr:?}");
+
+        let gemini = Arc::clone(self);
+        let fut = async move {
+            let stream = timeout(timeouts::read_timeout(), acceptor.accept(stream)).await??;
+            println!("wrapped stream in tls");
+            let visitor = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| {
+                    let hash = Sha256::digest(cert);
+                    let hex = hash.iter().map(|b| format!("{b:02x}")).collect::<String>();
+                    format!("gemini:{hex}")
+                });
+            let mut stream = bandwidth::throttle("gemini", remote_addr.ip(), stream);
+            let mut log = access_log::Entry::start("gemini", remote_addr.ip());
+
+            let response = match respond(gemini, &mut stream, &mut log, remote_addr, visitor).await
+            {
+                Ok(response) => response,
+                Err(err) => {
+                    if err.is_internal() {
+                        eprintln!("error handling gemini request: {err}");
+                    }
+                    match status_line(&err, Locale::default()) {
+                        Some(line) => line.into(),
+                        None => {
+                            log.finish(err.name(), stream.written());
+                            return Ok(());
+                        }
+                    }
+                }
+            };
 
-                Ok(()) as io::Result<()>
+            let result = timeouts::write(stream.write_all(&response)).await;
+            // media is sent while it's being routed, after a `20` header
+            let status = match response.get(..2) {
+                Some(code) => String::from_utf8_lossy(code).into_owned(),
+                None => "20".to_owned(),
             };
+            log.finish(&status, stream.written());
+            result?;
+            stream.shutdown().await?;
 
-            tokio::spawn(async move {
-                if let Err(err) = fut.await {
-                    eprintln!("{:?}", err);
-                }
-            });
+            Ok(()) as io::Result<()>
+        };
+
+        tokio::spawn(async move {
+            let _session = lifecycle::Session::start("gemini");
+            if let Err(err) = fut.await {
+                eprintln!("{:?}", err);
+            }
+        });
+    }
+}
+
+impl Export for Gemini {
+    /// Directories have an `index.gmi` so the links work with most Gemini
+    /// servers.
+    fn artifacts(&self) -> Vec<Artifact> {
+        let mut artifacts = vec![
+            Artifact::new("index.gmi", &self.index_gmi[&Locale::default()]),
+            Artifact::new("blog/index.gmi", &self.blog_gmi[&Locale::default()]),
+            Artifact::new(
+                "blog/by-length/index.gmi",
+                &self.blog_by_length_gmi[&Locale::default()],
+            ),
+            Artifact::new("projects/index.gmi", &self.projects_gmi),
+            Artifact::new("tags/index.gmi", &self.tags_gmi),
+            Artifact::new("downloads/index.gmi", &self.downloads_gmi),
+            Artifact::new("index/index.gmi", &self.urls_gmi),
+        ];
+        for (tag, page) in &self.tag_pages_gmi {
+            artifacts.push(Artifact::new(format!("tag/{tag}/index.gmi"), page));
+        }
+        for (slug, post) in &self.posts_gmi {
+            artifacts.push(Artifact::new(
+                format!("{slug}/index.gmi"),
+                format!("{post}=> /blog ⬅ Back\n"),
+            ));
         }
+        artifacts
     }
 }
 
 async fn respond(
     gemini: Arc<Gemini>,
-    stream: &mut TlsStream<TcpStream>,
-) -> std::io::Result<Vec<u8>> {
+    stream: &mut GeminiStream,
+    log: &mut access_log::Entry,
+    remote_addr: SocketAddr,
+    visitor: Option<String>,
+) -> Result<Arc<[u8]>, ProtocolError> {
+    let request = timeouts::read(read_request(stream)).await?;
+    let Ok(request) = std::str::from_utf8(&request) else {
+        return Err(ProtocolError::BadRequest("Request is not UTF-8".to_owned()));
+    };
+
+    println!("Gemini request: {request}");
+    log.request(request);
+
+    let Ok(url) = Url::parse(request) else {
+        return Err(ProtocolError::BadRequest(
+            "Request is not a valid URL".to_owned(),
+        ));
+    };
+
+    if url.scheme() != "gemini" {
+        return Ok(b"53 Request is not a Gemini URL\r\n".as_slice().into());
+    };
+    if !url
+        .host_str()
+        .is_some_and(|host| hostnames().any(|h| h == host))
+    {
+        return Ok(b"53 Host doesn't match\r\n".as_slice().into());
+    };
+    if url.port().unwrap_or(BIND_PORT) != BIND_PORT {
+        return Ok(b"53 Port doesn't match\r\n".as_slice().into());
+    };
+
+    // the language can be picked with a prefix, like `/de/blog`
+    let (locale, path) = Locale::strip_path_prefix(url.path());
+    let locale = locale.unwrap_or_default();
+    // slugs and tags can have characters that aren't ascii, which are encoded
+    // in the url
+    let Ok(decoded_path) = percent_decode_str(path).decode_utf8() else {
+        return Err(ProtocolError::BadRequest("Path is not UTF-8".to_owned()));
+    };
+    let path = decoded_path.as_ref();
+
+    let slug = path.strip_prefix('/').unwrap_or(path);
+    analytics::record(
+        "gemini",
+        path,
+        gemini.posts_gmi.contains_key(slug).then_some(slug),
+        remote_addr.ip(),
+    );
+
+    // people with a client certificate can be recognized when they come back
+    let previous_visit = visitor.and_then(|visitor| visitors::visit(&visitor));
+
+    match route(
+        &gemini,
+        stream,
+        &url,
+        path,
+        locale,
+        previous_visit,
+        remote_addr.ip(),
+    )
+    .await
+    {
+        // answered here so the message is in the request's language
+        Err(err) => match status_line(&err, locale) {
+            Some(line) => {
+                if err.is_internal() {
+                    eprintln!("error handling gemini request: {err}");
+                }
+                Ok(line.into())
+            }
+            None => Err(err),
+        },
+        response => response,
+    }
+}
+
+/// Read the request line. It's a URL of up to 1024 bytes followed by `\r\n`,
+/// so anything longer is rejected.
+async fn read_request(stream: &mut GeminiStream) -> Result<Vec<u8>, ProtocolError> {
     let mut request = [0; 1026];
     let mut len = 0;
     loop {
         let mut buffer = [0; 1027];
-        let Ok(n) = stream.read(&mut buffer).await else {
-            return Ok(b"59 Couldn't receive request\r\n".to_vec());
-        };
+        let n = stream.read(&mut buffer).await?;
         println!("read {n} bytes: {}", String::from_utf8_lossy(&buffer[..n]));
         if n == 0 {
             break;
         }
         if n + len > request.len() {
-            return Ok(b"59 Request is too large\r\n".to_vec());
+            return Err(ProtocolError::BadRequest("Request is too large".to_owned()));
         }
         // add the new data to the request
         request[len..len + n].copy_from_slice(&buffer[..n]);
@@ -302,75 +649,294 @@ async fn respond(
     }
     // ignore everything after the first \r
     let request = request[..len].split(|v| v == &b'\r').next().unwrap();
-    let Ok(request) = std::str::from_utf8(request) else {
-        return Ok(b"59 Request is not UTF-8\r\n".to_vec());
-    };
-
-    println!("Gemini request: {request}");
-
-    let Ok(url) = Url::parse(request) else {
-        return Ok(b"59 Request is not a valid URL\r\n".to_vec());
-    };
-
-    if url.scheme() != "gemini" {
-        return Ok(b"53 Request is not a Gemini URL\r\n".to_vec());
-    };
-    if url.host_str() != Some(HOSTNAME) {
-        return Ok(b"53 Host doesn't match\r\n".to_vec());
-    };
-    if url.port().unwrap_or(BIND_PORT) != BIND_PORT {
-        return Ok(b"53 Port doesn't match\r\n".to_vec());
-    };
+    Ok(request.to_vec())
+}
 
-    Ok(match url.path() {
-        "/" | "" => format!("20 text/gemini\r\n{INDEX_GMI}\n")
-            .as_bytes()
-            .to_vec(),
-        "/blog" => format!("20 text/gemini\r\n{}\n", gemini.blog_gmi)
-            .as_bytes()
-            .to_vec(),
-        "/projects" => format!("20 text/gemini\r\n{}\n", gemini.projects_gmi)
-            .as_bytes()
-            .to_vec(),
+async fn route(
+    gemini: &Gemini,
+    stream: &mut GeminiStream,
+    url: &Url,
+    path: &str,
+    locale: Locale,
+    previous_visit: Option<DateTime<Utc>>,
+    ip: IpAddr,
+) -> Result<Arc<[u8]>, ProtocolError> {
+    Ok(match path {
+        "/" | "" => gemini.page(url.path(), &gemini.index_gmi[&locale]),
+        // the cached ones don't have anything marked as new
+        "/blog" | "/blog/by-length" if previous_visit.is_some() => {
+            let sort = match path {
+                "/blog" => PostSort::Date,
+                _ => PostSort::Length,
+            };
+            let page = blog_page(&gemini.blog, locale, sort, previous_visit);
+            format!("20 text/gemini\r\n{page}\n").into_bytes().into()
+        }
+        "/blog" => gemini.page(url.path(), &gemini.blog_gmi[&locale]),
+        "/blog/by-length" => gemini.page(url.path(), &gemini.blog_by_length_gmi[&locale]),
+        "/projects" => gemini.page(url.path(), &gemini.projects_gmi),
+        "/tags" => gemini.page(url.path(), &gemini.tags_gmi),
+        "/downloads" => gemini.page(url.path(), &gemini.downloads_gmi),
+        "/index" => gemini.page(url.path(), &gemini.urls_gmi),
+        "/qotd" => qotd_gmi(&gemini.qotd.message.read(), url.query()).into(),
+        "/search" => search_gmi(url.query(), locale).into(),
+        // not cached, since the uptime and counters change
+        "/about-server" => format!("20 text/gemini\r\n{}", server_info::get().to_text())
+            .into_bytes()
+            .into(),
+        "/playground" => format!("20 text/gemini\r\n{}", server_info::playground_text())
+            .into_bytes()
+            .into(),
         path => {
             let slug = match path.strip_prefix('/') {
                 Some(slug) => slug,
                 None => path,
             };
+            if let Some(tag) = slug.strip_prefix("tag/") {
+                let page = gemini
+                    .tag_pages_gmi
+                    .get(tag)
+                    .ok_or(ProtocolError::NotFound)?;
+                return Ok(gemini.page(url.path(), page));
+            }
+            if let Some(token) = slug.strip_prefix("draft/") {
+                let post = gemini
+                    .drafts_gmi
+                    .get(token)
+                    .ok_or(ProtocolError::NotFound)?;
+                return Ok(format!("20 text/gemini\r\n{post}\r\n").as_bytes().into());
+            }
+            if let Some(file_name) = slug.strip_prefix("downloads/") {
+                let torrent = tracker::torrent(file_name).ok_or(ProtocolError::NotFound)?;
+                let mut response = b"20 application/x-bittorrent\r\n".to_vec();
+                response.extend(&torrent.metainfo);
+                return Ok(response.into());
+            }
+            if let Some(slug) = slug
+                .strip_suffix("/comment")
+                .filter(|slug| gemini.posts_gmi.contains_key(*slug))
+            {
+                return Ok(comment_input(slug, url.query(), ip).into());
+            }
+            if let Some((format, content)) = alternate::render_path(&gemini.blog, path) {
+                return Ok(format!("20 {}\r\n{content}", format.content_type())
+                    .into_bytes()
+                    .into());
+            }
             // if it has another slash, that means it's media
             if slug.contains('/') {
-                // get the path relative to the media directory
-                let path = slug;
-                let path = Path::new("media").join(path);
-                // this feels completely safe and not dangerous at all
-                if !path
-                    .components()
-                    .all(|x| matches!(x, std::path::Component::Normal(..)))
-                {
-                    return Ok(b"inyaa~ >_<\tfake\t(NULL)\t0\r\n".to_vec());
-                }
+                // get the path relative to the media directory, in a format
+                // that the client can show
+                let Some(path) = transcode::resolve(slug, true).await else {
+                    return Err(ProtocolError::PolicyViolation("nyaa~ >_<".to_owned()));
+                };
                 let mime = mime_guess::from_path(&path).first_or_octet_stream();
-                let mime = mime.to_string();
                 println!("path: {path:?}, mime: {mime}");
-                let Ok(mut file) = tokio::fs::File::open(path).await else {
-                    return Ok(b"iNot found\tfake\t(NULL)\t0\r\n".to_vec());
+                let Ok(file) = Media::open(&path).await else {
+                    return Err(ProtocolError::NotFound);
                 };
-                let mut content = Vec::new();
-                let _ = file.read_to_end(&mut content).await;
-                format!("20 {}\r\n", mime)
-                    .as_bytes()
-                    .iter()
-                    .copied()
-                    .chain(content)
-                    .collect()
+                // the file is written directly so it doesn't all have to be in
+                // memory
+                timeouts::write(async {
+                    stream
+                        .write_all(format!("20 {mime}\r\n").as_bytes())
+                        .await?;
+                    file.send(stream).await
+                })
+                .await?;
+                Arc::default()
             } else {
-                match gemini.posts_gmi.get(slug) {
-                    Some(post) => format!("20 text/gemini\r\n{}\r\n", post)
-                        .as_bytes()
-                        .to_vec(),
-                    None => b"51 Not found\r\n".to_vec(),
-                }
+                let post = gemini.posts_gmi.get(slug).ok_or(ProtocolError::NotFound)?;
+                format!(
+                    "20 text/gemini\r\n{post}{}{}=> {}/blog ⬅ {}\n\r\n",
+                    alternates_gmi(slug),
+                    comments_gmi(slug, locale),
+                    locale.path_prefix(),
+                    locale.strings().back
+                )
+                .as_bytes()
+                .into()
             }
         }
     })
 }
+
+/// The list of posts, with the ones published after the visitor's previous
+/// visit marked as new.
+pub fn blog_page(
+    blog: &[Post],
+    locale: Locale,
+    sort: PostSort,
+    previous_visit: Option<DateTime<Utc>>,
+) -> String {
+    let strings = locale.strings();
+    let prefix = locale.path_prefix();
+    let (other_path, other_name) = match sort {
+        PostSort::Date => ("/blog/by-length", strings.longest),
+        PostSort::Length => ("/blog", strings.newest),
+    };
+    let mut page = format!("# {}\n\n", strings.blog);
+    page.push_str(&format!("=> {prefix}/tags 🏷️ {}\n", strings.tags));
+    page.push_str(&format!("=> {prefix}/search 🔍 {}\n", strings.search));
+    page.push_str(&format!(
+        "=> {prefix}{other_path} ↕️ {}: {other_name}\n\n",
+        strings.sort
+    ));
+    for post in sort.sort(drafts::published(blog)) {
+        let date = post.published.format("%Y-%m-%d");
+        let new = if visitors::is_new(post.published, previous_visit) {
+            format!(" {}", visitors::NEW_MARKER)
+        } else {
+            String::new()
+        };
+        page.push_str(&format!(
+            "=> {prefix}/{} {date} - {} ({} {}){new}\n",
+            encode_segment(&post.slug),
+            post.title,
+            post.reading_minutes(),
+            strings.min_read
+        ));
+    }
+    page
+}
+
+/// A tag or slug that can be put in a link.
+fn encode_segment(segment: &str) -> String {
+    utf8_percent_encode(segment, SEGMENT_ENCODE_SET).to_string()
+}
+
+/// The status line for an error, or `None` if there's no one to send it to.
+/// Requests we refuse get `50`, since `53` is only for hosts and ports we
+/// don't serve.
+fn status_line(err: &ProtocolError, locale: Locale) -> Option<Vec<u8>> {
+    let strings = locale.strings();
+    let line = match err {
+        ProtocolError::BadRequest(message) => format!("59 {message}"),
+        ProtocolError::NotFound => format!("51 {}", strings.not_found),
+        ProtocolError::PolicyViolation(message) => format!("50 {message}"),
+        ProtocolError::ClientDisconnected => return None,
+        ProtocolError::TimedOut => "59 Request timed out".to_owned(),
+        ProtocolError::Internal(_) => format!("40 {}", strings.internal_error),
+    };
+    Some(format!("{line}\r\n").into_bytes())
+}
+
+/// Links to the post's other formats, for downloading it.
+fn alternates_gmi(slug: &str) -> String {
+    let mut content = String::from("\n");
+    for format in AlternateFormat::ALL {
+        content.push_str(&format!(
+            "=> {} 💾 {}\n",
+            format.path(&encode_segment(slug)),
+            format.name()
+        ));
+    }
+    content
+}
+
+/// The approved comments on a post, and a link to leave a new one.
+fn comments_gmi(slug: &str, locale: Locale) -> String {
+    let mut content = String::new();
+    content.push_str(&format!("\n## {}\n\n", locale.strings().comments));
+    for comment in comments::approved(slug) {
+        let date = comment.timestamp.format("%Y-%m-%d");
+        content.push_str(&format!("### {} ({date})\n", comment.author));
+        for line in comment.body.lines() {
+            content.push_str(&format!("> {line}\n"));
+        }
+        if let Some(url) = &comment.url {
+            content.push_str(&format!("=> {url} ↩️ On the Fediverse\n"));
+        }
+        content.push('\n');
+    }
+    content.push_str(&format!(
+        "=> /{}/comment 💬 Leave a comment\n\n",
+        encode_segment(slug)
+    ));
+    content
+}
+
+/// Ask for a comment using Gemini's input status, and submit it once we get
+/// one.
+fn comment_input(slug: &str, query: Option<&str>, ip: IpAddr) -> Vec<u8> {
+    let Some(query) = query else {
+        return b"10 Your comment (it'll be shown after it's approved)\r\n".to_vec();
+    };
+    let body = percent_decode_str(query).decode_utf8_lossy();
+    match comments::submit(slug, "", &body, ip) {
+        Ok(_) => format!(
+            "20 text/gemini\r\n# Thanks!\nYour comment will be shown after it's approved.\n\n=> /{} ⬅ Back\n",
+            encode_segment(slug)
+        )
+        .as_bytes()
+        .to_vec(),
+        Err(err) => format!("10 {err}, try again\r\n").as_bytes().to_vec(),
+    }
+}
+
+/// Ask for a query using Gemini's input status, and show the matching posts
+/// once we get one.
+fn search_gmi(query: Option<&str>, locale: Locale) -> Vec<u8> {
+    let strings = locale.strings();
+    let prefix = locale.path_prefix();
+    let Some(query) = query.filter(|query| !query.is_empty()) else {
+        return format!("10 {}\r\n", strings.search).as_bytes().to_vec();
+    };
+    let query = percent_decode_str(query).decode_utf8_lossy();
+
+    let mut content = format!("# Results for \"{query}\"\n\n");
+    let results = search::search(&query);
+    if results.is_empty() {
+        content.push_str(&format!("{}\n\n", strings.no_posts_found));
+    }
+    for result in results {
+        content.push_str(&format!(
+            "=> {prefix}/{} {}\n",
+            encode_segment(&result.slug),
+            result.title
+        ));
+        content.push_str(&format!("> {}\n\n", result.snippet));
+    }
+    content.push_str(&format!(
+        "=> {prefix}/search 🔍 {}\n=> {prefix}/blog ⬅ {}\n",
+        strings.search, strings.back
+    ));
+    format!("20 text/gemini\r\n{content}").as_bytes().to_vec()
+}
+
+/// Today's quote and links to the old ones, or the quote from the day in the
+/// query.
+fn qotd_gmi(current: &[u8], query: Option<&str>) -> Vec<u8> {
+    let quote_lines = |quote: &str| {
+        quote
+            .lines()
+            .map(|line| format!("> {line}\n"))
+            .collect::<String>()
+    };
+
+    if let Some(query) = query {
+        let Some(quote) = NaiveDate::parse_from_str(query, "%Y-%m-%d")
+            .ok()
+            .and_then(qotd::on)
+        else {
+            return b"51 No quote for that day\r\n".to_vec();
+        };
+        return format!(
+            "20 text/gemini\r\n# Quote of the day on {query}\n\n{}\n=> /qotd ⬅ Back\n",
+            quote_lines(&quote)
+        )
+        .as_bytes()
+        .to_vec();
+    }
+
+    let current = String::from_utf8_lossy(current);
+    let current = current
+        .strip_prefix("Quote of the day:\n")
+        .unwrap_or(&current);
+    let mut content = format!("# Quote of the day\n\n{}\n", quote_lines(current));
+    content.push_str("## History\n\n");
+    for (date, _) in qotd::history() {
+        content.push_str(&format!("=> /qotd?{date} {date}\n"));
+    }
+    format!("20 text/gemini\r\n{content}").as_bytes().to_vec()
+}
@@ -2,23 +2,42 @@ use std::{
     collections::HashMap,
     fmt::{Display, Formatter},
     io::{self},
-    path::Path,
-    sync::Arc,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
 };
 
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
 };
+use tokio_rustls::TlsAcceptor;
 
 use crate::{
-    crawl::{ImageSource, PostPart, SiteData},
-    HOSTNAME,
+    access_log, analytics,
+    bandwidth::{self, Throttled},
+    banner,
+    cache::ResponseCache,
+    comments,
+    crawl::{list_lines, ImageSource, ListItem, Post, PostSort, SiteData, STALE_NOTICE},
+    drafts, lifecycle,
+    links::{self, Href},
+    listen::Listener,
+    media::Media,
+    onion_address, related,
+    routes::{self, Scheme},
+    search::SearchIndex,
+    server_info, table, thumbnail, timeouts, tls, transcode, HOSTNAME,
 };
 
-use super::Protocol;
+use super::{
+    alternate::{self, AlternateFormat},
+    error::ProtocolError,
+    render::{self, Link, PostVisitor},
+    tracker, Artifact, Export, Protocol, ServerConfig, Shutdown,
+};
 
-const BIND_HOST: &str = "[::]";
 const BIND_PORT: u16 = {
     #[cfg(debug_assertions)]
     {
@@ -27,17 +46,59 @@ const BIND_PORT: u16 = {
     #[cfg(not(debug_assertions))]
     70
 };
+/// Used for gophers:// when [`Gopher::tls`] is enabled.
+const TLS_BIND_PORT: u16 = 7443;
+/// The longest selector and search query that we'll read, together.
+const MAX_REQUEST_LENGTH: usize = 2048;
+
+/// How many posts long menus like the blog show before they're split into
+/// pages, if it wasn't changed with `--gopher-items-per-page`.
+const DEFAULT_ITEMS_PER_PAGE: usize = 50;
+
+static LINK_STYLE: OnceLock<LinkStyle> = OnceLock::new();
+static ITEMS_PER_PAGE: OnceLock<usize> = OnceLock::new();
+
+/// How links to things that aren't on gopher are written in menus. It's
+/// changed with `--gopher-links url|text`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LinkStyle {
+    /// `h` items with a `URL:` selector, which most clients open directly.
+    /// The ones that don't understand it ask us for the selector and get a
+    /// page that redirects, see [`url_redirect`].
+    #[default]
+    Url,
+    /// Info lines with the URL written out, for strict clients that only know
+    /// the item types from RFC 1436.
+    Text,
+}
+
+impl LinkStyle {
+    pub fn from_name(name: &str) -> Option<LinkStyle> {
+        match name {
+            "url" => Some(LinkStyle::Url),
+            "text" => Some(LinkStyle::Text),
+            _ => None,
+        }
+    }
+}
+
+/// Change how external links are written. This can only be done once, before
+/// the menus are generated.
+pub fn set_link_style(style: LinkStyle) {
+    LINK_STYLE
+        .set(style)
+        .expect("the gopher link style was already set");
+}
 
-const INDEX_HEADER: &str = r#"                       888        888                                 888                   
-                       888        888                                 888                   
-                       888        888                                 888                   
-88888b.d88b.   8888b.  888888 .d88888  .d88b.   .d88b.  .d8888b   .d88888  .d88b.  888  888 
-888 "888 "88b     "88b 888   d88" 888 d88""88b d8P  Y8b 88K      d88" 888 d8P  Y8b 888  888 
-888  888  888 .d888888 888   888  888 888  888 88888888 "Y8888b. 888  888 88888888 Y88  88P 
-888  888  888 888  888 Y88b. Y88b 888 Y88..88P Y8b.          X88 Y88b 888 Y8b.      Y8bd8P  
-888  888  888 "Y888888  "Y888 "Y88888  "Y88P"   "Y8888   88888P'  "Y88888  "Y8888    Y88P
+/// Change how many items the menus that are split into pages have on each
+/// page. Like the link style, it has to be set before the menus are generated.
+pub fn set_items_per_page(items_per_page: usize) {
+    ITEMS_PER_PAGE
+        .set(items_per_page)
+        .expect("the gopher items per page were already set");
+}
 
-I'm mat, I do full-stack software development.
+const ABOUT: &str = r#"I'm mat, I do full-stack software development.
 This portfolio contains my blog posts and links to some of the projects I've made.
 "#;
 
@@ -52,19 +113,47 @@ This portfolio contains my blog posts and links to some of the projects I've mad
 pub struct Gopher {
     pub index_content: String,
     pub blog_content: String,
-    pub posts_content: HashMap<String, String>,
+    /// The blog with the longest posts first.
+    pub blog_by_length_content: String,
+    /// Kept as buffers so the comments can be added when they're requested.
+    pub posts_content: HashMap<String, GopherBuffer>,
+    /// Draft posts by their preview token.
+    pub drafts_content: HashMap<String, String>,
     pub projects_content: String,
-}
-
-pub struct Link {
-    pub text: String,
-    pub href: String,
+    pub tags_content: String,
+    /// The posts with each tag, by the tag.
+    pub tag_pages_content: HashMap<String, String>,
+    /// The pages after the first one of the menus that are split into pages,
+    /// by their selector like `/blog/page/2`.
+    pub later_pages_content: HashMap<String, String>,
+    pub downloads_content: String,
+    /// The capabilities file that some clients and crawlers look for, see
+    /// [`caps_txt`].
+    pub caps_txt: String,
+    /// Whether to also listen for gopher over tls.
+    pub tls: bool,
+    /// The other sites that are served here, by lowercase hostname.
+    pub sites: HashMap<String, Arc<Gopher>>,
+    /// The ASCII art thumbnails of the images in posts, by the image's path
+    /// in the media directory.
+    pub thumbs_content: HashMap<String, String>,
+    /// The posts, for making their other formats when they're requested.
+    pub blog: Vec<Post>,
+    /// This site's posts, since the shared index only has the main site's.
+    pub search: SearchIndex,
+    cache: ResponseCache,
 }
 
 #[derive(Default, Clone)]
 pub struct GopherBuffer {
     pub buffer: String,
     pub out: String,
+    /// The most items that are on each page when the menu is split with
+    /// [`Self::pages`]. `None` keeps it as one page.
+    pub items_per_page: Option<usize>,
+    /// Where the items that are split between the pages start in `out`.
+    /// Everything before it is at the top of every page.
+    list_start: usize,
 }
 
 impl GopherBuffer {
@@ -72,6 +161,26 @@ impl GopherBuffer {
         Self::default()
     }
 
+    /// A menu that's split into pages of the configured size, see
+    /// [`set_items_per_page`].
+    pub fn paginated() -> Self {
+        Self {
+            items_per_page: Some(
+                ITEMS_PER_PAGE
+                    .get()
+                    .copied()
+                    .unwrap_or(DEFAULT_ITEMS_PER_PAGE),
+            ),
+            ..Self::default()
+        }
+    }
+
+    /// Start the part of the menu that's split between the pages.
+    pub fn start_list(&mut self) {
+        self.flush();
+        self.list_start = self.out.len();
+    }
+
     pub fn text(&mut self, content: &str) {
         self.buffer.push_str(content);
     }
@@ -96,26 +205,167 @@ impl GopherBuffer {
         }
     }
 
+    /// A link to something on this server, with the item type for what it
+    /// points to.
     pub fn link(&mut self, href: &str, text: &str) {
         self.flush();
+        let item_type = item_type(href);
         for line in text.lines() {
-            self.out
-                .push_str(&format!("1{line}\t{href}\t{HOSTNAME}\t{BIND_PORT}\r\n"));
+            self.out.push_str(&format!(
+                "{item_type}{line}\t{href}\t{HOSTNAME}\t{BIND_PORT}\r\n"
+            ));
         }
     }
 
+    /// A search item, which clients send the query to after a tab.
+    pub fn search(&mut self, href: &str, text: &str) {
+        self.flush();
+        self.out
+            .push_str(&format!("7{text}\t{href}\t{HOSTNAME}\t{BIND_PORT}\r\n"));
+    }
+
     pub fn image(&mut self, href: &str, alt: &str) {
+        self.flush();
+        // gifs have their own type, and everything else is a generic image
+        let item_type = match item_type(href) {
+            'g' => 'g',
+            _ => 'I',
+        };
+        self.out.push_str(&format!(
+            "{item_type}{alt}\t{href}\t{HOSTNAME}\t{BIND_PORT}\r\n"
+        ));
+    }
+
+    /// A binary file, which clients save instead of showing.
+    pub fn binary(&mut self, href: &str, text: &str) {
+        self.flush();
+        self.out
+            .push_str(&format!("9{text}\t{href}\t{HOSTNAME}\t{BIND_PORT}\r\n"));
+    }
+
+    /// A text file, which clients show as it is.
+    pub fn document(&mut self, href: &str, text: &str) {
         self.flush();
         self.out
-            .push_str(&format!("I{alt}\t{href}\t{HOSTNAME}\t{BIND_PORT}\r\n"));
+            .push_str(&format!("0{text}\t{href}\t{HOSTNAME}\t{BIND_PORT}\r\n"));
     }
 
     pub fn external_link(&mut self, href: &str, text: &str) {
         self.flush();
-        for line in text.lines() {
-            self.out
-                .push_str(&format!("h{line}\tURL:{href}\t\t443\r\n"));
+        match LINK_STYLE.get().copied().unwrap_or_default() {
+            LinkStyle::Url => {
+                for line in text.lines() {
+                    self.out
+                        .push_str(&format!("h{line}\tURL:{href}\t{HOSTNAME}\t{BIND_PORT}\r\n"));
+                }
+            }
+            LinkStyle::Text => {
+                for line in text.lines() {
+                    self.out.push_str(&format!("i{line}\tfake\tnull\t0\r\n"));
+                }
+                self.out.push_str(&format!("i{href}\tfake\tnull\t0\r\n"));
+            }
+        }
+    }
+}
+
+impl GopherBuffer {
+    /// The menu split into pages of at most [`Self::items_per_page`] items
+    /// each, with links between them. The first page is at `selector` and the
+    /// rest are at [`page_selector`]. Info lines go on the same page as the
+    /// item after them, so a heading isn't separated from what it's for.
+    pub fn pages(&self, selector: &str) -> Vec<String> {
+        let mut flushed = self.clone();
+        flushed.flush();
+        let Some(items_per_page) = self.items_per_page.filter(|&n| n > 0) else {
+            return vec![flushed.to_string()];
+        };
+        let (header, list) = flushed.out.split_at(self.list_start);
+
+        let mut items = Vec::new();
+        let mut pending = String::new();
+        for line in list.split_inclusive("\r\n") {
+            pending.push_str(line);
+            if !line.starts_with('i') {
+                items.push(std::mem::take(&mut pending));
+            }
+        }
+        let mut pages = items
+            .chunks(items_per_page)
+            .map(|items| items.concat())
+            .collect::<Vec<_>>();
+        match pages.last_mut() {
+            Some(last) => last.push_str(&pending),
+            None => pages.push(pending),
         }
+
+        let count = pages.len();
+        pages
+            .into_iter()
+            .enumerate()
+            .map(|(i, items)| {
+                let mut page = GopherBuffer {
+                    out: format!("{header}{items}"),
+                    ..GopherBuffer::new()
+                };
+                let number = i + 1;
+                if count > 1 {
+                    page.line("");
+                    page.line(&format!("Page {number} of {count}"));
+                    if number > 1 {
+                        page.link(&page_selector(selector, number - 1), "Previous page");
+                    }
+                    if number < count {
+                        page.link(&page_selector(selector, number + 1), "Next page");
+                    }
+                }
+                page.to_string()
+            })
+            .collect()
+    }
+}
+
+/// The selector of a page of a menu that's split into pages. The first page
+/// is the menu's own selector, like `/blog`, and the rest are like
+/// `/blog/page/2`.
+fn page_selector(selector: &str, number: usize) -> String {
+    match number {
+        1 => selector.to_owned(),
+        _ => format!("{}/page/{number}", selector.trim_end_matches('/')),
+    }
+}
+
+/// Put every page of the menu except the first in `later_pages`, and return
+/// the first.
+fn paginate(
+    menu: &GopherBuffer,
+    selector: &str,
+    later_pages: &mut HashMap<String, String>,
+) -> String {
+    let mut pages = menu.pages(selector).into_iter();
+    let first = pages.next().unwrap_or_default();
+    for (i, page) in pages.enumerate() {
+        later_pages.insert(page_selector(selector, i + 2), page);
+    }
+    first
+}
+
+/// The item type of a link to something on this server, from the extension
+/// of the file it's to. Anything without an extension that we know is a menu,
+/// since that's what posts and the other pages are.
+fn item_type(href: &str) -> char {
+    let path = href.split(['?', '#']).next().unwrap_or(href);
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("gif") => 'g',
+        Some("png" | "jpg" | "jpeg" | "webp" | "avif" | "bmp" | "svg") => 'I',
+        Some("txt" | "md" | "gmi") => '0',
+        Some("html" | "htm") => 'h',
+        Some("torrent" | "zip" | "pdf" | "mp3" | "mp4" | "webm" | "ogg") => '9',
+        _ => '1',
     }
 }
 
@@ -127,129 +377,288 @@ impl Display for GopherBuffer {
     }
 }
 
+/// Writes a post's parts as info lines, with the links after their paragraph.
+/// Images that have an ASCII art thumbnail get a link to it too.
+struct GopherPost<'a>(&'a mut GopherBuffer, &'a HashMap<PathBuf, String>);
+
+impl GopherPost<'_> {
+    fn links(&mut self, links: &[Link]) {
+        for Link { href, text } in links {
+            match Href::classify(href, Scheme::Gopher) {
+                Href::Internal(path) => self.0.link(path, text),
+                _ => self.0.external_link(href, text),
+            }
+        }
+    }
+}
+
+impl PostVisitor for GopherPost<'_> {
+    const QUEUE_LINKS: bool = true;
+
+    fn text(&mut self, text: &str) {
+        self.0.text(text);
+    }
+
+    fn inline_code(&mut self, code: &str) {
+        self.0.text(&format!("`{code}`"));
+    }
+
+    fn code_block(&mut self, code: &str) {
+        self.0.line(&format!("```\n{code}\n```\n"));
+    }
+
+    fn italic(&mut self, text: &str) {
+        self.0.text(&format!("*{text}*"));
+    }
+
+    fn bold(&mut self, text: &str) {
+        self.0.text(&format!("**{text}**"));
+    }
+
+    fn image(&mut self, src: &ImageSource, alt: Option<&str>) {
+        let alt = alt.unwrap_or_default();
+        match src {
+            ImageSource::Local(path) => {
+                let href = render::media_path(path);
+                self.0.image(&href, alt);
+                if self.1.contains_key(path) {
+                    self.0
+                        .document(&format!("/thumbs{href}"), "ASCII art preview");
+                }
+            }
+            ImageSource::Remote(url) => self.0.external_link(url, alt),
+        }
+    }
+
+    fn line_break(&mut self, _repeated: bool, links: &[Link]) {
+        self.0.flush();
+        self.links(links);
+    }
+
+    fn heading(&mut self, level: usize, text: &str, _after_line_break: bool) {
+        match level {
+            1 => self.0.line(&format!("# {text}\n")),
+            2 => self.0.line(&format!("## {text}\n")),
+            3 => self.0.line(&format!("### {text}\n")),
+            _ => {}
+        }
+    }
+
+    fn quote(&mut self, text: &str) {
+        for line in text.lines() {
+            self.0.line(&format!("> {line}"));
+        }
+    }
+
+    fn footnote_reference(&mut self, label: &str) {
+        self.0.text(&format!("[{label}]"));
+    }
+
+    fn horizontal_rule(&mut self) {
+        self.0.line("");
+        self.0.line(&"-".repeat(40));
+        self.0.line("");
+    }
+
+    fn definition_list(&mut self, definitions: &[(String, String)]) {
+        for (term, definition) in definitions {
+            self.0.line(term);
+            for line in definition.lines() {
+                self.0.line(&format!("> {line}"));
+            }
+            self.0.line("");
+        }
+    }
+
+    fn caption(&mut self, text: &str) {
+        self.0.line(&format!("*{text}*"));
+    }
+
+    fn table(&mut self, rows: &[Vec<String>]) {
+        for line in table::render(rows, 80, &table::ASCII) {
+            self.0.line(&line);
+        }
+    }
+
+    fn list(&mut self, ordered: bool, items: &[ListItem], _after_line_break: bool) {
+        for line in list_lines(ordered, items) {
+            let marker = match line.number {
+                Some(number) => format!("{number}."),
+                None => "*".to_owned(),
+            };
+            self.0.line(&format!(
+                "{}{marker} {}",
+                "  ".repeat(line.depth),
+                line.text
+            ));
+        }
+        self.0.line("");
+    }
+
+    fn end(&mut self, links: &[Link]) {
+        self.links(links);
+    }
+
+    fn footnotes(&mut self, footnotes: &[(&str, &str)]) {
+        self.0.line("");
+        self.0.line("## Notes\n");
+        for (id, text) in footnotes {
+            self.0.line(&format!("[{id}] {text}"));
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct GopherOptions {
+    /// Whether to also listen for gopher over tls.
+    pub tls: bool,
+    /// The other sites that are served here, by lowercase hostname.
+    pub sites: HashMap<String, Arc<Gopher>>,
+}
+
 impl Protocol for Gopher {
-    fn generate(data: &SiteData) -> Self {
+    type Options = GopherOptions;
+
+    fn generate(data: &SiteData, options: &Self::Options) -> Self {
         let mut index_content = GopherBuffer::new();
-        index_content.line(INDEX_HEADER);
+        index_content.line(&banner::for_protocol("gopher"));
+        index_content.line("");
+        if data.stale {
+            index_content.line(STALE_NOTICE);
+            index_content.line("");
+        }
+        index_content.line(ABOUT);
+        // point tor users to the onion service, if we have one
+        if let Some(address) = onion_address() {
+            index_content.line("");
+            index_content.line(&format!("Also on Tor at gopher://{address}"));
+        }
 
         index_content.line("");
         index_content.link("/blog", "Blog");
         index_content.link("/projects", "Projects");
+        index_content.link("/downloads", "Downloads");
+        index_content.link("/about-server", "Server");
+        index_content.link("/playground", "Playground");
         index_content.line("");
         index_content.external_link("https://github.com/mat-1", "GitHub");
         index_content.external_link("https://matrix.to/#/@mat:matdoes.dev", "Matrix");
         index_content.external_link("https://ko-fi.com/matdoesdev", "Ko-fi (donate)");
 
-        let mut blog_content = GopherBuffer::new();
-        blog_content.line("# Blog");
-        blog_content.line("");
+        let blog_menu = |sort: PostSort| {
+            let mut menu = GopherBuffer::paginated();
+            menu.line("# Blog");
+            menu.line("");
+            menu.link("/tags", "Tags");
+            menu.search("/search", "Search");
+            match sort {
+                PostSort::Date => menu.link("/blog/by-length", "Sort: Longest"),
+                PostSort::Length => menu.link("/blog", "Sort: Newest"),
+            }
+            menu.line("");
+            menu.start_list();
+            for post in sort.sort(drafts::published(&data.blog)) {
+                let date = post.published.format("%Y-%m-%d");
+                menu.link(
+                    &format!("/{}", post.slug),
+                    &format!(
+                        "{date} - {} ({} min read)",
+                        post.title,
+                        post.reading_minutes()
+                    ),
+                );
+            }
+            menu
+        };
+        let mut later_pages_content = HashMap::new();
+        let blog_content = paginate(
+            &blog_menu(PostSort::Date),
+            "/blog",
+            &mut later_pages_content,
+        );
+        let blog_by_length_content = paginate(
+            &blog_menu(PostSort::Length),
+            "/blog/by-length",
+            &mut later_pages_content,
+        );
 
         let mut posts_content = HashMap::new();
+        let mut drafts_content = HashMap::new();
+        let related = related::related_posts(&data.blog);
+        let thumbnails = thumbnail::for_posts(&data.blog);
         for post in &data.blog {
             let slug = &post.slug;
             let date = post.published.format("%Y-%m-%d").to_string();
             let title = &post.title;
-            // add it to the index
-            blog_content.link(&format!("/{slug}"), &format!("{date} - {title}"));
             // generate the content
             let mut out = GopherBuffer::new();
 
             out.line(&format!("# {title}"));
             out.line(&date);
+            out.line(&format!(
+                "{} min read, {} words",
+                post.reading_minutes(),
+                post.word_count
+            ));
             out.line("");
 
-            let mut queued_links: Vec<Link> = Vec::new();
-            for (i, part) in post.content.iter().enumerate() {
-                match part {
-                    PostPart::Text(content) => out.text(content),
-                    PostPart::CodeBlock(content) => {
-                        out.line(&format!("```\n{content}\n```\n"));
-                    }
-                    PostPart::InlineCode(text) => {
-                        out.text(&format!("`{text}`"));
-                    }
-                    PostPart::Image { src, alt } => {
-                        match src {
-                            ImageSource::Local(path) => {
-                                // get the path relative to the media directory
-                                let local_path = path
-                                    .to_string_lossy()
-                                    .into_owned()
-                                    .strip_prefix(
-                                        &Path::new("media").to_string_lossy().into_owned(),
-                                    )
-                                    .unwrap()
-                                    .to_string();
-                                out.image(&local_path, &alt.to_owned().unwrap_or_default());
-                            }
-                            ImageSource::Remote(url) => {
-                                out.external_link(url, &alt.to_owned().unwrap_or_default());
-                            }
-                        };
-                    }
-                    PostPart::Link { text, href } => {
-                        queued_links.push(Link {
-                            text: text.to_owned(),
-                            href: match href {
-                                h if h.starts_with("https://gemini.circumlunar.space/") => {
-                                    // replace the https:// with gemini://
-                                    h.replacen("https://", "gemini://", 1)
-                                }
-                                h if h.starts_with("https://gmi.skyjake.fi/") => {
-                                    // replace the https://gmi. with gemini://
-                                    h.replacen("https://gmi.", "gemini://", 1)
-                                }
-                                h => h.to_owned(),
-                            },
-                        });
-                        // add the link text unless the part before and after are line breaks
-                        let before_is_line_break =
-                            i == 0 || matches!(post.content[i - 1], PostPart::LineBreak);
-                        let after_is_line_break = i == post.content.len() - 1
-                            || matches!(post.content[i + 1], PostPart::LineBreak);
-                        if before_is_line_break && after_is_line_break {
-                            // remove the last line break too
-                            // out.pop();
-                        } else {
-                            out.text(text);
-                        }
-                    }
-                    PostPart::LineBreak => {
-                        out.flush();
-                        if !queued_links.is_empty() {
-                            // flush the queued links
-                            for Link { href, text } in queued_links.drain(..) {
-                                out.link(&href, &text);
-                            }
-                        }
-                        continue;
-                    }
-                    PostPart::Heading { level, text } => match level {
-                        1 => out.line(&format!("# {text}\n")),
-                        2 => out.line(&format!("## {text}\n")),
-                        3 => out.line(&format!("### {text}\n")),
-                        _ => {}
-                    },
-                    PostPart::Italic(text) => {
-                        out.line(&format!("*{text}*"));
-                    }
-                    PostPart::Bold(text) => {
-                        out.line(&format!("**{text}**"));
-                    }
-                    PostPart::Quote(text) => {
-                        for line in text.lines() {
-                            out.line(&format!("> {line}\n"));
-                        }
-                    }
-                }
+            render::visit_post(&post.content, &mut GopherPost(&mut out, &thumbnails));
+
+            out.line("");
+            for format in AlternateFormat::ALL {
+                out.document(&format.path(slug), &format!("Download: {}", format.name()));
             }
-            // flush the queued links
-            for Link { href, text } in queued_links.drain(..) {
-                out.link(&href, &text);
+
+            if let Some(related) = related.get(slug).filter(|r| !r.is_empty()) {
+                out.line("");
+                out.line("## Related posts");
+                out.line("");
+                for related_post in related {
+                    out.link(&format!("/{}", related_post.slug), &related_post.title);
+                }
             }
 
             // add the content to the posts map
-            posts_content.insert(slug.to_string(), out.to_string());
+            if !post.draft {
+                posts_content.insert(slug.to_string(), out);
+            } else if let Some(token) = drafts::preview_token(slug) {
+                drafts_content.insert(token, out.to_string());
+            }
+        }
+
+        // tags
+        let mut tags_content = GopherBuffer::paginated();
+        tags_content.line("# Tags");
+        tags_content.line("");
+        tags_content.start_list();
+        let mut tag_pages_content = HashMap::new();
+        for (tag, tagged_posts) in data.tags() {
+            tags_content.link(
+                &format!("/tag/{tag}"),
+                &format!("{tag} ({})", tagged_posts.len()),
+            );
+
+            let mut tag_content = GopherBuffer::paginated();
+            tag_content.line(&format!("# Posts tagged {tag}"));
+            tag_content.line("");
+            tag_content.start_list();
+            for post in tagged_posts {
+                let date = post.published.format("%Y-%m-%d");
+                tag_content.link(
+                    &format!("/{}", post.slug),
+                    &format!("{date} - {}", post.title),
+                );
+            }
+            tag_content.line("");
+            tag_content.link("/tags", "All tags");
+            tag_pages_content.insert(
+                tag.to_owned(),
+                paginate(
+                    &tag_content,
+                    &format!("/tag/{tag}"),
+                    &mut later_pages_content,
+                ),
+            );
         }
 
         // projects
@@ -264,14 +673,12 @@ impl Protocol for Gopher {
             // only include the link if it's different from the source
             if project.href != project.source {
                 if let Some(href) = &project.href {
-                    if href.starts_with('/') {
-                        projects_content.link(href, href);
-                    } else {
-                        let pretty_href = href
-                            .strip_prefix("https://")
-                            .unwrap_or(href.strip_prefix("http://").unwrap_or(href));
-                        let pretty_href = pretty_href.strip_suffix('/').unwrap_or(pretty_href);
-                        projects_content.external_link(href, pretty_href);
+                    match Href::classify(href, Scheme::Gopher) {
+                        Href::Internal(path) => projects_content.link(path, path),
+                        href => {
+                            let href = href.into_href();
+                            projects_content.external_link(&href, links::pretty(&href));
+                        }
                     }
                 }
             }
@@ -306,105 +713,587 @@ impl Protocol for Gopher {
             }
         }
 
+        let mut downloads_content = GopherBuffer::new();
+        downloads_content.line("# Downloads");
+        downloads_content.line("");
+        downloads_content.line("Torrents of the media on the site and of every post as text.");
+        downloads_content.line("");
+        for torrent in tracker::torrents() {
+            downloads_content.binary(
+                &format!("/downloads/{}", torrent.file_name),
+                &format!("{} ({})", torrent.name, torrent.human_size()),
+            );
+        }
+
         Gopher {
             index_content: index_content.to_string(),
-            blog_content: blog_content.to_string(),
+            blog_content,
+            blog_by_length_content,
             posts_content,
+            drafts_content,
             projects_content: projects_content.to_string(),
+            tags_content: paginate(&tags_content, "/tags", &mut later_pages_content),
+            tag_pages_content,
+            later_pages_content,
+            downloads_content: downloads_content.to_string(),
+            caps_txt: caps_txt(data),
+            search: SearchIndex::new(&data.blog),
+            tls: options.tls,
+            sites: options.sites.clone(),
+            thumbs_content: thumbnails
+                .iter()
+                .map(|(path, art)| {
+                    let href = render::media_path(path);
+                    (href.trim_start_matches('/').to_owned(), text_file(art))
+                })
+                .collect(),
+            blog: data.blog.clone(),
+            cache: ResponseCache::default(),
         }
     }
 
-    async fn serve(self) {
-        // start a tcp server
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        let tls_port = config.tls_port_or(TLS_BIND_PORT);
+        shutdown
+            .run(async move {
+                let gopher = Arc::new(self);
+
+                if gopher.tls {
+                    tokio::join!(
+                        listen(Arc::clone(&gopher), port, None),
+                        listen(gopher, tls_port, Some(tls::acceptor()))
+                    );
+                } else {
+                    listen(gopher, port, None).await;
+                }
+            })
+            .await;
+    }
+}
+
+/// Start a tcp server, with tls if there's an acceptor.
+async fn listen(gopher: Arc<Gopher>, port: u16, acceptor: Option<TlsAcceptor>) {
+    let Some(listener) = Listener::bind("gopher", port) else {
+        return;
+    };
+
+    loop {
+        let (stream, remote_addr) = listener.accept().await.unwrap();
+        gopher.handle_connection(stream, remote_addr, acceptor.clone());
+    }
+}
 
-        let gopher = Arc::new(self);
+impl Gopher {
+    /// Answer the request on a connection that's already been accepted, in
+    /// its own task. It's wrapped in tls if there's an acceptor.
+    pub fn handle_connection(
+        self: &Arc<Self>,
+        stream: TcpStream,
+        remote_addr: SocketAddr,
+        acceptor: Option<TlsAcceptor>,
+    ) {
+        println!("started tcp connection for gopher: {remote_addr:?}");
 
-        let listener = match TcpListener::bind(format!("{BIND_HOST}:{BIND_PORT}")).await {
-            Ok(listener) => listener,
-            Err(e) => {
-                eprintln!("failed to bind to port {BIND_PORT}: {e}");
-                return;
+        let gopher = Arc::clone(self);
+        let fut = async move {
+            match acceptor {
+                Some(acceptor) => {
+                    let stream =
+                        timeout(timeouts::read_timeout(), acceptor.accept(stream)).await??;
+                    let stream = bandwidth::throttle("gopher", remote_addr.ip(), stream);
+                    handle(gopher, stream, remote_addr, true).await
+                }
+                None => {
+                    let stream = bandwidth::throttle("gopher", remote_addr.ip(), stream);
+                    handle(gopher, stream, remote_addr, false).await
+                }
             }
         };
 
-        loop {
-            let (mut stream, remote_addr) = listener.accept().await.unwrap();
-            println!("started tcp connection for gopher: {remote_addr:?}");
+        tokio::spawn(async move {
+            let _session = lifecycle::Session::start("gopher");
+            if let Err(err) = fut.await {
+                eprintln!("{:?}", err);
+            }
+        });
+    }
+}
 
-            let gopher = Arc::clone(&gopher);
-            let fut = async move {
-                let response = respond(gopher, &mut stream)
-                    .await
-                    .unwrap_or(b"iNot found\tfake\t(NULL)\t0\r\n".to_vec());
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+    gopher: Arc<Gopher>,
+    mut stream: Throttled<S>,
+    remote_addr: SocketAddr,
+    is_tls: bool,
+) -> io::Result<()> {
+    let mut log = access_log::Entry::start("gopher", remote_addr.ip());
+    let (response, status) = match respond(gopher, &mut stream, &mut log, remote_addr, is_tls).await
+    {
+        Ok(response) => (response, "ok"),
+        Err(err) => {
+            if err.is_internal() {
+                eprintln!("error handling gopher request: {err}");
+            }
+            match error_item(&err) {
+                Some(item) => (item.into(), err.name()),
+                None => {
+                    log.finish(err.name(), stream.written());
+                    return Ok(());
+                }
+            }
+        }
+    };
 
-                stream.write_all(&response).await?;
-                stream.shutdown().await?;
+    let result = timeouts::write(stream.write_all(&response)).await;
+    log.finish(status, stream.written());
+    result?;
+    stream.shutdown().await?;
+    Ok(())
+}
 
-                Ok(()) as io::Result<()>
-            };
+/// A type `3` item, which clients show as an error instead of as a line of
+/// the menu. Returns `None` if there's no one to send it to.
+fn error_item(err: &ProtocolError) -> Option<Vec<u8>> {
+    let message = match err {
+        ProtocolError::BadRequest(message) | ProtocolError::PolicyViolation(message) => {
+            message.as_str()
+        }
+        ProtocolError::NotFound => "Not found",
+        ProtocolError::ClientDisconnected => return None,
+        ProtocolError::TimedOut => "Request timed out",
+        ProtocolError::Internal(_) => "Internal error",
+    };
+    Some(format!("3{message}\tfake\t(NULL)\t0\r\n.").into_bytes())
+}
 
-            tokio::spawn(async move {
-                if let Err(err) = fut.await {
-                    eprintln!("{:?}", err);
-                }
-            });
+impl Export for Gopher {
+    /// Every menu is written as a `gophermap` in its own directory, which is
+    /// what servers like Gophernicus expect.
+    fn artifacts(&self) -> Vec<Artifact> {
+        // gophermaps don't have the . at the end
+        let gophermap = |content: &str| content.strip_suffix('.').unwrap_or(content).to_owned();
+
+        let mut artifacts = vec![
+            Artifact::new("gophermap", gophermap(&self.index_content)),
+            Artifact::new("caps.txt", &self.caps_txt),
+            Artifact::new("blog/gophermap", gophermap(&self.blog_content)),
+            Artifact::new(
+                "blog/by-length/gophermap",
+                gophermap(&self.blog_by_length_content),
+            ),
+            Artifact::new("projects/gophermap", gophermap(&self.projects_content)),
+            Artifact::new("tags/gophermap", gophermap(&self.tags_content)),
+            Artifact::new("downloads/gophermap", gophermap(&self.downloads_content)),
+        ];
+        for (tag, page) in &self.tag_pages_content {
+            artifacts.push(Artifact::new(
+                format!("tag/{tag}/gophermap"),
+                gophermap(page),
+            ));
+        }
+        for (selector, page) in &self.later_pages_content {
+            artifacts.push(Artifact::new(
+                format!("{}/gophermap", selector.trim_start_matches('/')),
+                gophermap(page),
+            ));
         }
+        for (slug, post) in &self.posts_content {
+            artifacts.push(Artifact::new(
+                format!("{slug}/gophermap"),
+                gophermap(&post.to_string()),
+            ));
+        }
+        artifacts
+    }
+}
+
+/// Make the links in menus stay on the tls port.
+fn tls_links(response: Vec<u8>, is_tls: bool) -> Vec<u8> {
+    if !is_tls {
+        return response;
     }
+    String::from_utf8_lossy(&response)
+        .replace(
+            &format!("\t{HOSTNAME}\t{BIND_PORT}\r\n"),
+            &format!("\t{HOSTNAME}\t{TLS_BIND_PORT}\r\n"),
+        )
+        .into_bytes()
 }
 
-async fn respond(gopher: Arc<Gopher>, stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
-    let mut retreival_string = String::new();
+/// Read the line with the selector, without the `\n`. Selectors are UTF-8,
+/// since slugs don't have to be ascii.
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> Result<String, ProtocolError> {
+    let mut request = Vec::new();
     loop {
         let c = stream.read_u8().await?;
-        if matches!(c, b'\n' | b'\t') {
-            break;
+        if c == b'\n' {
+            return String::from_utf8(request)
+                .map_err(|_| ProtocolError::BadRequest("Request is not UTF-8".to_owned()));
+        }
+        if request.len() >= MAX_REQUEST_LENGTH {
+            return Err(ProtocolError::BadRequest("Request is too large".to_owned()));
+        }
+        request.push(c);
+    }
+}
+
+impl Gopher {
+    /// The menus that are the same every time they're requested.
+    fn static_page(&self, selector: &str) -> Option<&String> {
+        match selector {
+            "/" | "" => Some(&self.index_content),
+            "/blog" => Some(&self.blog_content),
+            "/blog/by-length" => Some(&self.blog_by_length_content),
+            "/projects" => Some(&self.projects_content),
+            "/tags" => Some(&self.tags_content),
+            "/downloads" => Some(&self.downloads_content),
+            "caps.txt" | "/caps.txt" => Some(&self.caps_txt),
+            _ => {
+                if let Some(page) = self.later_pages_content.get(selector) {
+                    return Some(page);
+                }
+                let slug = selector.strip_prefix('/').unwrap_or(selector);
+                self.tag_pages_content.get(slug.strip_prefix("tag/")?)
+            }
         }
-        retreival_string.push(c as char);
     }
-    let retreival_string = retreival_string.trim_end_matches('\r').to_owned();
+}
+
+async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    gopher: Arc<Gopher>,
+    stream: &mut S,
+    log: &mut access_log::Entry,
+    remote_addr: SocketAddr,
+    is_tls: bool,
+) -> Result<Arc<[u8]>, ProtocolError> {
+    let request = timeouts::read(read_request(stream)).await?;
+    let request = request.trim_end_matches('\r');
+    // search queries come after a tab
+    let (retreival_string, query) = match request.split_once('\t') {
+        Some((selector, query)) => (selector.to_owned(), Some(query)),
+        None => (request.to_owned(), None),
+    };
 
     println!("Gopher request: {retreival_string:?}");
+    log.request(request);
+
+    let slug = retreival_string
+        .strip_prefix('/')
+        .unwrap_or(&retreival_string);
+    analytics::record(
+        "gopher",
+        &retreival_string,
+        gopher.posts_content.contains_key(slug).then_some(slug),
+        remote_addr.ip(),
+    );
+
+    if let Some((hostname, selector)) = split_site(&retreival_string) {
+        let site = gopher
+            .sites
+            .get(&hostname.to_lowercase())
+            .ok_or(ProtocolError::NotFound)?;
+        let response = respond_to_selector(site, stream, selector, query, is_tls).await?;
+        return Ok(site_links(&response, hostname).into());
+    }
+
+    respond_to_selector(&gopher, stream, &retreival_string, query, is_tls).await
+}
+
+async fn respond_to_selector<S: AsyncRead + AsyncWrite + Unpin>(
+    gopher: &Gopher,
+    stream: &mut S,
+    retreival_string: &str,
+    query: Option<&str>,
+    is_tls: bool,
+) -> Result<Arc<[u8]>, ProtocolError> {
+    if let Some(page) = gopher.static_page(retreival_string) {
+        let route = if is_tls {
+            format!("tls:{retreival_string}")
+        } else {
+            retreival_string.to_owned()
+        };
+        return Ok(gopher
+            .cache
+            .get_or_insert_with(&route, || tls_links(page.as_bytes().to_vec(), is_tls)));
+    }
+
+    let response = respond_uncached(gopher, stream, retreival_string, query).await?;
+    Ok(tls_links(response, is_tls).into())
+}
+
+/// The server's capabilities in the format from gopher.floodgap.com's
+/// `caps.txt`, so clients know how our selectors work. The selectors are
+/// listed at the end as comments, for crawlers.
+fn caps_txt(data: &SiteData) -> String {
+    let mut lines = vec![
+        "CAPS".to_owned(),
+        String::new(),
+        "CapsVersion=1".to_owned(),
+        "ExpireCapsAfter=3600".to_owned(),
+        String::new(),
+        "PathDelimeter=/".to_owned(),
+        "PathIdentity=.".to_owned(),
+        "PathParent=..".to_owned(),
+        "PathParentDouble=FALSE".to_owned(),
+        "PathEscapeCharacter=\\".to_owned(),
+        "PathKeepPreDelimeter=FALSE".to_owned(),
+        String::new(),
+        "ServerSoftware=matdoesdev-protocols".to_owned(),
+        format!("ServerSoftwareVersion={}", env!("CARGO_PKG_VERSION")),
+        "ServerDefaultEncoding=utf-8".to_owned(),
+        String::new(),
+        "# Selectors:".to_owned(),
+    ];
+    for route in routes::for_scheme(data, Scheme::Gopher) {
+        lines.push(format!("# {}", route.path));
+    }
+    format!("{}\r\n.", lines.join("\r\n"))
+}
 
-    let content = match retreival_string.as_str() {
-        "/" | "" => gopher.index_content.as_bytes().to_vec(),
-        "/blog" => gopher.blog_content.as_bytes().to_vec(),
-        "/projects" => gopher.projects_content.as_bytes().to_vec(),
+/// Split a selector like `/@example.com/blog` into the hostname of the site
+/// it's for and the selector on that site.
+fn split_site(selector: &str) -> Option<(&str, &str)> {
+    let rest = selector.strip_prefix("/@")?;
+    Some(match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    })
+}
+
+/// Make the links in another site's menus stay on that site, by putting its
+/// hostname before the selectors that point here.
+fn site_links(response: &[u8], hostname: &str) -> Vec<u8> {
+    String::from_utf8_lossy(response)
+        .split("\r\n")
+        .map(|line| {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            match fields.as_slice() {
+                // URL: selectors are the same on every site
+                [item, selector, host, rest @ ..]
+                    if *host == HOSTNAME && !selector.starts_with("URL:") =>
+                {
+                    let selector = format!("/@{hostname}/{}", selector.trim_start_matches('/'));
+                    [*item, selector.as_str(), *host]
+                        .into_iter()
+                        .chain(rest.iter().copied())
+                        .collect::<Vec<_>>()
+                        .join("\t")
+                }
+                _ => line.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+        .into_bytes()
+}
+
+async fn respond_uncached<S: AsyncRead + AsyncWrite + Unpin>(
+    gopher: &Gopher,
+    stream: &mut S,
+    retreival_string: &str,
+    query: Option<&str>,
+) -> Result<Vec<u8>, ProtocolError> {
+    if let Some(url) = retreival_string.strip_prefix("URL:") {
+        return Ok(url_redirect(url).into_bytes());
+    }
+    let content = match retreival_string {
+        "/search" => search_menu(&gopher.search, query.unwrap_or_default()),
+        "/about-server" => server_info_menu(),
+        "/playground" => playground_menu(),
         path => {
             let slug = match path.strip_prefix('/') {
                 Some(slug) => slug,
                 None => path,
             };
+            if slug.starts_with("tag/") {
+                return Err(ProtocolError::NotFound);
+            }
+            if let Some(token) = slug.strip_prefix("draft/") {
+                let post = gopher
+                    .drafts_content
+                    .get(token)
+                    .ok_or(ProtocolError::NotFound)?;
+                return Ok(post.as_bytes().to_vec());
+            }
+            if let Some(file_name) = slug.strip_prefix("downloads/") {
+                let Some(torrent) = tracker::torrent(file_name) else {
+                    return Err(ProtocolError::NotFound);
+                };
+                // written directly since the response is turned into a string
+                // for tls, which would break it
+                timeouts::write(stream.write_all(&torrent.metainfo)).await?;
+                return Ok(Vec::new());
+            }
+            if let Some(image) = slug.strip_prefix("thumbs/") {
+                let thumb = gopher
+                    .thumbs_content
+                    .get(image)
+                    .ok_or(ProtocolError::NotFound)?;
+                return Ok(thumb.as_bytes().to_vec());
+            }
+            if let Some((_, content)) = alternate::render_path(&gopher.blog, path) {
+                return Ok(text_file(&content).into_bytes());
+            }
             // if it has another slash, that means it's media
             if slug.contains('/') {
-                // get the path relative to the media directory
-                let path = slug;
-                // this feels completely safe and not dangerous at all
-
-                let path = Path::new("media").join(path);
-                if !path
-                    .components()
-                    .all(|x| matches!(x, std::path::Component::Normal(..)))
-                {
-                    return Ok(b"inyaa~ >_<\tfake\t(NULL)\t0\r\n".to_vec());
-                }
-                let mime = mime_guess::from_path(&path).first_or_octet_stream();
-                let mime = mime.to_string();
-                println!("path: {path:?}, mime: {mime}");
-                let Ok(mut file) = tokio::fs::File::open(path).await else {
-                    return Ok(b"iNot found\tfake\t(NULL)\t0\r\n".to_vec());
+                // get the path relative to the media directory, in a format
+                // that the client can show
+                let Some(path) = transcode::resolve(slug, true).await else {
+                    return Err(ProtocolError::PolicyViolation("nyaa~ >_<".to_owned()));
                 };
-                let mut content = Vec::new();
-                let _ = file.read_to_end(&mut content).await;
-                content.extend_from_slice(b"\r\n");
-                content
+                println!("path: {path:?}");
+                let Ok(file) = Media::open(&path).await else {
+                    return Err(ProtocolError::NotFound);
+                };
+                // the file is written directly so it doesn't all have to be in
+                // memory
+                timeouts::write(file.send(stream)).await?;
+                Vec::new()
             } else {
-                match gopher.posts_content.get(slug) {
-                    Some(post) => post.as_bytes().to_vec(),
-                    None => b"iNot found\tfake\t(NULL)\t0\r\n".to_vec(),
-                }
+                let mut post = gopher
+                    .posts_content
+                    .get(slug)
+                    .ok_or(ProtocolError::NotFound)?
+                    .clone();
+                add_comments(&mut post, slug);
+                post.to_string().as_bytes().to_vec()
             }
         }
     };
 
     Ok(content)
 }
+
+/// A text file for a `0` item, with lines that start with a dot doubled so they
+/// aren't taken as the end.
+fn text_file(text: &str) -> String {
+    let mut out = String::new();
+    for line in text.lines() {
+        if line.starts_with('.') {
+            out.push('.');
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+    out.push('.');
+    out
+}
+
+/// Add the approved comments to the end of a post. Gopher can't submit them,
+/// so we point people to Gemini for that.
+fn add_comments(out: &mut GopherBuffer, slug: &str) {
+    out.line("");
+    out.line("## Comments");
+    out.line("");
+    for comment in comments::approved(slug) {
+        let date = comment.timestamp.format("%Y-%m-%d");
+        out.line(&format!("### {} ({date})", comment.author));
+        for line in comment.body.lines() {
+            out.line(&format!("> {line}"));
+        }
+        out.line("");
+    }
+    out.line(&format!(
+        "Leave a comment at gemini://{HOSTNAME}/{slug}/comment"
+    ));
+}
+
+/// The page for clients that requested a `URL:` selector instead of opening
+/// the URL themselves, like the gopher URL link convention says to send. It's
+/// HTML that sends browsers to the URL, and says what it is for everyone else.
+fn url_redirect(url: &str) -> String {
+    let href = html_escape::encode_double_quoted_attribute(url);
+    let text = html_escape::encode_text(url);
+    format!(
+        r#"<HTML>
+<HEAD>
+<META HTTP-EQUIV="refresh" content="2;URL={href}">
+</HEAD>
+<BODY>
+You are following a link from gopher to a web site. You will be automatically
+taken to the web site shortly. If you do not get sent there, please click
+<A HREF="{href}">here</A> to go to the web site.
+<P>
+The URL linked is:
+<P>
+<A HREF="{href}">{text}</A>
+<P>
+Thanks for using gopher!
+</BODY>
+</HTML>
+"#
+    )
+}
+
+fn server_info_menu() -> Vec<u8> {
+    let mut out = GopherBuffer::new();
+    out.line(server_info::get().to_text().trim_end());
+    out.to_string().into_bytes()
+}
+
+fn playground_menu() -> Vec<u8> {
+    let mut out = GopherBuffer::new();
+    out.line(server_info::playground_text().trim_end());
+    out.to_string().into_bytes()
+}
+
+fn search_menu(index: &SearchIndex, query: &str) -> Vec<u8> {
+    let mut out = GopherBuffer::new();
+    out.line(&format!("# Results for \"{query}\""));
+    out.line("");
+    let results = index.search(query);
+    if results.is_empty() {
+        out.line("No posts found.");
+        out.line("");
+    }
+    for result in results {
+        out.link(&format!("/{}", result.slug), &result.title);
+        out.line(&format!("> {}", result.snippet));
+        out.line("");
+    }
+    out.search("/search", "Search again");
+    out.to_string().as_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_have_the_right_item_type() {
+        assert_eq!(item_type("/blog"), '1');
+        assert_eq!(item_type("/txt/hello-world.txt"), '0');
+        assert_eq!(item_type("/media/cat.PNG"), 'I');
+        assert_eq!(item_type("/media/cat.gif?size=small"), 'g');
+        assert_eq!(item_type("/downloads/site.torrent"), '9');
+    }
+
+    #[test]
+    fn splits_menus_into_pages() {
+        let mut menu = GopherBuffer {
+            items_per_page: Some(2),
+            ..GopherBuffer::new()
+        };
+        menu.line("# Blog");
+        menu.start_list();
+        for i in 1..=5 {
+            menu.line(&format!("heading {i}"));
+            menu.link(&format!("/post-{i}"), &format!("Post {i}"));
+        }
+
+        let pages = menu.pages("/blog");
+        assert_eq!(pages.len(), 3);
+        for page in &pages {
+            assert!(page.starts_with("i# Blog\t"));
+        }
+        assert!(pages[0].contains("iheading 2\t"));
+        assert!(pages[0].contains("1Next page\t/blog/page/2\t"));
+        assert!(!pages[0].contains("Previous page"));
+        assert!(pages[1].contains("1Previous page\t/blog\t"));
+        assert!(pages[2].contains("1Post 5\t/post-5\t"));
+        assert!(!pages[2].contains("Next page"));
+
+        menu.items_per_page = None;
+        assert_eq!(menu.pages("/blog"), vec![menu.to_string()]);
+    }
+}
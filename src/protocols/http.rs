@@ -1,94 +1,276 @@
 //! HTTP server for stuff like changing the QOTD. The actual matdoes.dev HTTP
 //! server is built statically and served by Caddy.
 
+mod auth;
+mod middleware;
+mod response;
+mod router;
+
 use std::{
-    collections::HashMap,
-    io::{self},
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
+use chrono::NaiveDate;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use serde_json::json;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    time::timeout,
 };
 
-use super::{qotd::Qotd, Protocol};
-use crate::{crawl::SiteData, protocols::qotd::QOTD_MESSAGE_PATH};
+use auth::Scope;
+use response::{Body, Response};
+use router::{HttpError, Request, Router};
+
+use super::{
+    alternate,
+    error::ProtocolError,
+    gemini,
+    qotd::{self, Qotd},
+    render::{self, Format, Pages},
+    tracker, Protocol, ServerConfig, Shutdown,
+};
+use crate::{
+    acme, analytics, comments,
+    crawl::{Post, PostSort, SiteData},
+    drafts,
+    federation::activitypub,
+    lifecycle,
+    listen::{self, Listener},
+    locale::Locale,
+    media, onion_address,
+    routes::{self, Scheme},
+    server_info, timeouts, visitors, HOSTNAME,
+};
 
-const BIND_HOST: &str = "[::]";
 const BIND_PORT: u16 = 6758;
 
-const QOTD_SECRET_PATH: &str = "data/qotd/secret.txt";
+/// How many requests that aren't `GET` each IP can make in
+/// `RATE_LIMIT_WINDOW`.
+const RATE_LIMIT_REQUESTS: usize = 10;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+/// How long a connection can stay open without a request.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// 1mb of content. hopefully this is fine.
+const MAX_BODY_LENGTH: usize = 1024 * 1024;
+/// The request line and headers together.
+const MAX_HEAD_LENGTH: usize = 64 * 1024;
+
+/// The cookie that recognizes visitors when they come back, so the posts that
+/// are new to them can be marked.
+const VISITOR_COOKIE: &str = "visitor";
+/// A year, in seconds.
+const VISITOR_COOKIE_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+/// The characters that have to be escaped in the paths in the sitemap.
+const PATH_ENCODE_SET: &AsciiSet = &CONTROLS.add(b' ').add(b'?').add(b'#').add(b'%');
 
 #[derive(Clone)]
 pub struct Http {
+    router: Arc<Router<Http>>,
     pub qotd: Qotd,
+    /// The slugs of every blog post, so we know which ones can be commented
+    /// on.
+    pub post_slugs: HashSet<String>,
+    sitemap_xml: String,
+    robots_txt: String,
+    /// The site's pages as gemtext and plain text, for clients that ask for
+    /// them with `Accept`.
+    pages: Pages,
+    /// The published posts, for the pages that can't be made ahead of time,
+    /// like the blog page for visitors who have posts that are new to them.
+    blog: Vec<Post>,
 }
 
 impl Protocol for Http {
-    fn generate(_: &SiteData) -> Self {
+    type Options = Qotd;
+
+    fn generate(data: &SiteData, qotd: &Self::Options) -> Self {
+        let mut router = router();
+        // so tor users are sent to the onion service
+        if let Some(address) = onion_address() {
+            router = router.middleware(middleware::OnionAltSvc::new(address, BIND_PORT));
+        }
+
+        let robots_txt = robots_txt(&router);
         Http {
-            qotd: Qotd {
-                message: Default::default(),
-            },
+            router: Arc::new(router),
+            qotd: qotd.clone(),
+            post_slugs: drafts::published(&data.blog)
+                .map(|p| p.slug.clone())
+                .collect(),
+            sitemap_xml: sitemap_xml(data),
+            robots_txt,
+            pages: Pages::generate(data),
+            blog: drafts::published(&data.blog).cloned().collect(),
         }
     }
 
-    async fn serve(self) {
-        let http = Arc::new(self);
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        shutdown
+            .run(async move {
+                let http = Arc::new(self);
 
-        let listener = match TcpListener::bind(format!("{BIND_HOST}:{BIND_PORT}")).await {
-            Ok(listener) => listener,
-            Err(e) => {
-                eprintln!("failed to bind to port {BIND_PORT}: {e}");
-                return;
-            }
-        };
+                let Some(listener) = Listener::bind("http", port) else {
+                    return;
+                };
 
-        loop {
-            let (mut stream, remote_addr) = listener.accept().await.unwrap();
-            println!("started tcp connection for http: {remote_addr:?}");
+                loop {
+                    let (stream, remote_addr) = listener.accept().await.unwrap();
+                    http.handle_connection(stream, remote_addr);
+                }
+            })
+            .await;
+    }
+}
 
-            let http = Arc::clone(&http);
-            let fut = async move {
-                let response = respond(http, &mut stream)
-                    .await
-                    .unwrap_or(b"iNot found\tfake\t(NULL)\t0\r\n".to_vec());
+impl Http {
+    /// Answer the requests on a connection that's already been accepted, in
+    /// its own task.
+    pub fn handle_connection(self: &Arc<Self>, stream: TcpStream, remote_addr: SocketAddr) {
+        println!("started tcp connection for http: {remote_addr:?}");
 
-                stream.write_all(&response).await?;
-                stream.shutdown().await?;
+        let http = Arc::clone(self);
+        tokio::spawn(async move {
+            let _session = lifecycle::Session::start("http");
+            if let Err(err) = serve_connection(&http, &http.router, stream, remote_addr).await {
+                eprintln!("{:?}", err);
+            }
+        });
+    }
+}
 
-                Ok(()) as io::Result<()>
-            };
+fn router() -> Router<Http> {
+    Router::default()
+        .middleware(middleware::Log)
+        // before auth so tokens can't be guessed quickly
+        .middleware(middleware::RateLimit::new(
+            RATE_LIMIT_REQUESTS,
+            RATE_LIMIT_WINDOW,
+        ))
+        .middleware(middleware::Auth)
+        .get("/qotd", get_qotd)
+        .post("/qotd", set_qotd)
+        .auth(Scope::Qotd)
+        .get("/qotd/quotes", list_quotes)
+        .auth(Scope::Qotd)
+        .post("/qotd/quotes", add_quote)
+        .auth(Scope::Qotd)
+        .post("/qotd/quotes/delete", delete_quote)
+        .auth(Scope::Qotd)
+        .get("/.well-known/acme-challenge/:token", acme_challenge)
+        .get("/healthz", healthz)
+        .get("/stats", stats)
+        .auth(Scope::Stats)
+        .post("/comments", submit_comment)
+        .get("/comments", list_comments)
+        .auth(Scope::Comments)
+        .post("/comments/approve", approve_comment)
+        .auth(Scope::Comments)
+        .post("/comments/delete", delete_comment)
+        .auth(Scope::Comments)
+        .post("/admin/recrawl", recrawl)
+        .auth(Scope::Admin)
+        .get("/admin/sessions", sessions)
+        .auth(Scope::Admin)
+        .post("/admin/drain", drain)
+        .auth(Scope::Admin)
+        .get("/announce", announce)
+        .get("/downloads", downloads)
+        .get("/downloads/*path", download)
+        .get("/media/*path", media)
+        .get("/raw/:file", alternate_format)
+        .get("/gmi/:file", alternate_format)
+        .get("/md/:file", alternate_format)
+        .get("/sitemap.xml", sitemap)
+        .get("/robots.txt", robots)
+        .get("/.well-known/webfinger", webfinger)
+        .get("/actor", actor)
+        .post("/actor/inbox", inbox)
+        .get("/actor/outbox", outbox)
+        .get("/actor/followers", followers)
+        .get("/", page)
+        .get("/blog", page)
+        .get("/projects", page)
+        // last, since it matches everything with one segment
+        .get("/:slug", page)
+}
 
-            tokio::spawn(async move {
-                if let Err(err) = fut.await {
-                    eprintln!("{:?}", err);
-                }
-            });
+/// Handle requests on the connection until the client closes it, asks us to,
+/// or doesn't send anything for `IDLE_TIMEOUT`. Once a request starts, all of
+/// it has to arrive within the read timeout. Clients can send requests before
+/// they get the response to the last one, and they're answered in order.
+async fn serve_connection(
+    http: &Http,
+    router: &Router<Http>,
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+) -> io::Result<()> {
+    let mut stream = BufStream::new(stream);
+    loop {
+        match timeout(IDLE_TIMEOUT, stream.fill_buf()).await {
+            Ok(Ok(buffered)) if !buffered.is_empty() => {}
+            Ok(Err(err)) => return Err(err),
+            // closed or idle
+            _ => break,
+        }
+        let head = match timeouts::read(read_head(&mut stream)).await {
+            Ok(head) => head,
+            Err(ProtocolError::ClientDisconnected) => break,
+            Err(err) => {
+                let response = HttpError::from(err).into_response(Locale::default());
+                timeouts::write(response.write_unread(&mut stream)).await?;
+                break;
+            }
+        };
+        let keep_alive = respond(http, router, &mut stream, remote_addr, &head).await?;
+        timeouts::write(stream.flush()).await?;
+        if !keep_alive {
+            break;
         }
     }
+    stream.shutdown().await
 }
 
-async fn respond(http: Arc<Http>, stream: &mut TcpStream) -> io::Result<Vec<u8>> {
-    let mut request = String::new();
+/// The request line and headers.
+async fn read_head(stream: &mut BufStream<TcpStream>) -> Result<String, ProtocolError> {
+    let mut head = Vec::new();
     loop {
-        let c = stream.read_u8().await?;
-        request.push(c as char);
-        if request.len() > 65536 {
-            // too long, no thanks
-            return Ok(b"".to_vec());
+        head.push(stream.read_u8().await?);
+        if head.len() > MAX_HEAD_LENGTH {
+            return Err(ProtocolError::BadRequest(
+                "Headers are too large".to_owned(),
+            ));
         }
         // until it ends in \r\n\r\n
-        if request.ends_with("\r\n\r\n") {
-            break;
+        if head.ends_with(b"\r\n\r\n") {
+            return String::from_utf8(head)
+                .map_err(|_| ProtocolError::BadRequest("Request is not UTF-8".to_owned()));
         }
     }
+}
+
+/// Read the rest of the request and send the response. Returns whether the
+/// connection should stay open.
+async fn respond(
+    http: &Http,
+    router: &Router<Http>,
+    stream: &mut BufStream<TcpStream>,
+    remote_addr: SocketAddr,
+    head: &str,
+) -> io::Result<bool> {
+    let received = Instant::now();
 
     // parse headers
     let mut headers = HashMap::new();
-    let mut lines = request.lines();
-    let request_line = lines.next().unwrap();
+    let mut lines = head.lines();
+    let request_line = lines.next().unwrap_or_default();
     for line in lines {
         let mut parts = line.splitn(2, ": ");
         let key = parts.next().unwrap_or_default().to_lowercase();
@@ -102,86 +284,456 @@ async fn respond(http: Arc<Http>, stream: &mut TcpStream) -> io::Result<Vec<u8>>
     let mut parts = request_line.split_whitespace();
     let method = parts.next().unwrap_or_default();
     let path = parts.next().unwrap_or_default();
-    // ignore the version and hope it's fine. it should be http/1.1 anyways. :3
-    let _version = parts.next().unwrap_or_default();
+    let version = parts.next().unwrap_or("HTTP/1.0");
 
-    println!("---");
-    println!("request_line: {request_line:?}");
-    println!("headers: {headers:?}");
-    println!("HTTP request: {request:?}");
-    println!("---");
+    // http/1.1 connections stay open unless the client says otherwise, and
+    // http/1.0 ones are closed unless it asks for them to stay open
+    let connection = headers
+        .get("connection")
+        .map(|value| value.to_lowercase())
+        .unwrap_or_default();
+    let keep_alive = if version == "HTTP/1.1" {
+        connection != "close"
+    } else {
+        connection == "keep-alive"
+    };
 
     // parse query params
-    let mut query_params = HashMap::new();
+    let mut query = HashMap::new();
     let (path, query_string) = path.split_once('?').unwrap_or((path, ""));
-    for pair in query_string.split('&') {
+    for pair in query_string.split('&').filter(|pair| !pair.is_empty()) {
         let mut parts = pair.splitn(2, '=');
         let key = parts.next().unwrap_or_default();
         let value = parts.next().unwrap_or_default();
-        query_params.insert(key, value);
+        query.insert(key, value);
     }
 
+    analytics::record("http", path, None, remote_addr.ip());
+
     let content_length = headers
         .get("content-length")
         .and_then(|s| s.parse::<usize>().ok())
-        .unwrap_or_default()
-        // 1mb of content. hopefully this is fine.
-        .min(1024 * 1024);
+        .unwrap_or_default();
+    let is_chunked = headers.contains_key("transfer-encoding");
+
+    let mut request = Request {
+        method,
+        path,
+        version,
+        query,
+        client_ip: client_ip(remote_addr, &headers),
+        headers,
+        body: Vec::new(),
+        received,
+        keep_alive,
+        params: HashMap::new(),
+    };
+
+    // we don't know where the next request would start after bodies we don't
+    // read, so the connection is closed after these
+    if is_chunked {
+        request.keep_alive = false;
+        let response = Response::text(501, "Chunked requests aren't supported\n");
+        timeouts::write(response.write(stream, &request)).await?;
+        return Ok(false);
+    }
+    if content_length > MAX_BODY_LENGTH {
+        request.keep_alive = false;
+        let response = Response::text(413, "Content Too Large\n");
+        timeouts::write(response.write(stream, &request)).await?;
+        return Ok(false);
+    }
+
     // read body
-    let mut body = Vec::new();
-    for _ in 0..content_length {
-        body.push(stream.read_u8().await?);
+    request.body = vec![0; content_length];
+    match timeouts::read(stream.read_exact(&mut request.body)).await {
+        Ok(_) => {}
+        Err(ProtocolError::ClientDisconnected) => return Ok(false),
+        Err(err) => {
+            request.keep_alive = false;
+            let response = HttpError::from(err).into_response(request.locale());
+            timeouts::write(response.write(stream, &request)).await?;
+            return Ok(false);
+        }
     }
 
-    let mut response = Vec::<u8>::new();
+    let response = router.handle(http, &mut request);
+    timeouts::write(response.write(stream, &request)).await?;
+    Ok(request.keep_alive)
+}
+
+fn get_qotd(http: &Http, request: &Request) -> Result<Response, HttpError> {
+    // old quotes can be seen with ?date=YYYY-MM-DD
+    let Some(date) = request.query("date") else {
+        return Ok(Response::text(200, http.qotd.message.read().clone()));
+    };
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| ProtocolError::BadRequest("The date has to be YYYY-MM-DD".to_owned()))?;
+    Ok(match qotd::on(date) {
+        Some(quote) => Response::text(200, qotd::message(&quote)),
+        None => Response::text(404, "No quote for that day\n"),
+    })
+}
+
+/// Replaces today's quote until the next one is picked at midnight.
+fn set_qotd(http: &Http, request: &Request) -> Result<Response, HttpError> {
+    http.qotd.set(&String::from_utf8_lossy(&request.body));
+    Ok(Response::text(200, "OK\n"))
+}
+
+fn list_quotes(_: &Http, _: &Request) -> Result<Response, HttpError> {
+    Response::json(&qotd::quotes())
+}
+
+fn add_quote(http: &Http, request: &Request) -> Result<Response, HttpError> {
+    let id = qotd::add_quote(&String::from_utf8_lossy(&request.body))
+        .map_err(|err| ProtocolError::BadRequest(err.to_string()))?;
+    // in case there weren't any quotes before
+    http.qotd.rotate();
+    Ok(Response::text(200, format!("{id}\n")))
+}
+
+fn delete_quote(_: &Http, request: &Request) -> Result<Response, HttpError> {
+    Ok(if qotd::delete_quote(id_param(request)?) {
+        Response::text(200, "OK\n")
+    } else {
+        Response::text(404, "Quote not found\n")
+    })
+}
+
+fn acme_challenge(_: &Http, request: &Request) -> Result<Response, HttpError> {
+    let key_authorization =
+        acme::challenge_response(request.param("token")).ok_or(ProtocolError::NotFound)?;
+    Ok(Response::text(200, key_authorization))
+}
+
+fn stats(_: &Http, _: &Request) -> Result<Response, HttpError> {
+    Response::json(&analytics::stats())
+}
+
+/// Anyone can submit a comment, but they have to be approved before they're
+/// shown.
+fn submit_comment(http: &Http, request: &Request) -> Result<Response, HttpError> {
+    let post = request.query("post").unwrap_or_default();
+    let author = decode_query_value(request.query("author").unwrap_or_default());
+    if !http.post_slugs.contains(post) {
+        return Err(ProtocolError::BadRequest("Post not found".to_owned()).into());
+    }
+    comments::submit(
+        post,
+        &author,
+        &String::from_utf8_lossy(&request.body),
+        request.client_ip,
+    )
+    .map_err(|err| ProtocolError::BadRequest(err.to_string()))?;
+    Ok(Response::text(
+        200,
+        "Your comment will be shown after it's approved.\n",
+    ))
+}
+
+fn list_comments(_: &Http, _: &Request) -> Result<Response, HttpError> {
+    Response::json(&comments::all())
+}
+
+fn approve_comment(_: &Http, request: &Request) -> Result<Response, HttpError> {
+    Ok(if comments::approve(id_param(request)?) {
+        Response::text(200, "OK\n")
+    } else {
+        Response::text(404, "Comment not found\n")
+    })
+}
+
+fn delete_comment(_: &Http, request: &Request) -> Result<Response, HttpError> {
+    Ok(if comments::delete(id_param(request)?) {
+        Response::text(200, "OK\n")
+    } else {
+        Response::text(404, "Comment not found\n")
+    })
+}
+
+fn recrawl(_: &Http, _: &Request) -> Result<Response, HttpError> {
+    lifecycle::request_recrawl();
+    Ok(Response::text(
+        202,
+        "Recrawling, the servers will restart when it's done\n",
+    ))
+}
+
+fn sessions(_: &Http, _: &Request) -> Result<Response, HttpError> {
+    Response::json(&lifecycle::sessions())
+}
 
-    match (path, method) {
-        ("/qotd", "GET") => {
-            response.extend(b"HTTP/1.1 200 OK\r\n");
-            response.extend(b"Content-Type: text/plain\r\n");
-            response.extend(b"\r\n");
-            response.extend(http.qotd.message.read().as_slice());
+/// Which protocols are listening and whether any of them had a problem, for
+/// load balancers and container healthchecks. It's a 503 if any did.
+fn healthz(_: &Http, _: &Request) -> Result<Response, HttpError> {
+    let failures = listen::failures();
+    let mut protocols = serde_json::Map::new();
+    for (protocol, ports) in server_info::get().listeners {
+        let error = failures.get(&protocol);
+        protocols.insert(protocol, json!({ "ports": ports, "error": error }));
+    }
+    for (protocol, error) in &failures {
+        if !protocols.contains_key(protocol) {
+            protocols.insert(protocol.clone(), json!({ "ports": [], "error": error }));
         }
-        ("/qotd", "POST") => {
-            // validate the secret
-            let expected_secret = tokio::fs::read_to_string(QOTD_SECRET_PATH)
-                .await
-                .unwrap_or_default();
-            if !expected_secret.is_empty()
-                && query_params.get("secret") == Some(&expected_secret.trim())
-            {
-                let qotd_content_str = String::from_utf8_lossy(&body);
-                println!("changing qotd to \"{qotd_content_str}\"");
-                let mut full_qotd = Vec::<u8>::new();
-                full_qotd.extend(b"Quote of the day:\n");
-                full_qotd.extend(&body);
-                // add another \n if it's not there
-                if full_qotd.last() != Some(&b'\n') {
-                    full_qotd.push(b'\n');
-                }
+    }
+
+    let healthy = failures.is_empty();
+    let mut response = Response::json(&json!({
+        "healthy": healthy,
+        "protocols": protocols,
+    }))?;
+    if !healthy {
+        response.status = 503;
+    }
+    Ok(response)
+}
+
+fn drain(_: &Http, _: &Request) -> Result<Response, HttpError> {
+    lifecycle::request_drain();
+    Ok(Response::text(
+        202,
+        "Not accepting new connections, exiting once the open ones are closed\n",
+    ))
+}
+
+fn announce(_: &Http, request: &Request) -> Result<Response, HttpError> {
+    Ok(Response::text(
+        200,
+        tracker::announce(&request.query, request.client_ip),
+    ))
+}
+
+fn downloads(_: &Http, _: &Request) -> Result<Response, HttpError> {
+    Ok(Response::text(200, downloads_page()))
+}
+
+/// A `.torrent` file, or one of the files in the posts torrent, which torrent
+/// clients download from us when there aren't any other peers.
+fn download(_: &Http, request: &Request) -> Result<Response, HttpError> {
+    let path = request.param("path");
+    if let Some(file) = tracker::bundle_file(path) {
+        return Ok(Response::new(200).body(Body::Media(path.into(), file)));
+    }
+    let torrent = tracker::torrent(path).ok_or(ProtocolError::NotFound)?;
+    Ok(Response::new(200)
+        .header("Content-Type", "application/x-bittorrent")
+        .body(Body::Bytes(torrent.metainfo.clone())))
+}
+
+/// Files from the media directory, which are opened when the response is sent
+/// since they can be huge.
+fn media(_: &Http, request: &Request) -> Result<Response, HttpError> {
+    let path = media::resolve(request.param("path")).ok_or(ProtocolError::NotFound)?;
+    Ok(Response::new(200).body(Body::File(path)))
+}
+
+/// A post in one of its other formats, like `/md/<slug>.md`.
+fn alternate_format(http: &Http, request: &Request) -> Result<Response, HttpError> {
+    let path = percent_decode_str(request.path).decode_utf8_lossy();
+    let (format, content) =
+        alternate::render_path(&http.blog, &path).ok_or(ProtocolError::NotFound)?;
+    Ok(Response::new(200)
+        .header("Content-Type", format.content_type())
+        .body(Body::Bytes(content.into_bytes())))
+}
 
-                // write to file
-                tokio::fs::write(QOTD_MESSAGE_PATH, &full_qotd).await?;
-                *http.qotd.message.write() = full_qotd;
-                response.extend(b"HTTP/1.1 200 OK\r\n");
-                response.extend(b"Content-Type: text/plain\r\n");
-                response.extend(b"\r\n");
-                response.extend(b"OK\n");
-            } else {
-                response.extend(b"HTTP/1.1 403 Forbidden\r\n");
-                response.extend(b"Content-Type: text/plain\r\n");
-                response.extend(b"\r\n");
-                response.extend(b"Forbidden\n");
-                return Ok(response);
+/// A page from the site in the format from the `Accept` header, so it can be
+/// read as gemtext or plain text too.
+fn page(http: &Http, request: &Request) -> Result<Response, HttpError> {
+    let path = match request.path.strip_suffix('/') {
+        Some(path) if !path.is_empty() => path,
+        _ => request.path,
+    };
+    let path = percent_decode_str(path).decode_utf8_lossy();
+    let page = http.pages.get(&path).ok_or(ProtocolError::NotFound)?;
+    let format = Format::from_accept(request.header("accept").unwrap_or_default());
+
+    // visitors are only remembered once they send the cookie back, so
+    // clients that ignore it don't each add one. it also has to look like one
+    // we made.
+    let visitor = request
+        .cookie(VISITOR_COOKIE)
+        .filter(|id| id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit()));
+    let previous_visit = visitor.and_then(|visitor| visitors::visit(&format!("http:{visitor}")));
+
+    let body = match previous_visit {
+        // the plain text doesn't come from gemtext, so it isn't marked
+        Some(_) if path == "/blog" && format != Format::PlainText => {
+            let gemtext = gemini::blog_page(
+                &http.blog,
+                Locale::default(),
+                PostSort::Date,
+                previous_visit,
+            );
+            match format {
+                Format::Html => render::gemtext_to_html(&gemtext),
+                _ => gemtext,
             }
         }
-        _ => {
-            response.extend(b"HTTP/1.1 404 Not Found\r\n");
-            response.extend(b"Content-Type: text/plain\r\n");
-            response.extend(b"\r\n");
-            response.extend(b"Not Found\n");
+        _ => page.render(format),
+    };
+    let mut response = Response::new(200)
+        .header("Content-Type", format.content_type())
+        .header("Vary", "Accept, Cookie")
+        .body(Body::Bytes(body.into_bytes()));
+    if visitor.is_none() {
+        let visitor = rand::random::<[u8; 16]>()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        response = response.header(
+            "Set-Cookie",
+            format!(
+                "{VISITOR_COOKIE}={visitor}; Max-Age={VISITOR_COOKIE_MAX_AGE}; Path=/; \
+                 HttpOnly; SameSite=Lax"
+            ),
+        );
+    }
+    Ok(response)
+}
+
+/// Looking up the blog's Fediverse address, like `acct:blog@matdoes.dev`.
+fn webfinger(_: &Http, request: &Request) -> Result<Response, HttpError> {
+    let resource = decode_query_value(request.query("resource").unwrap_or_default());
+    let webfinger = activitypub::webfinger(&resource).ok_or(ProtocolError::NotFound)?;
+    json_as("application/jrd+json", &webfinger)
+}
+
+fn actor(_: &Http, _: &Request) -> Result<Response, HttpError> {
+    json_as(activitypub::CONTENT_TYPE_ACTIVITY, &activitypub::actor())
+}
+
+fn inbox(http: &Http, request: &Request) -> Result<Response, HttpError> {
+    let inbox_request = activitypub::InboxRequest {
+        path: request.path.to_owned(),
+        headers: request
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), (*value).to_owned()))
+            .collect(),
+        body: request.body.clone(),
+    };
+    let accepted = activitypub::receive(inbox_request, http.post_slugs.clone())
+        .map_err(|err| ProtocolError::BadRequest(err.to_string()))?;
+    if !accepted {
+        return Err(HttpError::TooManyRequests {
+            retry_after_secs: 60,
+        });
+    }
+    Ok(Response::new(202))
+}
+
+fn outbox(http: &Http, _: &Request) -> Result<Response, HttpError> {
+    json_as(
+        activitypub::CONTENT_TYPE_ACTIVITY,
+        &activitypub::outbox(&http.blog),
+    )
+}
+
+fn followers(_: &Http, _: &Request) -> Result<Response, HttpError> {
+    json_as(
+        activitypub::CONTENT_TYPE_ACTIVITY,
+        &activitypub::followers(),
+    )
+}
+
+/// Like [`Response::json`], for the formats that are JSON with another type.
+fn json_as(content_type: &'static str, value: &serde_json::Value) -> Result<Response, HttpError> {
+    Ok(Response::new(200)
+        .header("Content-Type", content_type)
+        .body(Body::Bytes(serde_json::to_vec(value)?)))
+}
+
+fn sitemap(http: &Http, _: &Request) -> Result<Response, HttpError> {
+    Ok(Response::new(200)
+        .header("Content-Type", "application/xml")
+        .body(Body::Bytes(http.sitemap_xml.clone().into_bytes())))
+}
+
+fn robots(http: &Http, _: &Request) -> Result<Response, HttpError> {
+    Ok(Response::text(200, http.robots_txt.clone()))
+}
+
+/// Every page on the HTTP site, in the format from sitemaps.org.
+fn sitemap_xml(data: &SiteData) -> String {
+    let mut xml = String::new();
+    xml.push_str(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    xml.push('\n');
+    xml.push_str(r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">"#);
+    xml.push('\n');
+    for route in routes::for_scheme(data, Scheme::Http) {
+        let path = utf8_percent_encode(&route.path, PATH_ENCODE_SET);
+        let location = html_escape::encode_text(&format!("https://{HOSTNAME}{path}")).into_owned();
+        xml.push_str(&format!("  <url>\n    <loc>{location}</loc>\n"));
+        if let Some(modified) = route.modified {
+            xml.push_str(&format!(
+                "    <lastmod>{}</lastmod>\n",
+                modified.format("%Y-%m-%d")
+            ));
         }
+        xml.push_str("  </url>\n");
     }
+    xml.push_str("</urlset>\n");
+    xml
+}
 
-    Ok(response)
+/// Crawlers can look at everything except the endpoints that need a token.
+fn robots_txt(router: &Router<Http>) -> String {
+    let mut robots = String::from("User-agent: *\n");
+    for path in router.private_paths() {
+        robots.push_str(&format!("Disallow: {path}\n"));
+    }
+    robots.push_str(&format!("\nSitemap: https://{HOSTNAME}/sitemap.xml\n"));
+    robots
+}
+
+/// The `id` query parameter, for the endpoints that approve or delete things.
+fn id_param(request: &Request) -> Result<u64, HttpError> {
+    request
+        .query("id")
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| ProtocolError::BadRequest("Missing id".to_owned()).into())
+}
+
+/// Every torrent with links to its `.torrent` file and a magnet link.
+fn downloads_page() -> String {
+    let mut page = String::new();
+    page.push_str("# Downloads\n\n");
+    page.push_str("Torrents of the media on the site and of every post as text.\n\n");
+    for torrent in tracker::torrents() {
+        page.push_str(&format!(
+            "{name} ({size})\n/downloads/{file_name}\n{magnet}\n\n",
+            name = torrent.name,
+            size = torrent.human_size(),
+            file_name = torrent.file_name,
+            magnet = torrent.magnet(),
+        ));
+    }
+    page
+}
+
+/// The address of whoever sent the request. We're behind Caddy, so for
+/// connections from localhost it's in the `X-Forwarded-For` header instead.
+fn client_ip(remote_addr: SocketAddr, headers: &HashMap<String, &str>) -> IpAddr {
+    let remote_ip = remote_addr.ip().to_canonical();
+    if !remote_ip.is_loopback() {
+        return remote_ip;
+    }
+    headers
+        .get("x-forwarded-for")
+        // the first one is the original client, the rest are proxies
+        .and_then(|value| value.split(',').next())
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| ip.to_canonical())
+        .unwrap_or(remote_ip)
+}
+
+/// Query parameters are left encoded, so this has to be used for ones that
+/// might contain spaces or non-ASCII characters.
+fn decode_query_value(value: &str) -> String {
+    percent_decode_str(&value.replace('+', " "))
+        .decode_utf8_lossy()
+        .into_owned()
 }
@@ -0,0 +1,59 @@
+//! Bearer tokens for the endpoints that aren't public. Each scope has its own
+//! token in a file, and the admin token works for all of them. If a token's
+//! file doesn't exist, nobody can use it.
+
+use std::{collections::HashMap, fs};
+
+const QOTD_SECRET_PATH: &str = "data/qotd/secret.txt";
+const STATS_SECRET_PATH: &str = "data/analytics/secret.txt";
+const COMMENTS_SECRET_PATH: &str = "data/comments/secret.txt";
+const ADMIN_SECRET_PATH: &str = "data/admin/secret.txt";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Scope {
+    Qotd,
+    Stats,
+    Comments,
+    Admin,
+}
+
+impl Scope {
+    fn secret_path(self) -> &'static str {
+        match self {
+            Scope::Qotd => QOTD_SECRET_PATH,
+            Scope::Stats => STATS_SECRET_PATH,
+            Scope::Comments => COMMENTS_SECRET_PATH,
+            Scope::Admin => ADMIN_SECRET_PATH,
+        }
+    }
+}
+
+/// Whether the request has an `Authorization: Bearer` header with the token
+/// for the scope, or the admin token.
+pub fn is_authorized(scope: Scope, headers: &HashMap<String, &str>) -> bool {
+    let Some(token) = headers.get("authorization").and_then(|value| {
+        let (kind, token) = value.trim().split_once(' ')?;
+        kind.eq_ignore_ascii_case("bearer").then_some(token.trim())
+    }) else {
+        return false;
+    };
+
+    let mut scopes = vec![scope];
+    if scope != Scope::Admin {
+        scopes.push(Scope::Admin);
+    }
+    for scope in scopes {
+        let expected = fs::read_to_string(scope.secret_path()).unwrap_or_default();
+        let expected = expected.trim();
+        if !expected.is_empty() && constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Compare without stopping at the first difference, so the time it takes
+/// doesn't say how much of the token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
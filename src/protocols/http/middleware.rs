@@ -0,0 +1,123 @@
+//! The middleware that every request to the HTTP server goes through.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use super::{
+    auth,
+    response::Response,
+    router::{HttpError, Middleware, Request, RouteInfo},
+};
+use crate::access_log;
+
+/// Print one line for every request after it's handled, and write it to the
+/// access log.
+pub struct Log;
+
+impl Middleware for Log {
+    fn after(&self, request: &Request, response: &mut Response) {
+        println!(
+            "http {} {} from {}: {} in {:?}",
+            request.method,
+            request.path,
+            request.client_ip,
+            response.status,
+            request.received.elapsed()
+        );
+        let mut log = access_log::Entry::started_at("http", request.client_ip, request.received);
+        log.request(&format!("{} {}", request.method, request.path));
+        log.finish(&response.status.to_string(), response.body.size());
+    }
+}
+
+/// Tell clients that the same server is also at an onion service, so Tor
+/// Browser can switch to it.
+pub struct OnionAltSvc {
+    alt_svc: String,
+}
+
+impl OnionAltSvc {
+    pub fn new(address: &str, port: u16) -> Self {
+        OnionAltSvc {
+            alt_svc: format!("http/1.1=\"{address}:{port}\"; ma=86400"),
+        }
+    }
+}
+
+impl Middleware for OnionAltSvc {
+    fn after(&self, _request: &Request, response: &mut Response) {
+        response.headers.push(("Alt-Svc", self.alt_svc.clone()));
+    }
+}
+
+/// Reject requests to routes that need a token if they don't have it.
+pub struct Auth;
+
+impl Middleware for Auth {
+    fn before(&self, request: &Request, route: &RouteInfo) -> Result<(), HttpError> {
+        match route.scope {
+            Some(scope) if !auth::is_authorized(scope, &request.headers) => {
+                Err(HttpError::Unauthorized)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Limit how many requests each IP can make that change something, like
+/// submitting comments, or that need a token. Other `GET` requests aren't
+/// limited.
+pub struct RateLimit {
+    max_requests: usize,
+    window: Duration,
+    requests: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl RateLimit {
+    pub fn new(max_requests: usize, window: Duration) -> Self {
+        RateLimit {
+            max_requests,
+            window,
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Middleware for RateLimit {
+    fn before(&self, request: &Request, route: &RouteInfo) -> Result<(), HttpError> {
+        // the routes with a token are limited for every method, so the tokens
+        // can't be guessed quickly with GET requests
+        if route.method == "GET" && route.scope.is_none() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut requests = self.requests.lock();
+        // forget about the ips that haven't made any requests recently
+        requests.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|&time| now.duration_since(time) > self.window)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+
+        let times = requests.entry(request.client_ip).or_default();
+        if times.len() >= self.max_requests {
+            let oldest = times[0];
+            let retry_after = self.window.saturating_sub(now.duration_since(oldest));
+            return Err(HttpError::TooManyRequests {
+                retry_after_secs: retry_after.as_secs() + 1,
+            });
+        }
+        times.push_back(now);
+        Ok(())
+    }
+}
@@ -0,0 +1,482 @@
+//! Responses that handlers return, and writing them to the connection. Text is
+//! compressed if the client supports it, and files support `Range` requests.
+
+use std::{
+    io::{self, Write},
+    mem,
+    path::PathBuf,
+};
+
+use chrono::{DateTime, Utc};
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::router::{HttpError, Request};
+use crate::media::Media;
+
+/// How long clients can cache media for, in seconds.
+const MEDIA_MAX_AGE: u64 = 60 * 60 * 24;
+/// Smaller bodies than this aren't worth compressing.
+const MIN_COMPRESS_LENGTH: usize = 256;
+/// How much of a file is compressed at once when it's sent in chunks.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Body,
+}
+
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// A file that's opened when the response is sent, and 404s if it can't
+    /// be.
+    File(PathBuf),
+    /// A file that's already open. The path is only used to guess the content
+    /// type.
+    Media(PathBuf, Media),
+}
+
+impl Body {
+    /// How many bytes it is before it's compressed.
+    pub fn size(&self) -> u64 {
+        match self {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File(path) => std::fs::metadata(path).map_or(0, |m| m.len()),
+            Body::Media(_, media) => media.size(),
+        }
+    }
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    pub fn text(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Response::new(status)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(Body::Bytes(body.into()))
+    }
+
+    pub fn json(value: &impl Serialize) -> Result<Self, HttpError> {
+        Ok(Response::new(200)
+            .header("Content-Type", "application/json")
+            .body(Body::Bytes(serde_json::to_vec(value)?)))
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: Body) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// Write the response, and tell the client whether the connection is
+    /// going to stay open.
+    pub async fn write(
+        self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        request: &Request<'_>,
+    ) -> io::Result<()> {
+        let Response {
+            status,
+            mut headers,
+            body,
+        } = self;
+        let connection = if request.keep_alive {
+            "keep-alive"
+        } else {
+            "close"
+        };
+        headers.push(("Connection", connection.to_owned()));
+
+        match body {
+            Body::Bytes(body) => {
+                let accept_encoding = request.header("accept-encoding").unwrap_or_default();
+                write_bytes(stream, status, headers, body, accept_encoding).await
+            }
+            Body::File(path) => match Media::open(&path).await {
+                Ok(media) => write_media(stream, request, status, headers, path, media).await,
+                Err(_) => {
+                    let headers = vec![
+                        ("Connection", connection.to_owned()),
+                        ("Content-Type", "text/plain; charset=utf-8".to_owned()),
+                    ];
+                    write_bytes(stream, 404, headers, b"Not Found\n".to_vec(), "").await
+                }
+            },
+            Body::Media(path, media) => {
+                write_media(stream, request, status, headers, path, media).await
+            }
+        }
+    }
+
+    /// Write the response to a request that couldn't be read, which always
+    /// closes the connection. Only bodies that are in memory are sent.
+    pub async fn write_unread(self, stream: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+        let Response {
+            status,
+            mut headers,
+            body,
+        } = self;
+        headers.push(("Connection", "close".to_owned()));
+        let body = match body {
+            Body::Bytes(body) => body,
+            Body::File(_) | Body::Media(..) => Vec::new(),
+        };
+        write_bytes(stream, status, headers, body, "").await
+    }
+}
+
+async fn write_head(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    headers: &[(&'static str, String)],
+) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {status} {}\r\n", reason_phrase(status));
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await
+}
+
+/// Send a body that's in memory, compressed if it's text and the client
+/// supports it.
+async fn write_bytes(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    mut headers: Vec<(&'static str, String)>,
+    mut body: Vec<u8>,
+    accept_encoding: &str,
+) -> io::Result<()> {
+    let is_text = headers
+        .iter()
+        .any(|(name, value)| name.eq_ignore_ascii_case("content-type") && is_compressible(value));
+    if is_text {
+        // caches have to know that the body depends on the accept-encoding
+        headers.push(("Vary", "Accept-Encoding".to_owned()));
+        let compressed = preferred_encoding(accept_encoding)
+            .filter(|_| body.len() >= MIN_COMPRESS_LENGTH)
+            .and_then(|encoding| Some((encoding, encode(encoding, &body).ok()?)));
+        if let Some((encoding, compressed)) = compressed {
+            headers.push(("Content-Encoding", encoding.to_owned()));
+            body = compressed;
+        }
+    }
+    headers.push(("Content-Length", body.len().to_string()));
+
+    write_head(stream, status, &headers).await?;
+    stream.write_all(&body).await
+}
+
+/// Send a file, or the part of it from the `Range` header. Text files are
+/// compressed as they're sent if the client supports it, so we don't know how
+/// long they'll be and they're sent in chunks.
+async fn write_media(
+    stream: &mut (impl AsyncWrite + Unpin),
+    request: &Request<'_>,
+    status: u16,
+    mut headers: Vec<(&'static str, String)>,
+    path: PathBuf,
+    file: Media,
+) -> io::Result<()> {
+    let file_len = file.size();
+
+    let range = request
+        .header("range")
+        .map(|range| parse_range(range, file_len));
+    let (start, end) = match range {
+        None | Some(RangeRequest::Ignored) => (0, file_len),
+        Some(RangeRequest::Satisfiable { start, end }) => (start, end),
+        Some(RangeRequest::Unsatisfiable) => {
+            headers.push(("Content-Range", format!("bytes */{file_len}")));
+            headers.push(("Content-Length", "0".to_owned()));
+            return write_head(stream, 416, &headers).await;
+        }
+    };
+    let is_partial = (start, end) != (0, file_len);
+
+    let status = if is_partial {
+        headers.push((
+            "Content-Range",
+            format!("bytes {start}-{}/{file_len}", end - 1),
+        ));
+        206
+    } else {
+        status
+    };
+    let content_type = match headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+    {
+        Some((_, content_type)) => content_type.clone(),
+        None => {
+            let mime = mime_guess::from_path(&path)
+                .first_or_octet_stream()
+                .to_string();
+            headers.push(("Content-Type", mime.clone()));
+            mime
+        }
+    };
+    headers.push(("Cache-Control", format!("public, max-age={MEDIA_MAX_AGE}")));
+    if let Some(modified) = file.modified() {
+        let modified = DateTime::<Utc>::from(modified);
+        headers.push((
+            "Last-Modified",
+            modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+        ));
+    }
+
+    let is_compressible = is_compressible(&content_type);
+    if is_compressible {
+        headers.push(("Vary", "Accept-Encoding".to_owned()));
+    }
+    // http/1.0 doesn't have chunked responses
+    let encoding = preferred_encoding(request.header("accept-encoding").unwrap_or_default())
+        .filter(|_| is_compressible && !is_partial && request.version == "HTTP/1.1")
+        .filter(|_| file_len >= MIN_COMPRESS_LENGTH as u64);
+    if let Some(encoding) = encoding {
+        headers.push(("Content-Encoding", encoding.to_owned()));
+        headers.push(("Transfer-Encoding", "chunked".to_owned()));
+        write_head(stream, status, &headers).await?;
+        return write_compressed(stream, file, encoding).await;
+    }
+
+    headers.push(("Content-Length", (end - start).to_string()));
+    headers.push(("Accept-Ranges", "bytes".to_owned()));
+    write_head(stream, status, &headers).await?;
+    file.send_range(stream, start, end).await
+}
+
+/// Compress the file as it's read, and send it with chunked transfer encoding.
+async fn write_compressed(
+    stream: &mut (impl AsyncWrite + Unpin),
+    file: Media,
+    encoding: &str,
+) -> io::Result<()> {
+    let mut reader = file.into_reader();
+    let mut encoder = Encoder::new(encoding);
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        encoder.write_all(&buf[..n])?;
+        write_chunk(stream, &encoder.take_output()).await?;
+    }
+    write_chunk(stream, &encoder.finish()?).await?;
+    // the empty chunk at the end
+    stream.write_all(b"0\r\n\r\n").await
+}
+
+async fn write_chunk(stream: &mut (impl AsyncWrite + Unpin), chunk: &[u8]) -> io::Result<()> {
+    // an empty chunk would end the body
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    stream
+        .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+        .await?;
+    stream.write_all(chunk).await?;
+    stream.write_all(b"\r\n").await
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || content_type.starts_with("application/json")
+        || content_type.starts_with("application/javascript")
+        || content_type.starts_with("image/svg+xml")
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        206 => "Partial Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        416 => "Range Not Satisfiable",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        _ => "",
+    }
+}
+
+/// The encoding from the `Accept-Encoding` header that we should use, if any.
+/// Gzip is preferred when the client likes both equally.
+fn preferred_encoding(accept_encoding: &str) -> Option<&'static str> {
+    let mut best: Option<(&'static str, f32)> = None;
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim().to_lowercase();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.);
+        let encoding = match name.as_str() {
+            "gzip" | "x-gzip" | "*" => "gzip",
+            "deflate" => "deflate",
+            _ => continue,
+        };
+        if quality > 0. && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+fn encode(encoding: &str, body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = Encoder::new(encoding);
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    /// "deflate" in http is actually zlib
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: &str) -> Self {
+        match encoding {
+            "gzip" => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            _ => Encoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::default())),
+        }
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write_all(bytes),
+            Encoder::Deflate(encoder) => encoder.write_all(bytes),
+        }
+    }
+
+    /// The compressed bytes that are ready so far.
+    fn take_output(&mut self) -> Vec<u8> {
+        match self {
+            Encoder::Gzip(encoder) => mem::take(encoder.get_mut()),
+            Encoder::Deflate(encoder) => mem::take(encoder.get_mut()),
+        }
+    }
+
+    /// The rest of the compressed bytes.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum RangeRequest {
+    /// The byte range from `start` up to but not including `end`.
+    Satisfiable {
+        start: u64,
+        end: u64,
+    },
+    Unsatisfiable,
+    /// Ranges we don't support (like multiple ranges) are ignored, so the
+    /// whole file is sent.
+    Ignored,
+}
+
+fn parse_range(range: &str, file_len: u64) -> RangeRequest {
+    let Some(range) = range.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Ignored;
+    };
+    if range.contains(',') {
+        return RangeRequest::Ignored;
+    }
+    let Some((start, end)) = range.split_once('-') else {
+        return RangeRequest::Ignored;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    let (start, end) = if start.is_empty() {
+        // bytes=-500 is the last 500 bytes
+        let Ok(suffix_len) = end.parse::<u64>() else {
+            return RangeRequest::Ignored;
+        };
+        (file_len.saturating_sub(suffix_len), file_len)
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return RangeRequest::Ignored;
+        };
+        let end = match end {
+            "" => file_len,
+            // the end in the header is inclusive
+            end => match end.parse::<u64>() {
+                Ok(end) if end >= start => u64::min(end.saturating_add(1), file_len),
+                _ => return RangeRequest::Ignored,
+            },
+        };
+        (start, end)
+    };
+    if start >= end {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Satisfiable { start, end }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges() {
+        let range = |start, end| RangeRequest::Satisfiable { start, end };
+        assert_eq!(parse_range("bytes=0-99", 1000), range(0, 100));
+        assert_eq!(parse_range("bytes=900-", 1000), range(900, 1000));
+        assert_eq!(parse_range("bytes=-100", 1000), range(900, 1000));
+        assert_eq!(parse_range("bytes=-5000", 1000), range(0, 1000));
+        assert_eq!(parse_range("bytes=500-5000", 1000), range(500, 1000));
+        assert_eq!(
+            parse_range(&format!("bytes=0-{}", u64::MAX), 1000),
+            range(0, 1000)
+        );
+    }
+
+    #[test]
+    fn ignores_ranges_we_dont_support() {
+        assert_eq!(parse_range("items=0-99", 1000), RangeRequest::Ignored);
+        assert_eq!(parse_range("bytes=0-9,20-29", 1000), RangeRequest::Ignored);
+        assert_eq!(parse_range("bytes=99-0", 1000), RangeRequest::Ignored);
+        assert_eq!(parse_range("bytes=a-b", 1000), RangeRequest::Ignored);
+        assert_eq!(parse_range("bytes=100", 1000), RangeRequest::Ignored);
+    }
+
+    #[test]
+    fn rejects_ranges_past_the_end() {
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range("bytes=2000-3000", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+    }
+}
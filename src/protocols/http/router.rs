@@ -0,0 +1,300 @@
+//! Picking the handler for a request by its method and path. Paths can have
+//! parameters like `/downloads/:file` for one segment or `/media/*path` for
+//! the rest of the path, and every route goes through the middleware.
+
+use std::{collections::HashMap, net::IpAddr, time::Instant};
+
+use percent_encoding::percent_decode_str;
+
+use super::{auth::Scope, response::Response};
+use crate::{locale::Locale, protocols::error::ProtocolError};
+
+pub type Handler<S> = fn(&S, &Request) -> Result<Response, HttpError>;
+
+pub struct Router<S> {
+    routes: Vec<Route<S>>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+struct Route<S> {
+    info: RouteInfo,
+    handler: Handler<S>,
+}
+
+/// What middleware gets to know about the route that matched.
+pub struct RouteInfo {
+    pub method: &'static str,
+    pub pattern: &'static str,
+    /// The token that's needed for the route, or `None` if it's public.
+    pub scope: Option<Scope>,
+}
+
+pub trait Middleware: Send + Sync {
+    /// Called once we know which route the request is for. Returning an error
+    /// stops the handler from running.
+    fn before(&self, _request: &Request, _route: &RouteInfo) -> Result<(), HttpError> {
+        Ok(())
+    }
+
+    /// Called with every response, including errors and unknown routes.
+    fn after(&self, _request: &Request, _response: &mut Response) {}
+}
+
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    /// Like `HTTP/1.1`.
+    pub version: &'a str,
+    /// The query parameters, which are still percent-encoded since some of
+    /// them (like a torrent's info hash) are binary.
+    pub query: HashMap<&'a str, &'a str>,
+    /// The header names are lowercase.
+    pub headers: HashMap<String, &'a str>,
+    pub body: Vec<u8>,
+    pub client_ip: IpAddr,
+    pub received: Instant,
+    /// Whether the connection stays open for more requests after this one.
+    pub keep_alive: bool,
+    /// The parts of the path that matched the route's parameters, decoded.
+    pub params: HashMap<&'static str, String>,
+}
+
+impl Request<'_> {
+    pub fn query(&self, key: &str) -> Option<&str> {
+        self.query.get(key).copied()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).copied()
+    }
+
+    /// A cookie from the `Cookie` header, which looks like `a=1; b=2`.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.header("cookie")?
+            .split(';')
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find_map(|(key, value)| (key == name).then_some(value))
+    }
+
+    /// The language the client asked for with `Accept-Language`.
+    pub fn locale(&self) -> Locale {
+        Locale::from_accept_language(self.header("accept-language").unwrap_or_default())
+    }
+
+    /// A parameter from the route's path. Routes always have the parameters
+    /// that their handlers ask for, so this panics if it's missing.
+    pub fn param(&self, name: &str) -> &str {
+        &self.params[name]
+    }
+}
+
+/// The errors that only HTTP has, and the ones every protocol has.
+pub enum HttpError {
+    Unauthorized,
+    /// The path exists, but not with this method. Has the methods that do
+    /// work.
+    MethodNotAllowed(Vec<&'static str>),
+    TooManyRequests {
+        retry_after_secs: u64,
+    },
+    Protocol(ProtocolError),
+}
+
+impl<E: Into<anyhow::Error>> From<E> for HttpError {
+    fn from(err: E) -> Self {
+        HttpError::Protocol(ProtocolError::Internal(err.into()))
+    }
+}
+
+impl From<ProtocolError> for HttpError {
+    fn from(err: ProtocolError) -> Self {
+        HttpError::Protocol(err)
+    }
+}
+
+impl HttpError {
+    /// The response for the error, with the message in the language. Bad
+    /// requests and refusals are only in English since the handlers write
+    /// those messages.
+    pub fn into_response(self, locale: Locale) -> Response {
+        let strings = locale.strings();
+        let response = match self {
+            HttpError::Unauthorized => Response::text(401, format!("{}\n", strings.unauthorized))
+                .header("WWW-Authenticate", "Bearer"),
+            HttpError::MethodNotAllowed(methods) => {
+                Response::text(405, format!("{}\n", strings.method_not_allowed))
+                    .header("Allow", methods.join(", "))
+            }
+            HttpError::TooManyRequests { retry_after_secs } => {
+                Response::text(429, format!("{}\n", strings.too_many_requests))
+                    .header("Retry-After", retry_after_secs.to_string())
+            }
+            HttpError::Protocol(ProtocolError::BadRequest(message)) => {
+                return Response::text(400, format!("{message}\n"))
+            }
+            HttpError::Protocol(ProtocolError::PolicyViolation(message)) => {
+                return Response::text(403, format!("{message}\n"))
+            }
+            HttpError::Protocol(ProtocolError::NotFound) => {
+                Response::text(404, format!("{}\n", strings.not_found))
+            }
+            // the client won't see this, but the connection still needs a
+            // response to be done with
+            HttpError::Protocol(ProtocolError::ClientDisconnected) => {
+                Response::text(400, String::new())
+            }
+            HttpError::Protocol(ProtocolError::TimedOut) => {
+                return Response::text(408, "Request Timeout\n")
+            }
+            HttpError::Protocol(ProtocolError::Internal(err)) => {
+                eprintln!("error handling http request: {err:?}");
+                Response::text(500, format!("{}\n", strings.internal_error))
+            }
+        };
+        response.header("Content-Language", locale.code())
+    }
+}
+
+impl<S> Default for Router<S> {
+    fn default() -> Self {
+        Router {
+            routes: Vec::new(),
+            middleware: Vec::new(),
+        }
+    }
+}
+
+impl<S> Router<S> {
+    pub fn get(self, pattern: &'static str, handler: Handler<S>) -> Self {
+        self.route("GET", pattern, handler)
+    }
+
+    pub fn post(self, pattern: &'static str, handler: Handler<S>) -> Self {
+        self.route("POST", pattern, handler)
+    }
+
+    /// Routes are tried in the order they're added.
+    pub fn route(
+        mut self,
+        method: &'static str,
+        pattern: &'static str,
+        handler: Handler<S>,
+    ) -> Self {
+        self.routes.push(Route {
+            info: RouteInfo {
+                method,
+                pattern,
+                scope: None,
+            },
+            handler,
+        });
+        self
+    }
+
+    /// Make the route that was just added need the token for the scope.
+    pub fn auth(mut self, scope: Scope) -> Self {
+        let route = self
+            .routes
+            .last_mut()
+            .expect("auth has to come after a route");
+        route.info.scope = Some(scope);
+        self
+    }
+
+    /// The paths that need a token whatever the method is, so crawlers can be
+    /// told to stay away from them. Parameters are left off the end, since
+    /// robots.txt matches prefixes.
+    pub fn private_paths(&self) -> Vec<&'static str> {
+        let mut paths = Vec::new();
+        for route in &self.routes {
+            let pattern = route.info.pattern;
+            let all_private = self
+                .routes
+                .iter()
+                .filter(|other| other.info.pattern == pattern)
+                .all(|other| other.info.scope.is_some());
+            let path = match pattern.find([':', '*']) {
+                Some(i) => &pattern[..i],
+                None => pattern,
+            };
+            if all_private && !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        paths
+    }
+
+    /// Middleware runs in the order it's added.
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    pub fn handle(&self, state: &S, request: &mut Request) -> Response {
+        let mut response = self
+            .dispatch(state, request)
+            .unwrap_or_else(|err| err.into_response(request.locale()));
+        for middleware in &self.middleware {
+            middleware.after(request, &mut response);
+        }
+        response
+    }
+
+    fn dispatch(&self, state: &S, request: &mut Request) -> Result<Response, HttpError> {
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            let Some(params) = match_path(route.info.pattern, request.path) else {
+                continue;
+            };
+            if route.info.method != request.method {
+                allowed.push(route.info.method);
+                continue;
+            }
+            request.params = params;
+            for middleware in &self.middleware {
+                middleware.before(request, &route.info)?;
+            }
+            return (route.handler)(state, request);
+        }
+
+        if allowed.is_empty() {
+            Err(ProtocolError::NotFound.into())
+        } else {
+            Err(HttpError::MethodNotAllowed(allowed))
+        }
+    }
+}
+
+/// The parameters from the path if it matches the pattern. A slash at the end
+/// of the path is ignored.
+fn match_path(pattern: &'static str, path: &str) -> Option<HashMap<&'static str, String>> {
+    let path = match path.strip_suffix('/') {
+        Some(path) if !path.is_empty() => path,
+        _ => path,
+    };
+    let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().into_owned();
+
+    let mut params = HashMap::new();
+    let mut path_segments = path.split('/');
+    for pattern_segment in pattern.split('/') {
+        if let Some(name) = pattern_segment.strip_prefix('*') {
+            // the rest of the path, which has to have something in it
+            let rest = path_segments.collect::<Vec<_>>().join("/");
+            if rest.is_empty() {
+                return None;
+            }
+            params.insert(name, decode(&rest));
+            return Some(params);
+        }
+        let path_segment = path_segments.next()?;
+        match pattern_segment.strip_prefix(':') {
+            Some(name) if !path_segment.is_empty() => {
+                params.insert(name, decode(path_segment));
+            }
+            Some(_) => return None,
+            None if pattern_segment == decode(path_segment) => {}
+            None => return None,
+        }
+    }
+    path_segments.next().is_none().then_some(params)
+}
@@ -0,0 +1,141 @@
+//! Ident (RFC 1413), which is how a server asks who's on the other end of a
+//! connection to it. IRC servers and some older services still check it when
+//! someone connects from here, so every valid query is answered with the same
+//! user id instead of the connection being refused.
+
+use std::net::SocketAddr;
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{access_log, crawl::SiteData, lifecycle, listen::Listener, timeouts};
+
+use super::{error::ProtocolError, Protocol, ServerConfig, Shutdown};
+
+const BIND_PORT: u16 = {
+    #[cfg(debug_assertions)]
+    {
+        1113
+    }
+    #[cfg(not(debug_assertions))]
+    113
+};
+/// The RFC says queries are at most 1000 characters, and they're usually much
+/// shorter.
+const MAX_QUERY_LENGTH: u64 = 1000;
+
+/// The operating system and user id that every connection belongs to.
+const OPERATING_SYSTEM: &str = "UNIX";
+const USER_ID: &str = "mat";
+
+pub struct Ident;
+
+impl Protocol for Ident {
+    type Options = ();
+
+    fn generate(_: &SiteData, _: &Self::Options) -> Self {
+        Ident
+    }
+
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        shutdown
+            .run(async move {
+                let Some(listener) = Listener::bind("ident", port) else {
+                    return;
+                };
+
+                loop {
+                    let (stream, remote_addr) = listener.accept().await.unwrap();
+                    println!("started tcp connection for ident: {remote_addr:?}");
+
+                    tokio::spawn(async move {
+                        let _session = lifecycle::Session::start("ident");
+                        if let Err(err) = handle(stream, remote_addr).await {
+                            if err.is_internal() {
+                                eprintln!("error handling ident query: {err}");
+                            }
+                        }
+                    });
+                }
+            })
+            .await;
+    }
+}
+
+/// Answer queries until the client closes the connection. Most only send one.
+// not recorded in the analytics, since these come from servers checking up on
+// connections and not from people
+async fn handle(mut stream: TcpStream, remote_addr: SocketAddr) -> Result<(), ProtocolError> {
+    let (read, mut write) = stream.split();
+    let mut read = BufReader::new(read);
+    loop {
+        let mut query = String::new();
+        let mut limited = (&mut read).take(MAX_QUERY_LENGTH);
+        if timeouts::read(limited.read_line(&mut query)).await? == 0 {
+            break;
+        }
+        // too long, or the connection was closed in the middle of it
+        let last = !query.ends_with('\n');
+        let mut log = access_log::Entry::start("ident", remote_addr.ip());
+        log.request(query.trim());
+
+        let response = respond(&query);
+        let status = if response.contains(" : ERROR : ") {
+            "error"
+        } else {
+            "ok"
+        };
+        let result = timeouts::write(write.write_all(response.as_bytes())).await;
+        log.finish(status, response.len() as u64);
+        result?;
+        if last {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The response to a query like `6193, 23`, which is the port on our side
+/// and then the port on the client's side.
+fn respond(query: &str) -> String {
+    let Some((server_port, client_port)) = query.trim().split_once(',') else {
+        return format!("{} : ERROR : UNKNOWN-ERROR\r\n", query.trim());
+    };
+    let (server_port, client_port) = (server_port.trim(), client_port.trim());
+    let ports = format!("{server_port}, {client_port}");
+    match (parse_port(server_port), parse_port(client_port)) {
+        (Some(_), Some(_)) => format!("{ports} : USERID : {OPERATING_SYSTEM} : {USER_ID}\r\n"),
+        _ => format!("{ports} : ERROR : INVALID-PORT\r\n"),
+    }
+}
+
+/// Ports are 1 to 65535, and written in decimal without a sign.
+fn parse_port(port: &str) -> Option<u16> {
+    if !port.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    port.parse().ok().filter(|&port| port != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_queries() {
+        assert_eq!(
+            respond("6193, 23\r\n"),
+            "6193, 23 : USERID : UNIX : mat\r\n"
+        );
+        assert_eq!(respond(" 6195 ,23 "), "6195, 23 : USERID : UNIX : mat\r\n");
+        assert_eq!(respond("0, 23\r\n"), "0, 23 : ERROR : INVALID-PORT\r\n");
+        assert_eq!(
+            respond("65536, +23\r\n"),
+            "65536, +23 : ERROR : INVALID-PORT\r\n"
+        );
+        assert_eq!(respond("hello\r\n"), "hello : ERROR : UNKNOWN-ERROR\r\n");
+    }
+}
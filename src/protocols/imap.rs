@@ -0,0 +1,878 @@
+//! A read-only IMAP server (RFC 3501) where the blog posts are the messages in
+//! the INBOX, so the blog can be read in a mail client. Any username and
+//! password are accepted.
+//!
+//! A message's uid is the same as its sequence number. The messages are
+//! numbered from the oldest post, so new posts don't change the old uids.
+
+use std::{
+    collections::BTreeSet, iter::Peekable, net::SocketAddr, str::Chars, sync::Arc, time::Duration,
+};
+
+use anyhow::bail;
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{analytics, crawl::SiteData, lifecycle, listen::Listener, timeouts, tls, HOSTNAME};
+
+use super::{
+    mail_render::{self, encode_header, Message, FROM_MAILBOX, FROM_NAME},
+    Protocol, ServerConfig, Shutdown,
+};
+
+const BIND_PORT: u16 = {
+    #[cfg(debug_assertions)]
+    {
+        1143
+    }
+    #[cfg(not(debug_assertions))]
+    143
+};
+const TLS_BIND_PORT: u16 = {
+    #[cfg(debug_assertions)]
+    {
+        1993
+    }
+    #[cfg(not(debug_assertions))]
+    993
+};
+
+/// Commands are short since nothing can be uploaded, so this also limits
+/// literals.
+const MAX_COMMAND_LENGTH: usize = 8 * 1024;
+/// RFC 3501 says clients have to be logged out after at least 30 minutes of
+/// not doing anything.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// How deep parenthesized lists can be nested. Nothing needs more than a couple
+/// of levels, and the parsers recurse for each one.
+const MAX_NESTING: usize = 8;
+
+const CAPABILITIES: &str = "IMAP4rev1 LITERAL+";
+const MAILBOX: &str = "INBOX";
+/// This would only have to change if a post was deleted.
+const UID_VALIDITY: u32 = 1;
+
+pub struct Imap {
+    /// Oldest first, since that's the order that messages are numbered in.
+    pub messages: Vec<Message>,
+}
+
+impl Protocol for Imap {
+    type Options = ();
+
+    fn generate(data: &SiteData, _: &Self::Options) -> Self {
+        Imap {
+            messages: mail_render::messages(data),
+        }
+    }
+
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        let tls_port = config.tls_port_or(TLS_BIND_PORT);
+        shutdown
+            .run(async move {
+                let imap = Arc::new(self);
+
+                tokio::join!(
+                    listen(Arc::clone(&imap), port, None),
+                    listen(imap, tls_port, Some(tls::acceptor()))
+                );
+            })
+            .await;
+    }
+}
+
+fn envelope(message: &Message) -> String {
+    let address = format!("((\"{FROM_NAME}\" NIL \"{FROM_MAILBOX}\" \"{HOSTNAME}\"))");
+    // date, subject, from, sender, reply-to, to, cc, bcc, in-reply-to,
+    // message-id
+    format!(
+        "(\"{date}\" {subject} {address} {address} {address} NIL NIL NIL NIL \"<{slug}@{HOSTNAME}>\")",
+        date = message.published.to_rfc2822(),
+        subject = quote(&encode_header(&message.title)),
+        slug = message.slug,
+    )
+}
+
+/// Start a tcp server, with tls if there's an acceptor.
+async fn listen(imap: Arc<Imap>, port: u16, acceptor: Option<TlsAcceptor>) {
+    let Some(listener) = Listener::bind("imap", port) else {
+        return;
+    };
+
+    loop {
+        let (stream, remote_addr) = listener.accept().await.unwrap();
+        println!("started tcp connection for imap: {remote_addr:?}");
+
+        let imap = Arc::clone(&imap);
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            let _session = lifecycle::Session::start("imap");
+            let result = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => handle(imap, stream, remote_addr).await,
+                    Err(e) => Err(e.into()),
+                },
+                None => handle(imap, stream, remote_addr).await,
+            };
+            if let Err(e) = result {
+                eprintln!("{:?}", e);
+            }
+        });
+    }
+}
+
+#[derive(PartialEq)]
+enum State {
+    NotAuthenticated,
+    Authenticated,
+    Selected,
+    Logout,
+}
+
+/// How the tagged response to a command starts.
+enum Completion {
+    Ok(String),
+    No(String),
+    Bad(String),
+}
+
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+    imap: Arc<Imap>,
+    stream: S,
+    remote_addr: SocketAddr,
+) -> anyhow::Result<()> {
+    let (read, mut write) = tokio::io::split(stream);
+    let mut read = BufReader::new(read);
+
+    write
+        .write_all(format!("* OK [CAPABILITY {CAPABILITIES}] {HOSTNAME} IMAP ready\r\n").as_bytes())
+        .await?;
+
+    let mut state = State::NotAuthenticated;
+    while state != State::Logout {
+        // clients can take their time between commands, but each command has
+        // to arrive within the read timeout once it starts
+        if timeout(IDLE_TIMEOUT, read.fill_buf()).await??.is_empty() {
+            break;
+        }
+        let reading = read_command(&mut read, &mut write);
+        let Some(command) = timeout(timeouts::read_timeout(), reading).await?? else {
+            break;
+        };
+        let response = respond(&imap, &mut state, &command, remote_addr);
+        write.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Read a line, and any literals that are in it. The literals are turned into
+/// quoted strings so the rest of the parsing doesn't have to know about them.
+async fn read_command(
+    read: &mut (impl AsyncBufRead + Unpin),
+    write: &mut (impl AsyncWrite + Unpin),
+) -> anyhow::Result<Option<String>> {
+    let mut command = String::new();
+    loop {
+        let mut line = String::new();
+        let remaining = MAX_COMMAND_LENGTH.saturating_sub(command.len()) as u64;
+        if (&mut *read).take(remaining).read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        let Some(line) = line.strip_suffix('\n') else {
+            bail!("command is too long");
+        };
+        let line = line.strip_suffix('\r').unwrap_or(line);
+
+        // a literal is like {5}\r\nhello, and with LITERAL+ the client can
+        // send {5+} to not wait for us to say it's fine
+        let Some((before, length, non_synchronizing)) = literal_marker(line) else {
+            command.push_str(line);
+            return Ok(Some(command));
+        };
+        if command.len() + length > MAX_COMMAND_LENGTH {
+            bail!("literal is too long");
+        }
+        if !non_synchronizing {
+            write.write_all(b"+ Ready for literal\r\n").await?;
+        }
+        let mut literal = vec![0; length];
+        read.read_exact(&mut literal).await?;
+
+        command.push_str(before);
+        command.push('"');
+        for c in String::from_utf8_lossy(&literal).chars() {
+            if c == '"' || c == '\\' {
+                command.push('\\');
+            }
+            command.push(c);
+        }
+        command.push('"');
+    }
+}
+
+/// If the line ends with `{n}` or `{n+}`, the line before it and `n`.
+fn literal_marker(line: &str) -> Option<(&str, usize, bool)> {
+    let (before, marker) = line.strip_suffix('}')?.rsplit_once('{')?;
+    let (length, non_synchronizing) = match marker.strip_suffix('+') {
+        Some(length) => (length, true),
+        None => (marker, false),
+    };
+    Some((before, length.parse().ok()?, non_synchronizing))
+}
+
+fn respond(imap: &Imap, state: &mut State, command: &str, remote_addr: SocketAddr) -> String {
+    let Some(args) = parse_args(command) else {
+        return "* BAD Couldn't parse command\r\n".to_owned();
+    };
+    let (Some(Arg::Atom(tag)), Some(Arg::Atom(name))) = (args.first(), args.get(1)) else {
+        return "* BAD Missing tag or command\r\n".to_owned();
+    };
+    let mut name = name.to_ascii_uppercase();
+    let mut args = &args[2..];
+
+    // UID FETCH and UID SEARCH are the same as without UID here, except that
+    // FETCH always includes the uid
+    let uid = name == "UID";
+    if uid {
+        let Some((Arg::Atom(uid_name), rest)) = args.split_first() else {
+            return format!("{tag} BAD Missing command after UID\r\n");
+        };
+        name = uid_name.to_ascii_uppercase();
+        args = rest;
+    }
+
+    let (untagged, completion) = run(imap, state, uid, &name, args, remote_addr);
+    let (status, text) = match completion {
+        Completion::Ok(text) => ("OK", text),
+        Completion::No(text) => ("NO", text),
+        Completion::Bad(text) => ("BAD", text),
+    };
+    format!("{untagged}{tag} {status} {text}\r\n")
+}
+
+fn run(
+    imap: &Imap,
+    state: &mut State,
+    uid: bool,
+    name: &str,
+    args: &[Arg],
+    remote_addr: SocketAddr,
+) -> (String, Completion) {
+    let completed = || Completion::Ok(format!("{name} completed"));
+    let read_only = || Completion::No("[CANNOT] The mailbox is read-only".to_owned());
+
+    // commands that work in any state
+    match name {
+        "CAPABILITY" => return (format!("* CAPABILITY {CAPABILITIES}\r\n"), completed()),
+        "NOOP" => return (String::new(), completed()),
+        "LOGOUT" => {
+            *state = State::Logout;
+            return ("* BYE Logging out\r\n".to_owned(), completed());
+        }
+        _ => {}
+    }
+
+    if *state == State::NotAuthenticated {
+        return match name {
+            "LOGIN" if args.len() == 2 => {
+                *state = State::Authenticated;
+                (String::new(), completed())
+            }
+            "AUTHENTICATE" => (String::new(), Completion::No("Use LOGIN".to_owned())),
+            _ => (String::new(), Completion::Bad("Log in first".to_owned())),
+        };
+    }
+
+    match name {
+        "SELECT" | "EXAMINE" => {
+            let [mailbox] = args else {
+                return (
+                    String::new(),
+                    Completion::Bad("Expected a mailbox".to_owned()),
+                );
+            };
+            // a failed select still leaves the old mailbox
+            *state = State::Authenticated;
+            if !is_inbox(mailbox) {
+                return (String::new(), Completion::No("No such mailbox".to_owned()));
+            }
+            *state = State::Selected;
+            let count = imap.messages.len();
+            (
+                format!(
+                    "* FLAGS (\\Seen \\Answered \\Flagged \\Deleted \\Draft)\r\n\
+                    * OK [PERMANENTFLAGS ()] No permanent flags permitted\r\n\
+                    * {count} EXISTS\r\n\
+                    * 0 RECENT\r\n\
+                    * OK [UIDVALIDITY {UID_VALIDITY}] UIDs valid\r\n\
+                    * OK [UIDNEXT {}] Predicted next UID\r\n",
+                    count + 1
+                ),
+                Completion::Ok(format!("[READ-ONLY] {name} completed")),
+            )
+        }
+        "LIST" | "LSUB" => {
+            let [Some(reference), Some(pattern)] = [args.first(), args.get(1)].map(|a| a?.as_str())
+            else {
+                return (
+                    String::new(),
+                    Completion::Bad("Expected a reference and mailbox".to_owned()),
+                );
+            };
+            let untagged = if pattern.is_empty() {
+                // asking for the hierarchy delimiter
+                format!("* {name} (\\Noselect) \"/\" \"\"\r\n")
+            } else if wildcard_match(
+                &format!("{reference}{pattern}").to_ascii_uppercase(),
+                MAILBOX,
+            ) {
+                format!("* {name} (\\HasNoChildren) \"/\" {MAILBOX}\r\n")
+            } else {
+                String::new()
+            };
+            (untagged, completed())
+        }
+        "STATUS" => {
+            let [mailbox, Arg::List(items)] = args else {
+                return (
+                    String::new(),
+                    Completion::Bad("Expected a mailbox and items".to_owned()),
+                );
+            };
+            if !is_inbox(mailbox) {
+                return (String::new(), Completion::No("No such mailbox".to_owned()));
+            }
+            let count = imap.messages.len();
+            let mut values = Vec::new();
+            for item in items {
+                let item = item.as_str().unwrap_or_default().to_ascii_uppercase();
+                let value = match item.as_str() {
+                    "MESSAGES" | "UNSEEN" => count,
+                    "RECENT" => 0,
+                    "UIDNEXT" => count + 1,
+                    "UIDVALIDITY" => UID_VALIDITY as usize,
+                    _ => {
+                        return (
+                            String::new(),
+                            Completion::Bad(format!("Unknown item {item}")),
+                        );
+                    }
+                };
+                values.push(format!("{item} {value}"));
+            }
+            (
+                format!("* STATUS {MAILBOX} ({})\r\n", values.join(" ")),
+                completed(),
+            )
+        }
+        "CREATE" | "DELETE" | "RENAME" | "SUBSCRIBE" | "UNSUBSCRIBE" | "APPEND" => {
+            (String::new(), read_only())
+        }
+        _ if *state != State::Selected => (
+            String::new(),
+            Completion::Bad("Select a mailbox first".to_owned()),
+        ),
+        "CHECK" => (String::new(), completed()),
+        "CLOSE" | "UNSELECT" => {
+            *state = State::Authenticated;
+            (String::new(), completed())
+        }
+        "EXPUNGE" | "STORE" | "COPY" | "MOVE" => (String::new(), read_only()),
+        "FETCH" => fetch(imap, uid, args, remote_addr),
+        "SEARCH" => search(imap, args),
+        _ => (String::new(), Completion::Bad("Unknown command".to_owned())),
+    }
+}
+
+fn is_inbox(mailbox: &Arg) -> bool {
+    mailbox
+        .as_str()
+        .is_some_and(|mailbox| mailbox.eq_ignore_ascii_case(MAILBOX))
+}
+
+/// `*` and `%` match anything. They're different when there's a hierarchy, but
+/// we only have one mailbox.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    match pattern.chars().next() {
+        None => name.is_empty(),
+        Some('*' | '%') => {
+            let rest = &pattern[1..];
+            (0..=name.len())
+                .filter(|&i| name.is_char_boundary(i))
+                .any(|i| wildcard_match(rest, &name[i..]))
+        }
+        Some(c) => {
+            name.starts_with(c) && wildcard_match(&pattern[c.len_utf8()..], &name[c.len_utf8()..])
+        }
+    }
+}
+
+fn fetch(imap: &Imap, uid: bool, args: &[Arg], remote_addr: SocketAddr) -> (String, Completion) {
+    let [set, items] = args else {
+        return (
+            String::new(),
+            Completion::Bad("Expected a sequence set and items".to_owned()),
+        );
+    };
+    let Some(set) = set
+        .as_str()
+        .and_then(|set| parse_sequence_set(set, imap.messages.len() as u32))
+    else {
+        return (
+            String::new(),
+            Completion::Bad("Invalid sequence set".to_owned()),
+        );
+    };
+    let items = match items {
+        Arg::List(items) => items.iter().map(Arg::as_str).collect::<Option<Vec<_>>>(),
+        item => item.as_str().map(|item| vec![item]),
+    };
+    let Some(items) = items.and_then(|items| parse_fetch_items(&items)) else {
+        return (
+            String::new(),
+            Completion::Bad("Invalid fetch items".to_owned()),
+        );
+    };
+    let mut items = items;
+    if uid && !items.iter().any(|item| matches!(item, FetchItem::Uid)) {
+        items.insert(0, FetchItem::Uid);
+    }
+
+    let mut untagged = String::new();
+    for number in set {
+        let message = &imap.messages[number as usize - 1];
+        if items.iter().any(|item| {
+            matches!(
+                item,
+                FetchItem::Section {
+                    section: Section::All | Section::Text,
+                    ..
+                }
+            )
+        }) {
+            analytics::record("imap", &message.slug, Some(&message.slug), remote_addr.ip());
+        }
+        let values = items
+            .iter()
+            .map(|item| item.render(number, message))
+            .collect::<Vec<_>>();
+        untagged.push_str(&format!("* {number} FETCH ({})\r\n", values.join(" ")));
+    }
+    (untagged, Completion::Ok("FETCH completed".to_owned()))
+}
+
+enum FetchItem {
+    Flags,
+    Uid,
+    InternalDate,
+    Size,
+    Envelope,
+    /// `BODY` or `BODYSTRUCTURE`, which are the same since messages only have
+    /// one part.
+    Structure(&'static str),
+    Section {
+        /// What it's called in the response, like `BODY[HEADER]`.
+        name: String,
+        section: Section,
+        /// The start and length, for `<start.length>`.
+        partial: Option<(usize, usize)>,
+    },
+}
+
+enum Section {
+    All,
+    Header,
+    HeaderFields { fields: Vec<String>, not: bool },
+    Text,
+}
+
+fn parse_fetch_items(items: &[&str]) -> Option<Vec<FetchItem>> {
+    let mut parsed = Vec::new();
+    for item in items {
+        let item = item.to_ascii_uppercase();
+        let expanded: &[&str] = match item.as_str() {
+            "ALL" => &["FLAGS", "INTERNALDATE", "RFC822.SIZE", "ENVELOPE"],
+            "FAST" => &["FLAGS", "INTERNALDATE", "RFC822.SIZE"],
+            "FULL" => &["FLAGS", "INTERNALDATE", "RFC822.SIZE", "ENVELOPE", "BODY"],
+            item => &[item],
+        };
+        for item in expanded {
+            parsed.push(parse_fetch_item(item)?);
+        }
+    }
+    Some(parsed)
+}
+
+/// The item has to already be uppercase.
+fn parse_fetch_item(item: &str) -> Option<FetchItem> {
+    let whole = |name: &str, section| FetchItem::Section {
+        name: name.to_owned(),
+        section,
+        partial: None,
+    };
+    Some(match item {
+        "FLAGS" => FetchItem::Flags,
+        "UID" => FetchItem::Uid,
+        "INTERNALDATE" => FetchItem::InternalDate,
+        "RFC822.SIZE" => FetchItem::Size,
+        "ENVELOPE" => FetchItem::Envelope,
+        "BODY" => FetchItem::Structure("BODY"),
+        "BODYSTRUCTURE" => FetchItem::Structure("BODYSTRUCTURE"),
+        "RFC822" => whole("RFC822", Section::All),
+        "RFC822.HEADER" => whole("RFC822.HEADER", Section::Header),
+        "RFC822.TEXT" => whole("RFC822.TEXT", Section::Text),
+        _ => {
+            // BODY[section]<start.length>, and PEEK is the same since we
+            // don't keep track of \Seen
+            let rest = item
+                .strip_prefix("BODY.PEEK[")
+                .or_else(|| item.strip_prefix("BODY["))?;
+            let (section, partial) = rest.split_once(']')?;
+            let partial = if partial.is_empty() {
+                None
+            } else {
+                let (start, length) = partial
+                    .strip_prefix('<')?
+                    .strip_suffix('>')?
+                    .split_once('.')?;
+                Some((start.parse().ok()?, length.parse().ok()?))
+            };
+            let parsed_section = match section {
+                "" => Section::All,
+                "HEADER" => Section::Header,
+                // part 1 of a message that isn't multipart is its text
+                "TEXT" | "1" => Section::Text,
+                _ => {
+                    let (kind, fields) = section.split_once(' ')?;
+                    let not = match kind {
+                        "HEADER.FIELDS" => false,
+                        "HEADER.FIELDS.NOT" => true,
+                        _ => return None,
+                    };
+                    let fields = fields
+                        .strip_prefix('(')?
+                        .strip_suffix(')')?
+                        .split_whitespace()
+                        .map(str::to_owned)
+                        .collect();
+                    Section::HeaderFields { fields, not }
+                }
+            };
+            FetchItem::Section {
+                name: format!("BODY[{section}]"),
+                section: parsed_section,
+                partial,
+            }
+        }
+    })
+}
+
+impl FetchItem {
+    fn render(&self, number: u32, message: &Message) -> String {
+        match self {
+            FetchItem::Flags => "FLAGS ()".to_owned(),
+            FetchItem::Uid => format!("UID {number}"),
+            FetchItem::InternalDate => format!(
+                "INTERNALDATE \"{}\"",
+                message.published.format("%d-%b-%Y %H:%M:%S %z")
+            ),
+            FetchItem::Size => format!("RFC822.SIZE {}", message.size()),
+            FetchItem::Envelope => format!("ENVELOPE {}", envelope(message)),
+            FetchItem::Structure(name) => format!(
+                "{name} (\"TEXT\" \"PLAIN\" (\"CHARSET\" \"UTF-8\") NIL NIL \"8BIT\" {} {})",
+                message.text.len(),
+                message.text.lines().count()
+            ),
+            FetchItem::Section {
+                name,
+                section,
+                partial,
+            } => {
+                let content = match section {
+                    Section::All => message.raw(),
+                    Section::Header => message.header.clone(),
+                    Section::HeaderFields { fields, not } => message.header_fields(fields, *not),
+                    Section::Text => message.text.clone(),
+                };
+                match partial {
+                    Some((start, length)) => {
+                        let start = usize::min(*start, content.len());
+                        let end = usize::min(start.saturating_add(*length), content.len());
+                        let content = String::from_utf8_lossy(&content.as_bytes()[start..end]);
+                        format!("{name}<{start}> {}", literal(&content))
+                    }
+                    None => format!("{name} {}", literal(&content)),
+                }
+            }
+        }
+    }
+}
+
+fn search(imap: &Imap, args: &[Arg]) -> (String, Completion) {
+    let mut args = args;
+    if let [Arg::Atom(charset), Arg::Atom(name) | Arg::String(name), rest @ ..] = args {
+        if charset.eq_ignore_ascii_case("CHARSET") {
+            if !name.eq_ignore_ascii_case("UTF-8") && !name.eq_ignore_ascii_case("US-ASCII") {
+                return (
+                    String::new(),
+                    Completion::No("[BADCHARSET (UTF-8)] Unsupported charset".to_owned()),
+                );
+            }
+            args = rest;
+        }
+    }
+    let Some(criteria) = parse_criteria(args, imap.messages.len() as u32) else {
+        return (
+            String::new(),
+            Completion::Bad("Invalid search criteria".to_owned()),
+        );
+    };
+
+    let numbers = (1..=imap.messages.len() as u32)
+        .filter(|&number| {
+            let message = &imap.messages[number as usize - 1];
+            criteria.iter().all(|c| c.matches(number, message))
+        })
+        .map(|number| format!(" {number}"))
+        .collect::<String>();
+    (
+        format!("* SEARCH{numbers}\r\n"),
+        Completion::Ok("SEARCH completed".to_owned()),
+    )
+}
+
+/// All of them have to match.
+enum Criterion {
+    /// Flags, which are always the same for every message.
+    Always(bool),
+    Subject(String),
+    Body(String),
+    Text(String),
+    From(String),
+    Numbers(BTreeSet<u32>),
+}
+
+fn parse_criteria(args: &[Arg], max: u32) -> Option<Vec<Criterion>> {
+    let mut criteria = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let key = match arg {
+            Arg::List(list) => {
+                criteria.extend(parse_criteria(list, max)?);
+                continue;
+            }
+            arg => arg.as_str()?.to_ascii_uppercase(),
+        };
+        let mut string = || Some(args.next()?.as_str()?.to_lowercase());
+        criteria.push(match key.as_str() {
+            "ALL" | "OLD" | "UNSEEN" | "UNANSWERED" | "UNDELETED" | "UNDRAFT" | "UNFLAGGED" => {
+                Criterion::Always(true)
+            }
+            "NEW" | "RECENT" | "SEEN" | "ANSWERED" | "DELETED" | "DRAFT" | "FLAGGED" => {
+                Criterion::Always(false)
+            }
+            "SUBJECT" => Criterion::Subject(string()?),
+            "BODY" => Criterion::Body(string()?),
+            "TEXT" => Criterion::Text(string()?),
+            "FROM" => Criterion::From(string()?),
+            "UID" => Criterion::Numbers(parse_sequence_set(&string()?, max)?),
+            set => Criterion::Numbers(parse_sequence_set(set, max)?),
+        });
+    }
+    Some(criteria)
+}
+
+impl Criterion {
+    fn matches(&self, number: u32, message: &Message) -> bool {
+        match self {
+            Criterion::Always(matches) => *matches,
+            Criterion::Subject(s) => message.title.to_lowercase().contains(s),
+            Criterion::Body(s) => message.text.to_lowercase().contains(s),
+            Criterion::Text(s) => {
+                message.header.to_lowercase().contains(s) || message.text.to_lowercase().contains(s)
+            }
+            Criterion::From(s) => mail_render::from().to_lowercase().contains(s),
+            Criterion::Numbers(numbers) => numbers.contains(&number),
+        }
+    }
+}
+
+/// Parse something like `1:3,5,7:*` into the numbers in it, where `*` is the
+/// biggest number. Numbers that are too big are left out, so there aren't any
+/// when the mailbox is empty.
+fn parse_sequence_set(set: &str, max: u32) -> Option<BTreeSet<u32>> {
+    let number = |n: &str| {
+        if n == "*" {
+            Some(max)
+        } else {
+            n.parse::<u32>().ok().filter(|&n| n > 0)
+        }
+    };
+    let mut numbers = BTreeSet::new();
+    for range in set.split(',') {
+        let (start, end) = range.split_once(':').unwrap_or((range, range));
+        let (start, end) = (number(start)?, number(end)?);
+        numbers.extend(u32::min(start, end).max(1)..=u32::max(start, end).min(max));
+    }
+    Some(numbers)
+}
+
+enum Arg {
+    Atom(String),
+    String(String),
+    List(Vec<Arg>),
+}
+
+impl Arg {
+    /// The contents of an atom or string.
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Arg::Atom(s) | Arg::String(s) => Some(s),
+            Arg::List(_) => None,
+        }
+    }
+}
+
+fn parse_args(command: &str) -> Option<Vec<Arg>> {
+    parse_list(&mut command.chars().peekable(), 0)
+}
+
+/// Parse the arguments until the end of the list, which is the end of the
+/// command if `depth` is 0.
+fn parse_list(chars: &mut Peekable<Chars>, depth: usize) -> Option<Vec<Arg>> {
+    if depth > MAX_NESTING {
+        return None;
+    }
+    let nested = depth > 0;
+    let mut args = Vec::new();
+    loop {
+        while chars.next_if_eq(&' ').is_some() {}
+        match chars.peek() {
+            None => return (!nested).then_some(args),
+            Some(')') => {
+                chars.next();
+                return nested.then_some(args);
+            }
+            Some('(') => {
+                chars.next();
+                args.push(Arg::List(parse_list(chars, depth + 1)?));
+            }
+            Some('"') => {
+                chars.next();
+                let mut string = String::new();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => string.push(chars.next()?),
+                        c => string.push(c),
+                    }
+                }
+                args.push(Arg::String(string));
+            }
+            Some(_) => {
+                // fetch items like BODY[HEADER.FIELDS (DATE)] have spaces and
+                // parentheses in the brackets
+                let mut atom = String::new();
+                let mut brackets = 0_usize;
+                while let Some(&c) = chars.peek() {
+                    if brackets == 0 && matches!(c, ' ' | '(' | ')' | '"') {
+                        break;
+                    }
+                    match c {
+                        '[' => brackets += 1,
+                        ']' => brackets = brackets.saturating_sub(1),
+                        _ => {}
+                    }
+                    atom.push(c);
+                    chars.next();
+                }
+                args.push(Arg::Atom(atom));
+            }
+        }
+    }
+}
+
+/// A quoted string if it can be one, otherwise a literal.
+fn quote(s: &str) -> String {
+    if s.is_ascii() && !s.contains(['\r', '\n']) {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        literal(s)
+    }
+}
+
+fn literal(s: &str) -> String {
+    format!("{{{}}}\r\n{s}", s.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> Message {
+        Message {
+            slug: "hello".to_owned(),
+            title: "Hello".to_owned(),
+            published: Default::default(),
+            header: "Subject: Hello\r\n\r\n".to_owned(),
+            text: text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn parses_sequence_sets() {
+        let set = |set, max| parse_sequence_set(set, max).map(Vec::from_iter);
+        assert_eq!(set("1:3,5,7:*", 8), Some(vec![1, 2, 3, 5, 7, 8]));
+        assert_eq!(set("*:2", 3), Some(vec![2, 3]));
+        assert_eq!(set("2:100", 3), Some(vec![2, 3]));
+        assert_eq!(set("0", 3), None);
+        assert_eq!(set("a", 3), None);
+        // there's nothing for * to be in an empty mailbox
+        assert_eq!(set("*", 0), Some(vec![]));
+        assert_eq!(set("1:*", 0), Some(vec![]));
+    }
+
+    #[test]
+    fn parses_args() {
+        let args = parse_args(r#"FETCH 1:* (FLAGS BODY[HEADER.FIELDS (DATE)]) "a \"b\"""#).unwrap();
+        let [Arg::Atom(command), Arg::Atom(set), Arg::List(items), Arg::String(string)] = &args[..]
+        else {
+            panic!("wrong args");
+        };
+        assert_eq!((command.as_str(), set.as_str()), ("FETCH", "1:*"));
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].as_str(), Some("BODY[HEADER.FIELDS (DATE)]"));
+        assert_eq!(string, r#"a "b""#);
+
+        assert!(parse_args("FETCH (1").is_none());
+        assert!(parse_args("FETCH 1)").is_none());
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth| format!("SEARCH {}ALL{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse_args(&nested(MAX_NESTING)).is_some());
+        assert!(parse_args(&nested(MAX_NESTING + 1)).is_none());
+        assert!(parse_args(&nested(MAX_COMMAND_LENGTH / 2)).is_none());
+    }
+
+    #[test]
+    fn fetches_partial_bodies() {
+        let message = message("Hello, world!");
+        let fetch = |item| parse_fetch_items(&[item]).unwrap()[0].render(1, &message);
+        assert_eq!(fetch("BODY[TEXT]<7.5>"), "BODY[TEXT]<7> {5}\r\nworld");
+        assert_eq!(fetch("BODY[TEXT]<7.100>"), "BODY[TEXT]<7> {6}\r\nworld!");
+        assert_eq!(fetch("BODY[TEXT]<100.5>"), "BODY[TEXT]<13> {0}\r\n");
+        assert_eq!(
+            fetch("BODY[TEXT]<1.18446744073709551615>"),
+            "BODY[TEXT]<1> {12}\r\nello, world!"
+        );
+    }
+}
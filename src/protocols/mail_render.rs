@@ -0,0 +1,135 @@
+//! The blog posts as email messages (RFC 822), for IMAP and POP3.
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::{crawl::SiteData, drafts, links, HOSTNAME};
+
+use super::plain_text::{Links, PlainTextSite};
+
+pub const FROM_NAME: &str = "mat";
+pub const FROM_MAILBOX: &str = "mat";
+
+pub struct Message {
+    pub slug: String,
+    pub title: String,
+    pub published: DateTime<Utc>,
+    /// Ends with the empty line that separates it from the text.
+    pub header: String,
+    pub text: String,
+}
+
+/// Links in the messages go to the website, since mail clients can open those.
+struct Mail;
+
+impl Links for Mail {
+    fn address(path: &str) -> String {
+        links::absolute(path)
+    }
+}
+
+/// Every published post as a message, oldest first.
+pub fn messages(data: &SiteData) -> Vec<Message> {
+    let site = PlainTextSite::generate::<Mail>(data);
+
+    let mut messages = drafts::published(&data.blog)
+        .map(|post| {
+            let header = format!(
+                "Date: {date}\r\n\
+                From: {from}\r\n\
+                Subject: {subject}\r\n\
+                Message-ID: <{slug}@{HOSTNAME}>\r\n\
+                MIME-Version: 1.0\r\n\
+                Content-Type: text/plain; charset=utf-8\r\n\
+                Content-Transfer-Encoding: 8bit\r\n\
+                \r\n",
+                date = post.published.to_rfc2822(),
+                from = from(),
+                subject = encode_header(&post.title),
+                slug = post.slug,
+            );
+            let text = site.posts[&post.slug]
+                .replace("\r\n", "\n")
+                .replace('\n', "\r\n");
+            Message {
+                slug: post.slug.clone(),
+                title: post.title.clone(),
+                published: post.published,
+                header,
+                text,
+            }
+        })
+        .collect::<Vec<_>>();
+    messages.sort_by_key(|message| message.published);
+    messages
+}
+
+/// What's in the From header.
+pub fn from() -> String {
+    format!("{FROM_NAME} <{FROM_MAILBOX}@{HOSTNAME}>")
+}
+
+impl Message {
+    /// The header and text together.
+    pub fn raw(&self) -> String {
+        format!("{}{}", self.header, self.text)
+    }
+
+    pub fn size(&self) -> usize {
+        self.header.len() + self.text.len()
+    }
+
+    /// An id that stays the same as long as the slug does, for POP3's UIDL.
+    pub fn uidl(&self) -> String {
+        let hash = Sha256::digest(self.slug.as_bytes());
+        hash[..16].iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// Only the header lines with (or without, if `not`) these names.
+    pub fn header_fields(&self, fields: &[String], not: bool) -> String {
+        let mut out = String::new();
+        for line in self.header.split("\r\n").filter(|line| !line.is_empty()) {
+            let name = line.split(':').next().unwrap_or_default();
+            if fields.iter().any(|f| f.eq_ignore_ascii_case(name)) != not {
+                out.push_str(line);
+                out.push_str("\r\n");
+            }
+        }
+        out.push_str("\r\n");
+        out
+    }
+}
+
+/// Headers can only have ascii in them, so anything else has to be in an
+/// encoded-word like `=?utf-8?Q?caf=C3=A9?=` (RFC 2047).
+pub fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_owned();
+    }
+    // encoded-words can't be longer than 75 characters
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for c in value.chars() {
+        let mut encoded = String::new();
+        if c == ' ' {
+            encoded.push('_');
+        } else if c.is_ascii_alphanumeric() {
+            encoded.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                encoded.push_str(&format!("={b:02X}"));
+            }
+        }
+        if word.len() + encoded.len() > 60 {
+            words.push(std::mem::take(&mut word));
+        }
+        word.push_str(&encoded);
+    }
+    words.push(word);
+    words
+        .iter()
+        .map(|word| format!("=?utf-8?Q?{word}?="))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
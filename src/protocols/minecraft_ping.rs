@@ -0,0 +1,266 @@
+//! The Minecraft Server List Ping, so adding matdoes.dev as a server shows the
+//! quote of the day as the MOTD and the number of blog posts as the player
+//! count. Actually joining isn't possible, you just get disconnected.
+//!
+//! See <https://minecraft.wiki/w/Java_Edition_protocol/Server_List_Ping>.
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{analytics, crawl::SiteData, drafts, lifecycle, listen::Listener, HOSTNAME};
+
+use super::{qotd::Qotd, Protocol, ServerConfig, Shutdown};
+
+const BIND_PORT: u16 = 25565;
+
+/// Status packets are small, anything bigger than this is nonsense.
+const MAX_PACKET_LENGTH: i32 = 32 * 1024;
+/// How many post titles are shown when hovering over the player count.
+const SAMPLE_SIZE: usize = 5;
+/// The first byte that clients from before 1.7 send.
+const LEGACY_PING: u8 = 0xfe;
+/// Clients before 1.4 only send [`LEGACY_PING`], so if nothing else comes
+/// after this long we answer in the oldest format.
+const LEGACY_PING_TIMEOUT: Duration = Duration::from_millis(100);
+
+const DISCONNECT_MESSAGE: &str = "This isn't a real server, but thanks for stopping by!";
+
+pub struct MinecraftPing {
+    pub qotd: Qotd,
+    pub post_count: usize,
+    /// The titles of the newest posts, which are shown as the players.
+    pub recent_titles: Vec<String>,
+}
+
+impl Protocol for MinecraftPing {
+    type Options = Qotd;
+
+    fn generate(data: &SiteData, qotd: &Self::Options) -> Self {
+        MinecraftPing {
+            qotd: qotd.clone(),
+            post_count: drafts::published(&data.blog).count(),
+            recent_titles: drafts::published(&data.blog)
+                .take(SAMPLE_SIZE)
+                .map(|post| post.title.clone())
+                .collect(),
+        }
+    }
+
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        shutdown
+            .run(async move {
+                let ping = Arc::new(self);
+
+                let Some(listener) = Listener::bind("minecraft_ping", port) else {
+                    return;
+                };
+
+                loop {
+                    let (stream, remote_addr) = listener.accept().await.unwrap();
+                    println!("started tcp connection for minecraft ping: {remote_addr:?}");
+
+                    let ping = Arc::clone(&ping);
+                    tokio::spawn(async move {
+                        let _session = lifecycle::Session::start("minecraft_ping");
+                        if let Err(err) = handle(ping, stream, remote_addr).await {
+                            eprintln!("{:?}", err);
+                        }
+                    });
+                }
+            })
+            .await;
+    }
+}
+
+impl MinecraftPing {
+    fn motd(&self) -> String {
+        String::from_utf8_lossy(&self.qotd.message.read())
+            .trim()
+            .to_owned()
+    }
+
+    fn status_json(&self, protocol_version: i32) -> String {
+        json!({
+            // the client's own version so it doesn't say we're outdated
+            "version": { "name": HOSTNAME, "protocol": protocol_version },
+            "players": {
+                "max": self.post_count,
+                "online": self.post_count,
+                "sample": self.recent_titles.iter().map(|title| json!({
+                    "name": title,
+                    "id": "00000000-0000-0000-0000-000000000000",
+                })).collect::<Vec<_>>(),
+            },
+            "description": { "text": self.motd() },
+        })
+        .to_string()
+    }
+
+    /// The response for clients from before 1.7, which is a kick packet with
+    /// the fields in the reason.
+    fn legacy_status(&self, pre_1_4: bool) -> Vec<u8> {
+        // the old clients can't show newlines, and § separates the fields
+        let motd = self.motd().replace(['\n', '§'], " ");
+        let text = if pre_1_4 {
+            format!("{motd}§{0}§{0}", self.post_count)
+        } else {
+            format!("§1\0127\0{HOSTNAME}\0{motd}\0{0}\0{0}", self.post_count)
+        };
+
+        let text = text.encode_utf16().collect::<Vec<_>>();
+        let mut packet = vec![0xff];
+        packet.extend((text.len() as u16).to_be_bytes());
+        for unit in text {
+            packet.extend(unit.to_be_bytes());
+        }
+        packet
+    }
+}
+
+async fn handle(
+    ping: Arc<MinecraftPing>,
+    mut stream: TcpStream,
+    remote_addr: SocketAddr,
+) -> io::Result<()> {
+    analytics::record("minecraft", "", None, remote_addr.ip());
+
+    let first_byte = stream.read_u8().await?;
+    if first_byte == LEGACY_PING {
+        let pre_1_4 = timeout(LEGACY_PING_TIMEOUT, stream.read_u8())
+            .await
+            .is_err();
+        stream.write_all(&ping.legacy_status(pre_1_4)).await?;
+        return Ok(());
+    }
+
+    // handshake
+    let handshake = read_packet(&mut stream, first_byte).await?;
+    let mut data = handshake.as_slice();
+    let (Some(0x00), Some(protocol_version), Some(_address), Some(_port), Some(next_state)) = (
+        read_varint(&mut data),
+        read_varint(&mut data),
+        read_string(&mut data),
+        read_u16(&mut data),
+        read_varint(&mut data),
+    ) else {
+        return Ok(());
+    };
+
+    // 1 is status, 2 is login
+    if next_state != 1 {
+        let mut packet = Vec::new();
+        write_varint(&mut packet, 0x00);
+        write_string(
+            &mut packet,
+            &json!({ "text": DISCONNECT_MESSAGE }).to_string(),
+        );
+        write_packet(&mut stream, &packet).await?;
+        return Ok(());
+    }
+
+    loop {
+        let first_byte = stream.read_u8().await?;
+        let packet = read_packet(&mut stream, first_byte).await?;
+        let mut data = packet.as_slice();
+        match read_varint(&mut data) {
+            // status request
+            Some(0x00) => {
+                let mut response = Vec::new();
+                write_varint(&mut response, 0x00);
+                write_string(&mut response, &ping.status_json(protocol_version));
+                write_packet(&mut stream, &response).await?;
+            }
+            // ping, which gets sent back as the pong so the client can show
+            // the latency
+            Some(0x01) => {
+                write_packet(&mut stream, &packet).await?;
+                return Ok(());
+            }
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Read a length-prefixed packet. The first byte of the length was already
+/// read so we could check if it's a legacy ping.
+async fn read_packet(stream: &mut TcpStream, first_byte: u8) -> io::Result<Vec<u8>> {
+    let mut length = 0;
+    let mut byte = first_byte;
+    for i in 0..5 {
+        length |= ((byte & 0x7f) as i32) << (7 * i);
+        if byte & 0x80 == 0 {
+            break;
+        }
+        byte = stream.read_u8().await?;
+    }
+    if !(1..=MAX_PACKET_LENGTH).contains(&length) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad packet length {length}"),
+        ));
+    }
+
+    let mut packet = vec![0; length as usize];
+    stream.read_exact(&mut packet).await?;
+    Ok(packet)
+}
+
+async fn write_packet(stream: &mut TcpStream, packet: &[u8]) -> io::Result<()> {
+    let mut framed = Vec::with_capacity(packet.len() + 5);
+    write_varint(&mut framed, packet.len() as i32);
+    framed.extend(packet);
+    stream.write_all(&framed).await
+}
+
+fn read_varint(data: &mut &[u8]) -> Option<i32> {
+    let mut value = 0;
+    for i in 0..5 {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= ((byte & 0x7f) as i32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn write_varint(buf: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7f == 0 {
+            buf.push(value as u8);
+            return;
+        }
+        buf.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+fn read_string(data: &mut &[u8]) -> Option<String> {
+    let length = usize::try_from(read_varint(data)?).ok()?;
+    if data.len() < length {
+        return None;
+    }
+    let (string, rest) = data.split_at(length);
+    *data = rest;
+    String::from_utf8(string.to_vec()).ok()
+}
+
+fn write_string(buf: &mut Vec<u8>, string: &str) {
+    write_varint(buf, string.len() as i32);
+    buf.extend(string.as_bytes());
+}
+
+fn read_u16(data: &mut &[u8]) -> Option<u16> {
+    let (bytes, rest) = data.split_first_chunk::<2>()?;
+    *data = rest;
+    Some(u16::from_be_bytes(*bytes))
+}
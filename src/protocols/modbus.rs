@@ -0,0 +1,181 @@
+//! Modbus/TCP, so the quote of the day can be read from a PLC. Only Read
+//! Holding Registers is supported, and the registers are:
+//!
+//! - 0: how many blog posts there are
+//! - 1: how many projects there are
+//! - 2-3: when the newest post was published, as a unix timestamp
+//! - 4: how many bytes long the quote of the day is
+//! - 100 and up: the quote of the day, two bytes per register
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{analytics, crawl::SiteData, drafts, lifecycle, listen::Listener, timeouts};
+
+use super::{qotd::Qotd, Protocol, ServerConfig, Shutdown};
+
+const BIND_PORT: u16 = {
+    #[cfg(debug_assertions)]
+    {
+        5020
+    }
+    #[cfg(not(debug_assertions))]
+    502
+};
+
+/// How long clients can go between requests. PLCs usually poll every few
+/// seconds.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Where the quote of the day starts.
+const QOTD_ADDRESS: usize = 100;
+/// The most registers that can be read at once, from the spec.
+const MAX_READ_QUANTITY: u16 = 125;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+
+pub struct Modbus {
+    pub qotd: Qotd,
+    pub post_count: u16,
+    pub project_count: u16,
+    /// Unix timestamp of the newest published post.
+    pub newest_post: u32,
+}
+
+impl Protocol for Modbus {
+    type Options = Qotd;
+
+    fn generate(data: &SiteData, qotd: &Self::Options) -> Self {
+        Modbus {
+            qotd: qotd.clone(),
+            post_count: drafts::published(&data.blog).count() as u16,
+            project_count: data.projects.len() as u16,
+            newest_post: drafts::published(&data.blog)
+                .map(|post| post.published.timestamp() as u32)
+                .max()
+                .unwrap_or_default(),
+        }
+    }
+
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        shutdown
+            .run(async move {
+                let modbus = Arc::new(self);
+
+                let Some(listener) = Listener::bind("modbus", port) else {
+                    return;
+                };
+
+                loop {
+                    let (stream, remote_addr) = listener.accept().await.unwrap();
+                    println!("started tcp connection for modbus: {remote_addr:?}");
+
+                    let modbus = Arc::clone(&modbus);
+                    tokio::spawn(async move {
+                        let _session = lifecycle::Session::start("modbus");
+                        if let Err(err) = handle(modbus, stream, remote_addr).await {
+                            eprintln!("{:?}", err);
+                        }
+                    });
+                }
+            })
+            .await;
+    }
+}
+
+impl Modbus {
+    /// Every register, starting from address 0.
+    fn registers(&self) -> Vec<u16> {
+        let qotd = self.qotd.message.read().clone();
+
+        let mut registers = vec![0; QOTD_ADDRESS];
+        registers[0] = self.post_count;
+        registers[1] = self.project_count;
+        registers[2] = (self.newest_post >> 16) as u16;
+        registers[3] = self.newest_post as u16;
+        registers[4] = qotd.len() as u16;
+        // the last register is padded with a zero if the length is odd
+        registers.extend(
+            qotd.chunks(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)])),
+        );
+        registers
+    }
+}
+
+/// Requests are answered one at a time until the client disconnects.
+async fn handle(
+    modbus: Arc<Modbus>,
+    mut stream: TcpStream,
+    remote_addr: SocketAddr,
+) -> io::Result<()> {
+    analytics::record("modbus", "", None, remote_addr.ip());
+
+    loop {
+        // the mbap header: transaction id, protocol id, length, unit id
+        let mut header = [0; 7];
+        match timeout(IDLE_TIMEOUT, stream.read_exact(&mut header)).await? {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let protocol_id = u16::from_be_bytes([header[2], header[3]]);
+        let length = u16::from_be_bytes([header[4], header[5]]);
+        // the length includes the unit id, and there has to be a function code
+        if protocol_id != 0 || !(2..=254).contains(&length) {
+            return Ok(());
+        }
+        let mut pdu = vec![0; length as usize - 1];
+        timeout(timeouts::read_timeout(), stream.read_exact(&mut pdu)).await??;
+
+        let response_pdu = respond(&modbus, &pdu);
+
+        let mut response = Vec::with_capacity(7 + response_pdu.len());
+        response.extend(&header[..4]);
+        response.extend((response_pdu.len() as u16 + 1).to_be_bytes());
+        response.push(header[6]);
+        response.extend(response_pdu);
+        stream.write_all(&response).await?;
+    }
+}
+
+fn respond(modbus: &Modbus, pdu: &[u8]) -> Vec<u8> {
+    let function = pdu[0];
+    match function {
+        READ_HOLDING_REGISTERS => {
+            let [address_hi, address_lo, quantity_hi, quantity_lo] = pdu[1..] else {
+                return exception(function, ILLEGAL_DATA_VALUE);
+            };
+            let address = u16::from_be_bytes([address_hi, address_lo]) as usize;
+            let quantity = u16::from_be_bytes([quantity_hi, quantity_lo]);
+            if !(1..=MAX_READ_QUANTITY).contains(&quantity) {
+                return exception(function, ILLEGAL_DATA_VALUE);
+            }
+
+            let registers = modbus.registers();
+            let Some(values) = registers.get(address..address + quantity as usize) else {
+                return exception(function, ILLEGAL_DATA_ADDRESS);
+            };
+            let mut response = vec![function, (quantity * 2) as u8];
+            for value in values {
+                response.extend(value.to_be_bytes());
+            }
+            response
+        }
+        _ => exception(function, ILLEGAL_FUNCTION),
+    }
+}
+
+fn exception(function: u8, code: u8) -> Vec<u8> {
+    vec![function | 0x80, code]
+}
@@ -0,0 +1,420 @@
+//! A minimal MQTT 3.1.1 broker where we're the only publisher. Clients can
+//! subscribe to these topics, which all have retained messages:
+//!
+//! - `blog/latest`: the newest post, as JSON
+//! - `qotd`: the quote of the day
+//! - `stats/visitors`: how many requests there have been, across every
+//!   protocol
+//!
+//! Only QoS 0 is supported, and clients that try to publish are disconnected.
+
+use std::{collections::BTreeMap, io, net::SocketAddr, sync::LazyLock, time::Duration};
+
+use anyhow::bail;
+use parking_lot::RwLock;
+use serde_json::json;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{broadcast, mpsc},
+    time::{sleep, timeout},
+};
+
+use crate::{analytics, crawl::SiteData, drafts, lifecycle, links, listen::Listener};
+
+use super::{qotd::Qotd, Protocol, ServerConfig, Shutdown};
+
+const BIND_PORT: u16 = 1883;
+
+pub const BLOG_LATEST_TOPIC: &str = "blog/latest";
+pub const QOTD_TOPIC: &str = "qotd";
+pub const VISITORS_TOPIC: &str = "stats/visitors";
+
+/// How long clients have to send CONNECT after connecting.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long clients that turned off keep alive can go without sending
+/// anything. They'd usually ping more often than this anyway.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// How often we check if the visitor count changed.
+const STATS_INTERVAL: Duration = Duration::from_secs(30);
+/// Nothing that a client sends us should be anywhere near this big.
+const MAX_PACKET_LENGTH: usize = 64 * 1024;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+
+const UNACCEPTABLE_PROTOCOL_VERSION: u8 = 0x01;
+const IDENTIFIER_REJECTED: u8 = 0x02;
+
+static BROKER: LazyLock<Broker> = LazyLock::new(|| Broker {
+    retained: Default::default(),
+    updates: broadcast::channel(64).0,
+});
+
+struct Broker {
+    /// The last message on every topic, which is sent to new subscribers.
+    retained: RwLock<BTreeMap<String, Vec<u8>>>,
+    /// Every message that's published, for the clients that are connected.
+    updates: broadcast::Sender<(String, Vec<u8>)>,
+}
+
+/// Send a message to everyone subscribed to the topic, and keep it for the
+/// ones that subscribe later.
+pub fn publish(topic: &str, payload: impl Into<Vec<u8>>) {
+    let payload = payload.into();
+    BROKER
+        .retained
+        .write()
+        .insert(topic.to_owned(), payload.clone());
+    // it's fine if nobody is connected
+    let _ = BROKER.updates.send((topic.to_owned(), payload));
+}
+
+pub struct Mqtt {
+    pub qotd: Qotd,
+    /// The payload for [`BLOG_LATEST_TOPIC`].
+    pub latest_post: Option<String>,
+}
+
+impl Protocol for Mqtt {
+    type Options = Qotd;
+
+    fn generate(data: &SiteData, qotd: &Self::Options) -> Self {
+        Mqtt {
+            qotd: qotd.clone(),
+            latest_post: drafts::published(&data.blog)
+                .max_by_key(|post| post.published)
+                .map(|post| {
+                    json!({
+                        "title": post.title,
+                        "slug": post.slug,
+                        "published": post.published,
+                        "url": links::absolute(&post.slug),
+                    })
+                    .to_string()
+                }),
+        }
+    }
+
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        shutdown
+            .run(async move {
+                if let Some(latest_post) = self.latest_post {
+                    publish(BLOG_LATEST_TOPIC, latest_post);
+                }
+                publish(QOTD_TOPIC, self.qotd.message.read().clone());
+
+                // not spawned, so it stops when the server does
+                tokio::join!(poll_visitors(), listen(port));
+            })
+            .await;
+    }
+}
+
+/// The visitor count changes all the time, so it's checked every now and then
+/// instead of on every request.
+async fn poll_visitors() {
+    let mut last_total = None;
+    loop {
+        let total = analytics::stats().total;
+        if last_total != Some(total) {
+            publish(VISITORS_TOPIC, total.to_string());
+            last_total = Some(total);
+        }
+        sleep(STATS_INTERVAL).await;
+    }
+}
+
+async fn listen(port: u16) {
+    let Some(listener) = Listener::bind("mqtt", port) else {
+        return;
+    };
+
+    loop {
+        let (stream, remote_addr) = listener.accept().await.unwrap();
+        println!("started tcp connection for mqtt: {remote_addr:?}");
+
+        tokio::spawn(async move {
+            let _session = lifecycle::Session::start("mqtt");
+            if let Err(err) = handle(stream, remote_addr).await {
+                eprintln!("{:?}", err);
+            }
+        });
+    }
+}
+
+struct Packet {
+    kind: u8,
+    body: Vec<u8>,
+}
+
+async fn handle(stream: TcpStream, remote_addr: SocketAddr) -> anyhow::Result<()> {
+    let (mut read, mut write) = stream.into_split();
+
+    let connect = timeout(CONNECT_TIMEOUT, read_packet(&mut read)).await??;
+    if connect.kind != CONNECT {
+        bail!("expected CONNECT, got packet type {}", connect.kind);
+    }
+    let keep_alive = match parse_connect(&connect.body) {
+        Ok(keep_alive) => keep_alive,
+        Err(return_code) => {
+            write.write_all(&[CONNACK << 4, 2, 0, return_code]).await?;
+            return Ok(());
+        }
+    };
+    write.write_all(&[CONNACK << 4, 2, 0, 0]).await?;
+    analytics::record("mqtt", "", None, remote_addr.ip());
+
+    // packets are read in another task, since reading isn't cancel-safe and we
+    // also have to wait for updates at the same time
+    let (packets_tx, mut packets) = mpsc::channel(16);
+    let reader = tokio::spawn(async move {
+        loop {
+            let idle_timeout = keep_alive.unwrap_or(IDLE_TIMEOUT);
+            let packet = match timeout(idle_timeout, read_packet(&mut read)).await {
+                Ok(packet) => packet,
+                Err(_) => break,
+            };
+            let Ok(packet) = packet else {
+                break;
+            };
+            if packets_tx.send(packet).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut updates = BROKER.updates.subscribe();
+    let mut subscriptions = Vec::<String>::new();
+    let result = async {
+        loop {
+            tokio::select! {
+                packet = packets.recv() => {
+                    let Some(packet) = packet else {
+                        break;
+                    };
+                    match packet.kind {
+                        SUBSCRIBE => {
+                            let Some((packet_id, filters)) = parse_subscribe(&packet.body) else {
+                                break;
+                            };
+                            let mut suback = packet_id.to_be_bytes().to_vec();
+                            for filter in &filters {
+                                // 0 is the qos that was granted
+                                suback.push(if is_valid_filter(filter) { 0 } else { 0x80 });
+                            }
+                            write.write_all(&encode_packet(SUBACK << 4, &suback)).await?;
+
+                            for filter in filters.into_iter().filter(|f| is_valid_filter(f)) {
+                                let retained = BROKER
+                                    .retained
+                                    .read()
+                                    .iter()
+                                    .filter(|(topic, _)| topic_matches(&filter, topic))
+                                    .map(|(topic, payload)| encode_publish(topic, payload, true))
+                                    .collect::<Vec<_>>();
+                                for publish in retained {
+                                    write.write_all(&publish).await?;
+                                }
+                                if !subscriptions.contains(&filter) {
+                                    subscriptions.push(filter);
+                                }
+                            }
+                        }
+                        UNSUBSCRIBE => {
+                            let Some((packet_id, filters)) = parse_unsubscribe(&packet.body) else {
+                                break;
+                            };
+                            subscriptions.retain(|s| !filters.contains(s));
+                            write
+                                .write_all(&encode_packet(UNSUBACK << 4, &packet_id.to_be_bytes()))
+                                .await?;
+                        }
+                        PINGREQ => write.write_all(&[PINGRESP << 4, 0]).await?,
+                        // DISCONNECT, and PUBLISH since we're the only one who
+                        // can publish
+                        _ => break,
+                    }
+                }
+                update = updates.recv() => match update {
+                    Ok((topic, payload)) => {
+                        if subscriptions.iter().any(|f| topic_matches(f, &topic)) {
+                            write.write_all(&encode_publish(&topic, &payload, false)).await?;
+                        }
+                    }
+                    // they'll get the next one
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            }
+        }
+        Ok(()) as io::Result<()>
+    }
+    .await;
+    reader.abort();
+    Ok(result?)
+}
+
+async fn read_packet(read: &mut (impl AsyncRead + Unpin)) -> io::Result<Packet> {
+    let first_byte = read.read_u8().await?;
+
+    // the remaining length is 7 bits per byte, with up to 4 bytes
+    let mut length = 0;
+    for i in 0..4 {
+        let byte = read.read_u8().await?;
+        length |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if i == 3 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "remaining length is too long",
+            ));
+        }
+    }
+    if length > MAX_PACKET_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("packet is too big ({length} bytes)"),
+        ));
+    }
+
+    let mut body = vec![0; length];
+    read.read_exact(&mut body).await?;
+    Ok(Packet {
+        kind: first_byte >> 4,
+        body,
+    })
+}
+
+fn encode_packet(first_byte: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![first_byte];
+    let mut length = body.len();
+    loop {
+        let mut byte = (length & 0x7f) as u8;
+        length >>= 7;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    packet
+}
+
+/// A QoS 0 PUBLISH. `retain` is only set when it's a retained message being
+/// sent because of a new subscription.
+fn encode_publish(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::with_capacity(2 + topic.len() + payload.len());
+    body.extend((topic.len() as u16).to_be_bytes());
+    body.extend(topic.as_bytes());
+    body.extend(payload);
+    encode_packet((PUBLISH << 4) | retain as u8, &body)
+}
+
+/// Returns the keep alive (with the grace period that the spec allows), or the
+/// CONNACK return code if the connection was refused.
+fn parse_connect(body: &[u8]) -> Result<Option<Duration>, u8> {
+    let mut data = body;
+    let protocol_name = read_string(&mut data).ok_or(UNACCEPTABLE_PROTOCOL_VERSION)?;
+    let [level, flags, keep_alive_hi, keep_alive_lo, ..] = *data else {
+        return Err(UNACCEPTABLE_PROTOCOL_VERSION);
+    };
+    if protocol_name != "MQTT" || level != 4 {
+        return Err(UNACCEPTABLE_PROTOCOL_VERSION);
+    }
+    data = &data[4..];
+
+    // clients without an id have to start a clean session, which is the only
+    // kind we have anyways
+    let client_id = read_string(&mut data).ok_or(IDENTIFIER_REJECTED)?;
+    let clean_session = flags & 0x02 != 0;
+    if client_id.is_empty() && !clean_session {
+        return Err(IDENTIFIER_REJECTED);
+    }
+
+    let keep_alive = u16::from_be_bytes([keep_alive_hi, keep_alive_lo]);
+    Ok((keep_alive > 0).then(|| Duration::from_secs(keep_alive as u64 * 3 / 2)))
+}
+
+/// The packet id and the topic filters (the requested QoS is ignored).
+fn parse_subscribe(body: &[u8]) -> Option<(u16, Vec<String>)> {
+    let mut data = body;
+    let packet_id = read_u16(&mut data)?;
+    let mut filters = Vec::new();
+    while !data.is_empty() {
+        filters.push(read_string(&mut data)?);
+        let (_qos, rest) = data.split_first()?;
+        data = rest;
+    }
+    (!filters.is_empty()).then_some((packet_id, filters))
+}
+
+fn parse_unsubscribe(body: &[u8]) -> Option<(u16, Vec<String>)> {
+    let mut data = body;
+    let packet_id = read_u16(&mut data)?;
+    let mut filters = Vec::new();
+    while !data.is_empty() {
+        filters.push(read_string(&mut data)?);
+    }
+    (!filters.is_empty()).then_some((packet_id, filters))
+}
+
+fn read_u16(data: &mut &[u8]) -> Option<u16> {
+    let (bytes, rest) = data.split_first_chunk::<2>()?;
+    *data = rest;
+    Some(u16::from_be_bytes(*bytes))
+}
+
+fn read_string(data: &mut &[u8]) -> Option<String> {
+    let length = read_u16(data)? as usize;
+    if data.len() < length {
+        return None;
+    }
+    let (string, rest) = data.split_at(length);
+    *data = rest;
+    String::from_utf8(string.to_vec()).ok()
+}
+
+/// `+` can only be a whole level, and `#` can only be the whole last level.
+fn is_valid_filter(filter: &str) -> bool {
+    let levels = filter.split('/').collect::<Vec<_>>();
+    !filter.is_empty()
+        && levels.iter().enumerate().all(|(i, level)| {
+            (!level.contains('+') || *level == "+")
+                && (!level.contains('#') || (*level == "#" && i == levels.len() - 1))
+        })
+}
+
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match level {
+            // this also matches the parent, so `blog/#` matches `blog`
+            "#" => return true,
+            "+" => {
+                if topic_levels.next().is_none() {
+                    return false;
+                }
+            }
+            level => {
+                if topic_levels.next() != Some(level) {
+                    return false;
+                }
+            }
+        }
+    }
+    topic_levels.next().is_none()
+}
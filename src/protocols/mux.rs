@@ -0,0 +1,201 @@
+//! One port for the protocols that can be told apart by how their connections
+//! start, for networks that only let a single port through. The first bytes
+//! are peeked instead of read, so the protocol's own handler still gets the
+//! whole connection.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    net::TcpStream,
+    time::{sleep, timeout_at, Instant},
+};
+use tokio_rustls::TlsAcceptor;
+
+use super::{finger::Finger, gemini::Gemini, gopher::Gopher, http::Http, ssh::Ssh};
+use crate::{listen::Listener, tls};
+
+/// How long we wait for the client to say something. SSH clients might wait
+/// for the server to go first, so connections that stay quiet are SSH.
+const DETECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long to wait before peeking again when there isn't enough to tell yet.
+/// Peeking doesn't wait for new bytes once there are some.
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+/// Enough for any of the prefixes, and for most gopher selectors and finger
+/// queries.
+const PEEK_LENGTH: usize = 256;
+
+/// The first bytes of HTTP requests.
+const HTTP_METHODS: [&[u8]; 7] = [
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Ssh,
+    Gemini,
+    Http,
+    Gopher,
+    Finger,
+}
+
+pub struct Mux {
+    pub port: u16,
+    pub ssh: Ssh,
+    pub gemini: Arc<Gemini>,
+    pub http: Arc<Http>,
+    pub gopher: Arc<Gopher>,
+    pub finger: Arc<Finger>,
+}
+
+impl Mux {
+    pub async fn serve(self) {
+        let Some(listener) = Listener::bind("mux", self.port) else {
+            return;
+        };
+
+        let mux = Arc::new(self);
+        // tls is only for gemini here
+        let acceptor = tls::acceptor_with_client_certs();
+        loop {
+            let (stream, remote_addr) = listener.accept().await.unwrap();
+            let mux = Arc::clone(&mux);
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                mux.dispatch(stream, remote_addr, acceptor).await;
+            });
+        }
+    }
+
+    async fn dispatch(&self, stream: TcpStream, remote_addr: SocketAddr, acceptor: TlsAcceptor) {
+        let kind = match detect_stream(&stream).await {
+            Ok(Some(kind)) => kind,
+            // closed before sending anything
+            Ok(None) => return,
+            Err(err) => {
+                eprintln!("couldn't peek at connection from {remote_addr:?}: {err}");
+                return;
+            }
+        };
+        println!("mux detected {kind:?} from {remote_addr:?}");
+
+        match kind {
+            Kind::Ssh => self.ssh.handle_connection(stream, remote_addr),
+            Kind::Gemini => self.gemini.handle_connection(stream, remote_addr, acceptor),
+            Kind::Http => self.http.handle_connection(stream, remote_addr),
+            Kind::Gopher => self.gopher.handle_connection(stream, remote_addr, None),
+            Kind::Finger => self.finger.handle_connection(stream, remote_addr, None),
+        }
+    }
+}
+
+/// Peek at the start of the connection until we know what it is. Returns
+/// `None` if it was closed first.
+async fn detect_stream(stream: &TcpStream) -> std::io::Result<Option<Kind>> {
+    let deadline = Instant::now() + DETECT_TIMEOUT;
+    let mut buffer = [0; PEEK_LENGTH];
+    let mut len = 0;
+    while let Ok(peeked) = timeout_at(deadline, stream.peek(&mut buffer)).await {
+        len = peeked?;
+        if len == 0 {
+            return Ok(None);
+        }
+        if let Some(kind) = detect(&buffer[..len]) {
+            return Ok(Some(kind));
+        }
+        if len == buffer.len() {
+            // a really long line
+            return Ok(Some(line_kind(&buffer)));
+        }
+        sleep(PEEK_INTERVAL).await;
+    }
+
+    Ok(Some(if len == 0 {
+        Kind::Ssh
+    } else {
+        // a line that was never finished
+        line_kind(&buffer[..len])
+    }))
+}
+
+/// Which protocol a connection that starts with these bytes is using, or
+/// `None` if we need more of them to tell.
+fn detect(bytes: &[u8]) -> Option<Kind> {
+    if bytes.is_empty() {
+        return None;
+    }
+    // a tls handshake record, gemini is the only protocol here that starts
+    // with tls
+    if bytes[0] == 0x16 {
+        return Some(Kind::Gemini);
+    }
+
+    let prefixes = [(&b"SSH-"[..], Kind::Ssh)]
+        .into_iter()
+        .chain(HTTP_METHODS.map(|method| (method, Kind::Http)));
+    let mut might_match = false;
+    for (prefix, kind) in prefixes {
+        if bytes.starts_with(prefix) {
+            return Some(kind);
+        }
+        might_match |= prefix.starts_with(bytes);
+    }
+    if might_match {
+        return None;
+    }
+
+    // gopher and finger both send a line, so wait for all of it
+    if !bytes.contains(&b'\n') {
+        return None;
+    }
+    Some(line_kind(bytes))
+}
+
+/// Gopher selectors on this server are empty or start with a slash (except
+/// for `caps.txt`, which clients ask for without one), and anything else is a
+/// finger query. `/W` is finger's verbose flag.
+fn line_kind(line: &[u8]) -> Kind {
+    let line = line.split(|&b| b == b'\r' || b == b'\n').next().unwrap();
+    if line == b"caps.txt" {
+        Kind::Gopher
+    } else if line.starts_with(b"/W") || !(line.is_empty() || line.starts_with(b"/")) {
+        Kind::Finger
+    } else {
+        Kind::Gopher
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_protocols() {
+        assert_eq!(detect(b"SSH-2.0-OpenSSH_9.6\r\n"), Some(Kind::Ssh));
+        assert_eq!(detect(b"\x16\x03\x01\x02\x00\x01"), Some(Kind::Gemini));
+        assert_eq!(detect(b"GET / HTTP/1.1\r\n"), Some(Kind::Http));
+        assert_eq!(detect(b"POST /comments"), Some(Kind::Http));
+        assert_eq!(detect(b"\r\n"), Some(Kind::Gopher));
+        assert_eq!(detect(b"/blog\r\n"), Some(Kind::Gopher));
+        assert_eq!(detect(b"/search\tquery\r\n"), Some(Kind::Gopher));
+        assert_eq!(detect(b"caps.txt\r\n"), Some(Kind::Gopher));
+        assert_eq!(detect(b"blog\r\n"), Some(Kind::Finger));
+        assert_eq!(detect(b"/W mat\r\n"), Some(Kind::Finger));
+    }
+
+    #[test]
+    fn waits_for_more() {
+        assert_eq!(detect(b""), None);
+        assert_eq!(detect(b"SS"), None);
+        assert_eq!(detect(b"GE"), None);
+        // could still be a finger query for "get"
+        assert_eq!(detect(b"GET"), None);
+        assert_eq!(detect(b"/blog"), None);
+        assert_eq!(detect(b"GEMS\r\n"), Some(Kind::Finger));
+    }
+}
@@ -0,0 +1,134 @@
+//! Nex is like a simpler gopher: the client sends a path, and gets back either a
+//! directory listing (if the path ends with a slash) or a file. Links in
+//! directory listings are lines that start with `=> `.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{
+    access_log, analytics, crawl::SiteData, lifecycle, listen::Listener, timeouts, HOSTNAME,
+};
+
+use super::{
+    plain_text::{Links, PlainTextSite},
+    Protocol, ServerConfig, Shutdown,
+};
+
+const BIND_PORT: u16 = 1900;
+const MAX_REQUEST_LENGTH: u64 = 1024;
+
+const INDEX: &str = r#"matdoesdev
+
+I'm mat, I do full-stack software development.
+This portfolio contains my blog posts and links to some of the projects I've made.
+
+=> blog/ Blog
+=> projects Projects
+
+=> https://github.com/mat-1 GitHub
+=> https://matrix.to/#/@mat:matdoes.dev Matrix
+=> https://ko-fi.com/matdoesdev Ko-fi (donate)
+"#;
+
+pub struct Nex {
+    pub site: PlainTextSite,
+}
+
+impl Links for Nex {
+    fn address(path: &str) -> String {
+        format!("nex://{HOSTNAME}/{path}")
+    }
+
+    fn link_line(path: &str, text: &str) -> String {
+        format!("=> {} {text}", Self::address(path))
+    }
+}
+
+impl Protocol for Nex {
+    type Options = ();
+
+    fn generate(data: &SiteData, _: &Self::Options) -> Self {
+        Nex {
+            site: PlainTextSite::generate::<Nex>(data),
+        }
+    }
+
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        shutdown
+            .run(async move {
+                let nex = Arc::new(self);
+
+                let Some(listener) = Listener::bind("nex", port) else {
+                    return;
+                };
+
+                loop {
+                    let (stream, remote_addr) = listener.accept().await.unwrap();
+                    println!("started tcp connection for nex: {remote_addr:?}");
+
+                    let nex = Arc::clone(&nex);
+                    tokio::spawn(async move {
+                        let _session = lifecycle::Session::start("nex");
+                        if let Err(err) = handle(nex, stream, remote_addr).await {
+                            eprintln!("{:?}", err);
+                        }
+                    });
+                }
+            })
+            .await;
+    }
+}
+
+async fn handle(nex: Arc<Nex>, mut stream: TcpStream, remote_addr: SocketAddr) -> io::Result<()> {
+    let mut log = access_log::Entry::start("nex", remote_addr.ip());
+    let mut request = String::new();
+    let mut limited = BufReader::new(&mut stream).take(MAX_REQUEST_LENGTH);
+    timeout(timeouts::read_timeout(), limited.read_line(&mut request)).await??;
+    let path = request.trim();
+    println!("Nex request: {path}");
+    log.request(path);
+
+    let slug = path.strip_prefix('/').unwrap_or(path);
+    analytics::record(
+        "nex",
+        path,
+        nex.site.posts.contains_key(slug).then_some(slug),
+        remote_addr.ip(),
+    );
+
+    let response = match slug {
+        "" => INDEX,
+        // the blog is a directory so the clients know to look for links in it
+        "blog/" | "blog" => &nex.site.blog,
+        "projects" => &nex.site.projects,
+        _ => {
+            if let Some(post) = nex.site.posts.get(slug) {
+                post
+            } else if let Some(post) = slug
+                .strip_prefix("draft/")
+                .and_then(|token| nex.site.drafts.get(token))
+            {
+                post
+            } else {
+                "Not found\n"
+            }
+        }
+    };
+
+    let result = stream.write_all(response.as_bytes()).await;
+    let status = if response == "Not found\n" {
+        "not-found"
+    } else {
+        "ok"
+    };
+    log.finish(status, response.len() as u64);
+    result?;
+    stream.shutdown().await?;
+    Ok(())
+}
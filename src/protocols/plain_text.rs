@@ -0,0 +1,270 @@
+//! The site rendered as plain text, for the protocols that don't have any
+//! formatting of their own (finger, nex and scroll). They only differ in how
+//! they link to each other's pages.
+
+use std::{collections::HashMap, marker::PhantomData};
+
+use super::render::{self, Link, PostVisitor};
+use crate::{
+    crawl::{list_lines, ImageSource, ListItem, Post, PostSort, SiteData},
+    drafts,
+    links::{self, Href},
+    routes::Scheme,
+    table,
+};
+
+/// How a protocol refers to pages on the site.
+pub trait Links {
+    /// The address of a page, like `blog@matdoes.dev`. The path doesn't start
+    /// with a slash.
+    fn address(path: &str) -> String;
+
+    /// One or more lines that link to a page.
+    fn link_line(path: &str, text: &str) -> String {
+        format!("{text}\n{}", Self::address(path))
+    }
+}
+
+pub struct PlainTextSite {
+    pub blog: String,
+    /// The blog with the longest posts first.
+    pub blog_by_length: String,
+    pub projects: String,
+    pub posts: HashMap<String, String>,
+    /// Draft posts by their preview token.
+    pub drafts: HashMap<String, String>,
+}
+
+impl PlainTextSite {
+    pub fn generate<L: Links>(data: &SiteData) -> Self {
+        let mut posts = HashMap::new();
+        let mut drafts = HashMap::new();
+        for post in &data.blog {
+            let out = render_post::<L>(post);
+            if !post.draft {
+                posts.insert(post.slug.clone(), out);
+            } else if let Some(token) = drafts::preview_token(&post.slug) {
+                drafts.insert(token, out);
+            }
+        }
+
+        Self {
+            blog: render_blog::<L>(data, PostSort::Date),
+            blog_by_length: render_blog::<L>(data, PostSort::Length),
+            projects: render_projects::<L>(data),
+            posts,
+            drafts,
+        }
+    }
+}
+
+/// Links to pages on the website, for plain text that's read on its own
+/// instead of over one of the protocols.
+struct Web;
+
+impl Links for Web {
+    fn address(path: &str) -> String {
+        links::absolute(path)
+    }
+}
+
+/// A post as a text file that can be downloaded.
+pub fn raw_post(post: &Post) -> String {
+    render_post::<Web>(post)
+}
+
+fn render_blog<L: Links>(data: &SiteData, sort: PostSort) -> String {
+    let mut blog = String::new();
+    blog.push_str("# Blog\n\n");
+    for post in sort.sort(drafts::published(&data.blog)) {
+        let date = post.published.format("%Y-%m-%d").to_string();
+        blog.push_str(&L::link_line(
+            &post.slug,
+            &format!(
+                "{date} - {title} ({minutes} min read)",
+                title = post.title,
+                minutes = post.reading_minutes()
+            ),
+        ));
+        blog.push_str("\n\n");
+    }
+    blog
+}
+
+fn render_post<L: Links>(post: &Post) -> String {
+    let date = post.published.format("%Y-%m-%d").to_string();
+    let mut out = String::new();
+
+    out.push_str(&format!(
+        "# {title}\n{date}\n{minutes} min read, {words} words\n\n",
+        title = post.title,
+        minutes = post.reading_minutes(),
+        words = post.word_count
+    ));
+
+    render::visit_post(
+        &post.content,
+        &mut PlainTextPost::<L> {
+            out: &mut out,
+            links: PhantomData,
+        },
+    );
+    out
+}
+
+/// Writes a post's parts as markdown-ish plain text, with the links where they
+/// are.
+struct PlainTextPost<'a, L> {
+    out: &'a mut String,
+    links: PhantomData<L>,
+}
+
+impl<L: Links> PostVisitor for PlainTextPost<'_, L> {
+    fn text(&mut self, text: &str) {
+        self.out.push_str(text);
+    }
+
+    fn inline_code(&mut self, code: &str) {
+        self.out.push_str(&format!("`{code}`"));
+    }
+
+    fn code_block(&mut self, code: &str) {
+        self.out.push_str(&format!("\n```\n{code}\n```\n"));
+    }
+
+    fn italic(&mut self, text: &str) {
+        self.out.push_str(&format!("*{text}*"));
+    }
+
+    fn bold(&mut self, text: &str) {
+        self.out.push_str(&format!("**{text}**"));
+    }
+
+    fn image(&mut self, src: &ImageSource, alt: Option<&str>) {
+        let href = match src {
+            ImageSource::Local(path) => render::media_path(path),
+            ImageSource::Remote(url) => url.to_owned(),
+        };
+        let alt = alt.unwrap_or_default();
+        self.out.push_str(&format!("![{alt}]({href})"));
+    }
+
+    fn link(&mut self, text: &str, href: &str) {
+        let href = match Href::classify(href, Scheme::Http) {
+            Href::Internal(path) => L::address(path.trim_start_matches('/')),
+            href => href.into_href(),
+        };
+        self.out.push_str(&format!("[{text}]({href})"));
+    }
+
+    fn line_break(&mut self, _repeated: bool, _links: &[Link]) {
+        self.out.push('\n');
+    }
+
+    fn heading(&mut self, level: usize, text: &str, _after_line_break: bool) {
+        match level {
+            1 => self.out.push_str(&format!("\n# {text}\n")),
+            2 => self.out.push_str(&format!("\n## {text}\n")),
+            3 => self.out.push_str(&format!("\n### {text}\n")),
+            _ => self.out.push_str(&format!("\n{text}\n")),
+        }
+    }
+
+    fn quote(&mut self, text: &str) {
+        for line in text.lines() {
+            self.out.push_str(&format!("\n> {line}\n"));
+        }
+    }
+
+    fn footnote_reference(&mut self, label: &str) {
+        self.out.push_str(&format!("[{label}]"));
+    }
+
+    fn footnotes(&mut self, footnotes: &[(&str, &str)]) {
+        self.out.push_str("\n## Notes\n");
+        for (id, text) in footnotes {
+            self.out.push_str(&format!("\n[{id}] {text}\n"));
+        }
+    }
+
+    fn horizontal_rule(&mut self) {
+        self.out.push_str(&format!("\n{}\n", "-".repeat(40)));
+    }
+
+    fn definition_list(&mut self, definitions: &[(String, String)]) {
+        for (term, definition) in definitions {
+            self.out.push_str(&format!("\n{term}\n"));
+            for line in definition.lines() {
+                self.out.push_str(&format!("    {line}\n"));
+            }
+        }
+    }
+
+    fn caption(&mut self, text: &str) {
+        self.out.push_str(&format!("\n*{text}*\n"));
+    }
+
+    fn table(&mut self, rows: &[Vec<String>]) {
+        self.out.push('\n');
+        for line in table::render(rows, 80, &table::ASCII) {
+            self.out.push_str(&format!("{line}\n"));
+        }
+    }
+
+    fn list(&mut self, ordered: bool, items: &[ListItem], _after_line_break: bool) {
+        if !self.out.ends_with('\n') {
+            self.out.push('\n');
+        }
+        for line in list_lines(ordered, items) {
+            let marker = match line.number {
+                Some(number) => format!("{number}."),
+                None => "*".to_owned(),
+            };
+            self.out.push_str(&format!(
+                "{}{marker} {}\n",
+                "  ".repeat(line.depth),
+                line.text
+            ));
+        }
+    }
+}
+
+fn render_projects<L: Links>(data: &SiteData) -> String {
+    let mut out = String::new();
+    out.push_str("# Projects\n\n");
+    for project in &data.projects {
+        let name = &project.name;
+        let description = &project.description;
+        out.push_str(&format!("## {name}\n{description}\n"));
+
+        // only include the link if it's different from the source
+        if project.href != project.source {
+            if let Some(href) = &project.href {
+                if let Some(href) = href.strip_prefix('/') {
+                    out.push_str(&format!("{}\n", L::address(href)));
+                } else {
+                    out.push_str(&format!("{href}\n"));
+                }
+            }
+        }
+
+        let languages = project
+            .languages
+            .iter()
+            .map(|l| l.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        if let Some(source) = &project.source {
+            if project.languages.is_empty() {
+                out.push_str(&format!("Source code: {source}\n"));
+            } else {
+                out.push_str(&format!("Source code ({languages}): {source}\n"));
+            }
+        } else if !project.languages.is_empty() {
+            out.push_str(&format!("Languages: {languages}\n"))
+        }
+
+        out.push('\n');
+    }
+    out
+}
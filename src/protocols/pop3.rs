@@ -0,0 +1,305 @@
+//! POP3 (RFC 1939) with the same messages as IMAP. Any username and password
+//! are accepted, and deleting messages only lasts until the connection is
+//! closed.
+
+use std::{collections::BTreeSet, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::bail;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{analytics, crawl::SiteData, lifecycle, listen::Listener, timeouts, tls, HOSTNAME};
+
+use super::{
+    mail_render::{self, Message},
+    Protocol, ServerConfig, Shutdown,
+};
+
+const BIND_PORT: u16 = {
+    #[cfg(debug_assertions)]
+    {
+        1110
+    }
+    #[cfg(not(debug_assertions))]
+    110
+};
+const TLS_BIND_PORT: u16 = {
+    #[cfg(debug_assertions)]
+    {
+        1995
+    }
+    #[cfg(not(debug_assertions))]
+    995
+};
+
+/// The RFC says commands and their arguments can't be longer than this.
+const MAX_COMMAND_LENGTH: u64 = 255;
+/// RFC 1939 says idle clients can't be logged out before 10 minutes.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+const CAPABILITIES: &str = "USER\r\nUIDL\r\nTOP\r\n";
+
+pub struct Pop3 {
+    /// Oldest first, like in IMAP.
+    pub messages: Vec<Message>,
+}
+
+impl Protocol for Pop3 {
+    type Options = ();
+
+    fn generate(data: &SiteData, _: &Self::Options) -> Self {
+        Pop3 {
+            messages: mail_render::messages(data),
+        }
+    }
+
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        let tls_port = config.tls_port_or(TLS_BIND_PORT);
+        shutdown
+            .run(async move {
+                let pop3 = Arc::new(self);
+
+                tokio::join!(
+                    listen(Arc::clone(&pop3), port, None),
+                    listen(pop3, tls_port, Some(tls::acceptor()))
+                );
+            })
+            .await;
+    }
+}
+
+/// Start a tcp server, with tls if there's an acceptor.
+async fn listen(pop3: Arc<Pop3>, port: u16, acceptor: Option<TlsAcceptor>) {
+    let Some(listener) = Listener::bind("pop3", port) else {
+        return;
+    };
+
+    loop {
+        let (stream, remote_addr) = listener.accept().await.unwrap();
+        println!("started tcp connection for pop3: {remote_addr:?}");
+
+        let pop3 = Arc::clone(&pop3);
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            let _session = lifecycle::Session::start("pop3");
+            let result = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => handle(pop3, stream, remote_addr).await,
+                    Err(e) => Err(e.into()),
+                },
+                None => handle(pop3, stream, remote_addr).await,
+            };
+            if let Err(e) = result {
+                eprintln!("{:?}", e);
+            }
+        });
+    }
+}
+
+#[derive(PartialEq)]
+enum State {
+    /// Waiting for USER, or for PASS if `user` is true.
+    Authorization {
+        user: bool,
+    },
+    Transaction,
+    /// QUIT was sent, so the connection should be closed.
+    Update,
+}
+
+struct Session {
+    state: State,
+    /// The indexes of the messages that were deleted with DELE, which are
+    /// hidden until RSET.
+    deleted: BTreeSet<usize>,
+}
+
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+    pop3: Arc<Pop3>,
+    stream: S,
+    remote_addr: SocketAddr,
+) -> anyhow::Result<()> {
+    let (read, mut write) = tokio::io::split(stream);
+    let mut read = BufReader::new(read);
+
+    write
+        .write_all(format!("+OK {HOSTNAME} POP3 ready\r\n").as_bytes())
+        .await?;
+
+    let mut session = Session {
+        state: State::Authorization { user: false },
+        deleted: BTreeSet::new(),
+    };
+    while session.state != State::Update {
+        // clients can take their time between commands, but each command has
+        // to arrive within the read timeout once it starts
+        if timeout(IDLE_TIMEOUT, read.fill_buf()).await??.is_empty() {
+            break;
+        }
+        let mut line = String::new();
+        let mut limited = (&mut read).take(MAX_COMMAND_LENGTH);
+        timeout(timeouts::read_timeout(), limited.read_line(&mut line)).await??;
+        if !line.ends_with('\n') {
+            bail!("command is too long");
+        }
+        let response = respond(&pop3, &mut session, line.trim_end(), remote_addr);
+        write.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn respond(pop3: &Pop3, session: &mut Session, line: &str, remote_addr: SocketAddr) -> String {
+    let mut words = line.split(' ');
+    let command = words.next().unwrap_or_default().to_ascii_uppercase();
+    let args = words.collect::<Vec<_>>();
+
+    match (command.as_str(), args.as_slice()) {
+        ("QUIT", []) => {
+            // this is where deleted messages would actually be deleted
+            session.state = State::Update;
+            return "+OK Bye\r\n".to_owned();
+        }
+        ("CAPA", []) => return format!("+OK Capabilities follow\r\n{CAPABILITIES}.\r\n"),
+        ("NOOP", []) if session.state == State::Transaction => return "+OK\r\n".to_owned(),
+        _ => {}
+    }
+
+    if let State::Authorization { user } = session.state {
+        return match (command.as_str(), args.as_slice()) {
+            ("USER", [_]) => {
+                session.state = State::Authorization { user: true };
+                "+OK Any password works\r\n".to_owned()
+            }
+            // the password can have spaces in it
+            ("PASS", [_, ..]) if user => {
+                session.state = State::Transaction;
+                let (count, size) = pop3.stat(session);
+                format!("+OK {count} messages ({size} octets)\r\n")
+            }
+            ("PASS", _) => "-ERR Send USER first\r\n".to_owned(),
+            ("APOP", _) => "-ERR Use USER and PASS\r\n".to_owned(),
+            _ => "-ERR Log in first\r\n".to_owned(),
+        };
+    }
+
+    match (command.as_str(), args.as_slice()) {
+        ("STAT", []) => {
+            let (count, size) = pop3.stat(session);
+            format!("+OK {count} {size}\r\n")
+        }
+        ("LIST", []) => {
+            let (count, size) = pop3.stat(session);
+            let listing = pop3
+                .visible(session)
+                .map(|(number, message)| format!("{number} {}\n", message.size()))
+                .collect::<String>();
+            multi_line(&format!("{count} messages ({size} octets)"), &listing)
+        }
+        ("LIST", [number]) => match pop3.message(session, number) {
+            Ok(message) => format!("+OK {number} {}\r\n", message.size()),
+            Err(err) => err,
+        },
+        ("UIDL", []) => {
+            let listing = pop3
+                .visible(session)
+                .map(|(number, message)| format!("{number} {}\n", message.uidl()))
+                .collect::<String>();
+            multi_line("", &listing)
+        }
+        ("UIDL", [number]) => match pop3.message(session, number) {
+            Ok(message) => format!("+OK {number} {}\r\n", message.uidl()),
+            Err(err) => err,
+        },
+        ("RETR", [number]) => match pop3.message(session, number) {
+            Ok(message) => {
+                analytics::record("pop3", &message.slug, Some(&message.slug), remote_addr.ip());
+                multi_line(&format!("{} octets", message.size()), &message.raw())
+            }
+            Err(err) => err,
+        },
+        ("TOP", [number, lines]) => {
+            let Ok(lines) = lines.parse::<usize>() else {
+                return "-ERR Invalid number of lines\r\n".to_owned();
+            };
+            match pop3.message(session, number) {
+                Ok(message) => {
+                    let mut top = message.header.clone();
+                    for line in message.text.lines().take(lines) {
+                        top.push_str(line);
+                        top.push('\n');
+                    }
+                    multi_line("", &top)
+                }
+                Err(err) => err,
+            }
+        }
+        ("DELE", [number]) => match pop3.message(session, number) {
+            Ok(_) => {
+                // it parsed in pop3.message so it can't fail here
+                let number = number.parse::<usize>().unwrap();
+                session.deleted.insert(number - 1);
+                format!("+OK Message {number} deleted\r\n")
+            }
+            Err(err) => err,
+        },
+        ("RSET", []) => {
+            session.deleted.clear();
+            "+OK\r\n".to_owned()
+        }
+        _ => "-ERR Unknown command\r\n".to_owned(),
+    }
+}
+
+impl Pop3 {
+    /// The messages that haven't been deleted, and their numbers (which start
+    /// at 1).
+    fn visible<'a>(&'a self, session: &'a Session) -> impl Iterator<Item = (usize, &'a Message)> {
+        self.messages
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !session.deleted.contains(i))
+            .map(|(i, message)| (i + 1, message))
+    }
+
+    /// How many messages there are and their total size.
+    fn stat(&self, session: &Session) -> (usize, usize) {
+        self.visible(session)
+            .fold((0, 0), |(count, size), (_, message)| {
+                (count + 1, size + message.size())
+            })
+    }
+
+    /// The message with this number, or the error to respond with.
+    fn message(&self, session: &Session, number: &str) -> Result<&Message, String> {
+        let Ok(number) = number.parse::<usize>() else {
+            return Err("-ERR Invalid message number\r\n".to_owned());
+        };
+        let index = number.wrapping_sub(1);
+        if session.deleted.contains(&index) {
+            return Err(format!("-ERR Message {number} was deleted\r\n"));
+        }
+        self.messages
+            .get(index)
+            .ok_or(format!("-ERR No message {number}\r\n"))
+    }
+}
+
+/// `+OK` and a status, then the lines of the text ending with a line that only
+/// has a dot. Lines in the text that start with a dot get another one so they
+/// aren't mistaken for the end.
+fn multi_line(status: &str, text: &str) -> String {
+    let mut out = format!("+OK {status}\r\n");
+    for line in text.lines() {
+        if line.starts_with('.') {
+            out.push('.');
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+    out.push_str(".\r\n");
+    out
+}
@@ -1,21 +1,34 @@
+//! The quote of the day (RFC 865). Quotes are kept in `data/qotd/quotes/`
+//! with one file each, and a different one is picked every day at midnight
+//! UTC. The quote that was used on each day is saved so old ones can be
+//! looked up.
+
 use std::{
-    collections::VecDeque,
+    collections::BTreeMap,
     fs, io,
-    sync::Arc,
-    time::{Duration, Instant},
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, LazyLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use anyhow::bail;
+use chrono::{Datelike, Days, NaiveDate, Utc};
+use futures_util::future::join_all;
+use hmac::{Hmac, Mac};
 use parking_lot::RwLock;
-use tokio::{
-    io::AsyncWriteExt,
-    net::{TcpListener, UdpSocket},
-    time::sleep,
-};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::{io::AsyncWriteExt, net::UdpSocket, sync::watch, time::sleep};
 
-use super::Protocol;
-use crate::crawl::SiteData;
+use super::{mqtt, Protocol, ServerConfig, Shutdown};
+use crate::{
+    analytics,
+    crawl::SiteData,
+    lifecycle,
+    listen::{self, Listener},
+};
 
-const BIND_HOST: &str = "[::]";
 const BIND_PORT: u16 = {
     #[cfg(debug_assertions)]
     {
@@ -25,110 +38,352 @@ const BIND_PORT: u16 = {
     17
 };
 
+/// How long a udp cookie works for. Cookies from the period before are also
+/// accepted, so one doesn't stop working right after it's sent.
+const COOKIE_PERIOD: Duration = Duration::from_secs(2 * 60);
+/// The most that's read from a udp request, which is plenty for a cookie.
+const MAX_UDP_REQUEST_LENGTH: usize = 64;
+
+/// What udp cookies are signed with. It's different every time we start,
+/// which just means old cookies stop working after a restart.
+static COOKIE_SECRET: LazyLock<[u8; 32]> = LazyLock::new(rand::random);
+
+pub const MAX_QUOTE_LENGTH: usize = 400;
+
+static QUOTES: LazyLock<RwLock<BTreeMap<u64, String>>> = LazyLock::new(|| {
+    let mut quotes = BTreeMap::new();
+    for entry in fs::read_dir(QUOTES_PATH).into_iter().flatten().flatten() {
+        let path = entry.path();
+        let Some(id) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        if let Ok(quote) = fs::read_to_string(&path) {
+            quotes.insert(id, quote.trim().to_owned());
+        }
+    }
+    RwLock::new(quotes)
+});
+/// The quote that was used on each day.
+static HISTORY: LazyLock<RwLock<BTreeMap<NaiveDate, String>>> = LazyLock::new(|| {
+    let history = fs::read_to_string(HISTORY_PATH)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default();
+    RwLock::new(history)
+});
+
 #[derive(Clone)]
 pub struct Qotd {
     pub message: Arc<RwLock<Vec<u8>>>,
+    /// Told whenever the message changes, so connections that are showing it
+    /// can redraw.
+    changed: Arc<watch::Sender<()>>,
 }
 
+/// An empty message, for servers that haven't been given the real one.
+impl Default for Qotd {
+    fn default() -> Self {
+        Qotd::new(Vec::new())
+    }
+}
+
+/// The current message, which is only read at startup if there aren't any
+/// quotes to pick from.
 pub const QOTD_MESSAGE_PATH: &str = "data/qotd/message.txt";
+pub const QUOTES_PATH: &str = "data/qotd/quotes";
+pub const HISTORY_PATH: &str = "data/qotd/history.json";
+
+#[derive(Serialize)]
+pub struct Quote {
+    pub id: u64,
+    pub text: String,
+}
 
 impl Protocol for Qotd {
-    fn generate(_: &SiteData) -> Self {
+    /// A quote of the day that's already being shown by other servers, so
+    /// they all change together. A new one is made if this isn't set.
+    type Options = Option<Qotd>;
+
+    fn generate(_: &SiteData, shared: &Self::Options) -> Self {
+        if let Some(qotd) = shared {
+            return qotd.clone();
+        }
+
         // read message from file
         let message = fs::read(QOTD_MESSAGE_PATH).unwrap_or_default();
 
+        let qotd = Qotd::new(message);
+        qotd.rotate();
+        qotd
+    }
+
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        shutdown
+            .run(async move {
+                // qotd runs on tcp and udp. they're all in one future instead of being
+                // spawned so that they stop when it's dropped
+                let qotd = Arc::new(self);
+
+                tokio::join!(
+                    rotate_daily(Arc::clone(&qotd)),
+                    serve_tcp(Arc::clone(&qotd), port),
+                    serve_udp(qotd, port)
+                );
+            })
+            .await;
+    }
+}
+
+async fn rotate_daily(qotd: Arc<Qotd>) {
+    loop {
+        sleep(until_midnight()).await;
+        qotd.rotate();
+    }
+}
+
+async fn serve_tcp(qotd: Arc<Qotd>, port: u16) {
+    let Some(tcp_listener) = Listener::bind("qotd", port) else {
+        return;
+    };
+
+    loop {
+        let (mut stream, remote_addr) = tcp_listener.accept().await.unwrap();
+        println!("started tcp connection for qotd: {remote_addr:?}");
+        analytics::record("qotd", "", None, remote_addr.ip());
+
+        let qotd = Arc::clone(&qotd);
+        let fut = async move {
+            stream.set_nodelay(true)?;
+            let response = qotd.message.read().to_vec();
+            stream.write_all(&response).await?;
+            stream.shutdown().await?;
+
+            sleep(Duration::from_millis(200)).await;
+            stream.set_linger(Some(Duration::from_millis(0)))?;
+
+            Ok(()) as io::Result<()>
+        };
+
+        tokio::spawn(async move {
+            let _session = lifecycle::Session::start("qotd");
+            if let Err(err) = fut.await {
+                eprintln!("{:?}", err);
+            }
+        });
+    }
+}
+
+async fn serve_udp(qotd: Arc<Qotd>, port: u16) {
+    let sockets = listen::bind_udp("qotd", port);
+    join_all(
+        sockets
+            .into_iter()
+            .map(|socket| serve_udp_socket(Arc::clone(&qotd), socket)),
+    )
+    .await;
+}
+
+async fn serve_udp_socket(qotd: Arc<Qotd>, udp_listener: UdpSocket) {
+    let mut buf = [0u8; MAX_UDP_REQUEST_LENGTH];
+    loop {
+        let Ok((len, remote_addr)) = udp_listener.recv_from(&mut buf).await else {
+            continue;
+        };
+        println!("received udp request for qotd: {remote_addr:?}");
+
+        // the source address of a udp packet can be spoofed, so to avoid
+        // being a ddos amplification vector we only send a short cookie until
+        // the client proves it's really at that address by sending the cookie
+        // back. sorry haylin.
+        let request = String::from_utf8_lossy(&buf[..len]);
+        let response = if is_valid_cookie(request.trim(), remote_addr.ip()) {
+            analytics::record("qotd", "", None, remote_addr.ip());
+            qotd.message.read().to_vec()
+        } else {
+            format!("{}\n", cookie(remote_addr.ip(), cookie_period())).into_bytes()
+        };
+        let _ = udp_listener.send_to(&response, remote_addr).await;
+    }
+}
+
+impl Qotd {
+    pub fn new(message: Vec<u8>) -> Self {
         Qotd {
             message: Arc::new(RwLock::new(message)),
+            changed: Arc::new(watch::channel(()).0),
         }
     }
 
-    async fn serve(self) {
-        // qotd runs on tcp and udp
+    /// Notified every time the message changes.
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
 
-        let qotd = Arc::new(self);
+    /// Change the quote for today. Everything that shares this [`Qotd`] sees
+    /// the new one.
+    pub fn set(&self, quote: &str) {
+        let quote = quote.trim();
+        println!("changing qotd to \"{quote}\"");
+        let message = message(quote);
 
-        let tcp_listener = match TcpListener::bind(format!("{BIND_HOST}:{BIND_PORT}")).await {
-            Ok(listener) => listener,
-            Err(e) => {
-                eprintln!("failed to bind to port {BIND_PORT}: {e}");
-                return;
-            }
+        let mut history = HISTORY.write();
+        history.insert(today(), quote.to_owned());
+        let write = || -> anyhow::Result<()> {
+            fs::create_dir_all(Path::new(HISTORY_PATH).parent().unwrap())?;
+            fs::write(HISTORY_PATH, serde_json::to_string_pretty(&*history)?)?;
+            fs::write(QOTD_MESSAGE_PATH, &message)?;
+            Ok(())
         };
-
-        {
-            let qotd = Arc::clone(&qotd);
-            tokio::spawn(async move {
-                loop {
-                    let (mut stream, remote_addr) = tcp_listener.accept().await.unwrap();
-                    println!("started tcp connection for qotd: {remote_addr:?}");
-
-                    let qotd = Arc::clone(&qotd);
-                    let fut = async move {
-                        stream.set_nodelay(true)?;
-                        let response = qotd.message.read().to_vec();
-                        stream.write_all(&response).await?;
-                        stream.shutdown().await?;
-
-                        sleep(Duration::from_millis(200)).await;
-                        stream.set_linger(Some(Duration::from_millis(0)))?;
-
-                        Ok(()) as io::Result<()>
-                    };
-
-                    tokio::spawn(async move {
-                        if let Err(err) = fut.await {
-                            eprintln!("{:?}", err);
-                        }
-                    });
-                }
-            });
+        if let Err(err) = write() {
+            eprintln!("failed to save qotd: {err}");
         }
 
-        let mut udp_request_timestamps = VecDeque::<Instant>::new();
+        mqtt::publish(mqtt::QOTD_TOPIC, message.clone());
+        *self.message.write() = message;
+        self.changed.send_replace(());
+    }
 
-        let udp_listener = match UdpSocket::bind(format!("{BIND_HOST}:{BIND_PORT}")).await {
-            Ok(listener) => listener,
-            Err(e) => {
-                eprintln!("failed to bind to udp port {BIND_PORT}: {e}");
-                return;
-            }
+    /// Switch to today's quote. If one was already picked (or set by hand)
+    /// today then it's kept, and if there aren't any quotes then the message
+    /// stays the same.
+    pub fn rotate(&self) {
+        let today = today();
+        let quote = HISTORY.read().get(&today).cloned();
+        if let Some(quote) = quote.or_else(|| scheduled(today)) {
+            self.set(&quote);
+        }
+    }
+
+    /// Use the message in [`QOTD_MESSAGE_PATH`] after it was edited by hand.
+    pub fn reload(&self) {
+        let Ok(message) = fs::read(QOTD_MESSAGE_PATH) else {
+            return;
         };
-        let mut buf = [0u8; 0];
-        let mut ratelimited_until = None;
-        loop {
-            if let Ok((_, remote_addr)) = udp_listener.recv_from(&mut buf).await {
-                if let Some(ratelimited_until_time) = ratelimited_until {
-                    if Instant::now() < ratelimited_until_time {
-                        continue;
-                    }
-                    ratelimited_until = None;
-
-                    while udp_request_timestamps.len() > 120 {
-                        let _ = udp_request_timestamps.pop_front();
-                    }
-                }
-
-                println!("received udp request for qotd: {remote_addr:?}");
-
-                // if there's more than 120 requests in the past 60 seconds, wait until the
-                // oldest request is older than 60 seconds.
-                // this is to prevent us from becoming a ddos amplification vector.
-                // sorry haylin.
-                if udp_request_timestamps.len() > 120 {
-                    let oldest = udp_request_timestamps.pop_front().unwrap();
-                    let window = Duration::from_secs(60);
-                    let elapsed = oldest.elapsed();
-                    if elapsed < window {
-                        println!("ratelimting qotd udp request from {remote_addr:?}");
-                        ratelimited_until = Some(oldest + window);
-                        continue;
-                    }
-                }
-                udp_request_timestamps.push_back(Instant::now());
-
-                let response = qotd.message.read().to_vec();
-                let _ = udp_listener.send_to(&response, remote_addr).await;
-            }
+        // this also happens after `set` writes it
+        if *self.message.read() == message {
+            return;
         }
+        println!("qotd message was changed on disk");
+        mqtt::publish(mqtt::QOTD_TOPIC, message.clone());
+        *self.message.write() = message;
+        self.changed.send_replace(());
+    }
+}
+
+/// The full message for a quote, like it's sent over tcp.
+pub fn message(quote: &str) -> Vec<u8> {
+    format!("Quote of the day:\n{quote}\n").into_bytes()
+}
+
+fn today() -> NaiveDate {
+    Utc::now().date_naive()
+}
+
+fn until_midnight() -> Duration {
+    let now = Utc::now();
+    let midnight = (now.date_naive() + Days::new(1))
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc();
+    (midnight - now).to_std().unwrap_or_default()
+}
+
+/// The quote that would be picked for a day, which goes through every quote
+/// in order and then starts over.
+fn scheduled(date: NaiveDate) -> Option<String> {
+    let quotes = QUOTES.read();
+    if quotes.is_empty() {
+        return None;
     }
+    let index = date.num_days_from_ce() as usize % quotes.len();
+    quotes.values().nth(index).cloned()
+}
+
+/// The quote that was used on a day, if we know.
+pub fn on(date: NaiveDate) -> Option<String> {
+    HISTORY.read().get(&date).cloned()
+}
+
+/// Every day that had a quote, newest first.
+pub fn history() -> Vec<(NaiveDate, String)> {
+    HISTORY
+        .read()
+        .iter()
+        .rev()
+        .map(|(date, quote)| (*date, quote.clone()))
+        .collect()
+}
+
+pub fn quotes() -> Vec<Quote> {
+    QUOTES
+        .read()
+        .iter()
+        .map(|(id, text)| Quote {
+            id: *id,
+            text: text.clone(),
+        })
+        .collect()
+}
+
+/// Add a quote to the ones that get picked from, and return its id.
+pub fn add_quote(text: &str) -> anyhow::Result<u64> {
+    let text = text.trim();
+    if text.is_empty() {
+        bail!("Quote is empty");
+    }
+    if text.len() > MAX_QUOTE_LENGTH {
+        bail!("Quote is longer than {MAX_QUOTE_LENGTH} bytes");
+    }
+
+    let mut quotes = QUOTES.write();
+    let id = quotes
+        .keys()
+        .next_back()
+        .map(|id| id + 1)
+        .unwrap_or_default();
+    fs::create_dir_all(QUOTES_PATH)?;
+    fs::write(format!("{QUOTES_PATH}/{id}.txt"), text)?;
+    quotes.insert(id, text.to_owned());
+    Ok(id)
+}
+
+/// Returns false if there's no quote with that id. Days that already used the
+/// quote keep it in their history.
+pub fn delete_quote(id: u64) -> bool {
+    let mut quotes = QUOTES.write();
+    if quotes.remove(&id).is_none() {
+        return false;
+    }
+    if let Err(err) = fs::remove_file(format!("{QUOTES_PATH}/{id}.txt")) {
+        eprintln!("failed to delete quote {id}: {err}");
+    }
+    true
+}
+
+/// The number of the current [`COOKIE_PERIOD`] since the unix epoch.
+fn cookie_period() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / COOKIE_PERIOD.as_secs()
+}
+
+/// A short token that only works for this address during this period.
+fn cookie(ip: IpAddr, period: u64) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&*COOKIE_SECRET).unwrap();
+    mac.update(ip.to_canonical().to_string().as_bytes());
+    mac.update(&period.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    hash[..8].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn is_valid_cookie(request: &str, ip: IpAddr) -> bool {
+    let period = cookie_period();
+    request == cookie(ip, period) || request == cookie(ip, period.saturating_sub(1))
 }
@@ -0,0 +1,354 @@
+//! Rendering the site in different formats. [`PostVisitor`] is how each
+//! protocol turns a post's parts into its own format, and [`Pages`] has the
+//! pages in every format that the HTTP server can send, for clients that pick
+//! one with the `Accept` header. The gemtext is the same as the Gemini
+//! server's and the plain text is the same as finger's, and the HTML is made
+//! from the gemtext.
+
+use std::{collections::HashMap, path::Path};
+
+use super::{finger::Finger, gemini::Gemini};
+use crate::{
+    crawl::{ImageSource, ListItem, PostPart, SiteData},
+    links,
+    locale::Locale,
+    routes::Scheme,
+};
+
+/// A link that was taken out of its paragraph, for formats where links have to
+/// be on their own line.
+pub struct Link {
+    pub text: String,
+    pub href: String,
+}
+
+/// The formatting for each kind of [`PostPart`]. [`visit_post`] walks the
+/// parts and calls these, and it's the one that decides when links are queued
+/// and when line breaks are collapsed, so every protocol does it the same way.
+pub trait PostVisitor {
+    /// Whether links are saved up and given to [`PostVisitor::line_break`] at
+    /// the end of their paragraph instead of going to [`PostVisitor::link`].
+    /// The link's text is left in the paragraph unless the link is the whole
+    /// paragraph.
+    const QUEUE_LINKS: bool = false;
+
+    fn text(&mut self, text: &str);
+    fn inline_code(&mut self, code: &str);
+    fn code_block(&mut self, code: &str);
+    fn italic(&mut self, text: &str);
+    fn bold(&mut self, text: &str);
+    fn image(&mut self, src: &ImageSource, alt: Option<&str>);
+    /// Only called when links aren't queued.
+    fn link(&mut self, text: &str, _href: &str) {
+        self.text(text);
+    }
+    /// `repeated` is whether the part before was also a line break, and
+    /// `links` are the ones that were queued since the last line break.
+    fn line_break(&mut self, repeated: bool, links: &[Link]);
+    /// `after_line_break` is whether it's at the start of a paragraph.
+    fn heading(&mut self, level: usize, text: &str, after_line_break: bool);
+    fn quote(&mut self, text: &str);
+    fn footnote_reference(&mut self, label: &str);
+    fn horizontal_rule(&mut self);
+    fn definition_list(&mut self, definitions: &[(String, String)]);
+    fn caption(&mut self, text: &str);
+    fn table(&mut self, rows: &[Vec<String>]);
+    /// `after_line_break` is whether it's at the start of a paragraph.
+    fn list(&mut self, ordered: bool, items: &[ListItem], after_line_break: bool);
+    /// After the last part, with the links that were queued since the last
+    /// line break.
+    fn end(&mut self, _links: &[Link]) {}
+    /// The post's footnotes as their ids and text, after everything else.
+    /// Only called if there are any.
+    fn footnotes(&mut self, footnotes: &[(&str, &str)]);
+}
+
+/// Give every part of the post to the visitor.
+pub fn visit_post<V: PostVisitor>(parts: &[PostPart], visitor: &mut V) {
+    let is_line_break = |i: Option<usize>| {
+        i.and_then(|i| parts.get(i))
+            .is_none_or(|part| matches!(part, PostPart::LineBreak))
+    };
+
+    let mut queued_links = Vec::new();
+    let mut footnotes = Vec::new();
+    let mut last_was_line_break = false;
+    for (i, part) in parts.iter().enumerate() {
+        match part {
+            PostPart::Text(text) => visitor.text(text),
+            PostPart::InlineCode(code) => visitor.inline_code(code),
+            PostPart::CodeBlock(code) => visitor.code_block(code),
+            PostPart::Italic(text) => visitor.italic(text),
+            PostPart::Bold(text) => visitor.bold(text),
+            PostPart::Image { src, alt } => visitor.image(src, alt.as_deref()),
+            PostPart::Link { text, href } if V::QUEUE_LINKS => {
+                queued_links.push(Link {
+                    text: text.to_owned(),
+                    // the formats that queue links are the ones that are
+                    // read with gemini clients
+                    href: links::rewrite(href, Scheme::Gemini),
+                });
+                // a link that's the whole paragraph is only written once, and
+                // the paragraph is empty without it
+                if is_line_break(i.checked_sub(1)) && is_line_break(Some(i + 1)) {
+                    continue;
+                }
+                visitor.text(text);
+            }
+            PostPart::Link { text, href } => visitor.link(text, href),
+            PostPart::LineBreak => {
+                visitor.line_break(last_was_line_break, &queued_links);
+                queued_links.clear();
+                last_was_line_break = true;
+                continue;
+            }
+            PostPart::Heading { level, text } => visitor.heading(*level, text, last_was_line_break),
+            PostPart::Quote(text) => visitor.quote(text),
+            PostPart::FootnoteReference(label) => visitor.footnote_reference(label),
+            PostPart::Footnote { id, text } => {
+                // they go at the end even if the post has them somewhere else
+                footnotes.push((id.as_str(), text.as_str()));
+                continue;
+            }
+            PostPart::HorizontalRule => visitor.horizontal_rule(),
+            PostPart::DefinitionList(definitions) => visitor.definition_list(definitions),
+            PostPart::Caption(text) => visitor.caption(text),
+            PostPart::Table(rows) => visitor.table(rows),
+            PostPart::List { ordered, items } => visitor.list(*ordered, items, last_was_line_break),
+        }
+        last_was_line_break = false;
+    }
+    visitor.end(&queued_links);
+    if !footnotes.is_empty() {
+        visitor.footnotes(&footnotes);
+    }
+}
+
+/// The path of a local image relative to the media directory, which is how
+/// it's linked to.
+pub fn media_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    path.strip_prefix("media").unwrap_or(&path).to_owned()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Gemtext,
+    PlainText,
+    Html,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Gemtext => "text/gemini; charset=utf-8",
+            Format::PlainText => "text/plain; charset=utf-8",
+            Format::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Format> {
+        match media_type {
+            "text/gemini" => Some(Format::Gemtext),
+            "text/plain" => Some(Format::PlainText),
+            "text/html" | "text/*" | "*/*" => Some(Format::Html),
+            _ => None,
+        }
+    }
+
+    /// The format the client likes the most, from an `Accept` header like
+    /// `text/gemini, text/html;q=0.5`. Ties go to the one that comes first,
+    /// and it's HTML if none of them are ones we have.
+    pub fn from_accept(header: &str) -> Format {
+        let mut best = None;
+        for item in header.split(',') {
+            let mut params = item.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.);
+            let Some(format) = Format::from_media_type(&media_type) else {
+                continue;
+            };
+            if quality > 0. && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                best = Some((format, quality));
+            }
+        }
+        best.map_or(Format::Html, |(format, _)| format)
+    }
+}
+
+/// One page in the formats that are kept. HTML is made when it's asked for.
+#[derive(Clone)]
+pub struct Page {
+    pub gemtext: String,
+    pub plain_text: String,
+}
+
+impl Page {
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Gemtext => self.gemtext.clone(),
+            Format::PlainText => self.plain_text.clone(),
+            Format::Html => gemtext_to_html(&self.gemtext),
+        }
+    }
+}
+
+/// The pages by their path, like `/blog` or `/hello-world`.
+#[derive(Default, Clone)]
+pub struct Pages {
+    pages: HashMap<String, Page>,
+}
+
+impl Pages {
+    /// Generate the Gemini and finger sites and keep the pages that they both
+    /// have.
+    pub fn generate(data: &SiteData) -> Pages {
+        let gemini = <Gemini as super::Protocol>::generate(data, &Default::default());
+        let finger = <Finger as super::Protocol>::generate(data, &Default::default());
+        let locale = Locale::default();
+
+        let mut pages = HashMap::new();
+        let mut add = |path: String, gemtext: &str, plain_text: &str| {
+            pages.insert(
+                path,
+                Page {
+                    gemtext: gemtext.to_owned(),
+                    plain_text: plain_text.to_owned(),
+                },
+            );
+        };
+        add(
+            "/".to_owned(),
+            &gemini.index_gmi[&locale],
+            &finger.index_content,
+        );
+        add(
+            "/blog".to_owned(),
+            &gemini.blog_gmi[&locale],
+            &finger.blog_content,
+        );
+        add(
+            "/projects".to_owned(),
+            &gemini.projects_gmi,
+            &finger.projects_content,
+        );
+        for (slug, gemtext) in &gemini.posts_gmi {
+            if let Some(plain_text) = finger.posts_content.get(slug) {
+                add(format!("/{slug}"), gemtext, plain_text);
+            }
+        }
+        Pages { pages }
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Page> {
+        self.pages.get(path)
+    }
+}
+
+/// A whole HTML document for the gemtext, with the first heading as the
+/// title. Every gemtext line type has an HTML element that's about the same.
+pub fn gemtext_to_html(gemtext: &str) -> String {
+    let escape = |text: &str| html_escape::encode_text(text).into_owned();
+
+    let mut title = None;
+    let mut body = String::new();
+    let mut preformatted = false;
+    let mut in_list = false;
+    for line in gemtext.lines() {
+        if line.starts_with("```") {
+            // a list right before a code block ends there
+            if std::mem::take(&mut in_list) {
+                body.push_str("</ul>\n");
+            }
+            body.push_str(if preformatted { "</pre>\n" } else { "<pre>" });
+            preformatted = !preformatted;
+            continue;
+        }
+        if preformatted {
+            body.push_str(&format!("{}\n", escape(line)));
+            continue;
+        }
+
+        let is_list_item = line.starts_with("* ");
+        if in_list && !is_list_item {
+            body.push_str("</ul>\n");
+        } else if !in_list && is_list_item {
+            body.push_str("<ul>\n");
+        }
+        in_list = is_list_item;
+
+        if let Some(item) = line.strip_prefix("* ") {
+            body.push_str(&format!("<li>{}</li>\n", escape(item)));
+        } else if let Some(link) = line.strip_prefix("=>") {
+            let link = link.trim();
+            let (href, text) = link
+                .split_once(char::is_whitespace)
+                .map_or((link, link), |(href, text)| (href, text.trim()));
+            body.push_str(&format!(
+                "<p><a href=\"{}\">{}</a></p>\n",
+                html_escape::encode_double_quoted_attribute(href),
+                escape(text)
+            ));
+        } else if let Some(heading) = line.strip_prefix("###") {
+            body.push_str(&format!("<h3>{}</h3>\n", escape(heading.trim())));
+        } else if let Some(heading) = line.strip_prefix("##") {
+            body.push_str(&format!("<h2>{}</h2>\n", escape(heading.trim())));
+        } else if let Some(heading) = line.strip_prefix('#') {
+            let heading = heading.trim();
+            title.get_or_insert_with(|| heading.to_owned());
+            body.push_str(&format!("<h1>{}</h1>\n", escape(heading)));
+        } else if let Some(quote) = line.strip_prefix('>') {
+            body.push_str(&format!(
+                "<blockquote>{}</blockquote>\n",
+                escape(quote.trim())
+            ));
+        } else if !line.trim().is_empty() {
+            body.push_str(&format!("<p>{}</p>\n", escape(line)));
+        }
+    }
+    if preformatted {
+        body.push_str("</pre>\n");
+    }
+    if in_list {
+        body.push_str("</ul>\n");
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width\">\n\
+         <title>{}</title>\n</head>\n<body>\n{body}</body>\n</html>\n",
+        escape(title.as_deref().unwrap_or("matdoesdev"))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_formats_from_accept() {
+        assert_eq!(Format::from_accept(""), Format::Html);
+        assert_eq!(Format::from_accept("text/gemini"), Format::Gemtext);
+        assert_eq!(
+            Format::from_accept("text/html;q=0.5, text/plain"),
+            Format::PlainText
+        );
+        assert_eq!(
+            Format::from_accept("text/plain;q=0, application/json"),
+            Format::Html
+        );
+        assert_eq!(Format::from_accept("text/html, text/gemini"), Format::Html);
+    }
+
+    #[test]
+    fn gemtext_becomes_html() {
+        let html = gemtext_to_html("# Hi & bye\n=> /blog Blog\n* one\n* two\n```\n<x>\n```");
+        assert!(html.contains("<title>Hi &amp; bye</title>"));
+        assert!(html.contains("<p><a href=\"/blog\">Blog</a></p>"));
+        assert!(html.contains("<ul>\n<li>one</li>\n<li>two</li>\n</ul>"));
+        assert!(html.contains("<pre>&lt;x&gt;\n</pre>"));
+    }
+}
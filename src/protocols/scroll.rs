@@ -0,0 +1,158 @@
+//! Scroll is a lot like Gemini, but the request also has the languages that the
+//! client prefers, like `scroll://matdoes.dev/blog en\r\n`. We only have
+//! English so those are ignored.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_rustls::server::TlsStream;
+use url::Url;
+
+use crate::{
+    access_log, analytics, crawl::SiteData, hostnames, lifecycle, listen::Listener, timeouts, tls,
+    HOSTNAME,
+};
+
+use super::{
+    plain_text::{Links, PlainTextSite},
+    Protocol, ServerConfig, Shutdown,
+};
+
+const BIND_PORT: u16 = 5699;
+const MAX_REQUEST_LENGTH: u64 = 1024;
+
+const INDEX: &str = r#"# matdoesdev
+
+I'm mat, I do full-stack software development.
+This portfolio contains my blog posts and links to some of the projects I've made.
+
+=> /blog Blog
+=> /projects Projects
+
+=> https://github.com/mat-1 GitHub
+=> https://matrix.to/#/@mat:matdoes.dev Matrix
+=> https://ko-fi.com/matdoesdev Ko-fi (donate)
+"#;
+
+pub struct Scroll {
+    pub site: PlainTextSite,
+}
+
+impl Links for Scroll {
+    fn address(path: &str) -> String {
+        format!("scroll://{HOSTNAME}/{path}")
+    }
+
+    fn link_line(path: &str, text: &str) -> String {
+        format!("=> {} {text}", Self::address(path))
+    }
+}
+
+impl Protocol for Scroll {
+    type Options = ();
+
+    fn generate(data: &SiteData, _: &Self::Options) -> Self {
+        Scroll {
+            site: PlainTextSite::generate::<Scroll>(data),
+        }
+    }
+
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        shutdown
+            .run(async move {
+                let scroll = Arc::new(self);
+
+                let acceptor = tls::acceptor();
+                let Some(listener) = Listener::bind("scroll", port) else {
+                    return;
+                };
+
+                loop {
+                    let (stream, remote_addr) = listener.accept().await.unwrap();
+                    println!("started tcp connection for scroll: {remote_addr:?}");
+                    let acceptor = acceptor.clone();
+
+                    let scroll = Arc::clone(&scroll);
+                    let fut = async move {
+                        let mut stream = acceptor.accept(stream).await?;
+                        let mut log = access_log::Entry::start("scroll", remote_addr.ip());
+                        let response = respond(scroll, &mut stream, &mut log, remote_addr).await?;
+                        let result = stream.write_all(response.as_bytes()).await;
+                        log.finish(response.get(..2).unwrap_or_default(), response.len() as u64);
+                        result?;
+                        stream.shutdown().await?;
+                        Ok(()) as io::Result<()>
+                    };
+
+                    tokio::spawn(async move {
+                        let _session = lifecycle::Session::start("scroll");
+                        if let Err(err) = fut.await {
+                            eprintln!("{:?}", err);
+                        }
+                    });
+                }
+            })
+            .await;
+    }
+}
+
+async fn respond(
+    scroll: Arc<Scroll>,
+    stream: &mut TlsStream<TcpStream>,
+    log: &mut access_log::Entry,
+    remote_addr: SocketAddr,
+) -> io::Result<String> {
+    let mut request = String::new();
+    let mut limited = BufReader::new(stream).take(MAX_REQUEST_LENGTH);
+    timeout(timeouts::read_timeout(), limited.read_line(&mut request)).await??;
+    println!("Scroll request: {}", request.trim());
+    log.request(request.trim());
+
+    // the url and then the languages
+    let url = request.split_whitespace().next().unwrap_or_default();
+    let Ok(url) = Url::parse(url) else {
+        return Ok("59 Request is not a valid URL\r\n".to_owned());
+    };
+    if url.scheme() != "scroll" {
+        return Ok("53 Request is not a Scroll URL\r\n".to_owned());
+    }
+    if !url
+        .host_str()
+        .is_some_and(|host| hostnames().any(|h| h == host))
+    {
+        return Ok("53 Host doesn't match\r\n".to_owned());
+    }
+    if url.port().unwrap_or(BIND_PORT) != BIND_PORT {
+        return Ok("53 Port doesn't match\r\n".to_owned());
+    }
+
+    let slug = url.path().strip_prefix('/').unwrap_or(url.path());
+    analytics::record(
+        "scroll",
+        url.path(),
+        scroll.site.posts.contains_key(slug).then_some(slug),
+        remote_addr.ip(),
+    );
+
+    // the listings have links so they're scrolltext, but the rest is shown as-is
+    Ok(match slug {
+        "" => format!("20 text/scroll\r\n{INDEX}"),
+        "blog" => format!("20 text/scroll\r\n{}", scroll.site.blog),
+        "projects" => format!("20 text/plain\r\n{}", scroll.site.projects),
+        _ => {
+            let post = scroll.site.posts.get(slug).or_else(|| {
+                slug.strip_prefix("draft/")
+                    .and_then(|token| scroll.site.drafts.get(token))
+            });
+            match post {
+                Some(post) => format!("20 text/plain\r\n{post}"),
+                None => "51 Not found\r\n".to_owned(),
+            }
+        }
+    })
+}
@@ -1,36 +1,59 @@
+#[cfg(feature = "test-client")]
+#[cfg_attr(not(test), allow(dead_code))]
+pub mod client;
+mod compression;
+#[cfg(all(test, feature = "test-client"))]
+mod conformance;
 pub mod connection;
 mod crypto;
 mod protocol;
 
-use std::io::Cursor;
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use aes::{
     cipher::{IvSizeUser, KeySizeUser},
     Aes128,
 };
 use anyhow::bail;
+use chrono::{DateTime, Utc};
 use ctr::Ctr128BE;
 use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
+use ed25519_dalek::SigningKey;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener,
+        TcpStream,
     },
+    sync::mpsc,
 };
 
 use crate::{
     crawl::SiteData,
+    lifecycle,
+    listen::Listener,
+    locale::Locale,
+    motd,
     protocols::ssh::{
-        connection::{Channel, EncryptedConnection, ReadConnection},
+        compression::Decompressor,
+        connection::{Channel, EncryptedConnection, Exit, ProtocolError, ReadConnection},
         protocol::ChannelRequestExtra,
     },
-    terminal::TerminalSession,
+    terminal::{modes::TerminalModes, profile::Profile, Location, TerminalSession},
+    visitors, HOSTNAME,
 };
 
-use super::Protocol;
+use super::{qotd::Qotd, Protocol, ServerConfig, Shutdown};
 
-const BIND_HOST: &str = "[::]";
 const BIND_PORT: u16 = {
     #[cfg(debug_assertions)]
     {
@@ -39,50 +62,77 @@ const BIND_PORT: u16 = {
     #[cfg(not(debug_assertions))]
     22
 };
+/// How wide the home page is laid out for clients that didn't ask for a pty,
+/// since they don't say how big their terminal is.
+const NO_PTY_WIDTH: usize = 80;
+/// Every channel has its own terminal, so there can't be very many of them.
+const MAX_CHANNELS: usize = 8;
+
+const OPEN_ADMINISTRATIVELY_PROHIBITED: u32 = 1;
+const OPEN_UNKNOWN_CHANNEL_TYPE: u32 = 3;
+const OPEN_RESOURCE_SHORTAGE: u32 = 4;
 
 #[derive(Clone)]
 pub struct Ssh {
-    pub site_data: SiteData,
+    pub site_data: Arc<SiteData>,
+    /// Shown when someone connects.
+    pub qotd: Qotd,
 }
 
 impl Protocol for Ssh {
-    fn generate(data: &SiteData) -> Self {
+    type Options = Qotd;
+
+    fn generate(data: &SiteData, qotd: &Self::Options) -> Self {
         Ssh {
-            site_data: data.clone(),
+            site_data: Arc::new(data.clone()),
+            qotd: qotd.clone(),
         }
     }
 
-    async fn serve(self) {
-        // start a tcp server
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        shutdown
+            .run(async move {
+                // start a tcp server
 
-        let listener = match TcpListener::bind(format!("{BIND_HOST}:{BIND_PORT}")).await {
-            Ok(listener) => listener,
-            Err(e) => {
-                eprintln!("failed to bind to port {BIND_PORT}: {e}");
-                return;
-            }
-        };
+                let Some(listener) = Listener::bind("ssh", port) else {
+                    return;
+                };
 
-        loop {
-            let (stream, remote_addr) = listener.accept().await.unwrap();
-            println!("started tcp connection for ssh: {remote_addr:?}");
+                loop {
+                    let (stream, remote_addr) = listener.accept().await.unwrap();
+                    self.handle_connection(stream, remote_addr);
+                }
+            })
+            .await;
+    }
+}
 
-            let (read, write) = stream.into_split();
+impl Ssh {
+    /// Start a session on a connection that's already been accepted, in its
+    /// own task.
+    pub fn handle_connection(&self, stream: TcpStream, remote_addr: SocketAddr) {
+        println!("started tcp connection for ssh: {remote_addr:?}");
 
-            let site_data = self.site_data.clone();
-            tokio::spawn(async move {
-                if let Err(e) = connection(read, write, site_data).await {
-                    println!("error: {e}");
-                }
-            });
-        }
+        let (read, write) = stream.into_split();
+
+        let site_data = self.site_data.clone();
+        let qotd = self.qotd.clone();
+        tokio::spawn(async move {
+            let _session = lifecycle::Session::start("ssh");
+            if let Err(e) = connection(read, write, site_data, qotd, remote_addr).await {
+                println!("error: {e}");
+            }
+        });
     }
 }
 
 async fn connection(
     mut read: OwnedReadHalf,
     mut write: OwnedWriteHalf,
-    site_data: SiteData,
+    site_data: Arc<SiteData>,
+    qotd: Qotd,
+    remote_addr: SocketAddr,
 ) -> anyhow::Result<()> {
     let server_id = "SSH-2.0-matssh_1.0";
     let keypair = crypto::ed25519::load_keypair();
@@ -108,22 +158,7 @@ async fn connection(
     let mut sequence_number_server_to_client = 0;
 
     // send key exchange
-    let cookie = crypto::generate_cookie();
-    let server_kex_init_payload = protocol::write_message(protocol::Message::KexInit {
-        cookie,
-        kex_algorithms: vec!["curve25519-sha256".to_string()],
-        server_host_key_algorithms: vec!["ssh-ed25519".to_string()],
-        encryption_algorithms_client_to_server: vec!["aes128-ctr".to_string()],
-        encryption_algorithms_server_to_client: vec!["aes128-ctr".to_string()],
-        mac_algorithms_client_to_server: vec!["hmac-sha2-256".to_string()],
-        mac_algorithms_server_to_client: vec!["hmac-sha2-256".to_string()],
-        compression_algorithms_client_to_server: vec!["none".to_string()],
-        compression_algorithms_server_to_client: vec!["none".to_string()],
-        languages_client_to_server: vec![],
-        languages_server_to_client: vec![],
-        first_kex_packet_follows: false,
-        reserved: 0,
-    })?;
+    let server_kex_init_payload = server_kex_init()?;
     let server_kex_init_bytes = protocol::write_payload(server_kex_init_payload.clone(), None)?;
     write.write_all(&server_kex_init_bytes).await?;
     sequence_number_server_to_client += 1;
@@ -132,12 +167,18 @@ async fn connection(
     let client_kex_init_payload = read.read_payload().await?;
     let client_kex_init_message =
         protocol::read_message(Cursor::new(client_kex_init_payload.clone()))?;
-    match client_kex_init_message {
-        protocol::Message::KexInit { .. } => {
-            // check to make sure we support the algorithms
-        }
+    // compression is picked once, and keeps going through any rekeying
+    let (compress_inbound, compress_outbound) = match client_kex_init_message {
+        protocol::Message::KexInit {
+            compression_algorithms_client_to_server,
+            compression_algorithms_server_to_client,
+            ..
+        } => (
+            compression::negotiated(&compression_algorithms_client_to_server),
+            compression::negotiated(&compression_algorithms_server_to_client),
+        ),
         _ => bail!("expected KexInit"),
-    }
+    };
 
     // the session ID is the exchange hash from the first key exchange, and then never changes after that
     let session_id: Vec<u8>;
@@ -158,59 +199,30 @@ async fn connection(
                 );
             }
             protocol::Message::KexEcdhInit { client_public_key } => {
-                let client_public_key = <[u8; 32]>::try_from(client_public_key)
-                    .map_err(|_| anyhow::anyhow!("client public key is not 32 bytes long"))?;
-                let client_public_key = curve25519_dalek::MontgomeryPoint(client_public_key);
-                let server_secret =
-                    curve25519_dalek::Scalar::from_bytes_mod_order(rand::random::<[u8; 32]>());
-                let server_public_key = (ED25519_BASEPOINT_TABLE * &server_secret).to_montgomery();
-
-                let shared_secret = server_secret * client_public_key;
-
-                let mut server_public_host_key = Vec::new();
-                protocol::write_string(&mut server_public_host_key, "ssh-ed25519")?;
-                protocol::write_bytes(
-                    &mut server_public_host_key,
-                    keypair.verifying_key().as_bytes(),
-                )?;
-
-                exchange_hash = crypto::ed25519::compute_exchange_hash(
-                    &server_public_host_key,
-                    Some(shared_secret.as_bytes()),
+                let exchange = key_exchange(
+                    &keypair,
                     &crypto::ed25519::Exchange {
                         client_id: client_id.as_bytes().to_vec(),
                         server_id: server_id.as_bytes().to_vec(),
                         client_kex_init: client_kex_init_payload.clone(),
                         server_kex_init: server_kex_init_payload.clone(),
-                        client_ephemeral: client_public_key.as_bytes().to_vec(),
-                        server_ephemeral: server_public_key.as_bytes().to_vec(),
+                        client_ephemeral: client_public_key,
+                        server_ephemeral: Vec::new(),
                     },
+                    None,
                 )?;
 
                 write
-                    .write_all(&protocol::write_packet(
-                        protocol::Message::KexEcdhReply {
-                            server_public_host_key,
-                            server_public_key: server_public_key.as_bytes().to_vec(),
-                            signature: crypto::ed25519::add_signature(&keypair, &exchange_hash)?,
-                        },
-                        None,
-                    )?)
+                    .write_all(&protocol::write_packet(exchange.reply, None)?)
                     .await?;
                 write
                     .write_all(&protocol::write_packet(protocol::Message::NewKeys, None)?)
                     .await?;
                 sequence_number_server_to_client += 2;
 
+                exchange_hash = exchange.exchange_hash;
                 session_id = exchange_hash.clone();
-                encryption_keys = crypto::compute_keys(
-                    shared_secret.as_bytes(),
-                    &exchange_hash,
-                    &session_id,
-                    Ctr128BE::<Aes128>::key_size(),
-                    Ctr128BE::<Aes128>::iv_size(),
-                    32,
-                )?;
+                encryption_keys = exchange.encryption_keys;
                 break;
             }
             _ => println!("unexpected message"),
@@ -229,23 +241,113 @@ async fn connection(
     }
 
     // encryption is now enabled!
-    read.set_cipher(
-        &encryption_keys.encryption_key_client_to_server,
-        &encryption_keys.initial_iv_client_to_server,
-    );
-    read.integrity_key = Some(encryption_keys.integrity_key_client_to_server.clone());
+    read.set_keys(&encryption_keys);
     let mut conn = EncryptedConnection::new(
         write,
         exchange_hash,
-        session_id,
+        session_id.clone(),
         &encryption_keys,
         sequence_number_server_to_client,
     )
     .await?;
 
-    let mut terminal_session = TerminalSession::new(site_data);
+    // made when the connection starts so the quote and uptime are current
+    let motd = motd::compose(motd::fragments(), &site_data, &qotd);
+    // every session channel gets its own terminal, since clients like OpenSSH
+    // with ControlMaster open several of them over one connection
+    let mut sessions: HashMap<u32, Session> = HashMap::new();
+    let mut user = User::default();
+    let mut authenticated = false;
+    // the KexInit payloads while the client is rekeying, since they're part
+    // of the exchange hash
+    let mut rekey: Option<(Vec<u8>, Vec<u8>)> = None;
+
+    // read the packets in another task, since reading a packet isn't cancel safe
+    let (packet_sender, mut packet_receiver) = mpsc::channel(16);
+    let (keys_sender, mut keys_receiver) = mpsc::channel(1);
+    // set right before the client is told it's authenticated, since its
+    // packets are compressed from then on
+    let decompress = Arc::new(AtomicBool::new(false));
+    let reader_decompress = Arc::clone(&decompress);
+    tokio::spawn(async move {
+        let mut decompressor = None;
+        loop {
+            let payload = read.read_payload().await.and_then(|payload| {
+                if reader_decompress.load(Ordering::Acquire) {
+                    decompressor
+                        .get_or_insert_with(Decompressor::default)
+                        .decompress(&payload)
+                } else {
+                    Ok(payload)
+                }
+            });
+            let payload = match payload {
+                Ok(payload) => payload,
+                Err(err) => {
+                    // so the main loop can tell the client if it broke the protocol
+                    let _ = packet_sender.send(Err(err)).await;
+                    break;
+                }
+            };
+            let Ok(packet) = protocol::read_message(Cursor::new(&payload)) else {
+                break;
+            };
+            let new_keys = matches!(packet, protocol::Message::NewKeys);
+            if packet_sender.send(Ok((packet, payload))).await.is_err() {
+                break;
+            }
+            // the packets after the client's NewKeys use the keys from the
+            // exchange, which we get once the main loop has computed them
+            if new_keys {
+                let Some(encryption_keys) = keys_receiver.recv().await else {
+                    break;
+                };
+                read.set_keys(&encryption_keys);
+            }
+        }
+    });
+    // redraw every second so things like the clock are updated
+    let mut redraw_interval = tokio::time::interval(Duration::from_secs(1));
 
-    while let Ok(packet) = read.read_packet().await {
+    // in a block so the client's terminal is put back however the
+    // connection ends
+    let result: anyhow::Result<()> = async {
+    loop {
+        let (packet, payload) = tokio::select! {
+            packet = packet_receiver.recv() => match packet {
+                Some(Ok(packet)) => packet,
+                Some(Err(err)) => match err.downcast::<ProtocolError>() {
+                    Ok(err) => {
+                        conn.write_packet(protocol::Message::Disconnect {
+                            reason_code: err.reason_code,
+                            description: err.description.clone(),
+                            language_tag: "".to_string(),
+                        })
+                        .await?;
+                        bail!(err);
+                    }
+                    // usually just the client closing the connection
+                    Err(_) => break,
+                },
+                None => break,
+            },
+            _ = redraw_interval.tick() => {
+                // only key exchange messages can be sent while rekeying
+                if rekey.is_some() {
+                    continue;
+                }
+                for (&channel, session) in &mut sessions {
+                    if !session.pty {
+                        continue;
+                    }
+                    let data = session.terminal.draw();
+                    if !data.is_empty() {
+                        conn.write_data(&data, channel).await?;
+                    }
+                }
+                continue;
+            }
+        };
         // println!("packet: {packet:?}");
         match packet {
             protocol::Message::ServiceRequest { service_name } => {
@@ -253,8 +355,9 @@ async fn connection(
                     conn.write_packet(protocol::Message::ServiceAccept { service_name })
                         .await?;
                     conn.write_packet(protocol::Message::UserauthBanner {
-                        message: "welcome to mat does dev free preview no download required\n"
-                            .to_string(),
+                        message: format!(
+                            "welcome to mat does dev free preview no download required\n\n{motd}\n"
+                        ),
                         language_tag: "".to_string(),
                     })
                     .await?;
@@ -274,19 +377,106 @@ async fn connection(
             }
             protocol::Message::UserauthRequest {
                 username,
-                service_name: _,
-                authentication_method: _,
+                service_name,
+                authentication_method,
+                extra,
             } => {
+                // anyone can connect, but clients only offer their keys if
+                // "none" doesn't work. the ones that don't have a key fall
+                // back to keyboard-interactive, which is let in right away.
+                let failure = protocol::Message::UserauthFailure {
+                    authentication_methods: vec![
+                        "publickey".to_string(),
+                        "keyboard-interactive".to_string(),
+                    ],
+                    partial_success: false,
+                };
+                match extra {
+                    _ if authentication_method == "none" => {
+                        conn.write_packet(failure).await?;
+                        continue;
+                    }
+                    protocol::UserauthRequestExtra::PublicKey {
+                        algorithm,
+                        public_key,
+                        signature: None,
+                    } => {
+                        // they're asking whether the key would be accepted
+                        // before signing with it
+                        if algorithm == "ssh-ed25519" {
+                            conn.write_packet(protocol::Message::UserauthPkOk {
+                                algorithm,
+                                public_key,
+                            })
+                            .await?;
+                        } else {
+                            conn.write_packet(failure).await?;
+                        }
+                        continue;
+                    }
+                    protocol::UserauthRequestExtra::PublicKey {
+                        algorithm,
+                        public_key,
+                        signature: Some(signature),
+                    } => {
+                        let signed = crypto::ed25519::userauth_signed_data(
+                            &session_id,
+                            &username,
+                            &service_name,
+                            &algorithm,
+                            &public_key,
+                        )?;
+                        if crypto::ed25519::verify_signature(&public_key, &signature, &signed)
+                            .is_err()
+                        {
+                            conn.write_packet(failure).await?;
+                            continue;
+                        }
+                        user.fingerprint = Some(crypto::ed25519::fingerprint(&public_key));
+                    }
+                    _ => {}
+                }
+
                 println!("user {username} is connecting");
+                if let Some(fingerprint) = &user.fingerprint {
+                    user.previous_visit = visitors::visit(&format!("ssh:{fingerprint}"));
+                }
+                user.username = username;
+                if compress_inbound {
+                    decompress.store(true, Ordering::Release);
+                }
                 conn.write_packet(protocol::Message::UserauthSuccess)
                     .await?;
+                authenticated = true;
+                if compress_outbound {
+                    conn.start_compression();
+                }
             }
             protocol::Message::ChannelOpen {
-                channel_type: _,
+                channel_type,
                 sender_channel,
                 initial_window_size,
                 maximum_packet_size,
             } => {
+                let failure = if !authenticated {
+                    Some((OPEN_ADMINISTRATIVELY_PROHIBITED, "not authenticated"))
+                } else if channel_type != "session" {
+                    Some((OPEN_UNKNOWN_CHANNEL_TYPE, "only session channels are supported"))
+                } else if conn.channels.len() >= MAX_CHANNELS {
+                    Some((OPEN_RESOURCE_SHORTAGE, "too many channels"))
+                } else {
+                    None
+                };
+                if let Some((reason_code, description)) = failure {
+                    conn.write_packet(protocol::Message::ChannelOpenFailure {
+                        recipient_channel: sender_channel,
+                        reason_code,
+                        description: description.to_owned(),
+                        language_tag: String::new(),
+                    })
+                    .await?;
+                    continue;
+                }
                 conn.channels.insert(
                     sender_channel,
                     Channel {
@@ -294,6 +484,8 @@ async fn connection(
                         _sender_window_size: 2097152,
                         recipient_maximum_packet_size: maximum_packet_size,
                         _sender_maximum_packet_size: 32768,
+                        pending: Vec::new(),
+                        exit: None,
                     },
                 );
                 conn.write_packet(protocol::Message::ChannelOpenConfirmation {
@@ -307,80 +499,325 @@ async fn connection(
                     recipient_channel: sender_channel,
                 })
                 .await?;
-
-                conn.write_data(&terminal_session.on_open(), sender_channel)
-                    .await?;
+                sessions.insert(
+                    sender_channel,
+                    Session::new(&site_data, &qotd, remote_addr, &user),
+                );
             }
             protocol::Message::ChannelRequest {
                 recipient_channel,
                 request_type: _,
                 want_reply: _,
                 extra,
-            } => match extra {
-                ChannelRequestExtra::Terminal {
-                    terminal_type: _,
-                    width_columns,
-                    height_rows,
-                    width_pixels: _,
-                    height_pixels: _,
-                    terminal_modes: _,
-                } => {
-                    let data = terminal_session.resize(width_columns, height_rows);
-                    conn.write_data(&data, recipient_channel).await?;
-                }
-                ChannelRequestExtra::WindowChange {
-                    width_columns,
-                    height_rows,
-                    width_pixels: _,
-                    height_pixels: _,
-                } => {
-                    let data = terminal_session.resize(width_columns, height_rows);
-                    conn.write_data(&data, recipient_channel).await?;
-                }
-                ChannelRequestExtra::Exec { command: _ } => {
-                    conn.write_packet(protocol::Message::ChannelSuccess { recipient_channel })
-                        .await?;
-                }
-                ChannelRequestExtra::Shell => {
-                    conn.write_packet(protocol::Message::ChannelSuccess { recipient_channel })
-                        .await?;
+            } => {
+                let Some(session) = sessions.get_mut(&recipient_channel) else {
+                    continue;
+                };
+                match extra {
+                    ChannelRequestExtra::Terminal {
+                        terminal_type,
+                        width_columns,
+                        height_rows,
+                        width_pixels: _,
+                        height_pixels: _,
+                        terminal_modes,
+                    } => {
+                        session.terminal.set_terminal_type(&terminal_type);
+                        session
+                            .terminal
+                            .set_modes(TerminalModes::from_ssh(&terminal_modes));
+                        if !session.pty {
+                            conn.write_data(&session.terminal.on_open(), recipient_channel)
+                                .await?;
+                            session.pty = true;
+                        }
+                        let data = session.terminal.resize(width_columns, height_rows);
+                        conn.write_data(&data, recipient_channel).await?;
+                    }
+                    ChannelRequestExtra::WindowChange {
+                        width_columns,
+                        height_rows,
+                        width_pixels: _,
+                        height_pixels: _,
+                    } => {
+                        let data = session.terminal.resize(width_columns, height_rows);
+                        if session.pty {
+                            conn.write_data(&data, recipient_channel).await?;
+                        }
+                    }
+                    ChannelRequestExtra::Exec { command: _ } | ChannelRequestExtra::Shell => {
+                        conn.write_packet(protocol::Message::ChannelSuccess { recipient_channel })
+                            .await?;
+                        // like `ssh -T`, which would get a screen that makes
+                        // no sense without a terminal
+                        if !session.pty {
+                            let text = no_pty_text(&mut session.terminal);
+                            conn.write_data(text.as_bytes(), recipient_channel).await?;
+                            conn.finish(recipient_channel, Exit::Status(0)).await?;
+                            sessions.remove(&recipient_channel);
+                        }
+                    }
+                    ChannelRequestExtra::ExitStatus { .. }
+                    | ChannelRequestExtra::ExitSignal { .. }
+                    | ChannelRequestExtra::None => {}
                 }
-                ChannelRequestExtra::None => {}
-            },
+            }
             protocol::Message::ChannelData {
                 recipient_channel,
                 data,
             } => {
-                if data == [3] || data == [4] {
-                    // ^C or ^D
-
-                    conn.write_data(&terminal_session.on_close(), recipient_channel)
-                        .await?;
-                    break;
+                // there's nothing to type into without a terminal
+                let Some(session) = sessions
+                    .get_mut(&recipient_channel)
+                    .filter(|session| session.pty)
+                else {
+                    continue;
+                };
+                if session.terminal.is_exit_key(&data) {
+                    if let Some(session) = sessions.remove(&recipient_channel) {
+                        close_session(&mut conn, recipient_channel, session, &user, Exit::Status(0))
+                            .await?;
+                    }
+                    continue;
                 }
-                let data = terminal_session.on_keystroke(&data);
+                let data = session.terminal.on_keystroke(&data);
                 conn.write_data(&data, recipient_channel).await?;
             }
             protocol::Message::ChannelWindowAdjust {
                 recipient_channel,
                 bytes_to_add,
             } => {
-                if let Some(channel) = conn.channels.get_mut(&recipient_channel) {
-                    channel.recipient_window_size += bytes_to_add;
+                conn.adjust_window(recipient_channel, bytes_to_add).await?;
+            }
+            protocol::Message::KexInit { .. } => {
+                // the client wants to rekey
+                let server_kex_init_payload = server_kex_init()?;
+                conn.write_payload(server_kex_init_payload.clone()).await?;
+                rekey = Some((payload, server_kex_init_payload));
+            }
+            protocol::Message::KexEcdhInit { client_public_key } => {
+                let Some((client_kex_init, server_kex_init)) = rekey.take() else {
+                    bail!("got KexEcdhInit without KexInit");
+                };
+                let exchange = key_exchange(
+                    &keypair,
+                    &crypto::ed25519::Exchange {
+                        client_id: client_id.as_bytes().to_vec(),
+                        server_id: server_id.as_bytes().to_vec(),
+                        client_kex_init,
+                        server_kex_init,
+                        client_ephemeral: client_public_key,
+                        server_ephemeral: Vec::new(),
+                    },
+                    Some(session_id.as_slice()),
+                )?;
+
+                conn.write_packet(exchange.reply).await?;
+                conn.write_packet(protocol::Message::NewKeys).await?;
+                conn.set_keys(&exchange.encryption_keys);
+                if keys_sender.send(exchange.encryption_keys).await.is_err() {
+                    break;
                 }
             }
-            protocol::Message::ChannelEof { recipient_channel } => {
-                conn.write_packet(protocol::Message::ChannelClose { recipient_channel })
-                    .await?;
+            // the reader task already switched to the new keys
+            protocol::Message::NewKeys => {}
+            // the client closing its side closes ours too, unless we already
+            // did
+            protocol::Message::ChannelEof { recipient_channel }
+            | protocol::Message::ChannelClose { recipient_channel } => {
+                if let Some(session) = sessions.remove(&recipient_channel) {
+                    session.save_profile(&user);
+                }
+                if conn.channels.remove(&recipient_channel).is_some() {
+                    conn.write_packet(protocol::Message::ChannelClose { recipient_channel })
+                        .await?;
+                }
             }
-            protocol::Message::ChannelClose {
-                recipient_channel: _,
-            } => {}
             _ => println!("unexpected message"),
         }
     }
-
+        Ok(())
+    }
+    .await;
+    // so `ssh` exits with a status instead of saying the connection was
+    // closed. the client's already gone if we disconnected it, and nothing but
+    // key exchange messages can be sent while rekeying.
+    let exit = match &result {
+        _ if rekey.is_some() => None,
+        Ok(()) => Some(Exit::Status(0)),
+        Err(err) if err.downcast_ref::<ProtocolError>().is_some() => None,
+        Err(_) => Some(Exit::Signal {
+            name: "ABRT",
+            message: "The server ran into an error".to_string(),
+        }),
+    };
+    for (channel, session) in sessions {
+        match &exit {
+            Some(exit) => {
+                let _ = close_session(&mut conn, channel, session, &user, exit.clone()).await;
+            }
+            None => session.save_profile(&user),
+        }
+    }
     println!("connection closed");
 
-    Ok(())
+    result
+}
+
+/// Who the client authenticated as, which every session they open starts
+/// with.
+#[derive(Default)]
+struct User {
+    username: String,
+    /// The fingerprint of the key they authenticated with, if any, so their
+    /// profile can be saved when they leave.
+    fingerprint: Option<String>,
+    previous_visit: Option<DateTime<Utc>>,
+}
+
+/// A session channel, and the terminal that's shown on it.
+struct Session {
+    terminal: TerminalSession,
+    /// Whether the client asked for a pty. The terminal is only drawn once
+    /// they have.
+    pty: bool,
+}
+
+impl Session {
+    fn new(site_data: &Arc<SiteData>, qotd: &Qotd, remote_addr: SocketAddr, user: &User) -> Self {
+        let mut terminal = TerminalSession::new(site_data.clone(), "ssh", remote_addr.ip());
+        terminal.set_motd(qotd);
+        if let Some(fingerprint) = &user.fingerprint {
+            terminal.restore(Profile::load(fingerprint));
+            terminal.set_previous_visit(user.previous_visit);
+        }
+        // the username can be used to pick a theme and a language, like
+        // `ssh light@matdoes.dev` or `ssh light-de@matdoes.dev`
+        let (theme, locale) = Locale::split_username(&user.username);
+        terminal.set_theme(theme);
+        if let Some(locale) = locale {
+            terminal.set_locale(locale);
+        }
+        Session {
+            terminal,
+            pty: false,
+        }
+    }
+
+    fn save_profile(&self, user: &User) {
+        if let (Some(fingerprint), Some(profile)) = (&user.fingerprint, self.terminal.profile()) {
+            profile.save(fingerprint);
+        }
+    }
+}
+
+/// Put the client's terminal back the way it was and close the channel. This
+/// only sends the terminal stuff if there was a pty.
+async fn close_session(
+    conn: &mut EncryptedConnection,
+    channel: u32,
+    mut session: Session,
+    user: &User,
+    exit: Exit,
+) -> anyhow::Result<()> {
+    session.save_profile(user);
+    let close = session.terminal.on_close();
+    if !close.is_empty() {
+        conn.write_data(&close, channel).await?;
+    }
+    conn.finish(channel, exit).await
+}
+
+/// The home page as plain text, and how to see the rest of the site.
+fn no_pty_text(terminal_session: &mut TerminalSession) -> String {
+    let index = terminal_session.render_text(Location::Index, NO_PTY_WIDTH);
+    format!(
+        "{index}\n\n\
+         This site is meant to be browsed in a terminal, but no pty was requested.\n\
+         Connect with one to see the blog and projects:\n\
+         \n    ssh -t {HOSTNAME}\n\n\
+         Or read them as plain text:\n\
+         \n    finger blog@{HOSTNAME}\n    finger projects@{HOSTNAME}\n"
+    )
+}
+
+fn server_kex_init() -> anyhow::Result<Vec<u8>> {
+    protocol::write_message(protocol::Message::KexInit {
+        cookie: crypto::generate_cookie(),
+        kex_algorithms: vec!["curve25519-sha256".to_string()],
+        server_host_key_algorithms: vec!["ssh-ed25519".to_string()],
+        encryption_algorithms_client_to_server: vec!["aes128-ctr".to_string()],
+        encryption_algorithms_server_to_client: vec!["aes128-ctr".to_string()],
+        mac_algorithms_client_to_server: vec!["hmac-sha2-256".to_string()],
+        mac_algorithms_server_to_client: vec!["hmac-sha2-256".to_string()],
+        compression_algorithms_client_to_server: compression::SUPPORTED
+            .map(str::to_string)
+            .to_vec(),
+        compression_algorithms_server_to_client: compression::SUPPORTED
+            .map(str::to_string)
+            .to_vec(),
+        languages_client_to_server: vec![],
+        languages_server_to_client: vec![],
+        first_kex_packet_follows: false,
+        reserved: 0,
+    })
+}
+
+struct KeyExchange {
+    reply: protocol::Message,
+    exchange_hash: Vec<u8>,
+    encryption_keys: crypto::EncryptionKeys,
+}
+
+/// Our half of a curve25519-sha256 key exchange, for the first one and when
+/// the client rekeys. The server's ephemeral key in the exchange is generated
+/// here. The session ID is `None` for the first exchange, since it's the
+/// exchange hash from that one.
+fn key_exchange(
+    keypair: &SigningKey,
+    exchange: &crypto::ed25519::Exchange,
+    session_id: Option<&[u8]>,
+) -> anyhow::Result<KeyExchange> {
+    let client_public_key = <[u8; 32]>::try_from(exchange.client_ephemeral.as_slice())
+        .map_err(|_| anyhow::anyhow!("client public key is not 32 bytes long"))?;
+    let client_public_key = curve25519_dalek::MontgomeryPoint(client_public_key);
+    let server_secret = curve25519_dalek::Scalar::from_bytes_mod_order(rand::random::<[u8; 32]>());
+    let server_public_key = (ED25519_BASEPOINT_TABLE * &server_secret).to_montgomery();
+
+    let shared_secret = server_secret * client_public_key;
+
+    let mut server_public_host_key = Vec::new();
+    protocol::write_string(&mut server_public_host_key, "ssh-ed25519")?;
+    protocol::write_bytes(
+        &mut server_public_host_key,
+        keypair.verifying_key().as_bytes(),
+    )?;
+
+    let exchange_hash = crypto::ed25519::compute_exchange_hash(
+        &server_public_host_key,
+        Some(shared_secret.as_bytes()),
+        &crypto::ed25519::Exchange {
+            server_ephemeral: server_public_key.as_bytes().to_vec(),
+            ..exchange.clone()
+        },
+    )?;
+
+    let encryption_keys = crypto::compute_keys(
+        shared_secret.as_bytes(),
+        &exchange_hash,
+        session_id.unwrap_or(&exchange_hash),
+        Ctr128BE::<Aes128>::key_size(),
+        Ctr128BE::<Aes128>::iv_size(),
+        32,
+    )?;
+
+    Ok(KeyExchange {
+        reply: protocol::Message::KexEcdhReply {
+            server_public_host_key,
+            server_public_key: server_public_key.as_bytes().to_vec(),
+            signature: crypto::ed25519::add_signature(keypair, &exchange_hash)?,
+        },
+        exchange_hash,
+        encryption_keys,
+    })
 }
@@ -0,0 +1,488 @@
+//! A minimal SSH client that's just enough to test our own server. It only
+//! supports the algorithms the server does. Everything the server sends is
+//! checked, including the framing, the MACs, and the host key's signature.
+//!
+//! The client only ever opens one channel, number 0.
+
+use std::{collections::VecDeque, io::Cursor, net::SocketAddr};
+
+use aes::{
+    cipher::{IvSizeUser, KeyIvInit, KeySizeUser, StreamCipher},
+    Aes128,
+};
+use anyhow::{anyhow, bail};
+use ctr::Ctr128BE;
+use curve25519_dalek::{constants::ED25519_BASEPOINT_TABLE, MontgomeryPoint, Scalar};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+
+use super::{
+    compression::{self, Compressor, Decompressor},
+    crypto,
+    protocol::{self, ChannelRequestExtra, Message, UserauthRequestExtra},
+};
+
+const CLIENT_ID: &str = "SSH-2.0-matssh_test_client";
+/// RFC 4253 says every implementation has to handle packets this big, and
+/// the server never needs to send bigger ones.
+const MAX_PACKET_LENGTH: usize = 35000;
+
+/// The cipher and MAC key for one direction.
+struct Keys {
+    cipher: Ctr128BE<Aes128>,
+    integrity_key: Vec<u8>,
+}
+
+impl Keys {
+    fn new(encryption_key: &[u8], initial_iv: &[u8], integrity_key: &[u8]) -> Self {
+        Keys {
+            cipher: Ctr128BE::<Aes128>::new_from_slices(encryption_key, initial_iv).unwrap(),
+            integrity_key: integrity_key.to_vec(),
+        }
+    }
+
+    /// The MAC of an unencrypted packet.
+    fn mac(&self, sequence_number: u32, packet: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.integrity_key)
+            .expect("hmac works with keys of any length");
+        mac.update(&sequence_number.to_be_bytes());
+        mac.update(packet);
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+pub struct Client {
+    read: OwnedReadHalf,
+    write: OwnedWriteHalf,
+
+    pub server_id: String,
+    session_id: Option<Vec<u8>>,
+
+    incoming: Option<Keys>,
+    outgoing: Option<Keys>,
+    incoming_sequence_number: u32,
+    outgoing_sequence_number: u32,
+
+    /// Whether to ask for compression, which starts once we're authenticated.
+    compression: bool,
+    compressor: Option<Compressor>,
+    decompressor: Option<Decompressor>,
+
+    /// The banner that the server sent while we were authenticating.
+    pub banner: Option<String>,
+    /// How much more channel data the server is allowed to send.
+    pub window: u32,
+    /// Channel data packets that came while waiting for something else,
+    /// which [`Self::read_data`] returns before reading anything new.
+    unread_data: VecDeque<Vec<u8>>,
+}
+
+impl Client {
+    /// Connect, exchange keys, and authenticate as the user.
+    pub async fn connect(addr: SocketAddr, username: &str) -> anyhow::Result<Self> {
+        let mut client = Self::handshake(addr).await?;
+        client.authenticate(username).await?;
+        Ok(client)
+    }
+
+    /// Like [`Self::connect`], but with `zlib@openssh.com` compression.
+    pub async fn connect_compressed(addr: SocketAddr, username: &str) -> anyhow::Result<Self> {
+        let mut client = Self::handshake_with(addr, true).await?;
+        client.authenticate(username).await?;
+        Ok(client)
+    }
+
+    /// Connect and exchange keys, without authenticating.
+    pub async fn handshake(addr: SocketAddr) -> anyhow::Result<Self> {
+        Self::handshake_with(addr, false).await
+    }
+
+    async fn handshake_with(addr: SocketAddr, compression: bool) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (mut read, mut write) = stream.into_split();
+
+        write
+            .write_all(format!("{CLIENT_ID}\r\n").as_bytes())
+            .await?;
+        let mut bytes = Vec::new();
+        while !bytes.ends_with(b"\r\n") {
+            bytes.push(read.read_u8().await?);
+        }
+        let server_id = String::from_utf8(bytes[..bytes.len() - 2].to_vec())?;
+
+        let mut client = Client {
+            read,
+            write,
+            server_id,
+            session_id: None,
+            incoming: None,
+            outgoing: None,
+            incoming_sequence_number: 0,
+            outgoing_sequence_number: 0,
+            compression,
+            compressor: None,
+            decompressor: None,
+            banner: None,
+            window: 0,
+            unread_data: VecDeque::new(),
+        };
+        client.key_exchange().await?;
+        Ok(client)
+    }
+
+    /// Exchange keys, which is also how we rekey once the connection is
+    /// encrypted. Anything the server sends before its KexInit is dropped.
+    pub async fn key_exchange(&mut self) -> anyhow::Result<()> {
+        let compression_algorithms = if self.compression {
+            vec![compression::ALGORITHM.to_string()]
+        } else {
+            vec!["none".to_string()]
+        };
+        let client_kex_init = protocol::write_message(Message::KexInit {
+            cookie: crypto::generate_cookie(),
+            kex_algorithms: vec!["curve25519-sha256".to_string()],
+            server_host_key_algorithms: vec!["ssh-ed25519".to_string()],
+            encryption_algorithms_client_to_server: vec!["aes128-ctr".to_string()],
+            encryption_algorithms_server_to_client: vec!["aes128-ctr".to_string()],
+            mac_algorithms_client_to_server: vec!["hmac-sha2-256".to_string()],
+            mac_algorithms_server_to_client: vec!["hmac-sha2-256".to_string()],
+            compression_algorithms_client_to_server: compression_algorithms.clone(),
+            compression_algorithms_server_to_client: compression_algorithms,
+            languages_client_to_server: vec![],
+            languages_server_to_client: vec![],
+            first_kex_packet_follows: false,
+            reserved: 0,
+        })?;
+        self.send_payload(client_kex_init.clone()).await?;
+
+        let server_kex_init = loop {
+            let payload = self.read_payload().await?;
+            if let Message::KexInit {
+                kex_algorithms,
+                server_host_key_algorithms,
+                encryption_algorithms_server_to_client,
+                mac_algorithms_server_to_client,
+                ..
+            } = protocol::read_message(Cursor::new(&payload))?
+            {
+                for (algorithms, ours) in [
+                    (kex_algorithms, "curve25519-sha256"),
+                    (server_host_key_algorithms, "ssh-ed25519"),
+                    (encryption_algorithms_server_to_client, "aes128-ctr"),
+                    (mac_algorithms_server_to_client, "hmac-sha2-256"),
+                ] {
+                    if !algorithms.iter().any(|a| a == ours) {
+                        bail!("server doesn't support {ours}, only {algorithms:?}");
+                    }
+                }
+                break payload;
+            }
+        };
+
+        let secret = Scalar::from_bytes_mod_order(rand::random::<[u8; 32]>());
+        let public_key = (ED25519_BASEPOINT_TABLE * &secret).to_montgomery();
+        self.send_packet(Message::KexEcdhInit {
+            client_public_key: public_key.as_bytes().to_vec(),
+        })
+        .await?;
+
+        let message = self.read_message().await?;
+        let Message::KexEcdhReply {
+            server_public_host_key,
+            server_public_key,
+            signature,
+        } = message
+        else {
+            bail!("expected KexEcdhReply, got {message:?}");
+        };
+        let server_public_key = <[u8; 32]>::try_from(server_public_key.as_slice())
+            .map_err(|_| anyhow!("server public key is not 32 bytes long"))?;
+        let shared_secret = secret * MontgomeryPoint(server_public_key);
+
+        let exchange_hash = crypto::ed25519::compute_exchange_hash(
+            &server_public_host_key,
+            Some(shared_secret.as_bytes()),
+            &crypto::ed25519::Exchange {
+                client_id: CLIENT_ID.as_bytes().to_vec(),
+                server_id: self.server_id.as_bytes().to_vec(),
+                client_kex_init,
+                server_kex_init,
+                client_ephemeral: public_key.as_bytes().to_vec(),
+                server_ephemeral: server_public_key.to_vec(),
+            },
+        )?;
+        crypto::ed25519::verify_signature(&server_public_host_key, &signature, &exchange_hash)?;
+
+        let session_id = self.session_id.get_or_insert(exchange_hash.clone());
+        let encryption_keys = crypto::compute_keys(
+            shared_secret.as_bytes(),
+            &exchange_hash,
+            session_id,
+            Ctr128BE::<Aes128>::key_size(),
+            Ctr128BE::<Aes128>::iv_size(),
+            32,
+        )?;
+
+        let message = self.read_message().await?;
+        let Message::NewKeys = message else {
+            bail!("expected NewKeys, got {message:?}");
+        };
+        self.incoming = Some(Keys::new(
+            &encryption_keys.encryption_key_server_to_client,
+            &encryption_keys.initial_iv_server_to_client,
+            &encryption_keys.integrity_key_server_to_client,
+        ));
+        self.send_packet(Message::NewKeys).await?;
+        self.outgoing = Some(Keys::new(
+            &encryption_keys.encryption_key_client_to_server,
+            &encryption_keys.initial_iv_client_to_server,
+            &encryption_keys.integrity_key_client_to_server,
+        ));
+
+        Ok(())
+    }
+
+    /// Authenticate without a key, the same way as a client that doesn't have
+    /// one would.
+    pub async fn authenticate(&mut self, username: &str) -> anyhow::Result<()> {
+        self.send_packet(Message::ServiceRequest {
+            service_name: "ssh-userauth".to_string(),
+        })
+        .await?;
+        let message = self.read_message().await?;
+        let Message::ServiceAccept { .. } = message else {
+            bail!("expected ServiceAccept, got {message:?}");
+        };
+
+        self.send_packet(Message::UserauthRequest {
+            username: username.to_string(),
+            service_name: "ssh-connection".to_string(),
+            authentication_method: "none".to_string(),
+            extra: UserauthRequestExtra::None,
+        })
+        .await?;
+        loop {
+            match self.read_message().await? {
+                Message::UserauthBanner { message, .. } => self.banner = Some(message),
+                Message::UserauthSuccess => {
+                    if self.compression {
+                        self.compressor = Some(Compressor::default());
+                        self.decompressor = Some(Decompressor::default());
+                    }
+                    return Ok(());
+                }
+                Message::UserauthFailure { .. } => {
+                    self.send_packet(Message::UserauthRequest {
+                        username: username.to_string(),
+                        service_name: "ssh-connection".to_string(),
+                        authentication_method: "keyboard-interactive".to_string(),
+                        extra: UserauthRequestExtra::None,
+                    })
+                    .await?;
+                }
+                message => bail!("expected UserauthSuccess, got {message:?}"),
+            }
+        }
+    }
+
+    /// Open a session channel. The window is how much data we let the server
+    /// send before it has to wait for [`Self::adjust_window`].
+    pub async fn open_session(
+        &mut self,
+        window: u32,
+        maximum_packet_size: u32,
+    ) -> anyhow::Result<()> {
+        self.window = window;
+        self.send_packet(Message::ChannelOpen {
+            channel_type: "session".to_string(),
+            sender_channel: 0,
+            initial_window_size: window,
+            maximum_packet_size,
+        })
+        .await?;
+        let message = self.read_message().await?;
+        let Message::ChannelOpenConfirmation { .. } = message else {
+            bail!("expected ChannelOpenConfirmation, got {message:?}");
+        };
+        Ok(())
+    }
+
+    pub async fn request_pty(&mut self, width: u32, height: u32) -> anyhow::Result<()> {
+        self.send_packet(Message::ChannelRequest {
+            recipient_channel: 0,
+            request_type: "pty-req".to_string(),
+            want_reply: false,
+            extra: ChannelRequestExtra::Terminal {
+                terminal_type: "xterm-256color".to_string(),
+                width_columns: width,
+                height_rows: height,
+                width_pixels: 0,
+                height_pixels: 0,
+                terminal_modes: vec![0],
+            },
+        })
+        .await
+    }
+
+    pub async fn request_shell(&mut self) -> anyhow::Result<()> {
+        self.send_packet(Message::ChannelRequest {
+            recipient_channel: 0,
+            request_type: "shell".to_string(),
+            want_reply: true,
+            extra: ChannelRequestExtra::Shell,
+        })
+        .await
+    }
+
+    pub async fn send_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.send_packet(Message::ChannelData {
+            recipient_channel: 0,
+            data: data.to_vec(),
+        })
+        .await
+    }
+
+    /// Wait for channel data, and fail if the server sent more than the
+    /// window allows. Other channel messages are skipped.
+    pub async fn read_data(&mut self) -> anyhow::Result<Vec<u8>> {
+        if let Some(data) = self.unread_data.pop_front() {
+            return Ok(data);
+        }
+        loop {
+            match self.read_message().await? {
+                Message::ChannelData { data, .. } => {
+                    self.use_window(&data)?;
+                    return Ok(data);
+                }
+                Message::ChannelSuccess { .. }
+                | Message::ChannelWindowAdjust { .. }
+                | Message::Ignore { .. }
+                | Message::Debug { .. } => {}
+                message => bail!("expected ChannelData, got {message:?}"),
+            }
+        }
+    }
+
+    /// Keep channel data that was read while waiting for another message, so
+    /// it's still returned by [`Self::read_data`].
+    pub fn keep_data(&mut self, data: Vec<u8>) -> anyhow::Result<()> {
+        self.use_window(&data)?;
+        self.unread_data.push_back(data);
+        Ok(())
+    }
+
+    fn use_window(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.window = self
+            .window
+            .checked_sub(data.len() as u32)
+            .ok_or_else(|| anyhow!("server sent more than the window allows"))?;
+        Ok(())
+    }
+
+    pub async fn adjust_window(&mut self, bytes_to_add: u32) -> anyhow::Result<()> {
+        self.window += bytes_to_add;
+        self.send_packet(Message::ChannelWindowAdjust {
+            recipient_channel: 0,
+            bytes_to_add,
+        })
+        .await
+    }
+
+    pub async fn send_packet(&mut self, message: Message) -> anyhow::Result<()> {
+        self.send_payload(protocol::write_message(message)?).await
+    }
+
+    pub async fn send_payload(&mut self, payload: Vec<u8>) -> anyhow::Result<()> {
+        let payload = match &mut self.compressor {
+            Some(compressor) => compressor.compress(&payload)?,
+            None => payload,
+        };
+        let block_size = self
+            .outgoing
+            .as_ref()
+            .map(|_| Ctr128BE::<Aes128>::key_size());
+        let packet = protocol::write_payload(payload, block_size)?;
+        let bytes = self.seal(packet);
+        self.write_raw(&bytes).await
+    }
+
+    /// Encrypt a packet and add its MAC, if encryption is enabled. The packet
+    /// has to already have its length and padding, so tests can make packets
+    /// that [`protocol::write_payload`] wouldn't.
+    pub fn seal(&mut self, mut packet: Vec<u8>) -> Vec<u8> {
+        if let Some(keys) = &mut self.outgoing {
+            let mac = keys.mac(self.outgoing_sequence_number, &packet);
+            keys.cipher.apply_keystream(&mut packet);
+            packet.extend(mac);
+        }
+        self.outgoing_sequence_number = self.outgoing_sequence_number.wrapping_add(1);
+        packet
+    }
+
+    /// Send bytes without doing anything to them.
+    pub async fn write_raw(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.write.write_all(bytes).await?;
+        Ok(())
+    }
+
+    pub async fn read_message(&mut self) -> anyhow::Result<Message> {
+        let payload = self.read_payload().await?;
+        protocol::read_message(Cursor::new(payload))
+    }
+
+    pub async fn read_payload(&mut self) -> anyhow::Result<Vec<u8>> {
+        let mut packet = vec![0; 4];
+        self.read.read_exact(&mut packet).await?;
+        if let Some(keys) = &mut self.incoming {
+            keys.cipher.apply_keystream(&mut packet);
+        }
+        let packet_length = u32::from_be_bytes(packet[..4].try_into().unwrap()) as usize;
+        if packet_length > MAX_PACKET_LENGTH {
+            bail!("packet is too long: {packet_length} bytes");
+        }
+
+        packet.resize(4 + packet_length, 0);
+        self.read.read_exact(&mut packet[4..]).await?;
+        if let Some(keys) = &mut self.incoming {
+            keys.cipher.apply_keystream(&mut packet[4..]);
+
+            let mut mac = [0; 32];
+            self.read.read_exact(&mut mac).await?;
+            if keys.mac(self.incoming_sequence_number, &packet) != mac {
+                bail!("invalid mac on packet {}", self.incoming_sequence_number);
+            }
+        }
+        self.incoming_sequence_number = self.incoming_sequence_number.wrapping_add(1);
+
+        let block_size = match self.incoming {
+            Some(_) => Ctr128BE::<Aes128>::key_size(),
+            None => 8,
+        };
+        let padding_length = *packet.get(4).ok_or_else(|| anyhow!("packet is empty"))? as usize;
+        if !packet.len().is_multiple_of(block_size) || padding_length < 4 {
+            bail!(
+                "packet is {} bytes with {padding_length} bytes of padding, which isn't allowed",
+                packet.len()
+            );
+        }
+        let payload_end = packet
+            .len()
+            .checked_sub(padding_length)
+            .filter(|&end| end > 5)
+            .ok_or_else(|| anyhow!("padding is longer than the packet"))?;
+
+        let payload = &packet[5..payload_end];
+        match &mut self.decompressor {
+            Some(decompressor) => decompressor.decompress(payload),
+            None => Ok(payload.to_vec()),
+        }
+    }
+}
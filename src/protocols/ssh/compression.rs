@@ -0,0 +1,134 @@
+//! `zlib@openssh.com` compression. Each direction is one zlib stream for the
+//! whole connection, and it only starts once the client has authenticated so
+//! nothing can be compressed before we know who we're talking to.
+
+use anyhow::bail;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+pub const ALGORITHM: &str = "zlib@openssh.com";
+/// What we put in our KexInit for both directions.
+pub const SUPPORTED: [&str; 2] = ["none", ALGORITHM];
+
+/// Decompressed packets can't be bigger than this, so a small packet can't
+/// make us allocate a huge buffer. It's the same as the limit before
+/// decompression.
+const MAX_PAYLOAD_LENGTH: usize = 35000;
+
+/// Whether zlib was negotiated for one direction. Like every algorithm, it's
+/// the first one in the client's list that we also support.
+pub fn negotiated(client_algorithms: &[String]) -> bool {
+    client_algorithms
+        .iter()
+        .find(|algorithm| SUPPORTED.contains(&algorithm.as_str()))
+        .is_some_and(|algorithm| algorithm == ALGORITHM)
+}
+
+pub struct Compressor(Compress);
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Compressor(Compress::new(Compression::default(), true))
+    }
+}
+
+impl Compressor {
+    /// Compress one payload, flushed so the other side can decompress it
+    /// without waiting for the next one.
+    pub fn compress(&mut self, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let start = self.0.total_in();
+        let mut out = Vec::with_capacity(payload.len() / 2 + 64);
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity());
+            }
+            let consumed = (self.0.total_in() - start) as usize;
+            self.0
+                .compress_vec(&payload[consumed..], &mut out, FlushCompress::Partial)?;
+            // it's done flushing once it stops before filling the buffer
+            let consumed = (self.0.total_in() - start) as usize;
+            if consumed == payload.len() && out.len() < out.capacity() {
+                return Ok(out);
+            }
+        }
+    }
+}
+
+pub struct Decompressor(Decompress);
+
+impl Default for Decompressor {
+    fn default() -> Self {
+        Decompressor(Decompress::new(true))
+    }
+}
+
+impl Decompressor {
+    pub fn decompress(&mut self, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let start = self.0.total_in();
+        let mut out = Vec::with_capacity(payload.len() * 4 + 64);
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity());
+            }
+            let (total_in, length) = (self.0.total_in(), out.len());
+            let consumed = (total_in - start) as usize;
+            let status =
+                self.0
+                    .decompress_vec(&payload[consumed..], &mut out, FlushDecompress::Sync)?;
+            if out.len() > MAX_PAYLOAD_LENGTH {
+                bail!("decompressed payload is too long");
+            }
+            // the stream lasts as long as the connection, so it ending means
+            // the rest of the payload can never be read. there's always room
+            // in the buffer, so not getting anywhere otherwise is the same.
+            if status == Status::StreamEnd {
+                bail!("the compressed stream ended");
+            }
+            if self.0.total_in() == total_in && out.len() == length {
+                bail!("the compressed payload can't be decompressed");
+            }
+            let consumed = (self.0.total_in() - start) as usize;
+            if consumed == payload.len() && out.len() < out.capacity() {
+                return Ok(out);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_across_packets() {
+        let mut compressor = Compressor::default();
+        let mut decompressor = Decompressor::default();
+        // the second one is mostly a reference back into the first
+        let screen = "\x1b[1;1H\x1b[37mmatdoesdev\x1b[0m ".repeat(200);
+        for payload in [screen.as_bytes(), screen.as_bytes(), b"\x5e\x00"] {
+            let compressed = compressor.compress(payload).unwrap();
+            assert_eq!(decompressor.decompress(&compressed).unwrap(), payload);
+        }
+        assert!(compressor.compress(screen.as_bytes()).unwrap().len() < screen.len() / 10);
+    }
+
+    #[test]
+    fn fails_when_the_stream_ends_early() {
+        // a whole zlib stream, and then more that can't be part of it
+        let mut finished = Compress::new(Compression::default(), true);
+        let mut payload = Vec::with_capacity(64);
+        finished
+            .compress_vec(b"hi", &mut payload, FlushCompress::Finish)
+            .unwrap();
+        payload.extend(b"more");
+        assert!(Decompressor::default().decompress(&payload).is_err());
+    }
+
+    #[test]
+    fn negotiates_like_the_client_prefers() {
+        let list =
+            |algorithms: &[&str]| algorithms.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert!(negotiated(&list(&["zlib@openssh.com", "zlib", "none"])));
+        assert!(!negotiated(&list(&["none", "zlib@openssh.com"])));
+        assert!(!negotiated(&list(&["zlib"])));
+    }
+}
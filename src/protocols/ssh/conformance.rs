@@ -0,0 +1,526 @@
+//! Running the server against the [`Client`] to check that it follows the
+//! protocol, plus throwing garbage at [`protocol::read_message`] to make sure
+//! it never panics.
+
+use std::{io::Cursor, net::SocketAddr, sync::Once, time::Duration};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{net::TcpListener, task::JoinHandle, time::timeout};
+
+use super::{
+    client::Client,
+    connection::{DISCONNECT_MAC_ERROR, DISCONNECT_PROTOCOL_ERROR},
+    crypto,
+    protocol::{self, ChannelRequestExtra, Message, UserauthRequestExtra},
+};
+use crate::{protocols::qotd::Qotd, terminal::testing::site_data};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The host key is made the first time it's loaded, so the tests can't all
+/// try to do that at once.
+static HOST_KEY: Once = Once::new();
+
+/// Start a server that handles a single connection, and get the task that's
+/// handling it.
+async fn serve_one() -> (SocketAddr, JoinHandle<anyhow::Result<()>>) {
+    HOST_KEY.call_once(|| {
+        crypto::ed25519::load_keypair();
+    });
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let task = tokio::spawn(async move {
+        let (stream, remote_addr) = listener.accept().await?;
+        let (read, write) = stream.into_split();
+        super::connection(
+            read,
+            write,
+            site_data().into(),
+            Qotd::default(),
+            remote_addr,
+        )
+        .await
+    });
+    (addr, task)
+}
+
+/// Connect and open a session with a pty, like `ssh` would.
+async fn session(addr: SocketAddr, window: u32, maximum_packet_size: u32) -> Client {
+    let mut client = Client::connect(addr, "test").await.unwrap();
+    client
+        .open_session(window, maximum_packet_size)
+        .await
+        .unwrap();
+    // the server says the channel request succeeded when it's opened
+    wait_for_success(&mut client).await;
+    client.request_pty(80, 24).await.unwrap();
+    assert_responds(&mut client).await;
+    client
+}
+
+/// Read channel data until the text shows up in it.
+async fn read_until(client: &mut Client, text: &str) {
+    let mut received = Vec::new();
+    timeout(TIMEOUT, async {
+        while !String::from_utf8_lossy(&received).contains(text) {
+            received.extend(client.read_data().await.unwrap());
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("{text:?} wasn't sent"));
+}
+
+/// Make sure the server is still reading our packets, by asking for a shell
+/// and waiting for its reply.
+async fn assert_responds(client: &mut Client) {
+    client.request_shell().await.unwrap();
+    wait_for_success(client).await;
+}
+
+/// Skip to the next ChannelSuccess. The channel data before it is kept for
+/// [`read_until`].
+async fn wait_for_success(client: &mut Client) {
+    timeout(TIMEOUT, async {
+        loop {
+            match client.read_message().await.unwrap() {
+                Message::ChannelSuccess { .. } => break,
+                Message::ChannelData { data, .. } => client.keep_data(data).unwrap(),
+                _ => {}
+            }
+        }
+    })
+    .await
+    .expect("server stopped responding");
+}
+
+/// Wait for the server to disconnect us, and check why.
+async fn assert_disconnects(client: &mut Client, expected_reason_code: u32) {
+    timeout(TIMEOUT, async {
+        loop {
+            match client.read_message().await {
+                Ok(Message::Disconnect { reason_code, .. }) => {
+                    assert_eq!(reason_code, expected_reason_code);
+                    break;
+                }
+                Ok(_) => {}
+                Err(err) => panic!("connection was closed without a disconnect message: {err}"),
+            }
+        }
+    })
+    .await
+    .expect("server didn't disconnect");
+}
+
+/// Frame a payload like [`protocol::write_payload`] does, but with the given
+/// amount of extra padding.
+fn frame(payload: &[u8], extra_padding_blocks: usize) -> Vec<u8> {
+    let mut padding_length = 16 - (payload.len() + 5) % 16;
+    if padding_length < 4 {
+        padding_length += 16;
+    }
+    padding_length += extra_padding_blocks * 16;
+
+    let mut packet = Vec::new();
+    packet.extend(((payload.len() + padding_length + 1) as u32).to_be_bytes());
+    packet.push(padding_length as u8);
+    packet.extend(payload);
+    packet.extend(vec![0; padding_length]);
+    packet
+}
+
+fn ignore_payload(data: &[u8]) -> Vec<u8> {
+    protocol::write_message(Message::Ignore {
+        data: data.to_vec(),
+    })
+    .unwrap()
+}
+
+#[tokio::test]
+async fn handshake_and_session() {
+    let (addr, _server) = serve_one().await;
+    let mut client = session(addr, 2097152, 32768).await;
+
+    assert!(client.server_id.starts_with("SSH-2.0-"));
+    assert!(client
+        .banner
+        .as_deref()
+        .is_some_and(|banner| banner.contains("welcome")));
+    read_until(&mut client, "matdoesdev").await;
+}
+
+#[tokio::test]
+async fn exit_status_on_quit() {
+    let (addr, _server) = serve_one().await;
+    let mut client = session(addr, 2097152, 32768).await;
+    // ctrl+c
+    client.send_data(&[3]).await.unwrap();
+
+    let exit_status = timeout(TIMEOUT, async {
+        let mut exit_status = None;
+        loop {
+            match client.read_message().await.unwrap() {
+                Message::ChannelRequest {
+                    extra:
+                        ChannelRequestExtra::ExitStatus {
+                            exit_status: status,
+                        },
+                    ..
+                } => exit_status = Some(status),
+                Message::ChannelClose { .. } => return exit_status,
+                _ => {}
+            }
+        }
+    })
+    .await
+    .expect("server didn't close the channel");
+    assert_eq!(exit_status, Some(0));
+}
+
+#[tokio::test]
+async fn multiple_sessions() {
+    let (addr, _server) = serve_one().await;
+    let mut client = session(addr, 2097152, 32768).await;
+
+    // a second session over the same connection, like ControlMaster opens
+    client
+        .send_packet(Message::ChannelOpen {
+            channel_type: "session".to_string(),
+            sender_channel: 1,
+            initial_window_size: 2097152,
+            maximum_packet_size: 32768,
+        })
+        .await
+        .unwrap();
+    client
+        .send_packet(Message::ChannelRequest {
+            recipient_channel: 1,
+            request_type: "shell".to_string(),
+            want_reply: true,
+            extra: ChannelRequestExtra::Shell,
+        })
+        .await
+        .unwrap();
+
+    // it doesn't have a pty so it's closed right away, without the first one
+    timeout(TIMEOUT, async {
+        loop {
+            if let Message::ChannelClose { recipient_channel } =
+                client.read_message().await.unwrap()
+            {
+                assert_eq!(recipient_channel, 1);
+                break;
+            }
+        }
+    })
+    .await
+    .expect("server didn't close the second session");
+    assert_responds(&mut client).await;
+}
+
+/// Open a channel and get the reason code if it's refused.
+async fn open_channel(client: &mut Client, channel_type: &str, channel: u32) -> Option<u32> {
+    client
+        .send_packet(Message::ChannelOpen {
+            channel_type: channel_type.to_string(),
+            sender_channel: channel,
+            initial_window_size: 2097152,
+            maximum_packet_size: 32768,
+        })
+        .await
+        .unwrap();
+    timeout(TIMEOUT, async {
+        loop {
+            match client.read_message().await.unwrap() {
+                Message::ChannelOpenConfirmation { .. } => break None,
+                Message::ChannelOpenFailure { reason_code, .. } => break Some(reason_code),
+                _ => {}
+            }
+        }
+    })
+    .await
+    .expect("server didn't answer the channel open")
+}
+
+#[tokio::test]
+async fn refuses_channels() {
+    let (addr, _server) = serve_one().await;
+    let mut client = Client::handshake(addr).await.unwrap();
+    assert_eq!(
+        open_channel(&mut client, "session", 0).await,
+        Some(super::OPEN_ADMINISTRATIVELY_PROHIBITED)
+    );
+
+    client.authenticate("test").await.unwrap();
+    assert_eq!(
+        open_channel(&mut client, "direct-tcpip", 0).await,
+        Some(super::OPEN_UNKNOWN_CHANNEL_TYPE)
+    );
+    for channel in 0..super::MAX_CHANNELS as u32 {
+        assert_eq!(open_channel(&mut client, "session", channel).await, None);
+    }
+    assert_eq!(
+        open_channel(&mut client, "session", super::MAX_CHANNELS as u32).await,
+        Some(super::OPEN_RESOURCE_SHORTAGE)
+    );
+}
+
+#[tokio::test]
+async fn session_without_pty() {
+    let (addr, _server) = serve_one().await;
+    let mut client = Client::connect(addr, "test").await.unwrap();
+    client.open_session(2097152, 32768).await.unwrap();
+    client.request_shell().await.unwrap();
+
+    // the home page is sent as plain text, and then the channel is closed
+    let mut received = Vec::new();
+    let mut exit_status = None;
+    timeout(TIMEOUT, async {
+        loop {
+            match client.read_message().await.unwrap() {
+                Message::ChannelData { data, .. } => received.extend(data),
+                Message::ChannelRequest {
+                    extra:
+                        ChannelRequestExtra::ExitStatus {
+                            exit_status: status,
+                        },
+                    ..
+                } => exit_status = Some(status),
+                Message::ChannelClose { .. } => break,
+                _ => {}
+            }
+        }
+    })
+    .await
+    .expect("server didn't close the channel");
+
+    let received = String::from_utf8_lossy(&received);
+    assert!(received.contains("matdoesdev"));
+    assert!(received.contains("ssh -t"));
+    // nothing that only makes sense to a terminal
+    assert!(!received.contains('\x1b'));
+    assert_eq!(exit_status, Some(0));
+}
+
+#[tokio::test]
+async fn compression() {
+    let (addr, _server) = serve_one().await;
+    let mut client = Client::connect_compressed(addr, "test").await.unwrap();
+    client.open_session(2097152, 32768).await.unwrap();
+    wait_for_success(&mut client).await;
+    client.request_pty(80, 24).await.unwrap();
+    read_until(&mut client, "matdoesdev").await;
+    assert_responds(&mut client).await;
+}
+
+#[tokio::test]
+async fn packet_framing() {
+    let (addr, _server) = serve_one().await;
+    let mut client = session(addr, 2097152, 32768).await;
+
+    // one byte at a time
+    let packet = frame(&ignore_payload(b"split"), 0);
+    let bytes = client.seal(packet);
+    for byte in bytes {
+        client.write_raw(&[byte]).await.unwrap();
+        tokio::task::yield_now().await;
+    }
+    assert_responds(&mut client).await;
+
+    // more padding than needed, which is allowed up to 255 bytes
+    for extra_padding_blocks in [1, 4, 14] {
+        let packet = frame(&ignore_payload(b"padded"), extra_padding_blocks);
+        let bytes = client.seal(packet);
+        client.write_raw(&bytes).await.unwrap();
+    }
+    assert_responds(&mut client).await;
+
+    // several packets in one write
+    let mut bytes = Vec::new();
+    for i in 0..8 {
+        let packet = frame(&ignore_payload(&vec![i; i as usize * 7]), 0);
+        bytes.extend(client.seal(packet));
+    }
+    client.write_raw(&bytes).await.unwrap();
+    assert_responds(&mut client).await;
+}
+
+#[tokio::test]
+async fn rejects_invalid_mac() {
+    // flipping a bit in the encrypted payload or in the mac itself should both
+    // make the mac wrong
+    for corrupt_index in [20, usize::MAX] {
+        let (addr, server) = serve_one().await;
+        let mut client = session(addr, 2097152, 32768).await;
+
+        let packet = frame(&ignore_payload(&[0; 32]), 0);
+        let mut bytes = client.seal(packet);
+        let corrupt_index = corrupt_index.min(bytes.len() - 1);
+        bytes[corrupt_index] ^= 1;
+        client.write_raw(&bytes).await.unwrap();
+
+        // the server has to close the connection instead of accepting it
+        assert_disconnects(&mut client, DISCONNECT_MAC_ERROR).await;
+        timeout(TIMEOUT, server)
+            .await
+            .expect("server didn't stop")
+            .unwrap()
+            .ok();
+    }
+}
+
+#[tokio::test]
+async fn rejects_invalid_lengths() {
+    let corruptions: [fn(&mut Vec<u8>); 3] = [
+        // a length that would need a huge buffer
+        |packet| packet[..4].copy_from_slice(&u32::MAX.to_be_bytes()),
+        // more padding than there is packet
+        |packet| packet[4] = 255,
+        // less than the minimum of 4 bytes of padding
+        |packet| packet[4] = 3,
+    ];
+
+    for corrupt in corruptions {
+        let (addr, _server) = serve_one().await;
+        let mut client = session(addr, 2097152, 32768).await;
+
+        let mut packet = frame(&ignore_payload(b"length"), 0);
+        corrupt(&mut packet);
+        let bytes = client.seal(packet);
+        client.write_raw(&bytes).await.unwrap();
+
+        assert_disconnects(&mut client, DISCONNECT_PROTOCOL_ERROR).await;
+    }
+}
+
+#[tokio::test]
+async fn respects_window() {
+    const WINDOW: u32 = 100;
+    const MAXIMUM_PACKET_SIZE: u32 = 32;
+
+    let (addr, _server) = serve_one().await;
+    let mut client = session(addr, WINDOW, MAXIMUM_PACKET_SIZE).await;
+
+    // the first screen is much more than the window, so the server should
+    // fill it and then wait. read_data fails if it sends too much.
+    while client.window > 0 {
+        let data = timeout(TIMEOUT, client.read_data())
+            .await
+            .expect("server didn't fill the window")
+            .unwrap();
+        assert!(data.len() <= MAXIMUM_PACKET_SIZE as usize);
+    }
+
+    // and once there's room again, it sends the rest
+    client.adjust_window(1000).await.unwrap();
+    let data = timeout(TIMEOUT, client.read_data())
+        .await
+        .expect("server didn't send anything after the window was adjusted")
+        .unwrap();
+    assert!(data.len() <= MAXIMUM_PACKET_SIZE as usize);
+
+    client.adjust_window(2097152).await.unwrap();
+    read_until(&mut client, "Home").await;
+}
+
+#[tokio::test]
+async fn disconnects_when_the_window_stays_closed() {
+    let (addr, server) = serve_one().await;
+    let mut client = session(addr, 0, 32768).await;
+
+    // every resize redraws the whole screen, which can't be sent
+    for width in (0..).map(|i| 80 + i % 2).take(10000) {
+        let request = client
+            .send_packet(Message::ChannelRequest {
+                recipient_channel: 0,
+                request_type: "window-change".to_string(),
+                want_reply: false,
+                extra: ChannelRequestExtra::WindowChange {
+                    width_columns: width,
+                    height_rows: 24,
+                    width_pixels: 0,
+                    height_pixels: 0,
+                },
+            })
+            .await;
+        if request.is_err() {
+            break;
+        }
+    }
+    let result = timeout(TIMEOUT, server).await.expect("server didn't stop");
+    assert!(result.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn rekey() {
+    let (addr, _server) = serve_one().await;
+    let mut client = session(addr, 2097152, 32768).await;
+    read_until(&mut client, "matdoesdev").await;
+
+    for _ in 0..3 {
+        timeout(TIMEOUT, client.key_exchange())
+            .await
+            .expect("rekeying timed out")
+            .unwrap();
+        // both directions have to be using the new keys now
+        assert_responds(&mut client).await;
+        client.send_data(b"\t").await.unwrap();
+        client.read_data().await.unwrap();
+    }
+}
+
+#[test]
+fn read_message_never_panics() {
+    let mut rng = StdRng::seed_from_u64(0);
+
+    // valid messages to mutate, since completely random bytes rarely get past
+    // the first field
+    let seeds = [
+        ignore_payload(b"hello"),
+        protocol::write_message(Message::ChannelData {
+            recipient_channel: 0,
+            data: b"data".to_vec(),
+        })
+        .unwrap(),
+        protocol::write_message(Message::ChannelRequest {
+            recipient_channel: 0,
+            request_type: "pty-req".to_string(),
+            want_reply: true,
+            extra: ChannelRequestExtra::Terminal {
+                terminal_type: "xterm".to_string(),
+                width_columns: 80,
+                height_rows: 24,
+                width_pixels: 0,
+                height_pixels: 0,
+                terminal_modes: vec![0],
+            },
+        })
+        .unwrap(),
+        protocol::write_message(Message::UserauthRequest {
+            username: "test".to_string(),
+            service_name: "ssh-connection".to_string(),
+            authentication_method: "none".to_string(),
+            extra: UserauthRequestExtra::None,
+        })
+        .unwrap(),
+    ];
+
+    for _ in 0..20000 {
+        let mut payload = if rng.gen_bool(0.5) {
+            seeds[rng.gen_range(0..seeds.len())].clone()
+        } else {
+            let mut payload = vec![0; rng.gen_range(1..64)];
+            rng.fill(payload.as_mut_slice());
+            // mostly message types that exist
+            payload[0] = rng.gen_range(0..=101);
+            payload
+        };
+        for _ in 0..rng.gen_range(0..4) {
+            let index = rng.gen_range(0..payload.len());
+            payload[index] = rng.gen();
+        }
+        payload.truncate(rng.gen_range(0..=payload.len()));
+
+        let _ = protocol::read_message(Cursor::new(payload));
+    }
+}
@@ -1,13 +1,10 @@
-use std::{
-    collections::HashMap,
-    io::{Cursor, Read},
-};
+use std::{collections::HashMap, fmt, io::Cursor};
 
 use aes::{
     cipher::{KeyIvInit, KeySizeUser, StreamCipher},
     Aes128,
 };
-use byteorder::ReadBytesExt;
+use anyhow::bail;
 use ctr::Ctr128BE;
 use hmac::{Hmac, Mac};
 use sha2::Sha256;
@@ -17,14 +14,55 @@ use tokio::{
 };
 
 use super::{
+    compression::Compressor,
     crypto,
     protocol::{self, read_message},
 };
 
+/// The biggest packet a client can send us. RFC 4253 says everyone has to
+/// handle packets this big, and the biggest ones clients need to send are
+/// channel data, which we limit to 32768 bytes.
+const MAX_PACKET_LENGTH: usize = 35000;
+/// How much channel data is kept for a client that isn't making room for it in
+/// its window. Every redraw adds more, so a client that never does would
+/// otherwise make it grow forever.
+const MAX_PENDING_DATA: usize = 1024 * 1024;
+
+pub const DISCONNECT_PROTOCOL_ERROR: u32 = 2;
+pub const DISCONNECT_MAC_ERROR: u32 = 5;
+
+/// The client sent something that breaks the protocol, so the connection has
+/// to be closed with a disconnect message saying why.
+#[derive(Debug)]
+pub struct ProtocolError {
+    pub reason_code: u32,
+    pub description: String,
+}
+
+impl ProtocolError {
+    fn new(reason_code: u32, description: impl Into<String>) -> Self {
+        ProtocolError {
+            reason_code,
+            description: description.into(),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "protocol error: {}", self.description)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
 pub struct ReadConnection {
     pub read: OwnedReadHalf,
     pub cipher: Option<Ctr128BE<Aes128>>,
     pub integrity_key: Option<Vec<u8>>,
+    /// Counts every packet, including the ones from before encryption was
+    /// enabled. It's part of the MAC.
+    pub sequence_number: u32,
 }
 
 impl ReadConnection {
@@ -33,9 +71,19 @@ impl ReadConnection {
             read,
             cipher: None,
             integrity_key: None,
+            sequence_number: 0,
         }
     }
 
+    /// Switch to the keys from a key exchange, after the client sends NewKeys.
+    pub fn set_keys(&mut self, encryption_keys: &crypto::EncryptionKeys) {
+        self.set_cipher(
+            &encryption_keys.encryption_key_client_to_server,
+            &encryption_keys.initial_iv_client_to_server,
+        );
+        self.integrity_key = Some(encryption_keys.integrity_key_client_to_server.clone());
+    }
+
     pub fn set_cipher(
         &mut self,
         encryption_key_client_to_server: &[u8],
@@ -60,38 +108,62 @@ impl ReadConnection {
             cipher.apply_keystream(&mut packet_length_bytes);
         }
         let packet_length = u32::from_be_bytes(packet_length_bytes) as usize;
-
-        // read the packet, one byte at a time so we don't allocate a huge buffer immediately
-        let mut packet_bytes = Vec::new();
-        for _ in 0..packet_length {
-            let mut byte = [0u8; 1];
-            self.read.read_exact(&mut byte).await?;
-            packet_bytes.push(byte[0]);
+        if packet_length > MAX_PACKET_LENGTH {
+            bail!(ProtocolError::new(
+                DISCONNECT_PROTOCOL_ERROR,
+                format!("packet is too long ({packet_length} bytes)"),
+            ));
         }
+
+        let mut packet_bytes = vec![0; packet_length];
+        self.read.read_exact(&mut packet_bytes).await?;
         if let Some(cipher) = &mut self.cipher {
             cipher.apply_keystream(&mut packet_bytes);
         }
-        let mut packet_bytes = Cursor::new(packet_bytes);
-
-        // now read the payload
-        let padding_length = ReadBytesExt::read_u8(&mut packet_bytes)? as usize;
-        let payload_length = packet_length - padding_length - 1;
-        let mut payload = Vec::new();
-        for _ in 0..payload_length {
-            payload.push(ReadBytesExt::read_u8(&mut packet_bytes)?);
-        }
 
-        // read the padding
-        let mut padding = vec![0; padding_length];
-        Read::read_exact(&mut packet_bytes, &mut padding)?;
+        if let Some(integrity_key) = &self.integrity_key {
+            // the mac is of the unencrypted packet, and it comes after the
+            // encrypted part
+            let mut expected_mac = Hmac::<Sha256>::new_from_slice(integrity_key)?;
+            expected_mac.update(&self.sequence_number.to_be_bytes());
+            expected_mac.update(&packet_length_bytes);
+            expected_mac.update(&packet_bytes);
 
-        if self.integrity_key.is_some() {
-            // read 32 bytes for the mac-
             let mut mac = [0u8; 32];
             self.read.read_exact(&mut mac).await?;
+            if expected_mac.verify_slice(&mac).is_err() {
+                bail!(ProtocolError::new(
+                    DISCONNECT_MAC_ERROR,
+                    format!("invalid mac on packet {}", self.sequence_number),
+                ));
+            }
+        }
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+
+        // the payload is between the padding length and the padding
+        let Some(&padding_length) = packet_bytes.first() else {
+            bail!(ProtocolError::new(
+                DISCONNECT_PROTOCOL_ERROR,
+                "packet is empty"
+            ));
+        };
+        let padding_length = padding_length as usize;
+        if padding_length < 4 {
+            bail!(ProtocolError::new(
+                DISCONNECT_PROTOCOL_ERROR,
+                format!("padding is too short ({padding_length} bytes)"),
+            ));
         }
+        let Some(payload_length) = packet_length.checked_sub(padding_length + 1) else {
+            bail!(ProtocolError::new(
+                DISCONNECT_PROTOCOL_ERROR,
+                format!(
+                    "padding ({padding_length} bytes) is longer than the packet ({packet_length} bytes)"
+                ),
+            ));
+        };
 
-        Ok(payload)
+        Ok(packet_bytes[1..1 + payload_length].to_vec())
     }
 
     pub async fn read_packet(&mut self) -> anyhow::Result<protocol::Message> {
@@ -110,6 +182,8 @@ pub struct EncryptedConnection {
     sequence_number_server_to_client: u32,
 
     pub channels: HashMap<u32, Channel>,
+    /// Set once compression starts, after the client authenticates.
+    compressor: Option<Compressor>,
 }
 pub struct Channel {
     pub recipient_window_size: u32,
@@ -117,6 +191,25 @@ pub struct Channel {
 
     pub recipient_maximum_packet_size: u32,
     pub _sender_maximum_packet_size: u32,
+
+    /// Data that didn't fit in the client's window yet. It's sent when the
+    /// client adjusts the window.
+    pub pending: Vec<u8>,
+    /// Set by [`EncryptedConnection::finish`], so the channel is closed
+    /// once there's nothing pending.
+    pub exit: Option<Exit>,
+}
+
+/// How the shell or command on a channel ended, which the client is told
+/// before the channel is closed so it can exit with the right code.
+#[derive(Debug, Clone)]
+pub enum Exit {
+    Status(u32),
+    /// Ended abnormally. The signal is named without the `SIG`, like `TERM`.
+    Signal {
+        name: &'static str,
+        message: String,
+    },
 }
 
 impl EncryptedConnection {
@@ -143,11 +236,39 @@ impl EncryptedConnection {
             integrity_key_server_to_client: encryption_keys.integrity_key_server_to_client.clone(),
             sequence_number_server_to_client,
             channels: HashMap::new(),
+            compressor: None,
         })
     }
 
+    /// Switch to the keys from a key exchange, right after we send NewKeys.
+    pub fn set_keys(&mut self, encryption_keys: &crypto::EncryptionKeys) {
+        self.cipher_server_to_client = Ctr128BE::<Aes128>::new(
+            &<[u8; 16]>::try_from(encryption_keys.encryption_key_server_to_client.clone())
+                .unwrap()
+                .into(),
+            &<[u8; 16]>::try_from(encryption_keys.initial_iv_server_to_client.clone())
+                .unwrap()
+                .into(),
+        );
+        self.integrity_key_server_to_client =
+            encryption_keys.integrity_key_server_to_client.clone();
+    }
+
     pub async fn write_packet(&mut self, packet: protocol::Message) -> anyhow::Result<()> {
-        let mut bytes = protocol::write_packet(packet, Some(Ctr128BE::<Aes128>::key_size()))?;
+        self.write_payload(protocol::write_message(packet)?).await
+    }
+
+    /// Compress every payload that's written after this.
+    pub fn start_compression(&mut self) {
+        self.compressor.get_or_insert_with(Compressor::default);
+    }
+
+    pub async fn write_payload(&mut self, payload: Vec<u8>) -> anyhow::Result<()> {
+        let payload = match &mut self.compressor {
+            Some(compressor) => compressor.compress(&payload)?,
+            None => payload,
+        };
+        let mut bytes = protocol::write_payload(payload, Some(Ctr128BE::<Aes128>::key_size()))?;
 
         // write mac
         let mut mac = Hmac::<Sha256>::new_from_slice(&self.integrity_key_server_to_client)?;
@@ -163,17 +284,28 @@ impl EncryptedConnection {
     }
 
     pub async fn write_data(&mut self, data: &[u8], recipient_channel: u32) -> anyhow::Result<()> {
-        if let Some(channel) = self.channels.get_mut(&recipient_channel) {
-            channel.recipient_window_size -= data.len() as u32;
-        }
+        // only send as much as fits in the client's window, and keep the rest
+        // for when it's adjusted
+        let (data, max_packet_size) = match self.channels.get_mut(&recipient_channel) {
+            Some(channel) => {
+                if channel.pending.len() + data.len() > MAX_PENDING_DATA {
+                    bail!("the client stopped making room for channel data");
+                }
+                channel.pending.extend_from_slice(data);
+                let length = channel
+                    .pending
+                    .len()
+                    .min(channel.recipient_window_size as usize);
+                channel.recipient_window_size -= length as u32;
+                (
+                    channel.pending.drain(..length).collect::<Vec<_>>(),
+                    channel.recipient_maximum_packet_size,
+                )
+            }
+            None => (data.to_vec(), 32768),
+        };
 
-        let max_packet_size = self
-            .channels
-            .get(&recipient_channel)
-            .map(|channel| channel.recipient_maximum_packet_size)
-            .unwrap_or(32768);
-
-        for chunk in data.chunks(max_packet_size as usize) {
+        for chunk in data.chunks(max_packet_size.max(1) as usize) {
             self.write_packet(protocol::Message::ChannelData {
                 recipient_channel,
                 data: chunk.to_vec(),
@@ -181,6 +313,62 @@ impl EncryptedConnection {
             .await?;
         }
 
+        let exit = self
+            .channels
+            .get_mut(&recipient_channel)
+            .filter(|channel| channel.pending.is_empty())
+            .and_then(|channel| channel.exit.take());
+        if let Some(exit) = exit {
+            self.channels.remove(&recipient_channel);
+            let (request_type, extra) = match exit {
+                Exit::Status(exit_status) => (
+                    "exit-status",
+                    protocol::ChannelRequestExtra::ExitStatus { exit_status },
+                ),
+                Exit::Signal { name, message } => (
+                    "exit-signal",
+                    protocol::ChannelRequestExtra::ExitSignal {
+                        signal_name: name.to_string(),
+                        core_dumped: false,
+                        error_message: message,
+                        language_tag: "".to_string(),
+                    },
+                ),
+            };
+            self.write_packet(protocol::Message::ChannelRequest {
+                recipient_channel,
+                request_type: request_type.to_string(),
+                want_reply: false,
+                extra,
+            })
+            .await?;
+            self.write_packet(protocol::Message::ChannelEof { recipient_channel })
+                .await?;
+            self.write_packet(protocol::Message::ChannelClose { recipient_channel })
+                .await?;
+        }
+
         Ok(())
     }
+
+    /// Close the channel like a command that exited, after sending everything
+    /// that's still waiting for the client's window.
+    pub async fn finish(&mut self, recipient_channel: u32, exit: Exit) -> anyhow::Result<()> {
+        if let Some(channel) = self.channels.get_mut(&recipient_channel) {
+            channel.exit = Some(exit);
+        }
+        self.write_data(&[], recipient_channel).await
+    }
+
+    pub async fn adjust_window(
+        &mut self,
+        recipient_channel: u32,
+        bytes_to_add: u32,
+    ) -> anyhow::Result<()> {
+        if let Some(channel) = self.channels.get_mut(&recipient_channel) {
+            channel.recipient_window_size =
+                channel.recipient_window_size.saturating_add(bytes_to_add);
+        }
+        self.write_data(&[], recipient_channel).await
+    }
 }
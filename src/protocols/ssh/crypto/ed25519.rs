@@ -1,6 +1,12 @@
-use std::{fs, io::Read, path::Path};
+use std::{
+    fs,
+    io::{Cursor, Read},
+    path::Path,
+};
 
-use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use anyhow::bail;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
 
 use crate::protocols::ssh::protocol;
 
@@ -27,13 +33,13 @@ pub fn load_keypair() -> SigningKey {
     if let Ok(key) = keypair_bytes
         .as_slice()
         .try_into()
-        .map(|secret_key: &[u8; 32]| SigningKey::from_bytes(&secret_key))
+        .map(|secret_key: &[u8; 32]| SigningKey::from_bytes(secret_key))
     {
         key
     } else if let Ok(key) = keypair_bytes
         .as_slice()
         .try_into()
-        .map(|secret_key: &[u8; 64]| SigningKey::from_keypair_bytes(&secret_key))
+        .map(|secret_key: &[u8; 64]| SigningKey::from_keypair_bytes(secret_key))
     {
         key.unwrap()
     } else {
@@ -53,7 +59,7 @@ fn generate_new_keypair() -> SigningKey {
     keypair
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Exchange {
     /// client's identification string (CR and LF excluded)
     pub client_id: Vec<u8>,
@@ -91,8 +97,7 @@ pub fn compute_exchange_hash(
         protocol::write_mpint(&mut buffer, shared)?;
     }
 
-    use sha2::Digest;
-    let mut hasher = sha2::Sha256::new();
+    let mut hasher = Sha256::new();
     hasher.update(&buffer);
 
     let mut res = Vec::new();
@@ -108,3 +113,53 @@ pub fn add_signature(keypair: &SigningKey, to_sign: &[u8]) -> anyhow::Result<Vec
 
     Ok(buffer)
 }
+
+/// Check that the message was signed by the key, with both in the format
+/// they're sent in.
+pub fn verify_signature(public_key: &[u8], signature: &[u8], message: &[u8]) -> anyhow::Result<()> {
+    let mut public_key = Cursor::new(public_key);
+    let mut signature = Cursor::new(signature);
+    for key_type in [
+        protocol::read_string(&mut public_key)?,
+        protocol::read_string(&mut signature)?,
+    ] {
+        if key_type != "ssh-ed25519" {
+            bail!("expected an ssh-ed25519 key, got {key_type}");
+        }
+    }
+
+    let public_key = <[u8; 32]>::try_from(protocol::read_bytes(&mut public_key)?.as_slice())?;
+    let signature = <[u8; 64]>::try_from(protocol::read_bytes(&mut signature)?.as_slice())?;
+    VerifyingKey::from_bytes(&public_key)?.verify(message, &Signature::from_bytes(&signature))?;
+    Ok(())
+}
+
+/// What the client signs to authenticate with a public key, see
+/// https://datatracker.ietf.org/doc/html/rfc4252#section-7
+pub fn userauth_signed_data(
+    session_id: &[u8],
+    username: &str,
+    service_name: &str,
+    algorithm: &str,
+    public_key: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    protocol::write_bytes(&mut buffer, session_id)?;
+    // SSH_MSG_USERAUTH_REQUEST
+    buffer.push(50);
+    protocol::write_string(&mut buffer, username)?;
+    protocol::write_string(&mut buffer, service_name)?;
+    protocol::write_string(&mut buffer, "publickey")?;
+    buffer.push(1);
+    protocol::write_string(&mut buffer, algorithm)?;
+    protocol::write_bytes(&mut buffer, public_key)?;
+    Ok(buffer)
+}
+
+/// The SHA-256 of a public key, in hex so it can be used as a file name.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    Sha256::digest(public_key)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
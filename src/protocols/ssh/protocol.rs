@@ -60,7 +60,7 @@ pub enum Message {
         username: String,
         service_name: String,
         authentication_method: String,
-        // depends
+        extra: UserauthRequestExtra,
     } = 50,
     UserauthFailure {
         authentication_methods: Vec<String>,
@@ -71,6 +71,12 @@ pub enum Message {
         message: String,
         language_tag: String,
     } = 53,
+    /// The public key in a [`UserauthRequestExtra::PublicKey`] without a
+    /// signature can be used, so the client should sign with it.
+    UserauthPkOk {
+        algorithm: String,
+        public_key: Vec<u8>,
+    } = 60,
 
     GlobalRequest {
         request_name: String,
@@ -134,6 +140,18 @@ pub enum Message {
     } = 100,
 }
 
+#[derive(Debug)]
+pub enum UserauthRequestExtra {
+    /// https://datatracker.ietf.org/doc/html/rfc4252#section-7
+    PublicKey {
+        algorithm: String,
+        public_key: Vec<u8>,
+        /// `None` when the client is only asking whether the key can be used.
+        signature: Option<Vec<u8>>,
+    },
+    None,
+}
+
 #[derive(Debug)]
 pub enum ChannelRequestExtra {
     Terminal {
@@ -154,6 +172,19 @@ pub enum ChannelRequestExtra {
         command: String,
     },
     Shell,
+    /// Sent by the server when the shell or command is done.
+    ExitStatus {
+        exit_status: u32,
+    },
+    /// Sent by the server instead of an exit status when the shell or command
+    /// was ended abnormally.
+    ExitSignal {
+        /// Without the `SIG`, like `TERM`.
+        signal_name: String,
+        core_dumped: bool,
+        error_message: String,
+        language_tag: String,
+    },
     None,
 }
 
@@ -283,10 +314,26 @@ pub fn read_message(mut data: impl Read) -> anyhow::Result<Message> {
             let username = read_string(&mut data)?;
             let service_name = read_string(&mut data)?;
             let authentication_method = read_string(&mut data)?;
+            let extra = match authentication_method.as_str() {
+                "publickey" => {
+                    let has_signature = data.read_u8()? != 0;
+                    UserauthRequestExtra::PublicKey {
+                        algorithm: read_string(&mut data)?,
+                        public_key: read_bytes(&mut data)?,
+                        signature: if has_signature {
+                            Some(read_bytes(&mut data)?)
+                        } else {
+                            None
+                        },
+                    }
+                }
+                _ => UserauthRequestExtra::None,
+            };
             Ok(Message::UserauthRequest {
                 username,
                 service_name,
                 authentication_method,
+                extra,
             })
         }
         51 => {
@@ -306,6 +353,14 @@ pub fn read_message(mut data: impl Read) -> anyhow::Result<Message> {
                 language_tag,
             })
         }
+        60 => {
+            let algorithm = read_string(&mut data)?;
+            let public_key = read_bytes(&mut data)?;
+            Ok(Message::UserauthPkOk {
+                algorithm,
+                public_key,
+            })
+        }
         80 => {
             let request_name = read_string(&mut data)?;
             let want_reply = data.read_u8()? != 0;
@@ -410,6 +465,15 @@ pub fn read_message(mut data: impl Read) -> anyhow::Result<Message> {
                     command: read_string(&mut data)?,
                 },
                 "shell" => ChannelRequestExtra::Shell,
+                "exit-status" => ChannelRequestExtra::ExitStatus {
+                    exit_status: data.read_u32::<BE>()?,
+                },
+                "exit-signal" => ChannelRequestExtra::ExitSignal {
+                    signal_name: read_string(&mut data)?,
+                    core_dumped: data.read_u8()? != 0,
+                    error_message: read_string(&mut data)?,
+                    language_tag: read_string(&mut data)?,
+                },
                 _ => ChannelRequestExtra::None,
             };
 
@@ -524,11 +588,27 @@ pub fn write_message(message: Message) -> anyhow::Result<Vec<u8>> {
             username,
             service_name,
             authentication_method,
+            extra,
         } => {
             buf.write_u8(50)?;
             write_string(&mut buf, &username)?;
             write_string(&mut buf, &service_name)?;
             write_string(&mut buf, &authentication_method)?;
+            match extra {
+                UserauthRequestExtra::PublicKey {
+                    algorithm,
+                    public_key,
+                    signature,
+                } => {
+                    buf.write_u8(u8::from(signature.is_some()))?;
+                    write_string(&mut buf, &algorithm)?;
+                    write_bytes(&mut buf, &public_key)?;
+                    if let Some(signature) = signature {
+                        write_bytes(&mut buf, &signature)?;
+                    }
+                }
+                UserauthRequestExtra::None => {}
+            }
         }
         Message::UserauthFailure {
             authentication_methods,
@@ -549,6 +629,14 @@ pub fn write_message(message: Message) -> anyhow::Result<Vec<u8>> {
             write_string(&mut buf, &message)?;
             write_string(&mut buf, &language_tag)?;
         }
+        Message::UserauthPkOk {
+            algorithm,
+            public_key,
+        } => {
+            buf.write_u8(60)?;
+            write_string(&mut buf, &algorithm)?;
+            write_bytes(&mut buf, &public_key)?;
+        }
         Message::GlobalRequest {
             request_name,
             want_reply,
@@ -676,6 +764,20 @@ pub fn write_message(message: Message) -> anyhow::Result<Vec<u8>> {
                 ChannelRequestExtra::Shell => {
                     // nothing
                 }
+                ChannelRequestExtra::ExitStatus { exit_status } => {
+                    buf.write_u32::<BE>(exit_status)?;
+                }
+                ChannelRequestExtra::ExitSignal {
+                    signal_name,
+                    core_dumped,
+                    error_message,
+                    language_tag,
+                } => {
+                    write_string(&mut buf, &signal_name)?;
+                    buf.write_u8(if core_dumped { 1 } else { 0 })?;
+                    write_string(&mut buf, &error_message)?;
+                    write_string(&mut buf, &language_tag)?;
+                }
                 ChannelRequestExtra::None => todo!(),
             }
         }
@@ -1,22 +1,20 @@
-use std::{io::Cursor, time::Duration};
+mod codec;
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use anyhow::bail;
-use byteorder::{ReadBytesExt, BE};
 use futures_util::StreamExt;
 use tokio::{
     io::AsyncWriteExt,
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener,
-    },
+    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
 };
 use tokio_util::codec::FramedRead;
 
-use crate::{crawl::SiteData, terminal::TerminalSession};
+use crate::{crawl::SiteData, lifecycle, listen::Listener, motd, terminal::TerminalSession};
 
-use super::Protocol;
+use super::{qotd::Qotd, Protocol, ServerConfig, Shutdown};
+use codec::{Event, TelnetCodec};
 
-const BIND_HOST: &str = "[::]";
 const BIND_PORT: u16 = {
     #[cfg(debug_assertions)]
     {
@@ -28,38 +26,47 @@ const BIND_PORT: u16 = {
 
 #[derive(Clone)]
 pub struct Telnet {
-    pub site_data: SiteData,
+    pub site_data: Arc<SiteData>,
+    /// Shown when someone connects.
+    pub qotd: Qotd,
 }
 
 impl Protocol for Telnet {
-    fn generate(data: &SiteData) -> Self {
+    type Options = Qotd;
+
+    fn generate(data: &SiteData, qotd: &Self::Options) -> Self {
         Telnet {
-            site_data: data.clone(),
+            site_data: Arc::new(data.clone()),
+            qotd: qotd.clone(),
         }
     }
 
-    async fn serve(self) {
-        let listener = match TcpListener::bind(format!("{BIND_HOST}:{BIND_PORT}")).await {
-            Ok(listener) => listener,
-            Err(e) => {
-                eprintln!("failed to bind to port {BIND_PORT}: {e}");
-                return;
-            }
-        };
+    async fn serve(self, config: &ServerConfig, shutdown: Shutdown) {
+        let port = config.port_or(BIND_PORT);
+        shutdown
+            .run(async move {
+                let Some(listener) = Listener::bind("telnet", port) else {
+                    return;
+                };
 
-        loop {
-            let (stream, remote_addr) = listener.accept().await.unwrap();
-            println!("started tcp connection for telnet: {remote_addr:?}");
+                loop {
+                    let (stream, remote_addr) = listener.accept().await.unwrap();
+                    println!("started tcp connection for telnet: {remote_addr:?}");
 
-            let (read, write) = stream.into_split();
+                    let (read, write) = stream.into_split();
 
-            let site_data = self.site_data.clone();
-            tokio::spawn(async move {
-                if let Err(e) = connection(read, write, site_data).await {
-                    println!("error: {e}");
+                    let site_data = self.site_data.clone();
+                    let qotd = self.qotd.clone();
+                    tokio::spawn(async move {
+                        let _session = lifecycle::Session::start("telnet");
+                        if let Err(e) = connection(read, write, site_data, qotd, remote_addr).await
+                        {
+                            println!("error: {e}");
+                        }
+                    });
                 }
-            });
-        }
+            })
+            .await;
     }
 }
 
@@ -73,22 +80,75 @@ enum Command {
 }
 #[derive(Clone, Copy, Debug)]
 enum Opt {
+    Binary = 0,
     Echo = 1,
     SuppressGoAhead = 3,
+    TerminalType = 24,
     WindowSize = 31,
     LineMode = 34,
+    NewEnviron = 39,
+    Charset = 42,
 }
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 enum Subnegotiation {
-    WindowSize { width: u16, height: u16 },
+    WindowSize {
+        width: u16,
+        height: u16,
+    },
+    /// The client's response to [`Subnegotiation::TerminalTypeSend`].
+    TerminalTypeIs {
+        terminal_type: String,
+    },
+    /// Ask the client for its terminal type.
+    TerminalTypeSend,
+    /// The client's response to an [`Subnegotiation::EnvironmentSend`].
+    EnvironmentIs {
+        variables: Vec<(String, String)>,
+    },
+    /// Ask the client for the values of these variables.
+    EnvironmentSend {
+        variables: Vec<String>,
+    },
+    /// Ask the other side to pick one of these charsets, in order of
+    /// preference.
+    CharsetRequest {
+        charsets: Vec<String>,
+    },
+    CharsetAccepted {
+        charset: String,
+    },
+    /// None of the charsets in the request are supported.
+    CharsetRejected,
 }
+
+// https://datatracker.ietf.org/doc/html/rfc1091
+const TERMINAL_TYPE_IS: u8 = 0;
+const TERMINAL_TYPE_SEND: u8 = 1;
+
+// https://datatracker.ietf.org/doc/html/rfc1572
+const ENVIRON_IS: u8 = 0;
+const ENVIRON_SEND: u8 = 1;
+const ENVIRON_INFO: u8 = 2;
+const ENVIRON_VAR: u8 = 0;
+const ENVIRON_VALUE: u8 = 1;
+const ENVIRON_ESC: u8 = 2;
+const ENVIRON_USERVAR: u8 = 3;
+
+// https://datatracker.ietf.org/doc/html/rfc2066
+const CHARSET_REQUEST: u8 = 1;
+const CHARSET_ACCEPTED: u8 = 2;
+const CHARSET_REJECTED: u8 = 3;
 impl Opt {
     fn from_u8(byte: u8) -> Option<Opt> {
         match byte {
+            0 => Some(Opt::Binary),
             1 => Some(Opt::Echo),
             3 => Some(Opt::SuppressGoAhead),
+            24 => Some(Opt::TerminalType),
             31 => Some(Opt::WindowSize),
             34 => Some(Opt::LineMode),
+            39 => Some(Opt::NewEnviron),
+            42 => Some(Opt::Charset),
             _ => None,
         }
     }
@@ -97,8 +157,7 @@ impl Opt {
         self as u8
     }
 
-    fn read(read: &mut Cursor<Vec<u8>>) -> anyhow::Result<Self> {
-        let byte = read.read_u8()?;
+    fn parse(byte: u8) -> anyhow::Result<Self> {
         match Self::from_u8(byte) {
             Some(opt) => Ok(opt),
             None => bail!("unknown option {byte}"),
@@ -107,57 +166,181 @@ impl Opt {
 }
 
 const IAC: u8 = 255;
+const SUBNEGOTIATION: u8 = 250;
 const END_SUBNEGOTIATION: u8 = 240;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
 
-impl Command {
-    fn read(read: &mut Cursor<Vec<u8>>) -> anyhow::Result<Self> {
-        let byte = read.read_u8()?;
-        match byte {
-            250 => {
-                let opt = Opt::read(read)?;
-                match opt {
-                    Opt::WindowSize => {
-                        let width = read.read_u16::<BE>()?;
-                        let height = read.read_u16::<BE>()?;
-                        let _ = read.read_u8()?; // iac
-                        let _ = read.read_u8()?; // end subnegotiation
-                        Ok(Command::Subnegotiation(Subnegotiation::WindowSize {
-                            width,
-                            height,
-                        }))
+impl Subnegotiation {
+    /// Parse what's between `IAC SB <option>` and `IAC SE`. Escaped IACs in
+    /// the data have to be unescaped already.
+    fn parse(opt: Opt, data: &[u8]) -> anyhow::Result<Self> {
+        match opt {
+            Opt::WindowSize => {
+                let [w1, w2, h1, h2] = *data else {
+                    bail!("window size subnegotiation has {} bytes", data.len());
+                };
+                Ok(Subnegotiation::WindowSize {
+                    width: u16::from_be_bytes([w1, w2]),
+                    height: u16::from_be_bytes([h1, h2]),
+                })
+            }
+            Opt::TerminalType => {
+                let Some((&TERMINAL_TYPE_IS, terminal_type)) = data.split_first() else {
+                    bail!("unexpected terminal type subnegotiation {data:?}");
+                };
+                Ok(Subnegotiation::TerminalTypeIs {
+                    terminal_type: terminal_type.iter().map(|&b| b as char).collect(),
+                })
+            }
+            Opt::NewEnviron => {
+                let Some((&kind, data)) = data.split_first() else {
+                    bail!("empty environment subnegotiation");
+                };
+                if kind != ENVIRON_IS && kind != ENVIRON_INFO {
+                    bail!("unexpected environment subnegotiation {kind}");
+                }
+
+                let mut variables = Vec::<(String, String)>::new();
+                let mut is_value = false;
+                let mut bytes = data.iter().copied();
+                while let Some(mut byte) = bytes.next() {
+                    match byte {
+                        ENVIRON_VAR | ENVIRON_USERVAR => {
+                            variables.push(Default::default());
+                            is_value = false;
+                            continue;
+                        }
+                        ENVIRON_VALUE => {
+                            is_value = true;
+                            continue;
+                        }
+                        ENVIRON_ESC => {
+                            let Some(escaped) = bytes.next() else {
+                                break;
+                            };
+                            byte = escaped;
+                        }
+                        _ => {}
+                    }
+                    if let Some((name, value)) = variables.last_mut() {
+                        if is_value {
+                            value.push(byte as char);
+                        } else {
+                            name.push(byte as char);
+                        }
                     }
-                    _ => bail!("unknown subnegotiation {opt:?}"),
                 }
+                Ok(Subnegotiation::EnvironmentIs { variables })
             }
-            251 => Ok(Command::Will(Opt::read(read)?)),
-            252 => Ok(Command::Wont(Opt::read(read)?)),
-            253 => Ok(Command::Do(Opt::read(read)?)),
-            254 => Ok(Command::Dont(Opt::read(read)?)),
-            _ => bail!("unknown command {byte}"),
+            Opt::Charset => {
+                let Some((&kind, data)) = data.split_first() else {
+                    bail!("empty charset subnegotiation");
+                };
+                let data = String::from_utf8_lossy(data);
+                match kind {
+                    CHARSET_REQUEST => {
+                        // we don't do translation tables
+                        let data = data.strip_prefix("[TTABLE]").unwrap_or(&data);
+                        // the first character is the separator
+                        let mut chars = data.chars();
+                        let charsets = match chars.next() {
+                            Some(separator) => {
+                                chars.as_str().split(separator).map(str::to_owned).collect()
+                            }
+                            None => Vec::new(),
+                        };
+                        Ok(Subnegotiation::CharsetRequest { charsets })
+                    }
+                    CHARSET_ACCEPTED => Ok(Subnegotiation::CharsetAccepted {
+                        charset: data.into_owned(),
+                    }),
+                    CHARSET_REJECTED => Ok(Subnegotiation::CharsetRejected),
+                    _ => bail!("unexpected charset subnegotiation {kind}"),
+                }
+            }
+            _ => bail!("unknown subnegotiation {opt:?}"),
         }
     }
+}
+
+impl Command {
+    /// Parse a whole command, starting with the IAC. Returns `None` for
+    /// commands we don't care about, like `NOP`.
+    fn parse(bytes: &[u8]) -> anyhow::Result<Option<Self>> {
+        let command = match bytes {
+            [IAC, SUBNEGOTIATION, opt, data @ .., IAC, END_SUBNEGOTIATION] => {
+                let opt = Opt::parse(*opt)?;
+                let data = unescape(data);
+                Command::Subnegotiation(Subnegotiation::parse(opt, &data)?)
+            }
+            [IAC, WILL, opt] => Command::Will(Opt::parse(*opt)?),
+            [IAC, WONT, opt] => Command::Wont(Opt::parse(*opt)?),
+            [IAC, DO, opt] => Command::Do(Opt::parse(*opt)?),
+            [IAC, DONT, opt] => Command::Dont(Opt::parse(*opt)?),
+            [IAC, _] => return Ok(None),
+            _ => bail!("invalid command {bytes:?}"),
+        };
+        Ok(Some(command))
+    }
 
     async fn write(&self, write: &mut OwnedWriteHalf) -> anyhow::Result<()> {
         let mut buf = vec![IAC];
         match self {
             Command::Subnegotiation(subnegotiation) => {
-                buf.push(250);
+                buf.push(SUBNEGOTIATION);
+                let mut data = Vec::new();
                 match subnegotiation {
                     Subnegotiation::WindowSize { width, height } => {
-                        buf.extend_from_slice(&[
-                            31,
-                            width.to_be_bytes()[0],
-                            width.to_be_bytes()[1],
-                        ]);
-                        buf.extend_from_slice(&[height.to_be_bytes()[0], height.to_be_bytes()[1]]);
+                        data.push(Opt::WindowSize.to_u8());
+                        data.extend_from_slice(&width.to_be_bytes());
+                        data.extend_from_slice(&height.to_be_bytes());
+                    }
+                    Subnegotiation::TerminalTypeIs { terminal_type } => {
+                        data.extend_from_slice(&[Opt::TerminalType.to_u8(), TERMINAL_TYPE_IS]);
+                        data.extend_from_slice(terminal_type.as_bytes());
+                    }
+                    Subnegotiation::TerminalTypeSend => {
+                        data.extend_from_slice(&[Opt::TerminalType.to_u8(), TERMINAL_TYPE_SEND]);
+                    }
+                    Subnegotiation::EnvironmentIs { variables } => {
+                        data.extend_from_slice(&[Opt::NewEnviron.to_u8(), ENVIRON_IS]);
+                        for (name, value) in variables {
+                            data.push(ENVIRON_VAR);
+                            data.extend_from_slice(name.as_bytes());
+                            data.push(ENVIRON_VALUE);
+                            data.extend_from_slice(value.as_bytes());
+                        }
+                    }
+                    Subnegotiation::EnvironmentSend { variables } => {
+                        data.extend_from_slice(&[Opt::NewEnviron.to_u8(), ENVIRON_SEND]);
+                        for name in variables {
+                            data.push(ENVIRON_USERVAR);
+                            data.extend_from_slice(name.as_bytes());
+                        }
+                    }
+                    Subnegotiation::CharsetRequest { charsets } => {
+                        data.extend_from_slice(&[Opt::Charset.to_u8(), CHARSET_REQUEST]);
+                        data.extend_from_slice(format!(";{}", charsets.join(";")).as_bytes());
+                    }
+                    Subnegotiation::CharsetAccepted { charset } => {
+                        data.extend_from_slice(&[Opt::Charset.to_u8(), CHARSET_ACCEPTED]);
+                        data.extend_from_slice(charset.as_bytes());
+                    }
+                    Subnegotiation::CharsetRejected => {
+                        data.extend_from_slice(&[Opt::Charset.to_u8(), CHARSET_REJECTED]);
                     }
                 }
+                // the data can't have a lone IAC, since that would end it
+                buf.extend(escape(&data));
                 buf.extend_from_slice(&[IAC, END_SUBNEGOTIATION]);
             }
-            Command::Will(opt) => buf.extend_from_slice(&[251, opt.to_u8()]),
-            Command::Wont(opt) => buf.extend_from_slice(&[252, opt.to_u8()]),
-            Command::Do(opt) => buf.extend_from_slice(&[253, opt.to_u8()]),
-            Command::Dont(opt) => buf.extend_from_slice(&[254, opt.to_u8()]),
+            Command::Will(opt) => buf.extend_from_slice(&[WILL, opt.to_u8()]),
+            Command::Wont(opt) => buf.extend_from_slice(&[WONT, opt.to_u8()]),
+            Command::Do(opt) => buf.extend_from_slice(&[DO, opt.to_u8()]),
+            Command::Dont(opt) => buf.extend_from_slice(&[DONT, opt.to_u8()]),
         };
         write.write_all(&buf).await?;
         Ok(())
@@ -167,9 +350,11 @@ impl Command {
 async fn connection(
     read: OwnedReadHalf,
     mut write: OwnedWriteHalf,
-    site_data: SiteData,
+    site_data: Arc<SiteData>,
+    qotd: Qotd,
+    remote_addr: SocketAddr,
 ) -> anyhow::Result<()> {
-    let mut read = FramedRead::new(read, tokio_util::codec::BytesCodec::new());
+    let mut read = FramedRead::new(read, TelnetCodec);
 
     Command::Will(Opt::Echo).write(&mut write).await?;
     Command::Will(Opt::SuppressGoAhead)
@@ -177,64 +362,212 @@ async fn connection(
         .await?;
     Command::Wont(Opt::LineMode).write(&mut write).await?;
     Command::Do(Opt::WindowSize).write(&mut write).await?;
+    Command::Do(Opt::NewEnviron).write(&mut write).await?;
+    Command::Do(Opt::TerminalType).write(&mut write).await?;
+    // utf-8 needs 8-bit bytes, which telnet only allows in binary mode
+    Command::Will(Opt::Binary).write(&mut write).await?;
+    Command::Do(Opt::Binary).write(&mut write).await?;
+    Command::Will(Opt::Charset).write(&mut write).await?;
+
+    // shown before the terminal takes over the screen
+    let greeting = motd::compose(motd::fragments(), &site_data, &qotd);
+    write
+        .write_all(format!("{greeting}\n").replace('\n', "\r\n").as_bytes())
+        .await?;
 
-    let mut terminal_session = TerminalSession::new(site_data);
+    let mut terminal_session = TerminalSession::new(site_data, "telnet", remote_addr.ip());
+    terminal_session.set_motd(&qotd);
 
     write.write_all(&terminal_session.on_open()).await?;
 
-    loop {
-        let Ok(read_result) = tokio::time::timeout(Duration::from_millis(100), read.next()).await
-        else {
-            // get window size every second
-            Command::Do(Opt::WindowSize).write(&mut write).await?;
-            continue;
-        };
-        let Some(data) = read_result.transpose()? else {
-            break;
-        };
-        println!("{data:?}");
-        let mut data = Cursor::new(data.to_vec());
-
-        while data.split().1.starts_with(&[IAC]) {
-            let _ = data.read_u8()?;
-            let command = match Command::read(&mut data) {
-                Ok(command) => command,
-                Err(err) => {
-                    println!("{err}");
+    // redraw every second so things like the clock are updated
+    let mut redraw_interval = tokio::time::interval(Duration::from_secs(1));
+    // so a new quote shows up without waiting for the client to do anything
+    let mut qotd_changed = qotd.subscribe();
+    // in a block so the client's terminal is put back however the
+    // connection ends
+    let result: anyhow::Result<()> = async {
+        loop {
+            // resizes don't need to be polled for, since clients that agreed
+            // to NAWS send a subnegotiation whenever the window changes
+            let event = tokio::select! {
+                event = read.next() => event,
+                _ = redraw_interval.tick() => {
+                    write.write_all(&terminal_session.draw()).await?;
                     continue;
                 }
-            };
-            match command {
-                Command::Will(opt) => {
-                    Command::Dont(opt).write(&mut write).await?;
+                Ok(()) = qotd_changed.changed() => {
+                    terminal_session.set_motd(&qotd);
+                    write.write_all(&terminal_session.draw()).await?;
+                    continue;
                 }
-                Command::Wont(_) => {}
-                Command::Do(_) => {}
-                Command::Dont(_) => {}
-                Command::Subnegotiation(subnegotiation) => match subnegotiation {
-                    Subnegotiation::WindowSize { width, height } => {
-                        write
-                            .write_all(&terminal_session.resize(width as u32, height as u32))
+            };
+            let Some(event) = event.transpose()? else {
+                break;
+            };
+            let data = match event {
+                Event::Data(data) => data,
+                Event::Command(command) => {
+                    match command {
+                        Command::Will(Opt::NewEnviron) => {
+                            // the theme can be picked with an environment variable, like
+                            // `telnet -l light matdoes.dev` or by setting THEME
+                            Command::Subnegotiation(Subnegotiation::EnvironmentSend {
+                                variables: ["USER", "THEME", "LANG", "LC_ALL", "LC_CTYPE"]
+                                    .map(str::to_string)
+                                    .to_vec(),
+                            })
+                            .write(&mut write)
                             .await?;
+                        }
+                        Command::Will(Opt::TerminalType) => {
+                            Command::Subnegotiation(Subnegotiation::TerminalTypeSend)
+                                .write(&mut write)
+                                .await?;
+                        }
+                        Command::Will(Opt::Charset) => {
+                            // they'll send a request with the charsets they want
+                            Command::Do(Opt::Charset).write(&mut write).await?;
+                        }
+                        // we already asked for binary mode
+                        Command::Will(Opt::Binary) => {}
+                        Command::Will(opt) => {
+                            Command::Dont(opt).write(&mut write).await?;
+                        }
+                        Command::Do(Opt::Charset) => {
+                            Command::Subnegotiation(Subnegotiation::CharsetRequest {
+                                charsets: vec!["UTF-8".to_string(), "US-ASCII".to_string()],
+                            })
+                            .write(&mut write)
+                            .await?;
+                        }
+                        Command::Dont(Opt::Binary) => {
+                            // they can't receive anything that isn't 7-bit ascii
+                            terminal_session.set_utf8(false);
+                            write.write_all(&terminal_session.draw()).await?;
+                        }
+                        Command::Wont(_) => {}
+                        Command::Do(_) => {}
+                        Command::Dont(_) => {}
+                        Command::Subnegotiation(subnegotiation) => match subnegotiation {
+                            Subnegotiation::WindowSize { width, height } => {
+                                write
+                                    .write_all(
+                                        &terminal_session.resize(width as u32, height as u32),
+                                    )
+                                    .await?;
+                            }
+                            Subnegotiation::EnvironmentIs { variables } => {
+                                // THEME takes priority over USER
+                                for name in ["USER", "THEME"] {
+                                    if let Some((_, value)) =
+                                        variables.iter().find(|(n, _)| n == name)
+                                    {
+                                        terminal_session.set_theme(value);
+                                    }
+                                }
+                                // the locale says which charset their terminal uses,
+                                // and LC_ALL overrides the others
+                                let locale =
+                                    ["LC_ALL", "LC_CTYPE", "LANG"].iter().find_map(|name| {
+                                        variables
+                                            .iter()
+                                            .find(|(n, value)| n == *name && !value.is_empty())
+                                    });
+                                if let Some((_, locale)) = locale {
+                                    let locale = locale.to_lowercase();
+                                    terminal_session.set_utf8(
+                                        locale.contains("utf-8") || locale.contains("utf8"),
+                                    );
+                                }
+                                write.write_all(&terminal_session.draw()).await?;
+                            }
+                            Subnegotiation::TerminalTypeIs { terminal_type } => {
+                                terminal_session.set_terminal_type(&terminal_type);
+                                write.write_all(&terminal_session.draw()).await?;
+                            }
+                            Subnegotiation::CharsetRequest { charsets } => {
+                                // we can send any charset that's a superset of ascii,
+                                // but only utf-8 gets anything that isn't ascii
+                                let reply = match charsets.iter().find(|charset| is_utf8(charset)) {
+                                    Some(charset) => {
+                                        terminal_session.set_utf8(true);
+                                        Subnegotiation::CharsetAccepted {
+                                            charset: charset.clone(),
+                                        }
+                                    }
+                                    None => match charsets.first() {
+                                        Some(charset) => {
+                                            terminal_session.set_utf8(false);
+                                            Subnegotiation::CharsetAccepted {
+                                                charset: charset.clone(),
+                                            }
+                                        }
+                                        None => Subnegotiation::CharsetRejected,
+                                    },
+                                };
+                                Command::Subnegotiation(reply).write(&mut write).await?;
+                                write.write_all(&terminal_session.draw()).await?;
+                            }
+                            Subnegotiation::CharsetAccepted { charset } => {
+                                terminal_session.set_utf8(is_utf8(&charset));
+                                write.write_all(&terminal_session.draw()).await?;
+                            }
+                            Subnegotiation::CharsetRejected => {
+                                // not even us-ascii, but that's the best we can do
+                                terminal_session.set_utf8(false);
+                                write.write_all(&terminal_session.draw()).await?;
+                            }
+                            Subnegotiation::EnvironmentSend { .. }
+                            | Subnegotiation::TerminalTypeSend => {}
+                        },
                     }
-                },
+                    continue;
+                }
+            };
+            let data = data.strip_suffix(b"\0").unwrap_or(&data);
+            if terminal_session.is_exit_key(data) {
+                break;
             }
-            continue;
+            let out = terminal_session.on_keystroke(data);
+            write.write_all(&out).await?;
         }
-        let data = data.split().1;
-        let data = match data.strip_suffix(b"\0") {
-            Some(data) => data,
-            None => data,
-        };
-        if data == [3] || data == [4] {
-            write.write_all(&terminal_session.on_close()).await?;
-            write.write_all(b"Bye!\r\n").await?;
-            break;
-        }
-        let out = terminal_session.on_keystroke(data);
-        write.write_all(&out).await?;
+        Ok(())
     }
+    .await;
+    // whether the loop ended normally or not
+    let _ = write.write_all(&terminal_session.on_close()).await;
     println!("connection closed");
 
-    Ok(())
+    result
+}
+
+fn is_utf8(charset: &str) -> bool {
+    charset.eq_ignore_ascii_case("utf-8") || charset.eq_ignore_ascii_case("utf8")
+}
+
+/// Turn `IAC IAC` back into a single 255 byte.
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter().copied();
+    while let Some(byte) = bytes.next() {
+        out.push(byte);
+        if byte == IAC {
+            // skip the second one
+            bytes.next();
+        }
+    }
+    out
+}
+
+/// Double every 255 byte so it isn't read as an IAC.
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &byte in data {
+        out.push(byte);
+        if byte == IAC {
+            out.push(IAC);
+        }
+    }
+    out
 }
@@ -0,0 +1,116 @@
+//! Splitting what the client sends into keystrokes and telnet commands.
+//! Commands can show up anywhere in the stream, even in the middle of
+//! keystrokes, and can be split across reads.
+
+use std::io;
+
+use tokio_util::{
+    bytes::{Buf, BytesMut},
+    codec::Decoder,
+};
+
+use super::{Command, DONT, END_SUBNEGOTIATION, IAC, SUBNEGOTIATION, WILL};
+
+/// Subnegotiations longer than this are probably someone messing with us.
+const MAX_SUBNEGOTIATION_LENGTH: usize = 4096;
+
+#[derive(Debug)]
+pub enum Event {
+    /// Keystrokes, with escaped IACs already turned back into 255.
+    Data(Vec<u8>),
+    Command(Command),
+}
+
+pub struct TelnetCodec;
+
+impl Decoder for TelnetCodec {
+    type Item = Event;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Event>> {
+        loop {
+            if src.is_empty() {
+                return Ok(None);
+            }
+
+            if src[0] != IAC || src.get(1) == Some(&IAC) {
+                let (data, len) = read_data(src);
+                src.advance(len);
+                return Ok(Some(Event::Data(data)));
+            }
+
+            let Some(len) = command_len(src)? else {
+                // wait for the rest of the command
+                return Ok(None);
+            };
+            let bytes = src.split_to(len);
+            match Command::parse(&bytes) {
+                Ok(Some(command)) => return Ok(Some(Event::Command(command))),
+                Ok(None) => {}
+                // skip commands we don't understand instead of giving up on
+                // the whole connection
+                Err(err) => println!("{err}"),
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<Event>> {
+        let event = self.decode(src)?;
+        if event.is_none() {
+            // half of a command that's never going to be finished
+            src.clear();
+        }
+        Ok(event)
+    }
+}
+
+/// The keystrokes at the start of the buffer, up to the next command, and how
+/// many bytes they took up.
+fn read_data(src: &[u8]) -> (Vec<u8>, usize) {
+    let mut data = Vec::new();
+    let mut i = 0;
+    while i < src.len() {
+        if src[i] != IAC {
+            data.push(src[i]);
+            i += 1;
+        } else if src.get(i + 1) == Some(&IAC) {
+            data.push(IAC);
+            i += 2;
+        } else {
+            // a command, or an IAC that we don't know the meaning of yet
+            break;
+        }
+    }
+    (data, i)
+}
+
+/// How long the command at the start of the buffer is, or `None` if we don't
+/// have all of it yet.
+fn command_len(src: &[u8]) -> io::Result<Option<usize>> {
+    let Some(&command) = src.get(1) else {
+        return Ok(None);
+    };
+    match command {
+        SUBNEGOTIATION => {
+            // ends at an IAC SE, but IAC IAC is an escaped 255
+            let mut i = 2;
+            while i + 1 < src.len() {
+                match (src[i], src[i + 1]) {
+                    (IAC, END_SUBNEGOTIATION) => return Ok(Some(i + 2)),
+                    (IAC, IAC) => i += 2,
+                    _ => i += 1,
+                }
+            }
+            if src.len() > MAX_SUBNEGOTIATION_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "subnegotiation is too long",
+                ));
+            }
+            Ok(None)
+        }
+        // will, won't, do, and don't have an option after them
+        WILL..=DONT => Ok((src.len() >= 3).then_some(3)),
+        _ => Ok(Some(2)),
+    }
+}
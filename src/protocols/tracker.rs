@@ -0,0 +1,428 @@
+//! A BitTorrent tracker for torrents of the files in the media directory and
+//! of every post as a text file. The HTTP server answers announces at
+//! `/announce`, and the files themselves are also served over HTTP so there's
+//! always a web seed (BEP 19) even if nobody else is seeding.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant},
+};
+
+use parking_lot::{Mutex, RwLock};
+use percent_encoding::{
+    percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS, NON_ALPHANUMERIC,
+};
+use sha1::{Digest, Sha1};
+
+use crate::{bencode::Value, crawl::SiteData, drafts, links, media::Media, HOSTNAME};
+
+use super::plain_text::{Links, PlainTextSite};
+
+/// What the posts torrent is called, which is also the directory that its
+/// files are in under `/downloads/`.
+pub const POSTS_BUNDLE_NAME: &str = "matdoesdev-posts";
+
+const PIECE_LENGTH: u64 = 256 * 1024;
+/// How often clients should announce, in seconds.
+const ANNOUNCE_INTERVAL: u64 = 30 * 60;
+/// Peers that haven't announced in this long are assumed to be gone.
+const PEER_TIMEOUT: Duration = Duration::from_secs(ANNOUNCE_INTERVAL * 2);
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_NUMWANT: usize = 50;
+const MAX_NUMWANT: usize = 200;
+/// The most peers that are kept for each torrent, so a client making up peer
+/// ids can't fill the memory before they expire.
+const MAX_PEERS_PER_TORRENT: usize = 1000;
+/// The most peers from the same ip in a torrent. There's more than one for
+/// the people behind a NAT.
+const MAX_PEERS_PER_IP: usize = 8;
+
+/// The characters that have to be encoded for a media path to be used in a
+/// url. Slashes are kept since they separate the directories.
+const PATH_ENCODE_SET: &AsciiSet = &CONTROLS.add(b' ').add(b'?').add(b'#').add(b'%');
+
+static TORRENTS: LazyLock<RwLock<Torrents>> = LazyLock::new(Default::default);
+/// The peers in each torrent, by the info hash.
+static SWARMS: LazyLock<Mutex<HashMap<[u8; 20], Swarm>>> = LazyLock::new(Default::default);
+
+/// The peers in a torrent, by their peer id.
+type Swarm = HashMap<Vec<u8>, Peer>;
+
+#[derive(Default)]
+struct Torrents {
+    list: Vec<Arc<Torrent>>,
+    /// The contents of the files in the posts torrent, by their file name.
+    bundle_files: HashMap<String, Arc<[u8]>>,
+}
+
+pub struct Torrent {
+    /// What it's called in the downloads pages, like the path of the file in
+    /// the media directory.
+    pub name: String,
+    /// The name of the `.torrent` file, which is served under `/downloads/`.
+    pub file_name: String,
+    /// The total size of the files in the torrent.
+    pub size: u64,
+    pub info_hash: [u8; 20],
+    /// The bencoded `.torrent` file.
+    pub metainfo: Vec<u8>,
+}
+
+struct Peer {
+    addr: SocketAddr,
+    /// How many bytes the peer still has to download, so 0 means it's a
+    /// seeder.
+    left: u64,
+    last_seen: Instant,
+}
+
+/// Links in the posts torrent go to the website, since the files are just
+/// text.
+struct Bundle;
+
+impl Links for Bundle {
+    fn address(path: &str) -> String {
+        links::absolute(path)
+    }
+}
+
+/// Make the torrents for the posts and everything in the media directory. The
+/// media files have to be read to hash them, so this can take a while.
+pub fn build(data: &SiteData) {
+    let mut torrents = Torrents::default();
+
+    let (bundle, bundle_files) = posts_bundle(data);
+    torrents.list.push(Arc::new(bundle));
+    torrents.bundle_files = bundle_files;
+
+    let mut paths = Vec::new();
+    if let Err(err) = media_files(Path::new("media"), &mut paths) {
+        eprintln!("couldn't list the media directory: {err}");
+    }
+    paths.sort();
+    for path in paths {
+        match media_torrent(&path) {
+            Ok(Some(torrent)) => torrents.list.push(Arc::new(torrent)),
+            Ok(None) => {}
+            Err(err) => eprintln!("couldn't make a torrent for {path:?}: {err}"),
+        }
+    }
+
+    println!("made {} torrents", torrents.list.len());
+    *TORRENTS.write() = torrents;
+}
+
+/// Every torrent, with the posts first and then the media files in order.
+pub fn torrents() -> Vec<Arc<Torrent>> {
+    TORRENTS.read().list.clone()
+}
+
+/// The torrent whose `.torrent` file has this name.
+pub fn torrent(file_name: &str) -> Option<Arc<Torrent>> {
+    TORRENTS
+        .read()
+        .list
+        .iter()
+        .find(|torrent| torrent.file_name == file_name)
+        .cloned()
+}
+
+/// A file from the posts torrent, for the web seed. The path is relative to
+/// `/downloads/`.
+pub fn bundle_file(path: &str) -> Option<Media> {
+    let file_name = path.strip_prefix(POSTS_BUNDLE_NAME)?.strip_prefix('/')?;
+    let bytes = TORRENTS.read().bundle_files.get(file_name).cloned()?;
+    Some(Media::Cached {
+        bytes,
+        modified: None,
+    })
+}
+
+impl Torrent {
+    /// The size like `1.5 MiB`.
+    pub fn human_size(&self) -> String {
+        const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+        if self.size < 1024 {
+            return format!("{} B", self.size);
+        }
+        let mut size = self.size as f64 / 1024.;
+        let mut unit = 0;
+        while size >= 1024. && unit < UNITS.len() - 1 {
+            size /= 1024.;
+            unit += 1;
+        }
+        format!("{size:.1} {}", UNITS[unit])
+    }
+
+    pub fn magnet(&self) -> String {
+        let info_hash = self
+            .info_hash
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        format!(
+            "magnet:?xt=urn:btih:{info_hash}&dn={name}&tr={tracker}",
+            name = utf8_percent_encode(&self.name, NON_ALPHANUMERIC),
+            tracker = utf8_percent_encode(&announce_url(), NON_ALPHANUMERIC),
+        )
+    }
+}
+
+fn announce_url() -> String {
+    format!("https://{HOSTNAME}/announce")
+}
+
+/// A multi-file torrent with every published post as a text file.
+fn posts_bundle(data: &SiteData) -> (Torrent, HashMap<String, Arc<[u8]>>) {
+    let site = PlainTextSite::generate::<Bundle>(data);
+
+    let mut slugs = drafts::published(&data.blog)
+        .map(|post| post.slug.as_str())
+        .collect::<Vec<_>>();
+    slugs.sort();
+
+    let mut contents = Vec::new();
+    let mut files = Vec::new();
+    let mut bundle_files = HashMap::new();
+    for slug in slugs {
+        let file_name = format!("{slug}.txt");
+        let bytes = site.posts[slug].as_bytes();
+        contents.extend(bytes);
+        files.push(Value::dict([
+            ("length", (bytes.len() as i64).into()),
+            ("path", Value::List(vec![file_name.clone().into()])),
+        ]));
+        bundle_files.insert(file_name, Arc::from(bytes));
+    }
+
+    // pieces go across file boundaries, so it's hashed as if it was one file
+    let (pieces, size) = hash_pieces(contents.as_slice()).expect("reading from memory can't fail");
+    let info = Value::dict([
+        ("name", POSTS_BUNDLE_NAME.into()),
+        ("piece length", (PIECE_LENGTH as i64).into()),
+        ("pieces", pieces.into()),
+        ("files", Value::List(files)),
+    ]);
+    // for multi-file torrents the name and the file's path get added to the
+    // end of the web seed url
+    let torrent = new_torrent(
+        POSTS_BUNDLE_NAME,
+        size,
+        info,
+        format!("https://{HOSTNAME}/downloads/"),
+    );
+    (torrent, bundle_files)
+}
+
+/// A single-file torrent for a file in the media directory, or `None` if the
+/// file is empty.
+fn media_torrent(path: &Path) -> io::Result<Option<Torrent>> {
+    let relative_path = path
+        .strip_prefix("media")
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/");
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| relative_path.clone());
+
+    let (pieces, size) = hash_pieces(File::open(path)?)?;
+    if size == 0 {
+        return Ok(None);
+    }
+    let info = Value::dict([
+        ("name", file_name.into()),
+        ("piece length", (PIECE_LENGTH as i64).into()),
+        ("pieces", pieces.into()),
+        ("length", (size as i64).into()),
+    ]);
+    let web_seed = format!(
+        "https://{HOSTNAME}/media/{}",
+        utf8_percent_encode(&relative_path, PATH_ENCODE_SET)
+    );
+    Ok(Some(new_torrent(&relative_path, size, info, web_seed)))
+}
+
+fn new_torrent(name: &str, size: u64, info: Value, web_seed: String) -> Torrent {
+    let info_hash = Sha1::digest(info.encode()).into();
+    let metainfo = Value::dict([
+        ("announce", announce_url().into()),
+        ("info", info),
+        ("url-list", web_seed.into()),
+    ])
+    .encode();
+    Torrent {
+        name: name.to_owned(),
+        file_name: format!("{}.torrent", name.replace('/', "-")),
+        size,
+        info_hash,
+        metainfo,
+    }
+}
+
+/// The SHA-1 hash of every piece, one after the other, and the total size.
+fn hash_pieces(mut reader: impl Read) -> io::Result<(Vec<u8>, u64)> {
+    let mut pieces = Vec::new();
+    let mut size = 0;
+    let mut piece = Vec::with_capacity(PIECE_LENGTH as usize);
+    loop {
+        piece.clear();
+        (&mut reader).take(PIECE_LENGTH).read_to_end(&mut piece)?;
+        if piece.is_empty() {
+            break;
+        }
+        size += piece.len() as u64;
+        pieces.extend(Sha1::digest(&piece));
+        if (piece.len() as u64) < PIECE_LENGTH {
+            break;
+        }
+    }
+    Ok((pieces, size))
+}
+
+/// Every file in the directory and the ones inside it, except hidden ones,
+/// which aren't served either.
+fn media_files(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            media_files(&entry.path(), paths)?;
+        } else if file_type.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Respond to an announce from a client, which adds it to the swarm and gets
+/// it some other peers. The query parameters are still percent-encoded, since
+/// the info hash and peer id are binary.
+pub fn announce(query_params: &HashMap<&str, &str>, client_ip: IpAddr) -> Vec<u8> {
+    let param = |key| query_params.get(key).copied();
+    let decode = |value: &str| percent_decode_str(value).collect::<Vec<u8>>();
+
+    let Some(info_hash) = param("info_hash").and_then(|h| <[u8; 20]>::try_from(decode(h)).ok())
+    else {
+        return failure("missing or invalid info_hash");
+    };
+    let Some(peer_id) = param("peer_id").map(decode).filter(|id| id.len() == 20) else {
+        return failure("missing or invalid peer_id");
+    };
+    let Some(port) = param("port").and_then(|p| p.parse::<u16>().ok()) else {
+        return failure("missing or invalid port");
+    };
+    let left = param("left")
+        .and_then(|l| l.parse::<u64>().ok())
+        .unwrap_or_default();
+    let numwant = param("numwant")
+        .and_then(|n| n.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_NUMWANT)
+        .min(MAX_NUMWANT);
+    // compact is the default since almost every client wants it
+    let compact = param("compact") != Some("0");
+
+    if !TORRENTS
+        .read()
+        .list
+        .iter()
+        .any(|torrent| torrent.info_hash == info_hash)
+    {
+        return failure("this tracker is only for torrents from matdoes.dev");
+    }
+
+    let mut swarms = SWARMS.lock();
+    let swarm = swarms.entry(info_hash).or_default();
+    let addr = SocketAddr::new(client_ip.to_canonical(), port);
+    // new peers that don't fit aren't added, but they still get the others
+    if param("event") == Some("stopped") {
+        swarm.remove(&peer_id);
+    } else if swarm.contains_key(&peer_id) || has_room(swarm, addr.ip()) {
+        swarm.insert(
+            peer_id.clone(),
+            Peer {
+                addr,
+                left,
+                last_seen: Instant::now(),
+            },
+        );
+    }
+
+    let complete = swarm.values().filter(|peer| peer.left == 0).count();
+    let incomplete = swarm.len() - complete;
+    let others = swarm
+        .iter()
+        .filter(|(id, _)| **id != peer_id)
+        .take(numwant)
+        .collect::<Vec<_>>();
+
+    let mut response = vec![
+        ("interval", (ANNOUNCE_INTERVAL as i64).into()),
+        ("complete", (complete as i64).into()),
+        ("incomplete", (incomplete as i64).into()),
+    ];
+    if compact {
+        // BEP 23 for ipv4 and BEP 7 for ipv6
+        let mut peers = Vec::new();
+        let mut peers6 = Vec::new();
+        for (_, peer) in others {
+            match peer.addr {
+                SocketAddr::V4(addr) => {
+                    peers.extend(addr.ip().octets());
+                    peers.extend(addr.port().to_be_bytes());
+                }
+                SocketAddr::V6(addr) => {
+                    peers6.extend(addr.ip().octets());
+                    peers6.extend(addr.port().to_be_bytes());
+                }
+            }
+        }
+        response.push(("peers", peers.into()));
+        response.push(("peers6", peers6.into()));
+    } else {
+        let peers = others
+            .into_iter()
+            .map(|(id, peer)| {
+                Value::dict([
+                    ("peer id", id.clone().into()),
+                    ("ip", peer.addr.ip().to_string().into()),
+                    ("port", (peer.addr.port() as i64).into()),
+                ])
+            })
+            .collect();
+        response.push(("peers", Value::List(peers)));
+    }
+    Value::dict(response).encode()
+}
+
+/// Whether a new peer with this ip can be added to the swarm.
+fn has_room(swarm: &Swarm, ip: IpAddr) -> bool {
+    swarm.len() < MAX_PEERS_PER_TORRENT
+        && swarm.values().filter(|peer| peer.addr.ip() == ip).count() < MAX_PEERS_PER_IP
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    Value::dict([("failure reason", reason.into())]).encode()
+}
+
+/// Remove the peers that stopped announcing, forever.
+pub async fn expire_peers() {
+    loop {
+        tokio::time::sleep(EXPIRE_INTERVAL).await;
+
+        let mut swarms = SWARMS.lock();
+        for swarm in swarms.values_mut() {
+            swarm.retain(|_, peer| peer.last_seen.elapsed() < PEER_TIMEOUT);
+        }
+        swarms.retain(|_, swarm| !swarm.is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+//! The posts that are most like each other, for the "Related posts" at the end
+//! of every post. Posts are compared by the cosine similarity of their tf-idf
+//! vectors, with a bonus for every tag they share.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    crawl::Post,
+    drafts,
+    search::{normalize, plain_text, words},
+};
+
+/// How many related posts are shown at most.
+pub const MAX_RELATED: usize = 3;
+/// Added to the similarity for every tag that two posts share. The similarity
+/// is between 0 and 1, so this is a lot.
+const TAG_WEIGHT: f64 = 0.25;
+
+pub struct RelatedPost {
+    pub slug: String,
+    pub title: String,
+}
+
+/// The related posts for every post, by slug. Only published posts are
+/// suggested, but drafts get suggestions too.
+pub fn related_posts(posts: &[Post]) -> HashMap<String, Vec<RelatedPost>> {
+    let counts = posts.iter().map(word_counts).collect::<Vec<_>>();
+
+    // how many published posts each word is in
+    let mut document_frequency = HashMap::<&str, usize>::new();
+    let published = drafts::published(posts)
+        .map(|post| post.slug.as_str())
+        .collect::<HashSet<_>>();
+    for (post, counts) in posts.iter().zip(&counts) {
+        if published.contains(post.slug.as_str()) {
+            for word in counts.keys() {
+                *document_frequency.entry(word.as_str()).or_default() += 1;
+            }
+        }
+    }
+
+    // words that are in every post have an idf of 0, so they don't count
+    let vectors = counts
+        .iter()
+        .map(|counts| {
+            let vector = counts
+                .iter()
+                .map(|(word, &count)| {
+                    let frequency = document_frequency.get(word.as_str()).copied();
+                    let idf = (published.len() as f64 / frequency.unwrap_or(1) as f64).ln();
+                    (word.as_str(), (1. + (count as f64).ln()) * idf)
+                })
+                .collect::<HashMap<_, _>>();
+            let length = vector.values().map(|w| w * w).sum::<f64>().sqrt();
+            (vector, length)
+        })
+        .collect::<Vec<_>>();
+
+    let mut related = HashMap::new();
+    for (i, post) in posts.iter().enumerate() {
+        let mut scores = posts
+            .iter()
+            .enumerate()
+            .filter(|(j, other)| *j != i && published.contains(other.slug.as_str()))
+            .map(|(j, other)| {
+                let shared_tags = post.tags.iter().filter(|t| other.tags.contains(t)).count();
+                let score =
+                    cosine_similarity(&vectors[i], &vectors[j]) + shared_tags as f64 * TAG_WEIGHT;
+                (score, other)
+            })
+            .filter(|(score, _)| *score > 0.)
+            .collect::<Vec<_>>();
+        scores.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        related.insert(
+            post.slug.clone(),
+            scores
+                .into_iter()
+                .take(MAX_RELATED)
+                .map(|(_, other)| RelatedPost {
+                    slug: other.slug.clone(),
+                    title: other.title.clone(),
+                })
+                .collect(),
+        );
+    }
+    related
+}
+
+fn word_counts(post: &Post) -> HashMap<String, usize> {
+    let text = plain_text(&post.content);
+    let mut counts = HashMap::new();
+    for word in words(&post.title).chain(words(&text)) {
+        *counts.entry(normalize(word)).or_default() += 1;
+    }
+    counts
+}
+
+fn cosine_similarity(
+    (a, a_length): &(HashMap<&str, f64>, f64),
+    (b, b_length): &(HashMap<&str, f64>, f64),
+) -> f64 {
+    if *a_length == 0. || *b_length == 0. {
+        return 0.;
+    }
+    let dot = a
+        .iter()
+        .filter_map(|(word, weight)| Some(weight * b.get(word)?))
+        .sum::<f64>();
+    dot / (a_length * b_length)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::crawl::PostPart;
+
+    fn post(slug: &str, text: &str, tags: &[&str]) -> Post {
+        Post {
+            title: slug.to_owned(),
+            slug: slug.to_owned(),
+            published: Utc::now(),
+            content: vec![PostPart::Text(text.to_owned())],
+            draft: false,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            word_count: 0,
+        }
+    }
+
+    #[test]
+    fn similar_posts_are_related() {
+        let posts = [
+            post("gemini", "serving gemini capsules over tls", &[]),
+            post("gopher", "serving gopher menus over tcp", &[]),
+            post("capsules", "writing gemini capsules", &[]),
+            post("cooking", "baking bread at home", &["food"]),
+            post("soup", "a recipe", &["food"]),
+        ];
+        let related = related_posts(&posts);
+        assert_eq!(related["gemini"][0].slug, "capsules");
+        assert_eq!(related["cooking"][0].slug, "soup");
+        // nothing in common
+        assert!(related["soup"].iter().all(|p| p.slug == "cooking"));
+    }
+}
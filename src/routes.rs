@@ -0,0 +1,76 @@
+//! Every public page on the site and the protocols that serve it. The indexes
+//! for crawlers (HTTP's sitemap, Gemini's `/index`, and Gopher's `caps.txt`)
+//! are made from this, so they don't have to be kept up to date by hand.
+
+use chrono::{DateTime, Utc};
+
+use crate::{crawl::SiteData, drafts};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Gemini,
+    Gopher,
+    Http,
+}
+
+const ALL: &[Scheme] = &[Scheme::Gemini, Scheme::Gopher, Scheme::Http];
+/// The pages that the static HTTP site doesn't have.
+const SMALL_WEB: &[Scheme] = &[Scheme::Gemini, Scheme::Gopher];
+
+/// The pages that are there no matter what the content is.
+const STATIC_ROUTES: [(&str, &[Scheme]); 10] = [
+    ("/", ALL),
+    ("/blog", ALL),
+    ("/blog/by-length", SMALL_WEB),
+    ("/projects", ALL),
+    ("/tags", SMALL_WEB),
+    ("/search", SMALL_WEB),
+    ("/downloads", ALL),
+    ("/qotd", &[Scheme::Gemini, Scheme::Http]),
+    ("/about-server", SMALL_WEB),
+    ("/playground", SMALL_WEB),
+];
+
+pub struct Route {
+    /// The path without any percent-encoding, starting with a slash.
+    pub path: String,
+    pub schemes: &'static [Scheme],
+    /// When the page last changed, if we know. This is only for posts.
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// Every page, with the static ones first and then the tags and posts.
+/// Drafts aren't included since they're only for people with the link.
+pub fn routes(data: &SiteData) -> Vec<Route> {
+    let mut routes = STATIC_ROUTES
+        .iter()
+        .map(|&(path, schemes)| Route {
+            path: path.to_owned(),
+            schemes,
+            modified: None,
+        })
+        .collect::<Vec<_>>();
+    for tag in data.tags().into_keys() {
+        routes.push(Route {
+            path: format!("/tag/{tag}"),
+            schemes: SMALL_WEB,
+            modified: None,
+        });
+    }
+    for post in drafts::published(&data.blog) {
+        routes.push(Route {
+            path: format!("/{}", post.slug),
+            schemes: ALL,
+            modified: Some(post.published),
+        });
+    }
+    routes
+}
+
+/// The pages that the protocol serves.
+pub fn for_scheme(data: &SiteData, scheme: Scheme) -> Vec<Route> {
+    routes(data)
+        .into_iter()
+        .filter(|route| route.schemes.contains(&scheme))
+        .collect()
+}
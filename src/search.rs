@@ -0,0 +1,213 @@
+//! Full-text search for the blog posts. The index for the main site is built
+//! once from the site data and shared by most protocols. Gopher and finger
+//! serve the other sites too, so they keep an index for each site.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::LazyLock,
+};
+
+use parking_lot::RwLock;
+
+use crate::{
+    crawl::{list_lines, Post, PostPart},
+    drafts,
+};
+
+pub const MAX_RESULTS: usize = 20;
+/// About how many bytes of text are shown on each side of the match in a
+/// snippet.
+const SNIPPET_CONTEXT: usize = 60;
+
+static INDEX: LazyLock<RwLock<SearchIndex>> = LazyLock::new(Default::default);
+
+#[derive(Default, Clone)]
+pub struct SearchIndex {
+    documents: Vec<Document>,
+    /// Every word, and the documents that it's in with how many times.
+    words: HashMap<String, Vec<(usize, usize)>>,
+}
+
+#[derive(Clone)]
+struct Document {
+    slug: String,
+    title: String,
+    /// The content of the post as plain text, for the snippets.
+    text: String,
+    title_words: HashSet<String>,
+}
+
+pub struct SearchResult {
+    pub slug: String,
+    pub title: String,
+    pub snippet: String,
+}
+
+/// Replace the index with one for these posts. Drafts aren't included.
+pub fn build(posts: &[Post]) {
+    *INDEX.write() = SearchIndex::new(posts);
+}
+
+/// The best matching posts for the query, best first.
+pub fn search(query: &str) -> Vec<SearchResult> {
+    INDEX.read().search(query)
+}
+
+impl SearchIndex {
+    pub fn new(posts: &[Post]) -> Self {
+        let mut index = Self::default();
+        for post in drafts::published(posts) {
+            let id = index.documents.len();
+            let text = plain_text(&post.content);
+
+            let mut counts = HashMap::<String, usize>::new();
+            for word in words(&post.title).chain(words(&text)) {
+                *counts.entry(normalize(word)).or_default() += 1;
+            }
+            for (word, count) in counts {
+                index.words.entry(word).or_default().push((id, count));
+            }
+
+            index.documents.push(Document {
+                slug: post.slug.clone(),
+                title: post.title.clone(),
+                title_words: words(&post.title).map(normalize).collect(),
+                text,
+            });
+        }
+        index
+    }
+
+    /// Posts are ranked by how many of the words in the query they have, and
+    /// then by tf-idf with a bonus for words in the title.
+    pub fn search(&self, query: &str) -> Vec<SearchResult> {
+        let query_words = words(query).map(normalize).collect::<HashSet<_>>();
+
+        // document id -> (matched words, score)
+        let mut scores = HashMap::<usize, (usize, f64)>::new();
+        for word in &query_words {
+            let Some(matches) = self.words.get(word) else {
+                continue;
+            };
+            let idf = (1. + self.documents.len() as f64 / matches.len() as f64).ln();
+            for &(id, count) in matches {
+                let mut score = idf * (1. + (count as f64).ln());
+                if self.documents[id].title_words.contains(word) {
+                    score += idf * 2.;
+                }
+                let entry = scores.entry(id).or_default();
+                entry.0 += 1;
+                entry.1 += score;
+            }
+        }
+
+        let mut ranked = scores.into_iter().collect::<Vec<_>>();
+        ranked.sort_by(|(_, (a_matched, a_score)), (_, (b_matched, b_score))| {
+            b_matched.cmp(a_matched).then(b_score.total_cmp(a_score))
+        });
+        ranked
+            .into_iter()
+            .take(MAX_RESULTS)
+            .map(|(id, _)| {
+                let document = &self.documents[id];
+                SearchResult {
+                    slug: document.slug.clone(),
+                    title: document.title.clone(),
+                    snippet: snippet(&document.text, &query_words),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Split the text into words, with the byte offset of each one. Punctuation
+/// and whitespace are thrown away.
+fn word_offsets(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(move |word| (word.as_ptr() as usize - text.as_ptr() as usize, word))
+}
+
+pub fn words(text: &str) -> impl Iterator<Item = &str> {
+    word_offsets(text).map(|(_, word)| word)
+}
+
+pub fn normalize(word: &str) -> String {
+    word.to_lowercase()
+}
+
+/// The text around the first word that matches the query, or the start of the
+/// text if there's no match (like when only the title matched).
+fn snippet(text: &str, query_words: &HashSet<String>) -> String {
+    let offset = word_offsets(text)
+        .find(|(_, word)| query_words.contains(&normalize(word)))
+        .map(|(offset, _)| offset)
+        .unwrap_or_default();
+
+    let mut start = offset.saturating_sub(SNIPPET_CONTEXT);
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = usize::min(offset + SNIPPET_CONTEXT, text.len());
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+
+    let mut snippet = text[start..end]
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if start > 0 {
+        snippet.insert(0, '…');
+    }
+    if end < text.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+pub fn plain_text(content: &[PostPart]) -> String {
+    let mut text = String::new();
+    for part in content {
+        match part {
+            PostPart::Text(t)
+            | PostPart::InlineCode(t)
+            | PostPart::Italic(t)
+            | PostPart::Bold(t)
+            | PostPart::Link { text: t, .. } => text.push_str(t),
+            PostPart::CodeBlock(t)
+            | PostPart::Quote(t)
+            | PostPart::Caption(t)
+            | PostPart::Footnote { text: t, .. }
+            | PostPart::Heading { text: t, .. } => {
+                text.push_str(&format!("\n{t}\n"));
+            }
+            PostPart::Image { alt, .. } => {
+                if let Some(alt) = alt {
+                    text.push_str(&format!("\n{alt}\n"));
+                }
+            }
+            PostPart::Table(rows) => {
+                for row in rows {
+                    text.push_str(&format!("\n{}", row.join(" ")));
+                }
+                text.push('\n');
+            }
+            PostPart::List { ordered, items } => {
+                for line in list_lines(*ordered, items) {
+                    text.push_str(&format!("\n{}", line.text));
+                }
+                text.push('\n');
+            }
+            PostPart::DefinitionList(definitions) => {
+                for (term, definition) in definitions {
+                    text.push_str(&format!("\n{term}\n{definition}"));
+                }
+                text.push('\n');
+            }
+            PostPart::LineBreak | PostPart::HorizontalRule => text.push('\n'),
+            PostPart::FootnoteReference(_) => {}
+        }
+    }
+    text
+}
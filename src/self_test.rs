@@ -0,0 +1,236 @@
+//! `--self-test`: start every server on ports that the system picks, make a
+//! request to a few of them like a client would, and exit with an error if any
+//! of them didn't answer properly. It can be used as a container healthcheck.
+
+use std::{
+    future::Future,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::CryptoProvider,
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        DigitallySignedStruct, SignatureScheme,
+    },
+    TlsConnector,
+};
+
+use crate::{listen, HOSTNAME};
+
+/// How long the servers get to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long each check gets.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest response that's read, so a broken server can't make us read
+/// forever.
+const MAX_RESPONSE_LENGTH: u64 = 1024 * 1024;
+
+/// Run every check while the servers are running, and return whether they
+/// all passed.
+pub async fn run() -> bool {
+    let mut passed = true;
+    passed &= check("gopher", gopher).await;
+    passed &= check("gemini", gemini).await;
+    passed &= check("finger", finger).await;
+    passed &= check("ssh", ssh).await;
+    passed &= check("qotd", qotd).await;
+    passed &= check("http", http).await;
+    passed
+}
+
+async fn check<F: Future<Output = anyhow::Result<()>>>(
+    protocol: &str,
+    make_request: impl FnOnce(SocketAddr) -> F,
+) -> bool {
+    let result = async {
+        let address = timeout(STARTUP_TIMEOUT, address(protocol))
+            .await
+            .context("never started listening")?;
+        timeout(CHECK_TIMEOUT, make_request(address))
+            .await
+            .context("timed out")?
+    }
+    .await;
+    match result {
+        Ok(()) => {
+            println!("self-test: {protocol} ok");
+            true
+        }
+        Err(err) => {
+            eprintln!("self-test: {protocol} failed: {err:#}");
+            false
+        }
+    }
+}
+
+/// Wait until the protocol is listening.
+async fn address(protocol: &str) -> SocketAddr {
+    loop {
+        if let Some(port) = listen::port(protocol) {
+            return SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Send the request and read until the server closes the connection.
+async fn request(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    request: &[u8],
+) -> anyhow::Result<String> {
+    stream.write_all(request).await?;
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE_LENGTH)
+        .read_to_end(&mut response)
+        .await?;
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
+
+async fn gopher(address: SocketAddr) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect(address).await?;
+    let menu = request(&mut stream, b"/\r\n").await?;
+    ensure!(menu.ends_with("\r\n."), "the menu doesn't end with a .");
+    ensure!(
+        menu.lines().any(|line| line.starts_with('1')),
+        "the menu doesn't have any links"
+    );
+    Ok(())
+}
+
+async fn gemini(address: SocketAddr) -> anyhow::Result<()> {
+    let config = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))))
+        .with_no_client_auth();
+    let stream = TcpStream::connect(address).await?;
+    let mut stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from(HOSTNAME)?, stream)
+        .await?;
+    let response = request(&mut stream, format!("gemini://{HOSTNAME}/\r\n").as_bytes()).await?;
+    let status = response.lines().next().unwrap_or_default();
+    ensure!(
+        status.starts_with("20 text/gemini"),
+        "got {status:?} instead of a page"
+    );
+    Ok(())
+}
+
+async fn finger(address: SocketAddr) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect(address).await?;
+    let response = request(&mut stream, b"\r\n").await?;
+    ensure!(!response.trim().is_empty(), "the response was empty");
+    Ok(())
+}
+
+/// Only the start of the handshake, since finishing it would need a whole SSH
+/// client: the server has to identify itself and then send its key exchange
+/// init.
+async fn ssh(address: SocketAddr) -> anyhow::Result<()> {
+    const KEXINIT: u8 = 20;
+
+    let mut stream = BufReader::new(TcpStream::connect(address).await?);
+    let mut server_id = String::new();
+    stream.read_line(&mut server_id).await?;
+    ensure!(
+        server_id.starts_with("SSH-2.0-"),
+        "got {server_id:?} instead of an identification string"
+    );
+    stream
+        .get_mut()
+        .write_all(b"SSH-2.0-matssh_self_test\r\n")
+        .await?;
+
+    // the packet length, padding length, and then the message type
+    let mut header = [0; 6];
+    stream.read_exact(&mut header).await?;
+    if header[5] != KEXINIT {
+        bail!("got message {} instead of the key exchange", header[5]);
+    }
+    Ok(())
+}
+
+async fn qotd(address: SocketAddr) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect(address).await?;
+    let quote = request(&mut stream, b"").await?;
+    ensure!(!quote.trim().is_empty(), "the quote was empty");
+    Ok(())
+}
+
+async fn http(address: SocketAddr) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect(address).await?;
+    let response = request(
+        &mut stream,
+        b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )
+    .await?;
+    let status = response.lines().next().unwrap_or_default();
+    ensure!(
+        status.starts_with("HTTP/1.1 200"),
+        "/healthz returned {status:?}"
+    );
+    Ok(())
+}
+
+/// The servers use a self-signed certificate unless `--acme` is used, so it
+/// can't be checked. This is only ever used to connect to ourselves.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,169 @@
+//! What's running here, for the "about this server" pages: how long it's been
+//! up, which version it is, which protocols are listening on which ports, and
+//! how many requests each of them has had. The protocols add themselves when
+//! they start listening, see [`crate::listen`].
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use parking_lot::RwLock;
+
+use crate::{
+    analytics::{self, Stats},
+    HOSTNAME,
+};
+
+/// When we started. This is forced in [`start`] so it's close to when the
+/// process started.
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+/// The ports that each protocol is listening on, like `7979/tcp`.
+static LISTENERS: LazyLock<RwLock<BTreeMap<String, BTreeSet<String>>>> =
+    LazyLock::new(Default::default);
+
+/// The commit that was built, if `GIT_HASH` was set when building.
+const GIT_HASH: Option<&str> = option_env!("GIT_HASH");
+
+pub struct ServerInfo {
+    pub uptime: Duration,
+    pub version: &'static str,
+    pub git_hash: Option<&'static str>,
+    /// The protocols and their ports, in alphabetical order.
+    pub listeners: Vec<(String, Vec<String>)>,
+    /// The requests for each protocol since the analytics started, most
+    /// first.
+    pub requests: Vec<(String, usize)>,
+}
+
+/// Start counting the uptime.
+pub fn start() {
+    LazyLock::force(&STARTED);
+}
+
+pub fn uptime() -> Duration {
+    STARTED.elapsed()
+}
+
+/// Say that the protocol is listening on the port. `transport` is `tcp` or
+/// `udp`.
+pub fn register(protocol: &str, port: u16, transport: &str) {
+    LISTENERS
+        .write()
+        .entry(protocol.to_owned())
+        .or_default()
+        .insert(format!("{port}/{transport}"));
+}
+
+pub fn get() -> ServerInfo {
+    let stats = analytics::stats();
+    ServerInfo {
+        uptime: uptime(),
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: GIT_HASH,
+        listeners: LISTENERS
+            .read()
+            .iter()
+            .map(|(protocol, ports)| (protocol.clone(), ports.iter().cloned().collect()))
+            .collect(),
+        requests: Stats::top(&stats.protocols, usize::MAX)
+            .into_iter()
+            .map(|(protocol, count)| (protocol.to_owned(), count))
+            .collect(),
+    }
+}
+
+impl ServerInfo {
+    /// Like `0.1.14 (abc1234)`.
+    pub fn version_string(&self) -> String {
+        match self.git_hash {
+            Some(hash) => format!("{} ({hash})", self.version),
+            None => self.version.to_owned(),
+        }
+    }
+
+    /// The whole page as plain text, for the protocols that don't have any
+    /// formatting.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# About this server\n\n");
+        out.push_str(&format!("Up for {}\n", human_duration(self.uptime)));
+        out.push_str(&format!("Version {}\n\n", self.version_string()));
+        out.push_str("## Protocols\n\n");
+        for (protocol, ports) in &self.listeners {
+            out.push_str(&format!("{protocol}: {}\n", ports.join(", ")));
+        }
+        out.push_str("\n## Requests\n\n");
+        for (protocol, count) in &self.requests {
+            out.push_str(&format!("{protocol}: {count}\n"));
+        }
+        out
+    }
+}
+
+/// The simple services that are fun to poke at with `nc`, and what to send
+/// them. The playground only lists the ones that are listening.
+const PLAYGROUND: &[(&str, &str, &str)] = &[
+    ("discard", "Throws away everything you send it.", "anything"),
+    (
+        "dict",
+        "Looks up blog posts by their slug or title (RFC 2229).",
+        "MATCH blog prefix rust",
+    ),
+    ("finger", "Shows the blog and some stats.", "blog"),
+    (
+        "ident",
+        "Says who owns a connection (RFC 1413).",
+        "6193, 23",
+    ),
+    ("nex", "The whole site, like a simpler gopher.", "blog/"),
+    ("qotd", "The quote of the day (RFC 865).", "nothing"),
+];
+
+/// The playground page as plain text: the simple services that are running,
+/// with their ports and something to try.
+pub fn playground_text() -> String {
+    let listeners = LISTENERS.read();
+    let mut out = String::new();
+    out.push_str(
+        "# Playground
+
+",
+    );
+    out.push_str(&format!(
+        "Some small services that you can talk to with netcat, like \
+        `nc {HOSTNAME} <port>`.\n"
+    ));
+    for (protocol, description, example) in PLAYGROUND {
+        let Some(ports) = listeners.get(*protocol) else {
+            continue;
+        };
+        let ports = ports.iter().cloned().collect::<Vec<_>>().join(", ");
+        out.push_str(&format!("\n## {protocol} ({ports})\n\n"));
+        out.push_str(&format!("{description}\nTry sending: {example}\n"));
+    }
+    out
+}
+
+/// Like `3 days, 4 hours`, with only the two biggest units.
+pub fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let units = [
+        (secs / 86400, "day"),
+        (secs / 3600 % 24, "hour"),
+        (secs / 60 % 60, "minute"),
+    ];
+    let parts = units
+        .into_iter()
+        .skip_while(|&(n, _)| n == 0)
+        .take(2)
+        .filter(|&(n, _)| n > 0)
+        .map(|(n, unit)| format!("{n} {unit}{}", if n == 1 { "" } else { "s" }))
+        .collect::<Vec<_>>();
+    if parts.is_empty() {
+        "less than a minute".to_owned()
+    } else {
+        parts.join(", ")
+    }
+}
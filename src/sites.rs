@@ -0,0 +1,67 @@
+//! Other people's sites that are served by the same process, each with its
+//! own content. They're added with `--site <hostname>=<markdown directory>`,
+//! and anything that doesn't ask for one of them gets the main site.
+//!
+//! Gopher and finger don't send the hostname the client connected to, so it's
+//! a hint in the request instead: a `/@example.com` prefix on gopher
+//! selectors, and finger's forwarding syntax (`blog@example.com@matdoes.dev`).
+
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use crate::{
+    crawl::SiteData,
+    protocols::Protocol,
+    sources::{ContentSource, MarkdownDir},
+};
+
+#[derive(Default)]
+pub struct SiteRegistry {
+    /// By lowercase hostname.
+    sites: HashMap<String, SiteData>,
+}
+
+impl SiteRegistry {
+    /// Load the sites from their directories. Ones that can't be loaded are
+    /// skipped, since the main site should still work.
+    pub async fn load(sites: Vec<(String, PathBuf)>) -> SiteRegistry {
+        let mut registry = SiteRegistry::default();
+        for (hostname, dir) in sites {
+            let source = MarkdownDir(dir);
+            println!("loading site data for {hostname} from {}...", source.name());
+            match source.load().await {
+                Ok(data) => {
+                    registry.sites.insert(hostname.to_lowercase(), data);
+                }
+                Err(err) => eprintln!("couldn't load {hostname}: {err}"),
+            }
+        }
+        registry
+    }
+
+    /// The content of every site.
+    pub fn data(&self) -> impl Iterator<Item = &SiteData> {
+        self.sites.values()
+    }
+
+    /// Generate a protocol for every site, by lowercase hostname.
+    pub fn generate<P: Protocol>(&self) -> HashMap<String, Arc<P>> {
+        self.sites
+            .iter()
+            .map(|(hostname, data)| {
+                (
+                    hostname.clone(),
+                    Arc::new(P::generate(data, &P::Options::default())),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Parse a `--site` argument, like `example.com=sites/example`.
+pub fn parse_arg(arg: &str) -> Option<(String, PathBuf)> {
+    let (hostname, dir) = arg.split_once('=')?;
+    if hostname.is_empty() || dir.is_empty() {
+        return None;
+    }
+    Some((hostname.to_owned(), PathBuf::from(dir)))
+}
@@ -0,0 +1,213 @@
+//! The places that the site data can be loaded from. `main` tries them in
+//! order until one of them works.
+
+use std::{
+    error::Error,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use chrono::{TimeZone, Utc};
+use futures_util::{future::LocalBoxFuture, FutureExt};
+use tokio::fs;
+
+use crate::crawl::{self, LanguageName, ListItem, Post, PostPart, Project, SiteData};
+
+pub const CACHE_PATH: &str = "cache.json";
+
+pub trait ContentSource {
+    /// Shown in the logs.
+    fn name(&self) -> String;
+
+    fn load(&self) -> LocalBoxFuture<'_, Result<SiteData, Box<dyn Error>>>;
+
+    /// Whether loading from this source is slow enough that the result should
+    /// be written to the cache.
+    fn cacheable(&self) -> bool {
+        false
+    }
+}
+
+/// Crawl matdoes.dev.
+pub struct Crawler {
+    /// How many posts are crawled at the same time.
+    pub concurrency: usize,
+    /// Send the requests through this proxy, like `socks5h://127.0.0.1:9050`
+    /// for Tor.
+    pub proxy: Option<String>,
+}
+
+impl ContentSource for Crawler {
+    fn name(&self) -> String {
+        "crawler".to_owned()
+    }
+
+    fn load(&self) -> LocalBoxFuture<'_, Result<SiteData, Box<dyn Error>>> {
+        crawl::crawl(self.concurrency, self.proxy.as_deref()).boxed_local()
+    }
+
+    fn cacheable(&self) -> bool {
+        true
+    }
+}
+
+/// A directory of Markdown posts, see [`crawl::read_markdown_dir`].
+pub struct MarkdownDir(pub PathBuf);
+
+impl ContentSource for MarkdownDir {
+    fn name(&self) -> String {
+        format!("markdown directory {:?}", self.0)
+    }
+
+    fn load(&self) -> LocalBoxFuture<'_, Result<SiteData, Box<dyn Error>>> {
+        crawl::read_markdown_dir(&self.0).boxed_local()
+    }
+}
+
+/// The data from the last time a cacheable source was loaded.
+pub struct Cache {
+    pub path: PathBuf,
+    /// If the cache is older than this, it's treated like it doesn't exist.
+    pub max_age: Option<Duration>,
+}
+
+impl Cache {
+    pub fn new(max_age: Option<Duration>) -> Self {
+        Self {
+            path: PathBuf::from(CACHE_PATH),
+            max_age,
+        }
+    }
+
+    pub async fn save(&self, data: &SiteData) -> Result<(), Box<dyn Error>> {
+        fs::write(&self.path, serde_json::to_string(data)?).await?;
+        Ok(())
+    }
+}
+
+impl ContentSource for Cache {
+    fn name(&self) -> String {
+        match self.max_age {
+            Some(max_age) => format!("cache (up to {}s old)", max_age.as_secs()),
+            None => "cache".to_owned(),
+        }
+    }
+
+    fn load(&self) -> LocalBoxFuture<'_, Result<SiteData, Box<dyn Error>>> {
+        async move {
+            if let Some(max_age) = self.max_age {
+                let modified = fs::metadata(&self.path).await?.modified()?;
+                let age = SystemTime::now()
+                    .duration_since(modified)
+                    .unwrap_or_default();
+                if age > max_age {
+                    return Err(format!("cache is {}s old", age.as_secs()).into());
+                }
+            }
+            let cache = fs::read_to_string(&self.path).await?;
+            let mut data: SiteData = serde_json::from_str(&cache)?;
+            data.blog.iter_mut().for_each(Post::fill_word_count);
+            Ok(data)
+        }
+        .boxed_local()
+    }
+}
+
+/// Some made up posts and projects, so the servers can be tried out without
+/// any content of your own.
+pub struct Demo;
+
+impl ContentSource for Demo {
+    fn name(&self) -> String {
+        "demo".to_owned()
+    }
+
+    fn load(&self) -> LocalBoxFuture<'_, Result<SiteData, Box<dyn Error>>> {
+        async { Ok(demo_data()) }.boxed_local()
+    }
+}
+
+fn demo_data() -> SiteData {
+    let projects = vec![
+        Project {
+            name: "matdoesdev-protocols".to_owned(),
+            href: None,
+            source: Some("https://github.com/mat-1/matdoesdev-protocols".to_owned()),
+            languages: vec![LanguageName::Rust],
+            description: "Make my personal website work on more than just HTTP.".to_owned(),
+        },
+        Project {
+            name: "Example".to_owned(),
+            href: Some("https://example.com/".to_owned()),
+            source: None,
+            languages: vec![LanguageName::TypeScript, LanguageName::Svelte],
+            description: "A project that doesn't exist.".to_owned(),
+        },
+    ];
+
+    let mut blog = vec![Post {
+        title: "Hello, world!".to_owned(),
+        slug: "hello-world".to_owned(),
+        published: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+        content: vec![
+            PostPart::Text("This is a demo post, so you can see how ".to_owned()),
+            PostPart::Bold("every".to_owned()),
+            PostPart::Text(" kind of content looks.".to_owned()),
+            PostPart::LineBreak,
+            PostPart::Heading {
+                level: 2,
+                text: "Code".to_owned(),
+            },
+            PostPart::Text("Inline code looks like ".to_owned()),
+            PostPart::InlineCode("this".to_owned()),
+            PostPart::Text(", and code blocks look like this:".to_owned()),
+            PostPart::LineBreak,
+            PostPart::CodeBlock("fn main() {\n    println!(\"Hello, world!\");\n}".to_owned()),
+            PostPart::Heading {
+                level: 2,
+                text: "Lists and tables".to_owned(),
+            },
+            PostPart::List {
+                ordered: true,
+                items: vec![
+                    ListItem {
+                        text: "First".to_owned(),
+                        children: vec![PostPart::List {
+                            ordered: false,
+                            items: vec![ListItem {
+                                text: "Nested".to_owned(),
+                                children: vec![],
+                            }],
+                        }],
+                    },
+                    ListItem {
+                        text: "Second".to_owned(),
+                        children: vec![],
+                    },
+                ],
+            },
+            PostPart::Table(vec![
+                vec!["Protocol".to_owned(), "Port".to_owned()],
+                vec!["Gemini".to_owned(), "1965".to_owned()],
+                vec!["Gopher".to_owned(), "70".to_owned()],
+            ]),
+            PostPart::Quote("Quotes look like this.".to_owned()),
+            PostPart::LineBreak,
+            PostPart::Link {
+                text: "A link".to_owned(),
+                href: "https://example.com/".to_owned(),
+            },
+            PostPart::LineBreak,
+        ],
+        draft: false,
+        tags: vec!["demo".to_owned()],
+        word_count: 0,
+    }];
+    blog.iter_mut().for_each(Post::fill_word_count);
+
+    SiteData {
+        projects,
+        blog,
+        stale: false,
+    }
+}
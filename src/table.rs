@@ -0,0 +1,162 @@
+//! Lay out tables as lines of text, used by the terminal UI and the text-based
+//! protocols.
+
+pub struct Borders {
+    pub horizontal: char,
+    pub vertical: char,
+
+    pub top_left: char,
+    pub top: char,
+    pub top_right: char,
+
+    pub left: char,
+    pub cross: char,
+    pub right: char,
+
+    pub bottom_left: char,
+    pub bottom: char,
+    pub bottom_right: char,
+}
+
+pub const BOX_DRAWING: Borders = Borders {
+    horizontal: '─',
+    vertical: '│',
+    top_left: '┌',
+    top: '┬',
+    top_right: '┐',
+    left: '├',
+    cross: '┼',
+    right: '┤',
+    bottom_left: '└',
+    bottom: '┴',
+    bottom_right: '┘',
+};
+
+pub const ASCII: Borders = Borders {
+    horizontal: '-',
+    vertical: '|',
+    top_left: '+',
+    top: '+',
+    top_right: '+',
+    left: '+',
+    cross: '+',
+    right: '+',
+    bottom_left: '+',
+    bottom: '+',
+    bottom_right: '+',
+};
+
+/// Render the rows of a table with aligned columns. The cells are wrapped so
+/// the table fits in `max_width` if possible.
+pub fn render(rows: &[Vec<String>], max_width: usize, borders: &Borders) -> Vec<String> {
+    let column_count = rows.iter().map(|row| row.len()).max().unwrap_or_default();
+    if column_count == 0 {
+        return vec![];
+    }
+
+    let mut widths = vec![0; column_count];
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = usize::max(widths[i], cell.chars().count());
+        }
+    }
+    // every column has a space on both sides and a border on the right, plus
+    // there's the border on the very left
+    let available_width = max_width.saturating_sub(column_count * 3 + 1);
+    while widths.iter().sum::<usize>() > available_width {
+        // shrink the widest column until it fits
+        let (widest_index, &widest) = widths
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, width)| **width)
+            .unwrap();
+        if widest <= 1 {
+            break;
+        }
+        widths[widest_index] -= 1;
+    }
+
+    let separator = |left: char, middle: char, right: char| {
+        let mut line = String::new();
+        line.push(left);
+        for (i, width) in widths.iter().enumerate() {
+            if i > 0 {
+                line.push(middle);
+            }
+            line.extend(std::iter::repeat_n(borders.horizontal, width + 2));
+        }
+        line.push(right);
+        line
+    };
+
+    let mut lines = vec![separator(borders.top_left, borders.top, borders.top_right)];
+    for (i, row) in rows.iter().enumerate() {
+        if i > 0 {
+            lines.push(separator(borders.left, borders.cross, borders.right));
+        }
+
+        let cells = (0..column_count)
+            .map(|column| {
+                wrap(
+                    row.get(column).map(String::as_str).unwrap_or_default(),
+                    widths[column],
+                )
+            })
+            .collect::<Vec<_>>();
+        let row_height = usize::max(1, cells.iter().map(Vec::len).max().unwrap_or_default());
+        for line_index in 0..row_height {
+            let mut line = String::new();
+            line.push(borders.vertical);
+            for (cell, width) in cells.iter().zip(&widths) {
+                let text = cell.get(line_index).map(String::as_str).unwrap_or_default();
+                line.push(' ');
+                line.push_str(text);
+                line.push_str(&" ".repeat(width - text.chars().count()));
+                line.push(' ');
+                line.push(borders.vertical);
+            }
+            lines.push(line);
+        }
+    }
+    lines.push(separator(
+        borders.bottom_left,
+        borders.bottom,
+        borders.bottom_right,
+    ));
+
+    lines
+}
+
+/// Split the text into lines that are at most `width` characters long. Words
+/// that are too long are split.
+pub fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    if width == 0 {
+        return lines;
+    }
+
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let mut word = word.chars().collect::<Vec<_>>();
+        let line_length = line.chars().count();
+        if line_length > 0 {
+            if line_length + 1 + word.len() > width {
+                lines.push(std::mem::take(&mut line));
+            } else {
+                line.push(' ');
+            }
+        }
+        while word.len() > width {
+            let rest = word.split_off(width);
+            line.extend(word);
+            lines.push(std::mem::take(&mut line));
+            word = rest;
+        }
+        line.extend(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+
+    lines
+}
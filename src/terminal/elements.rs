@@ -1,4 +1,5 @@
-use super::Location;
+use super::{screen::Screen, Location};
+use crate::table;
 
 #[derive(Clone)]
 pub enum Element {
@@ -11,22 +12,116 @@ pub enum Element {
         rect: Rectangle,
     },
     Container(Vec<Element>),
+    /// A table with borders, the inner vecs are the cells in each row.
+    Table(Vec<Vec<String>>),
 
     // links
     Link {
         inner: Box<Element>,
-        location: Location,
+        target: Target,
     },
     ExternalLink {
         inner: Box<Element>,
         url: String,
     },
+    /// A place on the page that [`Target::Anchor`] links can jump to.
+    Anchor(String),
 
     // formatting
     Formatted {
         inner: Box<Element>,
         format: String,
     },
+    /// A color that depends on the theme.
+    Colored {
+        inner: Box<Element>,
+        color: Color,
+    },
+}
+
+/// Where a link goes when it's followed.
+#[derive(Debug, Clone)]
+pub enum Target {
+    Location(Location),
+    /// The [`Element::Anchor`] with this name on the same page.
+    Anchor(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Color {
+    /// Titles and other text that should stand out.
+    Primary,
+    /// Less important text, like dates.
+    Secondary,
+    Link,
+}
+
+/// The SGR parameters used for each [`Color`].
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub name: &'static str,
+    pub primary: &'static str,
+    pub secondary: &'static str,
+    pub link: &'static str,
+}
+
+pub const THEMES: &[Theme] = &[
+    Theme {
+        name: "default",
+        primary: "97",
+        secondary: "90",
+        link: "38;2;13;199;249",
+    },
+    Theme {
+        name: "light",
+        primary: "30",
+        secondary: "90",
+        link: "38;2;0;110;170",
+    },
+    Theme {
+        name: "high-contrast",
+        primary: "1;97",
+        secondary: "37",
+        link: "96",
+    },
+    // for dumb terminals
+    Theme {
+        name: "monochrome",
+        primary: "",
+        secondary: "",
+        link: "",
+    },
+];
+
+impl Theme {
+    pub fn from_name(name: &str) -> Option<&'static Theme> {
+        THEMES
+            .iter()
+            .find(|theme| theme.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The theme that comes after this one, for cycling through them.
+    pub fn next(&self) -> &'static Theme {
+        let index = THEMES
+            .iter()
+            .position(|theme| theme.name == self.name)
+            .unwrap_or_default();
+        &THEMES[(index + 1) % THEMES.len()]
+    }
+
+    pub fn format(&self, color: Color) -> &'static str {
+        match color {
+            Color::Primary => self.primary,
+            Color::Secondary => self.secondary,
+            Color::Link => self.link,
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        THEMES[0].clone()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -45,12 +140,14 @@ pub struct Position {
 
 #[derive(Debug, Clone)]
 pub struct Data {
-    pub links: Vec<(Location, Vec<Position>)>,
-    pub link_index: Option<usize>,
+    pub links: Vec<(Target, Vec<Position>)>,
+    /// The anchors and the rows they're on.
+    pub anchors: Vec<(String, isize)>,
+    pub theme: Theme,
 }
 
-const RESET: &str = "\x1b[m";
-fn move_cursor(pos: &Position) -> String {
+pub const RESET: &str = "\x1b[m";
+pub fn move_cursor(pos: &Position) -> String {
     // 1-indexed
     format!("\x1b[{};{}H", pos.y + 1, pos.x + 1)
 }
@@ -62,7 +159,7 @@ fn flush_word(
     word: &mut String,
     parent_rect: &Rectangle,
     window: &Rectangle,
-    result: &mut String,
+    screen: &mut Screen,
 ) -> bool {
     let word_length = word.chars().count();
     if pos.x + word_length as isize > parent_rect.left + parent_rect.width as isize {
@@ -72,8 +169,7 @@ fn flush_word(
 
     let in_window = pos.y >= 0 && pos.y < window.height as isize;
     if in_window {
-        result.push_str(&move_cursor(pos));
-        result.push_str(word);
+        screen.put_str(pos, word);
     }
     pos.x += word_length as isize;
     word.clear();
@@ -88,43 +184,48 @@ impl Element {
         parent_rect: &Rectangle,
         window: &Rectangle,
         data: &mut Data,
-    ) -> String {
-        let mut result = String::new();
+        screen: &mut Screen,
+    ) {
         match self {
             Element::Text(text) => {
                 let mut word = String::new();
                 for c in text.chars() {
                     if c == ' ' {
-                        if flush_word(pos, &mut word, parent_rect, window, &mut result) {
-                            // sending characters after the end of the line causes it to
-                            // override the last character, which is bad and why we have this check
+                        if flush_word(pos, &mut word, parent_rect, window, screen) {
+                            // don't draw spaces past the end of the line
                             if pos.x + 1 < parent_rect.left + parent_rect.width as isize {
-                                // this is still important despite the fact that we set the cursor
-                                // position on every flush_word, so escape sequences like links
-                                // still work properly.
-                                result.push(' ');
+                                // this is still important despite the cells being blank by
+                                // default, so formatting like link underlines still covers the
+                                // spaces.
+                                screen.put(pos, ' ');
                             }
                         }
                         pos.x += 1;
                     } else if c == '\t' {
-                        if flush_word(pos, &mut word, parent_rect, window, &mut result) {
-                            result.push_str("    ");
+                        if flush_word(pos, &mut word, parent_rect, window, screen) {
+                            screen.put_str(pos, "    ");
                         }
                         pos.x += 4;
                     } else if c == '\n' {
-                        flush_word(pos, &mut word, parent_rect, window, &mut result);
+                        flush_word(pos, &mut word, parent_rect, window, screen);
                         pos.x = parent_rect.left;
                         pos.y += 1;
                     } else {
                         word.push(c);
                     }
                 }
-                flush_word(pos, &mut word, parent_rect, window, &mut result);
+                flush_word(pos, &mut word, parent_rect, window, screen);
             }
             Element::HorizontallyCentered(inner) => {
                 // render once to get length
                 let initial_pos = pos.clone();
-                inner.render(pos, parent_rect, window, &mut data.clone());
+                inner.render(
+                    pos,
+                    parent_rect,
+                    window,
+                    &mut data.clone(),
+                    &mut Screen::default(),
+                );
 
                 let width = if initial_pos.y == pos.y {
                     (pos.x - initial_pos.x) as usize
@@ -141,12 +242,18 @@ impl Element {
                     height: parent_rect.height,
                 };
                 pos.x = rect.left;
-                result.push_str(&inner.render(pos, &rect, window, data));
+                inner.render(pos, &rect, window, data, screen);
             }
             Element::VerticallyCentered(inner) => {
                 // render once to get height
                 let initial_pos = pos.clone();
-                inner.render(pos, parent_rect, window, &mut data.clone());
+                inner.render(
+                    pos,
+                    parent_rect,
+                    window,
+                    &mut data.clone(),
+                    &mut Screen::default(),
+                );
 
                 let height = usize::min((pos.y - initial_pos.y) as usize, parent_rect.height);
 
@@ -158,31 +265,39 @@ impl Element {
                     height,
                 };
                 pos.y = rect.top;
-                result.push_str(&inner.render(pos, &rect, window, data));
+                inner.render(pos, &rect, window, data, screen);
             }
             Element::Rectangle { elements, rect } => {
                 for element in elements {
-                    let element_rendered = element.render(pos, rect, window, data);
-                    result.push_str(&element_rendered);
+                    element.render(pos, rect, window, data, screen);
                 }
             }
             Element::Container(elements) => {
                 for element in elements {
-                    let element_rendered = element.render(pos, parent_rect, window, data);
-                    result.push_str(&element_rendered);
+                    element.render(pos, parent_rect, window, data, screen);
                 }
             }
-
-            Element::Link { inner, location } => {
-                let start_pos = pos.clone();
-                let selected = data.link_index == Some(data.links.len());
-                if selected {
-                    result.push_str("\x1b[7m");
+            Element::Table(rows) => {
+                // tables always start on a new line
+                if pos.x != parent_rect.left {
+                    pos.x = parent_rect.left;
+                    pos.y += 1;
                 }
-                result.push_str(&inner.render(pos, parent_rect, window, data));
-                if selected {
-                    result.push_str(RESET);
+                for line in table::render(rows, parent_rect.width, &table::BOX_DRAWING) {
+                    if pos.y >= 0 && pos.y < window.height as isize {
+                        screen.put_str(pos, &line);
+                    }
+                    pos.y += 1;
                 }
+            }
+
+            Element::Link { inner, target } => {
+                let start_pos = pos.clone();
+                // the cells are marked so the selected and hovered links can
+                // be highlighted without laying out the page again
+                let previous_link = screen.link.replace(data.links.len());
+                inner.render(pos, parent_rect, window, data, screen);
+                screen.link = previous_link;
 
                 // i was too lazy to make wrapping work
                 let mut positions = Vec::new();
@@ -191,32 +306,45 @@ impl Element {
                         positions.push(Position { x, y });
                     }
                 }
-                data.links.push((location.clone(), positions));
+                data.links.push((target.clone(), positions));
             }
+            Element::Anchor(name) => data.anchors.push((name.clone(), pos.y)),
             Element::ExternalLink { inner, url } => {
-                result.push_str("\x1b[4m"); // underline
-                result.push_str(&format!("\x1b]8;;{url}\x1b\\"));
-                result.push_str(&inner.render(pos, parent_rect, window, data));
-                result.push_str("\x1b]8;;\x1b\\");
-                result.push_str(RESET);
+                let previous_style = screen.style.clone();
+                screen.style.formats.push("4".to_string()); // underline
+                screen.style.url = Some(url.clone());
+                inner.render(pos, parent_rect, window, data, screen);
+                screen.style = previous_style;
             }
 
             Element::Formatted { inner, format } => {
-                result.push_str("\x1b[");
-                result.push_str(format);
-                result.push('m');
-                result.push_str(&inner.render(pos, parent_rect, window, data));
-                result.push_str(RESET);
+                let previous_style = screen.style.clone();
+                if format.is_empty() {
+                    screen.style.formats.clear();
+                } else {
+                    screen.style.formats.push(format.clone());
+                }
+                inner.render(pos, parent_rect, window, data, screen);
+                screen.style = previous_style;
+            }
+            Element::Colored { inner, color } => {
+                let previous_style = screen.style.clone();
+                let format = data.theme.format(*color);
+                if !format.is_empty() {
+                    screen.style.formats.push(format.to_string());
+                }
+                inner.render(pos, parent_rect, window, data, screen);
+                screen.style = previous_style;
             }
         }
-        result
     }
 }
 
 pub mod prelude {
     pub use super::{
-        bold, colorless_link, container, external_link, gray, horizontally_centered, italic, link,
-        rectangle, reset, text, vertically_centered, white, Element, Position, Rectangle,
+        anchor, anchor_link, bold, colorless_link, container, external_link, gray,
+        horizontally_centered, italic, link, rectangle, reset, table, text, vertically_centered,
+        white, Element, Position, Rectangle,
     };
 }
 
@@ -235,21 +363,37 @@ pub fn rectangle(elements: Vec<Element>, rect: Rectangle) -> Element {
 pub fn container(elements: Vec<Element>) -> Element {
     Element::Container(elements)
 }
+pub fn table(rows: Vec<Vec<String>>) -> Element {
+    Element::Table(rows)
+}
 pub fn link(inner: Element, location: Location) -> Element {
-    Element::Formatted {
+    Element::Colored {
         inner: Box::new(Element::Link {
             inner: Box::new(inner),
-            location,
+            target: Target::Location(location),
         }),
-        format: "38;2;13;199;249".to_string(),
+        color: Color::Link,
     }
 }
 pub fn colorless_link(inner: Element, location: Location) -> Element {
     Element::Link {
         inner: Box::new(inner),
-        location,
+        target: Target::Location(location),
     }
 }
+/// A link to an [`anchor`] on the same page.
+pub fn anchor_link(inner: Element, anchor: &str) -> Element {
+    Element::Colored {
+        inner: Box::new(Element::Link {
+            inner: Box::new(inner),
+            target: Target::Anchor(anchor.to_string()),
+        }),
+        color: Color::Link,
+    }
+}
+pub fn anchor(name: &str) -> Element {
+    Element::Anchor(name.to_string())
+}
 pub fn external_link(inner: Element, url: &str) -> Element {
     Element::ExternalLink {
         inner: Box::new(inner),
@@ -270,15 +414,15 @@ pub fn italic(inner: Element) -> Element {
     }
 }
 pub fn gray(inner: Element) -> Element {
-    Element::Formatted {
+    Element::Colored {
         inner: Box::new(inner),
-        format: "90".to_string(),
+        color: Color::Secondary,
     }
 }
 pub fn white(inner: Element) -> Element {
-    Element::Formatted {
+    Element::Colored {
         inner: Box::new(inner),
-        format: "97".to_string(),
+        color: Color::Primary,
     }
 }
 pub fn reset(inner: Element) -> Element {
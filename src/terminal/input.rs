@@ -0,0 +1,121 @@
+//! Separating the keys that were pressed from everything else the client
+//! sends: text that was pasted (with bracketed paste turned on, see
+//! [`super::TerminalSession::on_open`]), and reports that the terminal sends
+//! by itself, like focus changes and the cursor position.
+
+/// Sent by the terminal before and after pasted text.
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// Input from the client, split up.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct Input {
+    /// The keys that were pressed, without any reports.
+    pub keys: Vec<u8>,
+    /// The text that was pasted. It's never interpreted as keys.
+    pub pasted: Vec<u8>,
+}
+
+/// Remembers whether a paste is still going, since long pastes are split
+/// across reads.
+#[derive(Default)]
+pub struct InputFilter {
+    pasting: bool,
+}
+
+impl InputFilter {
+    pub fn filter(&mut self, mut bytes: &[u8]) -> Input {
+        let mut input = Input::default();
+        let mut keys = Vec::new();
+        while !bytes.is_empty() {
+            let (marker, out) = if self.pasting {
+                (PASTE_END, &mut input.pasted)
+            } else {
+                (PASTE_START, &mut keys)
+            };
+            match find(bytes, marker) {
+                Some(i) => {
+                    out.extend(&bytes[..i]);
+                    bytes = &bytes[i + marker.len()..];
+                    self.pasting = !self.pasting;
+                }
+                None => {
+                    out.extend(bytes);
+                    break;
+                }
+            }
+        }
+        input.keys = strip_reports(&keys);
+        input
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Remove the things that terminals send without a key being pressed: focus
+/// changes (`ESC [ I` and `ESC [ O`) and cursor position reports
+/// (`ESC [ <row> ; <column> R`).
+fn strip_reports(keys: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(keys.len());
+    let mut rest = keys;
+    while !rest.is_empty() {
+        if let Some(len) = report_len(rest) {
+            rest = &rest[len..];
+            continue;
+        }
+        out.push(rest[0]);
+        rest = &rest[1..];
+    }
+    out
+}
+
+/// The length of the report at the start of the keys, if there's one there.
+fn report_len(keys: &[u8]) -> Option<usize> {
+    let params = keys.strip_prefix(b"\x1b[")?;
+    if matches!(params.first(), Some(b'I' | b'O')) {
+        return Some(3);
+    }
+    let end = params
+        .iter()
+        .position(|&b| !(b.is_ascii_digit() || b == b';'))?;
+    let numbers = params[..end].split(|&b| b == b';').collect::<Vec<_>>();
+    let is_position =
+        params[end] == b'R' && numbers.len() == 2 && numbers.iter().all(|n| !n.is_empty());
+    is_position.then_some(2 + end + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_pastes_across_reads() {
+        let mut filter = InputFilter::default();
+        assert_eq!(
+            filter.filter(b"j\x1b[200~hello"),
+            Input {
+                keys: b"j".to_vec(),
+                pasted: b"hello".to_vec(),
+            }
+        );
+        assert_eq!(
+            filter.filter(b" world\x1b[201~k"),
+            Input {
+                keys: b"k".to_vec(),
+                pasted: b" world".to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn strips_reports() {
+        let mut filter = InputFilter::default();
+        assert_eq!(filter.filter(b"\x1b[Ij\x1b[12;40Rk\x1b[O").keys, b"jk");
+        // arrow keys aren't reports
+        assert_eq!(filter.filter(b"\x1b[A").keys, b"\x1b[A");
+    }
+}
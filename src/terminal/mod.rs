@@ -1,13 +1,87 @@
 pub mod elements;
+mod input;
+pub mod modes;
+pub mod profile;
+pub mod screen;
+#[cfg(test)]
+pub mod testing;
 
-use elements::prelude::*;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{
+        atomic::{self, AtomicUsize},
+        Arc,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use elements::{prelude::*, Target, Theme};
+use input::{Input, InputFilter};
+use modes::TerminalModes;
+use profile::Profile;
+use screen::{Capabilities, ColorSupport, Screen};
+
+use crate::{
+    analytics::{self, Stats},
+    comments,
+    crawl::{list_lines, ImageSource, LanguageName, ListItem, PostSort, SiteData, STALE_NOTICE},
+    drafts,
+    locale::Locale,
+    motd::{self, Fragment},
+    protocols::{
+        plain_text,
+        qotd::Qotd,
+        render::{self, Link, PostVisitor},
+    },
+    related::{self, RelatedPost},
+    search, server_info, visitors, HOSTNAME,
+};
+use serde::{Deserialize, Serialize};
 
-use crate::crawl::{ImageSource, PostPart, SiteData};
+/// The number of terminal sessions that are currently open, across every
+/// protocol.
+static SESSION_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 /// A session for the terminal-based protocols (currently just ssh)
 pub struct TerminalSession {
-    location: Location,
     ctx: Context,
+
+    /// Keys that don't form a complete command yet, like the `5` in `5j`.
+    pending_keys: Vec<u8>,
+
+    /// The locations we can go back to, with the most recent one last.
+    history: Vec<Location>,
+    /// The locations we went back from, so they can be navigated to again.
+    forward_history: Vec<Location>,
+
+    /// The screen that the client currently has, so we only have to send what
+    /// changed.
+    previous_screen: Option<Screen>,
+
+    /// The name of the protocol this session is using, for analytics.
+    protocol: &'static str,
+    remote_ip: IpAddr,
+    /// The last location that was recorded in the analytics, so redrawing
+    /// doesn't count as another visit.
+    recorded_location: Option<Location>,
+    /// Takes pasted text and terminal reports out of the input.
+    input_filter: InputFilter,
+    /// Whether [`Self::on_open`] changed the client's terminal and
+    /// [`Self::on_close`] hasn't put it back yet.
+    screen_open: bool,
+    /// Whether a post's raw text was printed on the main screen with `r`, so
+    /// the next key goes back instead of doing anything else.
+    printed_raw: bool,
+    /// Whether the keyboard shortcuts are shown on top of the page, which is
+    /// done with `?`. Any key closes them again.
+    help_open: bool,
+    /// The pages that were already laid out, so scrolling only has to copy
+    /// rows out of them. They're all thrown away when anything they depend on
+    /// changes, which is checked with [`LayoutKey`], or when the message of
+    /// the day or the read posts change.
+    layouts: HashMap<Location, Layout>,
+    layout_key: Option<LayoutKey>,
 }
 
 #[derive(Default)]
@@ -15,42 +89,295 @@ pub struct Context {
     width: usize,
     height: usize,
 
-    site_data: SiteData,
+    /// Shared between the sessions, since it's never changed.
+    site_data: Arc<SiteData>,
+    /// Shown at the bottom of the home page, see [`TerminalSession::set_motd`].
+    motd: String,
+    /// The related posts for every post, by slug. They're found once when
+    /// the session starts instead of on every redraw.
+    related: HashMap<String, Vec<RelatedPost>>,
 
     link_index: Option<usize>,
+    /// The link that the mouse is over, which is underlined.
+    hovered_link: Option<usize>,
 
-    scroll: usize,
+    theme: Theme,
+    capabilities: Capabilities,
+    locale: Locale,
+    /// The tty settings from the client, see [`TerminalSession::set_modes`].
+    modes: TerminalModes,
+
+    location: Location,
+    /// The order of the posts on the blog page, changed with `o`.
+    blog_sort: PostSort,
+    /// Whether keys are being typed into the search box instead of being used
+    /// as commands.
+    typing: bool,
+    /// The scroll position for the locations that can be gone back or forward
+    /// to, so it can be restored. See [`TerminalSession::forget_scroll`].
+    scroll: HashMap<Location, usize>,
+    /// The posts that have been opened, for marking the unread ones on the blog
+    /// page. This is only kept for sessions with a [`Profile`].
+    read_posts: Option<HashSet<String>>,
+    /// When the visitor was last here, for marking the posts that are new to
+    /// them.
+    previous_visit: Option<DateTime<Utc>>,
 }
 
-#[derive(Default, Clone, Debug, Eq, PartialEq, Hash)]
+impl Context {
+    /// The name of the current page, shown in the status bar.
+    fn location_name(&self) -> String {
+        let strings = self.locale.strings();
+        match &self.location {
+            Location::Index => strings.home.to_owned(),
+            Location::Blog => strings.blog.to_owned(),
+            Location::Projects { language: None } => strings.projects.to_owned(),
+            Location::Projects {
+                language: Some(language),
+            } => format!("{} ({language})", strings.projects),
+            Location::Stats => strings.stats.to_owned(),
+            Location::ServerInfo => strings.server.to_owned(),
+            Location::Tags => strings.tags.to_owned(),
+            Location::Tag { name } => format!("{} {name}", strings.tagged),
+            Location::Search { .. } => strings.search.to_owned(),
+            Location::BlogPost { slug } => self
+                .site_data
+                .blog
+                .iter()
+                .find(|p| &p.slug == slug)
+                .map(|p| p.title.clone())
+                .unwrap_or_else(|| slug.clone()),
+        }
+    }
+
+    fn scroll(&self) -> usize {
+        self.scroll.get(&self.location).copied().unwrap_or_default()
+    }
+
+    fn set_scroll(&mut self, scroll: usize) {
+        self.scroll.insert(self.location.clone(), scroll);
+    }
+}
+
+#[derive(Default, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Location {
     #[default]
     Index,
     Blog,
-    Projects,
+    Projects {
+        /// Only show projects that use this language.
+        language: Option<LanguageName>,
+    },
     BlogPost {
         slug: String,
     },
+    Stats,
+    /// Uptime, ports, and the like.
+    ServerInfo,
+    Tags,
+    /// The posts with this tag.
+    Tag {
+        name: String,
+    },
+    Search {
+        query: String,
+    },
+}
+
+impl Location {
+    /// Whether the page shows numbers that change on their own, so it has to
+    /// be laid out again every time it's drawn.
+    fn is_live(&self) -> bool {
+        matches!(self, Location::Stats | Location::ServerInfo)
+    }
+
+    /// The path that's recorded in the analytics when this location is
+    /// visited. These match the paths used by the other protocols.
+    fn path(&self) -> String {
+        match self {
+            Location::Index => "/".to_owned(),
+            Location::Blog => "/blog".to_owned(),
+            Location::Projects { .. } => "/projects".to_owned(),
+            Location::BlogPost { slug } => format!("/{slug}"),
+            Location::Stats => "/stats".to_owned(),
+            Location::ServerInfo => "/about-server".to_owned(),
+            Location::Tags => "/tags".to_owned(),
+            Location::Tag { name } => format!("/tag/{name}"),
+            Location::Search { .. } => "/search".to_owned(),
+        }
+    }
 }
 
 impl TerminalSession {
-    pub fn new(site_data: SiteData) -> Self {
+    pub fn new(site_data: Arc<SiteData>, protocol: &'static str, remote_ip: IpAddr) -> Self {
+        SESSION_COUNT.fetch_add(1, atomic::Ordering::Relaxed);
         Self {
-            location: Location::default(),
             ctx: Context {
+                related: related::related_posts(&site_data.blog),
                 site_data,
                 ..Default::default()
             },
+            pending_keys: Vec::new(),
+            history: Vec::new(),
+            forward_history: Vec::new(),
+            previous_screen: None,
+            protocol,
+            remote_ip,
+            recorded_location: None,
+            input_filter: InputFilter::default(),
+            screen_open: false,
+            printed_raw: false,
+            help_open: false,
+            layouts: HashMap::new(),
+            layout_key: None,
+        }
+    }
+
+    /// Switch to the theme with the given name. Returns false if there's no
+    /// theme with that name.
+    pub fn set_theme(&mut self, name: &str) -> bool {
+        match Theme::from_name(name) {
+            Some(theme) => {
+                self.ctx.theme = theme.clone();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Continue from a previous session, and start keeping track of which
+    /// posts are read.
+    pub fn restore(&mut self, profile: Profile) {
+        self.set_theme(&profile.theme);
+        self.ctx.location = profile.location;
+        self.ctx.read_posts = Some(profile.read_posts);
+        self.layouts.clear();
+    }
+
+    /// What should be saved for the next session, if this one was restored
+    /// from a [`Profile`].
+    pub fn profile(&self) -> Option<Profile> {
+        Some(Profile {
+            theme: self.ctx.theme.name.to_owned(),
+            location: self.ctx.location.clone(),
+            read_posts: self.ctx.read_posts.clone()?,
+        })
+    }
+
+    /// Mark the posts that were published after the visitor's previous visit,
+    /// see [`visitors::visit`].
+    pub fn set_previous_visit(&mut self, previous_visit: Option<DateTime<Utc>>) {
+        self.ctx.previous_visit = previous_visit;
+    }
+
+    /// Show the message of the day at the bottom of the home page. It's
+    /// everything except the art, since the page already has a title.
+    pub fn set_motd(&mut self, qotd: &Qotd) {
+        self.ctx.motd = motd::compose(
+            motd::fragments()
+                .iter()
+                .filter(|&&fragment| fragment != Fragment::Art),
+            &self.ctx.site_data,
+            qotd,
+        );
+        self.layouts.clear();
+    }
+
+    /// Switch the language of the UI.
+    pub fn set_locale(&mut self, locale: Locale) {
+        self.ctx.locale = locale;
+    }
+
+    /// Set the terminal type (like `xterm-256color`) that the client told us,
+    /// which is used to figure out what colors we can use.
+    pub fn set_terminal_type(&mut self, terminal_type: &str) {
+        let colors = ColorSupport::from_terminal_type(terminal_type);
+        self.set_capabilities(Capabilities {
+            colors,
+            ..self.ctx.capabilities
+        });
+    }
+
+    /// Set whether the client can display UTF-8. If it can't, everything is
+    /// sent as ASCII.
+    pub fn set_utf8(&mut self, utf8: bool) {
+        self.set_capabilities(Capabilities {
+            utf8,
+            ..self.ctx.capabilities
+        });
+    }
+
+    /// Use the client's tty settings, for which keys close the session and
+    /// whether what's typed is shown.
+    pub fn set_modes(&mut self, modes: TerminalModes) {
+        self.ctx.modes = modes;
+    }
+
+    pub fn modes(&self) -> TerminalModes {
+        self.ctx.modes
+    }
+
+    /// Whether the keys should close the session. That's the interrupt key,
+    /// and the end of file key unless it's `^D`, which scrolls instead.
+    pub fn is_exit_key(&self, keys: &[u8]) -> bool {
+        let modes = self.ctx.modes;
+        let eof = modes.eof.filter(|&eof| eof != 4);
+        [modes.intr, eof]
+            .into_iter()
+            .flatten()
+            .any(|key| keys == [key])
+    }
+
+    fn set_capabilities(&mut self, capabilities: Capabilities) {
+        if capabilities != self.ctx.capabilities {
+            self.ctx.capabilities = capabilities;
+            // everything has to be sent again with the new capabilities
+            self.previous_screen = None;
         }
     }
 
     pub fn resize(&mut self, width: u32, height: u32) -> Vec<u8> {
-        self.ctx.width = width as usize;
-        self.ctx.height = height as usize;
-        self.page().rendered
+        // the client can say anything, and the screen has a cell for every
+        // character
+        self.ctx.width = (width as usize).min(MAX_WIDTH);
+        self.ctx.height = (height as usize).min(MAX_HEIGHT);
+        self.draw()
     }
 
-    pub fn on_keystroke(&mut self, keys: &[u8]) -> Vec<u8> {
+    /// Handle input from the client. Pasted text is never treated as keys,
+    /// and reports that the terminal sends by itself are ignored.
+    pub fn on_keystroke(&mut self, input: &[u8]) -> Vec<u8> {
+        let Input { keys, pasted } = self.input_filter.filter(input);
+        let mut out = Vec::new();
+        if !keys.is_empty() {
+            out.extend(self.on_keys(&keys));
+        }
+        if !pasted.is_empty() {
+            out.extend(self.on_paste(&pasted));
+        }
+        out
+    }
+
+    fn on_keys(&mut self, keys: &[u8]) -> Vec<u8> {
+        if std::mem::take(&mut self.printed_raw) {
+            let mut out = self.on_open();
+            // the alternate screen might've been cleared when we left it
+            self.previous_screen = None;
+            out.extend(self.draw());
+            return out;
+        }
+        if self.help_open {
+            // moving the mouse over it doesn't count as pressing a key
+            if keys.starts_with(&[27, 91, 60]) {
+                return vec![];
+            }
+            self.help_open = false;
+            return self.draw();
+        }
+        if self.ctx.typing {
+            return self.on_search_input(keys);
+        }
+
         let page = self.page();
 
         // tab
@@ -60,7 +387,7 @@ impl TerminalSession {
             } else {
                 self.ctx.link_index = Some(0);
             }
-            return self.page().rendered;
+            return self.draw();
         }
         // shift+tab
         else if keys == [27, 91, 90] {
@@ -69,46 +396,38 @@ impl TerminalSession {
             } else {
                 self.ctx.link_index = Some(0);
             }
-            return self.page().rendered;
+            return self.draw();
         }
         // enter
         else if keys == b"\r" || keys == b"\r\n" {
             if let Some(index) = self.ctx.link_index {
-                if let Some((location, _)) = page.links.get(index) {
-                    self.location = location.clone();
-                    self.ctx.scroll = 0;
-                    self.ctx.link_index = None;
-                    return self.page().rendered;
+                if let Some((target, _)) = page.links.get(index) {
+                    self.follow(target.clone(), &page);
+                    return self.draw();
                 }
             }
         }
         // down arrow key
         else if keys == [27, 91, 66] {
-            self.ctx.scroll += 2;
-            return self.page().rendered;
+            self.ctx.set_scroll(self.ctx.scroll() + 2);
+            return self.draw();
         }
         // scroll up
         else if keys == [27, 91, 65] {
-            if self.ctx.scroll >= 2 {
-                self.ctx.scroll -= 2;
-            } else {
-                self.ctx.scroll = 0;
-            }
-            return self.page().rendered;
+            self.ctx.set_scroll(self.ctx.scroll().saturating_sub(2));
+            return self.draw();
         }
         // page up
         else if keys == [27, 91, 53, 126] {
-            if self.ctx.scroll >= self.ctx.height {
-                self.ctx.scroll -= self.ctx.height;
-            } else {
-                self.ctx.scroll = 0;
-            }
-            return self.page().rendered;
+            self.ctx
+                .set_scroll(self.ctx.scroll().saturating_sub(self.ctx.view_height()));
+            return self.draw();
         }
         // page down
         else if keys == [27, 91, 54, 126] {
-            self.ctx.scroll += self.ctx.height;
-            return self.page().rendered;
+            self.ctx
+                .set_scroll(self.ctx.scroll() + self.ctx.view_height());
+            return self.draw();
         } else if let Some(keys) = keys.strip_prefix(&[27, 91, 60]) {
             // https://invisible-island.net/xterm/ctlseqs/ctlseqs.html#h3-Extended-coordinates
             let Some((&last, keys)) = keys.split_last() else {
@@ -136,48 +455,283 @@ impl TerminalSession {
                 return vec![];
             };
             let is_pressed = last == b'M';
+            let mouse_position = Position {
+                x: px as isize - 1,
+                y: py as isize - 1,
+            };
 
             match button_value.as_str() {
+                // left mouse click, or dragging with it held down
+                "0" | "32" if is_pressed && self.ctx.is_on_scrollbar(&page, &mouse_position) => {
+                    // jump so the thumb is where the mouse is
+                    let view_height = self.ctx.view_height();
+                    self.ctx
+                        .set_scroll(mouse_position.y as usize * page.height / view_height);
+                    return self.draw();
+                }
                 "0" if is_pressed => {
                     // left mouse click
-                    let page = self.page();
-                    // find if we clicked on a link
-                    let mouse_position = Position {
-                        x: px as isize - 1,
-                        y: py as isize - 1,
-                    };
-                    for (location, positions) in page.links {
-                        if positions.contains(&mouse_position) {
-                            self.location = location.clone();
-                            self.ctx.scroll = 0;
-                            self.ctx.link_index = None;
-                            return self.page().rendered;
-                        }
+                    if let Some(index) = page.link_at(&mouse_position) {
+                        self.follow(page.links[index].0.clone(), &page);
+                        return self.draw();
                     }
                 }
+                "35" => {
+                    // the mouse moved without any buttons pressed
+                    self.ctx.hovered_link = page.link_at(&mouse_position);
+                    return self.draw();
+                }
                 "65" => {
                     // scroll down
-                    self.ctx.scroll += 2;
-                    return self.page().rendered;
+                    self.ctx.set_scroll(self.ctx.scroll() + 2);
+                    return self.draw();
                 }
                 "64" => {
                     // scroll up
-                    if self.ctx.scroll >= 2 {
-                        self.ctx.scroll -= 2;
-                    } else {
-                        self.ctx.scroll = 0;
-                    }
-                    return self.page().rendered;
+                    self.ctx.set_scroll(self.ctx.scroll().saturating_sub(2));
+                    return self.draw();
                 }
                 _ => {}
             }
+        } else if !keys.starts_with(&[27]) {
+            return self.on_vim_keys(keys);
         }
 
         vec![]
     }
 
-    pub fn on_open(&self) -> Vec<u8> {
+    /// Handle vim-style keybindings. Multiple keys might be sent at once, and
+    /// count prefixes (like `5j`) are kept in `pending_keys` until the command
+    /// is complete.
+    fn on_vim_keys(&mut self, keys: &[u8]) -> Vec<u8> {
+        let mut changed = false;
+        for &key in keys {
+            // a 0 at the start isn't a count
+            if key.is_ascii_digit() && !(key == b'0' && self.pending_keys.is_empty()) {
+                self.pending_keys.push(key);
+                continue;
+            }
+            let count = String::from_utf8_lossy(&self.pending_keys)
+                .parse::<usize>()
+                .unwrap_or(1);
+            self.pending_keys.clear();
+
+            let half_page = usize::max(1, self.ctx.view_height() / 2);
+            match key {
+                b'j' => self.ctx.set_scroll(self.ctx.scroll().saturating_add(count)),
+                b'k' => self.ctx.set_scroll(self.ctx.scroll().saturating_sub(count)),
+                b'g' => self.ctx.set_scroll(0),
+                // this gets clamped when the page is rendered
+                b'G' => self.ctx.set_scroll(usize::MAX),
+                // ctrl+d
+                4 => self.ctx.set_scroll(
+                    self.ctx
+                        .scroll()
+                        .saturating_add(half_page.saturating_mul(count)),
+                ),
+                // ctrl+u
+                21 => self.ctx.set_scroll(
+                    self.ctx
+                        .scroll()
+                        .saturating_sub(half_page.saturating_mul(count)),
+                ),
+                // backspace
+                b'u' | 8 | 127 => self.back(),
+                // ctrl+r
+                18 => self.forward(),
+                b'h' => self.navigate(Location::Index),
+                b'b' => self.navigate(Location::Blog),
+                b'p' => self.navigate(Location::Projects { language: None }),
+                b's' => self.navigate(Location::Stats),
+                b'/' => self.navigate(Location::Search {
+                    query: String::new(),
+                }),
+                b'f' => self.next_project_filter(),
+                b'o' => self.next_blog_sort(),
+                b't' => self.ctx.theme = self.ctx.theme.next().clone(),
+                b'?' => self.help_open = true,
+                b'r' => {
+                    if let Some(out) = self.print_raw() {
+                        return out;
+                    }
+                    continue;
+                }
+                _ => continue,
+            }
+            changed = true;
+        }
+
+        if changed {
+            self.draw()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Type into the search box. Enter, tab, or escape stop typing so the
+    /// results can be navigated.
+    fn on_search_input(&mut self, keys: &[u8]) -> Vec<u8> {
+        let Location::Search { query } = &mut self.ctx.location else {
+            self.ctx.typing = false;
+            return vec![];
+        };
+        let erase = self.ctx.modes.erase;
+        match keys {
+            b"\r" | b"\r\n" | b"\t" | [27] => self.ctx.typing = false,
+            // backspace
+            [8] | [127] => {
+                query.pop();
+            }
+            &[key] if Some(key) == erase => {
+                query.pop();
+            }
+            // arrow keys and mouse events
+            _ if keys.starts_with(&[27]) => return vec![],
+            _ => {
+                let room = MAX_QUERY_LENGTH.saturating_sub(query.chars().count());
+                query.extend(
+                    String::from_utf8_lossy(keys)
+                        .chars()
+                        .filter(|c| !c.is_control())
+                        .take(room),
+                );
+            }
+        }
+        self.ctx.link_index = None;
+        self.draw()
+    }
+
+    /// Pasted text goes into the search box if it's being typed in, and is
+    /// thrown away otherwise.
+    fn on_paste(&mut self, pasted: &[u8]) -> Vec<u8> {
+        if !self.ctx.typing {
+            return vec![];
+        }
+        let Location::Search { query } = &mut self.ctx.location else {
+            return vec![];
+        };
+        let room = MAX_QUERY_LENGTH.saturating_sub(query.chars().count());
+        query.extend(
+            String::from_utf8_lossy(pasted)
+                .chars()
+                // so pasting a few lines doesn't run them together
+                .map(|c| if c.is_whitespace() { ' ' } else { c })
+                .filter(|c| !c.is_control())
+                .take(room),
+        );
+        self.ctx.link_index = None;
+        self.draw()
+    }
+
+    fn follow(&mut self, target: Target, page: &Page) {
+        match target {
+            Target::Location(location) => self.navigate(location),
+            Target::Anchor(name) => {
+                if let Some((_, y)) = page.anchors.iter().find(|(anchor, _)| *anchor == name) {
+                    self.ctx.set_scroll(*y);
+                }
+            }
+        }
+    }
+
+    fn navigate(&mut self, location: Location) {
+        self.ctx.typing = matches!(location, Location::Search { .. });
+        let previous_location = std::mem::replace(&mut self.ctx.location, location);
+        self.push_history(previous_location);
+        self.forward_history.clear();
+        self.ctx.set_scroll(0);
+        self.ctx.link_index = None;
+        self.ctx.hovered_link = None;
+    }
+
+    /// Cycle through the languages that the projects page can be filtered by.
+    /// This doesn't get added to the history.
+    fn next_project_filter(&mut self) {
+        let Location::Projects { language } = self.ctx.location else {
+            return;
+        };
+        let next_language = match language {
+            None => Some(LanguageName::ALL[0]),
+            Some(language) => LanguageName::ALL
+                .iter()
+                .position(|&l| l == language)
+                .and_then(|i| LanguageName::ALL.get(i + 1))
+                .copied(),
+        };
+        self.ctx.location = Location::Projects {
+            language: next_language,
+        };
+        self.ctx.link_index = None;
+    }
+
+    fn next_blog_sort(&mut self) {
+        if self.ctx.location != Location::Blog {
+            return;
+        }
+        self.ctx.blog_sort = match self.ctx.blog_sort {
+            PostSort::Date => PostSort::Length,
+            PostSort::Length => PostSort::Date,
+        };
+        self.ctx.link_index = None;
+    }
+
+    /// Go to the previous location in the history, keeping the scroll position
+    /// it had before.
+    fn back(&mut self) {
+        if let Some(location) = self.history.pop() {
+            let previous_location = std::mem::replace(&mut self.ctx.location, location);
+            self.forward_history.push(previous_location);
+            self.ctx.link_index = None;
+        }
+    }
+
+    /// Undo a [`Self::back`].
+    fn forward(&mut self) {
+        if let Some(location) = self.forward_history.pop() {
+            let previous_location = std::mem::replace(&mut self.ctx.location, location);
+            self.push_history(previous_location);
+            self.ctx.link_index = None;
+        }
+    }
+
+    /// Forget the scroll positions of the locations that can't be gone back or
+    /// forward to anymore, so they don't pile up in long sessions.
+    fn forget_scroll(&mut self) {
+        if self.ctx.scroll.len() <= self.history.len() + self.forward_history.len() + 1 {
+            return;
+        }
+        let Self {
+            ctx,
+            history,
+            forward_history,
+            ..
+        } = self;
+        ctx.scroll.retain(|location, _| {
+            *location == ctx.location
+                || history.contains(location)
+                || forward_history.contains(location)
+        });
+    }
+
+    /// Add a location that can be gone back to, forgetting the oldest one if
+    /// there are too many.
+    fn push_history(&mut self, location: Location) {
+        if self.history.len() >= MAX_HISTORY {
+            self.history.remove(0);
+        }
+        self.history.push(location);
+    }
+
+    /// Take over the client's terminal. It's drawn on the alternate screen, so
+    /// their scrollback is still there when we're done.
+    pub fn on_open(&mut self) -> Vec<u8> {
+        self.screen_open = true;
         let mut out = String::new();
+        // save the cursor, for terminals that don't do it when switching
+        // screens
+        out.push_str("\x1b7");
+        // switch to the alternate screen
+        out.push_str("\x1b[?1049h");
         // hide the cursor
         out.push_str("\x1b[?25l");
         // disable line wrap
@@ -186,158 +740,812 @@ impl TerminalSession {
         out.push_str("\x1b[?1003h");
         // enable "extended coordinates"
         out.push_str("\x1b[?1006h");
+        // so pasted text can be told apart from keys
+        out.push_str("\x1b[?2004h");
         out.as_bytes().to_vec()
     }
 
-    pub fn on_close(&self) -> Vec<u8> {
+    /// Put the client's terminal back the way it was. This only does
+    /// something the first time, so it can be called again when the
+    /// connection ends in case something went wrong before it was closed
+    /// normally.
+    pub fn on_close(&mut self) -> Vec<u8> {
+        let mut out = self.leave_screen();
+        if out.is_empty() {
+            return vec![];
+        }
+        out.push_str("Bye!\r\n");
+        out.as_bytes().to_vec()
+    }
+
+    /// Undo everything [`Self::on_open`] did, or nothing if it's already been
+    /// undone.
+    fn leave_screen(&mut self) -> String {
+        if !std::mem::take(&mut self.screen_open) {
+            return String::new();
+        }
         let mut out = String::new();
         // give them their cursor back lol
         out.push_str("\x1b[?25h");
         out.push_str("\x1b[?7h");
         out.push_str("\x1b[?1003l");
         out.push_str("\x1b[?1006l");
-        out.push_str("Bye!\r\n");
+        out.push_str("\x1b[?2004l");
+        // back to the main screen where they were
+        out.push_str("\x1b[?1049l");
+        out.push_str("\x1b8");
+        out
+    }
+
+    /// Print the post that's open as plain text on the main screen, where it
+    /// can be selected and copied like the rest of their scrollback. Returns
+    /// `None` if there's no post open.
+    fn print_raw(&mut self) -> Option<Vec<u8>> {
+        let Location::BlogPost { slug } = &self.ctx.location else {
+            return None;
+        };
+        let post = self.ctx.site_data.blog.iter().find(|p| &p.slug == slug)?;
+        let text = plain_text::raw_post(post);
+
+        let mut out = self.leave_screen();
+        for line in text.lines() {
+            out.push_str(line);
+            out.push_str("\r\n");
+        }
+        out.push_str("\r\n(press any key to go back)\r\n");
+        self.printed_raw = true;
+        Some(out.as_bytes().to_vec())
+    }
+
+    /// Render the current page and return what has to be sent to the client to
+    /// display it.
+    pub fn draw(&mut self) -> Vec<u8> {
+        // it'd be drawn over the printed text
+        if self.printed_raw {
+            return vec![];
+        }
+        self.record_visit();
+        self.forget_scroll();
+        if let (Location::BlogPost { slug }, Some(read_posts)) =
+            (&self.ctx.location, &mut self.ctx.read_posts)
+        {
+            if read_posts.insert(slug.clone()) {
+                self.layouts.clear();
+            }
+        }
+        let screen = if self.ctx.is_too_small() {
+            too_small_screen(&self.ctx)
+        } else {
+            let mut screen = self.page().screen;
+            if self.help_open {
+                render_help(&self.ctx, &mut screen);
+            }
+            screen
+        };
+        let mut out = screen.diff(self.previous_screen.as_ref(), self.ctx.capabilities);
+        self.previous_screen = Some(screen);
+        if out.is_empty() {
+            return vec![];
+        }
+        out.push_str("\x1b[H"); // Move cursor to top left
         out.as_bytes().to_vec()
     }
 
+    /// The whole page at the location as plain text, laid out for a terminal
+    /// that's `width` columns wide. It isn't recorded as a visit.
+    pub fn render_text(&mut self, location: Location, width: usize) -> String {
+        self.ctx.location = location;
+        self.ctx.width = width.min(MAX_WIDTH);
+        // laid out once to see how tall it is, and then on a screen that it
+        // fits on so there's no scrolling
+        self.ctx.height = STATUS_LINE_HEIGHT + 1;
+        let height = self.page().height;
+        self.ctx.height = height + STATUS_LINE_HEIGHT;
+        let text = self.page().screen.text();
+        // without the status line
+        text.lines()
+            .take(height)
+            .map(|line| format!("{line}\n"))
+            .collect()
+    }
+
+    /// Add the current location to the analytics if it wasn't already the
+    /// last one recorded.
+    fn record_visit(&mut self) {
+        // wait until they're done typing so every key isn't a visit
+        if self.ctx.typing || self.recorded_location.as_ref() == Some(&self.ctx.location) {
+            return;
+        }
+        let post = match &self.ctx.location {
+            Location::BlogPost { slug } => Some(slug.as_str()),
+            _ => None,
+        };
+        analytics::record(
+            self.protocol,
+            &self.ctx.location.path(),
+            post,
+            self.remote_ip,
+        );
+        self.recorded_location = Some(self.ctx.location.clone());
+    }
+
     fn page(&mut self) -> Page {
-        match &self.location {
-            Location::Index => index_page(&mut self.ctx),
-            Location::Blog => blog_page(&mut self.ctx),
-            Location::BlogPost { slug } => blog_post_page(&mut self.ctx, slug),
-            Location::Projects => projects_page(&mut self.ctx),
+        let key = LayoutKey::new(&self.ctx);
+        if self.layout_key.as_ref() != Some(&key) {
+            self.layouts.clear();
+            self.layout_key = Some(key);
+        }
+
+        let location = self.ctx.location.clone();
+        if location.is_live() {
+            let layout = self.layout();
+            return Page::new(&mut self.ctx, &layout);
+        }
+        if !self.layouts.contains_key(&location) {
+            // long posts take a lot of cells, so don't keep every page that
+            // was visited
+            if self.layouts.len() >= MAX_CACHED_LAYOUTS {
+                self.layouts.clear();
+            }
+            let layout = self.layout();
+            self.layouts.insert(location.clone(), layout);
+        }
+        Page::new(&mut self.ctx, &self.layouts[&location])
+    }
+
+    /// Lay out the whole page at the current location.
+    fn layout(&self) -> Layout {
+        let ctx = &self.ctx;
+        match &ctx.location {
+            Location::Index => index_page(ctx),
+            Location::Stats => stats_page(ctx),
+            Location::ServerInfo => server_info_page(ctx),
+            Location::Blog => blog_page(ctx),
+            Location::Tags => tags_page(ctx),
+            Location::Tag { name } => tag_page(ctx, name),
+            Location::Search { query } => search_page(ctx, query),
+            Location::BlogPost { slug } => blog_post_page(ctx, slug),
+            Location::Projects { language } => projects_page(ctx, *language),
+        }
+    }
+}
+
+impl Drop for TerminalSession {
+    fn drop(&mut self) {
+        SESSION_COUNT.fetch_sub(1, atomic::Ordering::Relaxed);
+    }
+}
+
+/// Everything in the context that pages are laid out from, besides the
+/// location. The scroll position isn't in it, so scrolling can reuse the
+/// layouts.
+#[derive(PartialEq)]
+struct LayoutKey {
+    width: usize,
+    height: usize,
+    theme: &'static str,
+    locale: Locale,
+    modes: TerminalModes,
+    blog_sort: PostSort,
+    typing: bool,
+    previous_visit: Option<DateTime<Utc>>,
+}
+
+impl LayoutKey {
+    fn new(ctx: &Context) -> Self {
+        LayoutKey {
+            width: ctx.width,
+            height: ctx.height,
+            theme: ctx.theme.name,
+            locale: ctx.locale,
+            modes: ctx.modes,
+            blog_sort: ctx.blog_sort,
+            typing: ctx.typing,
+            previous_visit: ctx.previous_visit,
         }
     }
 }
 
+/// A whole page, drawn on a screen that's as tall as it is.
+struct Layout {
+    screen: Screen,
+    links: Vec<(Target, Vec<Position>)>,
+    /// The anchors on the page and the rows they're on.
+    anchors: Vec<(String, usize)>,
+    /// The height of the page, which is less than the screen's when the page
+    /// is shorter than the window.
+    height: usize,
+}
+
+/// The part of a [`Layout`] that's in the window.
 struct Page {
-    rendered: Vec<u8>,
-    links: Vec<(Location, Vec<Position>)>,
+    screen: Screen,
+    links: Vec<(Target, Vec<Position>)>,
+    /// The anchors on the page and the rows they're on, counted from the top
+    /// of the page.
+    anchors: Vec<(String, usize)>,
+    /// The height of the whole page, not just the part that's on the screen.
+    height: usize,
 }
 
-impl Page {
-    pub fn new(ctx: &mut Context, max_width: usize, elements: Vec<Element>) -> Self {
-        let width = max_width.min(ctx.width);
-        let left = (ctx.width - width) / 2;
-
-        let tree = Element::Rectangle {
-            elements: elements.clone(),
-            rect: Rectangle {
-                left: left as isize,
-                top: -(ctx.scroll as isize),
-                width,
-                height: ctx.height,
-            },
-        };
+/// The number of rows at the bottom of the window that are reserved for the
+/// status line.
+const STATUS_LINE_HEIGHT: usize = 1;
+/// The number of columns at the right of the window that are reserved for the
+/// scrollbar.
+const SCROLLBAR_WIDTH: usize = 1;
+/// The smallest window that pages can be laid out in. Anything smaller only
+/// gets asked to be resized.
+const MIN_WIDTH: usize = 40;
+const MIN_HEIGHT: usize = 10;
+/// The biggest window that's drawn. Bigger windows only get this much of it.
+const MAX_WIDTH: usize = 500;
+const MAX_HEIGHT: usize = 200;
+/// How many locations can be gone back to.
+const MAX_HISTORY: usize = 100;
+/// The longest search query, in characters.
+const MAX_QUERY_LENGTH: usize = 256;
+/// How many pages are kept laid out for each session.
+const MAX_CACHED_LAYOUTS: usize = 8;
+/// Windows narrower than this get the compact layout, where the headers and
+/// navigation take up less room.
+const COMPACT_WIDTH: usize = 60;
+
+impl Context {
+    /// The number of rows that can be used by the page content.
+    fn view_height(&self) -> usize {
+        self.height.saturating_sub(STATUS_LINE_HEIGHT)
+    }
+
+    fn is_compact(&self) -> bool {
+        self.width < COMPACT_WIDTH
+    }
 
-        let mut out: String = String::new();
+    fn is_too_small(&self) -> bool {
+        self.width < MIN_WIDTH || self.height < MIN_HEIGHT
+    }
+
+    /// Whether the position is on the page's scrollbar. It's only there when
+    /// the page doesn't fit in the window.
+    fn is_on_scrollbar(&self, page: &Page, position: &Position) -> bool {
+        page.height > self.view_height()
+            && position.x == self.width as isize - SCROLLBAR_WIDTH as isize
+            && (0..self.view_height() as isize).contains(&position.y)
+    }
+}
+
+impl Layout {
+    /// Lay out the whole page, from the top, on a screen that's as tall as it
+    /// is.
+    pub fn new(ctx: &Context, max_width: usize, elements: Vec<Element>) -> Self {
         let mut data = elements::Data {
             links: vec![],
-            link_index: ctx.link_index,
-        };
-        out.push_str("\x1b[2J\x1b[H"); // Clear screen
-        let mut position = Position {
-            x: 0,
-            y: -(ctx.scroll as isize),
+            anchors: vec![],
+            theme: ctx.theme.clone(),
         };
-        let initial_position = position.clone();
-        out.push_str(&tree.render(
-            &mut position,
-            // this one doesn't matter since it'll get overwritten by the Element::Rectangle
-            &Rectangle {
-                left: 0,
-                top: 0,
-                width: ctx.width,
-                height: ctx.height,
-            },
-            // this is the window size
-            &Rectangle {
-                left: 0,
-                top: 0,
-                width: ctx.width,
-                height: ctx.height,
-            },
-            &mut data,
-        ));
-        out.push_str("\x1b[H"); // Move cursor to top left
 
-        let page_height = (position.y - initial_position.y) as usize;
+        // render once to get the height of the page, so the screen can be
+        // made just tall enough for it
+        let height = render_elements(
+            ctx,
+            max_width,
+            &elements,
+            &mut data.clone(),
+            &mut Screen::default(),
+        );
+        // pages that are shorter than the window still get all of it, like they
+        // did before they were cached, so things that are centered in the
+        // window and the rows after them are all drawn
+        let mut screen = Screen::new(ctx.width, height.max(ctx.view_height()));
+        render_elements(ctx, max_width, &elements, &mut data, &mut screen);
 
-        // clamp scroll
-        let original_scroll = ctx.scroll;
-        if ctx.scroll + ctx.height > page_height {
-            ctx.scroll = isize::max(0, page_height as isize - ctx.height as isize) as usize;
-            if ctx.scroll < original_scroll {
-                // yes i know this is inefficient
-                // i do not care
-                return Self::new(ctx, max_width, elements);
-            }
+        Layout {
+            screen,
+            links: data.links,
+            anchors: data
+                .anchors
+                .into_iter()
+                .map(|(name, y)| (name, y.max(0) as usize))
+                .collect(),
+            height,
+        }
+    }
+}
+
+impl Page {
+    /// The part of the layout that's in the window at the current scroll
+    /// position, with the scrollbar and status line drawn on top.
+    fn new(ctx: &mut Context, layout: &Layout) -> Self {
+        let height = layout.height;
+        ctx.set_scroll(usize::min(
+            ctx.scroll(),
+            height.saturating_sub(ctx.view_height()),
+        ));
+        let scroll = ctx.scroll();
+
+        let mut screen = Screen::new(ctx.width, ctx.height);
+        screen.copy_rows(&layout.screen, scroll, ctx.view_height());
+        if let Some(index) = ctx.link_index {
+            screen.format_link(index, "7");
         }
+        if let Some(index) = ctx
+            .hovered_link
+            .filter(|&index| ctx.link_index != Some(index))
+        {
+            screen.format_link(index, "4"); // underline
+        }
+        render_overlay(ctx, height, &mut screen);
+
+        let links = layout
+            .links
+            .iter()
+            .map(|(target, positions)| {
+                let positions = positions
+                    .iter()
+                    .map(|position| Position {
+                        x: position.x,
+                        y: position.y - scroll as isize,
+                    })
+                    .collect();
+                (target.clone(), positions)
+            })
+            .collect();
 
         Page {
-            rendered: out.as_bytes().to_vec(),
-            links: data.links,
+            screen,
+            links,
+            anchors: layout.anchors.clone(),
+            height,
         }
     }
+
+    /// The index of the link at the position, for clicking and hovering.
+    fn link_at(&self, position: &Position) -> Option<usize> {
+        self.links
+            .iter()
+            .position(|(_, positions)| positions.contains(position))
+    }
 }
 
-fn index_page(ctx: &mut Context) -> Page {
-    Page::new(
-        ctx,
-        50,
+/// Render the elements from the top of the page. Returns the total height of
+/// the page.
+fn render_elements(
+    ctx: &Context,
+    max_width: usize,
+    elements: &[Element],
+    data: &mut elements::Data,
+    screen: &mut Screen,
+) -> usize {
+    let window_width = ctx.width.saturating_sub(SCROLLBAR_WIDTH);
+    let width = max_width.min(window_width);
+    let left = (window_width - width) / 2;
+
+    let tree = Element::Rectangle {
+        elements: elements.to_vec(),
+        rect: Rectangle {
+            left: left as isize,
+            top: 0,
+            width,
+            // things are centered vertically in the window and not the page
+            height: ctx.view_height(),
+        },
+    };
+
+    let mut position = Position { x: 0, y: 0 };
+    let initial_position = position.clone();
+    tree.render(
+        &mut position,
+        // this one doesn't matter since it'll get overwritten by the Element::Rectangle
+        &Rectangle {
+            left: 0,
+            top: 0,
+            width: ctx.width,
+            height: ctx.view_height(),
+        },
+        // this is everything that gets drawn, which is the whole screen since
+        // it's as tall as the page
+        &Rectangle {
+            left: 0,
+            top: 0,
+            width: ctx.width,
+            height: screen.height,
+        },
+        data,
+        screen,
+    );
+
+    (position.y - initial_position.y) as usize
+}
+
+/// Draw the scrollbar and the status line on top of the page.
+fn render_overlay(ctx: &Context, page_height: usize, screen: &mut Screen) {
+    if ctx.width == 0 || ctx.height == 0 {
+        return;
+    }
+    let view_height = ctx.view_height();
+    let scroll = ctx.scroll();
+    if !ctx.theme.secondary.is_empty() {
+        screen.style.formats = vec![ctx.theme.secondary.to_string()];
+    }
+
+    // the scrollbar is only shown if the page doesn't fit in the window
+    if view_height > 0 && page_height > view_height {
+        let thumb_height = usize::max(1, view_height * view_height / page_height);
+        let thumb_top = if scroll + view_height >= page_height {
+            // make sure the thumb touches the bottom when we're scrolled all the way down
+            view_height - thumb_height
+        } else {
+            usize::min(
+                scroll * view_height / page_height,
+                view_height - thumb_height,
+            )
+        };
+
+        for y in 0..view_height {
+            let c = if (thumb_top..thumb_top + thumb_height).contains(&y) {
+                '█'
+            } else {
+                '│'
+            };
+            screen.put(
+                &Position {
+                    x: (ctx.width - SCROLLBAR_WIDTH) as isize,
+                    y: y as isize,
+                },
+                c,
+            );
+        }
+    }
+
+    // the status bar has the page name on the left and everything else on the
+    // right. narrow windows only get the line number, and the name is cut off
+    // if it still doesn't fit.
+    let status_y = (ctx.height - STATUS_LINE_HEIGHT) as isize;
+    let name = ctx.location_name();
+    let current_line = usize::min(scroll + 1, page_height);
+    let mut status = format!(
+        "{online} online │ {time} │ line {current_line} of {page_height} ",
+        online = SESSION_COUNT.load(atomic::Ordering::Relaxed),
+        time = chrono::Utc::now().format("%H:%M UTC"),
+    );
+    // a space before the name and at least one after it
+    if name.chars().count() + status.chars().count() + 2 > ctx.width {
+        status = format!("line {current_line} of {page_height} ");
+    }
+    let room = ctx.width.saturating_sub(status.chars().count() + 2);
+    let name = match room {
+        _ if name.chars().count() <= room => name,
+        0 => String::new(),
+        _ => {
+            let mut name = name.chars().take(room - 1).collect::<String>();
+            name.push('…');
+            name
+        }
+    };
+    screen.put_str(&Position { x: 1, y: status_y }, &name);
+    screen.put_str(
+        &Position {
+            x: ctx.width.saturating_sub(status.chars().count()) as isize,
+            y: status_y,
+        },
+        &status,
+    );
+    screen.style = Default::default();
+}
+
+/// Shown instead of the page when the window is too small to lay it out.
+fn too_small_screen(ctx: &Context) -> Screen {
+    let mut screen = Screen::new(ctx.width, ctx.height);
+    let window = Rectangle {
+        left: 0,
+        top: 0,
+        width: ctx.width,
+        height: ctx.height,
+    };
+    let mut data = elements::Data {
+        links: vec![],
+        anchors: vec![],
+        theme: ctx.theme.clone(),
+    };
+    let message = format!("please resize to at least {MIN_WIDTH}x{MIN_HEIGHT}");
+    vertically_centered(horizontally_centered(text(&message))).render(
+        &mut Position::default(),
+        &window,
+        &window,
+        &mut data,
+        &mut screen,
+    );
+    screen
+}
+
+/// Draw a box with the keyboard shortcuts in the middle of the screen, on top
+/// of whatever's there.
+fn render_help(ctx: &Context, screen: &mut Screen) {
+    let strings = ctx.locale.strings();
+    let keys_width = strings
+        .shortcuts
+        .iter()
+        .map(|(keys, _)| keys.chars().count())
+        .max()
+        .unwrap_or_default();
+    let rows = strings
+        .shortcuts
+        .iter()
+        .map(|(keys, description)| format!("{keys:<keys_width$}  {description}"))
+        .collect::<Vec<_>>();
+    let inner_width = rows
+        .iter()
+        .map(|row| row.chars().count())
+        .chain([strings.close_hint.chars().count()])
+        .max()
+        .unwrap_or_default();
+
+    // a border and a space of padding on each side, and a blank line after
+    // the title and before the hint
+    let width = usize::min(inner_width + 4, ctx.width);
+    let height = usize::min(rows.len() + 6, ctx.view_height());
+    let mut help = Screen::new(width, height);
+
+    if !ctx.theme.primary.is_empty() {
+        help.style.formats = vec![ctx.theme.primary.to_string()];
+    }
+    help.put_str(&Position { x: 2, y: 1 }, strings.keyboard_shortcuts);
+    help.style.formats.clear();
+    for (y, row) in rows.iter().enumerate() {
+        help.put_str(
+            &Position {
+                x: 2,
+                y: y as isize + 3,
+            },
+            row,
+        );
+    }
+    if !ctx.theme.secondary.is_empty() {
+        help.style.formats = vec![ctx.theme.secondary.to_string()];
+    }
+    help.put_str(
+        &Position {
+            x: 2,
+            y: rows.len() as isize + 4,
+        },
+        strings.close_hint,
+    );
+
+    // the border goes last, so it's drawn over text that doesn't fit
+    let (right, bottom) = (width as isize - 1, height as isize - 1);
+    for x in 1..right {
+        help.put(&Position { x, y: 0 }, '─');
+        help.put(&Position { x, y: bottom }, '─');
+    }
+    for y in 1..bottom {
+        help.put(&Position { x: 0, y }, '│');
+        help.put(&Position { x: right, y }, '│');
+    }
+    help.put(&Position { x: 0, y: 0 }, '┌');
+    help.put(&Position { x: right, y: 0 }, '┐');
+    help.put(&Position { x: 0, y: bottom }, '└');
+    help.put(
+        &Position {
+            x: right,
+            y: bottom,
+        },
+        '┘',
+    );
+
+    screen.paste(
+        &help,
+        &Position {
+            x: ((ctx.width - width) / 2) as isize,
+            y: ((ctx.view_height() - height) / 2) as isize,
+        },
+    );
+}
+
+/// The link back and the page's title. Compact pages have them on one line
+/// without the blank line above.
+fn header(ctx: &Context, back: &str, location: Location, title: &str) -> Element {
+    let back = link(gray(text(&format!("← {back}"))), location);
+    let title = bold(white(text(title)));
+    if ctx.is_compact() {
+        container(vec![back, text("  "), title])
+    } else {
+        container(vec![text("\n"), back, text("\n\n"), title])
+    }
+}
+
+fn index_page(ctx: &Context) -> Layout {
+    let strings = ctx.locale.strings();
+    let compact = ctx.is_compact();
+
+    // compact pages have the socials on one line without the handles
+    let socials = if compact {
+        vec![horizontally_centered(gray(container(vec![
+            external_link(text("GitHub"), "https://github.com/mat-1"),
+            text(" · "),
+            external_link(text("Matrix"), "https://matrix.to/#/@mat:matdoes.dev"),
+            text(" · "),
+            external_link(text("Ko-fi"), "https://ko-fi.com/matdoesdev"),
+        ])))]
+    } else {
         vec![
-            vertically_centered(container(vec![
-                // title
-                text("\n"),
-                bold(horizontally_centered(white(text("matdoesdev")))),
-                text("\n\n"),
-
-                // socials
-                horizontally_centered(gray(container(vec![
-                    text("GitHub: "),
-                    external_link(text("mat-1"), "https://github.com/mat-1"),
-                ]))),
-                text("\n"),
-                horizontally_centered(gray(container(vec![
-                    text("Matrix: "),
-                    external_link(text("@mat:matdoes.dev"), "https://matrix.to/#/@mat:matdoes.dev"),
-                ]))),
-                text("\n"),
-                horizontally_centered(gray(container(vec![
-                    text("Ko-fi (donate): "),
-                    external_link(text("matdoesdev"), "https://ko-fi.com/matdoesdev"),
-                ]))),
+            horizontally_centered(gray(container(vec![
+                text("GitHub: "),
+                external_link(text("mat-1"), "https://github.com/mat-1"),
+            ]))),
+            text("\n"),
+            horizontally_centered(gray(container(vec![
+                text("Matrix: "),
+                external_link(
+                    text("@mat:matdoes.dev"),
+                    "https://matrix.to/#/@mat:matdoes.dev",
+                ),
+            ]))),
+            text("\n"),
+            horizontally_centered(gray(container(vec![
+                text("Ko-fi (donate): "),
+                external_link(text("matdoesdev"), "https://ko-fi.com/matdoesdev"),
+            ]))),
+        ]
+    };
+
+    // the links lose their brackets too, so they fit on one row
+    let mut navigation = Vec::new();
+    for (name, location) in [
+        (strings.blog, Location::Blog),
+        (strings.projects, Location::Projects { language: None }),
+        (strings.stats, Location::Stats),
+        (strings.server, Location::ServerInfo),
+    ] {
+        if !navigation.is_empty() {
+            navigation.push(text(" "));
+        }
+        let name = if compact {
+            name.to_owned()
+        } else {
+            format!("[{name}]")
+        };
+        navigation.push(link(text(&name), location));
+    }
 
-                text("\n\n"),
+    let mut elements = vec![
+        vertically_centered(container(vec![
+            // title
+            text(if compact { "" } else { "\n" }),
+            bold(horizontally_centered(white(text("matdoesdev")))),
+            text("\n\n"),
 
-                // description
-                text("I'm mat, I do full-stack software development.\n"),
-                text("This portfolio contains my blog posts and links to some of the projects I've made.\n"),
-                text("\n"),
+            // socials
+            container(socials),
 
-                // links
-                horizontally_centered(container(vec![
-                    link(text("[Blog]"), Location::Blog),
-                    text(" "),
-                    link(text("[Projects]"), Location::Projects),
-                ])),
-                text("\n"),
-            ])),
-            text("\n\n\n\n"),
-            italic(gray(horizontally_centered(text("(use tab to navigate links, enter to select)")))),
-        ],
-    )
+            text("\n\n"),
+
+            // description
+            text("I'm mat, I do full-stack software development.\n"),
+            text("This portfolio contains my blog posts and links to some of the projects I've made.\n"),
+            text("\n"),
+
+            // links
+            horizontally_centered(container(navigation)),
+            text("\n"),
+        ])),
+        text(if compact { "\n\n" } else { "\n\n\n\n" }),
+        italic(gray(horizontally_centered(text(strings.navigation_hint)))),
+    ];
+    if ctx.site_data.stale {
+        elements.push(text("\n\n"));
+        elements.push(italic(white(horizontally_centered(text(STALE_NOTICE)))));
+    }
+    if !ctx.motd.is_empty() {
+        elements.push(text("\n\n"));
+        for line in ctx.motd.lines() {
+            elements.push(gray(horizontally_centered(text(line))));
+            elements.push(text("\n"));
+        }
+    }
+    Layout::new(ctx, 50, elements)
 }
 
-fn blog_page(ctx: &mut Context) -> Page {
+fn blog_page(ctx: &Context) -> Layout {
+    let strings = ctx.locale.strings();
     let mut elements = vec![
-        text("\n"),
-        link(gray(text("← Home")), Location::Index),
+        header(ctx, strings.home, Location::Index, strings.blog),
+        text("\n\n"),
+        link(gray(text(&format!("[{}]", strings.tags))), Location::Tags),
+        text(" "),
+        link(
+            gray(text(&format!("[{}]", strings.search))),
+            Location::Search {
+                query: String::new(),
+            },
+        ),
         text("\n\n"),
-        bold(white(text("Blog"))),
+    ];
+
+    // the sort orders aren't links so the posts stay the first links after
+    // the navigation, the active one is bold
+    let mut sorts = vec![gray(text(&format!("{} (o): ", strings.sort)))];
+    for (sort, name) in [
+        (PostSort::Date, strings.newest),
+        (PostSort::Length, strings.longest),
+    ] {
+        let name = text(&format!("[{name}]"));
+        sorts.push(if sort == ctx.blog_sort {
+            bold(name)
+        } else {
+            gray(name)
+        });
+        sorts.push(text(" "));
+    }
+    elements.push(container(sorts));
+    elements.push(text("\n\n\n"));
+
+    for blog_post in ctx.blog_sort.sort(drafts::published(&ctx.site_data.blog)) {
+        let unread = ctx
+            .read_posts
+            .as_ref()
+            .is_some_and(|read_posts| !read_posts.contains(&blog_post.slug));
+        let mut title = if unread {
+            format!("• {}", blog_post.title)
+        } else {
+            blog_post.title.clone()
+        };
+        if visitors::is_new(blog_post.published, ctx.previous_visit) {
+            title.push_str(&format!(" {}", visitors::NEW_MARKER));
+        }
+        elements.push(colorless_link(
+            container(vec![
+                text(&title),
+                text("\n"),
+                gray(text(&format!(
+                    "{} · {} {}",
+                    blog_post.published.format("%m/%d/%Y"),
+                    blog_post.reading_minutes(),
+                    strings.min_read
+                ))),
+            ]),
+            Location::BlogPost {
+                slug: blog_post.slug.clone(),
+            },
+        ));
+        elements.push(text("\n\n"));
+    }
+
+    Layout::new(ctx, 80, elements)
+}
+
+fn tags_page(ctx: &Context) -> Layout {
+    let strings = ctx.locale.strings();
+    let mut elements = vec![
+        header(ctx, strings.back, Location::Blog, strings.tags),
         text("\n\n\n"),
     ];
-    for blog_post in &ctx.site_data.blog {
+    for (tag, tagged_posts) in ctx.site_data.tags() {
+        elements.push(link(
+            text(tag),
+            Location::Tag {
+                name: tag.to_owned(),
+            },
+        ));
+        elements.push(gray(text(&format!(" ({})", tagged_posts.len()))));
+        elements.push(text("\n"));
+    }
+
+    Layout::new(ctx, 80, elements)
+}
+
+fn tag_page(ctx: &Context, name: &str) -> Layout {
+    let strings = ctx.locale.strings();
+    let mut elements = vec![
+        header(
+            ctx,
+            strings.tags,
+            Location::Tags,
+            &format!("{} {name}", strings.tagged),
+        ),
+        text("\n\n\n"),
+    ];
+    let tags = ctx.site_data.tags();
+    for blog_post in tags.get(name).into_iter().flatten() {
         elements.push(colorless_link(
             container(vec![
                 text(&blog_post.title),
@@ -351,96 +1559,271 @@ fn blog_page(ctx: &mut Context) -> Page {
         elements.push(text("\n\n"));
     }
 
-    Page::new(ctx, 80, elements)
+    Layout::new(ctx, 80, elements)
 }
 
-fn blog_post_page(ctx: &mut Context, slug: &str) -> Page {
-    let Some(blog_post) = ctx.site_data.blog.iter().find(|p| p.slug == slug) else {
-        // uhhhh idk go to index page ig
-        return index_page(ctx);
+fn search_page(ctx: &Context, query: &str) -> Layout {
+    let (cursor, hint) = if ctx.typing {
+        ("▏", "(enter to finish typing)")
+    } else {
+        ("", "(press / to search again)")
     };
-
+    let strings = ctx.locale.strings();
     let mut elements = vec![
-        text("\n"),
-        link(gray(text("← Back")), Location::Blog),
+        header(ctx, strings.back, Location::Blog, strings.search),
         text("\n\n"),
-        bold(white(text(&blog_post.title))),
+        text("> "),
+        // with echo off, what's being typed is hidden like at a password
+        // prompt
+        bold(text(&if ctx.typing && !ctx.modes.echo {
+            cursor.to_owned()
+        } else {
+            format!("{query}{cursor}")
+        })),
         text("\n"),
-        gray(text(&blog_post.published.format("%m/%d/%Y").to_string())),
-        text("\n\n"),
+        italic(gray(text(hint))),
+        text("\n\n\n"),
     ];
+    if !query.trim().is_empty() {
+        let results = search::search(query);
+        if results.is_empty() {
+            elements.push(gray(text(strings.no_posts_found)));
+        }
+        for result in results {
+            elements.push(link(
+                text(&result.title),
+                Location::BlogPost { slug: result.slug },
+            ));
+            elements.push(text("\n"));
+            elements.push(gray(text(&result.snippet)));
+            elements.push(text("\n\n"));
+        }
+    }
 
-    let mut last_tag_was_line_break = false;
-    for part in &blog_post.content {
-        match part {
-            PostPart::Text(t) => {
-                elements.push(text(t));
-            }
-            PostPart::InlineCode(t) => {
-                elements.push(italic(text(&format!("`{t}`"))));
-            }
-            PostPart::CodeBlock(t) => {
-                elements.push(italic(text(&format!("```\n{t}\n```\n"))));
-            }
-            PostPart::Italic(t) => {
-                elements.push(italic(text(t)));
-            }
-            PostPart::Bold(content) => {
-                elements.push(bold(text(content)));
-            }
-            PostPart::Image { src, alt } => {
-                let mut image_desc = String::new();
-                image_desc.push_str("Image: ");
-                if let Some(alt) = alt {
-                    image_desc.push_str(alt);
-                    image_desc.push_str(" (");
-                }
-                match src {
-                    ImageSource::Local(path) => {
-                        image_desc.push_str(&path.to_string_lossy());
-                    }
-                    ImageSource::Remote(path) => {
-                        image_desc.push_str(path);
-                    }
-                }
-                if alt.is_some() {
-                    image_desc.push(')');
-                }
-                elements.push(italic(gray(text(&format!("\n{image_desc}\n")))));
-            }
-            PostPart::Link { text: t, href } => {
-                elements.push(external_link(text(t), href));
-            }
-            PostPart::LineBreak => {
-                elements.push(text("\n\n"));
-                last_tag_was_line_break = true;
-                continue;
+    Layout::new(ctx, 80, elements)
+}
+
+/// Adds a post's parts to a page, with the links where they are.
+struct TerminalPost<'a>(&'a mut Vec<Element>);
+
+impl PostVisitor for TerminalPost<'_> {
+    fn text(&mut self, t: &str) {
+        self.0.push(text(t));
+    }
+
+    fn inline_code(&mut self, code: &str) {
+        self.0.push(italic(text(&format!("`{code}`"))));
+    }
+
+    fn code_block(&mut self, code: &str) {
+        self.0.push(italic(text(&format!("```\n{code}\n```\n"))));
+    }
+
+    fn italic(&mut self, t: &str) {
+        self.0.push(italic(text(t)));
+    }
+
+    fn bold(&mut self, t: &str) {
+        self.0.push(bold(text(t)));
+    }
+
+    fn image(&mut self, src: &ImageSource, alt: Option<&str>) {
+        let mut image_desc = String::new();
+        image_desc.push_str("Image: ");
+        if let Some(alt) = alt {
+            image_desc.push_str(alt);
+            image_desc.push_str(" (");
+        }
+        match src {
+            ImageSource::Local(path) => {
+                image_desc.push_str(&path.to_string_lossy());
             }
-            PostPart::Heading { level: _, text: t } => {
-                if !last_tag_was_line_break {
-                    elements.push(text("\n"));
-                }
-                elements.push(bold(white(text(&format!("{t}\n")))));
+            ImageSource::Remote(path) => {
+                image_desc.push_str(path);
             }
-            PostPart::Quote(t) => {
-                elements.push(italic(text(&format!("> {t}\n"))));
+        }
+        if alt.is_some() {
+            image_desc.push(')');
+        }
+        self.0
+            .push(italic(gray(text(&format!("\n{image_desc}\n")))));
+    }
+
+    fn link(&mut self, t: &str, href: &str) {
+        self.0.push(external_link(text(t), href));
+    }
+
+    fn line_break(&mut self, _repeated: bool, _links: &[Link]) {
+        self.0.push(text("\n\n"));
+    }
+
+    fn heading(&mut self, _level: usize, t: &str, after_line_break: bool) {
+        if !after_line_break {
+            self.0.push(text("\n"));
+        }
+        self.0.push(bold(white(text(&format!("{t}\n")))));
+    }
+
+    fn quote(&mut self, t: &str) {
+        self.0.push(italic(text(&format!("> {t}\n"))));
+    }
+
+    fn footnote_reference(&mut self, label: &str) {
+        self.0.push(anchor(&format!("reference-{label}")));
+        self.0.push(anchor_link(
+            text(&format!("[{label}]")),
+            &format!("footnote-{label}"),
+        ));
+    }
+
+    /// Each footnote links back up to where it was referenced.
+    fn footnotes(&mut self, footnotes: &[(&str, &str)]) {
+        self.0.push(text("\n"));
+        self.0.push(bold(white(text("Notes\n"))));
+        for (id, t) in footnotes {
+            self.0.push(text("\n"));
+            self.0.push(anchor(&format!("footnote-{id}")));
+            self.0.push(anchor_link(
+                text(&format!("[{id}]")),
+                &format!("reference-{id}"),
+            ));
+            self.0.push(text(&format!(" {t}\n")));
+        }
+    }
+
+    fn horizontal_rule(&mut self) {
+        self.0
+            .push(gray(text(&format!("\n{}\n\n", "─".repeat(40)))));
+    }
+
+    fn definition_list(&mut self, definitions: &[(String, String)]) {
+        for (term, definition) in definitions {
+            self.0.push(bold(text(&format!("{term}\n"))));
+            for line in definition.lines() {
+                self.0.push(text(&format!("    {line}\n")));
             }
         }
-        last_tag_was_line_break = false;
+        self.0.push(text("\n"));
+    }
+
+    fn caption(&mut self, t: &str) {
+        self.0.push(italic(gray(text(&format!("{t}\n")))));
     }
 
-    Page::new(ctx, 80, elements)
+    fn table(&mut self, rows: &[Vec<String>]) {
+        self.0.push(table(rows.to_vec()));
+    }
+
+    fn list(&mut self, ordered: bool, items: &[ListItem], after_line_break: bool) {
+        if !after_line_break {
+            self.0.push(text("\n"));
+        }
+        for line in list_lines(ordered, items) {
+            let marker = match line.number {
+                Some(number) => format!("{number}."),
+                None => "•".to_owned(),
+            };
+            self.0.push(text(&"  ".repeat(line.depth + 1)));
+            self.0.push(gray(text(&format!("{marker} "))));
+            self.0.push(text(&format!("{}\n", line.text)));
+        }
+        self.0.push(text("\n"));
+    }
 }
 
-fn projects_page(ctx: &mut Context) -> Page {
+fn blog_post_page(ctx: &Context, slug: &str) -> Layout {
+    let Some(blog_post) = ctx.site_data.blog.iter().find(|p| p.slug == slug) else {
+        // uhhhh idk go to index page ig
+        return index_page(ctx);
+    };
+    let strings = ctx.locale.strings();
+
     let mut elements = vec![
+        header(ctx, strings.back, Location::Blog, &blog_post.title),
+        text("\n"),
+        gray(text(&blog_post.published.format("%m/%d/%Y").to_string())),
         text("\n"),
-        link(gray(text("← Home")), Location::Index),
+        gray(text(&strings.post_length(
+            blog_post.reading_minutes(),
+            blog_post.word_count,
+        ))),
         text("\n\n"),
-        bold(white(text("Projects"))),
+    ];
+
+    render::visit_post(&blog_post.content, &mut TerminalPost(&mut elements));
+
+    if let Some(related) = ctx.related.get(slug).filter(|r| !r.is_empty()) {
+        elements.push(text("\n\n"));
+        elements.push(bold(white(text(strings.related_posts))));
+        elements.push(text("\n\n"));
+        for related_post in related {
+            elements.push(link(
+                text(&related_post.title),
+                Location::BlogPost {
+                    slug: related_post.slug.clone(),
+                },
+            ));
+            elements.push(text("\n"));
+        }
+    }
+
+    elements.push(text("\n\n"));
+    elements.push(bold(white(text(strings.comments))));
+    elements.push(text("\n\n"));
+    for comment in comments::approved(slug) {
+        elements.push(bold(text(&comment.author)));
+        elements.push(gray(text(&format!(
+            " {}\n",
+            comment.timestamp.format("%m/%d/%Y")
+        ))));
+        elements.push(text(&format!("{}\n", comment.body)));
+        if let Some(url) = &comment.url {
+            elements.push(gray(text(&format!("{url}\n"))));
+        }
+        elements.push(text("\n"));
+    }
+    elements.push(gray(text(&format!(
+        "{} gemini://{HOSTNAME}/{slug}/comment\n",
+        strings.leave_a_comment
+    ))));
+    elements.push(gray(text(&format!("{}\n", strings.print_hint))));
+
+    Layout::new(ctx, 80, elements)
+}
+
+fn projects_page(ctx: &Context, language: Option<LanguageName>) -> Layout {
+    let strings = ctx.locale.strings();
+    let mut elements = vec![
+        header(ctx, strings.home, Location::Index, strings.projects),
         text("\n\n"),
     ];
-    for project in &ctx.site_data.projects {
+
+    // the filters, the active one is bold
+    let mut filters = vec![gray(text(&format!("{} (f): ", strings.filter)))];
+    for filter in [None].into_iter().chain(LanguageName::ALL.map(Some)) {
+        let name = match filter {
+            Some(filter) => filter.to_string(),
+            None => strings.all.to_owned(),
+        };
+        let mut filter_link = link(
+            text(&format!("[{name}]")),
+            Location::Projects { language: filter },
+        );
+        if filter == language {
+            filter_link = bold(filter_link);
+        }
+        filters.push(filter_link);
+        filters.push(text(" "));
+    }
+    elements.push(container(filters));
+    elements.push(text("\n\n"));
+
+    let projects = ctx
+        .site_data
+        .projects
+        .iter()
+        .filter(|project| language.is_none_or(|l| project.languages.contains(&l)));
+    for project in projects {
         let mut project_name = bold(text(&project.name));
         if let Some(href) = &project.href {
             project_name = external_link(project_name, href);
@@ -468,5 +1851,79 @@ fn projects_page(ctx: &mut Context) -> Page {
         elements.push(text("\n\n"));
     }
 
-    Page::new(ctx, 80, elements)
+    Layout::new(ctx, 80, elements)
+}
+
+fn stats_page(ctx: &Context) -> Layout {
+    let stats = analytics::stats();
+
+    let strings = ctx.locale.strings();
+    let mut elements = vec![
+        header(ctx, strings.home, Location::Index, strings.stats),
+        text("\n\n"),
+        gray(text(&format!(
+            "{} {}",
+            stats.total, strings.requests_in_total
+        ))),
+        text("\n\n\n"),
+        bold(text(strings.protocols)),
+        text("\n\n"),
+    ];
+    for (protocol, count) in Stats::top(&stats.protocols, usize::MAX) {
+        elements.push(text(&format!("{protocol}: {count}\n")));
+    }
+
+    elements.push(text("\n\n"));
+    elements.push(bold(text(strings.most_read_posts)));
+    elements.push(text("\n\n"));
+    for (slug, count) in Stats::top(&stats.posts, 10) {
+        let title = ctx
+            .site_data
+            .blog
+            .iter()
+            .find(|p| p.slug == slug)
+            .map(|p| p.title.clone())
+            .unwrap_or_else(|| slug.to_owned());
+        elements.push(link(
+            text(&title),
+            Location::BlogPost {
+                slug: slug.to_owned(),
+            },
+        ));
+        elements.push(gray(text(&format!(" ({count})"))));
+        elements.push(text("\n"));
+    }
+
+    Layout::new(ctx, 80, elements)
+}
+
+fn server_info_page(ctx: &Context) -> Layout {
+    let info = server_info::get();
+
+    let strings = ctx.locale.strings();
+    let mut elements = vec![
+        header(ctx, strings.home, Location::Index, strings.server),
+        text("\n\n"),
+        gray(text(&format!(
+            "Up for {}\nVersion {}",
+            server_info::human_duration(info.uptime),
+            info.version_string()
+        ))),
+        text("\n\n\n"),
+        bold(text(strings.protocols)),
+        text("\n\n"),
+    ];
+    for (protocol, ports) in &info.listeners {
+        elements.push(text(&format!("{protocol}: ")));
+        elements.push(gray(text(&format!("{}\n", ports.join(", ")))));
+    }
+
+    elements.push(text("\n\n"));
+    elements.push(bold(text("Requests")));
+    elements.push(text("\n\n"));
+    for (protocol, count) in &info.requests {
+        elements.push(text(&format!("{protocol}: {count}\n")));
+    }
+
+    Layout::new(ctx, 80, elements)
 }
@@ -0,0 +1,119 @@
+//! The tty settings that the client asked for, like which key interrupts and
+//! whether typed characters are echoed. SSH sends these with the pty request,
+//! encoded as described in RFC 4254 section 8.
+
+/// What a control character is set to when it's turned off.
+const DISABLED: u32 = 255;
+
+/// The opcodes that we care about. The rest are skipped.
+const TTY_OP_END: u8 = 0;
+const VINTR: u8 = 1;
+const VERASE: u8 = 3;
+const VEOF: u8 = 5;
+const ECHO: u8 = 53;
+/// Opcodes from here on aren't defined, and we have to stop parsing since we
+/// don't know how long their arguments are.
+const FIRST_UNDEFINED: u8 = 160;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TerminalModes {
+    /// The key that interrupts, which closes the session. `None` if the
+    /// client turned it off.
+    pub intr: Option<u8>,
+    /// The end of file key. `None` if the client turned it off.
+    pub eof: Option<u8>,
+    /// The key that deletes the last character, besides backspace and delete.
+    pub erase: Option<u8>,
+    /// Whether the characters that are typed should be shown.
+    pub echo: bool,
+}
+
+impl Default for TerminalModes {
+    /// What terminals usually have: `^C`, `^D`, and `DEL`, with echo on.
+    fn default() -> Self {
+        Self {
+            intr: Some(3),
+            eof: Some(4),
+            erase: Some(127),
+            echo: true,
+        }
+    }
+}
+
+impl TerminalModes {
+    /// Parse the `encoded terminal modes` from an SSH pty request. Modes that
+    /// aren't in it keep their defaults, and anything after a malformed mode
+    /// is ignored.
+    pub fn from_ssh(encoded: &[u8]) -> Self {
+        let mut modes = TerminalModes::default();
+        let control_character = |value: u32| match value {
+            DISABLED => None,
+            value => u8::try_from(value).ok(),
+        };
+
+        let mut rest = encoded;
+        while let Some((&opcode, after)) = rest.split_first() {
+            if opcode == TTY_OP_END || opcode >= FIRST_UNDEFINED {
+                break;
+            }
+            let Some((argument, after)) = after.split_first_chunk::<4>() else {
+                break;
+            };
+            let value = u32::from_be_bytes(*argument);
+            match opcode {
+                VINTR => modes.intr = control_character(value),
+                VERASE => modes.erase = control_character(value),
+                VEOF => modes.eof = control_character(value),
+                ECHO => modes.echo = value != 0,
+                _ => {}
+            }
+            rest = after;
+        }
+        modes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(modes: &[(u8, u32)]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for &(opcode, value) in modes {
+            encoded.push(opcode);
+            encoded.extend(value.to_be_bytes());
+        }
+        encoded.push(TTY_OP_END);
+        encoded
+    }
+
+    #[test]
+    fn parses_ssh_modes() {
+        assert_eq!(TerminalModes::from_ssh(&[]), TerminalModes::default());
+
+        let modes =
+            TerminalModes::from_ssh(&encode(&[(VINTR, 7), (VEOF, DISABLED), (ECHO, 0), (42, 1)]));
+        assert_eq!(
+            modes,
+            TerminalModes {
+                intr: Some(7),
+                eof: None,
+                echo: false,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn stops_at_unknown_opcodes() {
+        let mut encoded = vec![FIRST_UNDEFINED, 1, 2];
+        encoded.extend(encode(&[(ECHO, 0)]));
+        assert!(TerminalModes::from_ssh(&encoded).echo);
+
+        // cut off in the middle of the argument
+        assert_eq!(
+            TerminalModes::from_ssh(&[VINTR, 0, 0]),
+            TerminalModes::default()
+        );
+    }
+}
@@ -0,0 +1,88 @@
+//! What's remembered about someone who connected with a public key, so the next
+//! session can start where the last one left off.
+
+use std::{collections::HashSet, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::Location;
+
+pub const SESSIONS_PATH: &str = "data/sessions";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    /// The name of the theme, see [`super::elements::Theme::from_name`].
+    pub theme: String,
+    /// The page they were on when they disconnected.
+    pub location: Location,
+    /// The slugs of the posts they've opened.
+    #[serde(default)]
+    pub read_posts: HashSet<String>,
+}
+
+impl Profile {
+    /// The profile for the fingerprint of a public key, or an empty one if
+    /// they haven't connected before.
+    pub fn load(fingerprint: &str) -> Self {
+        fs::read_to_string(Path::new(SESSIONS_PATH).join(format!("{fingerprint}.json")))
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, fingerprint: &str) {
+        if cfg!(test) {
+            return;
+        }
+
+        let write = || -> anyhow::Result<()> {
+            fs::create_dir_all(SESSIONS_PATH)?;
+            fs::write(
+                Path::new(SESSIONS_PATH).join(format!("{fingerprint}.json")),
+                serde_json::to_string_pretty(self)?,
+            )?;
+            Ok(())
+        };
+        if let Err(err) = write() {
+            eprintln!("failed to save session for {fingerprint}: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::testing::{site_data, Replay};
+
+    #[test]
+    fn marks_unread_posts() {
+        let mut replay = Replay::new(site_data(), 80, 24);
+        replay.session.restore(Profile {
+            theme: "light".to_owned(),
+            location: Location::Blog,
+            read_posts: HashSet::from(["wrapping".to_owned()]),
+        });
+        replay.draw();
+        assert!(replay.screen.text().contains("• A long post"));
+        assert!(!replay.screen.text().contains("• Wrapping"));
+
+        replay.session.restore(Profile {
+            location: Location::BlogPost {
+                slug: "long-post".to_owned(),
+            },
+            ..replay.session.profile().unwrap()
+        });
+        replay.draw();
+        let profile = replay.session.profile().unwrap();
+        assert_eq!(profile.theme, "light");
+        assert!(profile.read_posts.contains("long-post"));
+    }
+
+    #[test]
+    fn anonymous_sessions_have_no_profile() {
+        let mut replay = Replay::new(site_data(), 80, 24);
+        replay.keys(b"b");
+        assert!(!replay.screen.text().contains('•'));
+        assert_eq!(replay.session.profile(), None);
+    }
+}
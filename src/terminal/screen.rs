@@ -0,0 +1,341 @@
+//! A grid of cells that the elements are rendered into. Keeping the previous
+//! screen around lets us only send the parts that changed, instead of clearing
+//! and redrawing everything on every keystroke.
+
+use super::elements::{move_cursor, Position, RESET};
+
+/// What kind of formatting the client's terminal supports.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSupport {
+    #[default]
+    TrueColor,
+    Indexed256,
+    /// The basic 16 colors.
+    Basic,
+    /// No formatting at all.
+    Dumb,
+}
+
+impl ColorSupport {
+    /// Guess the color support from the terminal type, like `xterm-256color`.
+    pub fn from_terminal_type(terminal_type: &str) -> Self {
+        let terminal_type = terminal_type.to_lowercase();
+        if terminal_type == "dumb" {
+            ColorSupport::Dumb
+        } else if terminal_type.contains("truecolor")
+            || terminal_type.contains("24bit")
+            || terminal_type.contains("direct")
+        {
+            ColorSupport::TrueColor
+        } else if terminal_type.contains("256color") {
+            ColorSupport::Indexed256
+        } else if matches!(
+            terminal_type.as_str(),
+            "ansi" | "linux" | "xterm" | "screen" | "cygwin" | "vt100" | "vt102" | "vt220"
+        ) {
+            ColorSupport::Basic
+        } else {
+            // probably a modern terminal
+            ColorSupport::TrueColor
+        }
+    }
+
+    /// Convert the SGR parameters to ones the terminal supports. Returns None if
+    /// nothing is left.
+    fn degrade(self, format: &str) -> Option<String> {
+        match self {
+            ColorSupport::TrueColor => return Some(format.to_owned()),
+            ColorSupport::Dumb => return None,
+            ColorSupport::Indexed256 | ColorSupport::Basic => {}
+        }
+
+        let params = format.split(';').collect::<Vec<_>>();
+        let mut degraded = Vec::new();
+        let mut i = 0;
+        while i < params.len() {
+            // 38;2;r;g;b and 48;2;r;g;b are truecolor
+            if matches!(params[i], "38" | "48") && params.get(i + 1) == Some(&"2") {
+                let rgb = params
+                    .get(i + 2..i + 5)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|c| c.parse::<u8>().ok())
+                    .collect::<Vec<_>>();
+                if let [r, g, b] = rgb[..] {
+                    if self == ColorSupport::Indexed256 {
+                        degraded.push(format!("{};5;{}", params[i], rgb_to_indexed(r, g, b)));
+                    } else if params[i] == "38" {
+                        // an underline is the closest thing we have to a custom color
+                        degraded.push("4".to_owned());
+                    }
+                }
+                i += 5;
+                continue;
+            }
+            degraded.push(params[i].to_owned());
+            i += 1;
+        }
+
+        if degraded.is_empty() {
+            None
+        } else {
+            Some(degraded.join(";"))
+        }
+    }
+}
+
+/// What the client's terminal can display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub colors: ColorSupport,
+    /// If this is false, characters that aren't ASCII are replaced with ones
+    /// that look kind of similar.
+    pub utf8: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            colors: ColorSupport::default(),
+            utf8: true,
+        }
+    }
+}
+
+/// The closest ASCII character, for terminals that can't display UTF-8.
+fn to_ascii(c: char) -> char {
+    match c {
+        c if c.is_ascii() => c,
+        '│' | '▏' | '┃' => '|',
+        '─' | '━' | '–' | '—' | '‐' => '-',
+        '┌' | '┐' | '└' | '┘' | '├' | '┤' | '┬' | '┴' | '┼' => '+',
+        '█' | '▓' | '▒' => '#',
+        '←' | '‹' | '«' => '<',
+        '→' | '›' | '»' => '>',
+        '•' | '·' | '∙' => '*',
+        '‘' | '’' | '′' => '\'',
+        '“' | '”' | '″' => '"',
+        '…' => '.',
+        '\u{a0}' => ' ',
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' => 'a',
+        'è' | 'é' | 'ê' | 'ë' => 'e',
+        'ì' | 'í' | 'î' | 'ï' => 'i',
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' => 'o',
+        'ù' | 'ú' | 'û' | 'ü' => 'u',
+        'ç' => 'c',
+        'ñ' => 'n',
+        _ => '?',
+    }
+}
+
+/// Get the closest color in the 6x6x6 cube of the 256 color palette.
+fn rgb_to_indexed(r: u8, g: u8, b: u8) -> u8 {
+    let to_cube = |c: u8| ((c as u16 * 5 + 127) / 255) as u8;
+    16 + 36 * to_cube(r) + 6 * to_cube(g) + to_cube(b)
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Style {
+    /// SGR parameters like `1` for bold, applied in order.
+    pub formats: Vec<String>,
+    /// The URL for an OSC 8 hyperlink.
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cell {
+    pub c: char,
+    pub style: Style,
+    /// The index of the link that the cell is part of.
+    pub link: Option<usize>,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            c: ' ',
+            style: Style::default(),
+            link: None,
+        }
+    }
+}
+
+impl Cell {
+    /// Whether the cell is drawn the same way as the other one, even if it's
+    /// part of a different link.
+    fn looks_like(&self, other: &Cell) -> bool {
+        self.c == other.c && self.style == other.style
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Screen {
+    pub width: usize,
+    pub height: usize,
+    cells: Vec<Cell>,
+
+    /// The style that's used for cells as they're written.
+    pub style: Style,
+    /// The link that cells are part of as they're written.
+    pub link: Option<usize>,
+}
+
+impl Screen {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![Cell::default(); width * height],
+            style: Style::default(),
+            link: None,
+        }
+    }
+
+    fn index(&self, pos: &Position) -> Option<usize> {
+        if pos.x < 0 || pos.y < 0 || pos.x as usize >= self.width || pos.y as usize >= self.height {
+            return None;
+        }
+        Some(pos.y as usize * self.width + pos.x as usize)
+    }
+
+    /// Write a character with the current style. Characters outside of the
+    /// screen are ignored.
+    pub fn put(&mut self, pos: &Position, c: char) {
+        if let Some(index) = self.index(pos) {
+            self.cells[index] = Cell {
+                c,
+                style: self.style.clone(),
+                link: self.link,
+            };
+        }
+    }
+
+    pub fn put_str(&mut self, pos: &Position, s: &str) {
+        for (i, c) in s.chars().enumerate() {
+            self.put(
+                &Position {
+                    x: pos.x + i as isize,
+                    y: pos.y,
+                },
+                c,
+            );
+        }
+    }
+
+    /// Copy `count` rows from `source`, starting at its row `top`, to the top
+    /// of this screen. Rows that either screen doesn't have are skipped.
+    pub fn copy_rows(&mut self, source: &Screen, top: usize, count: usize) {
+        let width = self.width.min(source.width);
+        let count = count
+            .min(self.height)
+            .min(source.height.saturating_sub(top));
+        for y in 0..count {
+            let from = (top + y) * source.width;
+            let to = y * self.width;
+            self.cells[to..to + width].clone_from_slice(&source.cells[from..from + width]);
+        }
+    }
+
+    /// Add an SGR parameter to every cell that's part of the link, like to show
+    /// that it's selected.
+    pub fn format_link(&mut self, link: usize, format: &str) {
+        for cell in self.cells.iter_mut().filter(|cell| cell.link == Some(link)) {
+            cell.style.formats.push(format.to_owned());
+        }
+    }
+
+    /// Draw `source` over this screen with its top left corner at `at`, like
+    /// for a box that's shown on top of the page.
+    pub fn paste(&mut self, source: &Screen, at: &Position) {
+        for (i, cell) in source.cells.iter().enumerate() {
+            let pos = Position {
+                x: at.x + (i % source.width) as isize,
+                y: at.y + (i / source.width) as isize,
+            };
+            if let Some(index) = self.index(&pos) {
+                self.cells[index] = cell.clone();
+            }
+        }
+    }
+
+    /// The characters on each row without their styles, with the spaces at the
+    /// end of the rows taken off.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for row in self.cells.chunks(self.width.max(1)) {
+            let line = row.iter().map(|cell| cell.c).collect::<String>();
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Get the escape sequences to turn the `previous` screen into this one. If
+    /// there's no previous screen or it's a different size, the whole screen
+    /// is redrawn.
+    pub fn diff(&self, previous: Option<&Screen>, capabilities: Capabilities) -> String {
+        let mut out = String::new();
+
+        let blank;
+        let previous = match previous {
+            Some(previous) if previous.width == self.width && previous.height == self.height => {
+                previous
+            }
+            _ => {
+                out.push_str("\x1b[2J"); // Clear screen
+                blank = Screen::new(self.width, self.height);
+                &blank
+            }
+        };
+
+        for y in 0..self.height {
+            let row = y * self.width;
+            let mut x = 0;
+            while x < self.width {
+                if self.cells[row + x].looks_like(&previous.cells[row + x]) {
+                    x += 1;
+                    continue;
+                }
+
+                // write every changed cell in this run
+                out.push_str(&move_cursor(&Position {
+                    x: x as isize,
+                    y: y as isize,
+                }));
+                let mut style = Style::default();
+                while x < self.width && !self.cells[row + x].looks_like(&previous.cells[row + x]) {
+                    let cell = &self.cells[row + x];
+                    write_style_change(&mut out, &style, &cell.style, capabilities.colors);
+                    style = cell.style.clone();
+                    out.push(if capabilities.utf8 {
+                        cell.c
+                    } else {
+                        to_ascii(cell.c)
+                    });
+                    x += 1;
+                }
+                write_style_change(&mut out, &style, &Style::default(), capabilities.colors);
+            }
+        }
+
+        out
+    }
+}
+
+fn write_style_change(out: &mut String, from: &Style, to: &Style, colors: ColorSupport) {
+    if colors == ColorSupport::Dumb {
+        return;
+    }
+    if from.url != to.url {
+        match &to.url {
+            Some(url) => out.push_str(&format!("\x1b]8;;{url}\x1b\\")),
+            None => out.push_str("\x1b]8;;\x1b\\"),
+        }
+    }
+    if from.formats != to.formats {
+        out.push_str(RESET);
+        for format in to.formats.iter().filter_map(|f| colors.degrade(f)) {
+            out.push_str(&format!("\x1b[{format}m"));
+        }
+    }
+}
@@ -0,0 +1,519 @@
+//! Replaying scripted keystrokes against a [`TerminalSession`] and checking
+//! what ends up on the screen. The output is interpreted like a terminal would,
+//! so the checks are on what the user would actually see instead of on the
+//! escape sequences.
+//!
+//! The scripts are in `tests/terminal/`. Each line is one of:
+//!
+//! - `size <width> <height>`
+//! - `terminal <type>`, like `terminal dumb`
+//! - `utf8 on` or `utf8 off`
+//! - `echo on` or `echo off`, for the client's tty echo setting
+//! - `locale <code>`, like `locale de`
+//! - `keys <keys>`, sent as one read. Special keys are written like `<tab>`,
+//!   `<shift-tab>`, `<enter>`, `<up>`, `<down>`, `<pgup>`, `<pgdn>`, `<esc>`,
+//!   `<bs>`, `<c-d>`, `<c-u>`, `<c-r>`, `<paste-start>`, `<paste-end>`, and
+//!   `<focus-in>`.
+//! - `mouse click <target>` and `mouse move <target>`, where the target is
+//!   `"<text>"` for the first character of the text or `<x> <y>` (0-indexed)
+//! - `expect "<text>"` and `expect-not "<text>"`, for the whole screen
+//! - `expect-row <row> "<text>"`, where the row is 0-indexed
+//! - `expect-format "<text>" <sgr>` and `expect-no-format "<text>" <sgr>`, for
+//!   whether the first character of the text has an SGR parameter like `7`
+//!
+//! Empty lines and lines starting with `#` are ignored.
+
+use std::{
+    fs,
+    net::{IpAddr, Ipv6Addr},
+    path::Path,
+};
+
+use chrono::{TimeZone, Utc};
+
+use super::{modes::TerminalModes, TerminalSession};
+use crate::{
+    crawl::{count_words, LanguageName, Post, PostPart, Project, SiteData},
+    locale::Locale,
+};
+
+#[derive(Clone, Default)]
+struct Cell {
+    c: char,
+    /// The SGR parameters that were active when the character was written,
+    /// split on `;`.
+    formats: Vec<String>,
+}
+
+/// Just enough of a terminal to understand what [`TerminalSession`] sends.
+pub struct VirtualScreen {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,
+    cursor: (usize, usize),
+    formats: Vec<String>,
+}
+
+impl VirtualScreen {
+    pub fn new(width: usize, height: usize) -> Self {
+        VirtualScreen {
+            width,
+            height,
+            cells: vec![Cell::default(); width * height],
+            cursor: (0, 0),
+            formats: Vec::new(),
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let text = String::from_utf8_lossy(bytes);
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\x1b' => match chars.next() {
+                    // CSI
+                    Some('[') => {
+                        let mut params = String::new();
+                        let mut command = None;
+                        for c in chars.by_ref() {
+                            if ('\x40'..='\x7e').contains(&c) {
+                                command = Some(c);
+                                break;
+                            }
+                            params.push(c);
+                        }
+                        if let Some(command) = command {
+                            self.csi(&params, command);
+                        }
+                    }
+                    // OSC, like hyperlinks. They end with ESC \ or BEL.
+                    Some(']') => {
+                        while let Some(c) = chars.next() {
+                            if c == '\x07' {
+                                break;
+                            }
+                            if c == '\x1b' && chars.peek() == Some(&'\\') {
+                                chars.next();
+                                break;
+                            }
+                        }
+                    }
+                    _ => {}
+                },
+                '\r' => self.cursor.0 = 0,
+                '\n' => self.cursor.1 += 1,
+                c if c.is_control() => {}
+                c => {
+                    let (x, y) = self.cursor;
+                    // line wrapping is disabled, so characters past the edge
+                    // are dropped
+                    if x < self.width && y < self.height {
+                        self.cells[y * self.width + x] = Cell {
+                            c,
+                            formats: self.formats.clone(),
+                        };
+                    }
+                    self.cursor.0 += 1;
+                }
+            }
+        }
+    }
+
+    fn csi(&mut self, params: &str, command: char) {
+        // private modes like hiding the cursor don't change what's shown
+        if params.starts_with('?') {
+            return;
+        }
+        match command {
+            'H' => {
+                let mut numbers = params
+                    .split(';')
+                    .map(|n| n.parse::<usize>().unwrap_or(1).max(1));
+                let y = numbers.next().unwrap_or(1);
+                let x = numbers.next().unwrap_or(1);
+                self.cursor = (x - 1, y - 1);
+            }
+            'J' if params == "2" => self.cells.fill(Cell::default()),
+            'm' => {
+                if params.is_empty() || params == "0" {
+                    self.formats.clear();
+                } else {
+                    self.formats.extend(params.split(';').map(str::to_owned));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The text in the row, without trailing spaces.
+    pub fn row(&self, y: usize) -> String {
+        self.cells[y * self.width..(y + 1) * self.width]
+            .iter()
+            .map(|cell| if cell.c == '\0' { ' ' } else { cell.c })
+            .collect::<String>()
+            .trim_end()
+            .to_owned()
+    }
+
+    pub fn text(&self) -> String {
+        (0..self.height)
+            .map(|y| self.row(y))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Where the text first appears on the screen.
+    pub fn find(&self, text: &str) -> Option<(usize, usize)> {
+        (0..self.height).find_map(|y| {
+            let row = self.row(y);
+            let byte_index = row.find(text)?;
+            Some((row[..byte_index].chars().count(), y))
+        })
+    }
+
+    /// The SGR parameters of the character at the position.
+    fn formats(&self, (x, y): (usize, usize)) -> &[String] {
+        &self.cells[y * self.width + x].formats
+    }
+}
+
+/// A [`TerminalSession`] and what its client would be seeing.
+pub struct Replay {
+    pub session: TerminalSession,
+    pub screen: VirtualScreen,
+}
+
+impl Replay {
+    pub fn new(site_data: SiteData, width: usize, height: usize) -> Self {
+        let mut replay = Replay {
+            session: TerminalSession::new(
+                site_data.into(),
+                "test",
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ),
+            screen: VirtualScreen::new(width, height),
+        };
+        let open = replay.session.on_open();
+        replay.screen.feed(&open);
+        replay.resize(width, height);
+        replay
+    }
+
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.screen = VirtualScreen::new(width, height);
+        let out = self.session.resize(width as u32, height as u32);
+        self.screen.feed(&out);
+    }
+
+    pub fn keys(&mut self, keys: &[u8]) {
+        let out = self.session.on_keystroke(keys);
+        self.screen.feed(&out);
+    }
+
+    /// Redraw after changing something about the session directly.
+    pub fn draw(&mut self) {
+        let out = self.session.draw();
+        self.screen.feed(&out);
+    }
+
+    /// Run one line of a script.
+    fn run_line(&mut self, line: &str) -> Result<(), String> {
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "size" => {
+                let (width, height) = args
+                    .split_once(' ')
+                    .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                    .ok_or("size needs a width and height")?;
+                self.resize(width, height);
+            }
+            "terminal" => {
+                self.session.set_terminal_type(args);
+                self.draw();
+            }
+            "utf8" => {
+                self.session.set_utf8(args == "on");
+                self.draw();
+            }
+            "echo" => {
+                self.session.set_modes(TerminalModes {
+                    echo: args == "on",
+                    ..self.session.modes()
+                });
+                self.draw();
+            }
+            "locale" => {
+                let locale = Locale::from_code(args).ok_or("unknown locale")?;
+                self.session.set_locale(locale);
+                self.draw();
+            }
+            "keys" => self.keys(&parse_keys(args)?),
+            "mouse" => {
+                let (action, target) = args.split_once(' ').ok_or("mouse needs a target")?;
+                let button = match action {
+                    "click" => 0,
+                    "move" => 35,
+                    _ => return Err(format!("unknown mouse action {action:?}")),
+                };
+                let (x, y) = match quoted(target) {
+                    Ok((text, _)) => self
+                        .screen
+                        .find(text)
+                        .ok_or_else(|| format!("{text:?} isn't on the screen"))?,
+                    Err(_) => target
+                        .split_once(' ')
+                        .and_then(|(x, y)| Some((x.parse().ok()?, y.parse().ok()?)))
+                        .ok_or("mouse needs some text or a position")?,
+                };
+                // the coordinates are 1-indexed
+                self.keys(format!("\x1b[<{button};{};{}M", x + 1, y + 1).as_bytes());
+            }
+            "expect" => {
+                let (text, _) = quoted(args)?;
+                if self.screen.find(text).is_none() {
+                    return Err(format!("{text:?} isn't on the screen"));
+                }
+            }
+            "expect-not" => {
+                let (text, _) = quoted(args)?;
+                if self.screen.find(text).is_some() {
+                    return Err(format!("{text:?} is on the screen"));
+                }
+            }
+            "expect-row" => {
+                let (row, args) = args.split_once(' ').ok_or("expect-row needs a row")?;
+                let row = row.parse::<usize>().map_err(|e| e.to_string())?;
+                let (text, _) = quoted(args)?;
+                if row >= self.screen.height {
+                    return Err(format!("row {row} is off the screen"));
+                }
+                if !self.screen.row(row).contains(text) {
+                    return Err(format!("{text:?} isn't in row {row}"));
+                }
+            }
+            "expect-format" | "expect-no-format" => {
+                let (text, format) = quoted(args)?;
+                let format = format.trim();
+                let position = self
+                    .screen
+                    .find(text)
+                    .ok_or_else(|| format!("{text:?} isn't on the screen"))?;
+                let has_format = self.screen.formats(position).iter().any(|f| f == format);
+                if has_format != (command == "expect-format") {
+                    return Err(format!(
+                        "{text:?} has the formats {:?}",
+                        self.screen.formats(position)
+                    ));
+                }
+            }
+            _ => return Err(format!("unknown command {command:?}")),
+        }
+        Ok(())
+    }
+}
+
+/// The text between the quotes at the start, and whatever's after it.
+fn quoted(s: &str) -> Result<(&str, &str), String> {
+    let s = s
+        .trim_start()
+        .strip_prefix('"')
+        .ok_or("expected a quoted string")?;
+    let end = s.find('"').ok_or("unclosed quote")?;
+    Ok((&s[..end], &s[end + 1..]))
+}
+
+fn parse_keys(s: &str) -> Result<Vec<u8>, String> {
+    let mut keys = Vec::new();
+    let mut rest = s;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('<') {
+            if let Some((name, after)) = after.split_once('>') {
+                let key: &[u8] = match name {
+                    "tab" => b"\t",
+                    "shift-tab" => b"\x1b[Z",
+                    "enter" => b"\r",
+                    "up" => b"\x1b[A",
+                    "down" => b"\x1b[B",
+                    "pgup" => b"\x1b[5~",
+                    "pgdn" => b"\x1b[6~",
+                    "esc" => b"\x1b",
+                    "bs" => b"\x7f",
+                    "c-d" => b"\x04",
+                    "c-u" => b"\x15",
+                    "c-r" => b"\x12",
+                    "paste-start" => b"\x1b[200~",
+                    "paste-end" => b"\x1b[201~",
+                    "focus-in" => b"\x1b[I",
+                    _ => return Err(format!("unknown key <{name}>")),
+                };
+                keys.extend(key);
+                rest = after;
+                continue;
+            }
+        }
+        let c = rest.chars().next().unwrap();
+        keys.extend(c.to_string().as_bytes());
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(keys)
+}
+
+/// A couple of posts and a project, so the pages have something on them.
+pub fn site_data() -> SiteData {
+    let post = |slug: &str, title: &str, content: Vec<PostPart>| Post {
+        title: title.to_owned(),
+        slug: slug.to_owned(),
+        published: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+        word_count: count_words(&content),
+        content,
+        draft: false,
+        tags: vec!["testing".to_owned()],
+    };
+
+    let mut paragraphs = (1..=30)
+        .flat_map(|i| {
+            [
+                PostPart::Text(format!("Paragraph {i}.")),
+                PostPart::LineBreak,
+            ]
+        })
+        .collect::<Vec<_>>();
+    paragraphs.insert(1, PostPart::FootnoteReference("1".to_owned()));
+    paragraphs.push(PostPart::Footnote {
+        id: "1".to_owned(),
+        text: "A note at the bottom.".to_owned(),
+    });
+    let wrapping = vec![PostPart::Text(
+        "Incomprehensibilities notwithstanding, extraordinarily long words \
+         should always be wrapped onto the next line instead of being split \
+         somewhere in the middle."
+            .to_owned(),
+    )];
+
+    SiteData {
+        projects: vec![Project {
+            name: "matdoesdev".to_owned(),
+            href: Some("https://matdoes.dev".to_owned()),
+            source: None,
+            languages: vec![LanguageName::Rust],
+            description: "The website you're looking at.".to_owned(),
+        }],
+        blog: vec![
+            post("long-post", "A long post", paragraphs),
+            post("wrapping", "Wrapping", wrapping),
+        ],
+        stale: false,
+    }
+}
+
+#[test]
+fn replay_scripts() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/terminal");
+    let mut paths = fs::read_dir(&dir)
+        .expect("tests/terminal should exist")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(!paths.is_empty(), "no scripts in {dir:?}");
+
+    let mut failures = Vec::new();
+    for path in paths {
+        let script = fs::read_to_string(&path).unwrap();
+        let mut replay = Replay::new(site_data(), 80, 24);
+        for (i, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Err(err) = replay.run_line(line) {
+                failures.push(format!(
+                    "{}:{}: {err}\n{}",
+                    path.display(),
+                    i + 1,
+                    replay.screen.text()
+                ));
+                break;
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+#[test]
+fn render_text_has_the_whole_page() {
+    let mut session =
+        TerminalSession::new(site_data().into(), "test", IpAddr::V6(Ipv6Addr::LOCALHOST));
+    let text = session.render_text(
+        super::Location::BlogPost {
+            slug: "long-post".to_owned(),
+        },
+        40,
+    );
+    assert!(text.contains("Paragraph 1."));
+    assert!(text.contains("Paragraph 30."));
+    assert!(text.lines().all(|line| line.chars().count() <= 40));
+}
+
+#[test]
+fn huge_windows_are_clamped() {
+    let mut session =
+        TerminalSession::new(site_data().into(), "test", IpAddr::V6(Ipv6Addr::LOCALHOST));
+    // this would be billions of cells if it wasn't
+    let out = session.resize(u32::from(u16::MAX), u32::from(u16::MAX));
+    assert!(String::from_utf8_lossy(&out).contains("matdoesdev"));
+}
+
+#[test]
+fn history_is_capped() {
+    let mut session =
+        TerminalSession::new(site_data().into(), "test", IpAddr::V6(Ipv6Addr::LOCALHOST));
+    for _ in 0..super::MAX_HISTORY * 2 {
+        session.navigate(super::Location::Blog);
+    }
+    assert_eq!(session.history.len(), super::MAX_HISTORY);
+}
+
+#[test]
+fn search_query_is_capped() {
+    let mut session =
+        TerminalSession::new(site_data().into(), "test", IpAddr::V6(Ipv6Addr::LOCALHOST));
+    session.navigate(super::Location::Search {
+        query: String::new(),
+    });
+    for _ in 0..super::MAX_QUERY_LENGTH {
+        session.on_keystroke("é".as_bytes());
+    }
+    session.on_keystroke(b"abc");
+    let super::Location::Search { query } = &session.ctx.location else {
+        panic!("not on the search page");
+    };
+    assert_eq!(query.chars().count(), super::MAX_QUERY_LENGTH);
+}
+
+#[test]
+fn pasted_query_is_capped() {
+    let mut session =
+        TerminalSession::new(site_data().into(), "test", IpAddr::V6(Ipv6Addr::LOCALHOST));
+    session.navigate(super::Location::Search {
+        query: String::new(),
+    });
+    session.on_paste(&[b'a'; 10_000]);
+    let super::Location::Search { query } = &session.ctx.location else {
+        panic!("not on the search page");
+    };
+    assert_eq!(query.len(), super::MAX_QUERY_LENGTH);
+}
+
+#[test]
+fn scroll_positions_are_forgotten() {
+    let mut replay = Replay::new(site_data(), 80, 24);
+    replay.session.navigate(super::Location::Search {
+        query: String::new(),
+    });
+    replay.draw();
+    for _ in 0..1000 {
+        replay.session.on_keystroke(b"a");
+    }
+    assert!(replay.session.ctx.scroll.len() <= 2);
+}
@@ -0,0 +1,135 @@
+//! Small ASCII art versions of the images in posts, for the protocols that
+//! can't show images. They're made when the site data is loaded and kept in
+//! `data/thumbs/`, so they're only made again when the image changes.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use tokio::fs;
+
+use crate::crawl::{ImageSource, Post, PostPart, SiteData};
+
+const THUMBS_DIR: &str = "data/thumbs";
+/// How wide the thumbnails are, in characters.
+const WIDTH: u32 = 40;
+/// Characters are about twice as tall as they are wide, so there's half as
+/// many rows as there would be pixels.
+const CHAR_ASPECT: f32 = 0.5;
+/// From the darkest to the lightest.
+const RAMP: &[u8] = b"@%#*+=-:. ";
+
+/// The local images in the posts, without duplicates.
+fn local_images(posts: &[Post]) -> Vec<&Path> {
+    let mut images = Vec::new();
+    for part in posts.iter().flat_map(|post| &post.content) {
+        if let PostPart::Image {
+            src: ImageSource::Local(path),
+            ..
+        } = part
+        {
+            if !images.contains(&path.as_path()) {
+                images.push(path.as_path());
+            }
+        }
+    }
+    images
+}
+
+/// Where the thumbnail for an image in the media directory is kept.
+fn thumb_path(image: &Path) -> PathBuf {
+    let relative = image.strip_prefix("media").unwrap_or(image);
+    let mut path = Path::new(THUMBS_DIR).join(relative).into_os_string();
+    path.push(".txt");
+    path.into()
+}
+
+/// Make the thumbnails for the images that don't have an up-to-date one.
+/// Images that can't be decoded (like SVGs) don't get one.
+pub async fn generate(data: &SiteData) {
+    for path in local_images(&data.blog) {
+        if ImageFormat::from_path(path).is_err() {
+            continue;
+        }
+        let thumb = thumb_path(path);
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if let (Some(image_modified), Some(thumb_modified)) = (modified(path), modified(&thumb)) {
+            if thumb_modified >= image_modified {
+                continue;
+            }
+        }
+
+        let owned_path = path.to_owned();
+        let art = tokio::task::spawn_blocking(move || image::open(owned_path))
+            .await
+            .map(|decoded| decoded.map(|decoded| ascii_art(&decoded, WIDTH)));
+        let art = match art {
+            Ok(Ok(art)) => art,
+            Ok(Err(err)) => {
+                eprintln!("couldn't make a thumbnail for {path:?}: {err}");
+                continue;
+            }
+            Err(_) => continue,
+        };
+        if let Some(parent) = thumb.parent() {
+            let _ = fs::create_dir_all(parent).await;
+        }
+        if let Err(err) = fs::write(&thumb, art).await {
+            eprintln!("couldn't save the thumbnail for {path:?}: {err}");
+        }
+    }
+}
+
+/// The thumbnails that have been made for the images in the posts, by the
+/// image's path.
+pub fn for_posts(posts: &[Post]) -> HashMap<PathBuf, String> {
+    local_images(posts)
+        .into_iter()
+        .filter_map(|image| {
+            let art = std::fs::read_to_string(thumb_path(image)).ok()?;
+            Some((image.to_owned(), art))
+        })
+        .collect()
+}
+
+/// Draw the image with characters, `width` columns wide. Transparent parts
+/// are left blank.
+pub fn ascii_art(image: &DynamicImage, width: u32) -> String {
+    let aspect = image.height() as f32 / image.width().max(1) as f32;
+    let height = (aspect * width as f32 * CHAR_ASPECT).round().max(1.) as u32;
+    let small = image
+        .resize_exact(width, height, FilterType::Triangle)
+        .to_luma_alpha8();
+
+    let mut art = String::new();
+    for row in small.rows() {
+        let line = row
+            .map(|pixel| {
+                let [luma, alpha] = pixel.0;
+                if alpha < 128 {
+                    return ' ';
+                }
+                let index = luma as usize * (RAMP.len() - 1) / 255;
+                RAMP[index] as char
+            })
+            .collect::<String>();
+        art.push_str(line.trim_end());
+        art.push('\n');
+    }
+    art
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma};
+
+    use super::*;
+
+    #[test]
+    fn dark_pixels_are_dense() {
+        let image = GrayImage::from_fn(4, 2, |x, _| if x < 2 { Luma([0]) } else { Luma([255]) });
+        assert_eq!(ascii_art(&DynamicImage::ImageLuma8(image), 4), "@@\n");
+    }
+}
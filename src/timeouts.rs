@@ -0,0 +1,61 @@
+//! How long clients get to send a request and to read the response, so slow
+//! or idle ones can't keep a connection (and its task) open forever. They can
+//! be changed with `--read-timeout` and `--write-timeout`.
+
+use std::{future::Future, io, sync::OnceLock, time::Duration};
+
+use tokio::time::timeout;
+
+use crate::protocols::error::ProtocolError;
+
+/// The whole request has to arrive in this long.
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// The whole response has to be sent in this long. It's longer since it
+/// includes media files.
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(120);
+
+static READ_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static WRITE_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// Change the timeouts from the defaults. This can only be done once, before
+/// any of the servers start.
+pub fn set(read: Option<Duration>, write: Option<Duration>) {
+    if let Some(read) = read {
+        READ_TIMEOUT
+            .set(read)
+            .expect("read timeout was already set");
+    }
+    if let Some(write) = write {
+        WRITE_TIMEOUT
+            .set(write)
+            .expect("write timeout was already set");
+    }
+}
+
+pub fn read_timeout() -> Duration {
+    *READ_TIMEOUT.get().unwrap_or(&DEFAULT_READ_TIMEOUT)
+}
+
+pub fn write_timeout() -> Duration {
+    *WRITE_TIMEOUT.get().unwrap_or(&DEFAULT_WRITE_TIMEOUT)
+}
+
+/// Read a request, or fail with [`ProtocolError::TimedOut`] if it takes longer
+/// than the read timeout.
+pub async fn read<T, E: Into<ProtocolError>>(
+    reading: impl Future<Output = Result<T, E>>,
+) -> Result<T, ProtocolError> {
+    match timeout(read_timeout(), reading).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(ProtocolError::TimedOut),
+    }
+}
+
+/// Send a response, or give up if it takes longer than the write timeout.
+/// Nothing else can be sent once writing is too slow, so it's just an io
+/// error.
+pub async fn write<T>(writing: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    timeout(write_timeout(), writing)
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
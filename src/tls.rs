@@ -0,0 +1,234 @@
+//! The certificates for the protocols that can use TLS, shared so they all
+//! have the same ones. There's one for every hostname, and they're self-signed
+//! unless [`crate::acme`] replaces them with real ones.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock},
+};
+
+use parking_lot::RwLock;
+use rcgen::{Certificate, CertificateParams, DnType, KeyPair};
+use tokio_rustls::{
+    rustls::{
+        client::danger::HandshakeSignatureValid,
+        crypto::{
+            ring::{self, sign::any_supported_type},
+            verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms,
+        },
+        pki_types::{CertificateDer, PrivateKeyDer, UnixTime},
+        server::{
+            danger::{ClientCertVerified, ClientCertVerifier},
+            ClientHello, ResolvesServerCert,
+        },
+        sign::CertifiedKey,
+        DigitallySignedStruct, DistinguishedName, Error, ServerConfig, SignatureScheme,
+    },
+    TlsAcceptor,
+};
+
+use crate::{hostnames, HOSTNAME};
+
+static RESOLVER: LazyLock<Arc<CertResolver>> = LazyLock::new(|| {
+    let keys = hostnames()
+        .map(|hostname| {
+            let (cert, private_key) = load_certs(hostname);
+            let key =
+                certified_key(vec![cert], private_key).expect("self-signed key should be valid");
+            (hostname.to_owned(), key)
+        })
+        .collect();
+    Arc::new(CertResolver {
+        keys: RwLock::new(keys),
+        replaced: Default::default(),
+    })
+});
+
+/// Gives every connection the current certificate for the hostname it asked
+/// for with SNI, so they can be swapped out without restarting the listeners.
+#[derive(Debug)]
+struct CertResolver {
+    keys: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    /// The hostnames whose certificates came from somewhere else, like ACME,
+    /// so they aren't replaced with the self-signed ones when those reload.
+    replaced: RwLock<HashSet<String>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let keys = self.keys.read();
+        // clients that don't send a hostname get the main one
+        let hostname = client_hello.server_name().unwrap_or(HOSTNAME);
+        keys.get(hostname).or_else(|| keys.get(HOSTNAME)).cloned()
+    }
+}
+
+fn certified_key(
+    certs: Vec<CertificateDer<'static>>,
+    private_key: PrivateKeyDer<'static>,
+) -> anyhow::Result<Arc<CertifiedKey>> {
+    let signing_key = any_supported_type(&private_key)?;
+    Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+}
+
+/// Use this certificate chain for every new connection to the hostname.
+pub fn set_certificate(
+    hostname: &str,
+    certs: Vec<CertificateDer<'static>>,
+    private_key: PrivateKeyDer<'static>,
+) -> anyhow::Result<()> {
+    let key = certified_key(certs, private_key)?;
+    RESOLVER.replaced.write().insert(hostname.to_owned());
+    RESOLVER.keys.write().insert(hostname.to_owned(), key);
+    Ok(())
+}
+
+/// Read the self-signed certificates again after they were changed on disk.
+/// If one can't be read, like when it's only been partly written, the old one
+/// is kept.
+pub fn reload_self_signed() {
+    for hostname in hostnames() {
+        if RESOLVER.replaced.read().contains(hostname) {
+            continue;
+        }
+        let key = read_certs(&key_path(hostname))
+            .and_then(|(cert, private_key)| certified_key(vec![cert], private_key));
+        match key {
+            Ok(key) => {
+                println!("reloaded the certificate for {hostname}");
+                RESOLVER.keys.write().insert(hostname.to_owned(), key);
+            }
+            Err(err) => eprintln!("couldn't reload the certificate for {hostname}: {err}"),
+        }
+    }
+}
+
+fn generate_new_cert(hostname: &str) -> (Certificate, KeyPair) {
+    let mut cert_params = CertificateParams::new(vec![hostname.to_string()]).unwrap();
+    cert_params
+        .distinguished_name
+        .push(DnType::CommonName, hostname);
+
+    let keypair = KeyPair::generate().unwrap();
+
+    let cert = cert_params.self_signed(&keypair).unwrap();
+
+    (cert, keypair)
+}
+
+// this is in the gemini directory since it used to be the only protocol with
+// tls, and changing the cert would make clients that pinned it complain
+pub const KEY_PATH: &str = "data/gemini/certs";
+const PUBLIC_KEY_FILENAME: &str = "public.der";
+const PRIVATE_KEY_FILENAME: &str = "private.der";
+
+fn key_path(hostname: &str) -> PathBuf {
+    // the other hostnames get their own directories
+    if hostname == HOSTNAME {
+        Path::new(KEY_PATH).to_owned()
+    } else {
+        Path::new(KEY_PATH).join(hostname)
+    }
+}
+
+fn load_certs(hostname: &str) -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+    // try to load the key files first, then generate them if they don't exist
+    let key_path = key_path(hostname);
+
+    let public_key_path = key_path.join(PUBLIC_KEY_FILENAME);
+    let private_key_path = key_path.join(PRIVATE_KEY_FILENAME);
+
+    if !public_key_path.exists() || !private_key_path.exists() {
+        let (new_cert, keypair) = generate_new_cert(hostname);
+
+        let public_key = new_cert.der();
+        let private_key = keypair.serialize_der();
+
+        // make the directory if it doesn't exist
+        fs::create_dir_all(&key_path).unwrap();
+        fs::write(&public_key_path, public_key).unwrap();
+        fs::write(&private_key_path, private_key).unwrap();
+    }
+
+    read_certs(&key_path).unwrap()
+}
+
+fn read_certs(
+    key_path: &Path,
+) -> anyhow::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let public_key = fs::read(key_path.join(PUBLIC_KEY_FILENAME))?;
+    let private_key = fs::read(key_path.join(PRIVATE_KEY_FILENAME))?;
+
+    let cert = CertificateDer::from(public_key);
+    let private_key = PrivateKeyDer::try_from(&private_key[..])
+        .map_err(anyhow::Error::msg)?
+        .clone_key();
+    Ok((cert, private_key))
+}
+
+pub fn acceptor() -> TlsAcceptor {
+    let tls_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::clone(&*RESOLVER) as Arc<dyn ResolvesServerCert>);
+    TlsAcceptor::from(Arc::new(tls_config))
+}
+
+/// Like [`acceptor`], but clients can send a certificate, which can be
+/// self-signed. This is how Gemini clients identify themselves, and browsers
+/// would ask which certificate to use, so it's only for Gemini.
+pub fn acceptor_with_client_certs() -> TlsAcceptor {
+    let verifier = AnyClientCert(ring::default_provider().signature_verification_algorithms);
+    let tls_config = ServerConfig::builder()
+        .with_client_cert_verifier(Arc::new(verifier))
+        .with_cert_resolver(Arc::clone(&*RESOLVER) as Arc<dyn ResolvesServerCert>);
+    TlsAcceptor::from(Arc::new(tls_config))
+}
+
+/// Accepts every client certificate without checking who issued it. The
+/// handshake still proves that the client has the certificate's key, which is
+/// all that's needed to recognize it again.
+#[derive(Debug)]
+struct AnyClientCert(WebPkiSupportedAlgorithms);
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
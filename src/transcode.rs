@@ -0,0 +1,162 @@
+//! JPEG and PNG versions of the images in formats that gopher and gemini
+//! clients usually can't show, like WebP and AVIF. They're made the first time
+//! they're requested and kept in `data/transcoded/`.
+
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use image::{DynamicImage, ImageFormat};
+use tokio::{fs, sync::Mutex};
+
+use crate::media;
+
+const TRANSCODED_DIR: &str = "data/transcoded";
+/// The formats that get a fallback.
+const MODERN_FORMATS: &[ImageFormat] = &[ImageFormat::WebP, ImageFormat::Avif];
+
+/// Only one image is transcoded at a time, so the same file isn't written
+/// twice at once and a burst of requests can't use every core.
+static TRANSCODING: Mutex<()> = Mutex::const_new(());
+
+/// Get the path of a file in the media directory like [`media::resolve`], but
+/// for clients that can't show modern formats. Adding `.jpg` or `.png` to the
+/// path of a modern image (like `cat.webp.jpg`) gets it in that format, and if
+/// `fallback` is set, modern images are always sent as JPEG or PNG.
+pub async fn resolve(relative_path: &str, fallback: bool) -> Option<PathBuf> {
+    if let Some(path) = media::resolve(relative_path) {
+        if fallback && is_modern(&path) {
+            let transcoded = transcode(&path, relative_path, None).await;
+            return Some(transcoded.unwrap_or(path));
+        }
+        return Some(path);
+    }
+
+    let (original, format) = split_alias(relative_path)?;
+    let path = media::resolve(original).filter(|path| is_modern(path))?;
+    transcode(&path, original, Some(format)).await
+}
+
+/// Split a path like `cat.webp.jpg` into the original path and the format
+/// that was asked for.
+fn split_alias(relative_path: &str) -> Option<(&str, ImageFormat)> {
+    let (original, extension) = relative_path.rsplit_once('.')?;
+    let format = match extension.to_ascii_lowercase().as_str() {
+        "jpg" | "jpeg" => ImageFormat::Jpeg,
+        "png" => ImageFormat::Png,
+        _ => return None,
+    };
+    Some((original, format))
+}
+
+fn is_modern(path: &Path) -> bool {
+    ImageFormat::from_path(path).is_ok_and(|format| MODERN_FORMATS.contains(&format))
+}
+
+/// Where the transcoded version of a file in the media directory is kept.
+fn transcoded_path(relative_path: &str, format: ImageFormat) -> PathBuf {
+    let extension = format.extensions_str()[0];
+    Path::new(TRANSCODED_DIR).join(format!("{relative_path}.{extension}"))
+}
+
+/// The path of an up-to-date transcode of the image in the format, making it
+/// if there isn't one. When no format is given, images with transparency
+/// become PNGs and the rest become JPEGs. `None` if the image couldn't be
+/// decoded.
+async fn transcode(
+    path: &Path,
+    relative_path: &str,
+    format: Option<ImageFormat>,
+) -> Option<PathBuf> {
+    let modified = |path: &Path| -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    };
+    let is_fresh = |transcoded: &Path| match (modified(path), modified(transcoded)) {
+        (Some(original), Some(transcoded)) => transcoded >= original,
+        _ => false,
+    };
+    let candidates = match format {
+        Some(format) => vec![format],
+        None => vec![ImageFormat::Jpeg, ImageFormat::Png],
+    };
+
+    let _guard = TRANSCODING.lock().await;
+    for &format in &candidates {
+        let transcoded = transcoded_path(relative_path, format);
+        if is_fresh(&transcoded) {
+            return Some(transcoded);
+        }
+    }
+
+    let owned_path = path.to_owned();
+    let encoded = tokio::task::spawn_blocking(move || {
+        let image = image::open(owned_path)?;
+        let format = format.unwrap_or(if image.color().has_alpha() {
+            ImageFormat::Png
+        } else {
+            ImageFormat::Jpeg
+        });
+        encode(&image, format).map(|bytes| (format, bytes))
+    })
+    .await
+    .ok()?;
+    let (format, bytes) = match encoded {
+        Ok(encoded) => encoded,
+        Err(err) => {
+            eprintln!("couldn't transcode {path:?}: {err}");
+            return None;
+        }
+    };
+
+    let transcoded = transcoded_path(relative_path, format);
+    if let Some(parent) = transcoded.parent() {
+        let _ = fs::create_dir_all(parent).await;
+    }
+    if let Err(err) = fs::write(&transcoded, bytes).await {
+        eprintln!("couldn't save the transcode of {path:?}: {err}");
+        return None;
+    }
+    Some(transcoded)
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat) -> image::ImageResult<Vec<u8>> {
+    let mut bytes = Cursor::new(Vec::new());
+    match format {
+        // jpeg can't have transparency
+        ImageFormat::Jpeg => {
+            DynamicImage::ImageRgb8(image.to_rgb8()).write_to(&mut bytes, format)?
+        }
+        _ => image.write_to(&mut bytes, format)?,
+    }
+    Ok(bytes.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn splits_aliases() {
+        assert_eq!(
+            split_alias("images/cat.webp.jpg"),
+            Some(("images/cat.webp", ImageFormat::Jpeg))
+        );
+        assert_eq!(
+            split_alias("cat.avif.PNG"),
+            Some(("cat.avif", ImageFormat::Png))
+        );
+        assert_eq!(split_alias("cat.webp"), None);
+        assert_eq!(split_alias("cat"), None);
+    }
+
+    #[test]
+    fn encodes_transparent_images_as_jpeg() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, Rgba([0, 0, 0, 0])));
+        let bytes = encode(&image, ImageFormat::Jpeg).unwrap();
+        assert_eq!(image::guess_format(&bytes).unwrap(), ImageFormat::Jpeg);
+    }
+}
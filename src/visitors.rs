@@ -0,0 +1,125 @@
+//! When people were last here, so the posts that were published since then
+//! can be marked as new. Visitors are only known when they can be recognized
+//! again: by their key over SSH, their client certificate over Gemini, and a
+//! cookie over HTTP.
+
+use std::{collections::HashMap, fs, path::Path, sync::LazyLock};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+pub const VISITORS_PATH: &str = "data/visitors/visitors.json";
+
+/// How long someone has to be gone before coming back counts as a new visit.
+/// Until then, the posts that were new when the visit started stay marked.
+const VISIT_GAP: TimeDelta = TimeDelta::minutes(30);
+
+/// What's put next to the posts that are new to the visitor.
+pub const NEW_MARKER: &str = "*new*";
+
+static VISITORS: LazyLock<RwLock<HashMap<String, Visitor>>> = LazyLock::new(|| {
+    let visitors = fs::read_to_string(VISITORS_PATH)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default();
+    RwLock::new(visitors)
+});
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct Visitor {
+    last_seen: DateTime<Utc>,
+    /// When the visit before the current one ended, or `None` if this is
+    /// their first one.
+    previous_visit: Option<DateTime<Utc>>,
+}
+
+/// Record that the visitor is here, and return when their previous visit
+/// ended. The id should say which protocol it's from, like `ssh:<fingerprint>`.
+pub fn visit(id: &str) -> Option<DateTime<Utc>> {
+    let mut visitors = VISITORS.write();
+    let (previous_visit, new_visit) = visit_at(&mut visitors, id, Utc::now());
+    // only written when a visit starts, since otherwise it'd be every request.
+    // losing the last few requests just makes the next visit's new posts
+    // start a bit earlier.
+    if new_visit {
+        save(&visitors);
+    }
+    previous_visit
+}
+
+/// Returns when the previous visit ended, and whether this started a new one.
+fn visit_at(
+    visitors: &mut HashMap<String, Visitor>,
+    id: &str,
+    now: DateTime<Utc>,
+) -> (Option<DateTime<Utc>>, bool) {
+    let Some(visitor) = visitors.get_mut(id) else {
+        visitors.insert(
+            id.to_owned(),
+            Visitor {
+                last_seen: now,
+                previous_visit: None,
+            },
+        );
+        return (None, true);
+    };
+    let new_visit = now - visitor.last_seen > VISIT_GAP;
+    if new_visit {
+        visitor.previous_visit = Some(visitor.last_seen);
+    }
+    visitor.last_seen = now;
+    (visitor.previous_visit, new_visit)
+}
+
+/// Whether a post is new to someone whose previous visit ended at
+/// `previous_visit`. Nothing is new on the first visit.
+pub fn is_new(published: DateTime<Utc>, previous_visit: Option<DateTime<Utc>>) -> bool {
+    previous_visit.is_some_and(|previous_visit| published > previous_visit)
+}
+
+fn save(visitors: &HashMap<String, Visitor>) {
+    if cfg!(test) {
+        return;
+    }
+
+    let write = || -> anyhow::Result<()> {
+        fs::create_dir_all(Path::new(VISITORS_PATH).parent().unwrap())?;
+        fs::write(VISITORS_PATH, serde_json::to_string(visitors)?)?;
+        Ok(())
+    };
+    if let Err(err) = write() {
+        eprintln!("failed to save visitors: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn visits_are_split_by_gaps() {
+        let mut visitors = HashMap::new();
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let at = |minutes| start + TimeDelta::minutes(minutes);
+
+        assert_eq!(visit_at(&mut visitors, "test", at(0)), (None, true));
+        assert_eq!(visit_at(&mut visitors, "test", at(20)), (None, false));
+        // the previous visit ended with the last request
+        assert_eq!(
+            visit_at(&mut visitors, "test", at(60)),
+            (Some(at(20)), true)
+        );
+        assert_eq!(
+            visit_at(&mut visitors, "test", at(70)),
+            (Some(at(20)), false)
+        );
+        assert_eq!(visit_at(&mut visitors, "other", at(70)), (None, true));
+
+        assert!(is_new(at(30), Some(at(20))));
+        assert!(!is_new(at(10), Some(at(20))));
+        assert!(!is_new(at(30), None));
+    }
+}
@@ -0,0 +1,102 @@
+//! Reloading the files that can be edited while we're running, so changing the
+//! quote of the day by hand or replacing the self-signed TLS certificates
+//! doesn't need a restart. The secrets and the SSH host key aren't watched
+//! since they're already read every time they're used.
+
+use std::{fs, path::Path, time::Duration};
+
+use notify::{Event, RecursiveMode, Watcher};
+use tokio::{sync::mpsc, time::timeout};
+
+use crate::{
+    protocols::qotd::{Qotd, QOTD_MESSAGE_PATH},
+    tls,
+};
+
+/// Editors often save a file in a few steps, so changes are only handled once
+/// nothing else has changed for this long.
+const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+#[derive(Debug, PartialEq)]
+enum Change {
+    Qotd,
+    Certificates,
+}
+
+/// What needs to be reloaded because of a change to the file. The watched
+/// directories only have these files, so the name is enough.
+fn classify(path: &Path) -> Option<Change> {
+    let file_name = path.file_name()?;
+    if Some(file_name) == Path::new(QOTD_MESSAGE_PATH).file_name() {
+        Some(Change::Qotd)
+    } else if path.extension().is_some_and(|extension| extension == "der") {
+        Some(Change::Certificates)
+    } else {
+        None
+    }
+}
+
+/// Watch the files and reload them into `qotd` and the TLS certificates when
+/// they change. This never returns, even if the files can't be watched, so it
+/// can be raced against the servers.
+pub async fn run(qotd: Qotd) {
+    if let Err(err) = watch(qotd).await {
+        eprintln!("couldn't watch files: {err}");
+    }
+    std::future::pending().await
+}
+
+async fn watch(qotd: Qotd) -> notify::Result<()> {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event) if !event.kind.is_access() => {
+                for path in event.paths {
+                    let _ = sender.send(path);
+                }
+            }
+            Ok(_) => {}
+            Err(err) => eprintln!("error watching files: {err}"),
+        })?;
+
+    let qotd_dir = Path::new(QOTD_MESSAGE_PATH).parent().unwrap();
+    for (dir, mode) in [
+        (qotd_dir, RecursiveMode::NonRecursive),
+        // the other hostnames' certificates are in subdirectories
+        (Path::new(tls::KEY_PATH), RecursiveMode::Recursive),
+    ] {
+        let _ = fs::create_dir_all(dir);
+        watcher.watch(dir, mode)?;
+    }
+
+    // the sender is only dropped with the watcher
+    while let Some(path) = receiver.recv().await {
+        let mut changes = Vec::from_iter(classify(&path));
+        while let Ok(Some(path)) = timeout(SETTLE_TIME, receiver.recv()).await {
+            changes.extend(classify(&path));
+        }
+
+        if changes.contains(&Change::Qotd) {
+            qotd.reload();
+        }
+        if changes.contains(&Change::Certificates) {
+            tls::reload_self_signed();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_changes() {
+        assert_eq!(classify(Path::new(QOTD_MESSAGE_PATH)), Some(Change::Qotd));
+        assert_eq!(
+            classify(&Path::new(tls::KEY_PATH).join("example.com/private.der")),
+            Some(Change::Certificates)
+        );
+        assert_eq!(classify(Path::new("data/qotd/history.json")), None);
+    }
+}